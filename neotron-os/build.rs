@@ -18,6 +18,12 @@ fn main() {
         .output()
     {
         let git_version = std::str::from_utf8(&cmd_output.stdout).unwrap();
+        // `git describe --long` ends `-g<hash>`, maybe followed by `-dirty`
+        let git_hash: String = git_version
+            .rsplit_once("-g")
+            .map(|(_, rest)| rest.chars().take_while(|c| c.is_ascii_hexdigit()).collect())
+            .unwrap_or_default();
+        println!("cargo:rustc-env=OS_GIT_HASH={}", git_hash);
         println!(
             "cargo:rustc-env=OS_VERSION={} (git:{})",
             env!("CARGO_PKG_VERSION"),
@@ -25,6 +31,7 @@ fn main() {
         );
     } else {
         println!("cargo:rustc-env=OS_VERSION={}", env!("CARGO_PKG_VERSION"));
+        println!("cargo:rustc-env=OS_GIT_HASH=");
     }
 
     if Ok("macos") == std::env::var("CARGO_CFG_TARGET_OS").as_deref() {
@@ -39,6 +46,41 @@ fn main() {
         println!("cargo:rustc-cfg=romfs_enabled=\"yes\"");
         println!("cargo:rerun-if-env-changed=ROMFS_PATH");
     }
+
+    if std::env::var_os("CARGO_FEATURE_SLIDES").is_some() {
+        make_slide_list();
+    }
+}
+
+/// Write out a list of the BMP files in `SLIDES_PATH`, for the OS to
+/// `include!`.
+fn make_slide_list() {
+    println!("cargo:rerun-if-env-changed=SLIDES_PATH");
+    let dir = std::env::var("SLIDES_PATH")
+        .expect("The slides feature needs SLIDES_PATH set to a directory of BMP files");
+    println!("cargo:rerun-if-changed={}", dir);
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(&dir)
+        .expect("reading SLIDES_PATH")
+        .map(|entry| entry.expect("reading SLIDES_PATH").path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("bmp"))
+        })
+        .collect();
+    // Slides are shown in name order
+    paths.sort();
+
+    let mut list = String::from("&[\n");
+    for path in paths {
+        let path = path.canonicalize().expect("finding slide");
+        let name = path.file_name().unwrap().to_str().expect("slide name");
+        let path = path.to_str().expect("slide path");
+        list += &format!("    ({:?}, include_bytes!({:?})),\n", name, path);
+    }
+    list += "]\n";
+
+    let out = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out.join("slides.rs"), list).unwrap();
 }
 
 /// Put the given script in our output directory and ensure it's on the linker
@@ -0,0 +1,1101 @@
+//! # BASIC Interpreter
+//!
+//! A small integer BASIC, so a Neotron with no SD card still has something
+//! interactive to do. Type `basic` at the OS prompt to start it, and `BYE` to
+//! get back to the OS.
+//!
+//! Variables are the letters `A` to `Z`, and hold 32-bit integers. Type a
+//! line with a line number to add it to the program, or without one to run
+//! it straight away.
+//!
+//! * Statements: `PRINT` (or `?`), `LET`, `INPUT`, `IF .. THEN`, `GOTO`,
+//!   `GOSUB`, `RETURN`, `FOR .. TO .. STEP`, `NEXT`, `REM`, `END`, `CLS` and
+//!   `SOUND <hz>, <ms>`
+//! * Functions: `RND(n)` (from 1 to n) and `ABS(n)`
+//! * Commands: `RUN`, `LIST`, `NEW`, `SAVE "file"`, `LOAD "file"` and `BYE`
+//!
+//! Press Escape or Ctrl+C to stop a running program, or Ctrl+C to get out of
+//! an `INPUT`.
+//!
+//! The program lives in the TPA, so loading an application throws it away.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::convert::TryFrom;
+
+use crate::{bios, osprint, osprintln, API, FILESYSTEM};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The longest line you can type
+const LINE_LEN: usize = 128;
+
+/// Each line in the program starts with the line number (two bytes, little
+/// endian) and the length of the text (one byte)
+const LINE_HEADER: usize = 3;
+
+/// How deep `GOSUB` can go
+const MAX_GOSUBS: usize = 8;
+
+/// How many `FOR` loops can be running at once
+const MAX_LOOPS: usize = 8;
+
+/// How deep brackets, minus signs and `IF .. THEN` can nest, so a silly line
+/// can't run us out of stack
+const MAX_DEPTH: usize = 16;
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Things that can go wrong running BASIC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Error {
+    /// We couldn't understand the line
+    Syntax,
+    /// A number was out of range
+    BadArgument,
+    /// `GOTO` or `GOSUB` to a line that isn't there
+    NoSuchLine,
+    /// Tried to divide by zero
+    DivideByZero,
+    /// `GOSUB` went too deep
+    TooManyGosubs,
+    /// `RETURN` without a `GOSUB`
+    ReturnWithoutGosub,
+    /// Too many `FOR` loops running at once
+    TooManyLoops,
+    /// `NEXT` without a `FOR`
+    NextWithoutFor,
+    /// The program doesn't fit in memory
+    OutOfMemory,
+    /// The user pressed Escape
+    Break,
+    /// Couldn't read or write a file
+    File,
+    /// Couldn't play a sound
+    Audio,
+}
+
+impl Error {
+    /// The message we show for this error
+    fn as_str(self) -> &'static str {
+        match self {
+            Error::Syntax => "Syntax error",
+            Error::BadArgument => "Bad argument",
+            Error::NoSuchLine => "No such line",
+            Error::DivideByZero => "Divide by zero",
+            Error::TooManyGosubs => "Too many GOSUBs",
+            Error::ReturnWithoutGosub => "RETURN without GOSUB",
+            Error::TooManyLoops => "Too many FOR loops",
+            Error::NextWithoutFor => "NEXT without FOR",
+            Error::OutOfMemory => "Out of memory",
+            Error::Break => "Break",
+            Error::File => "File error",
+            Error::Audio => "Audio error",
+        }
+    }
+}
+
+/// Where we are in the program
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Position {
+    /// The line number, or `None` for the line typed at the prompt
+    line: Option<u16>,
+    /// How far along the line we are
+    col: usize,
+}
+
+/// A running `FOR` loop
+#[derive(Debug, Copy, Clone)]
+struct ForLoop {
+    /// Which variable we are counting with
+    var: usize,
+    /// We stop when the variable goes past this
+    limit: i32,
+    /// How much we add each time around
+    step: i32,
+    /// Where the body of the loop starts
+    body: Position,
+}
+
+/// What to do after a statement
+enum Flow {
+    /// Carry on with the next statement
+    Next,
+    /// Carry on from somewhere else
+    Jump(Position),
+    /// Stop running
+    End,
+}
+
+/// Reads through a line of BASIC
+struct Scanner<'t> {
+    text: &'t [u8],
+    pos: usize,
+}
+
+impl<'t> Scanner<'t> {
+    /// Start reading `text` from `pos`
+    fn new(text: &'t [u8], pos: usize) -> Scanner<'t> {
+        Scanner { text, pos }
+    }
+
+    /// Move past any spaces
+    fn skip_spaces(&mut self) {
+        while self.text.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+    }
+
+    /// Have we run out of line?
+    fn at_end(&mut self) -> bool {
+        self.skip_spaces();
+        self.pos >= self.text.len()
+    }
+
+    /// Have we run out of statement?
+    fn at_statement_end(&mut self) -> bool {
+        self.at_end() || self.text[self.pos] == b':'
+    }
+
+    /// Move past `b`, if that's what comes next
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_spaces();
+        if self.text.get(self.pos) == Some(&b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move past `b`, or fail if something else comes next
+    fn expect(&mut self, b: u8) -> Result<(), Error> {
+        if self.eat(b) {
+            Ok(())
+        } else {
+            Err(Error::Syntax)
+        }
+    }
+
+    /// Move past `word` (in any case), if that's what comes next
+    fn keyword(&mut self, word: &str) -> bool {
+        self.skip_spaces();
+        match self.text.get(self.pos..self.pos + word.len()) {
+            Some(text) if text.eq_ignore_ascii_case(word.as_bytes()) => {
+                self.pos += word.len();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Read a positive number, if one comes next
+    fn number(&mut self) -> Option<i32> {
+        self.skip_spaces();
+        let start = self.pos;
+        let mut value: i32 = 0;
+        while let Some(digit) = self.text.get(self.pos).filter(|b| b.is_ascii_digit()) {
+            value = value
+                .checked_mul(10)?
+                .checked_add(i32::from(digit - b'0'))?;
+            self.pos += 1;
+        }
+        if self.pos == start {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Read a variable name, if one comes next, giving its index
+    fn variable(&mut self) -> Option<usize> {
+        self.skip_spaces();
+        let b = self.text.get(self.pos)?.to_ascii_uppercase();
+        if b.is_ascii_uppercase() {
+            self.pos += 1;
+            Some(usize::from(b - b'A'))
+        } else {
+            None
+        }
+    }
+
+    /// Read a string in double quotes, if one comes next
+    fn string(&mut self) -> Option<&'t [u8]> {
+        if !self.eat(b'"') {
+            return None;
+        }
+        let start = self.pos;
+        let len = self.text[start..].iter().position(|b| *b == b'"')?;
+        self.pos = start + len + 1;
+        Some(&self.text[start..start + len])
+    }
+}
+
+/// The interpreter, and the program it is running
+struct Interpreter<'a> {
+    /// Holds the program, one line after another, in line number order
+    program: &'a mut [u8],
+    /// How many bytes of `program` are in use
+    used: usize,
+    /// The variables `A` to `Z`
+    vars: [i32; 26],
+    /// Where each `RETURN` goes back to
+    gosubs: heapless::Vec<Position, MAX_GOSUBS>,
+    /// The `FOR` loops that are running
+    loops: heapless::Vec<ForLoop, MAX_LOOPS>,
+    /// The line we are running, for error messages
+    current: Option<u16>,
+    /// How deep we have nested, in the line we are working on
+    depth: usize,
+    /// State for `RND`
+    seed: u32,
+    /// Set when the user types `BYE`
+    quit: bool,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Make an interpreter, with an empty program
+    fn new(program: &'a mut [u8]) -> Interpreter<'a> {
+        let api = API.get();
+        Interpreter {
+            program,
+            used: 0,
+            vars: [0; 26],
+            gosubs: heapless::Vec::new(),
+            loops: heapless::Vec::new(),
+            current: None,
+            depth: 0,
+            // Any non-zero seed will do
+            seed: (api.time_ticks_get)().0 as u32 | 1,
+            quit: false,
+        }
+    }
+
+    /// Deal with a line the user typed (or we loaded).
+    ///
+    /// Lines starting with a number go into the program. Anything else runs
+    /// now.
+    fn enter(&mut self, line: &[u8]) {
+        self.current = None;
+        let mut s = Scanner::new(line, 0);
+        let result = match s.number() {
+            Some(number) => {
+                s.skip_spaces();
+                self.store_line(number, &line[s.pos..])
+            }
+            None => self.execute(line),
+        };
+        if let Err(e) = result {
+            match self.current {
+                Some(line) => {
+                    osprintln!("{} in line {}", e.as_str(), line);
+                }
+                None => {
+                    osprintln!("{}", e.as_str());
+                }
+            }
+        }
+    }
+
+    // -----------------------------------------------------------------------
+    // Program storage
+    // -----------------------------------------------------------------------
+
+    /// Get the line that starts at `offset` in the program.
+    ///
+    /// Gives the line number, the text, and where the next line starts.
+    fn line_at(&self, offset: usize) -> Option<(u16, &[u8], usize)> {
+        if offset >= self.used {
+            return None;
+        }
+        let header = &self.program[offset..offset + LINE_HEADER];
+        let number = u16::from_le_bytes([header[0], header[1]]);
+        let start = offset + LINE_HEADER;
+        let end = start + usize::from(header[2]);
+        Some((number, &self.program[start..end], end))
+    }
+
+    /// Find where line `number` is, or where it would go if it isn't there
+    fn find_line(&self, number: u16) -> Result<usize, usize> {
+        let mut offset = 0;
+        while let Some((this_number, _text, next)) = self.line_at(offset) {
+            if this_number == number {
+                return Ok(offset);
+            } else if this_number > number {
+                return Err(offset);
+            }
+            offset = next;
+        }
+        Err(offset)
+    }
+
+    /// Get the text of line `number`
+    fn get_line(&self, number: u16) -> Option<&[u8]> {
+        let offset = self.find_line(number).ok()?;
+        self.line_at(offset).map(|(_, text, _)| text)
+    }
+
+    /// Get the number of the first line after line `number`
+    fn next_line(&self, number: u16) -> Option<u16> {
+        let offset = match self.find_line(number) {
+            Ok(offset) => self.line_at(offset)?.2,
+            Err(offset) => offset,
+        };
+        self.line_at(offset).map(|(number, _, _)| number)
+    }
+
+    /// Add, replace or (if `text` is empty) delete a line of the program
+    fn store_line(&mut self, number: i32, text: &[u8]) -> Result<(), Error> {
+        let number = u16::try_from(number)
+            .ok()
+            .filter(|n| *n != 0)
+            .ok_or(Error::BadArgument)?;
+        let offset = match self.find_line(number) {
+            Ok(offset) => {
+                // Take the old line out
+                let next = self.line_at(offset).map_or(offset, |(_, _, next)| next);
+                self.program.copy_within(next..self.used, offset);
+                self.used -= next - offset;
+                offset
+            }
+            Err(offset) => offset,
+        };
+        if text.is_empty() {
+            return Ok(());
+        }
+        let len = LINE_HEADER + text.len();
+        if text.len() > LINE_LEN || self.used + len > self.program.len() {
+            return Err(Error::OutOfMemory);
+        }
+        self.program.copy_within(offset..self.used, offset + len);
+        self.used += len;
+        self.program[offset..offset + 2].copy_from_slice(&number.to_le_bytes());
+        self.program[offset + 2] = text.len() as u8;
+        self.program[offset + LINE_HEADER..offset + len].copy_from_slice(text);
+        Ok(())
+    }
+
+    /// Print the program
+    fn list(&self) {
+        let mut offset = 0;
+        while let Some((number, text, next)) = self.line_at(offset) {
+            osprintln!(
+                "{} {}",
+                number,
+                core::str::from_utf8(text).unwrap_or("<invalid>")
+            );
+            offset = next;
+        }
+    }
+
+    /// Write the program to a file, as text
+    fn save(&self, file_name: &str) -> Result<(), Error> {
+        use core::fmt::Write as _;
+        let mut file = FILESYSTEM
+            .open_file(file_name, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)
+            .map_err(|_| Error::File)?;
+        let mut offset = 0;
+        while let Some((number, text, next)) = self.line_at(offset) {
+            let text = core::str::from_utf8(text).unwrap_or("");
+            writeln!(file, "{} {}", number, text).map_err(|_| Error::File)?;
+            offset = next;
+        }
+        Ok(())
+    }
+
+    /// Replace the program with one from a file
+    fn load(&mut self, file_name: &str) -> Result<(), Error> {
+        let file = FILESYSTEM
+            .open_file(file_name, embedded_sdmmc::Mode::ReadOnly)
+            .map_err(|_| Error::File)?;
+        self.used = 0;
+        let mut line: heapless::Vec<u8, LINE_LEN> = heapless::Vec::new();
+        let mut buffer = [0u8; 64];
+        while !file.is_eof() {
+            let count = file.read(&mut buffer).map_err(|_| Error::File)?;
+            for b in &buffer[0..count] {
+                match b {
+                    b'\n' => {
+                        self.load_line(&line)?;
+                        line.clear();
+                    }
+                    b'\r' => {
+                        // Ignore these
+                    }
+                    _ => line.push(*b).map_err(|_| Error::OutOfMemory)?,
+                }
+            }
+        }
+        self.load_line(&line)
+    }
+
+    /// Store one line from a file we are loading
+    fn load_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        let mut s = Scanner::new(line, 0);
+        if s.at_end() {
+            return Ok(());
+        }
+        let number = s.number().ok_or(Error::Syntax)?;
+        s.skip_spaces();
+        self.store_line(number, &line[s.pos..])
+    }
+
+    // -----------------------------------------------------------------------
+    // Running things
+    // -----------------------------------------------------------------------
+
+    /// Run a line typed at the prompt, and any program it starts
+    fn execute(&mut self, immediate: &[u8]) -> Result<(), Error> {
+        let mut pos = Position { line: None, col: 0 };
+        // A copy of the line we are on, so we can change the program
+        let mut text: heapless::Vec<u8, 256> = heapless::Vec::new();
+        text.extend_from_slice(immediate)
+            .map_err(|_| Error::OutOfMemory)?;
+        self.current = None;
+        loop {
+            if break_pressed() {
+                return Err(Error::Break);
+            }
+            let mut s = Scanner::new(&text, pos.col);
+            let next = match self.statement(&mut s)? {
+                Flow::Next => {
+                    if s.eat(b':') {
+                        Position {
+                            line: pos.line,
+                            col: s.pos,
+                        }
+                    } else if !s.at_end() {
+                        return Err(Error::Syntax);
+                    } else if let Some(line) = pos.line.and_then(|n| self.next_line(n)) {
+                        Position {
+                            line: Some(line),
+                            col: 0,
+                        }
+                    } else {
+                        return Ok(());
+                    }
+                }
+                Flow::Jump(next) => next,
+                Flow::End => return Ok(()),
+            };
+            if next.line != pos.line {
+                let new_text = match next.line {
+                    Some(number) => self.get_line(number).ok_or(Error::NoSuchLine)?,
+                    None => immediate,
+                };
+                text.clear();
+                // Lines are never longer than `LINE_LEN`
+                let _ = text.extend_from_slice(new_text);
+                self.current = next.line;
+            }
+            pos = next;
+        }
+    }
+
+    /// Run one statement
+    fn statement(&mut self, s: &mut Scanner) -> Result<Flow, Error> {
+        let line = self.current;
+        if s.at_statement_end() {
+            Ok(Flow::Next)
+        } else if s.keyword("PRINT") || s.eat(b'?') {
+            self.print(s)?;
+            Ok(Flow::Next)
+        } else if s.keyword("IF") {
+            let condition = self.expr(s)?;
+            if !s.keyword("THEN") {
+                return Err(Error::Syntax);
+            }
+            if condition == 0 {
+                // Skip the rest of the line
+                s.pos = s.text.len();
+                Ok(Flow::Next)
+            } else if let Some(number) = s.number() {
+                self.goto(number)
+            } else {
+                self.nested(|this| this.statement(s))
+            }
+        } else if s.keyword("GOTO") {
+            let number = self.expr(s)?;
+            self.goto(number)
+        } else if s.keyword("GOSUB") {
+            let number = self.expr(s)?;
+            let back = Position { line, col: s.pos };
+            self.gosubs.push(back).map_err(|_| Error::TooManyGosubs)?;
+            self.goto(number)
+        } else if s.keyword("RETURN") {
+            let back = self.gosubs.pop().ok_or(Error::ReturnWithoutGosub)?;
+            Ok(Flow::Jump(back))
+        } else if s.keyword("FOR") {
+            self.for_loop(s)?;
+            Ok(Flow::Next)
+        } else if s.keyword("NEXT") {
+            self.next(s)
+        } else if s.keyword("INPUT") {
+            self.input(s)?;
+            Ok(Flow::Next)
+        } else if s.keyword("REM") || s.eat(b'\'') {
+            s.pos = s.text.len();
+            Ok(Flow::Next)
+        } else if s.keyword("END") || s.keyword("STOP") {
+            Ok(Flow::End)
+        } else if s.keyword("CLS") {
+            osprint!("\u{001b}[2J\u{001b}[1;1H");
+            Ok(Flow::Next)
+        } else if s.keyword("SOUND") {
+            let freq = self.expr(s)?;
+            s.expect(b',')?;
+            let ms = self.expr(s)?;
+            sound(freq, ms)?;
+            Ok(Flow::Next)
+        } else if s.keyword("RUN") {
+            self.vars = [0; 26];
+            self.gosubs.clear();
+            self.loops.clear();
+            match self.line_at(0) {
+                Some((number, _, _)) => Ok(Flow::Jump(Position {
+                    line: Some(number),
+                    col: 0,
+                })),
+                None => Ok(Flow::End),
+            }
+        } else if s.keyword("LIST") {
+            self.list();
+            Ok(Flow::Next)
+        } else if s.keyword("NEW") {
+            self.used = 0;
+            Ok(Flow::End)
+        } else if s.keyword("SAVE") {
+            self.save(file_name(s)?)?;
+            Ok(Flow::Next)
+        } else if s.keyword("LOAD") {
+            self.load(file_name(s)?)?;
+            Ok(Flow::End)
+        } else if s.keyword("BYE") {
+            self.quit = true;
+            Ok(Flow::End)
+        } else {
+            s.keyword("LET");
+            let var = s.variable().ok_or(Error::Syntax)?;
+            s.expect(b'=')?;
+            self.vars[var] = self.expr(s)?;
+            Ok(Flow::Next)
+        }
+    }
+
+    /// Work out where `GOTO <number>` goes
+    fn goto(&self, number: i32) -> Result<Flow, Error> {
+        let number = u16::try_from(number).map_err(|_| Error::NoSuchLine)?;
+        if self.get_line(number).is_none() {
+            return Err(Error::NoSuchLine);
+        }
+        Ok(Flow::Jump(Position {
+            line: Some(number),
+            col: 0,
+        }))
+    }
+
+    /// Handle `PRINT`
+    fn print(&mut self, s: &mut Scanner) -> Result<(), Error> {
+        loop {
+            if s.at_statement_end() {
+                osprintln!();
+                return Ok(());
+            }
+            if let Some(text) = s.string() {
+                osprint!("{}", core::str::from_utf8(text).unwrap_or("<invalid>"));
+            } else {
+                let value = self.expr(s)?;
+                osprint!("{}", value);
+            }
+            if s.eat(b',') {
+                osprint!(" ");
+            } else if !s.eat(b';') {
+                if s.at_statement_end() {
+                    osprintln!();
+                    return Ok(());
+                }
+                return Err(Error::Syntax);
+            }
+            // A trailing separator means no new-line
+            if s.at_statement_end() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle `INPUT`
+    fn input(&mut self, s: &mut Scanner) -> Result<(), Error> {
+        if let Some(prompt) = s.string() {
+            osprint!("{}", core::str::from_utf8(prompt).unwrap_or("<invalid>"));
+            if !s.eat(b';') && !s.eat(b',') {
+                return Err(Error::Syntax);
+            }
+        }
+        let var = s.variable().ok_or(Error::Syntax)?;
+        let mut line = heapless::Vec::new();
+        loop {
+            osprint!("? ");
+            if !read_line(&mut line) {
+                return Err(Error::Break);
+            }
+            let mut input = Scanner::new(&line, 0);
+            let negative = input.eat(b'-');
+            if let (Some(value), true) = (input.number(), input.at_end()) {
+                self.vars[var] = if negative { -value } else { value };
+                return Ok(());
+            }
+            osprintln!("Please enter a number");
+        }
+    }
+
+    /// Handle `FOR`
+    fn for_loop(&mut self, s: &mut Scanner) -> Result<(), Error> {
+        let var = s.variable().ok_or(Error::Syntax)?;
+        s.expect(b'=')?;
+        let start = self.expr(s)?;
+        if !s.keyword("TO") {
+            return Err(Error::Syntax);
+        }
+        let limit = self.expr(s)?;
+        let step = if s.keyword("STEP") { self.expr(s)? } else { 1 };
+        self.vars[var] = start;
+        // Starting a loop again throws away the old one
+        if let Some(idx) = self.loops.iter().position(|l| l.var == var) {
+            self.loops.truncate(idx);
+        }
+        let body = Position {
+            line: self.current,
+            col: s.pos,
+        };
+        self.loops
+            .push(ForLoop {
+                var,
+                limit,
+                step,
+                body,
+            })
+            .map_err(|_| Error::TooManyLoops)
+    }
+
+    /// Handle `NEXT`
+    fn next(&mut self, s: &mut Scanner) -> Result<Flow, Error> {
+        let idx = match s.variable() {
+            Some(var) => self.loops.iter().rposition(|l| l.var == var),
+            None => self.loops.len().checked_sub(1),
+        };
+        let idx = idx.ok_or(Error::NextWithoutFor)?;
+        // Any loops inside this one have finished
+        self.loops.truncate(idx + 1);
+        let l = self.loops[idx];
+        let value = self.vars[l.var].wrapping_add(l.step);
+        self.vars[l.var] = value;
+        let again = if l.step >= 0 {
+            value <= l.limit
+        } else {
+            value >= l.limit
+        };
+        if again {
+            Ok(Flow::Jump(l.body))
+        } else {
+            self.loops.pop();
+            Ok(Flow::Next)
+        }
+    }
+
+    // -----------------------------------------------------------------------
+    // Expressions
+    // -----------------------------------------------------------------------
+
+    /// Work out an expression, with an optional comparison (which gives 1
+    /// for true and 0 for false)
+    fn expr(&mut self, s: &mut Scanner) -> Result<i32, Error> {
+        let lhs = self.sum(s)?;
+        let result = if s.keyword("<>") {
+            lhs != self.sum(s)?
+        } else if s.keyword("<=") {
+            lhs <= self.sum(s)?
+        } else if s.keyword(">=") {
+            lhs >= self.sum(s)?
+        } else if s.eat(b'<') {
+            lhs < self.sum(s)?
+        } else if s.eat(b'>') {
+            lhs > self.sum(s)?
+        } else if s.eat(b'=') {
+            lhs == self.sum(s)?
+        } else {
+            return Ok(lhs);
+        };
+        Ok(i32::from(result))
+    }
+
+    /// Work out things added together
+    fn sum(&mut self, s: &mut Scanner) -> Result<i32, Error> {
+        let mut value = self.term(s)?;
+        loop {
+            if s.eat(b'+') {
+                value = value.wrapping_add(self.term(s)?);
+            } else if s.eat(b'-') {
+                value = value.wrapping_sub(self.term(s)?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Work out things multiplied together
+    fn term(&mut self, s: &mut Scanner) -> Result<i32, Error> {
+        let mut value = self.factor(s)?;
+        loop {
+            if s.eat(b'*') {
+                value = value.wrapping_mul(self.factor(s)?);
+            } else if s.eat(b'/') {
+                let rhs = self.factor(s)?;
+                if rhs == 0 {
+                    return Err(Error::DivideByZero);
+                }
+                value = value.wrapping_div(rhs);
+            } else if s.keyword("MOD") {
+                let rhs = self.factor(s)?;
+                if rhs == 0 {
+                    return Err(Error::DivideByZero);
+                }
+                value = value.wrapping_rem(rhs);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Work out a number, variable, function or bracketed expression
+    fn factor(&mut self, s: &mut Scanner) -> Result<i32, Error> {
+        if s.eat(b'-') {
+            Ok(self.nested(|this| this.factor(s))?.wrapping_neg())
+        } else if s.eat(b'(') {
+            let value = self.nested(|this| this.expr(s))?;
+            s.expect(b')')?;
+            Ok(value)
+        } else if let Some(value) = s.number() {
+            Ok(value)
+        } else if s.keyword("RND") {
+            let max = self.argument(s)?;
+            if max <= 0 {
+                return Err(Error::BadArgument);
+            }
+            Ok((self.random() % max as u32) as i32 + 1)
+        } else if s.keyword("ABS") {
+            Ok(self.argument(s)?.wrapping_abs())
+        } else if let Some(var) = s.variable() {
+            Ok(self.vars[var])
+        } else {
+            Err(Error::Syntax)
+        }
+    }
+
+    /// Work out a function's argument, in brackets
+    fn argument(&mut self, s: &mut Scanner) -> Result<i32, Error> {
+        s.expect(b'(')?;
+        let value = self.nested(|this| this.expr(s))?;
+        s.expect(b')')?;
+        Ok(value)
+    }
+
+    /// Call `f` one level deeper, or give a syntax error if we are already
+    /// as deep as we go
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::Syntax);
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Get a pseudo-random number (xorshift32)
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Run the BASIC interpreter until the user types `BYE`.
+///
+/// The program is kept in `memory`. If `file_name` is given, we load that
+/// program and run it first.
+pub fn run(memory: &mut [u8], file_name: Option<&str>) {
+    let mut interpreter = Interpreter::new(memory);
+    osprintln!("Neotron BASIC. Type BYE to go back to the OS.");
+    if let Some(file_name) = file_name {
+        match interpreter.load(file_name) {
+            Ok(()) => interpreter.enter(b"RUN"),
+            Err(e) => {
+                osprintln!("{}", e.as_str());
+            }
+        }
+    }
+    let mut line = heapless::Vec::new();
+    while !interpreter.quit {
+        osprint!("> ");
+        if read_line(&mut line) {
+            interpreter.enter(&line);
+        } else {
+            osprintln!();
+        }
+    }
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Get the file name for `SAVE` or `LOAD`
+fn file_name<'t>(s: &mut Scanner<'t>) -> Result<&'t str, Error> {
+    let name = s.string().ok_or(Error::Syntax)?;
+    core::str::from_utf8(name).map_err(|_| Error::Syntax)
+}
+
+/// Read a line from the keyboard, echoing it as it is typed.
+///
+/// Returns false if the user pressed Ctrl+C instead.
+fn read_line(line: &mut heapless::Vec<u8, LINE_LEN>) -> bool {
+    line.clear();
+    loop {
+        let mut buffer = [0u8; 1];
+        if crate::STD_INPUT.lock().get_data(&mut buffer) == 0 {
+            crate::stats::idle();
+            continue;
+        }
+        match buffer[0] {
+            b'\r' | b'\n' => {
+                osprintln!();
+                return true;
+            }
+            0x03 => {
+                return false;
+            }
+            0x08 | 0x7F if !line.is_empty() => {
+                line.pop();
+                osprint!("\u{0008} \u{0008}");
+            }
+            b @ 0x20..=0x7E if !line.is_full() => {
+                // This can't fail - we checked there was space
+                let _ = line.push(b);
+                osprint!("{}", b as char);
+            }
+            _ => {
+                // Ignore anything else
+            }
+        }
+    }
+}
+
+/// Has the user pressed Escape or Ctrl+C?
+fn break_pressed() -> bool {
+    let mut buffer = [0u8; 1];
+    crate::STD_INPUT.lock().get_data(&mut buffer) == 1 && matches!(buffer[0], 0x03 | 0x1B)
+}
+
+/// Play a square wave at `freq` Hz for `ms` milliseconds
+fn sound(freq: i32, ms: i32) -> Result<(), Error> {
+    let (Ok(freq), Ok(ms)) = (u32::try_from(freq), u32::try_from(ms)) else {
+        return Err(Error::BadArgument);
+    };
+    if freq == 0 {
+        return Err(Error::BadArgument);
+    }
+    let api = API.get();
+    let bios::FfiResult::Ok(config) = (api.audio_output_get_config)() else {
+        return Err(Error::Audio);
+    };
+    let channels = match config.sample_format.make_safe() {
+        Ok(bios::audio::SampleFormat::SixteenBitMono) => 1,
+        Ok(bios::audio::SampleFormat::SixteenBitStereo) => 2,
+        _ => return Err(Error::Audio),
+    };
+    let rate = config.sample_rate_hz;
+    let half_period = (rate / (freq * 2)).max(1);
+    let total_frames = u64::from(rate) * u64::from(ms) / 1000;
+    let mut frame: u64 = 0;
+    let mut buffer = [0u8; 256];
+    while frame < total_frames {
+        // Fill the buffer with as many whole frames as will fit
+        let mut used = 0;
+        while frame < total_frames && used + channels * 2 <= buffer.len() {
+            let high = (frame / u64::from(half_period)) & 1 == 0;
+            let sample: i16 = if high { 8192 } else { -8192 };
+            for _ in 0..channels {
+                buffer[used..used + 2].copy_from_slice(&sample.to_le_bytes());
+                used += 2;
+            }
+            frame += 1;
+        }
+        let mut data = &buffer[0..used];
+        while !data.is_empty() {
+            if break_pressed() {
+                return Err(Error::Break);
+            }
+            match crate::stats::audio_output(data) {
+                bios::FfiResult::Ok(played) => data = &data[played..],
+                bios::FfiResult::Err(_) => return Err(Error::Audio),
+            }
+        }
+    }
+    Ok(())
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_bios::MockBios;
+
+    /// Type each of these lines at the BASIC prompt, and give back what was
+    /// printed
+    fn basic(lines: &[&str]) -> String {
+        let bios = MockBios::new();
+        let _shell = bios.boot();
+        let mut memory = [0u8; 1024];
+        let mut interpreter = Interpreter::new(&mut memory);
+        let _ = bios.take_serial_output();
+        for line in lines {
+            interpreter.enter(line.as_bytes());
+        }
+        bios.take_serial_output()
+    }
+
+    /// Work out an expression on its own
+    fn eval(text: &str) -> Result<i32, Error> {
+        let bios = MockBios::new();
+        let _shell = bios.boot();
+        let mut memory = [0u8; 16];
+        let mut interpreter = Interpreter::new(&mut memory);
+        let mut s = Scanner::new(text.as_bytes(), 0);
+        let value = interpreter.expr(&mut s)?;
+        if s.at_end() {
+            Ok(value)
+        } else {
+            Err(Error::Syntax)
+        }
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("7 / 2"), Ok(3));
+        assert_eq!(eval("7 MOD 3"), Ok(1));
+        assert_eq!(eval("--5"), Ok(5));
+        assert_eq!(eval("ABS(-4)"), Ok(4));
+        assert_eq!(eval("3 < 4"), Ok(1));
+        assert_eq!(eval("3 >= 4"), Ok(0));
+        assert_eq!(eval("2 <> 2"), Ok(0));
+        assert_eq!(eval("RND(1)"), Ok(1));
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert_eq!(eval("1 / 0"), Err(Error::DivideByZero));
+        assert_eq!(eval("1 MOD 0"), Err(Error::DivideByZero));
+        assert_eq!(eval("(1 + 2"), Err(Error::Syntax));
+        assert_eq!(eval("1 +"), Err(Error::Syntax));
+        assert_eq!(eval("RND(0)"), Err(Error::BadArgument));
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let ok = format!("{}1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert_eq!(eval(&ok), Ok(1));
+        let deep = format!(
+            "{}1{}",
+            "(".repeat(MAX_DEPTH + 1),
+            ")".repeat(MAX_DEPTH + 1)
+        );
+        assert_eq!(eval(&deep), Err(Error::Syntax));
+        assert_eq!(eval(&"-".repeat(100)), Err(Error::Syntax));
+        let output = basic(&[&"IF 1 THEN ".repeat(MAX_DEPTH + 1)]);
+        assert_eq!(output, "Syntax error\n");
+    }
+
+    #[test]
+    fn goto_and_gosub() {
+        let output = basic(&[
+            "10 GOSUB 100",
+            "20 GOSUB 100",
+            "30 GOTO 50",
+            "40 PRINT \"skipped\"",
+            "50 END",
+            "100 A = A + 1: PRINT A",
+            "110 RETURN",
+            "RUN",
+        ]);
+        assert_eq!(output, "1\n2\n");
+    }
+
+    #[test]
+    fn for_loops() {
+        let output = basic(&[
+            "10 FOR I = 1 TO 3",
+            "20 FOR J = 10 TO 0 STEP -5: PRINT I * J;\" \";: NEXT J",
+            "30 NEXT I",
+            "40 PRINT",
+            "RUN",
+        ]);
+        assert_eq!(output, "10 5 0 20 10 0 30 15 0 \n");
+    }
+
+    #[test]
+    fn run_time_errors() {
+        let output = basic(&[
+            "10 GOTO 99",
+            "RUN",
+            "10 RETURN",
+            "RUN",
+            "10 NEXT",
+            "RUN",
+            "10 GOSUB 10",
+            "RUN",
+            "PRINT 1 +",
+            "0 PRINT",
+        ]);
+        assert_eq!(
+            output,
+            "No such line in line 10\n\
+             RETURN without GOSUB in line 10\n\
+             NEXT without FOR in line 10\n\
+             Too many GOSUBs in line 10\n\
+             Syntax error\n\
+             Bad argument\n"
+        );
+    }
+
+    #[test]
+    fn list_and_new() {
+        let output = basic(&[
+            "20 PRINT 2",
+            "10 PRINT 1",
+            "20",
+            "15 REM hi",
+            "LIST",
+            "NEW",
+            "LIST",
+        ]);
+        assert_eq!(output, "10 PRINT 1\n15 REM hi\n");
+    }
+}
+
+// End of file
@@ -0,0 +1,658 @@
+//! Hosted Neotron OS
+//!
+//! Runs the OS in a terminal on your PC, so you can work on the shell without
+//! a Neotron. This is a small BIOS, built on the standard library:
+//!
+//! * Serial port 0 is the terminal. Keys you press are read from stdin and
+//!   everything written goes to stdout, so the terminal handles the ANSI
+//!   escape sequences.
+//! * Block device 0 is a disk image file, if you give one.
+//! * There's no video, keyboard, audio, I²C or Neotron Bus.
+//!
+//! The configuration is kept in memory, and starts with the serial console
+//! on and the VGA console off.
+//!
+//! Run it with `cargo run --features hosted --bin hosted -- [disk.img]`. Press
+//! `Ctrl-]` to quit.
+//!
+//! Copyright (c) The Neotron Developers, 2024
+//!
+//! Licence: GPL v3 or higher (see ../LICENCE.md)
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{mpsc, Mutex, MutexGuard};
+
+use neotron_common_bios as bios;
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many ticks there are in a second
+const TICKS_PER_SECOND: u64 = 1000;
+
+/// How big our blocks are
+const BLOCK_SIZE: u64 = 512;
+
+/// How big the Transient Program Area is, in bytes
+const TPA_SIZE: usize = 256 * 1024;
+
+/// Seconds between the UNIX epoch and the Neotron epoch (2000-01-01)
+const NEOTRON_EPOCH_SECS: u64 = 946_684_800;
+
+/// The key which quits (`Ctrl-]`)
+const QUIT_KEY: u8 = 0x1D;
+
+/// How long to sleep when the OS is idle
+const IDLE_TIME: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// Our Transient Program Area. The OS holds on to pointers into this, so it
+/// must never move.
+static mut TPA: [u32; TPA_SIZE / 4] = [0; TPA_SIZE / 4];
+
+/// Everything our BIOS knows. Set up in `main`.
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// The BIOS API table we give the OS
+static API: bios::Api = bios::Api {
+    api_version_get,
+    bios_version_get,
+    serial_get_info,
+    serial_configure,
+    serial_write,
+    serial_read,
+    time_clock_get,
+    time_clock_set,
+    time_ticks_get,
+    time_ticks_per_second,
+    configuration_get,
+    configuration_set,
+    video_is_valid_mode,
+    video_mode_needs_vram,
+    video_set_mode,
+    video_get_mode,
+    video_get_framebuffer,
+    video_wait_for_line,
+    video_get_palette,
+    video_set_palette,
+    video_set_whole_palette,
+    memory_get_region,
+    hid_get_event,
+    hid_set_leds,
+    i2c_bus_get_info,
+    i2c_write_read,
+    audio_mixer_channel_get_info,
+    audio_mixer_channel_set_level,
+    audio_output_set_config,
+    audio_output_get_config,
+    audio_output_data,
+    audio_output_get_space,
+    audio_input_set_config,
+    audio_input_get_config,
+    audio_input_data,
+    audio_input_get_count,
+    bus_select,
+    bus_get_info,
+    bus_write_read,
+    bus_exchange,
+    bus_interrupt_status,
+    block_dev_get_info,
+    block_dev_eject,
+    block_write,
+    block_read,
+    block_verify,
+    power_idle,
+    power_control,
+    compare_and_swap_bool,
+};
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Everything our BIOS knows
+struct State {
+    /// When we started, for the tick counter
+    start: std::time::Instant,
+    /// Seconds to add to the PC's clock, because the OS set the time
+    clock_offset: i64,
+    /// The stored configuration
+    config: Vec<u8>,
+    /// The disk image, if we have one
+    disk: Option<Disk>,
+    /// Bytes typed at the terminal, from the stdin thread
+    input: mpsc::Receiver<u8>,
+}
+
+/// A disk image file
+struct Disk {
+    /// The file
+    file: std::fs::File,
+    /// How many blocks it holds
+    num_blocks: u64,
+    /// Could we only open it for reading?
+    read_only: bool,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let disk = match args.next().as_deref() {
+        Some("-h") | Some("--help") => {
+            println!("Usage: hosted [disk.img]");
+            println!("Runs Neotron OS in this terminal. Press Ctrl-] to quit.");
+            return;
+        }
+        Some(path) => match open_disk(path) {
+            Ok(disk) => Some(disk),
+            Err(e) => {
+                eprintln!("Can't open disk image {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut config = neotron_os::OsConfig::default();
+    config.set_vga_console(None);
+    config.set_serial_console_on(115200);
+    let mut buffer = [0u8; 256];
+    let config = config
+        .to_bytes(&mut buffer)
+        .expect("Default config should fit")
+        .to_vec();
+
+    let (tx, rx) = mpsc::channel();
+    *state() = Some(State {
+        start: std::time::Instant::now(),
+        clock_offset: 0,
+        config,
+        disk,
+        input: rx,
+    });
+
+    if let Err(e) = crossterm::terminal::enable_raw_mode() {
+        eprintln!("Can't put the terminal in raw mode: {}", e);
+        std::process::exit(1);
+    }
+    // Put the terminal back if the OS panics
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = crossterm::terminal::disable_raw_mode();
+        default_hook(info);
+    }));
+
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for byte in stdin.lock().bytes() {
+            match byte {
+                Ok(QUIT_KEY) | Err(_) => quit(0),
+                Ok(b) => {
+                    if tx.send(b).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        // End of input
+        quit(0);
+    });
+
+    neotron_os::os_main(&API);
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Get our state
+fn state() -> MutexGuard<'static, Option<State>> {
+    // If we panicked while holding the lock, we're quitting anyway
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Do something with our state.
+fn with_state<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut State) -> T,
+{
+    let mut guard = state();
+    f(guard.as_mut().expect("BIOS should be set up"))
+}
+
+/// Put the terminal back and exit
+fn quit(code: i32) -> ! {
+    let _ = crossterm::terminal::disable_raw_mode();
+    println!();
+    std::process::exit(code);
+}
+
+/// Open a disk image, for writing if we can.
+fn open_disk(path: &str) -> std::io::Result<Disk> {
+    let (file, read_only) = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(file) => (file, false),
+        Err(_) => (std::fs::File::open(path)?, true),
+    };
+    let num_blocks = file.metadata()?.len() / BLOCK_SIZE;
+    Ok(Disk {
+        file,
+        num_blocks,
+        read_only,
+    })
+}
+
+/// Find the disk, and where these blocks are on it.
+fn disk_seek(
+    state: &mut State,
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    len: usize,
+) -> Result<&mut Disk, bios::Error> {
+    if device_id != 0 {
+        return Err(bios::Error::InvalidDevice);
+    }
+    let disk = state.disk.as_mut().ok_or(bios::Error::NoMediaFound)?;
+    if start_block.0 + u64::from(num_blocks) > disk.num_blocks {
+        return Err(bios::Error::BlockOutOfBounds);
+    }
+    if len as u64 != u64::from(num_blocks) * BLOCK_SIZE {
+        return Err(bios::Error::UnsupportedConfiguration);
+    }
+    disk.file
+        .seek(SeekFrom::Start(start_block.0 * BLOCK_SIZE))
+        .map_err(|_| bios::Error::DeviceError)?;
+    Ok(disk)
+}
+
+/// Turn a `Result` into an FFI-safe `ApiResult`
+fn api_result<T>(result: Result<T, bios::Error>) -> bios::ApiResult<T> {
+    match result {
+        Ok(value) => bios::ApiResult::Ok(value),
+        Err(e) => bios::ApiResult::Err(e),
+    }
+}
+
+// ===========================================================================
+// The BIOS API
+// ===========================================================================
+
+extern "C" fn api_version_get() -> bios::Version {
+    bios::API_VERSION
+}
+
+extern "C" fn bios_version_get() -> bios::FfiString<'static> {
+    bios::FfiString::new(concat!("Neotron Hosted BIOS, v", env!("CARGO_PKG_VERSION")))
+}
+
+extern "C" fn serial_get_info(device_id: u8) -> bios::FfiOption<bios::serial::DeviceInfo> {
+    if device_id == 0 {
+        bios::FfiOption::Some(bios::serial::DeviceInfo {
+            name: bios::FfiString::new("Terminal"),
+            device_type: bios::serial::DeviceType::UsbCdc.make_ffi_safe(),
+        })
+    } else {
+        bios::FfiOption::None
+    }
+}
+
+extern "C" fn serial_configure(
+    device_id: u8,
+    _config: bios::serial::Config,
+) -> bios::ApiResult<()> {
+    api_result(if device_id == 0 {
+        Ok(())
+    } else {
+        Err(bios::Error::InvalidDevice)
+    })
+}
+
+extern "C" fn serial_write(
+    device_id: u8,
+    data: bios::FfiByteSlice,
+    _timeout: bios::FfiOption<bios::Timeout>,
+) -> bios::ApiResult<usize> {
+    if device_id != 0 {
+        return bios::ApiResult::Err(bios::Error::InvalidDevice);
+    }
+    let data = data.as_slice();
+    let mut stdout = std::io::stdout().lock();
+    // The terminal is in raw mode, so a new line doesn't go back to the
+    // start of the line, like it would on a real serial terminal
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let result = match line.split_last() {
+            Some((b'\n', rest)) => stdout
+                .write_all(rest)
+                .and_then(|_| stdout.write_all(b"\r\n")),
+            _ => stdout.write_all(line),
+        };
+        if result.is_err() {
+            return bios::ApiResult::Err(bios::Error::DeviceError);
+        }
+    }
+    let _ = stdout.flush();
+    bios::ApiResult::Ok(data.len())
+}
+
+extern "C" fn serial_read(
+    device_id: u8,
+    mut data: bios::FfiBuffer,
+    _timeout: bios::FfiOption<bios::Timeout>,
+) -> bios::ApiResult<usize> {
+    if device_id != 0 {
+        return bios::ApiResult::Err(bios::Error::InvalidDevice);
+    }
+    let Some(buffer) = data.as_mut_slice() else {
+        return bios::ApiResult::Ok(0);
+    };
+    with_state(|state| {
+        let mut count = 0;
+        for slot in buffer.iter_mut() {
+            let Ok(b) = state.input.try_recv() else {
+                break;
+            };
+            *slot = b;
+            count += 1;
+        }
+        bios::ApiResult::Ok(count)
+    })
+}
+
+extern "C" fn time_clock_get() -> bios::Time {
+    let offset = with_state(|state| state.clock_offset);
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs().saturating_sub(NEOTRON_EPOCH_SECS) as i64 + offset;
+    bios::Time {
+        secs: secs.clamp(0, i64::from(u32::MAX)) as u32,
+        nsecs: since_epoch.subsec_nanos(),
+    }
+}
+
+extern "C" fn time_clock_set(time: bios::Time) {
+    let now = i64::from(time_clock_get().secs);
+    with_state(|state| state.clock_offset += i64::from(time.secs) - now);
+}
+
+extern "C" fn time_ticks_get() -> bios::Ticks {
+    let elapsed = with_state(|state| state.start.elapsed());
+    bios::Ticks(elapsed.as_millis() as u64)
+}
+
+extern "C" fn time_ticks_per_second() -> bios::Ticks {
+    bios::Ticks(TICKS_PER_SECOND)
+}
+
+extern "C" fn configuration_get(mut buffer: bios::FfiBuffer) -> bios::ApiResult<usize> {
+    with_state(|state| match buffer.as_mut_slice() {
+        Some(buffer) if buffer.len() >= state.config.len() => {
+            buffer[0..state.config.len()].copy_from_slice(&state.config);
+            bios::ApiResult::Ok(state.config.len())
+        }
+        _ => bios::ApiResult::Err(bios::Error::UnsupportedConfiguration),
+    })
+}
+
+extern "C" fn configuration_set(buffer: bios::FfiByteSlice) -> bios::ApiResult<()> {
+    with_state(|state| state.config = buffer.as_slice().to_vec());
+    bios::ApiResult::Ok(())
+}
+
+extern "C" fn video_is_valid_mode(_mode: bios::video::Mode) -> bool {
+    false
+}
+
+extern "C" fn video_mode_needs_vram(_mode: bios::video::Mode) -> bool {
+    false
+}
+
+unsafe extern "C" fn video_set_mode(
+    _mode: bios::video::Mode,
+    _fb: *mut u32,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn video_get_mode() -> bios::video::Mode {
+    // Not a text mode, so the OS doesn't try to put a console on it
+    bios::video::Mode::new(bios::video::Timing::T640x480, bios::video::Format::Chunky1)
+}
+
+extern "C" fn video_get_framebuffer() -> *mut u32 {
+    core::ptr::null_mut()
+}
+
+extern "C" fn video_wait_for_line(_line: u16) {}
+
+extern "C" fn video_get_palette(_palette_idx: u8) -> bios::FfiOption<bios::video::RGBColour> {
+    bios::FfiOption::None
+}
+
+extern "C" fn video_set_palette(_palette_idx: u8, _colour: bios::video::RGBColour) {}
+
+unsafe extern "C" fn video_set_whole_palette(
+    _start: *const bios::video::RGBColour,
+    _length: usize,
+) {
+}
+
+extern "C" fn memory_get_region(region_index: u8) -> bios::FfiOption<bios::MemoryRegion> {
+    if region_index == 0 {
+        bios::FfiOption::Some(bios::MemoryRegion {
+            start: core::ptr::addr_of_mut!(TPA) as *mut u8,
+            length: TPA_SIZE,
+            kind: bios::MemoryKind::Ram.make_ffi_safe(),
+        })
+    } else {
+        bios::FfiOption::None
+    }
+}
+
+extern "C" fn hid_get_event() -> bios::ApiResult<bios::FfiOption<bios::hid::HidEvent>> {
+    bios::ApiResult::Ok(bios::FfiOption::None)
+}
+
+extern "C" fn hid_set_leds(_leds: bios::hid::KeyboardLeds) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn i2c_bus_get_info(_bus_id: u8) -> bios::FfiOption<bios::i2c::BusInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn i2c_write_read(
+    _bus_id: u8,
+    _i2c_device_address: u8,
+    _tx: bios::FfiByteSlice,
+    _tx2: bios::FfiByteSlice,
+    _rx: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn audio_mixer_channel_get_info(
+    _audio_mixer_id: u8,
+) -> bios::FfiOption<bios::audio::MixerChannelInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn audio_mixer_channel_set_level(
+    _audio_mixer_id: u8,
+    _level: u8,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn audio_output_set_config(_config: bios::audio::Config) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_output_get_config() -> bios::ApiResult<bios::audio::Config> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+unsafe extern "C" fn audio_output_data(_samples: bios::FfiByteSlice) -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_output_get_space() -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_set_config(_config: bios::audio::Config) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_get_config() -> bios::ApiResult<bios::audio::Config> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+unsafe extern "C" fn audio_input_data(_samples: bios::FfiBuffer) -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_get_count() -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn bus_select(_peripheral_id: bios::FfiOption<u8>) {}
+
+extern "C" fn bus_get_info(_peripheral_id: u8) -> bios::FfiOption<bios::bus::PeripheralInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn bus_write_read(
+    _tx: bios::FfiByteSlice,
+    _tx2: bios::FfiByteSlice,
+    _rx: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn bus_exchange(_buffer: bios::FfiBuffer) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn bus_interrupt_status() -> u32 {
+    0
+}
+
+extern "C" fn block_dev_get_info(device_id: u8) -> bios::FfiOption<bios::block_dev::DeviceInfo> {
+    if device_id != 0 {
+        return bios::FfiOption::None;
+    }
+    with_state(|state| {
+        let disk = state.disk.as_ref();
+        bios::FfiOption::Some(bios::block_dev::DeviceInfo {
+            name: bios::FfiString::new("Disk Image"),
+            device_type: bios::block_dev::DeviceType::HardDiskDrive.make_ffi_safe(),
+            block_size: BLOCK_SIZE as u32,
+            num_blocks: disk.map_or(0, |disk| disk.num_blocks),
+            ejectable: false,
+            removable: false,
+            media_present: disk.is_some(),
+            read_only: disk.is_some_and(|disk| disk.read_only),
+        })
+    })
+}
+
+extern "C" fn block_dev_eject(_device_id: u8) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn block_write(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    data: bios::FfiByteSlice,
+) -> bios::ApiResult<()> {
+    let data = data.as_slice();
+    api_result(with_state(|state| {
+        let disk = disk_seek(state, device_id, start_block, num_blocks, data.len())?;
+        if disk.read_only {
+            return Err(bios::Error::Unimplemented);
+        }
+        disk.file
+            .write_all(data)
+            .map_err(|_| bios::Error::DeviceError)
+    }))
+}
+
+extern "C" fn block_read(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    mut data: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    let Some(buffer) = data.as_mut_slice() else {
+        return bios::ApiResult::Err(bios::Error::UnsupportedConfiguration);
+    };
+    api_result(with_state(|state| {
+        let disk = disk_seek(state, device_id, start_block, num_blocks, buffer.len())?;
+        disk.file
+            .read_exact(buffer)
+            .map_err(|_| bios::Error::DeviceError)
+    }))
+}
+
+extern "C" fn block_verify(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    data: bios::FfiByteSlice,
+) -> bios::ApiResult<()> {
+    let data = data.as_slice();
+    api_result(with_state(|state| {
+        let disk = disk_seek(state, device_id, start_block, num_blocks, data.len())?;
+        let mut on_disk = vec![0u8; data.len()];
+        disk.file
+            .read_exact(&mut on_disk)
+            .map_err(|_| bios::Error::DeviceError)?;
+        if on_disk == data {
+            Ok(())
+        } else {
+            Err(bios::Error::DeviceError)
+        }
+    }))
+}
+
+extern "C" fn power_idle() {
+    std::thread::sleep(IDLE_TIME);
+}
+
+extern "C" fn power_control(mode: bios::FfiPowerMode) -> ! {
+    // We can't start the OS again, so every mode just quits
+    match mode.make_safe() {
+        Ok(bios::PowerMode::Off) => quit(0),
+        _ => quit(2),
+    }
+}
+
+extern "C" fn compare_and_swap_bool(
+    value: &core::sync::atomic::AtomicBool,
+    old_value: bool,
+    new_value: bool,
+) -> bool {
+    value
+        .compare_exchange(
+            old_value,
+            new_value,
+            core::sync::atomic::Ordering::Acquire,
+            core::sync::atomic::Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+// End of file
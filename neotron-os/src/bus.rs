@@ -0,0 +1,201 @@
+//! # Neotron Bus Drivers
+//!
+//! Keeps track of which driver (if any) has claimed each Neotron Bus slot.
+//! Drivers are listed in [`DRIVERS`], or added by OS modules (see
+//! [`crate::module`]) with [`register`]. About once a second we ask them to
+//! look in the empty slots for cards they understand, and to check that the
+//! cards they have already claimed are still there.
+//!
+//! Nothing tells us when a card goes in or comes out, so each driver has to
+//! talk to the card to find out.
+//!
+//! Applications get at a claimed card by opening `BUSn:`, where `n` is the
+//! peripheral ID that `lsbus` shows. Reads and writes go to the driver. If
+//! the card is removed, they fail.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios, osprintln, refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Every driver we have. Add new drivers here.
+static DRIVERS: &[&Driver] = &[];
+
+/// How many cards can be claimed at once
+const MAX_CLAIMS: usize = 4;
+
+/// How many drivers OS modules can add
+const MAX_REGISTERED: usize = 4;
+
+/// Which driver has claimed which slot
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    claims: [None; MAX_CLAIMS],
+    registered: heapless::Vec::new(),
+    last_poll: None,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// A driver for a kind of Neotron Bus card.
+///
+/// Each function is given the peripheral ID of the slot the card is in.
+/// Drivers can come from OS modules, so this is all C ABI.
+#[repr(C)]
+pub struct Driver {
+    /// A short name for the driver, for `lsbus`
+    pub name: neotron_api::FfiString<'static>,
+    /// Is our kind of card in this (unclaimed) slot? If we return true, we
+    /// have claimed the slot.
+    pub probe: extern "C" fn(slot: u8) -> bool,
+    /// Is the card we claimed still in this slot?
+    pub present: extern "C" fn(slot: u8) -> bool,
+    /// The card we claimed has gone from this slot
+    pub removed: extern "C" fn(slot: u8),
+    /// An application wants to read from the card
+    pub read: extern "C" fn(slot: u8, buffer: neotron_api::FfiBuffer) -> neotron_api::Result<usize>,
+    /// An application wants to write to the card
+    pub write: extern "C" fn(slot: u8, data: neotron_api::FfiByteSlice) -> neotron_api::Result<()>,
+}
+
+// # Safety
+//
+// The name is the only pointer in a driver, and it points at text that never
+// changes.
+unsafe impl Sync for Driver {}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// A slot that a driver has claimed
+#[derive(Copy, Clone)]
+struct Claim {
+    slot: u8,
+    driver: &'static Driver,
+}
+
+/// What we know about the bus
+struct State {
+    /// The slots drivers have claimed
+    claims: [Option<Claim>; MAX_CLAIMS],
+    /// The drivers OS modules have added
+    registered: heapless::Vec<&'static Driver, MAX_REGISTERED>,
+    /// When (in ticks) we last polled the drivers
+    last_poll: Option<u64>,
+}
+
+impl State {
+    /// Which driver (if any) has claimed this slot?
+    fn driver(&self, slot: u8) -> Option<&'static Driver> {
+        self.claims
+            .iter()
+            .flatten()
+            .find(|claim| claim.slot == slot)
+            .map(|claim| claim.driver)
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Look for cards that have been inserted or removed, if it's been long
+/// enough since we last looked.
+pub fn poll() {
+    let api = API.get();
+    let now = (api.time_ticks_get)().0;
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let mut state = STATE.lock();
+    if let Some(last_poll) = state.last_poll {
+        if now.saturating_sub(last_poll) < ticks_per_second {
+            return;
+        }
+    }
+    state.last_poll = Some(now);
+
+    for entry in state.claims.iter_mut() {
+        let Some(claim) = *entry else {
+            continue;
+        };
+        if !(claim.driver.present)(claim.slot) {
+            (claim.driver.removed)(claim.slot);
+            osprintln!(
+                log: Info,
+                "{} card removed from bus slot {}",
+                claim.driver.name,
+                claim.slot
+            );
+            *entry = None;
+        }
+    }
+
+    if DRIVERS.is_empty() && state.registered.is_empty() {
+        return;
+    }
+    for slot in 0..=255u8 {
+        let bios::FfiOption::Some(info) = (api.bus_get_info)(slot) else {
+            continue;
+        };
+        if !matches!(info.kind.make_safe(), Ok(bios::bus::PeripheralKind::Slot))
+            || state.driver(slot).is_some()
+        {
+            continue;
+        }
+        let Some(free_idx) = state.claims.iter().position(|entry| entry.is_none()) else {
+            // No room for any more cards
+            return;
+        };
+        let found = DRIVERS
+            .iter()
+            .chain(state.registered.iter())
+            .find(|driver| (driver.probe)(slot))
+            .copied();
+        if let Some(driver) = found {
+            state.claims[free_idx] = Some(Claim { slot, driver });
+            osprintln!(log: Info, "{} card found in bus slot {}", driver.name, slot);
+        }
+    }
+}
+
+/// Add a driver (from an OS module). Returns false if there's no room.
+pub fn register(driver: &'static Driver) -> bool {
+    STATE.lock().registered.push(driver).is_ok()
+}
+
+/// The name of the driver that has claimed a slot, if any
+pub fn driver_name(slot: u8) -> Option<&'static str> {
+    STATE.lock().driver(slot).map(|driver| driver.name.as_str())
+}
+
+/// Read from the card in a slot
+pub fn read(slot: u8, buffer: &mut [u8]) -> Result<usize, neotron_api::Error> {
+    // Don't hold the lock while the driver works
+    let driver = STATE.lock().driver(slot);
+    match driver {
+        Some(driver) => (driver.read)(slot, neotron_api::FfiBuffer::new(buffer)).into(),
+        None => Err(neotron_api::Error::DeviceSpecific),
+    }
+}
+
+/// Write to the card in a slot
+pub fn write(slot: u8, data: &[u8]) -> Result<(), neotron_api::Error> {
+    let driver = STATE.lock().driver(slot);
+    match driver {
+        Some(driver) => (driver.write)(slot, neotron_api::FfiByteSlice::new(data)).into(),
+        None => Err(neotron_api::Error::DeviceSpecific),
+    }
+}
+
+/// Unlock the slot table, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// End of file
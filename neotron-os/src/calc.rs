@@ -0,0 +1,442 @@
+//! # Calculator
+//!
+//! Evaluates expressions like `0x2000_0000 + 16 * 4`, for the `calc`
+//! command, so nobody has to do address arithmetic in their head.
+//!
+//! Numbers are 64-bit signed integers, unless they have a decimal point, in
+//! which case they are fixed-point with 16 bits after the binary point. The
+//! operators are the ones from C, with the same precedence. Dividing two
+//! integers gives an integer, like it does in C.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::convert::TryFrom;
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many bits of a fixed-point number come after the binary point
+const FRACTION_BITS: u32 = 16;
+
+/// How deeply brackets (and unary operators) can be nested, so we don't
+/// run out of stack
+const MAX_DEPTH: usize = 32;
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The result of a calculation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A whole number
+    Integer(i64),
+    /// A fixed-point number, with `FRACTION_BITS` bits after the binary
+    /// point
+    Fixed(i64),
+}
+
+/// The ways a calculation can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Something unexpected at this byte offset
+    Syntax(usize),
+    /// The result doesn't fit in 64 bits
+    Overflow,
+    /// Tried to divide by zero
+    DivideByZero,
+    /// This operator only works on integers
+    NotInteger,
+    /// Too many nested brackets
+    TooDeep,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Syntax(pos) => write!(f, "Syntax error at column {}", pos + 1),
+            Error::Overflow => write!(f, "Overflow"),
+            Error::DivideByZero => write!(f, "Divide by zero"),
+            Error::NotInteger => write!(f, "Operator needs integers"),
+            Error::TooDeep => write!(f, "Too many brackets"),
+        }
+    }
+}
+
+impl Value {
+    /// Convert to fixed-point
+    fn to_fixed(self) -> Result<i64, Error> {
+        match self {
+            Value::Integer(n) => n.checked_mul(1 << FRACTION_BITS).ok_or(Error::Overflow),
+            Value::Fixed(n) => Ok(n),
+        }
+    }
+
+    /// Get the integer, or fail if this is fixed-point
+    fn to_integer(self) -> Result<i64, Error> {
+        match self {
+            Value::Integer(n) => Ok(n),
+            Value::Fixed(_) => Err(Error::NotInteger),
+        }
+    }
+}
+
+impl core::fmt::Display for Value {
+    /// Integers are shown in decimal and hex. Fixed-point numbers are shown
+    /// to four decimal places.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Value::Integer(n) if n < 0 => write!(f, "{} (-0x{:x})", n, n.unsigned_abs()),
+            Value::Integer(n) => write!(f, "{} (0x{:x})", n, n),
+            Value::Fixed(n) => {
+                let sign = if n < 0 { "-" } else { "" };
+                let n = n.unsigned_abs();
+                let mut whole = n >> FRACTION_BITS;
+                let fraction = n & ((1 << FRACTION_BITS) - 1);
+                // Round to the nearest ten-thousandth
+                let mut decimals =
+                    (fraction * 10_000 + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS;
+                if decimals == 10_000 {
+                    whole += 1;
+                    decimals = 0;
+                }
+                write!(f, "{}{}.{:04}", sign, whole, decimals)
+            }
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// A recursive-descent parser, which works out the answer as it goes
+struct Parser<'a> {
+    /// The expression
+    input: &'a [u8],
+    /// Where we have got to in `input`
+    pos: usize,
+    /// How deeply nested we are
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Skip spaces, then look at the next byte (if any)
+    fn peek(&mut self) -> Option<u8> {
+        while self.input.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+        self.input.get(self.pos).copied()
+    }
+
+    /// If the next thing is `token`, step over it
+    fn eat(&mut self, token: &[u8]) -> bool {
+        if self.peek().is_some() && self.input[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// `a | b`
+    fn or(&mut self) -> Result<Value, Error> {
+        let mut value = self.xor()?;
+        // Don't mistake `||` for `|`
+        while self.peek() == Some(b'|') && !self.input[self.pos..].starts_with(b"||") {
+            self.pos += 1;
+            let rhs = self.xor()?;
+            value = Value::Integer(value.to_integer()? | rhs.to_integer()?);
+        }
+        Ok(value)
+    }
+
+    /// `a ^ b`
+    fn xor(&mut self) -> Result<Value, Error> {
+        let mut value = self.and()?;
+        while self.eat(b"^") {
+            let rhs = self.and()?;
+            value = Value::Integer(value.to_integer()? ^ rhs.to_integer()?);
+        }
+        Ok(value)
+    }
+
+    /// `a & b`
+    fn and(&mut self) -> Result<Value, Error> {
+        let mut value = self.shift()?;
+        while self.peek() == Some(b'&') && !self.input[self.pos..].starts_with(b"&&") {
+            self.pos += 1;
+            let rhs = self.shift()?;
+            value = Value::Integer(value.to_integer()? & rhs.to_integer()?);
+        }
+        Ok(value)
+    }
+
+    /// `a << b` and `a >> b`
+    fn shift(&mut self) -> Result<Value, Error> {
+        let mut value = self.sum()?;
+        loop {
+            let left = if self.eat(b"<<") {
+                true
+            } else if self.eat(b">>") {
+                false
+            } else {
+                return Ok(value);
+            };
+            let lhs = value.to_integer()?;
+            let amount = u32::try_from(self.sum()?.to_integer()?)
+                .ok()
+                .filter(|n| *n < 64)
+                .ok_or(Error::Overflow)?;
+            value = Value::Integer(if left { lhs << amount } else { lhs >> amount });
+        }
+    }
+
+    /// `a + b` and `a - b`
+    fn sum(&mut self) -> Result<Value, Error> {
+        let mut value = self.product()?;
+        loop {
+            let add = if self.eat(b"+") {
+                true
+            } else if self.eat(b"-") {
+                false
+            } else {
+                return Ok(value);
+            };
+            let rhs = self.product()?;
+            let op = if add {
+                i64::checked_add
+            } else {
+                i64::checked_sub
+            };
+            value = match (value, rhs) {
+                (Value::Integer(a), Value::Integer(b)) => {
+                    Value::Integer(op(a, b).ok_or(Error::Overflow)?)
+                }
+                (a, b) => Value::Fixed(op(a.to_fixed()?, b.to_fixed()?).ok_or(Error::Overflow)?),
+            };
+        }
+    }
+
+    /// `a * b`, `a / b` and `a % b`
+    fn product(&mut self) -> Result<Value, Error> {
+        let mut value = self.unary()?;
+        loop {
+            let Some(op) = self.peek().filter(|b| b"*/%".contains(b)) else {
+                return Ok(value);
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            value = match (op, value, rhs) {
+                (_, _, Value::Integer(0)) | (_, _, Value::Fixed(0)) if op != b'*' => {
+                    return Err(Error::DivideByZero);
+                }
+                (b'*', Value::Integer(a), Value::Integer(b)) => {
+                    Value::Integer(a.checked_mul(b).ok_or(Error::Overflow)?)
+                }
+                (b'/', Value::Integer(a), Value::Integer(b)) => {
+                    Value::Integer(a.checked_div(b).ok_or(Error::Overflow)?)
+                }
+                (b'%', a, b) => {
+                    let (a, b) = (a.to_integer()?, b.to_integer()?);
+                    Value::Integer(a.checked_rem(b).ok_or(Error::Overflow)?)
+                }
+                (b'*', a, b) => {
+                    let product = i128::from(a.to_fixed()?) * i128::from(b.to_fixed()?);
+                    Value::Fixed(
+                        i64::try_from(product >> FRACTION_BITS).map_err(|_| Error::Overflow)?,
+                    )
+                }
+                (_, a, b) => {
+                    let quotient =
+                        (i128::from(a.to_fixed()?) << FRACTION_BITS) / i128::from(b.to_fixed()?);
+                    Value::Fixed(i64::try_from(quotient).map_err(|_| Error::Overflow)?)
+                }
+            };
+        }
+    }
+
+    /// `-a`, `+a`, `~a`, or just `a`
+    fn unary(&mut self) -> Result<Value, Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        let result = if self.eat(b"-") {
+            match self.unary()? {
+                Value::Integer(n) => n.checked_neg().map(Value::Integer),
+                Value::Fixed(n) => n.checked_neg().map(Value::Fixed),
+            }
+            .ok_or(Error::Overflow)
+        } else if self.eat(b"+") {
+            self.unary()
+        } else if self.eat(b"~") {
+            Ok(Value::Integer(!self.unary()?.to_integer()?))
+        } else {
+            self.primary()
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// A number, or an expression in brackets
+    fn primary(&mut self) -> Result<Value, Error> {
+        if self.eat(b"(") {
+            let value = self.or()?;
+            if !self.eat(b")") {
+                return Err(Error::Syntax(self.pos));
+            }
+            return Ok(value);
+        }
+        self.peek();
+        let start = self.pos;
+        let (radix, digits_start) = if self.input[start..].starts_with(b"0x") {
+            (16, start + 2)
+        } else if self.input[start..].starts_with(b"0b") {
+            (2, start + 2)
+        } else {
+            (10, start)
+        };
+        self.pos = digits_start;
+        let whole = self.digits(radix)?.ok_or(Error::Syntax(start))?;
+        if radix != 10 || self.input.get(self.pos) != Some(&b'.') {
+            return Ok(Value::Integer(whole));
+        }
+        // A decimal fraction, which we turn into a binary one
+        self.pos += 1;
+        let mut numerator: u64 = 0;
+        let mut denominator: u64 = 1;
+        while let Some(digit) = self
+            .input
+            .get(self.pos)
+            .and_then(|b| (*b as char).to_digit(10))
+        {
+            // More digits than this don't change the answer
+            if denominator < 1_000_000_000 {
+                numerator = numerator * 10 + u64::from(digit);
+                denominator *= 10;
+            }
+            self.pos += 1;
+        }
+        let fraction = ((numerator << FRACTION_BITS) + denominator / 2) / denominator;
+        let whole = whole
+            .checked_mul(1 << FRACTION_BITS)
+            .ok_or(Error::Overflow)?;
+        whole
+            .checked_add(fraction as i64)
+            .map(Value::Fixed)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Read some digits (and `_` separators). Returns `None` if there
+    /// weren't any digits.
+    fn digits(&mut self, radix: u32) -> Result<Option<i64>, Error> {
+        let mut value: Option<i64> = None;
+        while let Some(b) = self.input.get(self.pos) {
+            if *b == b'_' && value.is_some() {
+                self.pos += 1;
+                continue;
+            }
+            let Some(digit) = (*b as char).to_digit(radix) else {
+                break;
+            };
+            // Hex and binary numbers can use all 64 bits, so 0xFFFF_FFFF_FFFF_FFFF is -1
+            let so_far = value.unwrap_or(0);
+            let next = if radix == 10 {
+                so_far
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(i64::from(digit)))
+            } else {
+                let shift = radix.trailing_zeros();
+                if (so_far as u64).leading_zeros() < shift {
+                    None
+                } else {
+                    Some((((so_far as u64) << shift) | u64::from(digit)) as i64)
+                }
+            };
+            value = Some(next.ok_or(Error::Overflow)?);
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Work out the value of an expression
+pub fn evaluate(expression: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        input: expression.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.or()?;
+    match parser.peek() {
+        Some(_) => Err(Error::Syntax(parser.pos)),
+        None => Ok(value),
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(expression: &str) -> i64 {
+        match evaluate(expression) {
+            Ok(Value::Integer(n)) => n,
+            other => panic!("{} gave {:?}", expression, other),
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(int("1 + 2 * 3"), 7);
+        assert_eq!(int("(1 + 2) * 3"), 9);
+        assert_eq!(int("0x2000_0000 + 0x100"), 0x2000_0100);
+        assert_eq!(int("0b1010 | 0x5"), 15);
+        assert_eq!(int("7 / 2"), 3);
+        assert_eq!(int("-7 % 3"), -1);
+        assert_eq!(int("1 << 4 + 1"), 32);
+        assert_eq!(int("0xff & ~0x0f ^ 0x01"), 0xf1);
+        assert_eq!(int("0xFFFF_FFFF_FFFF_FFFF"), -1);
+        assert_eq!(int("--5"), 5);
+    }
+
+    #[test]
+    fn fixed_point() {
+        assert_eq!(evaluate("1.5 * 3"), Ok(Value::Fixed(0x4_8000)));
+        assert_eq!(evaluate("7.0 / 2"), Ok(Value::Fixed(0x3_8000)));
+        assert_eq!(format!("{}", evaluate("1 / 3.0").unwrap()), "0.3333");
+        assert_eq!(format!("{}", evaluate("-2.25").unwrap()), "-2.2500");
+        assert_eq!(evaluate("1.5 & 1"), Err(Error::NotInteger));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", Value::Integer(255)), "255 (0xff)");
+        assert_eq!(format!("{}", Value::Integer(-16)), "-16 (-0x10)");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(evaluate("1 +"), Err(Error::Syntax(3)));
+        assert_eq!(evaluate("(1 + 2"), Err(Error::Syntax(6)));
+        assert_eq!(evaluate("1 2"), Err(Error::Syntax(2)));
+        assert_eq!(evaluate("1 / 0"), Err(Error::DivideByZero));
+        assert_eq!(evaluate("1 % 0"), Err(Error::DivideByZero));
+        assert_eq!(evaluate("9223372036854775807 + 1"), Err(Error::Overflow));
+        assert_eq!(evaluate("1 << 64"), Err(Error::Overflow));
+        assert_eq!(evaluate(&"(".repeat(100)), Err(Error::TooDeep));
+    }
+}
+
+// End of file
@@ -0,0 +1,23 @@
+//! BASIC related commands for Neotron OS
+
+use crate::Ctx;
+
+pub static BASIC_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: basic,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "file",
+            help: Some("A BASIC program to load and run"),
+        }],
+    },
+    command: "basic",
+    help: Some("Start the BASIC interpreter"),
+};
+
+/// Called when the "basic" command is executed.
+fn basic(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    // The program goes in the TPA, like any other program would
+    crate::basic::run(ctx.tpa.as_slice_u8(), args.first().copied());
+}
+
+// End of file
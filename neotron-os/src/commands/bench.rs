@@ -0,0 +1,258 @@
+//! Benchmark commands for Neotron OS
+
+use crate::{bios, osprint, osprintln, stats, Ctx, API};
+
+pub static BENCH_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: bench,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "test",
+            help: Some("Which test to run (mem, disk, console or audio)"),
+        }],
+    },
+    command: "bench",
+    help: Some("Measure how fast the system is"),
+};
+
+/// A test, which prints what it measured and gives the points it scored
+type Test = fn(&mut Ctx) -> Option<u64>;
+
+/// The tests `bench` can run, and how to run them
+const TESTS: [(&str, Test); 4] = [
+    ("mem", bench_mem),
+    ("disk", bench_disk),
+    ("console", bench_console),
+    ("audio", bench_audio),
+];
+
+/// How many bytes at a time the memory test copies
+const MEM_CHUNK_LEN: usize = 16 * 1024;
+
+/// A memory copy rate (in bytes per second) that scores 100
+const MEM_REFERENCE: u64 = 10 * 1024 * 1024;
+
+/// How many blocks the disk test reads
+const DISK_BLOCKS: u64 = 1024;
+
+/// How many blocks the disk test reads in one BIOS call
+const DISK_BLOCKS_PER_READ: u8 = 8;
+
+/// A disk read rate (in bytes per second) that scores 100
+const DISK_REFERENCE: u64 = 512 * 1024;
+
+/// The size of a disk block
+const BLOCK_SIZE: usize = 512;
+
+/// What the console test writes, over and over
+const CONSOLE_LINE: &str =
+    "The quick brown fox jumps over the lazy dog. 0123456789 !\"#$%&'()*+,-./:;<=>?@\n";
+
+/// A console rate (in characters per second) that scores 100
+const CONSOLE_REFERENCE: u64 = 20_000;
+
+/// How many bytes of silence the audio test gives the BIOS at a time
+const AUDIO_CHUNK_LEN: usize = 1024;
+
+/// Called when the "bench" command is executed.
+///
+/// Each test scores 100 if it runs at its reference rate, and the score is
+/// the average of the tests that ran. The reference rates never change, so
+/// scores can be compared between OS releases. The audio test scores 100 if
+/// the BIOS takes samples exactly as fast as it plays them.
+///
+/// The memory and disk tests use the Transient Program Area, so anything
+/// loaded there is lost.
+fn bench(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let which = menu::argument_finder(item, args, "test").ok().flatten();
+    if let Some(which) = which {
+        if !TESTS.iter().any(|(name, _)| *name == which) {
+            osprintln!("Unknown test {:?}", which);
+            return;
+        }
+    }
+    // Some of these take a few seconds without polling the keyboard
+    let _pause = crate::watchdog::pause();
+    let mut total = 0;
+    let mut count = 0;
+    for (name, test) in TESTS.iter() {
+        if which.is_some_and(|which| which != *name) {
+            continue;
+        }
+        if let Some(points) = test(ctx) {
+            total += points;
+            count += 1;
+        }
+    }
+    match total.checked_div(count) {
+        Some(score) => {
+            osprintln!("Score: {}", score);
+        }
+        None => {
+            osprintln!("No tests could run");
+        }
+    }
+}
+
+/// Copy memory around the Transient Program Area for a second.
+fn bench_mem(ctx: &mut Ctx) -> Option<u64> {
+    let api = API.get();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let tpa = ctx.tpa.as_slice_u8();
+    let chunk_len = MEM_CHUNK_LEN.min(tpa.len() / 2);
+    if chunk_len == 0 {
+        osprintln!("Memory : no room in the TPA");
+        return None;
+    }
+    let (src, dest) = tpa.split_at_mut(tpa.len() / 2);
+    let num_chunks = src.len() / chunk_len;
+    let mut bytes = 0u64;
+    let mut idx = 0;
+    let start = (api.time_ticks_get)().0;
+    let mut now = start;
+    while now.saturating_sub(start) < ticks_per_second {
+        let range = idx * chunk_len..(idx + 1) * chunk_len;
+        dest[range.clone()].copy_from_slice(&src[range]);
+        bytes += chunk_len as u64;
+        idx = (idx + 1) % num_chunks;
+        now = (api.time_ticks_get)().0;
+    }
+    let rate = per_second(bytes, now - start);
+    osprintln!(
+        "Memory : {}.{:02} MiB/s copied",
+        rate / (1024 * 1024),
+        (rate % (1024 * 1024)) * 100 / (1024 * 1024)
+    );
+    Some(points(rate, MEM_REFERENCE))
+}
+
+/// Read some blocks from the first block device.
+fn bench_disk(ctx: &mut Ctx) -> Option<u64> {
+    let api = API.get();
+    let device_idx = 0;
+    let bios::FfiOption::Some(info) = (api.block_dev_get_info)(device_idx) else {
+        osprintln!("Disk   : no block device");
+        return None;
+    };
+    if !info.media_present || info.block_size as usize != BLOCK_SIZE {
+        osprintln!("Disk   : no disk");
+        return None;
+    }
+    let buffer_len = BLOCK_SIZE * usize::from(DISK_BLOCKS_PER_READ);
+    let Some(buffer) = ctx.tpa.as_slice_u8().get_mut(0..buffer_len) else {
+        osprintln!("Disk   : no room in the TPA");
+        return None;
+    };
+    let num_blocks = DISK_BLOCKS.min(info.num_blocks);
+    let mut block_idx = 0;
+    let start = (api.time_ticks_get)().0;
+    while block_idx < num_blocks {
+        let count = (num_blocks - block_idx).min(u64::from(DISK_BLOCKS_PER_READ));
+        if let bios::ApiResult::Err(e) = (api.block_read)(
+            device_idx,
+            bios::block_dev::BlockIdx(block_idx),
+            count as u8,
+            bios::FfiBuffer::new(&mut buffer[0..count as usize * BLOCK_SIZE]),
+        ) {
+            osprintln!("Disk   : failed to read block {}: {:?}", block_idx, e);
+            return None;
+        }
+        block_idx += count;
+    }
+    let elapsed = (api.time_ticks_get)().0 - start;
+    let rate = per_second(num_blocks * BLOCK_SIZE as u64, elapsed);
+    osprintln!(
+        "Disk   : {} KiB/s read ({} blocks)",
+        rate / 1024,
+        num_blocks
+    );
+    Some(points(rate, DISK_REFERENCE))
+}
+
+/// Write text to the console for a second.
+fn bench_console(_ctx: &mut Ctx) -> Option<u64> {
+    let api = API.get();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let mut chars = 0u64;
+    let start = (api.time_ticks_get)().0;
+    let mut now = start;
+    while now.saturating_sub(start) < ticks_per_second {
+        osprint!("{}", CONSOLE_LINE);
+        chars += CONSOLE_LINE.len() as u64;
+        now = (api.time_ticks_get)().0;
+    }
+    let rate = per_second(chars, now - start);
+    osprintln!("Console: {} chars/s written", rate);
+    Some(points(rate, CONSOLE_REFERENCE))
+}
+
+/// Fill the audio buffer, then keep it full for a second.
+fn bench_audio(_ctx: &mut Ctx) -> Option<u64> {
+    let api = API.get();
+    let bios::FfiResult::Ok(config) = (api.audio_output_get_config)() else {
+        osprintln!("Audio  : no audio output");
+        return None;
+    };
+    // Eight-bit samples are unsigned, so their silence is in the middle
+    let (bytes_per_frame, silence) = match config.sample_format.make_safe() {
+        Ok(bios::audio::SampleFormat::EightBitMono) => (1, 0x80),
+        Ok(bios::audio::SampleFormat::EightBitStereo) => (2, 0x80),
+        Ok(bios::audio::SampleFormat::SixteenBitMono) => (2, 0x00),
+        Ok(bios::audio::SampleFormat::SixteenBitStereo) => (4, 0x00),
+        _ => {
+            osprintln!("Audio  : unknown sample format");
+            return None;
+        }
+    };
+    let expected = u64::from(config.sample_rate_hz) * bytes_per_frame;
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let chunk = [silence; AUDIO_CHUNK_LEN];
+
+    // Fill the buffer, timing how long that takes. Give up after a second,
+    // in case the BIOS plays it as fast as we give it.
+    let start = (api.time_ticks_get)().0;
+    let mut filled = 0u64;
+    while (api.time_ticks_get)().0.saturating_sub(start) < ticks_per_second {
+        match stats::audio_output(&chunk) {
+            bios::FfiResult::Ok(0) => break,
+            bios::FfiResult::Ok(n) => filled += n as u64,
+            bios::FfiResult::Err(e) => {
+                osprintln!("Audio  : failed to play: {:?}", e);
+                return None;
+            }
+        }
+    }
+    let fill_ticks = (api.time_ticks_get)().0 - start;
+
+    // Now see how fast it takes more
+    let start = (api.time_ticks_get)().0;
+    let mut now = start;
+    let mut bytes = 0u64;
+    while now.saturating_sub(start) < ticks_per_second {
+        if let bios::FfiResult::Ok(n) = stats::audio_output(&chunk) {
+            bytes += n as u64;
+        }
+        now = (api.time_ticks_get)().0;
+    }
+    let rate = per_second(bytes, now - start);
+    osprintln!(
+        "Audio  : {} bytes buffered in {} ms, then {} bytes/s taken ({} expected)",
+        filled,
+        fill_ticks * 1000 / ticks_per_second,
+        rate,
+        expected
+    );
+    Some(points(rate, expected))
+}
+
+/// Turn a count over some ticks into a count per second
+fn per_second(count: u64, ticks: u64) -> u64 {
+    let ticks_per_second = (API.get().time_ticks_per_second)().0;
+    count * ticks_per_second / ticks.max(1)
+}
+
+/// Score a rate, where the reference rate scores 100
+fn points(rate: u64, reference: u64) -> u64 {
+    rate * 100 / reference.max(1)
+}
+
+// End of file
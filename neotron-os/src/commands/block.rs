@@ -1,6 +1,8 @@
 //! Block Device related commands for Neotron OS
 
-use super::{parse_u64, parse_u8};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::{hex_digit, parse_u64, parse_u8, parse_usize, wait_for_key};
 use crate::{bios, osprint, osprintln, Ctx, API};
 
 pub static READ_ITEM: menu::Item<Ctx> = menu::Item {
@@ -21,6 +23,56 @@ pub static READ_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("Display one disk block, as hex"),
 };
 
+pub static WRITE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: write_block,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "device_idx",
+                help: Some("The block device ID to write to"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "block_idx",
+                help: Some("The block to modify, 0..num_blocks"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "offset",
+                help: Some("The offset within the block, 0..512"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "hex_bytes",
+                help: Some("The bytes to write, as hex (e.g. 55AA)"),
+            },
+        ],
+    },
+    command: "writeblk",
+    help: Some("Patch bytes in one disk block"),
+};
+
+pub static EDIT_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: edit_block,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "device_idx",
+                help: Some("The block device ID to edit"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "block_idx",
+                help: Some("The block to edit, 0..num_blocks"),
+            },
+        ],
+    },
+    command: "blkedit",
+    help: Some("Interactively edit one disk block"),
+};
+
+/// The size of a disk block, in bytes
+const BLOCK_SIZE: usize = 512;
+
+/// How many bytes we show on each line of a block dump
+const BYTES_PER_LINE: usize = 32;
+
 /// Called when the "read_block" command is executed.
 fn read_block(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
     let api = API.get();
@@ -33,7 +85,7 @@ fn read_block(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _
         return;
     };
     osprintln!("Reading block {}:", block_idx);
-    let mut buffer = [0u8; 512];
+    let mut buffer = [0u8; BLOCK_SIZE];
     match (api.block_read)(
         device_idx,
         bios::block_dev::BlockIdx(block_idx),
@@ -42,18 +94,205 @@ fn read_block(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _
     ) {
         bios::ApiResult::Ok(_) => {
             // Carry on
-            let mut count = 0;
-            for chunk in buffer.chunks(32) {
-                osprint!("{:03x}: ", count);
-                for b in chunk {
-                    osprint!("{:02x}", *b);
+            print_block(&buffer, None);
+        }
+        bios::ApiResult::Err(e) => {
+            osprintln!("Failed to read: {:?}", e);
+        }
+    }
+}
+
+/// Called when the "writeblk" command is executed.
+fn write_block(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let api = API.get();
+    let Ok(device_idx) = parse_u8(args[0]) else {
+        osprintln!("Couldn't parse {:?}", args[0]);
+        return;
+    };
+    let Ok(block_idx) = parse_u64(args[1]) else {
+        osprintln!("Couldn't parse {:?}", args[1]);
+        return;
+    };
+    let Ok(offset) = parse_usize(args[2]) else {
+        osprintln!("Couldn't parse {:?}", args[2]);
+        return;
+    };
+
+    let mut patch: heapless::Vec<u8, BLOCK_SIZE> = heapless::Vec::new();
+    for hex_pair in args[3].as_bytes().chunks(2) {
+        let (Some(top), Some(bottom)) = (
+            hex_pair.first().cloned().and_then(hex_digit),
+            hex_pair.get(1).cloned().and_then(hex_digit),
+        ) else {
+            osprintln!("Bad hex.");
+            return;
+        };
+        let Ok(_) = patch.push(top << 4 | bottom) else {
+            osprintln!("Too much hex.");
+            return;
+        };
+    }
+
+    let Some(end) = offset
+        .checked_add(patch.len())
+        .filter(|end| *end <= BLOCK_SIZE)
+    else {
+        osprintln!("Patch does not fit in block.");
+        return;
+    };
+
+    // Read the block first, so we only change the bytes we were given
+    let mut buffer = [0u8; BLOCK_SIZE];
+    if let bios::ApiResult::Err(e) = (api.block_read)(
+        device_idx,
+        bios::block_dev::BlockIdx(block_idx),
+        1,
+        bios::FfiBuffer::new(&mut buffer),
+    ) {
+        osprintln!("Failed to read: {:?}", e);
+        return;
+    }
+    buffer[offset..end].copy_from_slice(&patch);
+
+    if !confirm_write(device_idx, block_idx) {
+        osprintln!("Not written.");
+        return;
+    }
+    commit_block(device_idx, block_idx, &buffer);
+}
+
+/// Called when the "blkedit" command is executed.
+fn edit_block(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let api = API.get();
+    let Ok(device_idx) = parse_u8(args[0]) else {
+        osprintln!("Couldn't parse {:?}", args[0]);
+        return;
+    };
+    let Ok(block_idx) = parse_u64(args[1]) else {
+        osprintln!("Couldn't parse {:?}", args[1]);
+        return;
+    };
+
+    let mut buffer = [0u8; BLOCK_SIZE];
+    if let bios::ApiResult::Err(e) = (api.block_read)(
+        device_idx,
+        bios::block_dev::BlockIdx(block_idx),
+        1,
+        bios::FfiBuffer::new(&mut buffer),
+    ) {
+        osprintln!("Failed to read: {:?}", e);
+        return;
+    }
+
+    // The nibble we are editing. Two per byte, high nibble first.
+    let mut cursor: usize = 0;
+    let mut modified = false;
+    loop {
+        // Reset SGR, go home, clear screen
+        osprint!("\u{001b}[0m\u{001b}[1;1H\u{001b}[2J");
+        osprintln!(
+            "Device {}, block {}{}",
+            device_idx,
+            block_idx,
+            if modified { " (modified)" } else { "" }
+        );
+        osprintln!("Arrows move, 0-9/A-F edit, Ctrl-W writes, Ctrl-X quits");
+        print_block(&buffer, Some(cursor / 2));
+
+        let key = wait_for_key();
+        match key {
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => {
+                cursor = cursor.saturating_sub(1);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                cursor = (cursor + 1).min((BLOCK_SIZE * 2) - 1);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                cursor = cursor.saturating_sub(BYTES_PER_LINE * 2);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => {
+                cursor = (cursor + (BYTES_PER_LINE * 2)).min((BLOCK_SIZE * 2) - 1);
+            }
+            DecodedKey::Unicode('\u{0017}') => {
+                // Ctrl-W
+                if confirm_write(device_idx, block_idx) {
+                    commit_block(device_idx, block_idx, &buffer);
+                    modified = false;
+                }
+                osprintln!("Press any key...");
+                let _ = wait_for_key();
+            }
+            DecodedKey::Unicode('\u{0018}') => {
+                // Ctrl-X
+                break;
+            }
+            DecodedKey::Unicode(ch) if ch.is_ascii_hexdigit() => {
+                // We checked it was a hex digit, so this can't fail
+                let nibble = ch.to_digit(16).unwrap_or(0) as u8;
+                let byte = &mut buffer[cursor / 2];
+                if cursor & 1 == 0 {
+                    *byte = (*byte & 0x0F) | (nibble << 4);
+                } else {
+                    *byte = (*byte & 0xF0) | nibble;
                 }
-                count += chunk.len();
-                osprintln!();
+                modified = true;
+                cursor = (cursor + 1).min((BLOCK_SIZE * 2) - 1);
+            }
+            _ => {
+                // Ignore
+            }
+        }
+    }
+
+    if modified {
+        osprintln!("Changes discarded.");
+    }
+}
+
+/// Print a block as hex, optionally highlighting one byte.
+fn print_block(buffer: &[u8], highlight: Option<usize>) {
+    for (line_idx, chunk) in buffer.chunks(BYTES_PER_LINE).enumerate() {
+        let line_start = line_idx * BYTES_PER_LINE;
+        osprint!("{:03x}: ", line_start);
+        for (idx, b) in chunk.iter().enumerate() {
+            if Some(line_start + idx) == highlight {
+                // Reverse video
+                osprint!("\u{001b}[7m{:02x}\u{001b}[0m", *b);
+            } else {
+                osprint!("{:02x}", *b);
             }
         }
+        osprintln!();
+    }
+}
+
+/// Ask the user whether they really want to write to the disk
+fn confirm_write(device_idx: u8, block_idx: u64) -> bool {
+    osprint!(
+        "Write block {} on device {}? This cannot be undone. (y/N) ",
+        block_idx,
+        device_idx
+    );
+    let key = wait_for_key();
+    let confirmed = matches!(key, DecodedKey::Unicode('y' | 'Y'));
+    osprintln!("{}", if confirmed { "y" } else { "n" });
+    confirmed
+}
+
+/// Write a block to disk, reporting the result
+fn commit_block(device_idx: u8, block_idx: u64, buffer: &[u8]) {
+    let api = API.get();
+    match (api.block_write)(
+        device_idx,
+        bios::block_dev::BlockIdx(block_idx),
+        1,
+        bios::FfiByteSlice::new(buffer),
+    ) {
+        bios::ApiResult::Ok(_) => {
+            osprintln!("Written OK.");
+        }
         bios::ApiResult::Err(e) => {
-            osprintln!("Failed to read: {:?}", e);
+            osprintln!("Failed to write: {:?}", e);
         }
     }
 }
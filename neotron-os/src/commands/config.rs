@@ -1,6 +1,28 @@
 //! Configuration related commands for Neotron OS
 
-use crate::{bios, config, osprintln, Ctx};
+use crate::{bios, config, osprintln, vgaconsole::StatusLine, Ctx, FILESYSTEM};
+
+pub static CONSOLE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: console,
+        parameters: &[
+            menu::Parameter::Optional {
+                parameter_name: "command",
+                help: Some("Which operation to perform (try help)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "stream",
+                help: Some("stdin or stdout"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "consoles",
+                help: Some("vga, serial or vga+serial"),
+            },
+        ],
+    },
+    command: "console",
+    help: Some("Choose which consoles stdin and stdout use"),
+};
 
 pub static COMMAND_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
@@ -14,6 +36,10 @@ pub static COMMAND_ITEM: menu::Item<Ctx> = menu::Item {
                 parameter_name: "value",
                 help: Some("new value for the setting"),
             },
+            menu::Parameter::Optional {
+                parameter_name: "value2",
+                help: Some("second value for the setting, if needed"),
+            },
         ],
     },
     command: "config",
@@ -41,6 +67,32 @@ fn command(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx:
                 osprintln!("Error saving: {}", e);
             }
         },
+        "export" => {
+            let Some(filename) = args.get(1) else {
+                osprintln!("Give a filename as argument");
+                return;
+            };
+            match export(ctx, filename) {
+                Ok(()) => {}
+                Err(e) => {
+                    osprintln!("Error exporting: {:?}", e);
+                }
+            }
+        }
+        "import" => {
+            let Some(filename) = args.get(1) else {
+                osprintln!("Give a filename as argument");
+                return;
+            };
+            match import(ctx, filename) {
+                Ok(()) => {
+                    osprintln!("Imported {}. Use config save to keep it.", filename);
+                }
+                Err(e) => {
+                    osprintln!("Error importing: {:?}", e);
+                }
+            }
+        }
         "vga" => match args.get(1).cloned() {
             Some("off") => {
                 ctx.config.set_vga_console(None);
@@ -76,6 +128,321 @@ fn command(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx:
                 osprintln!("Give off or an integer as argument");
             }
         },
+        "status" => {
+            let status_line = match args.get(1).cloned() {
+                Some("top") => Some(StatusLine::Top),
+                Some("bottom") => Some(StatusLine::Bottom),
+                Some("off") => None,
+                _ => {
+                    osprintln!("Give top, bottom or off as argument");
+                    return;
+                }
+            };
+            ctx.config.set_status_line(status_line);
+            crate::vconsole::release();
+            if let Some(console) = crate::VGA_CONSOLE.lock().as_mut() {
+                console.set_status_line(status_line);
+            }
+            crate::vconsole::setup();
+            crate::statusbar::refresh(true);
+            osprintln!("Status line now {:?}", status_line);
+        }
+        "repeat" => match (args.get(1).cloned(), args.get(2).cloned()) {
+            (Some("off"), None) => {
+                ctx.config.set_key_repeat(None);
+                crate::STD_INPUT.lock().set_key_repeat(None);
+                osprintln!("Key repeat now off");
+            }
+            (Some(delay_str), Some(rate_str)) => {
+                let (Ok(delay_ms), Ok(rate_hz)) =
+                    (delay_str.parse::<u16>(), rate_str.parse::<u8>())
+                else {
+                    osprintln!("Give delay in ms and rate in Hz as arguments");
+                    return;
+                };
+                if rate_hz == 0 {
+                    osprintln!("Rate must be at least 1 Hz");
+                    return;
+                }
+                let key_repeat = config::KeyRepeat { delay_ms, rate_hz };
+                ctx.config.set_key_repeat(Some(key_repeat));
+                crate::STD_INPUT.lock().set_key_repeat(Some(key_repeat));
+                osprintln!("Key repeat now {} ms, {} Hz", delay_ms, rate_hz);
+            }
+            _ => {
+                osprintln!("Give off, or delay in ms and rate in Hz as arguments");
+            }
+        },
+        "tz" => {
+            let Some(minutes) = args
+                .get(1)
+                .and_then(|s| s.strip_prefix('+').unwrap_or(s).parse::<i16>().ok())
+                .filter(|m| (-14 * 60..=14 * 60).contains(m))
+            else {
+                osprintln!("Give minutes east of UTC (e.g. 60 or -300) as argument");
+                return;
+            };
+            ctx.config.set_tz_offset(minutes);
+            crate::API.set_tz_offset(minutes);
+            osprintln!("Timezone now UTC{}", config::TzOffset(minutes));
+        }
+        "watchdog" => {
+            if !crate::watchdog::is_supported() {
+                osprintln!("This BIOS has no watchdog");
+                return;
+            }
+            let timeout = match args.get(1).cloned() {
+                Some("off") => None,
+                Some(secs_str) => match secs_str.parse::<u16>() {
+                    Ok(secs) if secs > 0 => Some(secs),
+                    _ => {
+                        osprintln!("Give off or a number of seconds as argument");
+                        return;
+                    }
+                },
+                None => {
+                    osprintln!("Give off or a number of seconds as argument");
+                    return;
+                }
+            };
+            ctx.config.set_watchdog(timeout);
+            crate::watchdog::configure(timeout);
+            match timeout {
+                Some(secs) => {
+                    osprintln!("Watchdog now {} s", secs);
+                }
+                None => {
+                    osprintln!("Watchdog now off");
+                }
+            }
+        }
+        "blank" => {
+            let timeout = match args.get(1).cloned() {
+                Some("off") => None,
+                Some(mins_str) => match mins_str.parse::<u8>() {
+                    Ok(mins) if mins > 0 => Some(mins),
+                    _ => {
+                        osprintln!("Give off or a number of minutes as argument");
+                        return;
+                    }
+                },
+                None => {
+                    osprintln!("Give off or a number of minutes as argument");
+                    return;
+                }
+            };
+            ctx.config.set_screen_blank(timeout);
+            crate::power::configure_blank(timeout);
+            match timeout {
+                Some(mins) => {
+                    osprintln!("Screen blank now {} min", mins);
+                }
+                None => {
+                    osprintln!("Screen blank now off");
+                }
+            }
+        }
+        "verify" => {
+            let Some(check) = args.get(1).and_then(|s| config::ProgramCheck::parse(s)) else {
+                osprintln!("Give strict or lenient as argument");
+                return;
+            };
+            ctx.config.set_program_check(check);
+            crate::program::configure_check(check);
+            osprintln!("Program checksums now {}", check);
+        }
+        "modifiers" => {
+            let show = match args.get(1).cloned() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    osprintln!("Give on or off as argument");
+                    return;
+                }
+            };
+            ctx.config.set_show_modifiers(show);
+            crate::statusbar::configure_modifiers(show);
+            osprintln!("Modifier indicator now {}", if show { "on" } else { "off" });
+        }
+        "scrub" => {
+            let scrub = match args.get(1).cloned() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    osprintln!("Give on or off as argument");
+                    return;
+                }
+            };
+            ctx.config.set_scrub_tpa(scrub);
+            crate::program::configure_scrub(scrub);
+            osprintln!("Scrubbing now {}", if scrub { "on" } else { "off" });
+        }
+        "history" => {
+            let save = match args.get(1).cloned() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    osprintln!("Give on or off as argument");
+                    return;
+                }
+            };
+            ctx.config.set_save_history(save);
+            osprintln!("Saving history now {}", if save { "on" } else { "off" });
+        }
+        "sensors" => {
+            let limits = match (
+                args.get(1).cloned(),
+                args.get(2).cloned(),
+                args.get(3).cloned(),
+            ) {
+                (Some("off"), None, None) => None,
+                (Some(min_str), Some(max_str), Some(temp_str)) => {
+                    let (Ok(min_mv), Ok(max_mv), Ok(max_temp_c)) = (
+                        min_str.parse::<u16>(),
+                        max_str.parse::<u16>(),
+                        temp_str.parse::<i16>(),
+                    ) else {
+                        osprintln!(
+                            "Give the lowest and highest mV, and the hottest C as arguments"
+                        );
+                        return;
+                    };
+                    if min_mv > max_mv {
+                        osprintln!("The lowest voltage must be below the highest");
+                        return;
+                    }
+                    Some(config::SensorLimits {
+                        min_mv,
+                        max_mv,
+                        max_temp_c,
+                    })
+                }
+                _ => {
+                    osprintln!(
+                        "Give off, or the lowest and highest mV and the hottest C as arguments"
+                    );
+                    return;
+                }
+            };
+            ctx.config.set_sensor_limits(limits);
+            crate::sensors::configure(limits);
+            match limits {
+                Some(limits) => {
+                    osprintln!(
+                        "Sensors now warn below {} mV, above {} mV or above {} C",
+                        limits.min_mv,
+                        limits.max_mv,
+                        limits.max_temp_c
+                    );
+                }
+                None => {
+                    osprintln!("Sensors now never warn");
+                }
+            }
+        }
+        "ramdisk" => {
+            let kib = match args.get(1).cloned() {
+                Some("off") => 0,
+                Some(kib_str) => match kib_str.parse::<u16>() {
+                    Ok(kib)
+                        if (crate::ramdisk::MIN_KIB..=crate::ramdisk::MAX_KIB).contains(&kib) =>
+                    {
+                        kib
+                    }
+                    _ => {
+                        osprintln!(
+                            "Give off or a size between {} and {} KiB as argument",
+                            crate::ramdisk::MIN_KIB,
+                            crate::ramdisk::MAX_KIB
+                        );
+                        return;
+                    }
+                },
+                None => {
+                    osprintln!("Give off or a size in KiB as argument");
+                    return;
+                }
+            };
+            ctx.config.set_ramdisk_kib(kib);
+            if kib == 0 {
+                osprintln!("No RAM disk at start-up");
+            } else {
+                osprintln!("RAM disk of {} KiB at start-up", kib);
+            }
+        }
+        "printer" => {
+            let printer = match (args.get(1).cloned(), args.get(2).cloned()) {
+                (Some("off"), None) => None,
+                (Some(port_str), Some(baud_str)) => {
+                    let (Ok(port), Ok(baud)) = (port_str.parse::<u8>(), baud_str.parse::<u32>())
+                    else {
+                        osprintln!("Give the UART number and baud rate as arguments");
+                        return;
+                    };
+                    let charset = ctx
+                        .config
+                        .get_printer()
+                        .map_or(config::PrinterCharset::Cp437, |p| p.charset);
+                    Some(config::Printer {
+                        port,
+                        baud,
+                        charset,
+                    })
+                }
+                (Some(charset_str), None) => {
+                    let Some(charset) = config::PrinterCharset::parse(charset_str) else {
+                        osprintln!("Give cp850, cp437 or ascii as the character set");
+                        return;
+                    };
+                    let Some(printer) = ctx.config.get_printer() else {
+                        osprintln!("Set up the printer first");
+                        return;
+                    };
+                    Some(config::Printer { charset, ..printer })
+                }
+                _ => {
+                    osprintln!("Give off, a character set, or a UART and baud rate as arguments");
+                    return;
+                }
+            };
+            ctx.config.set_printer(printer);
+            crate::spool::configure(printer);
+            match printer {
+                Some(printer) => {
+                    osprintln!(
+                        "Printer now on UART {} at {} bps ({})",
+                        printer.port,
+                        printer.baud,
+                        printer.charset
+                    );
+                }
+                None => {
+                    osprintln!("Printer now off");
+                }
+            }
+        }
+        "saver" => {
+            let Some(saver) = args.get(1).and_then(|s| config::ScreenSaver::parse(s)) else {
+                osprintln!("Give blank or flames as argument");
+                return;
+            };
+            ctx.config.set_screen_saver(saver);
+            crate::power::configure_saver(saver);
+            osprintln!("Screensaver now {}", saver);
+        }
+        "keymap" => {
+            let Some(keymap) = args.get(1).and_then(|s| config::Keymap::parse(s)) else {
+                osprintln!("Give uk, us, de, fr, es, dvorak, colemak or bios as argument");
+                return;
+            };
+            ctx.config.set_keymap(keymap);
+            {
+                let mut std_input = crate::STD_INPUT.lock();
+                std_input.set_keymap(keymap);
+                std_input.update_leds();
+            }
+            osprintln!("Keymap now {}", keymap);
+        }
         "print" => {
             match ctx.config.get_vga_console() {
                 Some(m) => {
@@ -93,16 +460,230 @@ fn command(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx:
                     osprintln!("Serial: {} bps", config.data_rate_bps);
                 }
             }
+            match ctx.config.get_status_line() {
+                Some(StatusLine::Top) => {
+                    osprintln!("Status: top");
+                }
+                Some(StatusLine::Bottom) => {
+                    osprintln!("Status: bottom");
+                }
+                None => {
+                    osprintln!("Status: off");
+                }
+            }
+            osprintln!("Stdin : {}", ctx.config.get_stdin_route());
+            osprintln!("Stdout: {}", ctx.config.get_stdout_route());
+            osprintln!(
+                "TZ    : UTC{}",
+                config::TzOffset(ctx.config.get_tz_offset())
+            );
+            match ctx.config.get_watchdog() {
+                _ if !crate::watchdog::is_supported() => {
+                    osprintln!("Wdog  : unsupported");
+                }
+                Some(secs) => {
+                    osprintln!("Wdog  : {} s", secs);
+                }
+                None => {
+                    osprintln!("Wdog  : off");
+                }
+            }
+            match ctx.config.get_screen_blank() {
+                Some(mins) => {
+                    osprintln!("Blank : {} min", mins);
+                }
+                None => {
+                    osprintln!("Blank : off");
+                }
+            }
+            osprintln!("Saver : {}", ctx.config.get_screen_saver());
+            osprintln!("Verify: {}", ctx.config.get_program_check());
+            osprintln!("Keymap: {}", ctx.config.get_keymap());
+            osprintln!(
+                "Mods  : {}",
+                if ctx.config.get_show_modifiers() {
+                    "on"
+                } else {
+                    "off"
+                }
+            );
+            osprintln!(
+                "Scrub : {}",
+                if ctx.config.get_scrub_tpa() {
+                    "on"
+                } else {
+                    "off"
+                }
+            );
+            osprintln!(
+                "Hist  : {}",
+                if ctx.config.get_save_history() {
+                    "saved"
+                } else {
+                    "not saved"
+                }
+            );
+            match ctx.config.get_printer() {
+                Some(printer) => {
+                    osprintln!(
+                        "Print : UART {}, {} bps, {}",
+                        printer.port,
+                        printer.baud,
+                        printer.charset
+                    );
+                }
+                None => {
+                    osprintln!("Print : off");
+                }
+            }
+            match ctx.config.get_ramdisk_kib() {
+                0 => {
+                    osprintln!("RAM   : off");
+                }
+                kib => {
+                    osprintln!("RAM   : {} KiB", kib);
+                }
+            }
+            match ctx.config.get_sensor_limits() {
+                Some(limits) => {
+                    osprintln!(
+                        "Sensor: {}-{} mV, {} C",
+                        limits.min_mv,
+                        limits.max_mv,
+                        limits.max_temp_c
+                    );
+                }
+                None => {
+                    osprintln!("Sensor: off");
+                }
+            }
+            match ctx.config.get_key_repeat() {
+                Some(key_repeat) => {
+                    osprintln!(
+                        "Repeat: {} ms, {} Hz",
+                        key_repeat.delay_ms,
+                        key_repeat.rate_hz
+                    );
+                }
+                None => {
+                    osprintln!("Repeat: off");
+                }
+            }
         }
         _ => {
             osprintln!("config print - print the config");
             osprintln!("config help - print this help text");
             osprintln!("config reset - load config from BIOS store");
             osprintln!("config save - save config to BIOS store");
+            osprintln!("config export <file> - write config to a text file");
+            osprintln!("config import <file> - read config from a text file");
             osprintln!("config vga <n> - enable VGA in Mode <n>");
             osprintln!("config vga off - turn VGA off");
             osprintln!("config serial off - turn serial console off");
             osprintln!("config serial <baud> - turn serial console on with given baud rate");
+            osprintln!("config status <top|bottom|off> - set where the status line goes");
+            osprintln!("config repeat <delay_ms> <rate_hz> - make held keys repeat");
+            osprintln!("config repeat off - leave key repeat to the BIOS");
+            osprintln!("config tz <minutes> - set timezone, in minutes east of UTC");
+            osprintln!("config watchdog <secs> - reset if the OS doesn't run for <secs> seconds");
+            osprintln!("config watchdog off - turn the watchdog off");
+            osprintln!(
+                "config blank <mins> - blank the screen if no key is pressed for <mins> minutes"
+            );
+            osprintln!("config blank off - never blank the screen");
+            osprintln!("config saver <blank|flames> - what to show once the screen blanks");
+            osprintln!("config verify strict - only run programs with a good checksum");
+            osprintln!("config verify lenient - only check programs that have a checksum");
+            osprintln!("config keymap <name> - set the keyboard layout (uk, us, de, fr, es, ...)");
+            osprintln!("config keymap bios - use a BIOS that applies the layout itself");
+            osprintln!("config modifiers <on|off> - show Shift/Ctrl/Alt on the status line");
+            osprintln!("config scrub <on|off> - wipe program memory after each program runs");
+            osprintln!("config history <on|off> - keep the shell history in HISTORY.TXT");
+            osprintln!("config printer <uart> <baud> - use a serial printer on the given UART");
+            osprintln!("config printer <cp850|cp437|ascii> - set the printer's character set");
+            osprintln!("config printer off - forget the printer");
+            osprintln!("config ramdisk <KiB> - make a RAM disk at start-up");
+            osprintln!("config ramdisk off - don't make a RAM disk at start-up");
+            osprintln!(
+                "config sensors <min_mv> <max_mv> <max_c> - warn when the sensors go out of range"
+            );
+            osprintln!("config sensors off - never warn about the sensors");
+        }
+    }
+}
+
+/// Write our configuration to a text file.
+fn export(ctx: &mut Ctx, filename: &str) -> Result<(), crate::fs::Error> {
+    let mut file =
+        FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+    if ctx.config.export(&mut file).is_ok() {
+        osprintln!("Exported to {}", filename);
+    } else {
+        osprintln!("Failed writing to {}", filename);
+    }
+    Ok(())
+}
+
+/// Update our configuration from a text file.
+///
+/// Settings the file doesn't mention are left alone.
+fn import(ctx: &mut Ctx, filename: &str) -> Result<(), crate::fs::Error> {
+    let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+    let buffer = ctx.tpa.as_slice_u8();
+    let count = file.read(buffer)?;
+    if count != file.length() as usize {
+        osprintln!("File too large! Max {} bytes allowed.", buffer.len());
+        return Ok(());
+    }
+    let Ok(s) = core::str::from_utf8(&buffer[0..count]) else {
+        osprintln!("File is not valid UTF-8");
+        return Ok(());
+    };
+    for (line_no, line) in s.lines().enumerate() {
+        if let Err(e) = ctx.config.set_from_text(line) {
+            osprintln!("Line {}: {}", line_no + 1, e);
+        }
+    }
+    Ok(())
+}
+
+/// Called when the "console" command is executed.
+fn console(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let command = args.first().cloned().unwrap_or("print");
+    match command {
+        "route" => {
+            let Some(route) = args.get(2).and_then(|s| config::Route::parse(s)) else {
+                osprintln!("Give vga, serial or vga+serial as the consoles");
+                return;
+            };
+            let result = match args.get(1).cloned() {
+                Some("stdin") => crate::Console::set_stdin_route(route)
+                    .map(|_| ctx.config.set_stdin_route(route)),
+                Some("stdout") => crate::Console::set_stdout_route(route)
+                    .map(|_| ctx.config.set_stdout_route(route)),
+                _ => {
+                    osprintln!("Give stdin or stdout as the stream");
+                    return;
+                }
+            };
+            match result {
+                Ok(()) => {
+                    osprintln!("Routed {} to {}", args[1], route);
+                }
+                Err(e) => {
+                    osprintln!("Error: {}", e);
+                }
+            }
+        }
+        "print" => {
+            osprintln!("stdin : {}", crate::Console::stdin_route());
+            osprintln!("stdout: {}", crate::Console::stdout_route());
+        }
+        _ => {
+            osprintln!("console print - show where stdin and stdout go");
+            osprintln!("console help - print this help text");
+            osprintln!("console route <stdin|stdout> <vga|serial|vga+serial> - route a stream");
+            osprintln!("Use config save to keep the routes.");
         }
     }
 }
@@ -1,22 +1,40 @@
 //! File Systems related commands for Neotron OS
 
+use core::fmt::Write as _;
+
+use super::{parse_usize, print_hex_line, HEX_BYTES_PER_LINE};
 use crate::{osprint, osprintln, Ctx, FILESYSTEM};
 
 pub static DIR_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: dir,
-        parameters: &[],
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "path",
+            help: Some("The directory to list (default is the root directory)"),
+        }],
     },
     command: "dir",
-    help: Some("Dir the root directory on block device 0"),
+    help: Some("Dir a directory on block device 0 or the RAM disk"),
+};
+
+pub static TREE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: tree,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "path",
+            help: Some("The directory to start in (default is the root directory)"),
+        }],
+    },
+    command: "tree",
+    help: Some("Show a directory and all the directories under it"),
 };
 
 pub static LOAD_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: load,
-        parameters: &[menu::Parameter::Mandatory {
+        parameters: &[menu::Parameter::Optional {
             parameter_name: "file",
-            help: Some("The file to load"),
+            help: Some("The file to load (pick one from a list if not given)"),
         }],
     },
     command: "load",
@@ -47,25 +65,232 @@ pub static TYPE_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("Type a file to the console"),
 };
 
-pub static ROM_ITEM: menu::Item<Ctx> = menu::Item {
+pub static MORE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: more,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "file",
+            help: Some("The file to show"),
+        }],
+    },
+    command: "more",
+    help: Some("Show a file a screenful at a time (space for more, q to quit)"),
+};
+
+pub static HEXFILE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: hexfile,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to dump"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "offset",
+                help: Some("Where to start in the file"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "length",
+                help: Some("Number of bytes (default is the rest of the file)"),
+            },
+        ],
+    },
+    command: "hexfile",
+    help: Some("Dump the contents of a file as hex"),
+};
+
+pub static SUM_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: sum,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to check"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "hash",
+                help: Some("crc32 (the default) or sha256"),
+            },
+        ],
+    },
+    command: "sum",
+    help: Some("Print the CRC32 or SHA-256 of a file"),
+};
+
+pub static GREP_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: grep,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "pattern",
+                help: Some("The text to look for"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to search"),
+            },
+            menu::Parameter::Named {
+                parameter_name: "ignore-case",
+                help: Some("Match upper and lower case letters to each other"),
+            },
+        ],
+    },
+    command: "grep",
+    help: Some("Print the lines in a file that contain some text"),
+};
+
+pub static DIFF_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: diff,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "old",
+                help: Some("The first file"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "new",
+                help: Some("The second file"),
+            },
+        ],
+    },
+    command: "diff",
+    help: Some("Show the lines that differ between two small text files"),
+};
+
+pub static ELFINFO_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: elfinfo,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "file",
+            help: Some("The program to inspect"),
+        }],
+    },
+    command: "elfinfo",
+    help: Some("Show how a program would load, without running it"),
+};
+
+pub static COPY_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: copy,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "from",
+                help: Some("The file to copy"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "to",
+                help: Some("Where to copy it (like RAM: or DOCS/NEW.TXT)"),
+            },
+        ],
+    },
+    command: "copy",
+    help: Some("Copy a file, like between the SD card and the RAM disk"),
+};
+
+pub static RAMDISK_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
-        function: romfn,
+        function: ramdisk,
         parameters: &[menu::Parameter::Optional {
+            parameter_name: "size",
+            help: Some("How big to make it in KiB, or `off` to throw it away"),
+        }],
+    },
+    command: "ramdisk",
+    help: Some("Show, make or remove the RAM disk"),
+};
+
+pub static ATTRIB_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: attrib,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to examine or change"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "changes",
+                help: Some("Bits to set or clear, e.g. +r-h+s"),
+            },
+        ],
+    },
+    command: "attrib",
+    help: Some("Show or change file attributes"),
+};
+
+pub static TOUCH_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: touch,
+        parameters: &[menu::Parameter::Mandatory {
             parameter_name: "file",
-            help: Some("The ROM utility to run"),
+            help: Some("The file to touch"),
+        }],
+    },
+    command: "touch",
+    help: Some("Update a file's modification time (creating it if required)"),
+};
+
+pub static PRINT_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: print,
+        parameters: &[
+            menu::Parameter::Optional {
+                parameter_name: "file",
+                help: Some("The file to print (leave out to see what's waiting)"),
+            },
+            menu::Parameter::Named {
+                parameter_name: "cancel",
+                help: Some("Throw away everything waiting to print"),
+            },
+        ],
+    },
+    command: "print",
+    help: Some("Print a text file in the background"),
+};
+
+pub static SERVE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: serve,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "uart",
+                help: Some("Which UART the other computer is on"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "baud",
+                help: Some("How fast to run the UART (leave out to keep it as it is)"),
+            },
+        ],
+    },
+    command: "serve",
+    help: Some("Let another computer use the SD card over a UART"),
+};
+
+pub static MOUNT_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: mount,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "-r",
+            help: Some("Mount read-only"),
         }],
     },
-    command: "rom",
-    help: Some("Run a program from ROM"),
+    command: "mount",
+    help: Some("Mount block device 0, read-write or read-only"),
 };
 
 /// Called when the "dir" command is executed.
-fn dir(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
-    fn work() -> Result<(), crate::fs::Error> {
-        osprintln!("Listing files on Block Device 0, /");
+fn dir(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(path: &str) -> Result<(), crate::fs::Error> {
+        match crate::fs::ram_path(path) {
+            Some(dir) => {
+                osprintln!("Listing files on the RAM disk, /{}", dir);
+            }
+            None => {
+                osprintln!("Listing files on Block Device 0, /{}", path);
+            }
+        }
         let mut total_bytes = 0;
         let mut num_files = 0;
-        FILESYSTEM.iterate_root_dir(|dir_entry| {
+        FILESYSTEM.iterate_dir(path, |dir_entry| {
             let padding = 8 - dir_entry.name.base_name().len();
             for b in dir_entry.name.base_name() {
                 let ch = *b as char;
@@ -106,7 +331,7 @@ fn dir(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &
         Ok(())
     }
 
-    match work() {
+    match work(args.first().cloned().unwrap_or("")) {
         Ok(_) => {}
         Err(e) => {
             osprintln!("Error: {:?}", e);
@@ -114,33 +339,87 @@ fn dir(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &
     }
 }
 
+/// Called when the "tree" command is executed.
+fn tree(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let path = args.first().cloned().unwrap_or("");
+    let mut num_dirs = 0;
+    let mut total = crate::fs::DirSummary::default();
+    let result = FILESYSTEM.walk_dirs(path, |depth, name, summary| {
+        for _ in 0..depth {
+            osprint!("  ");
+        }
+        match name {
+            Some(name) => osprint!("{}", name),
+            None if path.is_empty() => osprint!("/"),
+            None => osprint!("{}", path),
+        }
+        osprintln!(" ({} file(s), {} bytes)", summary.files, summary.bytes);
+        num_dirs += 1;
+        total.files += summary.files;
+        total.bytes += summary.bytes;
+    });
+    match result {
+        Ok(too_deep) => {
+            if too_deep {
+                osprintln!(
+                    "Some directories are more than {} deep, and are not shown",
+                    crate::fs::MAX_DIR_DEPTH
+                );
+            }
+            osprintln!(
+                "{} dir(s), {} file(s), {} bytes",
+                num_dirs,
+                total.files,
+                total.bytes
+            );
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
 /// Called when the "load" command is executed.
 fn load(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
-    let Some(filename) = args.first() else {
-        osprintln!("Need a filename");
-        return;
+    let mut picked: heapless::String<12> = heapless::String::new();
+    let filename = match args.first() {
+        Some(filename) => *filename,
+        None if crate::ui::is_available() => {
+            let Some(name) = crate::ui::pick_file("Load") else {
+                return;
+            };
+            let _ = write!(picked, "{}", name);
+            picked.as_str()
+        }
+        None => {
+            osprintln!("Need a filename");
+            return;
+        }
     };
-    if let Err(e) = ctx.tpa.load_program(filename) {
-        osprintln!("Error: {:?}", e);
+    match ctx.tpa.load_program(filename) {
+        Ok(()) => {
+            osprintln!(log: Debug, "Loaded {:?}", filename);
+        }
+        Err(e) => {
+            osprintln!(log: Error, "Error loading {:?}: {}", filename, e);
+        }
     }
 }
 
 /// Called when the "exec" command is executed.
 fn exec(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
     fn work(ctx: &mut Ctx, filename: &str) -> Result<(), crate::fs::Error> {
-        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
-        let buffer = ctx.tpa.as_slice_u8();
-        let count = file.read(buffer)?;
-        if count != file.length() as usize {
-            osprintln!("File too large! Max {} bytes allowed.", buffer.len());
-            return Ok(());
+        // Check it's there before we try to run it
+        let _file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        // tell the main loop to run from this file next
+        match crate::Script::new(filename) {
+            Ok(script) => {
+                ctx.script = Some(script);
+            }
+            Err(e) => {
+                osprintln!("{}", e);
+            }
         }
-        let Ok(s) = core::str::from_utf8(&buffer[0..count]) else {
-            osprintln!("File is not valid UTF-8");
-            return Ok(());
-        };
-        // tell the main loop to run from these bytes next
-        ctx.exec_tpa = Some(s.len());
         Ok(())
     }
 
@@ -157,6 +436,10 @@ fn exec(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &m
 /// Called when the "type" command is executed.
 fn typefn(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
     fn work(ctx: &mut Ctx, filename: &str) -> Result<(), crate::fs::Error> {
+        if let Some(entry) = crate::sysfs::Entry::from_path(filename) {
+            osprint!("{}", entry.generate());
+            return Ok(());
+        }
         let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
         let buffer = ctx.tpa.as_slice_u8();
         let count = file.read(buffer)?;
@@ -184,30 +467,571 @@ fn typefn(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx:
     }
 }
 
-/// Called when the "romfn" command is executed.
-fn romfn(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
-    let Ok(romfs) = neotron_romfs::RomFs::new(crate::ROMFS) else {
-        osprintln!("No ROM available");
+/// Called when the "more" command is executed.
+fn more(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(filename: &str) -> Result<(), crate::fs::Error> {
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        // We read a bit at a time, so the file can be any size. A UTF-8
+        // character might be split between two reads, so we keep hold of
+        // any partial character at the end of the buffer.
+        let mut buffer = [0u8; 128];
+        let mut held = 0;
+        while !crate::Console::pager_quit() {
+            let count = file.read(&mut buffer[held..])?;
+            if count == 0 {
+                if held > 0 {
+                    // The file ends part way through a character
+                    osprint!("?");
+                }
+                break;
+            }
+            let total = held + count;
+            let mut used = 0;
+            while used < total {
+                match core::str::from_utf8(&buffer[used..total]) {
+                    Ok(s) => {
+                        osprint!("{}", s);
+                        used = total;
+                    }
+                    Err(e) => {
+                        let valid_end = used + e.valid_up_to();
+                        // Can't fail - this is the part that was valid
+                        let valid = core::str::from_utf8(&buffer[used..valid_end]);
+                        osprint!("{}", valid.unwrap_or_default());
+                        used = valid_end;
+                        let Some(bad_len) = e.error_len() else {
+                            // A partial character - wait for the rest
+                            break;
+                        };
+                        osprint!("?");
+                        used += bad_len;
+                    }
+                }
+            }
+            buffer.copy_within(used..total, 0);
+            held = total - used;
+        }
+        osprintln!();
+        Ok(())
+    }
+
+    crate::Console::start_pager();
+    // index can't panic - we always have enough args
+    let r = work(args[0]);
+    crate::Console::stop_pager();
+    // reset SGR
+    osprint!("\u{001b}[0m");
+    if let Err(e) = r {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "hexfile" command is executed.
+fn hexfile(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(filename: &str, offset: usize, len: Option<usize>) -> Result<(), crate::fs::Error> {
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        let file_len = file.length() as usize;
+        if offset > file_len {
+            osprintln!("Offset is past the end of the file");
+            return Ok(());
+        }
+        let len = len.unwrap_or(file_len).min(file_len - offset);
+        file.seek_from_start(offset as u32)?;
+        let mut line = [0u8; HEX_BYTES_PER_LINE];
+        for line_start in (offset..offset + len).step_by(HEX_BYTES_PER_LINE) {
+            let line_len = HEX_BYTES_PER_LINE.min(offset + len - line_start);
+            let count = file.read(&mut line[0..line_len])?;
+            print_hex_line(line_start, &line[0..count]);
+            if count < line_len {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    let Ok(offset) = args.get(1).map_or(Ok(0), |s| parse_usize(s)) else {
+        osprintln!("Bad offset");
         return;
     };
-    if let Some(arg) = args.get(0) {
-        let Some(entry) = romfs.find(arg) else {
-            osprintln!("Couldn't find {} in ROM", arg);
+    let Ok(len) = args.get(2).map(|s| parse_usize(s)).transpose() else {
+        osprintln!("Bad length");
+        return;
+    };
+    // index can't panic - we always have enough args
+    if let Err(e) = work(args[0], offset, len) {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "sum" command is executed.
+fn sum(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    /// Pass every byte of the file to `f`, using `buffer` to read it.
+    fn for_each_chunk<F>(
+        filename: &str,
+        buffer: &mut [u8],
+        mut f: F,
+    ) -> Result<(), crate::fs::Error>
+    where
+        F: FnMut(&[u8]),
+    {
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        // Big files can take a while
+        let _pause = crate::watchdog::pause();
+        loop {
+            let count = file.read(buffer)?;
+            if count == 0 {
+                return Ok(());
+            }
+            f(&buffer[0..count]);
+        }
+    }
+
+    // index can't panic - we always have enough args
+    let filename = args[0];
+    let buffer = ctx.tpa.as_slice_u8();
+    let result = match args.get(1).cloned() {
+        None | Some("crc32") => {
+            let mut crc = 0;
+            for_each_chunk(filename, buffer, |chunk| {
+                crc = crate::crc::crc32_update(crc, chunk);
+            })
+            .map(|_| {
+                osprintln!("{:08x}  {}", crc, filename);
+            })
+        }
+        Some("sha256") => {
+            let mut sha = crate::sha256::Sha256::new();
+            for_each_chunk(filename, buffer, |chunk| sha.update(chunk)).map(|_| {
+                for b in sha.finish() {
+                    osprint!("{:02x}", b);
+                }
+                osprintln!("  {}", filename);
+            })
+        }
+        Some(_) => {
+            osprintln!("Give crc32 or sha256 as the hash");
             return;
+        }
+    };
+    if let Err(e) = result {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "grep" command is executed.
+fn grep(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    /// How much of each matching line we print
+    const LINE_LEN: usize = 128;
+
+    /// Print a matching line
+    fn print_line(line_no: u32, line: &[u8], truncated: bool) {
+        let text = match core::str::from_utf8(line) {
+            Ok(s) => s,
+            // Probably a character cut in half when we truncated the line
+            Err(e) => core::str::from_utf8(&line[0..e.valid_up_to()]).unwrap_or_default(),
         };
-        if let Err(e) = ctx.tpa.load_rom_program(entry.contents) {
+        osprintln!(
+            "{:5}: {}{}",
+            line_no,
+            text,
+            if truncated { "..." } else { "" }
+        );
+    }
+
+    fn work(matcher: &mut crate::search::Matcher, filename: &str) -> Result<u32, crate::fs::Error> {
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        // Big files can take a while
+        let _pause = crate::watchdog::pause();
+        let mut buffer = [0u8; 128];
+        let mut line: heapless::Vec<u8, LINE_LEN> = heapless::Vec::new();
+        let mut truncated = false;
+        let mut line_matches = false;
+        let mut line_no = 1;
+        let mut num_matches = 0;
+        loop {
+            let count = file.read(&mut buffer)?;
+            // The matcher keeps track of partial matches, so it doesn't
+            // matter where the reads split the file
+            for b in &buffer[0..count] {
+                if *b == b'\n' {
+                    if line_matches {
+                        print_line(line_no, &line, truncated);
+                        num_matches += 1;
+                    }
+                    line.clear();
+                    truncated = false;
+                    line_matches = false;
+                    line_no += 1;
+                    matcher.reset();
+                    continue;
+                }
+                if matcher.push(*b) {
+                    line_matches = true;
+                }
+                if *b != b'\r' && line.push(*b).is_err() {
+                    truncated = true;
+                }
+            }
+            if count == 0 {
+                // The last line might not end with a new-line
+                if line_matches {
+                    print_line(line_no, &line, truncated);
+                    num_matches += 1;
+                }
+                return Ok(num_matches);
+            }
+        }
+    }
+
+    let (Ok(Some(pattern)), Ok(Some(filename))) = (
+        menu::argument_finder(item, args, "pattern"),
+        menu::argument_finder(item, args, "file"),
+    ) else {
+        osprintln!("Need a pattern and a filename");
+        return;
+    };
+    let ignore_case = matches!(
+        menu::argument_finder(item, args, "ignore-case"),
+        Ok(Some(_))
+    );
+    let Some(mut matcher) = crate::search::Matcher::new(pattern.as_bytes(), ignore_case) else {
+        osprintln!(
+            "Pattern must be 1 to {} bytes long",
+            crate::search::MAX_PATTERN_LEN
+        );
+        return;
+    };
+    match work(&mut matcher, filename) {
+        Ok(num_matches) => {
+            osprintln!("{} matching line(s)", num_matches);
+        }
+        Err(e) => {
             osprintln!("Error: {:?}", e);
         }
-    } else {
-        for entry in romfs.into_iter() {
-            if let Ok(entry) = entry {
-                osprintln!(
-                    "{} ({} bytes)",
-                    entry.metadata.file_name,
-                    entry.metadata.file_size
-                );
+    }
+}
+
+/// Called when the "diff" command is executed.
+fn diff(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    /// Read a whole file into the start of `buffer`, returning how long it
+    /// is, or `None` if it doesn't fit.
+    fn read_file(filename: &str, buffer: &mut [u8]) -> Result<Option<usize>, crate::fs::Error> {
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+        let len = file.length() as usize;
+        let Some(buffer) = buffer.get_mut(0..len) else {
+            return Ok(None);
+        };
+        let count = file.read(buffer)?;
+        Ok(Some(count))
+    }
+
+    /// Print a line, as much as is valid UTF-8
+    fn print_line(prefix: char, line_no: usize, text: &[u8]) {
+        let text = match core::str::from_utf8(text) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&text[0..e.valid_up_to()]).unwrap_or_default(),
+        };
+        osprintln!("{}{:5}: {}", prefix, line_no, text);
+    }
+
+    fn work(ctx: &mut Ctx, old_name: &str, new_name: &str) -> Result<(), crate::fs::Error> {
+        // Both files go in the TPA, and we use the rest to compare them
+        let buffer = ctx.tpa.as_slice_u8();
+        let Some(old_len) = read_file(old_name, buffer)? else {
+            osprintln!("{} is too large", old_name);
+            return Ok(());
+        };
+        let (old, rest) = buffer.split_at_mut(old_len);
+        let Some(new_len) = read_file(new_name, rest)? else {
+            osprintln!("{} is too large", new_name);
+            return Ok(());
+        };
+        let (new, scratch) = rest.split_at_mut(new_len);
+        // Comparing lots of lines can take a while
+        let _pause = crate::watchdog::pause();
+        let result = crate::diff::diff(old, new, scratch, |change| match change {
+            crate::diff::Change::Removed { line_no, text } => print_line('-', line_no, text),
+            crate::diff::Change::Added { line_no, text } => print_line('+', line_no, text),
+        });
+        match result {
+            Ok(0) => {
+                osprintln!("The files are the same");
+            }
+            Ok(changes) => {
+                osprintln!("{} line(s) differ", changes);
+            }
+            Err(crate::diff::TooBig) => {
+                osprintln!("The files have too many lines to compare");
+            }
+        }
+        Ok(())
+    }
+
+    // index can't panic - we always have enough args
+    if let Err(e) = work(ctx, args[0], args[1]) {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "elfinfo" command is executed.
+fn elfinfo(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    // index can't panic - we always have enough args
+    if let Err(e) = ctx.tpa.inspect_program(args[0]) {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "attrib" command is executed.
+fn attrib(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(Some(filename)) = menu::argument_finder(item, args, "file") else {
+        osprintln!("Need a filename");
+        return;
+    };
+    if let Ok(Some(changes)) = menu::argument_finder(item, args, "changes") {
+        let mut set = 0;
+        let mut clear = 0;
+        let mut adding = true;
+        for ch in changes.chars() {
+            let bit = match ch.to_ascii_lowercase() {
+                '+' => {
+                    adding = true;
+                    continue;
+                }
+                '-' => {
+                    adding = false;
+                    continue;
+                }
+                'r' => crate::fs::ATTR_READ_ONLY,
+                'h' => crate::fs::ATTR_HIDDEN,
+                's' => crate::fs::ATTR_SYSTEM,
+                'a' => crate::fs::ATTR_ARCHIVE,
+                _ => {
+                    osprintln!("Unknown attribute {:?}. Use r, h, s or a.", ch);
+                    return;
+                }
+            };
+            if adding {
+                set |= bit;
+            } else {
+                clear |= bit;
+            }
+        }
+        if let Err(e) = FILESYSTEM.set_attributes(filename, set, clear) {
+            osprintln!("Error: {:?}", e);
+            return;
+        }
+    }
+    match FILESYSTEM.stat(filename) {
+        Ok(entry) => {
+            let attr = entry.attributes;
+            osprintln!(
+                "{}{}{}{}  {}",
+                if attr.is_read_only() { 'R' } else { '-' },
+                if attr.is_hidden() { 'H' } else { '-' },
+                if attr.is_system() { 'S' } else { '-' },
+                if attr.is_archive() { 'A' } else { '-' },
+                entry.name
+            );
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Called when the "print" command is executed.
+fn print(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    if matches!(menu::argument_finder(item, args, "cancel"), Ok(Some(_))) {
+        osprintln!("Cancelled {} file(s)", crate::spool::cancel());
+        return;
+    }
+    let Ok(Some(filename)) = menu::argument_finder(item, args, "file") else {
+        let mut count = 0;
+        crate::spool::for_each_job(|path, offset| {
+            osprintln!("{}: {} ({} bytes sent)", count, path, offset);
+            count += 1;
+        });
+        if count == 0 {
+            osprintln!("Nothing waiting to print");
+        } else if crate::spool::is_paused() {
+            osprintln!("The printer has asked us to wait");
+        }
+        return;
+    };
+    if let Err(e) = FILESYSTEM.stat(filename) {
+        osprintln!("Error: {:?}", e);
+        return;
+    }
+    match crate::spool::submit(filename) {
+        Ok(0) => {
+            osprintln!("Printing {}", filename);
+        }
+        Ok(ahead) => {
+            osprintln!("Queued {} ({} ahead of it)", filename, ahead);
+        }
+        Err(e) => {
+            osprintln!("Error: {}", e);
+        }
+    }
+}
+
+/// Called when the "serve" command is executed.
+fn serve(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(Some(Ok(port))) = menu::argument_finder(item, args, "uart").map(|s| s.map(str::parse))
+    else {
+        osprintln!("Give the UART number as a number");
+        return;
+    };
+    let baud = match menu::argument_finder(item, args, "baud") {
+        Ok(Some(baud)) => match baud.parse::<u32>() {
+            Ok(baud) => Some(baud),
+            Err(_e) => {
+                osprintln!("Give the baud rate as a number");
+                return;
+            }
+        },
+        _ => None,
+    };
+    osprintln!("Serving files on UART {}. Press Ctrl-X to stop.", port);
+    const CTRL_X: char = '\u{0018}';
+    let result = crate::serve::run(port, baud, || {
+        matches!(
+            crate::STD_INPUT.lock().get_raw(),
+            Some(pc_keyboard::DecodedKey::Unicode(CTRL_X))
+        )
+    });
+    match result {
+        Ok(stats) => {
+            osprintln!(
+                "Finished: {} requests ({} damaged), {} bytes sent, {} bytes received",
+                stats.requests,
+                stats.bad_frames,
+                stats.bytes_read,
+                stats.bytes_written
+            );
+        }
+        Err(e) => {
+            osprintln!("Error: {}", e);
+        }
+    }
+}
+
+/// Called when the "copy" command is executed.
+fn copy(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    fn work(ctx: &mut Ctx, from: &str, to: &str) -> Result<u32, crate::fs::Error> {
+        let (from_dir, from_name) = crate::fs::split_path(from);
+        let (to_dir, mut to_name) = crate::fs::split_path(to);
+        if to_name.is_empty() {
+            // Copying into a directory keeps the name
+            to_name = from_name;
+        }
+        let source =
+            FILESYSTEM.open_file_in_dir(from_dir, from_name, embedded_sdmmc::Mode::ReadOnly)?;
+        let dest = FILESYSTEM.open_file_in_dir(
+            to_dir,
+            to_name,
+            embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+        )?;
+        let buffer = ctx.tpa.as_slice_u8();
+        let mut total = 0;
+        loop {
+            let count = source.read(buffer)?;
+            if count == 0 {
+                break;
+            }
+            dest.write(&buffer[0..count])?;
+            total += count as u32;
+        }
+        Ok(total)
+    }
+
+    // index can't panic - we always have enough args
+    match work(ctx, args[0], args[1]) {
+        Ok(bytes) => {
+            osprintln!("Copied {} bytes", bytes);
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Called when the "ramdisk" command is executed.
+fn ramdisk(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let result = match args.first().cloned() {
+        None => {
+            match crate::ramdisk::size() {
+                Some((memory, capacity)) => {
+                    osprintln!(
+                        "RAM: uses {} KiB, holds {} KiB of files",
+                        memory / 1024,
+                        capacity / 1024
+                    );
+                }
+                None => {
+                    osprintln!("No RAM disk");
+                }
             }
+            return;
+        }
+        Some("off") => crate::ramdisk::remove(&mut ctx.tpa),
+        Some(kib_str) => {
+            let Ok(kib) = kib_str.parse::<u16>() else {
+                osprintln!("Give off or a size in KiB as argument");
+                return;
+            };
+            crate::ramdisk::create(&mut ctx.tpa, kib)
+        }
+    };
+    match result {
+        Ok(()) => {
+            osprintln!("TPA now {} bytes", ctx.tpa.as_slice_u8().len());
+        }
+        Err(e) => {
+            osprintln!("Error: {}", e);
+        }
+    }
+}
+
+/// Called when the "touch" command is executed.
+fn touch(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(filename: &str) -> Result<(), crate::fs::Error> {
+        // Opening the file creates it if it's missing
+        let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrAppend)?;
+        drop(file);
+        let timestamp = embedded_sdmmc::TimeSource::get_timestamp(&crate::fs::BiosTime());
+        FILESYSTEM.set_mtime(filename, timestamp)?;
+        Ok(())
+    }
+
+    // index can't panic - we always have enough args
+    if let Err(e) = work(args[0]) {
+        osprintln!("Error: {:?}", e);
+    }
+}
+
+/// Called when the "mount" command is executed.
+fn mount(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let read_only = match args.first().cloned() {
+        None => false,
+        Some("-r") => true,
+        Some(arg) => {
+            osprintln!("Unknown option {:?}. Use -r to mount read-only.", arg);
+            return;
         }
+    };
+    if let Err(e) = FILESYSTEM.mount(read_only) {
+        osprintln!("Error: {:?}", e);
+        return;
+    }
+    if FILESYSTEM.is_read_only() {
+        if read_only {
+            osprintln!("Mounted block device 0 read-only");
+        } else {
+            osprintln!("Mounted block device 0 read-only (the disk is write-protected)");
+        }
+    } else {
+        osprintln!("Mounted block device 0 read-write");
     }
 }
 
@@ -0,0 +1,552 @@
+//! Graphics commands for Neotron OS
+
+use embedded_graphics_core::pixelcolor::RgbColor;
+use neotron_common_bios::video::RGBColour;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::wait_for_key;
+use crate::{
+    bios::{
+        video::{Format, Mode, Timing},
+        ApiResult,
+    },
+    osprintln, Ctx,
+};
+
+/// The most slides `show` will find in a directory
+const SHOW_MAX_SLIDES: usize = 64;
+
+/// The reasons we couldn't show a BMP image
+#[derive(Debug)]
+enum BmpError {
+    /// Couldn't read the file
+    Io(crate::fs::Error),
+    /// The file won't fit in the space we have
+    TooLarge,
+    /// The file isn't a BMP we understand
+    Parse(tinybmp::ParseError),
+    /// The image isn't 1, 4 or 8 bits per pixel
+    Depth,
+    /// There's no video mode we can show the image in
+    NoMode,
+    /// The BIOS wouldn't change to the given mode
+    SetMode(u8, crate::bios::Error),
+}
+
+/// Where the slides for `show` come from
+enum Slides<'a> {
+    /// BMP files in a directory on disk, with their lengths
+    Disk {
+        dir: &'a str,
+        files: &'a [(embedded_sdmmc::ShortFileName, u32)],
+    },
+    /// BMP files built into the OS (see the `slides` feature)
+    BuiltIn(&'static [(&'static str, &'static [u8])]),
+}
+
+impl<'a> Slides<'a> {
+    /// How many slides are there?
+    fn len(&self) -> usize {
+        match self {
+            Slides::Disk { files, .. } => files.len(),
+            Slides::BuiltIn(list) => list.len(),
+        }
+    }
+
+    /// How much of the TPA we need to load the biggest slide into
+    fn max_file_len(&self) -> usize {
+        match self {
+            Slides::Disk { files, .. } => files
+                .iter()
+                .map(|(_, size)| *size as usize)
+                .max()
+                .unwrap_or(0),
+            // These are already in memory
+            Slides::BuiltIn(_) => 0,
+        }
+    }
+}
+
+impl core::fmt::Display for BmpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BmpError::Io(e) => write!(f, "Error: {:?}", e),
+            BmpError::TooLarge => write!(f, "File too large!"),
+            BmpError::Parse(e) => write!(f, "Bad BMP: {:?}", e),
+            BmpError::Depth => write!(f, "Only 1, 4 and 8 bpp images are supported."),
+            BmpError::NoMode => write!(f, "No suitable video mode available."),
+            BmpError::SetMode(mode, e) => write!(f, "Couldn't set mode {}: {:?}", mode, e),
+        }
+    }
+}
+
+pub static GFX_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: gfx_cmd,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "new_mode",
+                help: Some("The new gfx mode to try"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "filename",
+                help: Some("a file to display"),
+            },
+        ],
+    },
+    command: "gfx",
+    help: Some("Test a graphics mode"),
+};
+
+pub static VIEW_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: view_cmd,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "filename",
+            help: Some("The 1, 4 or 8 bpp BMP file to display"),
+        }],
+    },
+    command: "view",
+    help: Some("Display a BMP image (press any key to exit)"),
+};
+
+pub static SHOW_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: show_cmd,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "dir",
+            help: Some(
+                "The directory of BMP slides to show, in name order (default: built-in slides)",
+            ),
+        }],
+    },
+    command: "show",
+    help: Some("Show BMP slides (any key for next, Left for previous, Esc to exit)"),
+};
+
+/// Called when the "gfx" command is executed
+fn gfx_cmd(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let Some(new_mode) = menu::argument_finder(item, args, "new_mode").unwrap() else {
+        osprintln!("Missing arg");
+        return;
+    };
+    let file_name = menu::argument_finder(item, args, "filename").unwrap();
+    let Ok(mode_num) = new_mode.parse::<u8>() else {
+        osprintln!("Invalid integer {:?}", new_mode);
+        return;
+    };
+    let Some(mode) = Mode::try_from_u8(mode_num) else {
+        osprintln!("Invalid mode {:?}", new_mode);
+        return;
+    };
+    let api = crate::API.get();
+    let old_mode = (api.video_get_mode)();
+    let old_ptr = (api.video_get_framebuffer)();
+
+    let buffer = ctx.tpa.as_slice_u8();
+    let buffer_ptr = buffer.as_mut_ptr() as *mut u32;
+    if let Some(file_name) = file_name {
+        let Ok(file) = crate::FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly)
+        else {
+            osprintln!("No such file.");
+            return;
+        };
+        let _ = file.read(buffer);
+    } else {
+        // draw a dummy non-zero data. In Chunky1 this is a checkerboard.
+        let line_size_words = mode.line_size_bytes() / 4;
+        for row in 0..mode.vertical_lines() as usize {
+            let word = if (row % 2) == 0 {
+                0x5555_5555
+            } else {
+                0xAAAA_AAAA
+            };
+            for col in 0..line_size_words {
+                let idx = (row * line_size_words) + col;
+                unsafe {
+                    // Let's try stripes?
+                    buffer_ptr.add(idx).write_volatile(word);
+                }
+            }
+        }
+    }
+
+    if let neotron_common_bios::FfiResult::Err(e) =
+        unsafe { (api.video_set_mode)(mode, buffer_ptr) }
+    {
+        osprintln!("Couldn't set mode {}: {:?}", mode_num, e);
+    }
+
+    // Now wait for user input
+    let mut r = 0u8;
+    let mut g = 80u8;
+    let mut b = 160u8;
+    'wait: loop {
+        crate::vsync::wait_for_vsync();
+        ((api.video_set_palette)(0, RGBColour::from_rgb(r, g, b)));
+        r = r.wrapping_add(1);
+        g = g.wrapping_add(1);
+        b = b.wrapping_add(1);
+
+        let keyin = crate::STD_INPUT.lock().get_raw();
+        if let Some(DecodedKey::Unicode('Q') | DecodedKey::Unicode('q')) = keyin {
+            break 'wait;
+        }
+    }
+
+    // Put it back as it was
+    unsafe {
+        (api.video_set_mode)(old_mode, old_ptr);
+    }
+}
+
+/// Called when the "view" command is executed
+fn view_cmd(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let api = crate::API.get();
+    let tpa = ctx.tpa.as_slice_u8();
+
+    // The file goes at the top of the TPA, the framebuffer at the bottom
+    // index can't panic - we always have enough args
+    let file = match crate::FILESYSTEM.open_file(args[0], embedded_sdmmc::Mode::ReadOnly) {
+        Ok(file) => file,
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+            return;
+        }
+    };
+    let file_len = file.length() as usize;
+    if file_len > tpa.len() {
+        osprintln!("File too large! Max {} bytes allowed.", tpa.len());
+        return;
+    }
+    // Keep the framebuffer 32-bit aligned
+    let split = (tpa.len() - file_len) & !3;
+    let (fb_area, file_area) = tpa.split_at_mut(split);
+    if let Err(e) = file.read(file_area) {
+        osprintln!("Error: {:?}", e);
+        return;
+    }
+    drop(file);
+
+    let old_palette = save_palette();
+    let old_mode = (api.video_get_mode)();
+    let old_ptr = (api.video_get_framebuffer)();
+
+    let result = draw_bmp(&file_area[0..file_len], fb_area).and_then(|mode| {
+        // # Safety
+        //
+        // The framebuffer lives in the TPA, which we own until we return,
+        // and we put the old mode back before we do.
+        unsafe { set_gfx_mode(mode, fb_area) }
+    });
+    match result {
+        Ok(()) => {
+            wait_for_key();
+            unsafe {
+                (api.video_set_mode)(old_mode, old_ptr);
+            }
+        }
+        Err(e) => {
+            osprintln!("{}", e);
+        }
+    }
+
+    restore_palette(&old_palette);
+}
+
+/// Called when the "show" command is executed
+fn show_cmd(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let api = crate::API.get();
+    let tpa = ctx.tpa.as_slice_u8();
+
+    let mut files: heapless::Vec<(embedded_sdmmc::ShortFileName, u32), SHOW_MAX_SLIDES> =
+        heapless::Vec::new();
+    let slides = if let Some(dir) = args.first() {
+        // Find the slides, and put them in name order
+        let mut too_many = false;
+        if let Err(e) = crate::FILESYSTEM.iterate_dir(dir, |entry| {
+            if !entry.attributes.is_directory()
+                && entry.name.extension() == b"BMP"
+                && files.push((entry.name.clone(), entry.size)).is_err()
+            {
+                too_many = true;
+            }
+        }) {
+            osprintln!("Error: {:?}", e);
+            return;
+        }
+        if files.is_empty() {
+            osprintln!("No BMP files found.");
+            return;
+        }
+        if too_many {
+            osprintln!("Only showing the first {} slides.", SHOW_MAX_SLIDES);
+        }
+        files.sort_unstable_by(|a, b| a.0.base_name().cmp(b.0.base_name()));
+        Slides::Disk { dir, files: &files }
+    } else if crate::SLIDES.is_empty() {
+        osprintln!("No built-in slides. Give a directory.");
+        return;
+    } else {
+        Slides::BuiltIn(crate::SLIDES)
+    };
+
+    // Slides from disk go at the top of the TPA, the framebuffer at the
+    // bottom. We need room for the biggest slide.
+    let max_len = slides.max_file_len();
+    if max_len > tpa.len() {
+        osprintln!("Slides too large! Max {} bytes allowed.", tpa.len());
+        return;
+    }
+    // Keep the framebuffer 32-bit aligned
+    let split = (tpa.len() - max_len) & !3;
+    let (fb_area, file_area) = tpa.split_at_mut(split);
+
+    let old_palette = save_palette();
+    let old_mode = (api.video_get_mode)();
+    let old_ptr = (api.video_get_framebuffer)();
+
+    let mut current = 0;
+    // Which slide is in `file_area` (and how long it is)
+    let mut loaded = None;
+    let mut mode_changed = false;
+    let result = loop {
+        let data = match &slides {
+            Slides::Disk { dir, files } => {
+                let file_len = match loaded {
+                    Some((idx, len)) if idx == current => len,
+                    _ => match load_file(dir, &files[current].0, file_area) {
+                        Ok(len) => len,
+                        Err(e) => break Err(e),
+                    },
+                };
+                &file_area[0..file_len]
+            }
+            Slides::BuiltIn(list) => list[current].1,
+        };
+        let mode = match draw_bmp(data, fb_area) {
+            Ok(mode) => mode,
+            Err(e) => break Err(e),
+        };
+        // # Safety
+        //
+        // The framebuffer lives in the TPA, which we own until we return,
+        // and we put the old mode back before we do.
+        if let Err(e) = unsafe { set_gfx_mode(mode, fb_area) } {
+            break Err(e);
+        }
+        mode_changed = true;
+
+        // Load the next slide while this one is on screen, so we can
+        // move on without waiting for the disk.
+        let next = current + 1;
+        loaded = None;
+        if let Slides::Disk { dir, files } = &slides {
+            if let Some((name, _)) = files.get(next) {
+                if let Ok(len) = load_file(dir, name, file_area) {
+                    loaded = Some((next, len));
+                }
+            }
+        }
+
+        match wait_for_key() {
+            DecodedKey::RawKey(KeyCode::Escape) | DecodedKey::Unicode('\u{1b}' | 'q' | 'Q') => {
+                break Ok(());
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft | KeyCode::ArrowUp | KeyCode::PageUp)
+            | DecodedKey::Unicode('\u{8}') => {
+                current = current.saturating_sub(1);
+            }
+            DecodedKey::RawKey(KeyCode::Home) => {
+                current = 0;
+            }
+            DecodedKey::RawKey(KeyCode::End) => {
+                current = slides.len() - 1;
+            }
+            _ if next < slides.len() => {
+                current = next;
+            }
+            _ => {
+                break Ok(());
+            }
+        }
+    };
+
+    if mode_changed {
+        unsafe {
+            (api.video_set_mode)(old_mode, old_ptr);
+        }
+    }
+    restore_palette(&old_palette);
+    if let Err(e) = result {
+        match &slides {
+            Slides::Disk { files, .. } => {
+                osprintln!("{}: {}", files[current].0, e);
+            }
+            Slides::BuiltIn(list) => {
+                osprintln!("{}: {}", list[current].0, e);
+            }
+        }
+    }
+}
+
+/// Read a whole file, from the directory `dir`, into the start of `buffer`.
+///
+/// Returns how long the file is.
+fn load_file(
+    dir: &str,
+    name: &embedded_sdmmc::ShortFileName,
+    buffer: &mut [u8],
+) -> Result<usize, BmpError> {
+    let file = crate::FILESYSTEM
+        .open_file_in_dir(dir, name, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(BmpError::Io)?;
+    let file_len = file.length() as usize;
+    let buffer = buffer.get_mut(0..file_len).ok_or(BmpError::TooLarge)?;
+    file.read(buffer).map_err(BmpError::Io)?;
+    Ok(file_len)
+}
+
+/// Draw a BMP image into the framebuffer area, and load its palette.
+///
+/// Returns the video mode the image should be shown in.
+fn draw_bmp(data: &[u8], fb_area: &mut [u8]) -> Result<Mode, BmpError> {
+    let api = crate::API.get();
+    let bmp = tinybmp::RawBmp::from_slice(data).map_err(BmpError::Parse)?;
+    let header = bmp.header();
+    let (format, bits_per_pixel) = match header.bpp {
+        tinybmp::Bpp::Bits1 => (Format::Chunky1, 1),
+        tinybmp::Bpp::Bits4 => (Format::Chunky4, 4),
+        tinybmp::Bpp::Bits8 => (Format::Chunky8, 8),
+        _ => {
+            return Err(BmpError::Depth);
+        }
+    };
+    let image_width = header.image_size.width as usize;
+    let image_height = header.image_size.height as usize;
+
+    // Pick the smallest mode the image fits in. If none of them are big
+    // enough, use the largest one we can, and crop the image.
+    let mut chosen = None;
+    for timing in [Timing::T640x400, Timing::T640x480, Timing::T800x600] {
+        let mode = Mode::new(timing, format);
+        if !(api.video_is_valid_mode)(mode) || mode.frame_size_bytes() > fb_area.len() {
+            continue;
+        }
+        chosen = Some(mode);
+        if usize::from(mode.horizontal_pixels()) >= image_width
+            && usize::from(mode.vertical_lines()) >= image_height
+        {
+            break;
+        }
+    }
+    let mode = chosen.ok_or(BmpError::NoMode)?;
+
+    // Letterbox the image in the middle of the screen
+    let line_size = mode.line_size_bytes();
+    let screen_width = line_size * 8 / bits_per_pixel;
+    let screen_height = mode.frame_size_bytes() / line_size;
+    let x_offset = screen_width.saturating_sub(image_width) / 2;
+    let y_offset = screen_height.saturating_sub(image_height) / 2;
+    let frame = &mut fb_area[0..mode.frame_size_bytes()];
+    frame.fill(0);
+    for pixel in bmp.pixels() {
+        let x = pixel.position.x as usize + x_offset;
+        let y = pixel.position.y as usize + y_offset;
+        if x >= screen_width || y >= screen_height {
+            continue;
+        }
+        set_chunky_pixel(
+            &mut frame[y * line_size..],
+            bits_per_pixel,
+            x,
+            pixel.color as u8,
+        );
+    }
+
+    if let Some(table) = bmp.color_table() {
+        for idx in 0..table.len().min(256) {
+            if let Some(colour) = table.get(idx as u32) {
+                (api.video_set_palette)(
+                    idx as u8,
+                    RGBColour::from_rgb(colour.r(), colour.g(), colour.b()),
+                );
+            }
+        }
+    }
+
+    Ok(mode)
+}
+
+/// Set pixel `x` in a line of a chunky framebuffer with 1, 2, 4 or 8 bits
+/// per pixel.
+///
+/// The pixel must be blank to start with. Any bits of `colour` that don't
+/// fit are ignored.
+fn set_chunky_pixel(line: &mut [u8], bits_per_pixel: usize, x: usize, colour: u8) {
+    let pixels_per_byte = 8 / bits_per_pixel;
+    let mask = u8::MAX >> (8 - bits_per_pixel);
+    // Leftmost pixel is in the most significant bits
+    let shift = (pixels_per_byte - 1 - (x % pixels_per_byte)) * bits_per_pixel;
+    line[x / pixels_per_byte] |= (colour & mask) << shift;
+}
+
+/// Switch to a graphics mode, with the framebuffer at the start of
+/// `fb_area`.
+///
+/// # Safety
+///
+/// `fb_area` must live until the caller puts the old mode back.
+unsafe fn set_gfx_mode(mode: Mode, fb_area: &mut [u8]) -> Result<(), BmpError> {
+    let api = crate::API.get();
+    match (api.video_set_mode)(mode, fb_area.as_mut_ptr() as *mut u32) {
+        ApiResult::Ok(()) => Ok(()),
+        ApiResult::Err(e) => Err(BmpError::SetMode(mode.as_u8(), e)),
+    }
+}
+
+/// Get the whole palette, so we can put it back later
+fn save_palette() -> [RGBColour; 256] {
+    let api = crate::API.get();
+    let mut palette = [RGBColour::from_rgb(0, 0, 0); 256];
+    for (idx, entry) in palette.iter_mut().enumerate() {
+        if let neotron_common_bios::FfiOption::Some(colour) = (api.video_get_palette)(idx as u8) {
+            *entry = colour;
+        }
+    }
+    palette
+}
+
+/// Put back a palette from [`save_palette`]
+fn restore_palette(palette: &[RGBColour; 256]) {
+    let api = crate::API.get();
+    for (idx, entry) in palette.iter().enumerate() {
+        (api.video_set_palette)(idx as u8, *entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunky_pixels() {
+        let mut line = [0u8; 2];
+        set_chunky_pixel(&mut line, 1, 1, 0xFF);
+        set_chunky_pixel(&mut line, 1, 15, 1);
+        assert_eq!(line, [0x40, 0x01]);
+        let mut line = [0u8; 2];
+        set_chunky_pixel(&mut line, 2, 0, 0x03);
+        set_chunky_pixel(&mut line, 2, 6, 0x06);
+        assert_eq!(line, [0xC0, 0x08]);
+        let mut line = [0u8; 2];
+        set_chunky_pixel(&mut line, 4, 1, 0x1A);
+        set_chunky_pixel(&mut line, 4, 2, 0x0F);
+        assert_eq!(line, [0x0A, 0xF0]);
+        let mut line = [0u8; 2];
+        set_chunky_pixel(&mut line, 8, 1, 0xAB);
+        assert_eq!(line, [0x00, 0xAB]);
+    }
+}
+
+// End of file
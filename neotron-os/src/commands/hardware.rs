@@ -1,8 +1,6 @@
 //! Hardware related commands for Neotron OS
 
-use crate::{bios, osprintln, Ctx, API};
-
-use super::{parse_u8, parse_usize};
+use crate::{bios, osprint, osprintln, stats, Ctx, API};
 
 pub static LSBLK_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
@@ -22,22 +20,13 @@ pub static LSBUS_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("List all the Neotron Bus devices"),
 };
 
-pub static LSI2C_ITEM: menu::Item<Ctx> = menu::Item {
-    item_type: menu::ItemType::Callback {
-        function: lsi2c,
-        parameters: &[],
-    },
-    command: "lsi2c",
-    help: Some("List all the BIOS I2C devices"),
-};
-
 pub static LSMEM_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: lsmem,
         parameters: &[],
     },
     command: "lsmem",
-    help: Some("List all the BIOS Memory regions"),
+    help: Some("List all the BIOS Memory regions, and what is reserved in the TPA"),
 };
 
 pub static LSUART_ITEM: menu::Item<Ctx> = menu::Item {
@@ -67,30 +56,43 @@ pub static SHUTDOWN_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("Shutdown the system"),
 };
 
-pub static I2C_ITEM: menu::Item<Ctx> = menu::Item {
+pub static POWER_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
-        function: i2c,
-        parameters: &[
-            menu::Parameter::Mandatory {
-                parameter_name: "bus_idx",
-                help: Some("I2C bus index"),
-            },
-            menu::Parameter::Mandatory {
-                parameter_name: "dev_addr",
-                help: Some("7-bit I2C device address"),
-            },
-            menu::Parameter::Mandatory {
-                parameter_name: "tx_bytes",
-                help: Some("Hex string to transmit"),
-            },
-            menu::Parameter::Mandatory {
-                parameter_name: "rx_count",
-                help: Some("How many bytes to receive"),
-            },
-        ],
+        function: power,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "mode",
+            help: Some("standby, off, reset or bootloader"),
+        }],
+    },
+    command: "power",
+    help: Some("Change the power mode"),
+};
+
+pub static FAULT_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: fault,
+        parameters: &[],
+    },
+    command: "fault",
+    help: Some("Show the last CPU fault"),
+};
+
+pub static TOP_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: top,
+        parameters: &[],
     },
-    command: "i2c",
-    help: Some("Do an I2C transaction on a bus"),
+    command: "top",
+    help: Some("Show how busy the system is, once a second"),
+};
+
+pub static SENSORS_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: sensors,
+        parameters: &[],
+    },
+    command: "sensors",
+    help: Some("Show the voltage and temperature sensors on the I2C buses"),
 };
 
 /// Called when the "lsblk" command is executed.
@@ -158,23 +160,17 @@ fn lsbus(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx:
                 Ok(bios::bus::PeripheralKind::Reserved) => "Reserved",
                 _ => "Unknown",
             };
-            osprintln!("\t{}: {} ({})", dev_idx, device_info.name, kind);
-            found = true;
-        }
-    }
-    if !found {
-        osprintln!("\tNone");
-    }
-}
-
-/// Called when the "lsi2c" command is executed.
-fn lsi2c(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
-    let api = API.get();
-    let mut found = false;
-    osprintln!("I2C Buses:");
-    for dev_idx in 0..=255u8 {
-        if let bios::FfiOption::Some(device_info) = (api.i2c_bus_get_info)(dev_idx) {
-            osprintln!("\t{}: {}", dev_idx, device_info.name);
+            if let Some(driver) = crate::bus::driver_name(dev_idx) {
+                osprintln!(
+                    "\t{}: {} ({}, {} driver)",
+                    dev_idx,
+                    device_info.name,
+                    kind,
+                    driver
+                );
+            } else {
+                osprintln!("\t{}: {} ({})", dev_idx, device_info.name, kind);
+            }
             found = true;
         }
     }
@@ -184,7 +180,7 @@ fn lsi2c(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx:
 }
 
 /// Called when the "lsmem" command is executed.
-fn lsmem(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+fn lsmem(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], ctx: &mut Ctx) {
     let api = API.get();
     let mut found = false;
     osprintln!("Memory regions:");
@@ -197,6 +193,26 @@ fn lsmem(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx:
     if !found {
         osprintln!("\tNone");
     }
+    let tpa = ctx.tpa.as_slice_u8().as_ptr_range();
+    osprintln!(
+        "TPA: 0x{:08x}..0x{:08x} ({} bytes)",
+        tpa.start as usize,
+        tpa.end as usize,
+        tpa.end as usize - tpa.start as usize
+    );
+    osprintln!("Reserved:");
+    for reservation in ctx.tpa.reservations() {
+        osprintln!(
+            "\t0x{:08x}..0x{:08x} ({} bytes) {}",
+            reservation.address,
+            reservation.end(),
+            reservation.size,
+            reservation.name
+        );
+    }
+    if ctx.tpa.reservations().is_empty() {
+        osprintln!("\tNone");
+    }
 }
 
 /// Called when the "lsuart" command is executed.
@@ -222,9 +238,35 @@ fn lsuart(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx
     }
 }
 
+/// Called when the "fault" command is executed.
+fn fault(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    let Some(fault) = crate::fault::last() else {
+        osprintln!("No faults since power on");
+        return;
+    };
+    let frame = &fault.frame;
+    osprintln!("{} (exception {})", fault.name(), fault.exception);
+    osprintln!(
+        "PC =0x{:08x} LR =0x{:08x} SP =0x{:08x} PSR=0x{:08x}",
+        frame.pc,
+        frame.lr,
+        fault.sp,
+        frame.xpsr
+    );
+    osprintln!(
+        "R0 =0x{:08x} R1 =0x{:08x} R2 =0x{:08x} R3 =0x{:08x}",
+        frame.r0,
+        frame.r1,
+        frame.r2,
+        frame.r3
+    );
+    osprintln!("R12=0x{:08x}", frame.r12);
+}
+
 /// Called when the "shutdown" command is executed.
-fn shutdown(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+fn shutdown(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
     let api = API.get();
+    save_history(ctx);
     if let Ok(Some(_)) = menu::argument_finder(item, args, "reboot") {
         osprintln!("Rebooting...");
         (api.power_control)(bios::PowerMode::Reset.make_ffi_safe());
@@ -237,98 +279,154 @@ fn shutdown(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx
     }
 }
 
-/// Called when the "i2c" command is executed.
-fn i2c(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
-    let bus_idx = menu::argument_finder(item, args, "bus_idx").unwrap();
-    let dev_addr = menu::argument_finder(item, args, "dev_addr").unwrap();
-    let tx_bytes = menu::argument_finder(item, args, "tx_bytes").unwrap();
-    let rx_count = menu::argument_finder(item, args, "rx_count").unwrap();
-
-    let (Some(bus_idx), Some(dev_addr), Some(tx_bytes), Some(rx_count)) =
-        (bus_idx, dev_addr, tx_bytes, rx_count)
-    else {
-        osprintln!("Missing arguments.");
-        return;
-    };
-
-    let mut tx_buffer: heapless::Vec<u8, 16> = heapless::Vec::new();
-
-    for hex_pair in tx_bytes.as_bytes().chunks(2) {
-        let Some(top) = hex_digit(hex_pair[0]) else {
-            osprintln!("Bad hex.");
-            return;
-        };
-        let Some(bottom) = hex_digit(hex_pair[1]) else {
-            osprintln!("Bad hex.");
-            return;
-        };
-        let byte = top << 4 | bottom;
-        let Ok(_) = tx_buffer.push(byte) else {
-            osprintln!("Too much hex.");
-            return;
-        };
+/// Called when the "power" command is executed.
+fn power(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let api = API.get();
+    match args.first().cloned() {
+        Some("standby") => {
+            osprintln!("Standing by (press any key to wake up)...");
+            crate::power::standby(&mut ctx.tpa);
+            osprintln!("Awake.");
+        }
+        Some("off") => {
+            save_history(ctx);
+            osprintln!("Shutting down...");
+            (api.power_control)(bios::PowerMode::Off.make_ffi_safe());
+        }
+        Some("reset") => {
+            save_history(ctx);
+            osprintln!("Rebooting...");
+            (api.power_control)(bios::PowerMode::Reset.make_ffi_safe());
+        }
+        Some("bootloader") => {
+            save_history(ctx);
+            osprintln!("Rebooting into bootloader...");
+            (api.power_control)(bios::PowerMode::Bootloader.make_ffi_safe());
+        }
+        Some(mode) => {
+            osprintln!("Unknown power mode {:?}", mode);
+        }
+        None => {
+            osprintln!("Modes: standby, off, reset, bootloader");
+            match ctx.config.get_screen_blank() {
+                Some(mins) => {
+                    osprintln!("Screen blanks after {} min (see `config blank`)", mins);
+                }
+                None => {
+                    osprintln!("Screen blanking is off (see `config blank`)");
+                }
+            }
+        }
     }
+}
 
-    let Ok(bus_idx) = parse_u8(bus_idx) else {
-        osprintln!("Bad bus_idx");
-        return;
-    };
-
-    let Ok(dev_addr) = parse_u8(dev_addr) else {
-        osprintln!("Bad dev_addr");
-        return;
-    };
-
-    let Ok(rx_count) = parse_usize(rx_count) else {
-        osprintln!("Bad rx count.");
+/// Write the shell history to disk before we power off, if the config says
+/// to.
+fn save_history(ctx: &Ctx) {
+    if !ctx.config.get_save_history() {
         return;
-    };
-
-    let mut rx_buf = [0u8; 16];
+    }
+    if let Err(e) = crate::history::save() {
+        osprintln!("Failed to save history: {:?}", e);
+    }
+}
 
-    let Some(rx_buf) = rx_buf.get_mut(0..rx_count) else {
-        osprintln!("Too much rx.");
+/// Called when the "sensors" command is executed.
+fn sensors(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], ctx: &mut Ctx) {
+    let found = crate::sensors::scan();
+    if found.is_empty() {
+        osprintln!("No sensors found");
         return;
-    };
-
-    let api = API.get();
-
-    match (api.i2c_write_read)(
-        bus_idx,
-        dev_addr,
-        tx_buffer.as_slice().into(),
-        bios::FfiByteSlice::empty(),
-        rx_buf.into(),
-    ) {
-        bios::FfiResult::Ok(_) => {
-            osprintln!("Ok, got {:x?}", rx_buf);
-        }
-        bios::FfiResult::Err(e) => {
-            osprintln!("Failed: {:?}", e);
+    }
+    let limits = ctx.config.get_sensor_limits();
+    for (idx, sensor) in found.iter().enumerate() {
+        osprint!(
+            "{}: {} on I2C bus {} at 0x{:02x}: ",
+            idx,
+            sensor.chip,
+            sensor.bus,
+            sensor.address
+        );
+        match crate::sensors::read(sensor) {
+            Ok(reading) => {
+                match limits.and_then(|limits| crate::sensors::Warning::check(&limits, reading)) {
+                    Some(warning) => {
+                        osprintln!("{} ({})", reading, warning);
+                    }
+                    None => {
+                        osprintln!("{}", reading);
+                    }
+                }
+            }
+            Err(e) => {
+                osprintln!("Error {:?}", e);
+            }
         }
     }
+    if limits.is_none() {
+        osprintln!("Set limits with `config sensors` to get warnings");
+    }
 }
 
-/// Convert an ASCII hex digit into a number
-fn hex_digit(input: u8) -> Option<u8> {
-    match input {
-        b'0' => Some(0),
-        b'1' => Some(1),
-        b'2' => Some(2),
-        b'3' => Some(3),
-        b'4' => Some(4),
-        b'5' => Some(5),
-        b'6' => Some(6),
-        b'7' => Some(7),
-        b'8' => Some(8),
-        b'9' => Some(9),
-        b'a' | b'A' => Some(10),
-        b'b' | b'B' => Some(11),
-        b'c' | b'C' => Some(12),
-        b'd' | b'D' => Some(13),
-        b'e' | b'E' => Some(14),
-        b'f' | b'F' => Some(15),
-        _ => None,
+/// Called when the "top" command is executed.
+fn top(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    let api = API.get();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let mut last_ticks = (api.time_ticks_get)().0;
+    let mut last = stats::counters();
+    // Clear the screen and go to the top
+    osprint!("\u{001b}[2J\u{001b}[1;1H");
+    osprintln!("Measuring...");
+    loop {
+        let deadline = last_ticks + ticks_per_second;
+        while (api.time_ticks_get)().0 < deadline {
+            let mut buffer = [0u8; 16];
+            if crate::STD_INPUT.lock().get_data(&mut buffer) > 0 {
+                return;
+            }
+            stats::idle();
+        }
+        let ticks = (api.time_ticks_get)().0;
+        let now = stats::counters();
+        let elapsed = ticks.saturating_sub(last_ticks).max(1);
+        let idle_permille = (now.idle_ticks - last.idle_ticks) * 1000 / elapsed;
+        let per_second = |count: u64| count * ticks_per_second / elapsed;
+        let secs = API.get_uptime().as_secs();
+
+        osprint!("\u{001b}[2J\u{001b}[1;1H");
+        osprintln!("System monitor (press any key to stop)\n");
+        osprintln!(
+            "Uptime     : {} days, {:02}:{:02}:{:02}",
+            secs / 86400,
+            (secs / 3600) % 24,
+            (secs / 60) % 60,
+            secs % 60
+        );
+        osprintln!("Ticks      : {} ({}/s)", ticks, ticks_per_second);
+        osprintln!(
+            "Idle       : {}.{}% ({} calls/s)",
+            idle_permille / 10,
+            idle_permille % 10,
+            per_second(now.idle_calls - last.idle_calls)
+        );
+        osprintln!(
+            "Busy       : {}.{}%",
+            (1000 - idle_permille.min(1000)) / 10,
+            (1000 - idle_permille.min(1000)) % 10
+        );
+        osprintln!(
+            "Audio      : {} bytes/s",
+            per_second(now.audio_bytes - last.audio_bytes)
+        );
+        osprintln!(
+            "Underruns  : {} (+{})",
+            now.audio_underruns,
+            now.audio_underruns - last.audio_underruns
+        );
+        osprintln!("Interrupts : {:#010x}", (api.bus_interrupt_status)());
+
+        last = now;
+        last_ticks = ticks;
     }
 }
 
@@ -0,0 +1,130 @@
+//! I²C related commands for Neotron OS
+
+use crate::{bios, osprintln, Ctx, API};
+
+use super::{hex_digit, parse_u8, parse_usize};
+
+pub static LSI2C_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: lsi2c,
+        parameters: &[],
+    },
+    command: "lsi2c",
+    help: Some("List all the BIOS I2C devices"),
+};
+
+pub static I2C_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: i2c,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "bus_idx",
+                help: Some("I2C bus index"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "dev_addr",
+                help: Some("7-bit I2C device address"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "tx_bytes",
+                help: Some("Hex string to transmit"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "rx_count",
+                help: Some("How many bytes to receive"),
+            },
+        ],
+    },
+    command: "i2c",
+    help: Some("Do an I2C transaction on a bus"),
+};
+
+/// Called when the "lsi2c" command is executed.
+fn lsi2c(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    let api = API.get();
+    let mut found = false;
+    osprintln!("I2C Buses:");
+    for dev_idx in 0..=255u8 {
+        if let bios::FfiOption::Some(device_info) = (api.i2c_bus_get_info)(dev_idx) {
+            osprintln!("\t{}: {}", dev_idx, device_info.name);
+            found = true;
+        }
+    }
+    if !found {
+        osprintln!("\tNone");
+    }
+}
+
+/// Called when the "i2c" command is executed.
+fn i2c(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let bus_idx = menu::argument_finder(item, args, "bus_idx").unwrap();
+    let dev_addr = menu::argument_finder(item, args, "dev_addr").unwrap();
+    let tx_bytes = menu::argument_finder(item, args, "tx_bytes").unwrap();
+    let rx_count = menu::argument_finder(item, args, "rx_count").unwrap();
+
+    let (Some(bus_idx), Some(dev_addr), Some(tx_bytes), Some(rx_count)) =
+        (bus_idx, dev_addr, tx_bytes, rx_count)
+    else {
+        osprintln!("Missing arguments.");
+        return;
+    };
+
+    let mut tx_buffer: heapless::Vec<u8, 16> = heapless::Vec::new();
+
+    for hex_pair in tx_bytes.as_bytes().chunks(2) {
+        let Some(top) = hex_digit(hex_pair[0]) else {
+            osprintln!("Bad hex.");
+            return;
+        };
+        let Some(bottom) = hex_digit(hex_pair[1]) else {
+            osprintln!("Bad hex.");
+            return;
+        };
+        let byte = top << 4 | bottom;
+        let Ok(_) = tx_buffer.push(byte) else {
+            osprintln!("Too much hex.");
+            return;
+        };
+    }
+
+    let Ok(bus_idx) = parse_u8(bus_idx) else {
+        osprintln!("Bad bus_idx");
+        return;
+    };
+
+    let Ok(dev_addr) = parse_u8(dev_addr) else {
+        osprintln!("Bad dev_addr");
+        return;
+    };
+
+    let Ok(rx_count) = parse_usize(rx_count) else {
+        osprintln!("Bad rx count.");
+        return;
+    };
+
+    let mut rx_buf = [0u8; 16];
+
+    let Some(rx_buf) = rx_buf.get_mut(0..rx_count) else {
+        osprintln!("Too much rx.");
+        return;
+    };
+
+    let api = API.get();
+
+    match (api.i2c_write_read)(
+        bus_idx,
+        dev_addr,
+        tx_buffer.as_slice().into(),
+        bios::FfiByteSlice::empty(),
+        rx_buf.into(),
+    ) {
+        bios::FfiResult::Ok(_) => {
+            osprintln!("Ok, got {:x?}", rx_buf);
+        }
+        bios::FfiResult::Err(e) => {
+            osprintln!("Failed: {:?}", e);
+        }
+    }
+}
+
+// End of file
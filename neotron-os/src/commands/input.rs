@@ -1,6 +1,6 @@
 //! Input related commands for Neotron OS
 
-use crate::{osprintln, Ctx};
+use crate::{hidrec, joypad, midi, osprint, osprintln, Ctx};
 
 pub static KBTEST_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
@@ -11,6 +11,51 @@ pub static KBTEST_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("Test the keyboard (press ESC to quit)"),
 };
 
+pub static PADTEST_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: padtest,
+        parameters: &[],
+    },
+    command: "padtest",
+    help: Some("Test the game controllers (press Ctrl-X to quit)"),
+};
+
+pub static MIDIMON_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: midimon,
+        parameters: &[menu::Parameter::Named {
+            parameter_name: "all",
+            help: Some("Also show clock and other real-time messages"),
+        }],
+    },
+    command: "midimon",
+    help: Some("Show the messages arriving on the MIDI port (press Ctrl-X to quit)"),
+};
+
+pub static RECORD_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: record,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "file",
+            help: Some("The file to record to (leave out to stop recording)"),
+        }],
+    },
+    command: "record",
+    help: Some("Record keyboard events to a file, for replaying later"),
+};
+
+pub static REPLAY_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: replay,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "file",
+            help: Some("The file to replay (leave out to stop replaying)"),
+        }],
+    },
+    command: "replay",
+    help: Some("Replay keyboard events from a file, as if they were typed"),
+};
+
 /// Called when the "kbtest" command is executed.
 fn kbtest(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
     osprintln!("Press Ctrl-X to quit");
@@ -43,4 +88,132 @@ fn kbtest(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx
     osprintln!("Finished.");
 }
 
+/// Called when the "padtest" command is executed.
+fn padtest(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    if !joypad::is_supported() {
+        osprintln!("This BIOS doesn't report game controllers");
+        return;
+    }
+    osprintln!("Press Ctrl-X to quit");
+    const CTRL_X: char = '\u{0018}';
+    let mut last_states = [None; joypad::NUM_PADS];
+    loop {
+        for (pad, last_state) in last_states.iter_mut().enumerate() {
+            let state = joypad::get(pad);
+            if state == *last_state {
+                continue;
+            }
+            *last_state = state;
+            let Some(state) = state.filter(|s| s.connected) else {
+                osprintln!("Pad {}: not connected", pad);
+                continue;
+            };
+            osprint!("Pad {}: axes {:?}, buttons", pad, state.axes);
+            for (bit, name) in joypad::BUTTON_NAMES.iter().enumerate() {
+                if state.buttons & (1 << bit) != 0 {
+                    osprint!(" {}", name);
+                }
+            }
+            osprintln!();
+        }
+        if let Some(pc_keyboard::DecodedKey::Unicode(CTRL_X)) = crate::STD_INPUT.lock().get_raw() {
+            break;
+        }
+        crate::stats::idle();
+    }
+    osprintln!("Finished.");
+}
+
+/// Called when the "midimon" command is executed.
+fn midimon(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let show_all = matches!(menu::argument_finder(item, args, "all"), Ok(Some(_)));
+    let port = match midi::open() {
+        Ok(port) => port,
+        Err(midi::Error::NoPort) => {
+            osprintln!("There is no MIDI port. Check `lsuart`.");
+            return;
+        }
+        Err(midi::Error::Bios(e)) => {
+            osprintln!("Failed to set up the MIDI port: {:?}", e);
+            return;
+        }
+    };
+    osprintln!("Watching MIDI on UART {}. Press Ctrl-X to quit", port);
+    const CTRL_X: char = '\u{0018}';
+    let mut parser = midi::Parser::new();
+    let mut buffer = [0u8; 32];
+    loop {
+        let count = match midi::read(port, &mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                osprintln!("Failed to read the MIDI port: {:?}", e);
+                break;
+            }
+        };
+        for byte in &buffer[0..count] {
+            if let Some(message) = parser.feed(*byte) {
+                if show_all || !message.is_realtime() {
+                    osprintln!("{}", message);
+                }
+            }
+        }
+        if let Some(pc_keyboard::DecodedKey::Unicode(CTRL_X)) = crate::STD_INPUT.lock().get_raw() {
+            break;
+        }
+    }
+    osprintln!("Finished.");
+}
+
+/// Called when the "record" command is executed.
+fn record(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    match args.first() {
+        Some(filename) => {
+            if let Some(lost) = hidrec::stop_recording() {
+                osprintln!("Stopped the last recording ({} events lost)", lost);
+            }
+            match hidrec::start_recording(filename) {
+                Ok(()) => {
+                    osprintln!("Recording keyboard events to {}", filename);
+                    osprintln!("Type 'record' to stop.");
+                }
+                Err(e) => {
+                    osprintln!("Error: {:?}", e);
+                }
+            }
+        }
+        None => match hidrec::stop_recording() {
+            Some(lost) => {
+                osprintln!("Stopped recording ({} events lost)", lost);
+            }
+            None => {
+                osprintln!("Not recording");
+            }
+        },
+    }
+}
+
+/// Called when the "replay" command is executed.
+fn replay(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    match args.first() {
+        Some(filename) => {
+            hidrec::stop_replay();
+            match hidrec::start_replay(filename) {
+                Ok(()) => {
+                    osprintln!("Replaying keyboard events from {}", filename);
+                }
+                Err(e) => {
+                    osprintln!("Error: {:?}", e);
+                }
+            }
+        }
+        None => {
+            if hidrec::stop_replay() {
+                osprintln!("Stopped replaying");
+            } else {
+                osprintln!("Not replaying");
+            }
+        }
+    }
+}
+
 // End of file
@@ -0,0 +1,60 @@
+//! System log related commands for Neotron OS
+
+use crate::{log, osprintln, Ctx};
+
+pub static DMESG_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: dmesg,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "level",
+            help: Some("Only show messages this important (error/warn/info/debug), or clear"),
+        }],
+    },
+    command: "dmesg",
+    help: Some("Show the system log"),
+};
+
+pub static LOGLEVEL_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: loglevel,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "level",
+            help: Some("error, warn, info or debug"),
+        }],
+    },
+    command: "loglevel",
+    help: Some("Get/set which log messages are printed on the console"),
+};
+
+/// Called when the "dmesg" command is executed.
+fn dmesg(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    match args.first().cloned() {
+        None => {
+            log::print(None);
+        }
+        Some("clear") => {
+            log::clear();
+        }
+        Some(level_str) => {
+            let Some(level) = log::Level::parse(level_str) else {
+                osprintln!("Give error, warn, info, debug or clear as argument");
+                return;
+            };
+            log::print(Some(level));
+        }
+    }
+}
+
+/// Called when the "loglevel" command is executed.
+fn loglevel(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    if let Some(level_str) = args.first() {
+        let Some(level) = log::Level::parse(level_str) else {
+            osprintln!("Give error, warn, info or debug as argument");
+            return;
+        };
+        log::set_console_level(level);
+    }
+    osprintln!("Console log level is {}", log::console_level().as_str());
+}
+
+// End of file
@@ -4,47 +4,151 @@
 
 pub use super::Ctx;
 
+use crate::{osprint, osprintln};
+
+#[cfg(feature = "basic")]
+mod basic;
+mod bench;
 mod block;
 mod config;
 mod fs;
+#[cfg(feature = "gfx")]
+mod gfx;
 mod hardware;
+#[cfg(feature = "i2c")]
+mod i2c;
 mod input;
+mod log;
+mod module;
+#[cfg(feature = "net")]
+mod net;
 mod ram;
+#[cfg(feature = "romfs")]
+mod rom;
 mod screen;
+mod shell;
+#[cfg(feature = "sound")]
 mod sound;
 mod timedate;
 
+pub use shell::AliasExpander;
+
 pub static OS_MENU: menu::Menu<Ctx> = menu::Menu {
     label: "root",
     items: &[
         &timedate::DATE_ITEM,
+        &timedate::UPTIME_ITEM,
+        &timedate::SETTIME_ITEM,
         &config::COMMAND_ITEM,
+        &config::CONSOLE_ITEM,
         &hardware::LSBLK_ITEM,
         &hardware::LSBUS_ITEM,
-        &hardware::LSI2C_ITEM,
+        #[cfg(feature = "i2c")]
+        &i2c::LSI2C_ITEM,
         &hardware::LSMEM_ITEM,
         &hardware::LSUART_ITEM,
-        &hardware::I2C_ITEM,
+        #[cfg(feature = "i2c")]
+        &i2c::I2C_ITEM,
+        &hardware::TOP_ITEM,
+        &hardware::SENSORS_ITEM,
+        &bench::BENCH_ITEM,
+        &module::LSMOD_ITEM,
+        &module::MODRUN_ITEM,
         &block::READ_ITEM,
+        &block::WRITE_ITEM,
+        &block::EDIT_ITEM,
         &fs::DIR_ITEM,
+        &fs::TREE_ITEM,
         &ram::HEXDUMP_ITEM,
+        &ram::CALC_ITEM,
+        &ram::SAVEMEM_ITEM,
+        &ram::LOADMEM_ITEM,
+        &ram::MEMTEST_ITEM,
         &ram::RUN_ITEM,
+        &ram::PIPE_ITEM,
         &fs::LOAD_ITEM,
         &fs::EXEC_ITEM,
+        &fs::ELFINFO_ITEM,
         &fs::TYPE_ITEM,
-        &fs::ROM_ITEM,
+        &fs::MORE_ITEM,
+        &fs::HEXFILE_ITEM,
+        &fs::SUM_ITEM,
+        &fs::GREP_ITEM,
+        &fs::DIFF_ITEM,
+        #[cfg(feature = "romfs")]
+        &rom::ROM_ITEM,
+        #[cfg(feature = "romfs")]
+        &rom::ROMTYPE_ITEM,
+        #[cfg(feature = "romfs")]
+        &rom::ROMCP_ITEM,
+        &fs::COPY_ITEM,
+        &fs::RAMDISK_ITEM,
+        &fs::ATTRIB_ITEM,
+        &fs::TOUCH_ITEM,
+        &fs::PRINT_ITEM,
+        &fs::SERVE_ITEM,
+        &fs::MOUNT_ITEM,
+        #[cfg(feature = "net")]
+        &net::IFCONFIG_ITEM,
+        #[cfg(feature = "net")]
+        &net::PING_ITEM,
+        #[cfg(feature = "net")]
+        &net::TELNET_ITEM,
         &screen::CLS_ITEM,
         &screen::MODE_ITEM,
-        &screen::GFX_ITEM,
+        #[cfg(feature = "gfx")]
+        &gfx::GFX_ITEM,
+        #[cfg(feature = "gfx")]
+        &gfx::VIEW_ITEM,
+        #[cfg(feature = "gfx")]
+        &gfx::SHOW_ITEM,
+        &screen::SCREENDUMP_ITEM,
+        &screen::SNAP_ITEM,
         &input::KBTEST_ITEM,
+        &input::PADTEST_ITEM,
+        &input::MIDIMON_ITEM,
+        &input::RECORD_ITEM,
+        &input::REPLAY_ITEM,
+        &log::DMESG_ITEM,
+        &log::LOGLEVEL_ITEM,
+        &shell::ALIAS_ITEM,
+        &shell::UNALIAS_ITEM,
+        &shell::COMMANDS_ITEM,
+        &shell::SLEEP_ITEM,
+        &shell::TIMING_ITEM,
+        &shell::HISTORY_ITEM,
+        &shell::WATCH_ITEM,
+        &shell::AT_ITEM,
+        &hardware::FAULT_ITEM,
         &hardware::SHUTDOWN_ITEM,
+        &hardware::POWER_ITEM,
+        #[cfg(feature = "sound")]
         &sound::MIXER_ITEM,
+        #[cfg(feature = "sound")]
         &sound::PLAY_ITEM,
+        #[cfg(feature = "basic")]
+        &basic::BASIC_ITEM,
     ],
     entry: None,
     exit: None,
 };
 
+/// The optional groups of commands, and whether they were compiled in
+const FEATURES: &[(&str, bool)] = &[
+    ("basic", cfg!(feature = "basic")),
+    ("gfx", cfg!(feature = "gfx")),
+    ("i2c", cfg!(feature = "i2c")),
+    ("net", cfg!(feature = "net")),
+    ("romfs", cfg!(feature = "romfs")),
+    ("slides", cfg!(feature = "slides")),
+    ("sound", cfg!(feature = "sound")),
+];
+
+/// Is there a built-in command with this name?
+pub fn is_builtin(name: &str) -> bool {
+    OS_MENU.items.iter().any(|item| item.command == name)
+}
+
 /// Parse a string into a `usize`
 ///
 /// Numbers like `0x123` are hex. Numbers like `123` are decimal.
@@ -84,4 +188,62 @@ fn parse_u64(input: &str) -> Result<u64, core::num::ParseIntError> {
     }
 }
 
+/// Convert an ASCII hex digit into a number
+fn hex_digit(input: u8) -> Option<u8> {
+    match input {
+        b'0' => Some(0),
+        b'1' => Some(1),
+        b'2' => Some(2),
+        b'3' => Some(3),
+        b'4' => Some(4),
+        b'5' => Some(5),
+        b'6' => Some(6),
+        b'7' => Some(7),
+        b'8' => Some(8),
+        b'9' => Some(9),
+        b'a' | b'A' => Some(10),
+        b'b' | b'B' => Some(11),
+        b'c' | b'C' => Some(12),
+        b'd' | b'D' => Some(13),
+        b'e' | b'E' => Some(14),
+        b'f' | b'F' => Some(15),
+        _ => None,
+    }
+}
+
+/// How many bytes `print_hex_line` shows on each line
+const HEX_BYTES_PER_LINE: usize = 16;
+
+/// Print up to `HEX_BYTES_PER_LINE` bytes as hex, and again as ASCII
+fn print_hex_line(address: usize, bytes: &[u8]) {
+    osprint!("{:08x}: ", address);
+    for b in bytes {
+        osprint!("{:02x} ", b);
+    }
+    // Line up the ASCII column on a short last line
+    for _ in bytes.len()..HEX_BYTES_PER_LINE {
+        osprint!("   ");
+    }
+    osprint!("|");
+    for b in bytes {
+        let ch = if b.is_ascii_graphic() || *b == b' ' {
+            *b as char
+        } else {
+            '.'
+        };
+        osprint!("{}", ch);
+    }
+    osprintln!("|");
+}
+
+/// Block until a key is pressed on the keyboard
+fn wait_for_key() -> pc_keyboard::DecodedKey {
+    loop {
+        if let Some(key) = crate::STD_INPUT.lock().get_raw() {
+            return key;
+        }
+        crate::stats::idle();
+    }
+}
+
 // End of file
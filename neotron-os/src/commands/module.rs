@@ -0,0 +1,84 @@
+//! OS module related commands for Neotron OS
+
+use crate::{osprint, osprintln, Ctx};
+
+pub static LSMOD_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: lsmod,
+        parameters: &[],
+    },
+    command: "lsmod",
+    help: Some("List the OS modules, and their commands"),
+};
+
+pub static MODRUN_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: modrun,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "command",
+                help: Some("The module command to run"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg1",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg2",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg3",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg4",
+                help: Some("An argument for the command"),
+            },
+        ],
+    },
+    command: "modrun",
+    help: Some("Run a command from an OS module (typing its name does this for you)"),
+};
+
+/// Called when the "lsmod" command is executed.
+fn lsmod(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    let mut found = false;
+    osprintln!("OS Modules:");
+    crate::module::for_each(|module, commands| {
+        osprint!(
+            "\t{:12} {:6} bytes @ 0x{:08x}",
+            module.name,
+            module.size,
+            module.address
+        );
+        if module.started {
+            osprintln!();
+        } else {
+            osprintln!(" (failed to start)");
+        }
+        for (name, help) in commands {
+            osprintln!("\t\t{:16} {}", name, help);
+        }
+        found = true;
+    });
+    if !found {
+        osprintln!("\tNone");
+    }
+}
+
+/// Called when the "modrun" command is executed.
+fn modrun(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    // index can't panic - we always have enough args
+    match crate::module::run_command(args[0], &args[1..]) {
+        Some(0) => {}
+        Some(code) => {
+            osprintln!(log: Warn, "Command exited with error code {}", code);
+        }
+        None => {
+            osprintln!("Unknown module command {:?}", args[0]);
+        }
+    }
+}
+
+// End of file
@@ -0,0 +1,292 @@
+//! Networking related commands for Neotron OS
+
+use crate::{net, osprint, osprintln, Ctx, API};
+
+pub static IFCONFIG_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: ifconfig,
+        parameters: &[
+            menu::Parameter::Optional {
+                parameter_name: "uart",
+                help: Some("Which UART to run SLIP on (or `down` to stop)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "baud",
+                help: Some("How fast the UART runs"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "address",
+                help: Some("Our address and prefix length, like 10.0.0.2/24"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "gateway",
+                help: Some("Where to send packets for other networks"),
+            },
+        ],
+    },
+    command: "ifconfig",
+    help: Some("Show or set up the SLIP network interface"),
+};
+
+pub static PING_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: ping,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "address",
+                help: Some("The IPv4 address to ping"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "count",
+                help: Some("How many pings to send (default 4)"),
+            },
+        ],
+    },
+    command: "ping",
+    help: Some("Check another machine is reachable"),
+};
+
+pub static TELNET_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: telnet,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "address",
+                help: Some("The IPv4 address to connect to"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "port",
+                help: Some("The TCP port to connect to (default 23)"),
+            },
+        ],
+    },
+    command: "telnet",
+    help: Some("Log in to another machine"),
+};
+
+/// How long we wait for each ping to come back, in milliseconds
+const PING_TIMEOUT_MS: u128 = 1000;
+
+/// Called when the "ifconfig" command is executed.
+fn ifconfig(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    match args {
+        [] => match net::status() {
+            Some((settings, stats)) => {
+                osprintln!(
+                    "sl0: SLIP on UART {} at {} bps",
+                    settings.port,
+                    settings.baud
+                );
+                osprintln!("  Address : {}", settings.address);
+                match settings.gateway {
+                    Some(gateway) => {
+                        osprintln!("  Gateway : {}", gateway);
+                    }
+                    None => {
+                        osprintln!("  Gateway : None");
+                    }
+                }
+                osprintln!(
+                    "  RX      : {} packets ({} errors)",
+                    stats.rx_packets,
+                    stats.rx_errors
+                );
+                osprintln!("  TX      : {} packets", stats.tx_packets);
+            }
+            None => {
+                osprintln!("sl0: down");
+            }
+        },
+        ["down"] => {
+            // Stopping can't fail
+            let _ = net::configure(&mut ctx.tpa, None);
+            osprintln!("sl0: down");
+        }
+        [port, baud, address, rest @ ..] if rest.len() <= 1 => {
+            let (Ok(port), Ok(baud)) = (port.parse::<u8>(), baud.parse::<u32>()) else {
+                osprintln!("Give the UART number and baud rate as numbers");
+                return;
+            };
+            let Ok(address) = address.parse() else {
+                osprintln!("Give the address like 10.0.0.2/24");
+                return;
+            };
+            let gateway = match rest.first() {
+                Some(gateway) => match net::parse_address(gateway) {
+                    Some(gateway) => Some(gateway),
+                    None => {
+                        osprintln!("Give the gateway like 10.0.0.1");
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let settings = net::Settings {
+                port,
+                baud,
+                address,
+                gateway,
+            };
+            match net::configure(&mut ctx.tpa, Some(settings)) {
+                Ok(()) => {
+                    osprintln!("sl0: {} on UART {} at {} bps", address, port, baud);
+                }
+                Err(e) => {
+                    osprintln!("Error: {}", e);
+                }
+            }
+        }
+        _ => {
+            osprintln!("Give no arguments, `down`, or a UART, baud rate and address");
+        }
+    }
+}
+
+/// Called when the "ping" command is executed.
+fn ping(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(Some(address)) = menu::argument_finder(item, args, "address") else {
+        osprintln!("Missing address");
+        return;
+    };
+    let Some(target) = net::parse_address(address) else {
+        osprintln!("Give the address like 10.0.0.1");
+        return;
+    };
+    let count = match menu::argument_finder(item, args, "count") {
+        Ok(Some(count)) => match count.parse::<u16>() {
+            Ok(count) => count,
+            Err(_e) => {
+                osprintln!("Bad count");
+                return;
+            }
+        },
+        _ => 4,
+    };
+    osprintln!("Pinging {}. Press Ctrl-X to stop.", target);
+    const CTRL_X: char = '\u{0018}';
+    let mut sent = 0;
+    let mut received = 0;
+    'pings: for seq_no in 0..count {
+        if let Err(e) = net::ping_send(target, seq_no) {
+            osprintln!("Error: {}", e);
+            return;
+        }
+        sent += 1;
+        let start = API.get_uptime();
+        loop {
+            net::poll();
+            let elapsed = (API.get_uptime() - start).as_millis();
+            if net::ping_reply() == Some(seq_no) {
+                osprintln!("Reply from {}: seq={} time={} ms", target, seq_no, elapsed);
+                received += 1;
+                // Wait out the rest of the second, so we don't flood
+                while (API.get_uptime() - start).as_millis() < PING_TIMEOUT_MS {
+                    net::poll();
+                }
+                break;
+            }
+            if elapsed >= PING_TIMEOUT_MS {
+                osprintln!("Timed out: seq={}", seq_no);
+                break;
+            }
+            if let Some(pc_keyboard::DecodedKey::Unicode(CTRL_X)) =
+                crate::STD_INPUT.lock().get_raw()
+            {
+                break 'pings;
+            }
+        }
+    }
+    osprintln!("{} sent, {} received", sent, received);
+}
+
+/// Called when the "telnet" command is executed.
+fn telnet(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(Some(address)) = menu::argument_finder(item, args, "address") else {
+        osprintln!("Missing address");
+        return;
+    };
+    let port = match menu::argument_finder(item, args, "port") {
+        Ok(Some(port)) => port,
+        _ => "23",
+    };
+    let mut endpoint: heapless::String<32> = heapless::String::new();
+    let _ = core::fmt::write(&mut endpoint, format_args!("{}:{}", address, port));
+    let Some(remote) = net::parse_endpoint(&endpoint) else {
+        osprintln!("Give the address like 10.0.0.1, and the port as a number");
+        return;
+    };
+    let connection = match net::connect(remote) {
+        Ok(connection) => connection,
+        Err(e) => {
+            osprintln!("Error: {}", e);
+            return;
+        }
+    };
+    osprintln!("Connecting to {}. Press Ctrl-] to quit.", remote);
+    const CTRL_BRACKET: char = '\u{001D}';
+    let mut filter = net::TelnetFilter::new();
+    let mut buffer = [0u8; 64];
+    let mut was_connected = false;
+    loop {
+        match connection.state() {
+            net::ConnectionState::Connected if !was_connected => {
+                osprintln!("Connected.");
+                was_connected = true;
+            }
+            net::ConnectionState::Closed if !was_connected => {
+                osprintln!("Failed to connect.");
+                return;
+            }
+            _ => {}
+        }
+        let count = match connection.read(&mut buffer) {
+            Ok(count) => count,
+            Err(_e) => {
+                osprintln!();
+                osprintln!("Connection closed.");
+                return;
+            }
+        };
+        let mut text: heapless::Vec<u8, 64> = heapless::Vec::new();
+        let mut replies: heapless::Vec<u8, 192> = heapless::Vec::new();
+        for byte in &buffer[0..count] {
+            if let Some(b) = filter.feed(*byte, |reply| {
+                let _ = replies.extend_from_slice(&reply);
+            }) {
+                // We only take as many bytes as we have room for
+                let _ = text.push(b);
+            }
+        }
+        match core::str::from_utf8(&text) {
+            Ok(s) => osprint!("{}", s),
+            Err(_e) => {
+                // Assume it's Latin-1
+                for b in &text {
+                    osprint!("{}", char::from(*b));
+                }
+            }
+        }
+        if !replies.is_empty() && connection.write(&replies).is_err() {
+            osprintln!();
+            osprintln!("Connection closed.");
+            return;
+        }
+        let key = crate::STD_INPUT.lock().get_raw();
+        let mut utf8 = [0u8; 4];
+        let out: &[u8] = match key {
+            Some(pc_keyboard::DecodedKey::Unicode(CTRL_BRACKET)) => break,
+            Some(pc_keyboard::DecodedKey::Unicode('\n' | '\r')) => b"\r\n",
+            Some(pc_keyboard::DecodedKey::Unicode(ch)) => ch.encode_utf8(&mut utf8).as_bytes(),
+            _ => &[],
+        };
+        if !out.is_empty() && connection.write(out).is_err() {
+            osprintln!();
+            osprintln!("Connection closed.");
+            return;
+        }
+    }
+    osprintln!();
+    osprintln!("Disconnected.");
+}
+
+// End of file
@@ -1,7 +1,11 @@
 //! Raw RAM read/write related commands for Neotron OS
 
-use super::parse_usize;
-use crate::{osprint, osprintln, Ctx};
+use super::{parse_u8, parse_usize, print_hex_line, HEX_BYTES_PER_LINE};
+use crate::{
+    bios, calc, osprintln,
+    program::{Redirects, Reservation, MAX_RESERVATIONS},
+    Ctx, API, FILESYSTEM,
+};
 
 pub static HEXDUMP_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
@@ -21,6 +25,106 @@ pub static HEXDUMP_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("Dump the contents of RAM as hex"),
 };
 
+pub static SAVEMEM_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: savemem,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "address",
+                help: Some("Start address"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "length",
+                help: Some("Number of bytes"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to write"),
+            },
+        ],
+    },
+    command: "savemem",
+    help: Some("Save a region of RAM to a file"),
+};
+
+pub static LOADMEM_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: loadmem,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "file",
+                help: Some("The file to read"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "address",
+                help: Some("Where to put it"),
+            },
+            menu::Parameter::Named {
+                parameter_name: "force",
+                help: Some("Allow addresses outside the TPA"),
+            },
+        ],
+    },
+    command: "loadmem",
+    help: Some("Load a file into RAM at an address"),
+};
+
+pub static CALC_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: calc,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "expr",
+                help: Some("Expression, like 0x2000+16*4"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more1",
+                help: Some("More of the expression"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more2",
+                help: Some("More of the expression"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more3",
+                help: Some("More of the expression"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more4",
+                help: Some("More of the expression"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more5",
+                help: Some("More of the expression"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "more6",
+                help: Some("More of the expression"),
+            },
+        ],
+    },
+    command: "calc",
+    help: Some("Work out an integer or fixed-point expression"),
+};
+
+pub static MEMTEST_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: memtest,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "region",
+                help: Some("Which memory region to test (see lsmem)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "pattern",
+                help: Some("walk, addr, random or all"),
+            },
+        ],
+    },
+    command: "memtest",
+    help: Some("Test a region of RAM"),
+};
+
 pub static RUN_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: run,
@@ -41,19 +145,126 @@ pub static RUN_ITEM: menu::Item<Ctx> = menu::Item {
                 parameter_name: "arg4",
                 help: None,
             },
+            menu::Parameter::Optional {
+                parameter_name: "arg5",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg6",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg7",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg8",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg9",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg10",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg11",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg12",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg13",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg14",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg15",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg16",
+                help: None,
+            },
         ],
     },
     command: "run",
-    help: Some("Run a program (with up to four arguments)"),
+    help: Some("Run a program (with up to 16 arguments - use \"quotes\" for spaces, and < file, > file or 2> file to redirect)"),
+};
+
+pub static PIPE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: pipe,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "program1",
+                help: Some("The program whose output is piped"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg1",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg2",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg3",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg4",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg5",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg6",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg7",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg8",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg9",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg10",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg11",
+                help: None,
+            },
+        ],
+    },
+    command: "pipe",
+    help: Some("Run two programs, the output of one going into the other (pipe a.elf x | b.elf y)"),
 };
 
+/// Where `pipe` keeps the output of the first program
+const PIPE_FILE: &str = "PIPE.TMP";
+
 /// Called when the "hexdump" command is executed.
 ///
 /// If you ask for an address that generates a HardFault, the OS will crash. So
 /// don't.
 fn hexdump(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
-    const BYTES_PER_LINE: usize = 16;
-
     let Some(address_str) = args.first() else {
         osprintln!("No address");
         return;
@@ -68,39 +279,611 @@ fn hexdump(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx
         return;
     };
 
-    let mut ptr = address as *const u8;
+    let mut line = [0u8; HEX_BYTES_PER_LINE];
+    for line_start in (0..len).step_by(HEX_BYTES_PER_LINE) {
+        let line_len = HEX_BYTES_PER_LINE.min(len - line_start);
+        // Dumping the top of memory carries on at address zero
+        let line_address = address.wrapping_add(line_start);
+        for (idx, slot) in line[0..line_len].iter_mut().enumerate() {
+            let ptr = line_address.wrapping_add(idx) as *const u8;
+            *slot = unsafe { ptr.read_volatile() };
+        }
+        print_hex_line(line_address, &line[0..line_len]);
+    }
+}
 
-    let mut this_line = 0;
-    osprint!("{:08x}: ", address);
-    for count in 0..len {
-        if this_line == BYTES_PER_LINE {
-            osprintln!();
-            osprint!("{:08x}: ", address + count);
-            this_line = 1;
-        } else {
-            this_line += 1;
+/// How many bytes `savemem` and `loadmem` copy at a time
+const MEM_CHUNK_LEN: usize = 256;
+
+/// Called when the "savemem" command is executed.
+fn savemem(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(address: usize, len: usize, filename: &str) -> Result<(), crate::fs::Error> {
+        let file =
+            FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+        let mut chunk = [0u8; MEM_CHUNK_LEN];
+        for chunk_start in (0..len).step_by(MEM_CHUNK_LEN) {
+            let chunk_len = MEM_CHUNK_LEN.min(len - chunk_start);
+            for (idx, slot) in chunk[0..chunk_len].iter_mut().enumerate() {
+                let ptr = (address + chunk_start + idx) as *const u8;
+                *slot = unsafe { ptr.read_volatile() };
+            }
+            file.write(&chunk[0..chunk_len])?;
         }
+        Ok(())
+    }
+
+    let (Ok(Some(address_str)), Ok(Some(len_str)), Ok(Some(filename))) = (
+        menu::argument_finder(item, args, "address"),
+        menu::argument_finder(item, args, "length"),
+        menu::argument_finder(item, args, "file"),
+    ) else {
+        osprintln!("Need an address, a length and a filename");
+        return;
+    };
+    let Ok(address) = parse_usize(address_str) else {
+        osprintln!("Bad address");
+        return;
+    };
+    let Some(len) = parse_usize(len_str)
+        .ok()
+        .filter(|len| address.checked_add(*len).is_some())
+    else {
+        osprintln!("Bad length");
+        return;
+    };
+    match work(address, len, filename) {
+        Ok(()) => {
+            osprintln!("Saved {} bytes from 0x{:08x} to {}", len, address, filename);
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Called when the "loadmem" command is executed.
+///
+/// Unless `--force` is given, the file must fit in the TPA, so we don't
+/// scribble over the OS.
+fn loadmem(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    fn work(file: &crate::fs::File, address: usize) -> Result<usize, crate::fs::Error> {
+        let mut chunk = [0u8; MEM_CHUNK_LEN];
+        let mut total = 0;
+        loop {
+            let count = file.read(&mut chunk)?;
+            if count == 0 {
+                return Ok(total);
+            }
+            for (idx, b) in chunk[0..count].iter().enumerate() {
+                let ptr = (address + total + idx) as *mut u8;
+                unsafe { ptr.write_volatile(*b) };
+            }
+            total += count;
+        }
+    }
+
+    let (Ok(Some(filename)), Ok(Some(address_str))) = (
+        menu::argument_finder(item, args, "file"),
+        menu::argument_finder(item, args, "address"),
+    ) else {
+        osprintln!("Need a filename and an address");
+        return;
+    };
+    let force = matches!(menu::argument_finder(item, args, "force"), Ok(Some(_)));
+    let Ok(address) = parse_usize(address_str) else {
+        osprintln!("Bad address");
+        return;
+    };
+    let file = match FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly) {
+        Ok(file) => file,
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+            return;
+        }
+    };
+    let len = file.length() as usize;
+    let Some(end) = address.checked_add(len) else {
+        osprintln!("File doesn't fit at that address");
+        return;
+    };
+    let tpa = ctx.tpa.as_slice_u8().as_ptr_range();
+    let in_tpa = address >= tpa.start as usize && end <= tpa.end as usize;
+    if !in_tpa && !force {
+        osprintln!(
+            "0x{:08x}..0x{:08x} is outside the TPA (0x{:08x}..0x{:08x}). Use --force to load it anyway.",
+            address,
+            end,
+            tpa.start as usize,
+            tpa.end as usize
+        );
+        return;
+    }
+    match work(&file, address) {
+        Ok(count) => {
+            osprintln!(
+                "Loaded {} bytes from {} to 0x{:08x}",
+                count,
+                filename,
+                address
+            );
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
 
-        let b = unsafe { ptr.read_volatile() };
-        osprint!("{:02x} ", b);
-        ptr = unsafe { ptr.offset(1) };
+/// Called when the "calc" command is executed.
+///
+/// The menu splits the expression up at the spaces, so we glue it back
+/// together.
+fn calc(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let mut expression: heapless::String<128> = heapless::String::new();
+    for arg in args {
+        if expression.push_str(arg).is_err() || expression.push(' ').is_err() {
+            osprintln!("Expression too long");
+            return;
+        }
+    }
+    match calc::evaluate(&expression) {
+        Ok(value) => {
+            osprintln!("= {}", value);
+        }
+        Err(e) => {
+            osprintln!("{}", e);
+        }
     }
-    osprintln!();
+}
+
+/// How many words of RAM we test at a time
+const MEMTEST_CHUNK_WORDS: usize = 64;
+
+/// How many bad reads we print before we just count them
+const MEMTEST_MAX_REPORTED: usize = 16;
+
+/// The patterns `memtest` can write to RAM
+#[derive(Debug, Copy, Clone)]
+enum Pattern {
+    /// A single set bit, in a different place in each word
+    WalkingOnes,
+    /// Each word holds its own address
+    AddressInAddress,
+    /// Pseudorandom numbers
+    Random,
+}
+
+impl Pattern {
+    /// What we call this pattern
+    fn name(self) -> &'static str {
+        match self {
+            Pattern::WalkingOnes => "walking ones",
+            Pattern::AddressInAddress => "address-in-address",
+            Pattern::Random => "pseudorandom",
+        }
+    }
+
+    /// What value goes in the word at `idx`, within a chunk at `base`?
+    ///
+    /// The `pass` lets each pattern vary from one pass to the next.
+    fn value(self, base: *mut u32, idx: usize, pass: u32) -> u32 {
+        match self {
+            Pattern::WalkingOnes => 1 << ((idx as u32 + pass) % 32),
+            Pattern::AddressInAddress => base.wrapping_add(idx) as u32,
+            Pattern::Random => {
+                // xorshift32, seeded from the address and the pass
+                let mut x = ((base.wrapping_add(idx) as u32) ^ pass.wrapping_mul(0x9E37_79B9)) | 1;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x
+            }
+        }
+    }
+
+    /// How many times do we run this pattern over each chunk?
+    fn passes(self) -> u32 {
+        match self {
+            Pattern::WalkingOnes => 32,
+            Pattern::AddressInAddress => 1,
+            Pattern::Random => 4,
+        }
+    }
+}
+
+/// Called when the "memtest" command is executed.
+fn memtest(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let Ok(region_idx) = parse_u8(args[0]) else {
+        osprintln!("Bad region");
+        return;
+    };
+    let patterns: &[Pattern] = match args.get(1).cloned() {
+        None | Some("all") => &[
+            Pattern::WalkingOnes,
+            Pattern::AddressInAddress,
+            Pattern::Random,
+        ],
+        Some("walk") => &[Pattern::WalkingOnes],
+        Some("addr") => &[Pattern::AddressInAddress],
+        Some("random") => &[Pattern::Random],
+        _ => {
+            osprintln!("Pattern must be walk, addr, random or all");
+            return;
+        }
+    };
+    let api = API.get();
+    let bios::FfiOption::Some(region) = (api.memory_get_region)(region_idx) else {
+        osprintln!("No such region");
+        return;
+    };
+    if !matches!(region.kind.make_safe(), Ok(bios::MemoryKind::Ram)) {
+        osprintln!("Region {} is not RAM we can test", region_idx);
+        return;
+    }
+    // Region 0 starts with the OS's own RAM, which we can't scribble over,
+    // so there we only test the TPA
+    let (region_start, region_end) = if region_idx == 0 {
+        let tpa = ctx.tpa.as_slice_u32().as_ptr_range();
+        (tpa.start as usize, tpa.end as usize)
+    } else {
+        let start = region.start as usize;
+        (start, start.saturating_add(region.length))
+    };
+    // We only test whole words, and nothing the OS has reserved
+    let spans = memtest_spans(
+        region_start.next_multiple_of(4),
+        region_end & !3,
+        ctx.tpa.reservations(),
+    );
+
+    let mut errors = 0;
+    for pattern in patterns {
+        for &(span_start, span_end) in spans.iter() {
+            osprintln!(
+                "Testing 0x{:08x}..0x{:08x} with {}...",
+                span_start,
+                span_end,
+                pattern.name()
+            );
+            let words = (span_end - span_start) / 4;
+            let start = span_start as *mut u32;
+            for chunk_start in (0..words).step_by(MEMTEST_CHUNK_WORDS) {
+                let len = MEMTEST_CHUNK_WORDS.min(words - chunk_start);
+                let chunk = start.wrapping_add(chunk_start);
+                // Safety: the BIOS says this region is RAM, it isn't ours or
+                // reserved, and we put back whatever was there before.
+                errors += unsafe { memtest_chunk(chunk, len, *pattern, errors) };
+                crate::watchdog::kick();
+            }
+        }
+    }
+    if errors == 0 {
+        osprintln!("No errors found");
+    } else {
+        osprintln!(log: Error, "Found {} errors in region {}", errors, region_idx);
+    }
+}
+
+/// Split `start..end` into the pieces that don't overlap any of the
+/// `reservations` (which are highest first), highest first.
+fn memtest_spans(
+    start: usize,
+    end: usize,
+    reservations: &[Reservation],
+) -> heapless::Vec<(usize, usize), { MAX_RESERVATIONS + 1 }> {
+    let mut spans = heapless::Vec::new();
+    let mut top = end;
+    for reservation in reservations {
+        let reserved_start = reservation.address.max(start);
+        let reserved_end = reservation.end().min(top);
+        if reserved_start >= reserved_end {
+            // Not in what we have left to test
+            continue;
+        }
+        if reserved_end < top {
+            // Can't fail - there's one more span than reservations
+            let _ = spans.push((reserved_end, top));
+        }
+        top = reserved_start;
+    }
+    if start < top {
+        let _ = spans.push((start, top));
+    }
+    spans
+}
+
+/// Test some RAM with a pattern, putting back what was there before.
+///
+/// Returns how many bad reads we found. We only print details of the bad
+/// reads if `already_found` is small.
+///
+/// # Safety
+///
+/// `chunk` must point at `len` words of RAM, no more than
+/// `MEMTEST_CHUNK_WORDS`, which nothing else will touch whilst we test it.
+unsafe fn memtest_chunk(
+    chunk: *mut u32,
+    len: usize,
+    pattern: Pattern,
+    already_found: usize,
+) -> usize {
+    let mut saved = [0u32; MEMTEST_CHUNK_WORDS];
+    for (idx, slot) in saved[0..len].iter_mut().enumerate() {
+        *slot = chunk.add(idx).read_volatile();
+    }
+    let mut errors = 0;
+    for pass in 0..pattern.passes() {
+        for idx in 0..len {
+            chunk
+                .add(idx)
+                .write_volatile(pattern.value(chunk, idx, pass));
+        }
+        for idx in 0..len {
+            let expected = pattern.value(chunk, idx, pass);
+            let found = chunk.add(idx).read_volatile();
+            if found != expected {
+                if already_found + errors < MEMTEST_MAX_REPORTED {
+                    osprintln!(
+                        "Bad read @ {:p}: wrote 0x{:08x}, read 0x{:08x}",
+                        chunk.add(idx),
+                        expected,
+                        found
+                    );
+                }
+                errors += 1;
+            }
+        }
+    }
+    for (idx, value) in saved[0..len].iter().enumerate() {
+        chunk.add(idx).write_volatile(*value);
+    }
+    errors
 }
 
 /// Called when the "run" command is executed.
 fn run(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
-    match ctx.tpa.execute(args) {
+    let mut program_args: heapless::Vec<&str, { crate::program::MAX_ARGS }> = heapless::Vec::new();
+    let redirects = match open_redirects(args, &mut program_args) {
+        Ok(redirects) => redirects,
+        Err(e) => {
+            osprintln!("{}", e);
+            return;
+        }
+    };
+    let mut buffer = [0u8; 256];
+    let mut unquoted = heapless::Vec::new();
+    if let Err(e) = unquote(&program_args, &mut buffer, &mut unquoted) {
+        osprintln!("{}", e);
+        return;
+    }
+    report_exit(ctx.tpa.execute(&unquoted, redirects));
+}
+
+/// Open the files for any `< file`, `> file` or `2> file` in the arguments.
+///
+/// Everything else goes into `program_args`.
+fn open_redirects<'a>(
+    args: &[&'a str],
+    program_args: &mut heapless::Vec<&'a str, { crate::program::MAX_ARGS }>,
+) -> Result<Redirects, &'static str> {
+    let mut redirects = Redirects::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (slot, mode) = match *arg {
+            "<" => (&mut redirects.stdin, embedded_sdmmc::Mode::ReadOnly),
+            ">" => (
+                &mut redirects.stdout,
+                embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+            ),
+            "2>" => (
+                &mut redirects.stderr,
+                embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+            ),
+            _ => {
+                program_args.push(arg).map_err(|_| "Too many arguments")?;
+                continue;
+            }
+        };
+        let file_name = args.next().ok_or("Missing file name to redirect to")?;
+        let file = crate::FILESYSTEM
+            .open_file(file_name, mode)
+            .map_err(|e| match e {
+                crate::fs::Error::AlreadyOpen => "Redirected file is already open",
+                _ => "Can't open redirected file",
+            })?;
+        *slot = Some(file);
+    }
+    Ok(redirects)
+}
+
+/// Called when the "pipe" command is executed.
+///
+/// We can only have one program in memory at a time, so the first program
+/// runs to completion with its output going into a file, and then the second
+/// program runs with its input coming from that file.
+fn pipe(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let Some(split) = args.iter().position(|arg| *arg == "|") else {
+        osprintln!("Need a '|' between the two programs");
+        return;
+    };
+    let (first, second) = (&args[..split], &args[split + 1..]);
+    if first.is_empty() || second.is_empty() {
+        osprintln!("Need a program on each side of the '|'");
+        return;
+    }
+    // If the first program didn't run, it didn't make the pipe file either
+    let Some(first_result) = pipe_stage(ctx, first, false) else {
+        return;
+    };
+    // The pipe exits however the second program does, so we only mention
+    // the first one if it went wrong
+    if !matches!(first_result, Ok(0)) {
+        report_exit(first_result);
+    }
+    if let Some(result) = pipe_stage(ctx, second, true) {
+        report_exit(result);
+    }
+    if let Err(e) = crate::FILESYSTEM.delete_file(PIPE_FILE) {
+        osprintln!("Couldn't delete {}: {:?}", PIPE_FILE, e);
+    }
+}
+
+/// Load and run one of the programs in a pipe.
+///
+/// The first program writes to [`PIPE_FILE`], and the second program
+/// (`reads_pipe`) reads from it. Gives back how the program exited, or
+/// `None` if it didn't run (having said why).
+fn pipe_stage(
+    ctx: &mut Ctx,
+    args: &[&str],
+    reads_pipe: bool,
+) -> Option<Result<i32, crate::program::Error>> {
+    let mut buffer = [0u8; 256];
+    let mut unquoted = heapless::Vec::new();
+    if let Err(e) = unquote(args, &mut buffer, &mut unquoted) {
+        osprintln!("{}", e);
+        return None;
+    }
+    // There's always at least one argument - the program name
+    if let Err(e) = ctx.tpa.load_program(unquoted[0]) {
+        osprintln!(log: Error, "Error loading {:?}: {}", unquoted[0], e);
+        return None;
+    }
+    let mode = if reads_pipe {
+        embedded_sdmmc::Mode::ReadOnly
+    } else {
+        embedded_sdmmc::Mode::ReadWriteCreateOrTruncate
+    };
+    let file = match crate::FILESYSTEM.open_file(PIPE_FILE, mode) {
+        Ok(file) => file,
+        Err(e) => {
+            osprintln!("Can't open {}: {:?}", PIPE_FILE, e);
+            return None;
+        }
+    };
+    let redirects = if reads_pipe {
+        Redirects {
+            stdin: Some(file),
+            ..Default::default()
+        }
+    } else {
+        Redirects {
+            stdout: Some(file),
+            ..Default::default()
+        }
+    };
+    Some(ctx.tpa.execute(&unquoted[1..], redirects))
+}
+
+/// Tell the user how a program got on.
+fn report_exit(result: Result<i32, crate::program::Error>) {
+    match result {
         Ok(0) => {
             osprintln!();
         }
         Ok(n) => {
-            osprintln!("\nError Code: {}", n);
+            osprintln!();
+            osprintln!(log: Warn, "Program exited with error code {}", n);
+        }
+        Err(crate::program::Error::StackSmashed) => {
+            osprintln!();
+            osprintln!(
+                log: Error,
+                "Stack smashed! The program overflowed its stack, so the OS may be damaged."
+            );
         }
         Err(e) => {
-            osprintln!("\nFailed to execute: {:?}", e);
+            osprintln!();
+            osprintln!(log: Error, "Failed to execute: {}", e);
+        }
+    }
+}
+
+/// Join up arguments that the user put in double quotes.
+///
+/// The menu splits our command line on whitespace, so `"two words"` arrives
+/// as `"two` and `words"`. We put the pieces back together in `buffer`,
+/// with a single space between them.
+fn unquote<'a>(
+    args: &[&str],
+    buffer: &'a mut [u8],
+    out: &mut heapless::Vec<&'a str, { crate::program::MAX_ARGS }>,
+) -> Result<(), &'static str> {
+    let mut ranges: heapless::Vec<(usize, usize), { crate::program::MAX_ARGS }> =
+        heapless::Vec::new();
+    let mut used = 0;
+    let mut start = 0;
+    let mut in_quotes = false;
+    for arg in args {
+        let mut text = *arg;
+        if in_quotes {
+            // Put back the space the menu took out
+            *buffer.get_mut(used).ok_or("Arguments too long")? = b' ';
+            used += 1;
+        } else {
+            start = used;
+            if let Some(rest) = text.strip_prefix('"') {
+                in_quotes = true;
+                text = rest;
+            }
+        }
+        if in_quotes {
+            if let Some(rest) = text.strip_suffix('"') {
+                in_quotes = false;
+                text = rest;
+            }
+        }
+        buffer
+            .get_mut(used..used + text.len())
+            .ok_or("Arguments too long")?
+            .copy_from_slice(text.as_bytes());
+        used += text.len();
+        if !in_quotes {
+            ranges
+                .push((start, used))
+                .map_err(|_| "Too many arguments")?;
         }
     }
+    if in_quotes {
+        return Err("Missing closing quote");
+    }
+    let buffer = &*buffer;
+    for (start, end) in ranges {
+        // We only split on ASCII characters, so this is still valid UTF-8
+        let arg = core::str::from_utf8(&buffer[start..end]).map_err(|_| "Bad argument")?;
+        out.push(arg).map_err(|_| "Too many arguments")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reservation(address: usize, size: usize) -> Reservation {
+        Reservation {
+            name: heapless::String::new(),
+            address,
+            size,
+        }
+    }
+
+    #[test]
+    fn memtest_skips_reservations() {
+        let reservations = [reservation(0x3000, 0x100), reservation(0x2000, 0x800)];
+        assert_eq!(
+            memtest_spans(0x1000, 0x4000, &reservations).as_slice(),
+            &[(0x3100, 0x4000), (0x2800, 0x3000), (0x1000, 0x2000)]
+        );
+        // Reservations at the very top, and outside the range
+        let reservations = [reservation(0x5000, 0x100), reservation(0x3C00, 0x400)];
+        assert_eq!(
+            memtest_spans(0x1000, 0x4000, &reservations).as_slice(),
+            &[(0x1000, 0x3C00)]
+        );
+        // Nothing left at all
+        let reservations = [reservation(0x0, 0x5000)];
+        assert!(memtest_spans(0x1000, 0x4000, &reservations).is_empty());
+    }
 }
 
 // End of file
@@ -0,0 +1,129 @@
+//! ROM related commands for Neotron OS
+
+use crate::{osprint, osprintln, Ctx, FILESYSTEM};
+
+pub static ROM_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: romfn,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "file",
+            help: Some("The ROM utility to run"),
+        }],
+    },
+    command: "rom",
+    help: Some("Run a program from ROM"),
+};
+
+pub static ROMTYPE_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: romtype,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "name",
+            help: Some("The ROM file to dump"),
+        }],
+    },
+    command: "romtype",
+    help: Some("Dump a text file from ROM"),
+};
+
+pub static ROMCP_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: romcp,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "name",
+                help: Some("The ROM file to copy"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "dest",
+                help: Some("The file to create on disk"),
+            },
+        ],
+    },
+    command: "romcp",
+    help: Some("Copy a file from ROM to disk"),
+};
+
+/// Called when the "romfn" command is executed.
+fn romfn(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let Ok(romfs) = neotron_romfs::RomFs::new(crate::ROMFS) else {
+        osprintln!("No ROM available");
+        return;
+    };
+    if let Some(arg) = args.get(0) {
+        let Some(entry) = romfs.find(arg) else {
+            osprintln!("Couldn't find {} in ROM", arg);
+            return;
+        };
+        match ctx.tpa.load_rom_program(arg, entry.contents) {
+            Ok(()) => {
+                osprintln!(log: Debug, "Loaded {:?} from ROM", arg);
+            }
+            Err(e) => {
+                osprintln!(log: Error, "Error loading {:?} from ROM: {}", arg, e);
+            }
+        }
+    } else {
+        for entry in romfs.into_iter() {
+            if let Ok(entry) = entry {
+                osprintln!(
+                    "{} ({} bytes)",
+                    entry.metadata.file_name,
+                    entry.metadata.file_size
+                );
+            }
+        }
+    }
+}
+
+/// Called when the "romtype" command is executed.
+fn romtype(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(romfs) = neotron_romfs::RomFs::new(crate::ROMFS) else {
+        osprintln!("No ROM available");
+        return;
+    };
+    // index can't panic - we always have enough args
+    let Some(entry) = romfs.find(args[0]) else {
+        osprintln!("Couldn't find {} in ROM", args[0]);
+        return;
+    };
+    let Ok(s) = core::str::from_utf8(entry.contents) else {
+        osprintln!("File is not valid UTF-8");
+        return;
+    };
+    osprintln!("{}", s);
+    // reset SGR
+    osprint!("\u{001b}[0m");
+}
+
+/// Called when the "romcp" command is executed.
+fn romcp(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    fn work(contents: &[u8], filename: &str) -> Result<(), crate::fs::Error> {
+        // Big files can take a while to write
+        let _pause = crate::watchdog::pause();
+        let file =
+            FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+        file.write(contents)?;
+        Ok(())
+    }
+
+    let Ok(romfs) = neotron_romfs::RomFs::new(crate::ROMFS) else {
+        osprintln!("No ROM available");
+        return;
+    };
+    // index can't panic - we always have enough args
+    let Some(entry) = romfs.find(args[0]) else {
+        osprintln!("Couldn't find {} in ROM", args[0]);
+        return;
+    };
+    match work(entry.contents, args[1]) {
+        Ok(_) => {
+            osprintln!("Copied {} bytes", entry.contents.len());
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
+// End of file
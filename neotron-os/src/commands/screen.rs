@@ -1,16 +1,20 @@
 //! Screen-related commands for Neotron OS
 
 use neotron_common_bios::video::RGBColour;
-use pc_keyboard::DecodedKey;
 
 use crate::{
     bios::{
         video::{Format, Mode},
         ApiResult,
     },
-    osprint, osprintln, Ctx,
+    config, osprint, osprintln,
+    vgaconsole::VgaConsole,
+    Ctx,
 };
 
+/// The widest region `snap` can send
+const SNAP_MAX_WIDTH: usize = 256;
+
 pub static CLS_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: cls_cmd,
@@ -32,22 +36,46 @@ pub static MODE_ITEM: menu::Item<Ctx> = menu::Item {
     help: Some("List/change video mode"),
 };
 
-pub static GFX_ITEM: menu::Item<Ctx> = menu::Item {
+pub static SCREENDUMP_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: screendump_cmd,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "filename",
+            help: Some("The file to write the screen contents to"),
+        }],
+    },
+    command: "screendump",
+    help: Some("Save the screen to disk (raw for text modes, BMP for graphics modes)"),
+};
+
+pub static SNAP_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
-        function: gfx_cmd,
+        function: snap_cmd,
         parameters: &[
             menu::Parameter::Mandatory {
-                parameter_name: "new_mode",
-                help: Some("The new gfx mode to try"),
+                parameter_name: "what",
+                help: Some("What to send (only text for now)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "col",
+                help: Some("The left edge of the region (default 0)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "row",
+                help: Some("The top edge of the region (default 0)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "width",
+                help: Some("How many columns (default all of them)"),
             },
             menu::Parameter::Optional {
-                parameter_name: "filename",
-                help: Some("a file to display"),
+                parameter_name: "height",
+                help: Some("How many rows (default all of them)"),
             },
         ],
     },
-    command: "gfx",
-    help: Some("Test a graphics mode"),
+    command: "snap",
+    help: Some("Send what is on the VGA console to the serial console"),
 };
 
 /// Called when the "cls" command is executed.
@@ -92,15 +120,20 @@ fn mode_cmd(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx
         // # Safety
         //
         // It's always OK to pass NULl to this API.
+        crate::vconsole::release();
         match unsafe { (api.video_set_mode)(mode, core::ptr::null_mut()) } {
             ApiResult::Ok(_) => {
-                let mut guard = crate::VGA_CONSOLE.lock();
-                if let Some(console) = guard.as_mut() {
-                    console.change_mode(mode);
+                {
+                    let mut guard = crate::VGA_CONSOLE.lock();
+                    if let Some(console) = guard.as_mut() {
+                        console.change_mode(mode);
+                    }
                 }
+                crate::vconsole::setup();
                 osprintln!("Now in mode {}", mode.as_u8());
             }
             ApiResult::Err(e) => {
+                crate::vconsole::setup();
                 osprintln!("Failed to change mode: {:?}", e);
             }
         }
@@ -109,80 +142,167 @@ fn mode_cmd(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx
     }
 }
 
-/// Called when the "gfx" command is executed
-fn gfx_cmd(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
-    let Some(new_mode) = menu::argument_finder(item, args, "new_mode").unwrap() else {
-        osprintln!("Missing arg");
+/// Called when the "screendump" command is executed
+fn screendump_cmd(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    // Writing out a big framebuffer can take a while
+    let _pause = crate::watchdog::pause();
+    let api = crate::API.get();
+    let mode = (api.video_get_mode)();
+    let fb_ptr = (api.video_get_framebuffer)();
+    if fb_ptr.is_null() {
+        osprintln!("No framebuffer to capture.");
         return;
+    }
+    // # Safety
+    //
+    // The BIOS tells us the framebuffer is this big, and it lives as long as
+    // the mode is not changed, which it won't be while we're in here.
+    let frame =
+        unsafe { core::slice::from_raw_parts(fb_ptr as *const u8, mode.frame_size_bytes()) };
+
+    let bits_per_pixel = match mode.format() {
+        Format::Chunky32 => Some(32),
+        Format::Chunky8 => Some(8),
+        Format::Chunky4 => Some(4),
+        Format::Chunky1 => Some(1),
+        _ => None,
     };
-    let file_name = menu::argument_finder(item, args, "filename").unwrap();
-    let Ok(mode_num) = new_mode.parse::<u8>() else {
-        osprintln!("Invalid integer {:?}", new_mode);
-        return;
+
+    // index can't panic - we always have enough args
+    let result = if let Some(bits_per_pixel) = bits_per_pixel {
+        write_bmp(args[0], mode, bits_per_pixel, frame)
+    } else {
+        // Text modes (and graphics modes BMP can't do) get a raw dump
+        crate::FILESYSTEM
+            .open_file(args[0], embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)
+            .and_then(|file| file.write(frame))
     };
-    let Some(mode) = Mode::try_from_u8(mode_num) else {
-        osprintln!("Invalid mode {:?}", new_mode);
+
+    match result {
+        Ok(_) => {
+            osprintln!(
+                "Saved {} bytes of mode {} as {}",
+                frame.len(),
+                mode.as_u8(),
+                if bits_per_pixel.is_some() {
+                    "BMP"
+                } else {
+                    "raw data"
+                }
+            );
+        }
+        Err(e) => {
+            osprintln!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Called when the "snap" command is executed.
+fn snap_cmd(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    // index can't panic - we always have enough args
+    if args[0] != "text" {
+        osprintln!("Only snap text is supported");
+        return;
+    }
+    let Some((screen_width, screen_height)) = crate::VGA_CONSOLE
+        .lock()
+        .as_ref()
+        .map(|c| (c.width() as usize, c.full_height() as usize))
+    else {
+        osprintln!("No VGA console");
         return;
     };
-    let api = crate::API.get();
-    let old_mode = (api.video_get_mode)();
-    let old_ptr = (api.video_get_framebuffer)();
-
-    let buffer = ctx.tpa.as_slice_u8();
-    let buffer_ptr = buffer.as_mut_ptr() as *mut u32;
-    if let Some(file_name) = file_name {
-        let Ok(file) = crate::FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly)
-        else {
-            osprintln!("No such file.");
+    let mut numbers = [0, 0, screen_width, screen_height];
+    for (number, arg) in numbers.iter_mut().zip(&args[1..]) {
+        let Ok(value) = arg.parse::<usize>() else {
+            osprintln!("Bad number {:?}", arg);
             return;
         };
-        let _ = file.read(buffer);
-    } else {
-        // draw a dummy non-zero data. In Chunky1 this is a checkerboard.
-        let line_size_words = mode.line_size_bytes() / 4;
-        for row in 0..mode.vertical_lines() as usize {
-            let word = if (row % 2) == 0 {
-                0x5555_5555
-            } else {
-                0xAAAA_AAAA
-            };
-            for col in 0..line_size_words {
-                let idx = (row * line_size_words) + col;
-                unsafe {
-                    // Let's try stripes?
-                    buffer_ptr.add(idx).write_volatile(word);
-                }
-            }
-        }
+        *number = value;
     }
+    let [col, row, width, height] = numbers;
+    let mut line = [0u8; SNAP_MAX_WIDTH];
+    let width = width
+        .min(screen_width.saturating_sub(col))
+        .min(SNAP_MAX_WIDTH);
+    let height = height.min(screen_height.saturating_sub(row));
 
-    if let neotron_common_bios::FfiResult::Err(e) =
-        unsafe { (api.video_set_mode)(mode, buffer_ptr) }
-    {
-        osprintln!("Couldn't set mode {}: {:?}", mode_num, e);
+    // Only send it to the serial console - the VGA console already has it
+    let old_route = crate::Console::stdout_route();
+    let serial_only = config::Route {
+        vga: false,
+        serial: true,
+    };
+    if crate::Console::set_stdout_route(serial_only).is_err() {
+        osprintln!("No serial console");
+        return;
     }
-
-    // Now wait for user input
-    let mut r = 0u8;
-    let mut g = 80u8;
-    let mut b = 160u8;
-    'wait: loop {
-        (api.video_wait_for_line)(0);
-        ((api.video_set_palette)(0, RGBColour::from_rgb(r, g, b)));
-        r = r.wrapping_add(1);
-        g = g.wrapping_add(1);
-        b = b.wrapping_add(1);
-
-        let keyin = crate::STD_INPUT.lock().get_raw();
-        if let Some(DecodedKey::Unicode('Q') | DecodedKey::Unicode('q')) = keyin {
-            break 'wait;
+    let table = VgaConsole::glyph_table();
+    for row in row..row + height {
+        let count = crate::VGA_CONSOLE.lock().as_mut().map_or(0, |c| {
+            c.read_glyphs(row as isize, col as isize, &mut line[0..width])
+        });
+        for glyph in &line[0..count] {
+            osprint!("{}", table[usize::from(*glyph)]);
         }
+        osprintln!();
     }
+    // This worked before, so it will work again
+    let _ = crate::Console::set_stdout_route(old_route);
+    osprintln!("Sent {}x{} characters to the serial console", width, height);
+}
+
+/// Write a framebuffer out to disk as a Windows Bitmap
+///
+/// We use a negative height, which makes the rows top-down, so we can copy
+/// them straight from the framebuffer.
+fn write_bmp(
+    filename: &str,
+    mode: Mode,
+    bits_per_pixel: u16,
+    frame: &[u8],
+) -> Result<(), crate::fs::Error> {
+    const HEADER_SIZE: u32 = 14 + 40;
+    let line_size = mode.line_size_bytes();
+    let width = (line_size * 8) as u32 / u32::from(bits_per_pixel);
+    let height = (frame.len() / line_size) as u32;
+    let palette_entries: u32 = if bits_per_pixel <= 8 {
+        1 << bits_per_pixel
+    } else {
+        0
+    };
+    let data_offset = HEADER_SIZE + (palette_entries * 4);
+    let file_size = data_offset + frame.len() as u32;
 
-    // Put it back as it was
-    unsafe {
-        (api.video_set_mode)(old_mode, old_ptr);
+    let mut header = [0u8; HEADER_SIZE as usize];
+    // BITMAPFILEHEADER
+    header[0..2].copy_from_slice(b"BM");
+    header[2..6].copy_from_slice(&file_size.to_le_bytes());
+    header[10..14].copy_from_slice(&data_offset.to_le_bytes());
+    // BITMAPINFOHEADER
+    header[14..18].copy_from_slice(&40u32.to_le_bytes());
+    header[18..22].copy_from_slice(&width.to_le_bytes());
+    header[22..26].copy_from_slice(&(-(height as i32)).to_le_bytes());
+    header[26..28].copy_from_slice(&1u16.to_le_bytes());
+    header[28..30].copy_from_slice(&bits_per_pixel.to_le_bytes());
+    header[34..38].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    header[46..50].copy_from_slice(&palette_entries.to_le_bytes());
+
+    let file =
+        crate::FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+    file.write(&header)?;
+
+    let api = crate::API.get();
+    for idx in 0..palette_entries {
+        let colour = match (api.video_get_palette)(idx as u8) {
+            neotron_common_bios::FfiOption::Some(colour) => colour,
+            neotron_common_bios::FfiOption::None => RGBColour::from_rgb(0, 0, 0),
+        };
+        file.write(&[colour.blue(), colour.green(), colour.red(), 0])?;
     }
+
+    file.write(frame)?;
+    Ok(())
 }
 
 /// Print out all supported video modes
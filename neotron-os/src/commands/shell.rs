@@ -0,0 +1,643 @@
+//! Shell scripting related commands for Neotron OS
+
+use crate::{osprint, osprintln, Ctx, API};
+
+pub static ALIAS_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: alias,
+        parameters: &[
+            menu::Parameter::Optional {
+                parameter_name: "name",
+                help: Some("The alias to show or set"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "command",
+                help: Some("The command to run instead"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg1",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg2",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg3",
+                help: Some("An argument for the command"),
+            },
+        ],
+    },
+    command: "alias",
+    help: Some("Show or set command aliases (use 'config save' to keep them)"),
+};
+
+pub static UNALIAS_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: unalias,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "name",
+            help: Some("The alias to remove"),
+        }],
+    },
+    command: "unalias",
+    help: Some("Remove a command alias"),
+};
+
+pub static COMMANDS_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: commands,
+        parameters: &[],
+    },
+    command: "commands",
+    help: Some("List the commands and features built into this OS"),
+};
+
+pub static SLEEP_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: sleep,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "ms",
+            help: Some("How many milliseconds to wait"),
+        }],
+    },
+    command: "sleep",
+    help: Some("Wait for a while"),
+};
+
+pub static HISTORY_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: history,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "clear/save",
+            help: Some("Forget the commands, or write them to HISTORY.TXT"),
+        }],
+    },
+    command: "history",
+    help: Some("List the commands you typed recently"),
+};
+
+pub static TIMING_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: timing,
+        parameters: &[menu::Parameter::Optional {
+            parameter_name: "on/off",
+            help: Some("Whether to say how long each command takes"),
+        }],
+    },
+    command: "timing",
+    help: Some("Say how long each command takes"),
+};
+
+pub static WATCH_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: watch,
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "secs",
+                help: Some("How many seconds between runs"),
+            },
+            menu::Parameter::Mandatory {
+                parameter_name: "command",
+                help: Some("The command to run"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg1",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg2",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg3",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg4",
+                help: Some("An argument for the command"),
+            },
+        ],
+    },
+    command: "watch",
+    help: Some("Run a command every few seconds, until a key is pressed"),
+};
+
+pub static AT_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: at,
+        parameters: &[
+            menu::Parameter::Optional {
+                parameter_name: "time",
+                help: Some("HH:MM, +<minutes> or YYYY-MM-DDTHH:MM (or `cancel`)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "command",
+                help: Some("The command to run (or which one to cancel)"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg1",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg2",
+                help: Some("An argument for the command"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "arg3",
+                help: Some("An argument for the command"),
+            },
+        ],
+    },
+    command: "at",
+    help: Some("Run a command later (saves the config)"),
+};
+
+/// Expands aliases on their way into the menu.
+///
+/// We keep a copy of the line being typed. If it starts with an alias when
+/// Enter is pressed, we rub the line out and type the expansion instead.
+/// Commands from OS modules aren't in the menu, so if the line starts with
+/// one of those, we put `modrun` in front of it.
+///
+/// We answer `help <command>` ourselves, with a table of the command's
+/// parameters, as the menu would print each one over several lines.
+///
+/// We also remember each line typed (see [`crate::history`]), and the up and
+/// down arrow keys rub the line out and type an older or newer one instead.
+pub struct AliasExpander {
+    line: heapless::Vec<u8, 128>,
+    overflowed: bool,
+    /// Which remembered line we are showing, counting back from the most
+    /// recent
+    recall: Option<usize>,
+    /// The escape sequence we are part way through
+    escape: heapless::Vec<u8, 8>,
+}
+
+impl AliasExpander {
+    /// Make a new alias expander, with an empty line
+    pub const fn new() -> AliasExpander {
+        AliasExpander {
+            line: heapless::Vec::new(),
+            overflowed: false,
+            recall: None,
+            escape: heapless::Vec::new(),
+        }
+    }
+
+    /// Pass a byte to the menu, expanding any alias if it ends a line.
+    ///
+    /// If `timing` is on, we say how long the command took.
+    pub fn input_byte(&mut self, runner: &mut menu::Runner<Ctx>, b: u8) {
+        if b == 0x1B || !self.escape.is_empty() {
+            self.escape_byte(runner, b);
+            return;
+        }
+        match b {
+            b'\r' => {
+                if !self.overflowed {
+                    if runner.context.script.is_none() {
+                        if let Ok(line) = core::str::from_utf8(&self.line) {
+                            crate::history::push(line);
+                        }
+                    }
+                    if self.help(runner) {
+                        self.recall = None;
+                        self.line.clear();
+                        return;
+                    }
+                    self.expand(runner);
+                }
+                self.recall = None;
+                let timed =
+                    runner.context.timing && !self.line.iter().all(|b| b.is_ascii_whitespace());
+                self.line.clear();
+                self.overflowed = false;
+                if timed {
+                    let api = API.get();
+                    let start = (api.time_ticks_get)().0;
+                    runner.input_byte(b);
+                    let elapsed = (api.time_ticks_get)().0.saturating_sub(start);
+                    let ms = elapsed * 1000 / (api.time_ticks_per_second)().0.max(1);
+                    // The menu has printed a new prompt, so print over it
+                    // and then put it back
+                    osprintln!("\r(took {}.{:03}s)", ms / 1000, ms % 1000);
+                    runner.prompt(false);
+                    return;
+                }
+            }
+            0x08 | 0x7F => {
+                self.line.pop();
+            }
+            _ => {
+                if self.line.push(b).is_err() {
+                    // Too long to expand - just pass it through
+                    self.overflowed = true;
+                }
+            }
+        }
+        runner.input_byte(b);
+    }
+
+    /// Collect an escape sequence, and either recall a line (for the up and
+    /// down arrow keys) or pass it on to the menu.
+    ///
+    /// We understand both the sequences our keyboard sends (`ESC [ 0 ; 72 b`
+    /// and `ESC [ 0 ; 80 b`), and the ones a serial terminal sends (`ESC [ A`
+    /// and `ESC [ B`, or `ESC O A` and `ESC O B`).
+    fn escape_byte(&mut self, runner: &mut menu::Runner<Ctx>, b: u8) {
+        let finished = self.escape.len() >= 2 && (b.is_ascii_alphabetic() || b == b'~');
+        if self.escape.push(b).is_err() || (self.escape.len() == 2 && b != b'[' && b != b'O') {
+            // Not a sequence we understand
+            self.flush_escape(runner);
+            return;
+        }
+        if !finished {
+            return;
+        }
+        match &self.escape[1..] {
+            b"[A" | b"OA" | b"[0;72b" => {
+                let older = self.recall.map_or(0, |n| n + 1);
+                if let Some(line) = crate::history::recent(older) {
+                    self.recall = Some(older);
+                    self.replace_line(runner, line.as_bytes());
+                }
+                self.escape.clear();
+            }
+            b"[B" | b"OB" | b"[0;80b" => {
+                match self.recall {
+                    Some(0) => {
+                        self.recall = None;
+                        self.replace_line(runner, b"");
+                    }
+                    Some(n) => {
+                        if let Some(line) = crate::history::recent(n - 1) {
+                            self.recall = Some(n - 1);
+                            self.replace_line(runner, line.as_bytes());
+                        }
+                    }
+                    None => {}
+                }
+                self.escape.clear();
+            }
+            _ => self.flush_escape(runner),
+        }
+    }
+
+    /// Pass the escape sequence we collected on to the menu unchanged
+    fn flush_escape(&mut self, runner: &mut menu::Runner<Ctx>) {
+        for b in core::mem::take(&mut self.escape) {
+            runner.input_byte(b);
+        }
+    }
+
+    /// Rub out the line being typed, and type this one instead
+    fn replace_line(&mut self, runner: &mut menu::Runner<Ctx>, line: &[u8]) {
+        for _ in 0..self.line.len() {
+            runner.input_byte(0x08);
+        }
+        self.line.clear();
+        self.overflowed = false;
+        for b in line {
+            // Can't fail - remembered lines are shorter than ours
+            let _ = self.line.push(*b);
+            runner.input_byte(*b);
+        }
+    }
+
+    /// If the line is `help <command>`, describe the command.
+    ///
+    /// We rub the line out of the menu's buffer, so it has nothing to do, and
+    /// print it again ourselves. Returns false if this isn't a command we
+    /// know, so the menu can say so.
+    fn help(&self, runner: &mut menu::Runner<Ctx>) -> bool {
+        let Ok(line) = core::str::from_utf8(&self.line) else {
+            return false;
+        };
+        let mut words = line.split_whitespace();
+        if words.next() != Some("help") {
+            return false;
+        }
+        let (Some(command), None) = (words.next(), words.next()) else {
+            return false;
+        };
+        let Some(item) = super::OS_MENU
+            .items
+            .iter()
+            .find(|item| item.command == command)
+        else {
+            return false;
+        };
+        for _ in 0..self.line.len() {
+            runner.input_byte(0x08);
+        }
+        osprintln!("{}", line);
+        describe(item);
+        runner.prompt(true);
+        true
+    }
+
+    /// If the line starts with an alias (or a module command), replace it in
+    /// the menu's buffer.
+    fn expand(&self, runner: &mut menu::Runner<Ctx>) {
+        let Ok(line) = core::str::from_utf8(&self.line) else {
+            return;
+        };
+        let line = line.trim_start();
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        // Copy it out, as the menu needs the context back
+        let expansion = match runner.context.config.get_alias(name) {
+            Some(alias) => heapless::String::<32>::from(alias),
+            None if crate::module::is_command(name) => {
+                let mut expansion = heapless::String::<32>::new();
+                // Can't fail - module command names are short
+                let _ = expansion.push_str("modrun ");
+                let _ = expansion.push_str(name);
+                expansion
+            }
+            None => return,
+        };
+        for _ in 0..self.line.len() {
+            runner.input_byte(0x08);
+        }
+        for b in expansion.bytes() {
+            runner.input_byte(b);
+        }
+        if !rest.is_empty() {
+            runner.input_byte(b' ');
+            for b in rest.bytes() {
+                runner.input_byte(b);
+            }
+        }
+    }
+}
+
+/// Called when the "alias" command is executed.
+fn alias(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    match args {
+        [] => {
+            for alias in ctx.config.get_aliases() {
+                osprintln!("{:8} = {}", alias.name, alias.expansion);
+            }
+        }
+        [name] => match ctx.config.get_alias(name) {
+            Some(expansion) => {
+                osprintln!("{:8} = {}", name, expansion);
+            }
+            None => {
+                osprintln!("No such alias {:?}", name);
+            }
+        },
+        [name, words @ ..] => {
+            // Join the words back up, without any quotes the user added
+            let mut expansion: heapless::String<32> = heapless::String::new();
+            for (idx, word) in words.iter().enumerate() {
+                if (idx > 0 && expansion.push(' ').is_err())
+                    || expansion.push_str(word.trim_matches('"')).is_err()
+                {
+                    osprintln!("Alias expansion too long");
+                    return;
+                }
+            }
+            if let Err(e) = ctx.config.set_alias(name, &expansion) {
+                osprintln!("Can't set alias: {}", e);
+            }
+        }
+    }
+}
+
+/// Called when the "at" command is executed.
+fn at(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    match args {
+        [] => {
+            if ctx.config.get_scheduled().is_empty() {
+                osprintln!("Nothing scheduled");
+            }
+            for (idx, scheduled) in ctx.config.get_scheduled().iter().enumerate() {
+                osprintln!(
+                    "{}: {} {}",
+                    idx,
+                    crate::schedule::When(scheduled.when),
+                    scheduled.command
+                );
+            }
+        }
+        ["cancel", idx] => {
+            let Ok(idx) = idx.parse::<usize>() else {
+                osprintln!("Give the number `at` shows for the command");
+                return;
+            };
+            match ctx.config.remove_scheduled(idx) {
+                Some(scheduled) => {
+                    crate::schedule::changed(&ctx.config);
+                    osprintln!("Cancelled {}", scheduled.command);
+                }
+                None => {
+                    osprintln!("No such scheduled command {}", idx);
+                }
+            }
+        }
+        [time, words @ ..] if !words.is_empty() => {
+            let Some(when) = crate::schedule::parse_when(time, API.get_local_time())
+                .and_then(crate::schedule::to_bios_secs)
+            else {
+                osprintln!("Give the time as HH:MM, +<minutes> or YYYY-MM-DDTHH:MM");
+                return;
+            };
+            // Join the words back up, without any quotes the user added
+            let mut command: heapless::String<32> = heapless::String::new();
+            for (idx, word) in words.iter().enumerate() {
+                if (idx > 0 && command.push(' ').is_err())
+                    || command.push_str(word.trim_matches('"')).is_err()
+                {
+                    osprintln!("Command too long");
+                    return;
+                }
+            }
+            if let Err(e) = ctx.config.add_scheduled(when, &command) {
+                osprintln!("Can't schedule command: {}", e);
+                return;
+            }
+            crate::schedule::changed(&ctx.config);
+            osprintln!(
+                "Will run {:?} at {}",
+                command.as_str(),
+                crate::schedule::When(when)
+            );
+        }
+        _ => {
+            osprintln!("Give a time and a command, or `cancel` and a number");
+        }
+    }
+}
+
+/// Called when the "unalias" command is executed.
+fn unalias(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    if !ctx.config.remove_alias(args[0]) {
+        osprintln!("No such alias {:?}", args[0]);
+    }
+}
+
+/// Print what a command does, how to use it, and a table of its parameters.
+fn describe(item: &menu::Item<Ctx>) {
+    osprintln!("{} - {}", item.command, item.help.unwrap_or(""));
+    let menu::ItemType::Callback { parameters, .. } = item.item_type else {
+        return;
+    };
+    osprint!("\nUsage: {}", item.command);
+    for parameter in parameters {
+        match parameter {
+            menu::Parameter::Mandatory { parameter_name, .. } => {
+                osprint!(" <{}>", parameter_name)
+            }
+            menu::Parameter::Optional { parameter_name, .. } => {
+                osprint!(" [ <{}> ]", parameter_name)
+            }
+            menu::Parameter::Named { parameter_name, .. } => {
+                osprint!(" [ --{} ]", parameter_name)
+            }
+            menu::Parameter::NamedValue {
+                parameter_name,
+                argument_name,
+                ..
+            } => osprint!(" [ --{}={} ]", parameter_name, argument_name),
+        }
+    }
+    osprintln!();
+    if parameters.is_empty() {
+        return;
+    }
+    osprintln!("\n{:16} {:10} Description", "Parameter", "Kind");
+    for parameter in parameters {
+        let (name, kind, help) = match parameter {
+            menu::Parameter::Mandatory {
+                parameter_name,
+                help,
+            } => (*parameter_name, "required", help),
+            menu::Parameter::Optional {
+                parameter_name,
+                help,
+            } => (*parameter_name, "optional", help),
+            menu::Parameter::Named {
+                parameter_name,
+                help,
+            } => (*parameter_name, "flag", help),
+            menu::Parameter::NamedValue {
+                parameter_name,
+                help,
+                ..
+            } => (*parameter_name, "option", help),
+        };
+        osprintln!("{:16} {:10} {}", name, kind, help.unwrap_or(""));
+    }
+}
+
+/// Called when the "commands" command is executed.
+///
+/// Small builds can leave out some groups of commands (see the cargo
+/// features), so this shows what this build has.
+fn commands(menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    const PER_LINE: usize = 6;
+    for line in menu.items.chunks(PER_LINE) {
+        for item in line {
+            osprint!("{:12}", item.command);
+        }
+        osprintln!();
+    }
+    osprintln!("{} commands", menu.items.len());
+    osprint!("Features:");
+    for (name, enabled) in super::FEATURES {
+        osprint!(" {}{}", if *enabled { '+' } else { '-' }, name);
+    }
+    osprintln!();
+}
+
+/// Called when the "sleep" command is executed.
+fn sleep(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    let Ok(ms) = args[0].parse::<u64>() else {
+        osprintln!("Give a number of milliseconds");
+        return;
+    };
+    let api = API.get();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let deadline = (api.time_ticks_get)().0 + (ms * ticks_per_second) / 1000;
+    while (api.time_ticks_get)().0 < deadline {
+        // We're not hung, just sleeping
+        crate::watchdog::kick();
+        crate::stats::idle();
+    }
+}
+
+/// Called when the "history" command is executed.
+fn history(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    match args.first().copied() {
+        None => crate::history::for_each(|idx, line| {
+            osprintln!("{:3}  {}", idx + 1, line);
+        }),
+        Some("clear") => crate::history::clear(),
+        Some("save") => match crate::history::save() {
+            Ok(()) => {
+                osprintln!("Saved history");
+            }
+            Err(e) => {
+                osprintln!("Failed to save history: {:?}", e);
+            }
+        },
+        Some(_) => {
+            osprintln!("Give clear or save");
+        }
+    }
+}
+
+/// Called when the "timing" command is executed.
+fn timing(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    match args.first().copied() {
+        None => {}
+        Some("on") => ctx.timing = true,
+        Some("off") => ctx.timing = false,
+        Some(_) => {
+            osprintln!("Give on or off");
+            return;
+        }
+    }
+    osprintln!("Timing is {}", if ctx.timing { "on" } else { "off" });
+}
+
+/// Called when the "watch" command is executed.
+fn watch(menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let Ok(secs) = args[0].parse::<u64>() else {
+        osprintln!("Give a number of seconds");
+        return;
+    };
+    let Some(item) = menu.items.iter().find(|item| item.command == args[1]) else {
+        osprintln!("Unknown command {:?}", args[1]);
+        return;
+    };
+    let menu::ItemType::Callback { function, .. } = item.item_type else {
+        osprintln!("Can't watch {:?}", args[1]);
+        return;
+    };
+    let api = API.get();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    loop {
+        // Clear the screen and go to the top
+        osprint!("\u{001b}[2J\u{001b}[1;1H");
+        osprint!("Every {}s:", secs);
+        for arg in &args[1..] {
+            osprint!(" {}", arg);
+        }
+        osprintln!(" (press any key to stop)\n");
+        function(menu, item, &args[2..], ctx);
+        let deadline = (api.time_ticks_get)().0 + secs * ticks_per_second;
+        while (api.time_ticks_get)().0 < deadline {
+            let mut buffer = [0u8; 16];
+            if crate::STD_INPUT.lock().get_data(&mut buffer) > 0 {
+                return;
+            }
+            crate::stats::idle();
+        }
+    }
+}
+
+// End of file
@@ -14,6 +14,10 @@ pub static MIXER_ITEM: menu::Item<Ctx> = menu::Item {
                 parameter_name: "level",
                 help: Some("New level for this mixer, as an integer."),
             },
+            menu::Parameter::Named {
+                parameter_name: "save",
+                help: Some("Set this level again every time the OS starts"),
+            },
         ],
     },
     command: "mixer",
@@ -23,19 +27,83 @@ pub static MIXER_ITEM: menu::Item<Ctx> = menu::Item {
 pub static PLAY_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: play,
-        parameters: &[menu::Parameter::Mandatory {
-            parameter_name: "filename",
-            help: Some("Which file to play"),
-        }],
+        parameters: &[
+            menu::Parameter::Mandatory {
+                parameter_name: "filename",
+                help: Some("Which file (or directory of files) to play"),
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file2",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file3",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file4",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file5",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file6",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file7",
+                help: None,
+            },
+            menu::Parameter::Optional {
+                parameter_name: "file8",
+                help: None,
+            },
+            menu::Parameter::Named {
+                parameter_name: "shuffle",
+                help: Some("Play the files in a random order"),
+            },
+        ],
     },
     command: "play",
-    help: Some("Play a raw 16-bit LE 48 kHz stereo file"),
+    help: Some("Play MOD files, or raw 16-bit LE 48 kHz stereo files"),
 };
 
+/// The most files `play` can queue up
+const MAX_TRACKS: usize = 64;
+
+/// How many bytes of audio we read from a file at a time
+const PLAY_CHUNK_LEN: usize = 4096;
+
+/// How many bytes a second of audio takes (16-bit stereo at 48 kHz)
+const BYTES_PER_SECOND: usize =
+    crate::tracker::SAMPLE_RATE as usize * crate::tracker::BYTES_PER_FRAME;
+
+/// One file in the playlist
+struct Track<'a> {
+    /// The directory it is in, or empty for the root directory
+    dir: &'a str,
+    /// Its name
+    name: embedded_sdmmc::ShortFileName,
+}
+
+/// What the user wants once a track stops
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Action {
+    /// Go on to the next track (also what happens when a track finishes)
+    Next,
+    /// Go back to the previous track
+    Previous,
+    /// Stop playing
+    Quit,
+}
+
 /// Called when the "mixer" command is executed.
-fn mixer(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+fn mixer(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
     let selected_mixer = menu::argument_finder(item, args, "mixer").unwrap();
     let level_str = menu::argument_finder(item, args, "level").unwrap();
+    let save = matches!(menu::argument_finder(item, args, "save"), Ok(Some(_)));
 
     let level_int = if let Some(level_str) = level_str {
         let Ok(value) = level_str.parse::<u8>() else {
@@ -68,6 +136,8 @@ fn mixer(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &
                                 level_int,
                                 e
                             );
+                        } else if save {
+                            save_level(ctx, mixer_info.name.as_str(), level_int);
                         }
                         found = true;
                         break;
@@ -118,53 +188,188 @@ fn mixer(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &
 }
 
 /// Called when the "play" command is executed.
-fn play(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
-    fn play_inner(file_name: &str, scratch: &mut [u8]) -> Result<(), crate::fs::Error> {
-        osprintln!("Loading /{} from Block Device 0", file_name);
-        let file = FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly)?;
-
-        osprintln!("Press Q to quit, P to pause/unpause...");
-
-        let api = API.get();
-
-        let buffer = &mut scratch[0..4096];
-        let mut bytes = 0;
-        let mut delta = 0;
-
-        let mut pause = false;
-
-        'playback: while !file.is_eof() {
-            if !pause {
-                let bytes_read = file.read(buffer)?;
-                let mut buffer = &buffer[0..bytes_read];
-                while !buffer.is_empty() {
-                    let slice = bios::FfiByteSlice::new(buffer);
-                    let played = unsafe { (api.audio_output_data)(slice).unwrap() };
-                    buffer = &buffer[played..];
-                    delta += played;
-                    if delta > 48000 {
-                        bytes += delta;
-                        delta = 0;
-                        let milliseconds = bytes / ((48000 / 1000) * 4);
-                        osprint!(
-                            "\rPlayed: {}.{:03} s",
-                            milliseconds / 1000,
-                            milliseconds % 1000
-                        );
-                    }
+///
+/// Each argument is a file, or a directory whose files are all played. With
+/// `--shuffle`, the tracks are played in a random order. Files ending `.MOD`
+/// are tracker music, and everything else is raw audio.
+fn play(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], ctx: &mut Ctx) {
+    let shuffle = matches!(menu::argument_finder(item, args, "shuffle"), Ok(Some(_)));
+    let mut tracks: heapless::Vec<Track, MAX_TRACKS> = heapless::Vec::new();
+    for arg in args.iter().filter(|arg| !arg.starts_with("--")) {
+        if let Err(e) = add_tracks(&mut tracks, arg) {
+            osprintln!("Can't play {:?}: {}", arg, e);
+            return;
+        }
+    }
+    if tracks.is_empty() {
+        osprintln!("Nothing to play");
+        return;
+    }
+    if shuffle {
+        shuffle_tracks(&mut tracks);
+    }
+
+    osprintln!("Press Q to quit, P to pause/unpause, N for the next track, B to go back...");
+    let scratch = ctx.tpa.as_slice_u8();
+    let (buffer, memory) = scratch.split_at_mut(PLAY_CHUNK_LEN);
+    let mut idx = 0;
+    while idx < tracks.len() {
+        let track = &tracks[idx];
+        osprintln!(
+            "Playing {}/{} ({} of {})",
+            track.dir,
+            track.name,
+            idx + 1,
+            tracks.len()
+        );
+        let result =
+            FILESYSTEM.open_file_in_dir(track.dir, &track.name, embedded_sdmmc::Mode::ReadOnly);
+        let action = match result {
+            Ok(file) if track.name.extension().eq_ignore_ascii_case(b"MOD") => {
+                play_module(&file, buffer, memory)
+            }
+            Ok(file) => report_errors(play_stream(|buffer| file.read(buffer), buffer)),
+            Err(e) => report_errors(Err(e)),
+        };
+        osprintln!();
+        match action {
+            Action::Next => idx += 1,
+            Action::Previous => idx = idx.saturating_sub(1),
+            Action::Quit => {
+                osprintln!("Quitting playback!");
+                break;
+            }
+        }
+    }
+}
+
+/// Add a file to the playlist, or every file in a directory.
+///
+/// `path` can be a path from the root directory, like `MUSIC/SONG.RAW`.
+fn add_tracks<'a>(
+    tracks: &mut heapless::Vec<Track<'a>, MAX_TRACKS>,
+    path: &'a str,
+) -> Result<(), &'static str> {
+    let mut full = false;
+    // If it opens as a directory, play everything in it
+    let is_dir = FILESYSTEM
+        .iterate_dir(path, |entry| {
+            if entry.attributes.is_directory() || entry.attributes.is_volume() {
+                return;
+            }
+            let track = Track {
+                dir: path,
+                name: entry.name.clone(),
+            };
+            full |= tracks.push(track).is_err();
+        })
+        .is_ok();
+    if full {
+        return Err("Too many files");
+    }
+    if !is_dir {
+        let (dir, name) = path.rsplit_once(['/', '\\']).unwrap_or(("", path));
+        let name =
+            embedded_sdmmc::ShortFileName::create_from_str(name).map_err(|_| "Bad file name")?;
+        tracks
+            .push(Track { dir, name })
+            .map_err(|_| "Too many files")?;
+    }
+    Ok(())
+}
+
+/// Play a tracker music file, with its samples loaded into `memory`.
+fn play_module(file: &crate::fs::File, buffer: &mut [u8], memory: &mut [u8]) -> Action {
+    let mut player = match crate::tracker::Player::new(file, memory) {
+        Ok(player) => player,
+        Err(crate::tracker::Error::Io(e)) => {
+            osprintln!("Can't read module: {:?}", e);
+            return Action::Next;
+        }
+        Err(crate::tracker::Error::NotAModule) => {
+            osprintln!("Not a MOD file we can play");
+            return Action::Next;
+        }
+        Err(crate::tracker::Error::TooBig) => {
+            osprintln!("Module is too big to fit in the TPA");
+            return Action::Next;
+        }
+    };
+    osprintln!("Title: {}", player.title());
+    report_errors(play_stream(|buffer| player.fill(buffer), buffer))
+}
+
+/// Print any error from playing a track, and go on to the next one.
+fn report_errors(result: Result<Action, crate::fs::Error>) -> Action {
+    result.unwrap_or_else(|e| {
+        osprintln!("\nError during playback: {:?}", e);
+        Action::Next
+    })
+}
+
+/// Put the playlist in a random order.
+fn shuffle_tracks(tracks: &mut [Track]) {
+    // Seed a xorshift32 generator from the clock. Zero would stick at zero.
+    let mut state = ((API.get().time_ticks_get)().0 as u32) | 1;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    // Fisher-Yates
+    for idx in (1..tracks.len()).rev() {
+        let other = random() as usize % (idx + 1);
+        tracks.swap(idx, other);
+    }
+}
+
+/// Play audio until `fill` runs out or the user presses a key.
+///
+/// `fill` puts the next chunk of 16-bit stereo 48 kHz audio in the buffer it
+/// is given, and says how many bytes it wrote, or 0 at the end.
+fn play_stream<F>(mut fill: F, buffer: &mut [u8]) -> Result<Action, crate::fs::Error>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, crate::fs::Error>,
+{
+    let mut bytes = 0;
+    let mut delta = 0;
+    let mut pause = false;
+
+    loop {
+        if !pause {
+            let bytes_read = fill(buffer)?;
+            if bytes_read == 0 {
+                return Ok(Action::Next);
+            }
+            let mut buffer = &buffer[0..bytes_read];
+            while !buffer.is_empty() {
+                let played = crate::stats::audio_output(buffer).unwrap();
+                buffer = &buffer[played..];
+                delta += played;
+                if delta > 48000 {
+                    bytes += delta;
+                    delta = 0;
+                    let milliseconds = bytes / (BYTES_PER_SECOND / 1000);
+                    osprint!(
+                        "\rPlayed: {}.{:03} s",
+                        milliseconds / 1000,
+                        milliseconds % 1000
+                    );
                 }
             }
+        }
 
-            let mut buffer = [0u8; 16];
-            let count = { crate::STD_INPUT.lock().get_data(&mut buffer) };
-            for b in &buffer[0..count] {
-                if *b == b'q' || *b == b'Q' {
-                    osprintln!("\nQuitting playback!");
-                    break 'playback;
-                } else if (*b == b'p' || *b == b'P') && pause {
-                    pause = false;
-                } else if (*b == b'p' || *b == b'P') && !pause {
-                    let milliseconds = bytes / ((48000 / 1000) * 4);
+        let mut buffer = [0u8; 16];
+        let count = { crate::STD_INPUT.lock().get_data(&mut buffer) };
+        for b in &buffer[0..count] {
+            match b.to_ascii_lowercase() {
+                b'q' => return Ok(Action::Quit),
+                b'n' => return Ok(Action::Next),
+                b'b' => return Ok(Action::Previous),
+                b'p' if pause => pause = false,
+                b'p' => {
+                    let milliseconds = bytes / (BYTES_PER_SECOND / 1000);
                     osprint!(
                         "\rPaused: {}.{:03} s",
                         milliseconds / 1000,
@@ -172,14 +377,25 @@ fn play(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], ctx: &m
                     );
                     pause = true;
                 }
+                _ => {}
             }
         }
-        osprintln!();
-        Ok(())
+        if pause {
+            // Nothing to do until a key is pressed
+            crate::stats::idle();
+        }
     }
+}
 
-    if let Err(e) = play_inner(args[0], ctx.tpa.as_slice_u8()) {
-        osprintln!("\nError during playback: {:?}", e);
+/// Remember a mixer level in the config, and save the config, so the level
+/// is set again at start-up.
+fn save_level(ctx: &mut Ctx, name: &str, level: u8) {
+    if let Err(e) = ctx.config.set_mixer_level(name, level) {
+        osprintln!("Can't remember mixer {:?}: {}", name, e);
+        return;
+    }
+    if let Err(e) = ctx.config.save() {
+        osprintln!("Can't save config: {}", e);
     }
 }
 
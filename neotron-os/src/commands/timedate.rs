@@ -1,21 +1,115 @@
 //! CLI commands for getting/setting time/date
 
+use core::convert::TryFrom;
+
 use chrono::{Datelike, Timelike};
 
-use crate::{osprintln, Ctx, API};
+use crate::{config::TzOffset, osprintln, Ctx, API};
 
 pub static DATE_ITEM: menu::Item<Ctx> = menu::Item {
     item_type: menu::ItemType::Callback {
         function: date,
         parameters: &[menu::Parameter::Optional {
             parameter_name: "timestamp",
-            help: Some("The new date/time, in ISO8601 format"),
+            help: Some("The new local date/time, in ISO8601 format"),
         }],
     },
     command: "date",
     help: Some("Get/set the time and date"),
 };
 
+pub static UPTIME_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: uptime,
+        parameters: &[],
+    },
+    command: "uptime",
+    help: Some("Show how long the system has been running"),
+};
+
+pub static SETTIME_ITEM: menu::Item<Ctx> = menu::Item {
+    item_type: menu::ItemType::Callback {
+        function: settime,
+        parameters: &[menu::Parameter::Mandatory {
+            parameter_name: "source",
+            help: Some("Where to get the time from (host)"),
+        }],
+    },
+    command: "settime",
+    help: Some("Set the time and date automatically"),
+};
+
+/// What we send to ask the host for the time
+const HOST_TIME_QUERY: &[u8] = b"\x1b]neotron;time?\x07";
+
+/// How long we wait for the host to reply, in seconds
+const HOST_TIME_TIMEOUT_SECS: u64 = 3;
+
+/// Parses the host's reply, which looks like `ESC ] neotron;time=<secs> BEL`.
+///
+/// `<secs>` is the number of seconds since 1970-01-01T00:00:00 UTC. Anything
+/// else on the serial port is ignored.
+struct HostTimeParser {
+    /// How much of the prefix we have seen
+    matched: usize,
+    /// The digits we have seen so far
+    value: Option<u64>,
+}
+
+impl HostTimeParser {
+    /// What comes before the number
+    const PREFIX: &'static [u8] = b"\x1b]neotron;time=";
+
+    /// The byte that ends the reply
+    const BEL: u8 = 0x07;
+
+    /// Make a new parser
+    const fn new() -> HostTimeParser {
+        HostTimeParser {
+            matched: 0,
+            value: None,
+        }
+    }
+
+    /// Process one byte from the serial port.
+    ///
+    /// Returns the time, once we've seen a complete reply.
+    fn feed(&mut self, b: u8) -> Option<u64> {
+        if self.matched < Self::PREFIX.len() {
+            if b == Self::PREFIX[self.matched] {
+                self.matched += 1;
+            } else {
+                self.matched = usize::from(b == Self::PREFIX[0]);
+            }
+            return None;
+        }
+        match b {
+            b'0'..=b'9' => {
+                let digit = u64::from(b - b'0');
+                self.value = self
+                    .value
+                    .unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|v| v.checked_add(digit));
+                if self.value.is_none() {
+                    // Too big - start again
+                    *self = HostTimeParser::new();
+                }
+                None
+            }
+            Self::BEL => {
+                let result = self.value;
+                *self = HostTimeParser::new();
+                result
+            }
+            _ => {
+                *self = HostTimeParser::new();
+                self.feed(b)
+            }
+        }
+    }
+}
+
 /// Called when the "date" command is executed.
 fn date(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
     if let Ok(Some(timestamp)) = menu::argument_finder(item, args, "timestamp") {
@@ -25,20 +119,89 @@ fn date(_menu: &menu::Menu<Ctx>, item: &menu::Item<Ctx>, args: &[&str], _ctx: &m
             osprintln!("Unable to parse date/time");
             return;
         };
-        API.set_time(timestamp);
+        API.set_local_time(timestamp);
     }
 
-    let time = API.get_time();
+    let time = API.get_local_time();
     // Ensure this matches `DATE_FMT`, for consistency
     osprintln!(
-        "The time is {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}",
+        "The time is {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}{}",
         time.year(),
         time.month(),
         time.day(),
         time.hour(),
         time.minute(),
         time.second(),
-        time.nanosecond()
+        time.nanosecond(),
+        TzOffset(API.get_tz_offset())
+    );
+}
+
+/// Called when the "settime" command is executed.
+fn settime(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, args: &[&str], _ctx: &mut Ctx) {
+    if args.first() != Some(&"host") {
+        osprintln!("Only 'settime host' is supported");
+        return;
+    }
+    let api = API.get();
+    let secs = {
+        let mut guard = crate::SERIAL_CONSOLE.lock();
+        let Some(console) = guard.as_mut() else {
+            osprintln!("No serial console");
+            return;
+        };
+        if console.write_bstr(HOST_TIME_QUERY).is_err() {
+            osprintln!("Failed to send query");
+            return;
+        }
+        let ticks_per_second = (api.time_ticks_per_second)().0;
+        let deadline = (api.time_ticks_get)().0 + HOST_TIME_TIMEOUT_SECS * ticks_per_second;
+        let mut parser = HostTimeParser::new();
+        let mut result = None;
+        'wait: while (api.time_ticks_get)().0 < deadline {
+            let mut buffer = [0u8; 16];
+            let count = console.read_data(&mut buffer).unwrap_or(0);
+            for b in &buffer[0..count] {
+                if let Some(secs) = parser.feed(*b) {
+                    result = Some(secs);
+                    break 'wait;
+                }
+            }
+        }
+        result
+    };
+    let Some(timestamp) = secs
+        .and_then(|secs| i64::try_from(secs).ok())
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+    else {
+        osprintln!("No reply from host");
+        return;
+    };
+    API.set_time(timestamp.naive_utc());
+    let time = API.get_local_time();
+    osprintln!(
+        "Time set to {:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}",
+        time.year(),
+        time.month(),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        TzOffset(API.get_tz_offset())
+    );
+}
+
+/// Called when the "uptime" command is executed.
+fn uptime(_menu: &menu::Menu<Ctx>, _item: &menu::Item<Ctx>, _args: &[&str], _ctx: &mut Ctx) {
+    let uptime = API.get_uptime();
+    let secs = uptime.as_secs();
+    osprintln!(
+        "Up {} days, {:02}:{:02}:{:02}.{:03}",
+        secs / 86400,
+        (secs / 3600) % 24,
+        (secs / 60) % 60,
+        secs % 60,
+        uptime.subsec_millis()
     );
 }
 
@@ -1,34 +1,390 @@
 //! # OS Configuration
 //!
 //! Handles persistently storing OS configuration, using the BIOS.
+//!
+//! We store a version byte, followed by the `Config` struct serialised with
+//! postcard, followed by a CRC32 of everything before it. New settings only
+//! ever go on the end of `Config`, so a config saved by an older OS is just
+//! shorter, and the settings it doesn't have get their default values.
 
-use crate::{bios, API};
+use crate::{bios, crc::crc32, vgaconsole::StatusLine, API};
 use serde::{Deserialize, Serialize};
 
+/// The layout version of `Config` that we store.
+///
+/// Version 1 was used up to v0.8.1, and had no version byte. It always
+/// starts with a 0 or a 1 (the tag for `vga_console`), so those can't be
+/// used as version numbers. Version 2 added the version byte and the CRC.
+///
+/// Only bump this if a setting changes in a way that an older OS would read
+/// wrongly. Adding one on the end doesn't need it.
+const CONFIG_VERSION: u8 = 2;
+
+/// The length of the CRC on the end of the stored config
+const CRC_LEN: usize = 4;
+
+/// The most space a stored config can take up
+const MAX_STORED_LEN: usize = 480;
+
 /// Represents our configuration information that we ask the BIOS to serialise
+///
+/// Any settings missing off the end of a stored config come from
+/// `Config::default()`.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     vga_console: Option<u8>,
     serial_console: bool,
     serial_baud: u32,
+    status_line: Option<StatusLine>,
+    key_repeat: Option<KeyRepeat>,
+    stdin_route: Route,
+    stdout_route: Route,
+    watchdog: Option<u16>,
+    tz_offset_mins: i16,
+    aliases: heapless::Vec<Alias, MAX_ALIASES>,
+    screen_blank_mins: Option<u8>,
+    program_check: ProgramCheck,
+    keymap: Keymap,
+    show_modifiers: bool,
+    screen_saver: ScreenSaver,
+    scrub_tpa: bool,
+    mixer_levels: heapless::Vec<MixerLevel, MAX_MIXER_LEVELS>,
+    printer: Option<Printer>,
+    ramdisk_kib: u16,
+    sensor_limits: Option<SensorLimits>,
+    scheduled: heapless::Vec<ScheduledCommand, MAX_SCHEDULED>,
+    save_history: bool,
+}
+
+/// The layout of `Config` used up to v0.8.1
+#[derive(Debug, Deserialize)]
+struct ConfigV1 {
+    vga_console: Option<u8>,
+    serial_console: bool,
+    serial_baud: u32,
+}
+
+impl From<ConfigV1> for Config {
+    fn from(old: ConfigV1) -> Config {
+        Config {
+            vga_console: old.vga_console,
+            serial_console: old.serial_console,
+            serial_baud: old.serial_baud,
+            ..Default::default()
+        }
+    }
+}
+
+/// How many aliases we can store
+pub const MAX_ALIASES: usize = 4;
+
+/// A short name for a longer command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alias {
+    /// What you type
+    pub name: heapless::String<8>,
+    /// What we run instead
+    pub expansion: heapless::String<32>,
+}
+
+/// How many commands `at` can have waiting to run
+pub const MAX_SCHEDULED: usize = 4;
+
+/// A command to run at a given time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledCommand {
+    /// When to run it, in seconds since the start of 2000 (UTC), like the
+    /// BIOS clock
+    pub when: u32,
+    /// The command line to run
+    pub command: heapless::String<32>,
+}
+
+/// How many mixer levels we can store
+pub const MAX_MIXER_LEVELS: usize = 4;
+
+/// A mixer level to set at start-up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MixerLevel {
+    /// The name the BIOS gives the mixer channel
+    pub name: heapless::String<12>,
+    /// The level to set it to
+    pub level: u8,
+}
+
+/// Displays a timezone offset like `+05:30`
+pub struct TzOffset(pub i16);
+
+impl core::fmt::Display for TzOffset {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let minutes = self.0.unsigned_abs();
+        write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+/// Which consoles a standard stream is connected to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    /// Use the VGA console (and the local keyboard)
+    pub vga: bool,
+    /// Use the serial console
+    pub serial: bool,
+}
+
+impl Route {
+    /// Connected to every console
+    pub const ALL: Route = Route {
+        vga: true,
+        serial: true,
+    };
+
+    /// Parse a route like `vga`, `serial` or `vga+serial`
+    pub fn parse(input: &str) -> Option<Route> {
+        let mut route = Route {
+            vga: false,
+            serial: false,
+        };
+        for part in input.split('+') {
+            match part {
+                "vga" => route.vga = true,
+                "serial" => route.serial = true,
+                _ => return None,
+            }
+        }
+        Some(route)
+    }
+}
+
+impl core::fmt::Display for Route {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (self.vga, self.serial) {
+            (true, true) => write!(f, "vga+serial"),
+            (true, false) => write!(f, "vga"),
+            (false, true) => write!(f, "serial"),
+            (false, false) => write!(f, "none"),
+        }
+    }
+}
+
+/// How fussy the loader is about the checksum footer on programs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProgramCheck {
+    /// Programs with a checksum must match it, but programs without one
+    /// still run
+    Lenient,
+    /// Programs must have a checksum, and must match it
+    Strict,
+}
+
+impl ProgramCheck {
+    /// Parse `lenient` or `strict`
+    pub fn parse(input: &str) -> Option<ProgramCheck> {
+        match input {
+            "lenient" => Some(ProgramCheck::Lenient),
+            "strict" => Some(ProgramCheck::Strict),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ProgramCheck {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProgramCheck::Lenient => write!(f, "lenient"),
+            ProgramCheck::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+/// Which keyboard layout we use, and who applies it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Keymap {
+    /// UK 105-key, applied by the OS
+    Uk,
+    /// US 104-key, applied by the OS
+    Us,
+    /// German 105-key, applied by the OS
+    De,
+    /// French AZERTY, applied by the OS
+    Fr,
+    /// Dvorak, applied by the OS
+    Dvorak,
+    /// Colemak, applied by the OS
+    Colemak,
+    /// The BIOS applies the layout, so the OS mustn't
+    Bios,
+    /// Spanish 105-key, applied by the OS
+    Es,
+}
+
+impl Keymap {
+    /// Parse a keymap name, like `uk` or `bios`
+    pub fn parse(input: &str) -> Option<Keymap> {
+        match input {
+            "uk" => Some(Keymap::Uk),
+            "us" => Some(Keymap::Us),
+            "de" => Some(Keymap::De),
+            "fr" => Some(Keymap::Fr),
+            "dvorak" => Some(Keymap::Dvorak),
+            "colemak" => Some(Keymap::Colemak),
+            "bios" => Some(Keymap::Bios),
+            "es" => Some(Keymap::Es),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for Keymap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            Keymap::Uk => "uk",
+            Keymap::Us => "us",
+            Keymap::De => "de",
+            Keymap::Fr => "fr",
+            Keymap::Dvorak => "dvorak",
+            Keymap::Colemak => "colemak",
+            Keymap::Bios => "bios",
+            Keymap::Es => "es",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What the screen shows when nobody has pressed a key for a while
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScreenSaver {
+    /// Nothing at all
+    Blank,
+    /// Flames, rising from the bottom of the screen
+    Flames,
+}
+
+impl ScreenSaver {
+    /// Parse `blank` or `flames`
+    pub fn parse(input: &str) -> Option<ScreenSaver> {
+        match input {
+            "blank" => Some(ScreenSaver::Blank),
+            "flames" => Some(ScreenSaver::Flames),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for ScreenSaver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScreenSaver::Blank => write!(f, "blank"),
+            ScreenSaver::Flames => write!(f, "flames"),
+        }
+    }
+}
+
+/// Which characters a printer understands
+///
+/// We send text as Code Page 850 (like the console uses), so anything else
+/// needs translating.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrinterCharset {
+    /// Code Page 850, so nothing needs translating
+    Cp850,
+    /// Code Page 437, like most dot-matrix printers use out of the box
+    Cp437,
+    /// Seven-bit ASCII, with accents taken off
+    Ascii,
+}
+
+impl PrinterCharset {
+    /// Parse `cp850`, `cp437` or `ascii`
+    pub fn parse(input: &str) -> Option<PrinterCharset> {
+        match input {
+            "cp850" => Some(PrinterCharset::Cp850),
+            "cp437" => Some(PrinterCharset::Cp437),
+            "ascii" => Some(PrinterCharset::Ascii),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for PrinterCharset {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PrinterCharset::Cp850 => write!(f, "cp850"),
+            PrinterCharset::Cp437 => write!(f, "cp437"),
+            PrinterCharset::Ascii => write!(f, "ascii"),
+        }
+    }
+}
+
+/// Where the printer is plugged in, and what it understands
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Printer {
+    /// Which BIOS serial device the printer is on
+    pub port: u8,
+    /// How fast the printer's serial port runs
+    pub baud: u32,
+    /// Which characters the printer understands
+    pub charset: PrinterCharset,
+}
+
+impl Printer {
+    /// The serial settings for the printer's port
+    ///
+    /// Printers pause us with XON/XOFF, which we handle ourselves, so the
+    /// BIOS doesn't do any handshaking.
+    pub fn serial_config(&self) -> bios::serial::Config {
+        bios::serial::Config {
+            data_rate_bps: self.baud,
+            data_bits: bios::serial::DataBits::Eight.make_ffi_safe(),
+            stop_bits: bios::serial::StopBits::One.make_ffi_safe(),
+            parity: bios::serial::Parity::None.make_ffi_safe(),
+            handshaking: bios::serial::Handshaking::None.make_ffi_safe(),
+        }
+    }
+}
+
+/// Settings for key repeat, when the OS does it instead of the BIOS
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRepeat {
+    /// How long a key must be held down before it repeats, in milliseconds
+    pub delay_ms: u16,
+    /// How many times a second a held key repeats
+    pub rate_hz: u8,
+}
+
+/// When the sensors should make us warn about the power supply or the
+/// temperature
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorLimits {
+    /// Warn if any voltage is below this, in millivolts
+    pub min_mv: u16,
+    /// Warn if any voltage is above this, in millivolts
+    pub max_mv: u16,
+    /// Warn if any temperature is above this, in degrees Celsius
+    pub max_temp_c: i16,
+}
+
+impl core::fmt::Display for SensorLimits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{},{},{}", self.min_mv, self.max_mv, self.max_temp_c)
+    }
 }
 
 impl Config {
     pub fn load() -> Result<Config, &'static str> {
         let api = API.get();
-        let mut buffer = [0u8; 64];
+        let mut buffer = [0u8; MAX_STORED_LEN];
         match (api.configuration_get)(bios::FfiBuffer::new(&mut buffer)) {
-            bios::ApiResult::Ok(n) => {
-                postcard::from_bytes(&buffer[0..n]).map_err(|_e| "Failed to parse config")
-            }
+            bios::ApiResult::Ok(n) => Config::from_bytes(&buffer[0..n]),
             bios::ApiResult::Err(_e) => Err("Failed to load config"),
         }
     }
 
     pub fn save(&self) -> Result<(), &'static str> {
         let api = API.get();
-        let mut buffer = [0u8; 64];
-        let slice = postcard::to_slice(self, &mut buffer).map_err(|_e| "Failed to parse config")?;
+        let mut buffer = [0u8; MAX_STORED_LEN];
+        let slice = self.to_bytes(&mut buffer)?;
         match (api.configuration_set)(bios::FfiByteSlice::new(slice)) {
             bios::ApiResult::Ok(_) => Ok(()),
             bios::ApiResult::Err(bios::Error::Unimplemented) => {
@@ -38,6 +394,41 @@ impl Config {
         }
     }
 
+    /// Parse a stored config, upgrading it if it uses an older layout.
+    fn from_bytes(data: &[u8]) -> Result<Config, &'static str> {
+        let parse_error = |_e| "Failed to parse config";
+        match data.first() {
+            Some(0) | Some(1) => {
+                let old: ConfigV1 = postcard::from_bytes(data).map_err(parse_error)?;
+                Ok(old.into())
+            }
+            Some(&CONFIG_VERSION) => {
+                Config::deserialize(Fields::new(check_crc(data)?)).map_err(parse_error)
+            }
+            Some(_) => Err("Config is from a newer OS"),
+            None => Err("Config is empty"),
+        }
+    }
+
+    /// Serialise this config, with a version byte and CRC, into the given
+    /// buffer.
+    ///
+    /// This is what the BIOS stores. A BIOS can use it to make a config the
+    /// OS will accept.
+    pub fn to_bytes<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a [u8], &'static str> {
+        let (version, rest) = buffer.split_first_mut().ok_or("Buffer too small")?;
+        *version = CONFIG_VERSION;
+        let used = 1 + postcard::to_slice(self, rest)
+            .map_err(|_e| "Failed to serialise config")?
+            .len();
+        let crc = crc32(&buffer[0..used]).to_le_bytes();
+        buffer
+            .get_mut(used..used + CRC_LEN)
+            .ok_or("Buffer too small")?
+            .copy_from_slice(&crc);
+        Ok(&buffer[0..used + CRC_LEN])
+    }
+
     /// Should this system use the VGA console?
     pub fn get_vga_console(&self) -> Option<bios::video::Mode> {
         self.vga_console.and_then(bios::video::Mode::try_from_u8)
@@ -77,6 +468,490 @@ impl Config {
         self.serial_console = true;
         self.serial_baud = serial_baud;
     }
+
+    /// Where should the status line go (if anywhere)?
+    pub fn get_status_line(&self) -> Option<StatusLine> {
+        self.status_line
+    }
+
+    /// Set where the status line should go
+    pub fn set_status_line(&mut self, new_value: Option<StatusLine>) {
+        self.status_line = new_value;
+    }
+
+    /// Should the OS make held keys repeat?
+    pub fn get_key_repeat(&self) -> Option<KeyRepeat> {
+        self.key_repeat
+    }
+
+    /// Set whether the OS should make held keys repeat
+    pub fn set_key_repeat(&mut self, new_value: Option<KeyRepeat>) {
+        self.key_repeat = new_value;
+    }
+
+    /// Which consoles should standard input come from?
+    pub fn get_stdin_route(&self) -> Route {
+        self.stdin_route
+    }
+
+    /// Set which consoles standard input comes from
+    pub fn set_stdin_route(&mut self, new_value: Route) {
+        self.stdin_route = new_value;
+    }
+
+    /// Which consoles should standard output go to?
+    pub fn get_stdout_route(&self) -> Route {
+        self.stdout_route
+    }
+
+    /// Set which consoles standard output goes to
+    pub fn set_stdout_route(&mut self, new_value: Route) {
+        self.stdout_route = new_value;
+    }
+
+    /// How long (in seconds) can the OS go without kicking the watchdog?
+    pub fn get_watchdog(&self) -> Option<u16> {
+        self.watchdog
+    }
+
+    /// Set the watchdog timeout, in seconds
+    pub fn set_watchdog(&mut self, new_value: Option<u16>) {
+        self.watchdog = new_value;
+    }
+
+    /// What timezone are we in, in minutes east of UTC?
+    pub fn get_tz_offset(&self) -> i16 {
+        self.tz_offset_mins
+    }
+
+    /// Set our timezone, in minutes east of UTC
+    pub fn set_tz_offset(&mut self, new_value: i16) {
+        self.tz_offset_mins = new_value;
+    }
+
+    /// How many minutes without a key press blanks the screen (if ever)?
+    pub fn get_screen_blank(&self) -> Option<u8> {
+        self.screen_blank_mins
+    }
+
+    /// Set how many minutes without a key press blanks the screen
+    pub fn set_screen_blank(&mut self, new_value: Option<u8>) {
+        self.screen_blank_mins = new_value;
+    }
+
+    /// How fussy is the loader about program checksums?
+    pub fn get_program_check(&self) -> ProgramCheck {
+        self.program_check
+    }
+
+    /// Set how fussy the loader is about program checksums
+    pub fn set_program_check(&mut self, new_value: ProgramCheck) {
+        self.program_check = new_value;
+    }
+
+    /// Which keyboard layout do we use?
+    pub fn get_keymap(&self) -> Keymap {
+        self.keymap
+    }
+
+    /// Set which keyboard layout we use
+    pub fn set_keymap(&mut self, new_value: Keymap) {
+        self.keymap = new_value;
+    }
+
+    /// Should the status line show which modifier keys are held down?
+    pub fn get_show_modifiers(&self) -> bool {
+        self.show_modifiers
+    }
+
+    /// Set whether the status line shows which modifier keys are held down
+    pub fn set_show_modifiers(&mut self, new_value: bool) {
+        self.show_modifiers = new_value;
+    }
+
+    /// What does the screen show when nobody has pressed a key for a while?
+    pub fn get_screen_saver(&self) -> ScreenSaver {
+        self.screen_saver
+    }
+
+    /// Set what the screen shows when nobody has pressed a key for a while
+    pub fn set_screen_saver(&mut self, new_value: ScreenSaver) {
+        self.screen_saver = new_value;
+    }
+
+    /// Should we wipe the TPA and the handle table after a program runs?
+    pub fn get_scrub_tpa(&self) -> bool {
+        self.scrub_tpa
+    }
+
+    /// Set whether we wipe the TPA and the handle table after a program runs
+    pub fn set_scrub_tpa(&mut self, new_value: bool) {
+        self.scrub_tpa = new_value;
+    }
+
+    /// What levels do we set the mixer channels to at start-up?
+    pub fn get_mixer_levels(&self) -> &[MixerLevel] {
+        &self.mixer_levels
+    }
+
+    /// Set the level a mixer channel gets at start-up, replacing any level
+    /// we already had for it.
+    pub fn set_mixer_level(&mut self, name: &str, level: u8) -> Result<(), &'static str> {
+        let mut new_level = MixerLevel {
+            name: heapless::String::new(),
+            level,
+        };
+        new_level
+            .name
+            .push_str(name)
+            .map_err(|_| "Mixer name too long")?;
+        if let Some(old) = self
+            .mixer_levels
+            .iter_mut()
+            .find(|m| m.name == new_level.name)
+        {
+            *old = new_level;
+            Ok(())
+        } else {
+            self.mixer_levels
+                .push(new_level)
+                .map_err(|_| "Too many mixer levels")
+        }
+    }
+
+    /// Is there a printer, and if so, where?
+    pub fn get_printer(&self) -> Option<Printer> {
+        self.printer
+    }
+
+    /// Set where the printer is (if there is one)
+    pub fn set_printer(&mut self, new_value: Option<Printer>) {
+        self.printer = new_value;
+    }
+
+    /// How big a RAM disk should we make at start-up, in KiB (0 for none)?
+    pub fn get_ramdisk_kib(&self) -> u16 {
+        self.ramdisk_kib
+    }
+
+    /// Set how big a RAM disk to make at start-up, in KiB (0 for none)
+    pub fn set_ramdisk_kib(&mut self, new_value: u16) {
+        self.ramdisk_kib = new_value;
+    }
+
+    /// When should the sensors warn us, if at all?
+    pub fn get_sensor_limits(&self) -> Option<SensorLimits> {
+        self.sensor_limits
+    }
+
+    /// Set when the sensors should warn us (or `None` to never warn)
+    pub fn set_sensor_limits(&mut self, new_value: Option<SensorLimits>) {
+        self.sensor_limits = new_value;
+    }
+
+    /// Do we keep the shell history in `HISTORY.TXT` between restarts?
+    pub fn get_save_history(&self) -> bool {
+        self.save_history
+    }
+
+    /// Set whether we keep the shell history in `HISTORY.TXT` between
+    /// restarts
+    pub fn set_save_history(&mut self, new_value: bool) {
+        self.save_history = new_value;
+    }
+
+    /// What commands are waiting to run, soonest first?
+    pub fn get_scheduled(&self) -> &[ScheduledCommand] {
+        &self.scheduled
+    }
+
+    /// Schedule a command to run at the given time (in seconds on the BIOS
+    /// clock). Scheduling the same command for the same time twice does
+    /// nothing.
+    pub fn add_scheduled(&mut self, when: u32, command: &str) -> Result<(), &'static str> {
+        let mut new_command = ScheduledCommand {
+            when,
+            command: heapless::String::new(),
+        };
+        new_command
+            .command
+            .push_str(command)
+            .map_err(|_| "Command too long")?;
+        if self.scheduled.contains(&new_command) {
+            return Ok(());
+        }
+        let idx = self
+            .scheduled
+            .iter()
+            .position(|s| s.when > when)
+            .unwrap_or(self.scheduled.len());
+        self.scheduled
+            .insert(idx, new_command)
+            .map_err(|_| "Too many scheduled commands")
+    }
+
+    /// Stop a scheduled command from running, given its position in
+    /// [`Self::get_scheduled`].
+    pub fn remove_scheduled(&mut self, idx: usize) -> Option<ScheduledCommand> {
+        if idx < self.scheduled.len() {
+            Some(self.scheduled.remove(idx))
+        } else {
+            None
+        }
+    }
+
+    /// What are all our aliases?
+    pub fn get_aliases(&self) -> &[Alias] {
+        &self.aliases
+    }
+
+    /// What does the given alias expand to?
+    pub fn get_alias(&self, name: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|alias| alias.name == name)
+            .map(|alias| alias.expansion.as_str())
+    }
+
+    /// Add an alias, or change an existing one
+    pub fn set_alias(&mut self, name: &str, expansion: &str) -> Result<(), &'static str> {
+        let mut new_alias = Alias {
+            name: heapless::String::new(),
+            expansion: heapless::String::new(),
+        };
+        new_alias
+            .name
+            .push_str(name)
+            .map_err(|_| "Alias name too long")?;
+        new_alias
+            .expansion
+            .push_str(expansion)
+            .map_err(|_| "Alias expansion too long")?;
+        if let Some(alias) = self.aliases.iter_mut().find(|a| a.name == new_alias.name) {
+            *alias = new_alias;
+            Ok(())
+        } else {
+            self.aliases.push(new_alias).map_err(|_| "Too many aliases")
+        }
+    }
+
+    /// Write out our settings as `key=value` lines, which `set_from_text`
+    /// can read back in.
+    pub fn export(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        writeln!(out, "# Neotron OS configuration")?;
+        match self.vga_console {
+            Some(mode) => writeln!(out, "vga={}", mode)?,
+            None => writeln!(out, "vga=off")?,
+        }
+        if self.serial_console {
+            writeln!(out, "serial={}", self.serial_baud)?;
+        } else {
+            writeln!(out, "serial=off")?;
+        }
+        match self.status_line {
+            Some(StatusLine::Top) => writeln!(out, "status=top")?,
+            Some(StatusLine::Bottom) => writeln!(out, "status=bottom")?,
+            None => writeln!(out, "status=off")?,
+        }
+        match self.key_repeat {
+            Some(key_repeat) => {
+                writeln!(out, "repeat={},{}", key_repeat.delay_ms, key_repeat.rate_hz)?
+            }
+            None => writeln!(out, "repeat=off")?,
+        }
+        writeln!(out, "stdin={}", self.stdin_route)?;
+        writeln!(out, "stdout={}", self.stdout_route)?;
+        match self.watchdog {
+            Some(secs) => writeln!(out, "watchdog={}", secs)?,
+            None => writeln!(out, "watchdog=off")?,
+        }
+        writeln!(out, "tz={}", self.tz_offset_mins)?;
+        match self.screen_blank_mins {
+            Some(mins) => writeln!(out, "blank={}", mins)?,
+            None => writeln!(out, "blank=off")?,
+        }
+        writeln!(out, "verify={}", self.program_check)?;
+        writeln!(out, "keymap={}", self.keymap)?;
+        writeln!(
+            out,
+            "modifiers={}",
+            if self.show_modifiers { "on" } else { "off" }
+        )?;
+        writeln!(out, "saver={}", self.screen_saver)?;
+        writeln!(out, "scrub={}", if self.scrub_tpa { "on" } else { "off" })?;
+        match self.printer {
+            Some(printer) => writeln!(
+                out,
+                "printer={},{},{}",
+                printer.port, printer.baud, printer.charset
+            )?,
+            None => writeln!(out, "printer=off")?,
+        }
+        writeln!(out, "ramdisk={}", self.ramdisk_kib)?;
+        match self.sensor_limits {
+            Some(limits) => writeln!(out, "sensors={}", limits)?,
+            None => writeln!(out, "sensors=off")?,
+        }
+        writeln!(
+            out,
+            "history={}",
+            if self.save_history { "on" } else { "off" }
+        )?;
+        for mixer in self.mixer_levels.iter() {
+            writeln!(out, "mixer.{}={}", mixer.name, mixer.level)?;
+        }
+        for alias in self.aliases.iter() {
+            writeln!(out, "alias.{}={}", alias.name, alias.expansion)?;
+        }
+        for scheduled in self.scheduled.iter() {
+            writeln!(out, "at.{}={}", scheduled.when, scheduled.command)?;
+        }
+        Ok(())
+    }
+
+    /// Change one setting, given as a line from a file written by `export`.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn set_from_text(&mut self, line: &str) -> Result<(), &'static str> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (key, value) = line.split_once('=').ok_or("Missing '='")?;
+        let (key, value) = (key.trim(), value.trim());
+        match (key, value) {
+            ("vga", "off") => self.vga_console = None,
+            ("vga", _) => {
+                let mode = value
+                    .parse::<u8>()
+                    .ok()
+                    .and_then(bios::video::Mode::try_from_u8)
+                    .filter(|m| m.is_text_mode())
+                    .ok_or("Not a valid text mode")?;
+                self.set_vga_console(Some(mode));
+            }
+            ("serial", "off") => self.set_serial_console_off(),
+            ("serial", _) => {
+                let baud = value.parse::<u32>().map_err(|_| "Bad baud rate")?;
+                self.set_serial_console_on(baud);
+            }
+            ("status", "top") => self.status_line = Some(StatusLine::Top),
+            ("status", "bottom") => self.status_line = Some(StatusLine::Bottom),
+            ("status", "off") => self.status_line = None,
+            ("repeat", "off") => self.key_repeat = None,
+            ("repeat", _) => {
+                let (delay, rate) = value.split_once(',').ok_or("Bad key repeat")?;
+                let delay_ms = delay.trim().parse::<u16>().map_err(|_| "Bad key repeat")?;
+                let rate_hz = rate.trim().parse::<u8>().map_err(|_| "Bad key repeat")?;
+                if rate_hz == 0 {
+                    return Err("Bad key repeat");
+                }
+                self.key_repeat = Some(KeyRepeat { delay_ms, rate_hz });
+            }
+            ("stdin", _) => self.stdin_route = Route::parse(value).ok_or("Bad route")?,
+            ("stdout", _) => self.stdout_route = Route::parse(value).ok_or("Bad route")?,
+            ("watchdog", "off") => self.watchdog = None,
+            ("watchdog", _) => {
+                let secs = value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or("Bad watchdog timeout")?;
+                self.watchdog = Some(secs);
+            }
+            ("blank", "off") => self.screen_blank_mins = None,
+            ("blank", _) => {
+                let mins = value
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|m| *m > 0)
+                    .ok_or("Bad screen blank timeout")?;
+                self.screen_blank_mins = Some(mins);
+            }
+            ("verify", _) => {
+                self.program_check = ProgramCheck::parse(value).ok_or("Bad verify mode")?;
+            }
+            ("modifiers", "on") => self.show_modifiers = true,
+            ("modifiers", "off") => self.show_modifiers = false,
+            ("keymap", _) => self.keymap = Keymap::parse(value).ok_or("Bad keymap")?,
+            ("saver", _) => {
+                self.screen_saver = ScreenSaver::parse(value).ok_or("Bad screensaver")?;
+            }
+            ("scrub", "on") => self.scrub_tpa = true,
+            ("scrub", "off") => self.scrub_tpa = false,
+            ("history", "on") => self.save_history = true,
+            ("history", "off") => self.save_history = false,
+            ("printer", "off") => self.printer = None,
+            ("printer", _) => {
+                let mut parts = value.split(',').map(str::trim);
+                let port = parts.next().and_then(|s| s.parse::<u8>().ok());
+                let baud = parts.next().and_then(|s| s.parse::<u32>().ok());
+                let charset = parts.next().and_then(PrinterCharset::parse);
+                let (Some(port), Some(baud), Some(charset), None) =
+                    (port, baud, charset, parts.next())
+                else {
+                    return Err("Bad printer");
+                };
+                self.printer = Some(Printer {
+                    port,
+                    baud,
+                    charset,
+                });
+            }
+            ("ramdisk", _) => {
+                self.ramdisk_kib = value.parse::<u16>().map_err(|_e| "Bad RAM disk size")?;
+            }
+            ("sensors", "off") => self.sensor_limits = None,
+            ("sensors", _) => {
+                let mut parts = value.split(',').map(str::trim);
+                let min_mv = parts.next().and_then(|s| s.parse::<u16>().ok());
+                let max_mv = parts.next().and_then(|s| s.parse::<u16>().ok());
+                let max_temp_c = parts.next().and_then(|s| s.parse::<i16>().ok());
+                let (Some(min_mv), Some(max_mv), Some(max_temp_c), None) =
+                    (min_mv, max_mv, max_temp_c, parts.next())
+                else {
+                    return Err("Bad sensor limits");
+                };
+                if min_mv > max_mv {
+                    return Err("Bad sensor limits");
+                }
+                self.sensor_limits = Some(SensorLimits {
+                    min_mv,
+                    max_mv,
+                    max_temp_c,
+                });
+            }
+            ("tz", _) => {
+                self.tz_offset_mins = value
+                    .parse::<i16>()
+                    .ok()
+                    .filter(|m| (-14 * 60..=14 * 60).contains(m))
+                    .ok_or("Bad timezone")?;
+            }
+            _ => {
+                if let Some(name) = key.strip_prefix("mixer.") {
+                    let level = value.parse::<u8>().map_err(|_| "Bad mixer level")?;
+                    self.set_mixer_level(name, level)?;
+                } else if let Some(when) = key.strip_prefix("at.") {
+                    let when = when.parse::<u32>().map_err(|_| "Bad time")?;
+                    self.add_scheduled(when, value)?;
+                } else {
+                    let name = key.strip_prefix("alias.").ok_or("Unknown setting")?;
+                    self.set_alias(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove an alias. Returns false if there was no such alias.
+    pub fn remove_alias(&mut self, name: &str) -> bool {
+        let Some(idx) = self.aliases.iter().position(|a| a.name == name) else {
+            return false;
+        };
+        self.aliases.remove(idx);
+        true
+    }
 }
 
 impl core::default::Default for Config {
@@ -85,7 +960,342 @@ impl core::default::Default for Config {
             vga_console: Some(0),
             serial_console: false,
             serial_baud: 115200,
+            status_line: None,
+            key_repeat: None,
+            stdin_route: Route::ALL,
+            stdout_route: Route::ALL,
+            watchdog: None,
+            tz_offset_mins: 0,
+            aliases: heapless::Vec::new(),
+            screen_blank_mins: None,
+            program_check: ProgramCheck::Lenient,
+            keymap: Keymap::Uk,
+            show_modifiers: false,
+            screen_saver: ScreenSaver::Blank,
+            scrub_tpa: true,
+            mixer_levels: heapless::Vec::new(),
+            printer: None,
+            ramdisk_kib: 0,
+            sensor_limits: None,
+            scheduled: heapless::Vec::new(),
+            save_history: false,
+        }
+    }
+}
+
+/// Check the CRC on the end of a stored config.
+///
+/// Gives back the serialised `Config`, without the version byte or the CRC.
+fn check_crc(data: &[u8]) -> Result<&[u8], &'static str> {
+    if data.len() < 1 + CRC_LEN {
+        return Err("Config corrupt");
+    }
+    let (contents, crc) = data.split_at(data.len() - CRC_LEN);
+    if crc32(contents).to_le_bytes() != crc {
+        return Err("Config corrupt");
+    }
+    Ok(&contents[1..])
+}
+
+/// Reads a stored `Config` one field at a time, stopping cleanly when the
+/// data runs out.
+///
+/// A plain postcard deserialiser wants every field of a struct, so it can't
+/// read a config saved before the newer settings were added.
+struct Fields<'de> {
+    data: &'de [u8],
+}
+
+impl<'de> Fields<'de> {
+    fn new(data: &'de [u8]) -> Fields<'de> {
+        Fields { data }
+    }
+}
+
+impl<'de> serde::de::SeqAccess<'de> for Fields<'de> {
+    type Error = postcard::Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, postcard::Error>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let mut de = postcard::Deserializer::from_bytes(self.data);
+        let value = seed.deserialize(&mut de)?;
+        self.data = de.finalize()?;
+        Ok(Some(value))
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for Fields<'de> {
+    type Error = postcard::Error;
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, postcard::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_seq(self)
+    }
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, postcard::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        // We only read structs
+        Err(postcard::Error::WontImplement)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_v1() {
+        // VGA mode 0, serial on at 115200 bps
+        let data = [0x01, 0x00, 0x01, 0x80, 0x84, 0x07];
+        let config = Config::from_bytes(&data).unwrap();
+        assert_eq!(config.vga_console, Some(0));
+        assert!(config.serial_console);
+        assert_eq!(config.serial_baud, 115200);
+        // Newer settings get their defaults
+        assert_eq!(config.get_status_line(), None);
+        assert_eq!(config.get_stdout_route(), Route::ALL);
+        assert_eq!(config.get_tz_offset(), 0);
+        assert!(config.get_aliases().is_empty());
+    }
+
+    #[test]
+    fn load_v1_vga_off() {
+        let data = [0x00, 0x00, 0x00];
+        let config = Config::from_bytes(&data).unwrap();
+        assert_eq!(config.vga_console, None);
+        assert!(!config.serial_console);
+        assert_eq!(config.serial_baud, 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = Config::default();
+        config.set_tz_offset(-300);
+        config.set_watchdog(Some(10));
+        config.set_alias("ll", "dir").unwrap();
+        config.set_screen_blank(Some(5));
+        config.set_program_check(ProgramCheck::Strict);
+        config.set_keymap(Keymap::Bios);
+        config.set_show_modifiers(true);
+        config.set_screen_saver(ScreenSaver::Flames);
+        config.set_scrub_tpa(false);
+        config.set_mixer_level("LineOut", 50).unwrap();
+        let mut buffer = [0u8; 256];
+        let data = config.to_bytes(&mut buffer).unwrap();
+        assert_eq!(data[0], CONFIG_VERSION);
+        let loaded = Config::from_bytes(data).unwrap();
+        assert_eq!(loaded.get_tz_offset(), -300);
+        assert_eq!(loaded.get_watchdog(), Some(10));
+        assert_eq!(loaded.get_screen_blank(), Some(5));
+        assert_eq!(loaded.get_alias("ll"), Some("dir"));
+        assert_eq!(loaded.get_program_check(), ProgramCheck::Strict);
+        assert_eq!(loaded.get_keymap(), Keymap::Bios);
+        assert!(loaded.get_show_modifiers());
+        assert_eq!(loaded.get_screen_saver(), ScreenSaver::Flames);
+        assert!(!loaded.get_scrub_tpa());
+        assert_eq!(loaded.get_mixer_levels()[0].level, 50);
+    }
+
+    #[test]
+    fn load_truncated() {
+        let mut config = Config::default();
+        config.set_tz_offset(-300);
+        config.set_ramdisk_kib(300);
+        config.set_save_history(true);
+        let mut buffer = [0u8; 256];
+        let len = config.to_bytes(&mut buffer).unwrap().len() - CRC_LEN;
+        // Cut `len - cut` bytes off the end, as if an older OS had saved it
+        let load = |cut: usize| {
+            let mut data = buffer[0..cut].to_vec();
+            data.extend_from_slice(&crc32(&data).to_le_bytes());
+            Config::from_bytes(&data)
+        };
+        // Without the sensor limits, the scheduled commands or the history
+        // flag (one byte each)
+        let loaded = load(len - 3).unwrap();
+        assert_eq!(loaded.get_tz_offset(), -300);
+        assert_eq!(loaded.get_ramdisk_kib(), 300);
+        assert!(!loaded.get_save_history());
+        // Without the RAM disk size (two bytes) too
+        let loaded = load(len - 5).unwrap();
+        assert_eq!(loaded.get_tz_offset(), -300);
+        assert_eq!(loaded.get_ramdisk_kib(), 0);
+        // Half a setting is no good
+        assert!(load(len - 4).is_err());
+    }
+
+    #[test]
+    fn save_history() {
+        let mut config = Config::default();
+        assert!(!config.get_save_history());
+        config.set_from_text("history=on").unwrap();
+        let mut buffer = [0u8; 256];
+        let data = config.to_bytes(&mut buffer).unwrap();
+        let loaded = Config::from_bytes(data).unwrap();
+        assert!(loaded.get_save_history());
+        let mut text = String::new();
+        loaded.export(&mut text).unwrap();
+        assert!(text.contains("history=on\n"), "{}", text);
+    }
+
+    #[test]
+    fn scheduled() {
+        let mut config = Config::default();
+        config.add_scheduled(2000, "play ALARM.MOD").unwrap();
+        config.set_from_text("at.1000=ping 10.0.0.1").unwrap();
+        // The same again does nothing
+        config.add_scheduled(2000, "play ALARM.MOD").unwrap();
+        assert!(config
+            .add_scheduled(3000, "a command that is much too long to keep")
+            .is_err());
+        let scheduled: Vec<(u32, &str)> = config
+            .get_scheduled()
+            .iter()
+            .map(|s| (s.when, s.command.as_str()))
+            .collect();
+        assert_eq!(
+            scheduled,
+            [(1000, "ping 10.0.0.1"), (2000, "play ALARM.MOD")]
+        );
+        let mut text = String::new();
+        config.export(&mut text).unwrap();
+        assert!(text.contains("at.2000=play ALARM.MOD\n"), "{}", text);
+        let removed = config.remove_scheduled(0).unwrap();
+        assert_eq!(removed.when, 1000);
+        assert!(config.remove_scheduled(1).is_none());
+        for when in 0..3 {
+            config.add_scheduled(when, "dir").unwrap();
+        }
+        assert!(config.add_scheduled(5, "dir").is_err());
+    }
+
+    #[test]
+    fn biggest_config_fits() {
+        let mut config = Config::default();
+        for idx in 0..MAX_ALIASES {
+            let name = format!("alias{}", idx);
+            config.set_alias(&name, &"x".repeat(32)).unwrap();
         }
+        for idx in 0..MAX_MIXER_LEVELS {
+            let name = format!("Mixer{:07}", idx);
+            config.set_mixer_level(&name, 100).unwrap();
+        }
+        for idx in 0..MAX_SCHEDULED {
+            config
+                .add_scheduled(u32::MAX - idx as u32, &"y".repeat(32))
+                .unwrap();
+        }
+        config.set_from_text("printer=255,4000000,cp437").unwrap();
+        config.set_from_text("sensors=65535,65535,-32768").unwrap();
+        let mut buffer = [0u8; MAX_STORED_LEN];
+        assert!(config.to_bytes(&mut buffer).is_ok());
+    }
+
+    #[test]
+    fn sensor_limits() {
+        let mut config = Config::default();
+        config.set_from_text("sensors=4750,5250,60").unwrap();
+        let expected = SensorLimits {
+            min_mv: 4750,
+            max_mv: 5250,
+            max_temp_c: 60,
+        };
+        assert_eq!(config.get_sensor_limits(), Some(expected));
+        assert!(config.set_from_text("sensors=5250,4750,60").is_err());
+        assert!(config.set_from_text("sensors=4750,5250").is_err());
+        let mut buffer = [0u8; 256];
+        let data = config.to_bytes(&mut buffer).unwrap();
+        let loaded = Config::from_bytes(data).unwrap();
+        assert_eq!(loaded.get_sensor_limits(), Some(expected));
+        let mut text = String::new();
+        loaded.export(&mut text).unwrap();
+        assert!(text.contains("sensors=4750,5250,60\n"), "{}", text);
+        config.set_from_text("sensors=off").unwrap();
+        assert_eq!(config.get_sensor_limits(), None);
+    }
+
+    #[test]
+    fn printer() {
+        let mut config = Config::default();
+        config.set_from_text("printer=1,9600,cp437").unwrap();
+        let expected = Printer {
+            port: 1,
+            baud: 9600,
+            charset: PrinterCharset::Cp437,
+        };
+        assert_eq!(config.get_printer(), Some(expected));
+        assert!(config.set_from_text("printer=1,9600").is_err());
+        assert!(config.set_from_text("printer=1,9600,ebcdic").is_err());
+        let mut buffer = [0u8; 256];
+        let data = config.to_bytes(&mut buffer).unwrap();
+        let loaded = Config::from_bytes(data).unwrap();
+        assert_eq!(loaded.get_printer(), Some(expected));
+        let mut text = String::new();
+        loaded.export(&mut text).unwrap();
+        assert!(text.contains("printer=1,9600,cp437\n"), "{}", text);
+        config.set_from_text("printer=off").unwrap();
+        assert_eq!(config.get_printer(), None);
+    }
+
+    #[test]
+    fn mixer_levels() {
+        let mut config = Config::default();
+        config.set_mixer_level("HeadphoneOut", 100).unwrap();
+        config.set_from_text("mixer.LineIn=20").unwrap();
+        config.set_mixer_level("HeadphoneOut", 80).unwrap();
+        assert!(config.set_mixer_level("MuchTooLongName", 1).is_err());
+        let mut buffer = [0u8; 256];
+        let data = config.to_bytes(&mut buffer).unwrap();
+        let loaded = Config::from_bytes(data).unwrap();
+        let levels: Vec<(&str, u8)> = loaded
+            .get_mixer_levels()
+            .iter()
+            .map(|m| (m.name.as_str(), m.level))
+            .collect();
+        assert_eq!(levels, [("HeadphoneOut", 80), ("LineIn", 20)]);
+    }
+
+    #[test]
+    fn reject_corrupt() {
+        let config = Config::default();
+        let mut buffer = [0u8; 256];
+        let len = config.to_bytes(&mut buffer).unwrap().len();
+        buffer[2] ^= 0x01;
+        assert_eq!(
+            Config::from_bytes(&buffer[0..len]).err(),
+            Some("Config corrupt")
+        );
+        // Too short to even hold a CRC
+        assert_eq!(
+            Config::from_bytes(&[CONFIG_VERSION]).err(),
+            Some("Config corrupt")
+        );
+    }
+
+    #[test]
+    fn reject_newer() {
+        let data = [CONFIG_VERSION + 1, 0x00];
+        assert!(Config::from_bytes(&data).is_err());
     }
 }
 
@@ -0,0 +1,53 @@
+//! # CRC32
+//!
+//! The CRC32 used by Ethernet and zip, worked out a bit at a time so we don't
+//! need a table. We use it to check stored configs, programs and files sent
+//! over the serial port.
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The CRC32 polynomial, bit-reversed
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Calculate the CRC32 of some data
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Carry on working out a CRC32, given the CRC32 of the bytes before `data`
+/// (or 0 if there weren't any).
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLYNOMIAL & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn in_pieces() {
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+    }
+}
+
+// End of file
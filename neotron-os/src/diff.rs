@@ -0,0 +1,273 @@
+//! # Line-based Diff
+//!
+//! Compares two small text files, line by line, and works out which lines
+//! were removed from the first and added to the second.
+//!
+//! We use the classic longest-common-subsequence table, which needs two
+//! bytes for every pair of lines. So this is only for small files (like
+//! config files and BASIC programs), and the caller gives us some scratch
+//! memory to work in.
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// One line that differs between the two files
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change<'a> {
+    /// A line in the old file that isn't in the new file
+    Removed {
+        /// Which line in the old file (from 1)
+        line_no: usize,
+        /// The line, without the line ending
+        text: &'a [u8],
+    },
+    /// A line in the new file that isn't in the old file
+    Added {
+        /// Which line in the new file (from 1)
+        line_no: usize,
+        /// The line, without the line ending
+        text: &'a [u8],
+    },
+}
+
+/// The files have too many lines to compare in the scratch memory we were
+/// given
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TooBig;
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Where each line of a file is, in some scratch memory
+struct LineTable<'a> {
+    /// The file
+    data: &'a [u8],
+    /// The start and end of each line, as pairs of little-endian `u32`s
+    bounds: &'a mut [u8],
+}
+
+impl<'a> LineTable<'a> {
+    /// How many bytes of scratch memory we need for each line
+    const ENTRY_LEN: usize = 8;
+
+    /// Find every line in `data`, using some of `scratch` to keep track.
+    ///
+    /// Returns the table, and whatever scratch memory we didn't need.
+    fn new(data: &'a [u8], scratch: &'a mut [u8]) -> Result<(LineTable<'a>, &'a mut [u8]), TooBig> {
+        let num_lines = lines(data).count();
+        let len = num_lines.checked_mul(Self::ENTRY_LEN).ok_or(TooBig)?;
+        if len > scratch.len() {
+            return Err(TooBig);
+        }
+        let (bounds, rest) = scratch.split_at_mut(len);
+        for ((start, end), entry) in lines(data).zip(bounds.chunks_exact_mut(Self::ENTRY_LEN)) {
+            entry[0..4].copy_from_slice(&(start as u32).to_le_bytes());
+            entry[4..8].copy_from_slice(&(end as u32).to_le_bytes());
+        }
+        Ok((LineTable { data, bounds }, rest))
+    }
+
+    /// How many lines are there?
+    fn len(&self) -> usize {
+        self.bounds.len() / Self::ENTRY_LEN
+    }
+
+    /// Get a line, without the line ending
+    fn line(&self, idx: usize) -> &'a [u8] {
+        let entry = &self.bounds[idx * Self::ENTRY_LEN..(idx + 1) * Self::ENTRY_LEN];
+        let start = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+        let end = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+        &self.data[start..end]
+    }
+}
+
+/// For every pair of lines, how long the longest common subsequence is of
+/// the rest of the old file (from that line) and the rest of the new file
+/// (from that line).
+struct LcsTable<'a> {
+    /// The entries, as little-endian `u16`s
+    entries: &'a mut [u8],
+    /// How many entries in each row (one more than the lines in the new
+    /// file)
+    row_len: usize,
+}
+
+impl<'a> LcsTable<'a> {
+    /// Get the entry for line `i` of the old file and line `j` of the new
+    /// file
+    fn get(&self, i: usize, j: usize) -> u16 {
+        let idx = (i * self.row_len + j) * 2;
+        u16::from_le_bytes([self.entries[idx], self.entries[idx + 1]])
+    }
+
+    /// Set the entry for line `i` of the old file and line `j` of the new
+    /// file
+    fn set(&mut self, i: usize, j: usize, value: u16) {
+        let idx = (i * self.row_len + j) * 2;
+        self.entries[idx..idx + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Compare two files, line by line, calling `f` for each line that was
+/// removed or added.
+///
+/// The changes come in order. Where lines were replaced, the removed lines
+/// come before the added ones. Returns how many changes there were (0 if
+/// the files have the same lines).
+pub fn diff<'a, F>(
+    old: &'a [u8],
+    new: &'a [u8],
+    scratch: &'a mut [u8],
+    mut f: F,
+) -> Result<usize, TooBig>
+where
+    F: FnMut(Change<'a>),
+{
+    let (old_lines, scratch) = LineTable::new(old, scratch)?;
+    let (new_lines, scratch) = LineTable::new(new, scratch)?;
+    let (n, m) = (old_lines.len(), new_lines.len());
+    // The table entries can't be bigger than the number of lines
+    if n > usize::from(u16::MAX) || m > usize::from(u16::MAX) {
+        return Err(TooBig);
+    }
+    let table_len = (n + 1)
+        .checked_mul(m + 1)
+        .and_then(|x| x.checked_mul(2))
+        .ok_or(TooBig)?;
+    let mut table = LcsTable {
+        entries: scratch.get_mut(0..table_len).ok_or(TooBig)?,
+        row_len: m + 1,
+    };
+
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            let value = if i == n || j == m {
+                0
+            } else if old_lines.line(i) == new_lines.line(j) {
+                table.get(i + 1, j + 1) + 1
+            } else {
+                table.get(i + 1, j).max(table.get(i, j + 1))
+            };
+            table.set(i, j, value);
+        }
+    }
+
+    // Walk through the table, following the longest common subsequence
+    let (mut i, mut j) = (0, 0);
+    let mut changes = 0;
+    while i < n || j < m {
+        if i < n && j < m && old_lines.line(i) == new_lines.line(j) {
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && table.get(i + 1, j) >= table.get(i, j + 1)) {
+            f(Change::Removed {
+                line_no: i + 1,
+                text: old_lines.line(i),
+            });
+            changes += 1;
+            i += 1;
+        } else {
+            f(Change::Added {
+                line_no: j + 1,
+                text: new_lines.line(j),
+            });
+            changes += 1;
+            j += 1;
+        }
+    }
+
+    Ok(changes)
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Find the start and end of each line.
+///
+/// Lines end with `\n` or `\r\n`, which is not included. The last line
+/// doesn't need a line ending.
+fn lines(data: &[u8]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = 0;
+    core::iter::from_fn(move || {
+        if start >= data.len() {
+            return None;
+        }
+        let line_start = start;
+        let mut end = match data[start..].iter().position(|b| *b == b'\n') {
+            Some(pos) => start + pos,
+            None => data.len(),
+        };
+        start = end + 1;
+        if end > line_start && data[end - 1] == b'\r' {
+            end -= 1;
+        }
+        Some((line_start, end))
+    })
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a diff and describe the changes, like `-2 b` and `+3 x`
+    fn changes(old: &str, new: &str) -> Vec<String> {
+        let mut scratch = [0u8; 1024];
+        let mut result = Vec::new();
+        diff(old.as_bytes(), new.as_bytes(), &mut scratch, |change| {
+            result.push(match change {
+                Change::Removed { line_no, text } => {
+                    format!("-{} {}", line_no, core::str::from_utf8(text).unwrap())
+                }
+                Change::Added { line_no, text } => {
+                    format!("+{} {}", line_no, core::str::from_utf8(text).unwrap())
+                }
+            })
+        })
+        .unwrap();
+        result
+    }
+
+    #[test]
+    fn same() {
+        assert!(changes("a\nb\nc\n", "a\nb\nc\n").is_empty());
+        // Line endings don't matter
+        assert!(changes("a\r\nb\r\nc", "a\nb\nc\n").is_empty());
+        assert!(changes("", "").is_empty());
+    }
+
+    #[test]
+    fn changed() {
+        assert_eq!(changes("a\nb\nc\n", "a\nc\n"), ["-2 b"]);
+        assert_eq!(changes("a\nc\n", "a\nb\nc\n"), ["+2 b"]);
+        assert_eq!(changes("a\nb\nc\n", "a\nx\nc\n"), ["-2 b", "+2 x"]);
+        assert_eq!(changes("", "a\n"), ["+1 a"]);
+        assert_eq!(
+            changes("10 PRINT X\n20 GOTO 10\n", "5 CLS\n10 PRINT X\n"),
+            ["+1 5 CLS", "-2 20 GOTO 10"]
+        );
+    }
+
+    #[test]
+    fn too_big() {
+        let old = "a\n".repeat(20);
+        let new = "b\n".repeat(20);
+        let mut scratch = [0u8; 1024];
+        assert_eq!(
+            diff(old.as_bytes(), new.as_bytes(), &mut scratch, |_| {}),
+            Err(TooBig)
+        );
+    }
+}
+
+// End of file
@@ -0,0 +1,283 @@
+//! # Fault Handling
+//!
+//! Catches CPU faults (like reading from an address where there is no
+//! memory) instead of locking up. We note where the fault happened, abandon
+//! whatever was running, go back to the shell's main loop, and keep the
+//! details for the `fault` command to print.
+//!
+//! The BIOS owns the vector table, so we copy it into memory reserved off
+//! the top of the TPA (OS RAM is too small, as the table needs 1 KiB,
+//! aligned to 1 KiB), point VTOR at our copy, and swap in our own fault
+//! handlers. This only happens on bare-metal Arm targets - elsewhere,
+//! `install` does nothing.
+//!
+//! We can only get back to the shell if the fault happened in Thread mode,
+//! after the main loop started. Otherwise, we reboot.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{program::TransientProgramArea, refcell::CsRefCell};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The last fault we caught
+static LAST_FAULT: CsRefCell<Option<Fault>> = CsRefCell::new(None);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Details of a CPU fault we caught
+#[derive(Debug, Copy, Clone)]
+// Only bare-metal Arm builds can catch faults
+#[cfg_attr(
+    not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))),
+    allow(dead_code)
+)]
+pub struct Fault {
+    /// The exception number (3 is HardFault, 6 is UsageFault, etc)
+    pub exception: u32,
+    /// The registers the CPU pushed onto the stack
+    pub frame: ExceptionFrame,
+    /// The stack pointer, before the CPU pushed `frame`
+    pub sp: u32,
+}
+
+impl Fault {
+    /// The name of the exception that caught the fault
+    pub fn name(&self) -> &'static str {
+        match self.exception {
+            3 => "HardFault",
+            4 => "MemManage",
+            5 => "BusFault",
+            6 => "UsageFault",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// The registers a Cortex-M CPU pushes onto the stack when it takes an
+/// exception.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ExceptionFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Get the last fault we caught, if any
+pub fn last() -> Option<Fault> {
+    *LAST_FAULT.lock()
+}
+
+/// Start catching faults, with a vector table reserved off the top of the
+/// TPA.
+#[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+pub fn install(tpa: &mut TransientProgramArea) {
+    arm::install(tpa);
+}
+
+/// Start catching faults.
+#[cfg(not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))))]
+pub fn install(_tpa: &mut TransientProgramArea) {
+    // We only know how to do this on bare-metal Arm
+}
+
+/// Run the shell's main loop for ever.
+///
+/// If we catch a fault, we abandon whatever faulted, call
+/// [`crate::recover_after_fault`], and carry on with `main_loop`.
+#[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+pub fn run(main_loop: &mut dyn FnMut()) -> ! {
+    arm::run(main_loop)
+}
+
+/// Run the shell's main loop for ever.
+#[cfg(not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))))]
+pub fn run(main_loop: &mut dyn FnMut()) -> ! {
+    loop {
+        main_loop();
+    }
+}
+
+/// Unlock the last fault, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    LAST_FAULT.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// The Cortex-M specific parts
+#[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+mod arm {
+    use super::{ExceptionFrame, Fault, LAST_FAULT};
+    use crate::program::TransientProgramArea;
+    use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    /// How many vectors we copy (16 exceptions plus up to 240 interrupts)
+    const NUM_VECTORS: usize = 256;
+
+    /// How big the vector table is. It has to be aligned to a power of two
+    /// at least this big.
+    const TABLE_LEN: usize = NUM_VECTORS * core::mem::size_of::<u32>();
+
+    /// The Vector Table Offset Register
+    const VTOR: *mut u32 = 0xE000_ED08 as *mut u32;
+
+    /// The stack pointer the main loop runs with, or 0 if it isn't running
+    static RESUME_SP: AtomicU32 = AtomicU32::new(0);
+
+    /// Where the main loop's `&mut dyn FnMut()` is
+    static MAIN_LOOP: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" {
+        fn neotron_fault_entry();
+        fn neotron_fault_resume() -> !;
+    }
+
+    // Finds the stack the CPU pushed the exception frame onto, and passes
+    // it (and the EXC_RETURN value) to `fault_handler`. We branch rather than
+    // call, so when `fault_handler` returns, the exception returns.
+    core::arch::global_asm!(
+        ".section .text.neotron_fault_entry, \"ax\"",
+        ".global neotron_fault_entry",
+        ".thumb_func",
+        "neotron_fault_entry:",
+        "movs r0, #4",
+        "mov r1, lr",
+        "tst r0, r1",
+        "beq 1f",
+        "mrs r0, psp",
+        "b 2f",
+        "1:",
+        "mrs r0, msp",
+        "2:",
+        "ldr r2, ={handler}",
+        "bx r2",
+        handler = sym fault_handler,
+    );
+
+    // Puts the stack back where it was when the main loop started, and
+    // carries on with the main loop.
+    core::arch::global_asm!(
+        ".section .text.neotron_fault_resume, \"ax\"",
+        ".global neotron_fault_resume",
+        ".thumb_func",
+        "neotron_fault_resume:",
+        "ldr r0, ={sp}",
+        "ldr r0, [r0]",
+        "mov sp, r0",
+        "ldr r0, ={resume}",
+        "bx r0",
+        sp = sym RESUME_SP,
+        resume = sym resume,
+    );
+
+    /// Copy the BIOS's vector table into the TPA, with our fault handlers in
+    /// it.
+    pub fn install(tpa: &mut TransientProgramArea) {
+        let Some(table) = tpa.reserve_aligned("Vector table", TABLE_LEN, TABLE_LEN) else {
+            crate::osprintln!(log: Warn, "No room to catch faults");
+            return;
+        };
+        let table = table.as_mut_ptr() as *mut u32;
+        unsafe {
+            let bios_table = VTOR.read_volatile() as *const u32;
+            for idx in 0..NUM_VECTORS {
+                table.add(idx).write(bios_table.add(idx).read_volatile());
+            }
+            // HardFault, MemManage, BusFault and UsageFault. The last three
+            // are reserved on Armv6-M, so changing them does no harm.
+            for idx in 3..=6 {
+                table.add(idx).write(neotron_fault_entry as usize as u32);
+            }
+            core::arch::asm!("dsb");
+            VTOR.write_volatile(table as u32);
+            core::arch::asm!("dsb", "isb");
+        }
+    }
+
+    /// Run the main loop, noting where to come back to after a fault.
+    pub fn run(main_loop: &mut dyn FnMut()) -> ! {
+        let mut main_loop = main_loop;
+        MAIN_LOOP.store(
+            &mut main_loop as *mut &mut dyn FnMut() as usize,
+            Ordering::Relaxed,
+        );
+        // Everything we need is above the stack pointer now, so a fault
+        // can throw away anything below it.
+        let sp: u32;
+        unsafe {
+            core::arch::asm!("mov {}, sp", out(reg) sp);
+        }
+        RESUME_SP.store(sp, Ordering::Release);
+        loop {
+            main_loop();
+        }
+    }
+
+    /// Called (in Handler mode) from `neotron_fault_entry`.
+    ///
+    /// Records the fault, then changes the exception frame so that the
+    /// exception returns into `neotron_fault_resume`.
+    extern "C" fn fault_handler(frame: *mut ExceptionFrame, exc_return: u32) {
+        let ipsr: u32;
+        unsafe {
+            core::arch::asm!("mrs {}, ipsr", out(reg) ipsr);
+        }
+        let frame_ref = unsafe { &mut *frame };
+        if let Ok(mut last) = LAST_FAULT.try_lock() {
+            *last = Some(Fault {
+                exception: ipsr & 0x1FF,
+                frame: *frame_ref,
+                sp: frame as u32 + core::mem::size_of::<ExceptionFrame>() as u32,
+            });
+        }
+        if exc_return & 0x8 == 0 || RESUME_SP.load(Ordering::Acquire) == 0 {
+            // We faulted in an interrupt handler, or before the main loop
+            // started, so there's no getting back
+            let api = crate::API.get();
+            (api.power_control)(crate::bios::PowerMode::Reset.make_ffi_safe());
+        }
+        frame_ref.pc = neotron_fault_resume as usize as u32 & !1;
+        // Just the Thumb bit
+        frame_ref.xpsr = 1 << 24;
+    }
+
+    /// Tidy up after the code we abandoned, and carry on with the main loop.
+    extern "C" fn resume() -> ! {
+        // # Safety
+        //
+        // `run` put this here, and its stack frame is still there, as we
+        // only threw away the stack below it.
+        let main_loop =
+            unsafe { &mut *(MAIN_LOOP.load(Ordering::Relaxed) as *mut &mut dyn FnMut()) };
+        // # Safety
+        //
+        // The code that faulted is never going to run again.
+        unsafe {
+            crate::recover_after_fault();
+        }
+        loop {
+            main_loop();
+        }
+    }
+}
+
+// End of file
@@ -1,9 +1,30 @@
 //! Filesystem related types
 
+use core::fmt::Write as _;
+
 use chrono::{Datelike, Timelike};
 use embedded_sdmmc::RawVolume;
 
-use crate::{bios, refcell::CsRefCell, API, FILESYSTEM};
+use crate::{bios, refcell::CsRefCell, API, FILESYSTEM, RAM_FILESYSTEM};
+
+/// The directory (in the root directory) we keep temporary files in
+const TEMP_DIR: &str = "TEMP";
+
+/// How many files can be open at once, between the OS and the running program
+pub const MAX_FILES: usize = 12;
+
+/// The first handle number `embedded-sdmmc` gives out, for the volumes,
+/// directories and files it opens for us
+pub const FIRST_HANDLE_ID: u32 = 5000;
+
+/// How many names we try before we give up on making a temporary file
+const MAX_TEMP_ATTEMPTS: u32 = 100;
+
+/// How many directories deep [`Filesystem::walk_dirs`] will go
+pub const MAX_DIR_DEPTH: usize = 8;
+
+/// How many times a second [`Filesystem::poll_media`] looks at the disk
+const MEDIA_POLLS_PER_SECOND: u64 = 2;
 
 /// Represents a block device that reads/writes disk blocks using the BIOS.
 ///
@@ -69,12 +90,100 @@ impl embedded_sdmmc::BlockDevice for BiosBlock {
     }
 }
 
+/// Which disk a [`Filesystem`] is on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Drive {
+    /// Block device 0 (the SD card)
+    Sd,
+    /// The RAM disk (see [`crate::ramdisk`])
+    Ram,
+}
+
+impl Drive {
+    /// The filesystem on this disk
+    fn filesystem(self) -> &'static Filesystem {
+        match self {
+            Drive::Sd => &FILESYSTEM,
+            Drive::Ram => &RAM_FILESYSTEM,
+        }
+    }
+
+    /// Is there a disk, how many blocks does it have, and is it
+    /// write-protected?
+    fn info(self) -> Option<(bool, u64, bool)> {
+        match self {
+            Drive::Sd => match (API.get().block_dev_get_info)(0) {
+                bios::FfiOption::Some(info) => {
+                    Some((info.media_present, info.num_blocks, info.read_only))
+                }
+                bios::FfiOption::None => None,
+            },
+            Drive::Ram => crate::ramdisk::num_blocks().map(|n| (true, u64::from(n), false)),
+        }
+    }
+}
+
+impl embedded_sdmmc::BlockDevice for Drive {
+    type Error = bios::Error;
+
+    fn read(
+        &self,
+        blocks: &mut [embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Drive::Sd => BiosBlock().read(blocks, start_block_idx, reason),
+            Drive::Ram => crate::ramdisk::RamBlock.read(blocks, start_block_idx, reason),
+        }
+    }
+
+    fn write(
+        &self,
+        blocks: &[embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Drive::Sd => BiosBlock().write(blocks, start_block_idx),
+            Drive::Ram => crate::ramdisk::RamBlock.write(blocks, start_block_idx),
+        }
+    }
+
+    fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, Self::Error> {
+        match self {
+            Drive::Sd => BiosBlock().num_blocks(),
+            Drive::Ram => crate::ramdisk::RamBlock.num_blocks(),
+        }
+    }
+}
+
+/// Gives the rest of a path that starts with `RAM:`, which is on the RAM
+/// disk.
+pub fn ram_path(path: &str) -> Option<&str> {
+    path.get(0..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("RAM:"))
+        .map(|_| &path[4..])
+}
+
+/// Split a path into the directory and the file name.
+///
+/// `RAM:` stays with the directory, so `RAM:NOTES.TXT` gives `RAM:` and
+/// `NOTES.TXT`.
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.rsplit_once(['/', '\\']) {
+        Some((dir, name)) => (dir, name),
+        None if ram_path(path).is_some() => path.split_at(4),
+        None => ("", path),
+    }
+}
+
 /// A type that lets you fetch the current time from the BIOS.
 pub struct BiosTime();
 
 impl embedded_sdmmc::TimeSource for BiosTime {
     fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
-        let time = API.get_time();
+        // FAT timestamps are in local time
+        let time = API.get_local_time();
         embedded_sdmmc::Timestamp {
             year_since_1970: (time.year() - 1970) as u8,
             zero_indexed_month: time.month0() as u8,
@@ -86,94 +195,753 @@ impl embedded_sdmmc::TimeSource for BiosTime {
     }
 }
 
+/// FAT attribute bit: file is read-only
+pub const ATTR_READ_ONLY: u8 = 0x01;
+/// FAT attribute bit: file is hidden
+pub const ATTR_HIDDEN: u8 = 0x02;
+/// FAT attribute bit: file is a system file
+pub const ATTR_SYSTEM: u8 = 0x04;
+/// FAT attribute bit: file needs archiving
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+/// Convert a timestamp into the packed (date, time) pair that FAT uses.
+fn fat_date_time(timestamp: &embedded_sdmmc::Timestamp) -> (u16, u16) {
+    // FAT years start at 1980, not 1970
+    let year = u16::from(timestamp.year_since_1970).saturating_sub(10);
+    let date = (year << 9)
+        | (u16::from(timestamp.zero_indexed_month + 1) << 5)
+        | u16::from(timestamp.zero_indexed_day + 1);
+    let time = (u16::from(timestamp.hours) << 11)
+        | (u16::from(timestamp.minutes) << 5)
+        | (u16::from(timestamp.seconds) / 2);
+    (date, time)
+}
+
+/// Convert an `embedded-sdmmc` timestamp into one for the Neotron API
+pub fn convert_timestamp(timestamp: &embedded_sdmmc::Timestamp) -> neotron_api::file::Time {
+    neotron_api::file::Time {
+        year_since_1970: timestamp.year_since_1970,
+        zero_indexed_month: timestamp.zero_indexed_month,
+        zero_indexed_day: timestamp.zero_indexed_day,
+        hours: timestamp.hours,
+        minutes: timestamp.minutes,
+        seconds: timestamp.seconds,
+    }
+}
+
+/// Convert `embedded-sdmmc` file attributes into those for the Neotron API
+pub fn convert_attributes(attributes: embedded_sdmmc::Attributes) -> neotron_api::file::Attributes {
+    let mut result = neotron_api::file::Attributes::empty();
+    if attributes.is_read_only() {
+        result |= neotron_api::file::Attributes::READ_ONLY;
+    }
+    if attributes.is_hidden() {
+        result |= neotron_api::file::Attributes::HIDDEN;
+    }
+    if attributes.is_system() {
+        result |= neotron_api::file::Attributes::SYSTEM;
+    }
+    if attributes.is_volume() {
+        result |= neotron_api::file::Attributes::VOLUME;
+    }
+    if attributes.is_directory() {
+        result |= neotron_api::file::Attributes::DIRECTORY;
+    }
+    if attributes.is_archive() {
+        result |= neotron_api::file::Attributes::ARCHIVE;
+    }
+    result
+}
+
 /// The errors this module can produce
 #[derive(Debug)]
 pub enum Error {
     /// Filesystem error
     Io(embedded_sdmmc::Error<bios::Error>),
+    /// The file is already open.
+    ///
+    /// A file can only be open once at a time - whether that's for reading
+    /// or for writing - so two handles can't get in each other's way.
+    AlreadyOpen,
+    /// The disk was removed or changed since the file was opened.
+    ///
+    /// The file belongs to a disk that isn't there any more, so you can't
+    /// use it. Open it again.
+    MediaChanged,
+    /// The disk is write-protected, or was mounted read-only (see
+    /// [`Filesystem::mount`]), so you can't change anything on it.
+    ReadOnly,
 }
 
 impl From<embedded_sdmmc::Error<bios::Error>> for Error {
     fn from(value: embedded_sdmmc::Error<bios::Error>) -> Self {
-        Error::Io(value)
+        match value {
+            embedded_sdmmc::Error::FileAlreadyOpen => Error::AlreadyOpen,
+            e => Error::Io(e),
+        }
+    }
+}
+
+/// What is in a directory (not counting its subdirectories)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DirSummary {
+    /// How many files
+    pub files: u32,
+    /// How many bytes in all those files
+    pub bytes: u64,
+}
+
+impl DirSummary {
+    /// Count a directory entry, if it's a file
+    fn add(&mut self, entry: &embedded_sdmmc::DirEntry) {
+        if !entry.attributes.is_directory() && !entry.attributes.is_volume() {
+            self.files += 1;
+            self.bytes += u64::from(entry.size);
+        }
     }
 }
 
+/// Is this a real subdirectory (and not `.` or `..`)?
+fn is_subdir(entry: &embedded_sdmmc::DirEntry) -> bool {
+    entry.attributes.is_directory()
+        && entry.name != embedded_sdmmc::ShortFileName::this_dir()
+        && entry.name != embedded_sdmmc::ShortFileName::parent_dir()
+}
+
 /// Represents an open file
+///
+/// Several `File`s can have the same file open for reading. They share one
+/// `embedded-sdmmc` file, and each keeps its own offset.
 pub struct File {
     inner: embedded_sdmmc::RawFile,
+    /// Which disk the file is on (see [`Filesystem::generation`])
+    generation: u32,
+    /// Which filesystem the file is on
+    drive: Drive,
+    /// Where we are in the file
+    offset: core::cell::Cell<u32>,
 }
 
 impl File {
     /// Read from a file
     pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-        FILESYSTEM.file_read(self, buffer)
+        self.drive.filesystem().file_read(self, buffer)
     }
 
     /// Write to a file
     pub fn write(&self, buffer: &[u8]) -> Result<(), Error> {
-        FILESYSTEM.file_write(self, buffer)
+        self.drive.filesystem().file_write(self, buffer)
     }
 
     /// Are we at the end of the file
+    ///
+    /// A file on a disk that has been removed is always at the end.
     pub fn is_eof(&self) -> bool {
-        FILESYSTEM
-            .file_eof(self)
-            .expect("File handle should be valid")
+        match self.drive.filesystem().file_eof(self) {
+            Err(Error::MediaChanged) => true,
+            result => result.expect("File handle should be valid"),
+        }
     }
 
     /// Seek to a position relative to the start of the file
     pub fn seek_from_start(&self, offset: u32) -> Result<(), Error> {
-        FILESYSTEM.file_seek_from_start(self, offset)
+        self.drive.filesystem().file_seek_from_start(self, offset)
     }
 
     /// What is the length of this file?
+    ///
+    /// A file on a disk that has been removed is empty.
     pub fn length(&self) -> u32 {
-        FILESYSTEM
-            .file_length(self)
-            .expect("File handle should be valid")
+        match self.drive.filesystem().file_length(self) {
+            Err(Error::MediaChanged) => 0,
+            result => result.expect("File handle should be valid"),
+        }
+    }
+}
+
+impl core::fmt::Write for File {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes()).map_err(|_e| core::fmt::Error)
     }
 }
 
 impl Drop for File {
     fn drop(&mut self) {
-        FILESYSTEM
-            .close_raw_file(self.inner)
-            .expect("Should only be dropping valid files!");
+        match self.drive.filesystem().close_raw_file(self) {
+            // The old disk has gone, and its open files went with it
+            Err(Error::MediaChanged) => {}
+            result => result.expect("Should only be dropping valid files!"),
+        }
     }
 }
 
+/// The `embedded-sdmmc` volume manager for one of our disks
+type VolumeManager = embedded_sdmmc::VolumeManager<Drive, BiosTime, 4, MAX_FILES, 1>;
+
+/// A file that one or more [`File`]s have open
+#[derive(Copy, Clone)]
+struct OpenFile {
+    /// The file, as `embedded-sdmmc` knows it
+    raw: embedded_sdmmc::RawFile,
+    /// Where the file's directory entry is, which tells us which file it is
+    entry: (embedded_sdmmc::BlockIdx, u32),
+    /// How many [`File`]s are using it
+    users: usize,
+    /// Is it open for writing? If so, it only has one user.
+    writing: bool,
+}
+
 /// Represent all open files and filesystems
 pub struct Filesystem {
-    volume_manager: CsRefCell<Option<embedded_sdmmc::VolumeManager<BiosBlock, BiosTime, 4, 4, 1>>>,
+    /// Which disk we're on
+    drive: Drive,
+    volume_manager: CsRefCell<Option<VolumeManager>>,
+    /// The files we have open, so readers can share them and writers can
+    /// have them to themselves
+    open_files: CsRefCell<heapless::Vec<OpenFile, MAX_FILES>>,
     first_volume: CsRefCell<Option<RawVolume>>,
+    /// The number in the name of the next temporary file we make
+    next_temp: CsRefCell<u32>,
+    /// What we last saw in the disk drive
+    media: CsRefCell<MediaState>,
+}
+
+/// What the BIOS told us about the disk in the drive
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct MediaState {
+    /// Goes up by one every time the disk is changed, so we can spot files
+    /// that were opened on the old disk
+    generation: u32,
+    /// Was there a disk in the drive, and how big was it?
+    last_seen: Option<(bool, u64)>,
+    /// The CRC32 of the disk's partition table and first boot sector, which
+    /// hold the volume serial number and label
+    volume_id: Option<u32>,
+    /// When (in ticks) we last read [`MediaState::volume_id`]
+    last_id_check: Option<u64>,
+    /// When (in ticks) we last looked
+    last_poll: Option<u64>,
+    /// Is the disk write-protected?
+    write_protected: bool,
+    /// Did someone ask for the disk to be mounted read-only?
+    mounted_read_only: bool,
 }
 
 impl Filesystem {
-    /// Create a new filesystem
-    pub const fn new() -> Filesystem {
+    /// Create a new filesystem, on the given disk
+    pub const fn new(drive: Drive) -> Filesystem {
         Filesystem {
+            drive,
             volume_manager: CsRefCell::new(None),
+            open_files: CsRefCell::new(heapless::Vec::new()),
             first_volume: CsRefCell::new(None),
+            next_temp: CsRefCell::new(0),
+            media: CsRefCell::new(MediaState {
+                generation: 0,
+                last_seen: None,
+                volume_id: None,
+                last_id_check: None,
+                last_poll: None,
+                write_protected: false,
+                mounted_read_only: false,
+            }),
+        }
+    }
+
+    /// Forget every open file and volume, as if the OS had just started.
+    ///
+    /// The tests boot the OS over and over, each time with a new disk.
+    #[cfg(test)]
+    pub fn reset(&self) {
+        let empty = Filesystem::new(self.drive);
+        *self.first_volume.lock() = None;
+        *self.volume_manager.lock() = None;
+        self.open_files.lock().clear();
+        *self.next_temp.lock() = 0;
+        *self.media.lock() = *empty.media.lock();
+    }
+
+    /// Unlock everything, even if someone is using it.
+    ///
+    /// # Safety
+    ///
+    /// Only do this when whoever was using the filesystem will never run
+    /// again, like code that was abandoned after a CPU fault.
+    pub unsafe fn force_unlock(&self) {
+        self.volume_manager.force_unlock();
+        self.open_files.force_unlock();
+        self.first_volume.force_unlock();
+        self.next_temp.force_unlock();
+        self.media.force_unlock();
+    }
+
+    /// Check whether the disk has been removed or changed, if it's been long
+    /// enough since we last looked.
+    ///
+    /// Call this regularly. The filesystem also checks whenever a file is
+    /// opened.
+    pub fn poll_media(&self) {
+        let api = API.get();
+        let now = (api.time_ticks_get)().0;
+        let interval = (api.time_ticks_per_second)().0 / MEDIA_POLLS_PER_SECOND;
+        {
+            let mut media = self.media.lock();
+            if let Some(last_poll) = media.last_poll {
+                if now.saturating_sub(last_poll) < interval {
+                    return;
+                }
+            }
+            media.last_poll = Some(now);
+        }
+        if self.check_media() {
+            crate::osprintln!(log: Info, "Disk changed");
+        }
+    }
+
+    /// Ask the BIOS what is in the disk drive. If the disk has been removed
+    /// or changed, forget everything we knew about the old one.
+    ///
+    /// Files opened on the old disk give [`Error::MediaChanged`] from then
+    /// on, and the new disk is mounted the next time someone uses it.
+    ///
+    /// Returns true if the disk changed.
+    fn check_media(&self) -> bool {
+        // A disk swapped between two checks usually changes size, so we
+        // check that as well
+        let (seen, write_protected) = match self.drive.info() {
+            Some((present, num_blocks, read_only)) => (Some((present, num_blocks)), read_only),
+            None => (None, false),
+        };
+        // Two cards the same size only differ in what's on them, but reading
+        // it costs two blocks, so we only look a few times a second
+        let volume_id = match seen {
+            Some((true, _)) if self.volume_id_due() => self.read_volume_id(),
+            _ => None,
+        };
+        let mut media = self.media.lock();
+        media.write_protected = write_protected;
+        let mut changed = matches!(media.last_seen, Some(last_seen) if Some(last_seen) != seen);
+        if let (Some(old), Some(new)) = (media.volume_id, volume_id) {
+            changed |= old != new;
+        }
+        media.last_seen = seen;
+        match seen {
+            Some((true, _)) => {
+                if volume_id.is_some() {
+                    media.volume_id = volume_id;
+                }
+            }
+            _ => media.volume_id = None,
+        }
+        if changed {
+            media.generation = media.generation.wrapping_add(1);
+            // Don't write anything back - it would go to the wrong disk
+            *self.first_volume.lock() = None;
+            *self.volume_manager.lock() = None;
+            self.open_files.lock().clear();
+        }
+        changed
+    }
+
+    /// Is it time to read the volume ID again? If so, note that we did.
+    fn volume_id_due(&self) -> bool {
+        let api = API.get();
+        let now = (api.time_ticks_get)().0;
+        let interval = (api.time_ticks_per_second)().0 / MEDIA_POLLS_PER_SECOND;
+        let mut media = self.media.lock();
+        let due = match (media.volume_id, media.last_id_check) {
+            (Some(_), Some(last)) => now.saturating_sub(last) >= interval,
+            _ => true,
+        };
+        if due {
+            media.last_id_check = Some(now);
         }
+        due
+    }
+
+    /// Work out the CRC32 of the partition table and the first partition's
+    /// boot sector, which changes when a different card goes in even if it's
+    /// the same size.
+    ///
+    /// Gives `None` if we can't read the disk.
+    fn read_volume_id(&self) -> Option<u32> {
+        use embedded_sdmmc::BlockDevice as _;
+        let mut blocks = [embedded_sdmmc::Block::new()];
+        self.drive
+            .read(&mut blocks, embedded_sdmmc::BlockIdx(0), "mbr")
+            .ok()?;
+        let crc = crate::crc::crc32(&blocks[0].contents);
+        // Where the first partition starts, from the partition table
+        let lba = &blocks[0].contents[0x1C6..0x1CA];
+        let lba = u32::from_le_bytes([lba[0], lba[1], lba[2], lba[3]]);
+        self.drive
+            .read(&mut blocks, embedded_sdmmc::BlockIdx(lba), "boot")
+            .ok()?;
+        Some(crate::crc::crc32_update(crc, &blocks[0].contents))
+    }
+
+    /// Forget everything we knew about the disk, because it has been
+    /// replaced (like when the RAM disk is made again).
+    ///
+    /// Files opened on the old disk give [`Error::MediaChanged`] from then
+    /// on.
+    pub fn disk_changed(&self) {
+        let mut media = self.media.lock();
+        media.generation = media.generation.wrapping_add(1);
+        media.last_seen = None;
+        media.volume_id = None;
+        *self.first_volume.lock() = None;
+        *self.volume_manager.lock() = None;
+        self.open_files.lock().clear();
+    }
+
+    /// Mount the disk, read-only or read-write.
+    ///
+    /// The disk is mounted read-write when the OS starts, and mounted again
+    /// automatically when it changes. Mounting read-only stops anything
+    /// writing to the disk (until it is mounted read-write again), which is
+    /// kinder to fragile cards. A write-protected disk is always read-only.
+    pub fn mount(&self, read_only: bool) -> Result<(), Error> {
+        self.check_media();
+        self.media.lock().mounted_read_only = read_only;
+        self.with_volume(|_fs, _volume| Ok(()))
+    }
+
+    /// Can we write to the disk?
+    ///
+    /// Returns true if the disk is write-protected, or was mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        let media = self.media.lock();
+        media.write_protected || media.mounted_read_only
+    }
+
+    /// Fail if we can't write to the disk
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.is_read_only() {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// If this is the SD card's filesystem, and the path starts with `RAM:`,
+    /// gives the rest of the path, which is on the RAM disk.
+    fn on_ram_disk<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.drive == Drive::Sd {
+            ram_path(path)
+        } else {
+            None
+        }
+    }
+
+    /// Which disk are we using? Goes up by one every time the disk changes.
+    fn generation(&self) -> u32 {
+        self.media.lock().generation
+    }
+
+    /// Check that a file is on the disk we are using now.
+    ///
+    /// Looks at the disk first, so we never touch a card that isn't the one
+    /// the file was opened on.
+    fn check_file(&self, file: &File) -> Result<(), Error> {
+        self.check_media();
+        if file.generation == self.generation() {
+            Ok(())
+        } else {
+            Err(Error::MediaChanged)
+        }
+    }
+
+    /// Call `f` with the volume manager, making it first if we haven't yet.
+    fn with_volume_manager<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut VolumeManager) -> Result<T, Error>,
+    {
+        let mut fs = self.volume_manager.lock();
+        let fs = fs.get_or_insert_with(|| {
+            embedded_sdmmc::VolumeManager::new_with_limits(self.drive, BiosTime(), FIRST_HANDLE_ID)
+        });
+        f(fs)
+    }
+
+    /// Call `f` with the volume manager and the first volume on the disk,
+    /// opening the volume first if we haven't yet.
+    fn with_volume<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut VolumeManager, RawVolume) -> Result<T, Error>,
+    {
+        self.with_volume_manager(|fs| {
+            let mut volume = self.first_volume.lock();
+            let volume = match *volume {
+                Some(volume) => volume,
+                None => *volume.insert(fs.open_raw_volume(embedded_sdmmc::VolumeIdx(0))?),
+            };
+            f(fs, volume)
+        })
+    }
+
+    /// Is someone using the filesystem right now?
+    ///
+    /// If so, any other filesystem call will panic.
+    pub fn is_busy(&self) -> bool {
+        self.volume_manager.try_lock().is_err()
+            || self.first_volume.try_lock().is_err()
+            || self.media.try_lock().is_err()
     }
 
     /// Open a file on the filesystem
+    ///
+    /// Names starting `RAM:` are on the RAM disk.
     pub fn open_file(&self, name: &str, mode: embedded_sdmmc::Mode) -> Result<File, Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
+        if let Some(name) = self.on_ram_disk(name) {
+            return RAM_FILESYSTEM.open_file(name, mode);
+        }
+        self.open_file_in_dir("", name, mode)
+    }
+
+    /// Open a file in some directory on the filesystem
+    ///
+    /// `dir` is a path from the root directory, like `SLIDES/TALK` (or empty
+    /// for the root directory). Paths starting `RAM:` are on the RAM disk.
+    ///
+    /// Any number of readers can have a file open at once, or one writer.
+    /// Otherwise you get [`Error::AlreadyOpen`].
+    pub fn open_file_in_dir<N>(
+        &self,
+        dir: &str,
+        name: N,
+        mode: embedded_sdmmc::Mode,
+    ) -> Result<File, Error>
+    where
+        N: embedded_sdmmc::filesystem::ToShortFileName,
+    {
+        if let Some(dir) = self.on_ram_disk(dir) {
+            return RAM_FILESYSTEM.open_file_in_dir(dir, name, mode);
+        }
+        self.check_media();
+        if !matches!(mode, embedded_sdmmc::Mode::ReadOnly) {
+            self.check_writable()?;
+        }
+        let generation = self.generation();
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            for dir_name in dir.split(['/', '\\']).filter(|s| !s.is_empty()) {
+                root.change_dir(dir_name)?;
+            }
+            let name = name
+                .to_short_filename()
+                .map_err(embedded_sdmmc::Error::FilenameError)?;
+            let writing = !matches!(mode, embedded_sdmmc::Mode::ReadOnly);
+            let entry = root
+                .find_directory_entry(&name)
+                .ok()
+                .map(|entry| (entry.entry_block, entry.entry_offset));
+            let mut open_files = self.open_files.lock();
+            if let Some(open) = open_files.iter_mut().find(|f| Some(f.entry) == entry) {
+                if writing || open.writing {
+                    return Err(Error::AlreadyOpen);
+                }
+                open.users += 1;
+                return Ok(File {
+                    inner: open.raw,
+                    generation,
+                    drive: self.drive,
+                    offset: core::cell::Cell::new(0),
+                });
+            }
+            let raw = root.open_file_in_dir(&name, mode)?.to_raw_file();
+            // A file we just made has a directory entry now
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    let entry = root.find_directory_entry(&name)?;
+                    (entry.entry_block, entry.entry_offset)
+                }
+            };
+            drop(root);
+            // Can't fail - the volume manager can't open any more files
+            // than this
+            let _ = open_files.push(OpenFile {
+                raw,
+                entry,
+                users: 1,
+                writing,
+            });
+            Ok(File {
+                inner: raw,
+                generation,
+                drive: self.drive,
+                offset: core::cell::Cell::new(fs.file_offset(raw)?),
+            })
+        })
+    }
+
+    /// Get the directory entry for a file in the root directory
+    pub fn stat(&self, name: &str) -> Result<embedded_sdmmc::DirEntry, Error> {
+        if let Some(name) = self.on_ram_disk(name) {
+            return RAM_FILESYSTEM.stat(name);
         }
-        let fs = fs.as_mut().unwrap();
-        let mut volume = self.first_volume.lock();
-        if volume.is_none() {
-            *volume = Some(fs.open_raw_volume(embedded_sdmmc::VolumeIdx(0))?);
+        self.check_media();
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            let entry = root.find_directory_entry(name)?;
+            Ok(entry)
+        })
+    }
+
+    /// Set and/or clear attribute bits on a file in the root directory.
+    ///
+    /// Bits are as per the FAT specification (see the `ATTR_` constants).
+    /// You cannot change the volume label or directory bits.
+    pub fn set_attributes(&self, name: &str, set: u8, clear: u8) -> Result<(), Error> {
+        if let Some(name) = self.on_ram_disk(name) {
+            return RAM_FILESYSTEM.set_attributes(name, set, clear);
+        }
+        self.check_writable()?;
+        let mask = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE;
+        self.modify_entry(name, |entry| {
+            entry[11] = (entry[11] | (set & mask)) & !(clear & mask);
+        })
+    }
+
+    /// Set the modification time of a file in the root directory.
+    pub fn set_mtime(&self, name: &str, timestamp: embedded_sdmmc::Timestamp) -> Result<(), Error> {
+        if let Some(name) = self.on_ram_disk(name) {
+            return RAM_FILESYSTEM.set_mtime(name, timestamp);
         }
-        let volume = volume.unwrap();
-        let mut root = fs.open_root_dir(volume)?.to_directory(fs);
-        let file = root.open_file_in_dir(name, mode)?;
-        let raw_file = file.to_raw_file();
-        Ok(File { inner: raw_file })
+        self.check_writable()?;
+        let (date, time) = fat_date_time(&timestamp);
+        self.modify_entry(name, |entry| {
+            entry[22..24].copy_from_slice(&time.to_le_bytes());
+            entry[24..26].copy_from_slice(&date.to_le_bytes());
+        })
+    }
+
+    /// Edit the raw 32-byte FAT directory entry for a file in the root directory.
+    ///
+    /// `embedded-sdmmc` doesn't let us change attributes or timestamps, so we
+    /// find where the entry lives on disk and patch it ourselves. The file
+    /// must not be open, otherwise closing it will overwrite our changes.
+    fn modify_entry<F>(&self, name: &str, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        use embedded_sdmmc::BlockDevice;
+        let entry = self.stat(name)?;
+        if self.is_open(&entry) {
+            return Err(Error::AlreadyOpen);
+        }
+        let mut blocks = [embedded_sdmmc::Block::new()];
+        let offset = entry.entry_offset as usize;
+        let block_dev = self.drive;
+        block_dev
+            .read(&mut blocks, entry.entry_block, "modify_entry")
+            .map_err(embedded_sdmmc::Error::DeviceError)?;
+        f(&mut blocks[0].contents[offset..offset + 32]);
+        block_dev
+            .write(&blocks, entry.entry_block)
+            .map_err(embedded_sdmmc::Error::DeviceError)?;
+        Ok(())
+    }
+
+    /// Delete a file in the root directory
+    pub fn delete_file(&self, name: &str) -> Result<(), Error> {
+        if let Some(name) = self.on_ram_disk(name) {
+            return RAM_FILESYSTEM.delete_file(name);
+        }
+        self.check_media();
+        self.check_writable()?;
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            root.delete_file_in_dir(name)?;
+            Ok(())
+        })
+    }
+
+    /// Create a new, empty, file with a unique name in the `TEMP` directory.
+    ///
+    /// The directory is created if it doesn't exist. Everything in it is
+    /// deleted by [`Self::clear_temp_dir`] when the OS starts.
+    pub fn create_temp_file(&self) -> Result<File, Error> {
+        self.check_media();
+        self.check_writable()?;
+        let generation = self.generation();
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            if let Err(embedded_sdmmc::Error::NotFound) = root.find_directory_entry(TEMP_DIR) {
+                root.make_dir_in_dir(TEMP_DIR)?;
+            }
+            let mut temp_dir = root.open_dir(TEMP_DIR)?;
+            let mut next_temp = self.next_temp.lock();
+            for _ in 0..MAX_TEMP_ATTEMPTS {
+                let mut name: heapless::String<12> = heapless::String::new();
+                let _ = write!(name, "T{:07}.TMP", *next_temp % 10_000_000);
+                *next_temp = next_temp.wrapping_add(1);
+                let opened = temp_dir
+                    .open_file_in_dir(name.as_str(), embedded_sdmmc::Mode::ReadWriteCreate)
+                    .map(|file| file.to_raw_file());
+                match opened {
+                    Ok(raw) => {
+                        let entry = temp_dir.find_directory_entry(name.as_str())?;
+                        // Can't fail - the volume manager can't open any
+                        // more files than this
+                        let _ = self.open_files.lock().push(OpenFile {
+                            raw,
+                            entry: (entry.entry_block, entry.entry_offset),
+                            users: 1,
+                            writing: true,
+                        });
+                        return Ok(File {
+                            inner: raw,
+                            generation,
+                            drive: self.drive,
+                            offset: core::cell::Cell::new(0),
+                        });
+                    }
+                    Err(embedded_sdmmc::Error::FileAlreadyExists) => {
+                        // Someone got there first - try the next name
+                    }
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                }
+            }
+            Err(embedded_sdmmc::Error::FileAlreadyExists.into())
+        })
+    }
+
+    /// Delete everything in the `TEMP` directory.
+    pub fn clear_temp_dir(&self) -> Result<(), Error> {
+        self.check_media();
+        if self.is_read_only() {
+            // We can't have put anything there
+            return Ok(());
+        }
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            let mut temp_dir = match root.find_directory_entry(TEMP_DIR) {
+                Ok(_) => root.open_dir(TEMP_DIR)?,
+                Err(embedded_sdmmc::Error::NotFound) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            // We can't delete files while we're walking the directory, so we
+            // collect a few names at a time.
+            loop {
+                let mut names: heapless::Vec<embedded_sdmmc::ShortFileName, 8> =
+                    heapless::Vec::new();
+                temp_dir.iterate_dir(|entry| {
+                    if !entry.attributes.is_directory() {
+                        let _ = names.push(entry.name.clone());
+                    }
+                })?;
+                if names.is_empty() {
+                    return Ok(());
+                }
+                for name in &names {
+                    temp_dir.delete_file_in_dir(name)?;
+                }
+            }
+        })
     }
 
     /// Walk through the root directory
@@ -181,87 +949,218 @@ impl Filesystem {
     where
         F: FnMut(&embedded_sdmmc::DirEntry),
     {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
+        self.iterate_dir("", f)
+    }
+
+    /// Walk through a directory
+    ///
+    /// `path` is a path from the root directory, like `SLIDES/TALK` (or empty
+    /// for the root directory). Paths starting `RAM:` are on the RAM disk.
+    pub fn iterate_dir<F>(&self, path: &str, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&embedded_sdmmc::DirEntry),
+    {
+        if let Some(path) = self.on_ram_disk(path) {
+            return RAM_FILESYSTEM.iterate_dir(path, f);
         }
-        let fs = fs.as_mut().unwrap();
-        let mut volume = self.first_volume.lock();
-        if volume.is_none() {
-            *volume = Some(fs.open_raw_volume(embedded_sdmmc::VolumeIdx(0))?);
+        self.check_media();
+        self.with_volume(|fs, volume| {
+            let mut root = fs.open_root_dir(volume)?.to_directory(fs);
+            for name in path.split(['/', '\\']).filter(|s| !s.is_empty()) {
+                root.change_dir(name)?;
+            }
+            root.iterate_dir(f)?;
+            Ok(())
+        })
+    }
+
+    /// Walk through a directory and everything under it, up to
+    /// [`MAX_DIR_DEPTH`] directories deep.
+    ///
+    /// `start` is a path from the root directory, like `GAMES/SAVES` (or
+    /// empty for the root directory). `f` is called for each directory, with
+    /// how deep it is, its name (or `None` for `start` itself), and what is
+    /// in it. Parents come before their children.
+    ///
+    /// We can only have a few directories open at once, so rather than
+    /// holding every directory on the way down open, we remember the path
+    /// and how far through each directory we got, and open the path again
+    /// each time. It is slow, but it doesn't need much memory.
+    ///
+    /// Returns true if some directories were too deep to visit.
+    pub fn walk_dirs<F>(&self, start: &str, mut f: F) -> Result<bool, Error>
+    where
+        F: FnMut(usize, Option<&embedded_sdmmc::ShortFileName>, &DirSummary),
+    {
+        if let Some(start) = self.on_ram_disk(start) {
+            return RAM_FILESYSTEM.walk_dirs(start, f);
         }
-        let volume = volume.unwrap();
-        let mut root = fs.open_root_dir(volume)?.to_directory(fs);
-        root.iterate_dir(f)?;
-        Ok(())
+        self.check_media();
+        self.with_volume(|fs, volume| {
+            // The directories we are in, below `start`, and how many of the
+            // subdirectories in each we have visited
+            let mut path: heapless::Vec<(embedded_sdmmc::ShortFileName, usize), MAX_DIR_DEPTH> =
+                heapless::Vec::new();
+            // How many of the subdirectories in `start` we have visited
+            let mut start_visited = 0;
+            let mut too_deep = false;
+
+            let mut dir = fs.open_root_dir(volume)?.to_directory(fs);
+            for name in start.split(['/', '\\']).filter(|s| !s.is_empty()) {
+                dir.change_dir(name)?;
+            }
+            let mut summary = DirSummary::default();
+            dir.iterate_dir(|entry| summary.add(entry))?;
+            f(0, None, &summary);
+            drop(dir);
+
+            loop {
+                let mut dir = fs.open_root_dir(volume)?.to_directory(fs);
+                for name in start.split(['/', '\\']).filter(|s| !s.is_empty()) {
+                    dir.change_dir(name)?;
+                }
+                for (name, _) in path.iter() {
+                    dir.change_dir(name)?;
+                }
+                let visited = match path.last_mut() {
+                    Some((_, visited)) => visited,
+                    None => &mut start_visited,
+                };
+                // Find the next subdirectory we haven't visited
+                let mut idx = 0;
+                let mut next = None;
+                dir.iterate_dir(|entry| {
+                    if is_subdir(entry) {
+                        if idx == *visited {
+                            next = Some(entry.name.clone());
+                        }
+                        idx += 1;
+                    }
+                })?;
+                *visited += 1;
+                match next {
+                    Some(name) if !path.is_full() => {
+                        dir.change_dir(&name)?;
+                        let mut summary = DirSummary::default();
+                        dir.iterate_dir(|entry| summary.add(entry))?;
+                        f(path.len() + 1, Some(&name), &summary);
+                        // Can't fail - we checked there was space
+                        let _ = path.push((name, 0));
+                    }
+                    Some(_) => {
+                        too_deep = true;
+                    }
+                    None => {
+                        // Finished this directory, so go back up
+                        if path.pop().is_none() {
+                            return Ok(too_deep);
+                        }
+                    }
+                }
+            }
+        })
     }
 
     /// Read from an open file
     pub fn file_read(&self, file: &File, buffer: &mut [u8]) -> Result<usize, Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
-        }
-        let fs = fs.as_mut().unwrap();
-        let bytes_read = fs.read(file.inner, buffer)?;
-        Ok(bytes_read)
+        self.check_file(file)?;
+        self.with_volume_manager(|fs| {
+            // Someone sharing the file may have moved it on
+            fs.file_seek_from_start(file.inner, file.offset.get())?;
+            let bytes_read = fs.read(file.inner, buffer)?;
+            file.offset.set(fs.file_offset(file.inner)?);
+            Ok(bytes_read)
+        })
     }
 
     /// Write to an open file
     pub fn file_write(&self, file: &File, buffer: &[u8]) -> Result<(), Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
-        }
-        let fs = fs.as_mut().unwrap();
-        fs.write(file.inner, buffer)?;
-        Ok(())
+        self.check_file(file)?;
+        self.check_writable()?;
+        self.with_volume_manager(|fs| {
+            fs.file_seek_from_start(file.inner, file.offset.get())?;
+            fs.write(file.inner, buffer)?;
+            file.offset.set(fs.file_offset(file.inner)?);
+            Ok(())
+        })
     }
 
     /// How large is a file?
     pub fn file_length(&self, file: &File) -> Result<u32, Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
-        }
-        let fs = fs.as_mut().unwrap();
-        let length = fs.file_length(file.inner)?;
-        Ok(length)
+        self.check_file(file)?;
+        self.with_volume_manager(|fs| {
+            let length = fs.file_length(file.inner)?;
+            Ok(length)
+        })
     }
 
     /// Seek a file with an offset from the start of the file.
     pub fn file_seek_from_start(&self, file: &File, offset: u32) -> Result<(), Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
-        }
-        let fs = fs.as_mut().unwrap();
-        fs.file_seek_from_start(file.inner, offset)?;
-        Ok(())
+        self.check_file(file)?;
+        self.with_volume_manager(|fs| {
+            fs.file_seek_from_start(file.inner, offset)?;
+            file.offset.set(offset);
+            Ok(())
+        })
     }
 
     /// Are we at the end of the file
     pub fn file_eof(&self, file: &File) -> Result<bool, Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
-        }
-        let fs = fs.as_mut().unwrap();
-        let is_eof = fs.file_eof(file.inner)?;
-        Ok(is_eof)
+        self.check_file(file)?;
+        self.with_volume_manager(|fs| {
+            let is_eof = file.offset.get() >= fs.file_length(file.inner)?;
+            Ok(is_eof)
+        })
     }
 
     /// Close an open file
     ///
-    /// Only used by File's drop impl.
-    fn close_raw_file(&self, file: embedded_sdmmc::RawFile) -> Result<(), Error> {
-        let mut fs = self.volume_manager.lock();
-        if fs.is_none() {
-            *fs = Some(embedded_sdmmc::VolumeManager::new(BiosBlock(), BiosTime()));
+    /// Only used by File's drop impl. The file is only really closed when
+    /// the last [`File`] sharing it is closed.
+    fn close_raw_file(&self, file: &File) -> Result<(), Error> {
+        self.check_file(file)?;
+        {
+            let mut open_files = self.open_files.lock();
+            if let Some(idx) = open_files.iter().position(|f| f.raw == file.inner) {
+                open_files[idx].users -= 1;
+                if open_files[idx].users > 0 {
+                    return Ok(());
+                }
+                open_files.swap_remove(idx);
+            }
         }
-        let fs = fs.as_mut().unwrap();
-        fs.close_file(file)?;
-        Ok(())
+        self.with_volume_manager(|fs| {
+            fs.close_file(file.inner)?;
+            Ok(())
+        })
+    }
+
+    /// Is this directory entry a file somebody has open?
+    fn is_open(&self, entry: &embedded_sdmmc::DirEntry) -> bool {
+        let entry = (entry.entry_block, entry.entry_offset);
+        self.open_files.lock().iter().any(|f| f.entry == entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_path, Error};
+
+    #[test]
+    fn already_open() {
+        let e = Error::from(embedded_sdmmc::Error::FileAlreadyOpen);
+        assert!(matches!(e, Error::AlreadyOpen));
+        let e = Error::from(embedded_sdmmc::Error::NotFound);
+        assert!(matches!(e, Error::Io(embedded_sdmmc::Error::NotFound)));
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(split_path("NOTES.TXT"), ("", "NOTES.TXT"));
+        assert_eq!(split_path("DOCS/NOTES.TXT"), ("DOCS", "NOTES.TXT"));
+        assert_eq!(split_path("ram:NOTES.TXT"), ("ram:", "NOTES.TXT"));
+        assert_eq!(split_path("RAM:DOCS\\NOTES.TXT"), ("RAM:DOCS", "NOTES.TXT"));
+        assert_eq!(split_path("RAM:"), ("RAM:", ""));
     }
 }
 
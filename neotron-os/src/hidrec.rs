@@ -0,0 +1,448 @@
+//! # Keyboard Event Recording
+//!
+//! Records every keyboard event the BIOS gives us to a file, with a
+//! timestamp, and plays them back later as if they were being typed. This
+//! lets us reproduce input bugs (like a keyboard layout producing the wrong
+//! characters) and test the input handling on real hardware.
+//!
+//! The file is text, with one event per line:
+//!
+//! ```text
+//! 1520 press LShift
+//! 1600 press A
+//! 1650 release A
+//! 1700 release LShift
+//! ```
+//!
+//! The number is how many milliseconds after the recording started the
+//! event happened. Lines starting with `#` are ignored. The OS ignores the
+//! mouse, so we don't record mouse events.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::fmt::Write as _;
+
+use pc_keyboard::KeyCode;
+
+use crate::{bios, fs, osprintln, refcell::CsRefCell, API, FILESYSTEM};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The longest line we read from a replay file
+const MAX_LINE_LEN: usize = 48;
+
+/// Every key code, so we can look them up by name
+static ALL_KEYS: [KeyCode; 124] = [
+    KeyCode::Escape,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::PrintScreen,
+    KeyCode::SysRq,
+    KeyCode::ScrollLock,
+    KeyCode::PauseBreak,
+    KeyCode::Oem8,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Key0,
+    KeyCode::OemMinus,
+    KeyCode::OemPlus,
+    KeyCode::Backspace,
+    KeyCode::Insert,
+    KeyCode::Home,
+    KeyCode::PageUp,
+    KeyCode::NumpadLock,
+    KeyCode::NumpadDivide,
+    KeyCode::NumpadMultiply,
+    KeyCode::NumpadSubtract,
+    KeyCode::Tab,
+    KeyCode::Q,
+    KeyCode::W,
+    KeyCode::E,
+    KeyCode::R,
+    KeyCode::T,
+    KeyCode::Y,
+    KeyCode::U,
+    KeyCode::I,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Oem4,
+    KeyCode::Oem6,
+    KeyCode::Oem5,
+    KeyCode::Oem7,
+    KeyCode::Delete,
+    KeyCode::End,
+    KeyCode::PageDown,
+    KeyCode::Numpad7,
+    KeyCode::Numpad8,
+    KeyCode::Numpad9,
+    KeyCode::NumpadAdd,
+    KeyCode::CapsLock,
+    KeyCode::A,
+    KeyCode::S,
+    KeyCode::D,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::Oem1,
+    KeyCode::Oem3,
+    KeyCode::Return,
+    KeyCode::Numpad4,
+    KeyCode::Numpad5,
+    KeyCode::Numpad6,
+    KeyCode::LShift,
+    KeyCode::Z,
+    KeyCode::X,
+    KeyCode::C,
+    KeyCode::V,
+    KeyCode::B,
+    KeyCode::N,
+    KeyCode::M,
+    KeyCode::OemComma,
+    KeyCode::OemPeriod,
+    KeyCode::Oem2,
+    KeyCode::RShift,
+    KeyCode::ArrowUp,
+    KeyCode::Numpad1,
+    KeyCode::Numpad2,
+    KeyCode::Numpad3,
+    KeyCode::NumpadEnter,
+    KeyCode::LControl,
+    KeyCode::LWin,
+    KeyCode::LAlt,
+    KeyCode::Spacebar,
+    KeyCode::RAltGr,
+    KeyCode::RWin,
+    KeyCode::Apps,
+    KeyCode::RControl,
+    KeyCode::ArrowLeft,
+    KeyCode::ArrowDown,
+    KeyCode::ArrowRight,
+    KeyCode::Numpad0,
+    KeyCode::NumpadPeriod,
+    KeyCode::Oem9,
+    KeyCode::Oem10,
+    KeyCode::Oem11,
+    KeyCode::Oem12,
+    KeyCode::Oem13,
+    KeyCode::PrevTrack,
+    KeyCode::NextTrack,
+    KeyCode::Mute,
+    KeyCode::Calculator,
+    KeyCode::Play,
+    KeyCode::Stop,
+    KeyCode::VolumeDown,
+    KeyCode::VolumeUp,
+    KeyCode::WWWHome,
+    KeyCode::PowerOnTestOk,
+    KeyCode::TooManyKeys,
+    KeyCode::RControl2,
+    KeyCode::RAlt2,
+];
+
+/// What we are recording and replaying
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    recording: None,
+    replaying: None,
+});
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we are recording and replaying
+struct State {
+    recording: Option<Recording>,
+    replaying: Option<Replay>,
+}
+
+/// A recording in progress
+struct Recording {
+    /// Where the events go
+    file: fs::File,
+    /// When (in ticks) we started
+    start: u64,
+    /// How many events we couldn't write, because the filesystem was busy
+    lost: u32,
+}
+
+/// A replay in progress
+struct Replay {
+    /// Where the events come from
+    file: fs::File,
+    /// When (in ticks) we started
+    start: u64,
+    /// Data we've read from the file but not used yet
+    buffer: [u8; 64],
+    /// How much of `buffer` we have used
+    pos: usize,
+    /// How much of `buffer` is valid
+    len: usize,
+    /// Which line of the file we are on
+    line_no: u32,
+    /// The next event, and when (in milliseconds from the start) to play it
+    next: Option<(u64, bios::hid::HidEvent)>,
+}
+
+impl Replay {
+    /// Get the next line from the file, or `None` at the end of the file
+    fn next_line<'a>(
+        &mut self,
+        line: &'a mut [u8; MAX_LINE_LEN],
+    ) -> Result<Option<&'a str>, &'static str> {
+        let mut used = 0;
+        loop {
+            if self.pos == self.len {
+                self.len = self.file.read(&mut self.buffer).map_err(|_| "Can't read")?;
+                self.pos = 0;
+                if self.len == 0 {
+                    if used == 0 {
+                        return Ok(None);
+                    }
+                    break;
+                }
+            }
+            let b = self.buffer[self.pos];
+            self.pos += 1;
+            if b == b'\n' {
+                break;
+            }
+            *line.get_mut(used).ok_or("Line too long")? = b;
+            used += 1;
+        }
+        self.line_no += 1;
+        let line = core::str::from_utf8(&line[0..used]).map_err(|_| "Not valid UTF-8")?;
+        Ok(Some(line.trim_end_matches('\r')))
+    }
+
+    /// Get the next event from the file, or `None` at the end of the file
+    fn next_event(&mut self) -> Result<Option<(u64, bios::hid::HidEvent)>, &'static str> {
+        let mut line = [0u8; MAX_LINE_LEN];
+        loop {
+            let Some(line) = self.next_line(&mut line)? else {
+                return Ok(None);
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return parse_event(line).map(Some).ok_or("Bad event");
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Start recording keyboard events to a file (replacing it if it exists).
+pub fn start_recording(filename: &str) -> Result<(), fs::Error> {
+    let mut file =
+        FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+    let _ = writeln!(file, "# Neotron OS keyboard events");
+    STATE.lock().recording = Some(Recording {
+        file,
+        start: now(),
+        lost: 0,
+    });
+    Ok(())
+}
+
+/// Stop recording keyboard events.
+///
+/// Returns `None` if we weren't recording, or how many events we couldn't
+/// write because the filesystem was busy.
+pub fn stop_recording() -> Option<u32> {
+    let recording = STATE.lock().recording.take()?;
+    Some(recording.lost)
+}
+
+/// Start playing back keyboard events from a file.
+///
+/// The events are fed in as if they were typed (along with anything that
+/// actually is typed), until the end of the file.
+pub fn start_replay(filename: &str) -> Result<(), fs::Error> {
+    let file = FILESYSTEM.open_file(filename, embedded_sdmmc::Mode::ReadOnly)?;
+    STATE.lock().replaying = Some(Replay {
+        file,
+        start: now(),
+        buffer: [0u8; 64],
+        pos: 0,
+        len: 0,
+        line_no: 0,
+        next: None,
+    });
+    Ok(())
+}
+
+/// Stop playing back keyboard events. Returns false if we weren't.
+pub fn stop_replay() -> bool {
+    STATE.lock().replaying.take().is_some()
+}
+
+/// Note an event we got from the BIOS, if we are recording.
+pub fn record(event: &bios::hid::HidEvent) {
+    let (verb, code) = match event {
+        bios::hid::HidEvent::KeyPress(code) => ("press", code),
+        bios::hid::HidEvent::KeyRelease(code) => ("release", code),
+        bios::hid::HidEvent::MouseInput(_) => return,
+    };
+    let Ok(mut state) = STATE.try_lock() else {
+        return;
+    };
+    let Some(recording) = state.recording.as_mut() else {
+        return;
+    };
+    if FILESYSTEM.is_busy() {
+        // We're probably being called from inside a filesystem operation
+        recording.lost += 1;
+        return;
+    }
+    let millis = ticks_to_millis(now().saturating_sub(recording.start));
+    if writeln!(recording.file, "{} {} {:?}", millis, verb, code).is_err() {
+        recording.lost += 1;
+    }
+}
+
+/// Get the next event to play back, if we are replaying and it is time.
+pub fn replay() -> Option<bios::hid::HidEvent> {
+    let Ok(mut state) = STATE.try_lock() else {
+        return None;
+    };
+    let replay = state.replaying.as_mut()?;
+    if FILESYSTEM.is_busy() {
+        return None;
+    }
+    if replay.next.is_none() {
+        match replay.next_event() {
+            Ok(Some(event)) => {
+                replay.next = Some(event);
+            }
+            Ok(None) => {
+                state.replaying = None;
+                osprintln!(log: Info, "Replay finished");
+                return None;
+            }
+            Err(e) => {
+                osprintln!(log: Warn, "Replay stopped at line {}: {}", replay.line_no, e);
+                state.replaying = None;
+                return None;
+            }
+        }
+    }
+    let millis = ticks_to_millis(now().saturating_sub(replay.start));
+    match replay.next.take() {
+        Some((due, event)) if due <= millis => Some(event),
+        next => {
+            replay.next = next;
+            None
+        }
+    }
+}
+
+/// Unlock the recorder, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Turn a line like `1600 press A` into an event
+fn parse_event(line: &str) -> Option<(u64, bios::hid::HidEvent)> {
+    let mut words = line.split_whitespace();
+    let millis = words.next()?.parse::<u64>().ok()?;
+    let verb = words.next()?;
+    let code = key_by_name(words.next()?)?;
+    if words.next().is_some() {
+        return None;
+    }
+    let event = match verb {
+        "press" => bios::hid::HidEvent::KeyPress(code),
+        "release" => bios::hid::HidEvent::KeyRelease(code),
+        _ => return None,
+    };
+    Some((millis, event))
+}
+
+/// Find a key code from its name (like `LShift`)
+fn key_by_name(name: &str) -> Option<KeyCode> {
+    ALL_KEYS.iter().copied().find(|code| {
+        let mut code_name: heapless::String<16> = heapless::String::new();
+        let _ = write!(code_name, "{:?}", code);
+        code_name == name
+    })
+}
+
+/// The time now, in ticks
+fn now() -> u64 {
+    (API.get().time_ticks_get)().0
+}
+
+/// Convert ticks to milliseconds
+fn ticks_to_millis(ticks: u64) -> u64 {
+    let ticks_per_second = (API.get().time_ticks_per_second)().0.max(1);
+    ticks.saturating_mul(1000) / ticks_per_second
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert!(matches!(
+            parse_event("1600 press A"),
+            Some((1600, bios::hid::HidEvent::KeyPress(KeyCode::A)))
+        ));
+        assert!(matches!(
+            parse_event("20  release   LShift"),
+            Some((20, bios::hid::HidEvent::KeyRelease(KeyCode::LShift)))
+        ));
+        assert!(parse_event("1600 press").is_none());
+        assert!(parse_event("1600 press NotAKey").is_none());
+        assert!(parse_event("1600 hold A").is_none());
+        assert!(parse_event("soon press A").is_none());
+        assert!(parse_event("1600 press A B").is_none());
+    }
+
+    #[test]
+    fn key_names() {
+        // Every key can be found by the name we record it with
+        for code in ALL_KEYS.iter() {
+            let mut name: heapless::String<16> = heapless::String::new();
+            write!(name, "{:?}", code).unwrap();
+            assert_eq!(key_by_name(&name), Some(*code));
+        }
+    }
+}
+
+// End of file
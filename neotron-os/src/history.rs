@@ -0,0 +1,211 @@
+//! # Shell History
+//!
+//! Remembers the last few commands typed at the shell, so you can get them
+//! back with the up and down arrow keys, or list them with `history`.
+//!
+//! If the config says so, we write them to `HISTORY.TXT` (one command per
+//! line, oldest first) when the system is shut down with `shutdown` or
+//! `power`, and read them back when it starts.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{fs, refcell::CsRefCell, FILESYSTEM};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many commands we remember.
+///
+/// They all live in OS RAM, so we don't keep many.
+pub const MAX_HISTORY: usize = 4;
+
+/// The longest command we remember
+pub const MAX_LINE_LEN: usize = 64;
+
+/// Where we keep the history between restarts
+const FILE_NAME: &str = "HISTORY.TXT";
+
+/// The commands we remember
+static HISTORY: CsRefCell<History> = CsRefCell::new(History::new());
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// A command we remember
+pub type Line = heapless::String<MAX_LINE_LEN>;
+
+/// The last few commands, oldest first
+pub struct History {
+    lines: heapless::Vec<Line, MAX_HISTORY>,
+}
+
+impl History {
+    /// Make an empty history
+    pub const fn new() -> History {
+        History {
+            lines: heapless::Vec::new(),
+        }
+    }
+
+    /// Remember a command, forgetting the oldest one if we're full.
+    ///
+    /// Blank lines, lines that are too long, and the same command twice in a
+    /// row aren't remembered.
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.recent(0) == Some(line) {
+            return;
+        }
+        let Ok(line) = line.parse::<Line>() else {
+            return;
+        };
+        if self.lines.is_full() {
+            self.lines.remove(0);
+        }
+        // Can't fail - we just made room
+        let _ = self.lines.push(line);
+    }
+
+    /// Get a command, counting back from the most recent (which is 0)
+    pub fn recent(&self, n: usize) -> Option<&str> {
+        let idx = self.lines.len().checked_sub(n + 1)?;
+        Some(self.lines[idx].as_str())
+    }
+
+    /// Go through the commands, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(|line| line.as_str())
+    }
+
+    /// Forget everything
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// Remember every line in some text, like the contents of `HISTORY.TXT`
+    pub fn parse(&mut self, text: &str) {
+        for line in text.lines() {
+            self.push(line);
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Remember a command typed at the shell
+pub fn push(line: &str) {
+    HISTORY.lock().push(line);
+}
+
+/// Get a command, counting back from the most recent (which is 0)
+pub fn recent(n: usize) -> Option<Line> {
+    HISTORY.lock().recent(n).map(Line::from)
+}
+
+/// Call `f` with each command we remember, oldest first
+pub fn for_each<F>(mut f: F)
+where
+    F: FnMut(usize, &str),
+{
+    for (idx, line) in HISTORY.lock().iter().enumerate() {
+        f(idx, line);
+    }
+}
+
+/// Forget every command
+pub fn clear() {
+    HISTORY.lock().clear();
+}
+
+/// Read the history back from disk.
+///
+/// Gives how many commands we now remember.
+pub fn load() -> Result<usize, fs::Error> {
+    let file = FILESYSTEM.open_file(FILE_NAME, embedded_sdmmc::Mode::ReadOnly)?;
+    let mut buffer = [0u8; MAX_HISTORY * (MAX_LINE_LEN + 1)];
+    let mut used = 0;
+    while used < buffer.len() {
+        let count = file.read(&mut buffer[used..])?;
+        if count == 0 {
+            break;
+        }
+        used += count;
+    }
+    // Ignore anything that isn't text
+    let text = match core::str::from_utf8(&buffer[0..used]) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&buffer[0..e.valid_up_to()]).unwrap_or(""),
+    };
+    let mut history = HISTORY.lock();
+    history.parse(text);
+    Ok(history.lines.len())
+}
+
+/// Write the history to disk, so we can read it back next time.
+pub fn save() -> Result<(), fs::Error> {
+    let file = FILESYSTEM.open_file(FILE_NAME, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)?;
+    for line in HISTORY.lock().iter() {
+        file.write(line.as_bytes())?;
+        file.write(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Unlock the command history, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    HISTORY.force_unlock();
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_and_recall() {
+        let mut history = History::new();
+        assert_eq!(history.recent(0), None);
+        history.push("dir");
+        history.push("  ");
+        history.push("type README.TXT");
+        // The same again is only kept once
+        history.push("type README.TXT");
+        assert_eq!(history.recent(0), Some("type README.TXT"));
+        assert_eq!(history.recent(1), Some("dir"));
+        assert_eq!(history.recent(2), None);
+        history.push(&"x".repeat(MAX_LINE_LEN + 1));
+        assert_eq!(history.iter().count(), 2);
+    }
+
+    #[test]
+    fn forgets_oldest() {
+        let mut history = History::new();
+        for idx in 0..MAX_HISTORY + 2 {
+            history.push(&format!("sleep {}", idx));
+        }
+        assert_eq!(history.iter().count(), MAX_HISTORY);
+        assert_eq!(history.iter().next(), Some("sleep 2"));
+        assert_eq!(
+            history.recent(0),
+            Some(format!("sleep {}", MAX_HISTORY + 1).as_str())
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let mut history = History::new();
+        history.parse("dir\r\n\r\nlsmem\r\nlsmem\n");
+        assert_eq!(history.iter().collect::<Vec<_>>(), ["dir", "lsmem"]);
+    }
+}
+
+// End of file
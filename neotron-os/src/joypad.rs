@@ -0,0 +1,165 @@
+//! # Game Controllers
+//!
+//! Keeps track of which buttons are held down, and where the sticks are, on
+//! each game controller. Applications poll this through the `PAD:` device,
+//! and you can watch it with the `padtest` command.
+//!
+//! Nothing is reported yet. There are no game controller events for
+//! `bios_poll` to fetch until the BIOS offers them, so [`is_supported`] says
+//! no, the `PAD:` device has no controllers and `padtest` won't start.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::refcell::CsRefCell;
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many game controllers we track
+pub const NUM_PADS: usize = 2;
+
+/// How many axes each game controller has (two sticks, each with X and Y)
+pub const NUM_AXES: usize = 4;
+
+/// The names of the buttons, in bit order
+pub const BUTTON_NAMES: [&str; 12] = [
+    "Up", "Down", "Left", "Right", "A", "B", "X", "Y", "L", "R", "Select", "Start",
+];
+
+/// The state of every game controller
+static PADS: CsRefCell<[PadState; NUM_PADS]> = CsRefCell::new([PadState::new(); NUM_PADS]);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// What one game controller is doing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PadState {
+    /// Is there a controller plugged in?
+    pub connected: bool,
+    /// One bit per button (see [`BUTTON_NAMES`]), set if it is held down
+    pub buttons: u16,
+    /// Where each axis is, from -128 to 127 (0 is the middle)
+    pub axes: [i8; NUM_AXES],
+}
+
+impl PadState {
+    /// A controller that isn't plugged in
+    const fn new() -> PadState {
+        PadState {
+            connected: false,
+            buttons: 0,
+            axes: [0; NUM_AXES],
+        }
+    }
+
+    /// Pack the state into a `u64`, for the `PAD:` device.
+    ///
+    /// See [`crate::program`]'s `api_ioctl` for the layout.
+    pub fn as_u64(&self) -> u64 {
+        let mut result = u64::from(self.buttons);
+        if self.connected {
+            result |= 1 << 16;
+        }
+        for (idx, axis) in self.axes.iter().enumerate() {
+            result |= u64::from(*axis as u8) << (32 + (idx * 8));
+        }
+        result
+    }
+}
+
+/// Something a game controller did
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum PadEvent {
+    /// The controller was plugged in (`true`) or unplugged (`false`)
+    Connected(bool),
+    /// A button was pressed or released
+    Button { index: u8, pressed: bool },
+    /// An axis moved
+    Axis { index: u8, value: i8 },
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Can the BIOS tell us about game controllers?
+pub fn is_supported() -> bool {
+    false
+}
+
+/// How many game controllers can we report on?
+pub fn num_pads() -> usize {
+    if is_supported() {
+        NUM_PADS
+    } else {
+        0
+    }
+}
+
+/// Get the current state of game controller `pad`
+pub fn get(pad: usize) -> Option<PadState> {
+    if pad >= num_pads() {
+        return None;
+    }
+    poll();
+    PADS.lock().get(pad).copied()
+}
+
+/// Update our state with something a game controller did.
+pub fn handle_event(pad: usize, event: PadEvent) {
+    let mut pads = PADS.lock();
+    let Some(state) = pads.get_mut(pad) else {
+        return;
+    };
+    match event {
+        PadEvent::Connected(connected) => {
+            // Forget about anything held down on the old controller
+            *state = PadState::new();
+            state.connected = connected;
+        }
+        PadEvent::Button { index, pressed } => {
+            if let Some(bit) = 1u16.checked_shl(u32::from(index)) {
+                if pressed {
+                    state.buttons |= bit;
+                } else {
+                    state.buttons &= !bit;
+                }
+            }
+        }
+        PadEvent::Axis { index, value } => {
+            if let Some(axis) = state.axes.get_mut(usize::from(index)) {
+                *axis = value;
+            }
+        }
+    }
+}
+
+/// Unlock the game controller state, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    PADS.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Handle any game controller events the BIOS has for us
+fn poll() {
+    while let Some((pad, event)) = bios_poll() {
+        handle_event(pad, event);
+    }
+}
+
+/// Ask the BIOS for a game controller event.
+fn bios_poll() -> Option<(usize, PadEvent)> {
+    // The BIOS API has no game controller support yet
+    None
+}
+
+// End of file
@@ -0,0 +1,507 @@
+//! # Keyboard Decoders
+//!
+//! The BIOS gives us key events, which say which key was pressed or
+//! released. A [`KeyboardDecoder`] keeps track of the modifier keys and turns
+//! those events into characters.
+//!
+//! Most BIOSes report where each key is on a US keyboard, and we apply the
+//! user's keyboard layout here in the OS. Some BIOSes apply the layout
+//! themselves, and report the key that is printed on the keycap. If we
+//! applied a layout on top of that, keys would be translated twice (so the
+//! `A` key on a French keyboard would type `q`). For those BIOSes, select
+//! [`Keymap::Bios`], and we decode every key as if it were on a US
+//! keyboard.
+//!
+//! Some layouts have dead keys, which don't type anything by themselves but
+//! put an accent on the next letter (so `^` then `e` types `ê`). The
+//! [`Compose`] state machine handles those, after the decoder.
+//!
+//! Any character can be typed by holding down Alt, typing its code point (in
+//! decimal) on the numeric keypad, and letting go of Alt. [`AltCode`] keeps
+//! track of the digits.
+//!
+//! [`KeyboardLocks`] keeps track of Caps Lock, Num Lock and Scroll Lock, so
+//! we can show them on the status line and light the keyboard LEDs.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios::hid::KeyboardLeds, config::Keymap};
+use pc_keyboard::{
+    layouts, DecodedKey, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyboardLayout, Modifiers,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The dead keys on a French AZERTY keyboard
+const FR_DEAD_KEYS: &[char] = &['^', '¨'];
+
+/// The dead keys on a German keyboard
+const DE_DEAD_KEYS: &[char] = &['^', '´', '`'];
+
+/// The dead keys on a Spanish keyboard
+const ES_DEAD_KEYS: &[char] = &['`', '^', '´', '¨'];
+
+/// What each dead key does to each letter, as `(dead key, letter, result)`
+const COMBINATIONS: &[(char, char, char)] = &[
+    ('^', 'a', 'â'),
+    ('^', 'e', 'ê'),
+    ('^', 'i', 'î'),
+    ('^', 'o', 'ô'),
+    ('^', 'u', 'û'),
+    ('^', 'A', 'Â'),
+    ('^', 'E', 'Ê'),
+    ('^', 'I', 'Î'),
+    ('^', 'O', 'Ô'),
+    ('^', 'U', 'Û'),
+    ('¨', 'a', 'ä'),
+    ('¨', 'e', 'ë'),
+    ('¨', 'i', 'ï'),
+    ('¨', 'o', 'ö'),
+    ('¨', 'u', 'ü'),
+    ('¨', 'y', 'ÿ'),
+    ('¨', 'A', 'Ä'),
+    ('¨', 'E', 'Ë'),
+    ('¨', 'I', 'Ï'),
+    ('¨', 'O', 'Ö'),
+    ('¨', 'U', 'Ü'),
+    ('¨', 'Y', 'Ÿ'),
+    ('´', 'a', 'á'),
+    ('´', 'e', 'é'),
+    ('´', 'i', 'í'),
+    ('´', 'o', 'ó'),
+    ('´', 'u', 'ú'),
+    ('´', 'y', 'ý'),
+    ('´', 'A', 'Á'),
+    ('´', 'E', 'É'),
+    ('´', 'I', 'Í'),
+    ('´', 'O', 'Ó'),
+    ('´', 'U', 'Ú'),
+    ('´', 'Y', 'Ý'),
+    ('`', 'a', 'à'),
+    ('`', 'e', 'è'),
+    ('`', 'i', 'ì'),
+    ('`', 'o', 'ò'),
+    ('`', 'u', 'ù'),
+    ('`', 'A', 'À'),
+    ('`', 'E', 'È'),
+    ('`', 'I', 'Ì'),
+    ('`', 'O', 'Ò'),
+    ('`', 'U', 'Ù'),
+];
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Something that turns key events into characters
+pub trait KeyboardDecoder {
+    /// Process a key being pressed or released.
+    ///
+    /// Returns the character (or special key) it produced, if any.
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey>;
+}
+
+/// Every decoder we have, so we can pick one at run-time without needing a
+/// heap.
+pub enum Decoder {
+    /// The BIOS reports key positions, and we apply a layout
+    Os(EventDecoder<layouts::AnyLayout>),
+    /// The BIOS has already applied the layout
+    Bios(EventDecoder<layouts::Us104Key>),
+    /// The BIOS reports key positions, and we apply our Spanish layout
+    Es(EventDecoder<Es105Key>),
+}
+
+impl Decoder {
+    /// Make the decoder we use at power-up, with a UK layout
+    pub const fn new() -> Decoder {
+        Decoder::Os(EventDecoder::new(
+            layouts::AnyLayout::Uk105Key(layouts::Uk105Key),
+            HandleControl::MapLettersToUnicode,
+        ))
+    }
+
+    /// Make a decoder for the given keymap.
+    ///
+    /// The new decoder thinks no modifier keys are held down, Caps Lock is
+    /// off and Num Lock is on.
+    pub fn with_keymap(keymap: Keymap) -> Decoder {
+        let layout = match keymap {
+            Keymap::Bios => {
+                return Decoder::Bios(EventDecoder::new(
+                    layouts::Us104Key,
+                    HandleControl::MapLettersToUnicode,
+                ));
+            }
+            Keymap::Es => {
+                return Decoder::Es(EventDecoder::new(
+                    Es105Key,
+                    HandleControl::MapLettersToUnicode,
+                ));
+            }
+            Keymap::Uk => layouts::AnyLayout::Uk105Key(layouts::Uk105Key),
+            Keymap::Us => layouts::AnyLayout::Us104Key(layouts::Us104Key),
+            Keymap::De => layouts::AnyLayout::De105Key(layouts::De105Key),
+            Keymap::Fr => layouts::AnyLayout::Azerty(layouts::Azerty),
+            Keymap::Dvorak => layouts::AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Keymap::Colemak => layouts::AnyLayout::Colemak(layouts::Colemak),
+        };
+        Decoder::Os(EventDecoder::new(
+            layout,
+            HandleControl::MapLettersToUnicode,
+        ))
+    }
+}
+
+impl KeyboardDecoder for Decoder {
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Decoder::Os(decoder) => decoder.process_keyevent(event),
+            Decoder::Bios(decoder) => decoder.process_keyevent(event),
+            Decoder::Es(decoder) => decoder.process_keyevent(event),
+        }
+    }
+}
+
+impl<L> KeyboardDecoder for EventDecoder<L>
+where
+    L: KeyboardLayout,
+{
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        EventDecoder::process_keyevent(self, event)
+    }
+}
+
+/// A Spanish 105-key layout.
+///
+/// pc-keyboard doesn't have one, so we handle the keys that are different to
+/// a US keyboard, and leave the rest to [`layouts::Us104Key`].
+pub struct Es105Key;
+
+impl KeyboardLayout for Es105Key {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let shifted = modifiers.lshift || modifiers.rshift;
+        let caps = shifted != modifiers.capslock;
+        let altgr = modifiers.alt_gr;
+        // What the key types normally, with Shift, and with AltGr (if
+        // anything)
+        let (normal, shift, alt_gr) = match keycode {
+            KeyCode::Oem8 => ('º', 'ª', Some('\\')),
+            KeyCode::Key1 => ('1', '!', Some('|')),
+            KeyCode::Key2 => ('2', '"', Some('@')),
+            KeyCode::Key3 => ('3', '·', Some('#')),
+            KeyCode::Key4 => ('4', '$', Some('~')),
+            KeyCode::Key5 => ('5', '%', Some('€')),
+            KeyCode::Key6 => ('6', '&', Some('¬')),
+            KeyCode::Key7 => ('7', '/', None),
+            KeyCode::Key8 => ('8', '(', None),
+            KeyCode::Key9 => ('9', ')', None),
+            KeyCode::Key0 => ('0', '=', None),
+            KeyCode::OemMinus => ('\'', '?', None),
+            KeyCode::OemPlus => ('¡', '¿', None),
+            KeyCode::Oem4 => ('`', '^', Some('[')),
+            KeyCode::Oem6 => ('+', '*', Some(']')),
+            KeyCode::Oem3 => ('´', '¨', Some('{')),
+            KeyCode::Oem5 => ('<', '>', None),
+            KeyCode::OemComma => (',', ';', None),
+            KeyCode::OemPeriod => ('.', ':', None),
+            KeyCode::Oem2 => ('-', '_', None),
+            // These are letters, so Caps Lock applies
+            KeyCode::Oem1 => {
+                return DecodedKey::Unicode(if caps { 'Ñ' } else { 'ñ' });
+            }
+            KeyCode::Oem7 if !altgr => {
+                return DecodedKey::Unicode(if caps { 'Ç' } else { 'ç' });
+            }
+            KeyCode::Oem7 => return DecodedKey::Unicode('}'),
+            KeyCode::E if altgr => return DecodedKey::Unicode('€'),
+            _ => return layouts::Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+        };
+        DecodedKey::Unicode(match alt_gr {
+            Some(ch) if altgr => ch,
+            _ if shifted => shift,
+            _ => normal,
+        })
+    }
+}
+
+/// Handles dead keys.
+///
+/// Give it everything the decoder produces. When a dead key is typed, we hold
+/// on to it until the next key. If the two go together we give back the
+/// accented letter, otherwise we give back both keys.
+pub struct Compose {
+    /// Which characters are dead keys, on this layout
+    dead_keys: &'static [char],
+    /// The dead key we are holding on to, if any
+    pending: Option<char>,
+}
+
+impl Compose {
+    /// Make a state machine for a layout with no dead keys
+    pub const fn new() -> Compose {
+        Compose {
+            dead_keys: &[],
+            pending: None,
+        }
+    }
+
+    /// Make a state machine for the dead keys on the given keymap
+    pub fn with_keymap(keymap: Keymap) -> Compose {
+        let dead_keys = match keymap {
+            Keymap::Fr => FR_DEAD_KEYS,
+            Keymap::De => DE_DEAD_KEYS,
+            Keymap::Es => ES_DEAD_KEYS,
+            Keymap::Uk | Keymap::Us | Keymap::Dvorak | Keymap::Colemak | Keymap::Bios => &[],
+        };
+        Compose {
+            dead_keys,
+            pending: None,
+        }
+    }
+
+    /// Process a key from the decoder, and get back what should be typed.
+    ///
+    /// A dead key followed by a space types the accent by itself. Backspace
+    /// forgets about the dead key.
+    pub fn push(&mut self, key: DecodedKey) -> heapless::Vec<DecodedKey, 2> {
+        let mut output = heapless::Vec::new();
+        let Some(dead_key) = self.pending.take() else {
+            match key {
+                DecodedKey::Unicode(ch) if self.dead_keys.contains(&ch) => {
+                    self.pending = Some(ch);
+                }
+                _ => {
+                    // Can't fail - we have room for two
+                    let _ = output.push(key);
+                }
+            }
+            return output;
+        };
+        let combined = match key {
+            DecodedKey::Unicode(' ') => Some(dead_key),
+            DecodedKey::Unicode('\u{0008}') | DecodedKey::Unicode('\u{007F}') => return output,
+            DecodedKey::Unicode(ch) => COMBINATIONS
+                .iter()
+                .find(|(dead, letter, _)| *dead == dead_key && *letter == ch)
+                .map(|(_, _, result)| *result),
+            DecodedKey::RawKey(_) => None,
+        };
+        match combined {
+            Some(ch) => {
+                let _ = output.push(DecodedKey::Unicode(ch));
+            }
+            None => {
+                let _ = output.push(DecodedKey::Unicode(dead_key));
+                let _ = output.push(key);
+            }
+        }
+        output
+    }
+}
+
+/// Collects a code point typed on the numeric keypad while Alt is held down
+pub struct AltCode {
+    /// The code point so far, if any digits have been typed
+    value: Option<u32>,
+}
+
+impl AltCode {
+    /// Make a new, empty, code
+    pub const fn new() -> AltCode {
+        AltCode { value: None }
+    }
+
+    /// A key was pressed while Alt is held down.
+    ///
+    /// Returns true if it was a numeric keypad digit, which we have used up.
+    pub fn key_pressed(&mut self, code: KeyCode) -> bool {
+        let digit = match code {
+            KeyCode::Numpad0 => 0,
+            KeyCode::Numpad1 => 1,
+            KeyCode::Numpad2 => 2,
+            KeyCode::Numpad3 => 3,
+            KeyCode::Numpad4 => 4,
+            KeyCode::Numpad5 => 5,
+            KeyCode::Numpad6 => 6,
+            KeyCode::Numpad7 => 7,
+            KeyCode::Numpad8 => 8,
+            KeyCode::Numpad9 => 9,
+            _ => return false,
+        };
+        // Too many digits gives an invalid code point, which `finish` ignores
+        let value = self.value.unwrap_or(0);
+        self.value = Some(value.saturating_mul(10).saturating_add(digit));
+        true
+    }
+
+    /// Alt was let go. Returns the character that was typed, if any.
+    pub fn finish(&mut self) -> Option<char> {
+        self.value.take().and_then(char::from_u32)
+    }
+}
+
+/// Tracks the state of the keyboard lock keys.
+///
+/// The keyboard decoder tracks Caps Lock and Num Lock internally but doesn't
+/// tell us about them, so we keep our own copy, toggled by the same key
+/// presses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyboardLocks {
+    /// Is Caps Lock on?
+    caps_lock: bool,
+    /// Is Num Lock on?
+    num_lock: bool,
+    /// Is Scroll Lock on?
+    scroll_lock: bool,
+}
+
+impl KeyboardLocks {
+    /// The state at power-up, which matches the keyboard decoder.
+    pub const fn new() -> KeyboardLocks {
+        KeyboardLocks {
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    /// Is Caps Lock on?
+    pub fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+
+    /// Is Num Lock on?
+    pub fn num_lock(&self) -> bool {
+        self.num_lock
+    }
+
+    /// Update the lock state given a key press.
+    ///
+    /// Returns `true` if the state changed.
+    pub fn key_pressed(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::CapsLock => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock => self.scroll_lock = !self.scroll_lock,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Get the keyboard LED state that matches our lock state.
+    pub fn leds(&self) -> KeyboardLeds {
+        let mut leds = KeyboardLeds::new();
+        if self.scroll_lock {
+            leds = leds.set_scroll_lock_on();
+        }
+        if self.num_lock {
+            leds = leds.set_num_lock_on();
+        }
+        if self.caps_lock {
+            leds = leds.set_caps_lock_on();
+        }
+        leds
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Type some characters into a French state machine, and see what comes
+    /// out
+    fn compose(input: &str) -> String {
+        let mut compose = Compose::with_keymap(Keymap::Fr);
+        let mut result = String::new();
+        for ch in input.chars() {
+            for key in compose.push(DecodedKey::Unicode(ch)) {
+                if let DecodedKey::Unicode(ch) = key {
+                    result.push(ch);
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn dead_keys() {
+        assert_eq!(compose("f^ete"), "fête");
+        assert_eq!(compose("^E¨i"), "Êï");
+        // Space types the accent by itself
+        assert_eq!(compose("^ x"), "^x");
+        // Letters that don't go with the accent type both
+        assert_eq!(compose("^x"), "^x");
+        assert_eq!(compose("^^"), "^^");
+        // Backspace forgets the dead key
+        assert_eq!(compose("^\u{0008}e"), "e");
+    }
+
+    #[test]
+    fn no_dead_keys() {
+        let mut compose = Compose::with_keymap(Keymap::Uk);
+        let output = compose.push(DecodedKey::Unicode('^'));
+        assert_eq!(output.as_slice(), [DecodedKey::Unicode('^')]);
+    }
+
+    #[test]
+    fn raw_keys() {
+        let mut compose = Compose::with_keymap(Keymap::De);
+        assert!(compose.push(DecodedKey::Unicode('´')).as_slice().is_empty());
+        let output = compose.push(DecodedKey::RawKey(KeyCode::ArrowLeft));
+        assert_eq!(
+            output.as_slice(),
+            [
+                DecodedKey::Unicode('´'),
+                DecodedKey::RawKey(KeyCode::ArrowLeft)
+            ]
+        );
+    }
+
+    #[test]
+    fn alt_codes() {
+        let mut alt_code = AltCode::new();
+        assert_eq!(alt_code.finish(), None);
+        for code in [KeyCode::Numpad2, KeyCode::Numpad3, KeyCode::Numpad3] {
+            assert!(alt_code.key_pressed(code));
+        }
+        assert_eq!(alt_code.finish(), Some('é'));
+        // Other keys aren't part of the code
+        assert!(!alt_code.key_pressed(KeyCode::Key1));
+        assert_eq!(alt_code.finish(), None);
+        // Not a valid code point
+        for _ in 0..12 {
+            alt_code.key_pressed(KeyCode::Numpad9);
+        }
+        assert_eq!(alt_code.finish(), None);
+    }
+
+    #[test]
+    fn lock_keys() {
+        let mut locks = KeyboardLocks::new();
+        assert!(!locks.caps_lock());
+        assert!(locks.num_lock());
+        assert!(locks.key_pressed(KeyCode::CapsLock));
+        assert!(locks.key_pressed(KeyCode::NumpadLock));
+        assert!(locks.caps_lock());
+        assert!(!locks.num_lock());
+        // Other keys don't change anything
+        assert!(!locks.key_pressed(KeyCode::A));
+        assert_eq!(locks.leds(), KeyboardLeds::new().set_caps_lock_on());
+    }
+}
+
+// End of file
@@ -6,24 +6,65 @@
 //!
 //! Licence: GPL v3 or higher (see ../LICENCE.md)
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "lib-mode")), no_std)]
 
 // ===========================================================================
 // Modules and Imports
 // ===========================================================================
 
-use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI16, AtomicPtr, Ordering};
 
 use neotron_common_bios as bios;
 
+#[cfg(feature = "basic")]
+mod basic;
+mod bus;
+mod calc;
 mod commands;
 mod config;
+mod crc;
+mod diff;
+mod fault;
 mod fs;
+mod hidrec;
+mod history;
+mod joypad;
+mod keyboard;
+mod log;
+mod lz4;
+mod midi;
+#[cfg(test)]
+mod mock_bios;
+mod module;
+mod mpu;
+#[cfg(feature = "net")]
+mod net;
+mod power;
 mod program;
+mod ramdisk;
+mod rawconsole;
 mod refcell;
+mod schedule;
+mod screensaver;
+mod search;
+mod sensors;
+mod serve;
+mod sha256;
+mod spool;
+mod staging;
+mod stats;
+mod statusbar;
+mod sysfs;
+#[cfg(feature = "sound")]
+mod tracker;
+mod ui;
+mod vconsole;
 mod vgaconsole;
+mod vsync;
+mod watchdog;
 
 pub use config::Config as OsConfig;
+use keyboard::KeyboardDecoder;
 use refcell::CsRefCell;
 
 // ===========================================================================
@@ -45,12 +86,29 @@ static VGA_CONSOLE: CsRefCell<Option<vgaconsole::VgaConsole>> = CsRefCell::new(N
 /// We store our serial console here.
 static SERIAL_CONSOLE: CsRefCell<Option<SerialConsole>> = CsRefCell::new(None);
 
+/// Output for the VGA console that we couldn't print because it was locked
+static VGA_STAGING: staging::Ring = staging::Ring::new();
+
+/// Output for the serial console that we couldn't print because it was
+/// locked
+static SERIAL_STAGING: staging::Ring = staging::Ring::new();
+
 /// Our overall text output console.
 ///
 /// Writes to the VGA console and/or the serial console (depending on which is
 /// configured).
 static CONSOLE: Console = Console;
 
+/// Which consoles standard input comes from
+static STDIN_ROUTE: CsRefCell<config::Route> = CsRefCell::new(config::Route::ALL);
+
+/// Which consoles standard output goes to
+static STDOUT_ROUTE: CsRefCell<config::Route> = CsRefCell::new(config::Route::ALL);
+
+/// Pauses standard output after every screenful, if it is turned on (see
+/// [`Console::start_pager`])
+static PAGER: CsRefCell<Option<Pager>> = CsRefCell::new(None);
+
 /// Note if we are panicking right now.
 ///
 /// If so, don't panic if a serial write fails.
@@ -59,7 +117,10 @@ static IS_PANIC: AtomicBool = AtomicBool::new(false);
 /// Our keyboard controller
 static STD_INPUT: CsRefCell<StdInput> = CsRefCell::new(StdInput::new());
 
-static FILESYSTEM: fs::Filesystem = fs::Filesystem::new();
+static FILESYSTEM: fs::Filesystem = fs::Filesystem::new(fs::Drive::Sd);
+
+/// The filesystem on the RAM disk
+static RAM_FILESYSTEM: fs::Filesystem = fs::Filesystem::new(fs::Drive::Ram);
 
 #[cfg(romfs_enabled = "yes")]
 static ROMFS: &'static [u8] = include_bytes!(env!("ROMFS_PATH"));
@@ -67,6 +128,13 @@ static ROMFS: &'static [u8] = include_bytes!(env!("ROMFS_PATH"));
 #[cfg(not(romfs_enabled = "yes"))]
 static ROMFS: &'static [u8] = &[];
 
+/// The slides built into the OS, as a name and the contents of a BMP file
+#[cfg(feature = "slides")]
+static SLIDES: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/slides.rs"));
+
+#[cfg(all(feature = "gfx", not(feature = "slides")))]
+static SLIDES: &[(&str, &[u8])] = &[];
+
 // ===========================================================================
 // Macros
 // ===========================================================================
@@ -82,9 +150,15 @@ macro_rules! osprint {
 }
 
 /// Prints to the screen and puts a new-line on the end
+///
+/// Use `osprintln!(log: Warn, "...")` to also put the message in the system
+/// log. It is then only printed if the console log level allows it.
 #[macro_export]
 macro_rules! osprintln {
     () => ($crate::osprint!("\n"));
+    (log: $level:ident, $($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::$level, format_args!($($arg)*))
+    };
     ($($arg:tt)*) => {
         $crate::osprint!($($arg)*);
         $crate::osprint!("\n");
@@ -98,6 +172,8 @@ macro_rules! osprintln {
 /// Represents the API supplied by the BIOS
 struct Api {
     bios: AtomicPtr<bios::Api>,
+    /// Our timezone, in minutes east of UTC
+    tz_offset_mins: AtomicI16,
 }
 
 impl Api {
@@ -105,6 +181,7 @@ impl Api {
     const fn new() -> Api {
         Api {
             bios: AtomicPtr::new(core::ptr::null_mut()),
+            tz_offset_mins: AtomicI16::new(0),
         }
     }
 
@@ -124,7 +201,35 @@ impl Api {
         api_ref
     }
 
-    /// Get the current time
+    /// Get the BIOS API as a reference, if we have been given one.
+    fn try_get(&self) -> Option<&'static bios::Api> {
+        let ptr = self.bios.load(Ordering::SeqCst) as *const bios::Api;
+        unsafe { ptr.as_ref() }
+    }
+
+    /// Get our timezone, in minutes east of UTC
+    fn get_tz_offset(&self) -> i16 {
+        self.tz_offset_mins.load(Ordering::Relaxed)
+    }
+
+    /// Set our timezone, in minutes east of UTC
+    fn set_tz_offset(&self, minutes: i16) {
+        self.tz_offset_mins.store(minutes, Ordering::Relaxed);
+    }
+
+    /// Get the current local time
+    fn get_local_time(&self) -> chrono::NaiveDateTime {
+        self.get_time() + chrono::TimeDelta::minutes(i64::from(self.get_tz_offset()))
+    }
+
+    /// Set the current time, given the local time
+    fn set_local_time(&self, timestamp: chrono::NaiveDateTime) {
+        self.set_time(timestamp - chrono::TimeDelta::minutes(i64::from(self.get_tz_offset())));
+    }
+
+    /// Get the current time, in UTC.
+    ///
+    /// The BIOS clock always runs on UTC.
     fn get_time(&self) -> chrono::NaiveDateTime {
         let api = self.get();
         let bios_time = (api.time_clock_get)();
@@ -135,7 +240,20 @@ impl Api {
             .naive_utc()
     }
 
-    /// Set the current time
+    /// Get how long the system has been running.
+    ///
+    /// This comes from the BIOS tick counter, so it always goes up, even if
+    /// someone changes the time and date.
+    fn get_uptime(&self) -> core::time::Duration {
+        let api = self.get();
+        let ticks = (api.time_ticks_get)().0;
+        let ticks_per_second = (api.time_ticks_per_second)().0.max(1);
+        let secs = ticks / ticks_per_second;
+        let nanos = ((ticks % ticks_per_second) * 1_000_000_000) / ticks_per_second;
+        core::time::Duration::new(secs, nanos as u32)
+    }
+
+    /// Set the current time, in UTC
     fn set_time(&self, timestamp: chrono::NaiveDateTime) {
         let api = self.get();
         let seconds = timestamp.and_utc().timestamp();
@@ -202,41 +320,543 @@ impl core::fmt::Write for SerialConsole {
 /// Represents either or both of the VGA console and the serial console.
 struct Console;
 
+impl Console {
+    /// Which consoles should standard output go to?
+    ///
+    /// If we're panicking, the answer is all of them.
+    fn stdout_route() -> config::Route {
+        if IS_PANIC.load(Ordering::Relaxed) {
+            return config::Route::ALL;
+        }
+        STDOUT_ROUTE
+            .try_lock()
+            .map(|r| *r)
+            .unwrap_or(config::Route::ALL)
+    }
+
+    /// Which consoles should standard input come from?
+    fn stdin_route() -> config::Route {
+        STDIN_ROUTE
+            .try_lock()
+            .map(|r| *r)
+            .unwrap_or(config::Route::ALL)
+    }
+
+    /// Change which consoles standard input comes from.
+    fn set_stdin_route(route: config::Route) -> Result<(), &'static str> {
+        Self::check_route(route)?;
+        *STDIN_ROUTE.lock() = route;
+        Ok(())
+    }
+
+    /// Change which consoles standard output goes to.
+    fn set_stdout_route(route: config::Route) -> Result<(), &'static str> {
+        Self::check_route(route)?;
+        *STDOUT_ROUTE.lock() = route;
+        Ok(())
+    }
+
+    /// Check a route includes at least one console we actually have.
+    ///
+    /// Otherwise we'd never hear from (or be heard by) the user again.
+    fn check_route(route: config::Route) -> Result<(), &'static str> {
+        let has_vga = VGA_CONSOLE.lock().is_some();
+        let has_serial = SERIAL_CONSOLE.lock().is_some();
+        if (route.vga && has_vga) || (route.serial && has_serial) {
+            Ok(())
+        } else {
+            Err("No such console configured")
+        }
+    }
+
+    /// Pause standard output after every screenful, until
+    /// [`Console::stop_pager`] is called.
+    ///
+    /// The screen size comes from the VGA console, if we have one.
+    fn start_pager() {
+        let (width, height) = VGA_CONSOLE
+            .lock()
+            .as_ref()
+            .map(|vga| (vga.width(), vga.height()))
+            .unwrap_or((Pager::DEFAULT_WIDTH, Pager::DEFAULT_HEIGHT));
+        *PAGER.lock() = Some(Pager {
+            // Leave a line for the prompt
+            page_lines: (height - 1).max(1),
+            width: width.max(1),
+            lines: 0,
+            col: 0,
+            in_escape: false,
+            quit: false,
+        });
+    }
+
+    /// Stop pausing standard output
+    fn stop_pager() {
+        *PAGER.lock() = None;
+    }
+
+    /// Did the user ask the pager to stop?
+    ///
+    /// If so, whatever is printing should give up, because nobody will see
+    /// it.
+    fn pager_quit() -> bool {
+        PAGER.lock().as_ref().is_some_and(|pager| pager.quit)
+    }
+
+    /// Write to the consoles standard output goes to, without paging.
+    ///
+    /// If a console is locked, the text is staged, and printed once whoever
+    /// has the lock lets go.
+    fn write_raw(s: &str) -> core::fmt::Result {
+        use core::fmt::Write as _;
+        let route = Console::stdout_route();
+
+        if route.vga {
+            match VGA_CONSOLE.try_lock() {
+                Ok(mut guard) => {
+                    if let Some(vga_console) = guard.as_mut() {
+                        VGA_STAGING.drain(|staged| vga_console.write_str(staged))?;
+                        vga_console.write_str(s)?;
+                    }
+                }
+                Err(_) => VGA_STAGING.push(s),
+            }
+        }
+
+        if route.serial {
+            match SERIAL_CONSOLE.try_lock() {
+                Ok(mut guard) => {
+                    if let Some(serial_console) = guard.as_mut() {
+                        SERIAL_STAGING.drain(|staged| serial_console.write_str(staged))?;
+                        serial_console.write_str(s)?;
+                    }
+                }
+                Err(_) => SERIAL_STAGING.push(s),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print anything that was staged because a console was locked.
+    ///
+    /// The main loop calls this, in case nothing else gets printed for a
+    /// while.
+    fn flush_staged() {
+        use core::fmt::Write as _;
+        if !VGA_STAGING.is_empty() {
+            if let Ok(mut guard) = VGA_CONSOLE.try_lock() {
+                if let Some(vga_console) = guard.as_mut() {
+                    let _ = VGA_STAGING.drain(|staged| vga_console.write_str(staged));
+                }
+            }
+        }
+        if !SERIAL_STAGING.is_empty() {
+            if let Ok(mut guard) = SERIAL_CONSOLE.try_lock() {
+                if let Some(serial_console) = guard.as_mut() {
+                    let _ = SERIAL_STAGING.drain(|staged| serial_console.write_str(staged));
+                }
+            }
+        }
+    }
+}
+
 impl core::fmt::Write for &Console {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        if let Ok(mut guard) = VGA_CONSOLE.try_lock() {
-            if let Some(vga_console) = guard.as_mut() {
-                vga_console.write_str(s)?;
+        if IS_PANIC.load(Ordering::Relaxed) {
+            // The consoles might be locked, or in a mess, so go around them
+            rawconsole::write_str(s);
+            return Ok(());
+        }
+        if let Ok(mut guard) = PAGER.try_lock() {
+            if let Some(pager) = guard.as_mut() {
+                return pager.write_str(s);
             }
         }
+        Console::write_raw(s)
+    }
+}
+
+/// Pauses the console after every screenful of text
+struct Pager {
+    /// How many lines we print before we pause
+    page_lines: isize,
+    /// How many characters fit on a line, before it wraps
+    width: isize,
+    /// How many lines we have printed since we last paused
+    lines: isize,
+    /// Which column the cursor is in
+    col: isize,
+    /// Are we in the middle of an escape sequence?
+    in_escape: bool,
+    /// Did the user press `q`? If so, we print nothing more.
+    quit: bool,
+}
 
-        if let Ok(mut guard) = SERIAL_CONSOLE.try_lock() {
-            if let Some(serial_console) = guard.as_mut() {
-                serial_console.write_str(s)?;
+impl Pager {
+    /// The screen width, if we don't have a VGA console
+    const DEFAULT_WIDTH: isize = 80;
+
+    /// The screen height, if we don't have a VGA console
+    const DEFAULT_HEIGHT: isize = 25;
+
+    /// Print some text, pausing whenever the screen is full
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut start = 0;
+        for (idx, ch) in s.char_indices() {
+            if self.quit {
+                return Ok(());
+            }
+            if self.in_escape {
+                // Escape sequences end with a letter, and take up no room
+                self.in_escape = !ch.is_ascii_alphabetic();
+                continue;
+            }
+            match ch {
+                '\u{001b}' => {
+                    self.in_escape = true;
+                    continue;
+                }
+                '\r' => {
+                    self.col = 0;
+                    continue;
+                }
+                '\n' => {
+                    self.col = 0;
+                    self.lines += 1;
+                }
+                _ => {
+                    self.col += 1;
+                    if self.col < self.width {
+                        continue;
+                    }
+                    // The line wraps
+                    self.col = 0;
+                    self.lines += 1;
+                }
+            }
+            if self.lines >= self.page_lines {
+                let end = idx + ch.len_utf8();
+                Console::write_raw(&s[start..end])?;
+                start = end;
+                self.more()?;
             }
         }
+        if self.quit {
+            return Ok(());
+        }
+        Console::write_raw(&s[start..])
+    }
 
+    /// Show the `--More--` prompt, and wait for a key.
+    ///
+    /// Space shows another screenful, Enter shows another line, and `q`
+    /// stops the output.
+    fn more(&mut self) -> core::fmt::Result {
+        Console::write_raw("--More--")?;
+        let key = loop {
+            // Big enough to swallow the escape sequence for a cursor key
+            let mut buffer = [0u8; 8];
+            if STD_INPUT.lock().get_data(&mut buffer) > 0 {
+                break buffer[0];
+            }
+            crate::stats::idle();
+        };
+        // Rub out the prompt
+        Console::write_raw("\r        \r")?;
+        match key {
+            b'q' | b'Q' => self.quit = true,
+            b'\r' | b'\n' => self.lines = self.page_lines - 1,
+            _ => self.lines = 0,
+        }
         Ok(())
     }
 }
 
 /// Represents the standard input of our console
 struct StdInput {
-    keyboard: pc_keyboard::EventDecoder<pc_keyboard::layouts::AnyLayout>,
+    keyboard: keyboard::Decoder,
+    /// Handles dead keys, after the decoder
+    compose: keyboard::Compose,
+    /// Any code point being typed on the numeric keypad with Alt held down
+    alt_code: keyboard::AltCode,
     buffer: heapless::spsc::Queue<u8, 16>,
+    /// Which lock keys are on
+    locks: keyboard::KeyboardLocks,
+    /// If set, we make held keys repeat (for BIOSes that don't do it for us)
+    key_repeat: Option<config::KeyRepeat>,
+    /// The key we might need to repeat
+    held_key: Option<HeldKey>,
+    /// Which modifier keys are held down
+    modifiers: HeldModifiers,
+    /// How the mouse has moved since someone last asked
+    mouse: Option<MouseMotion>,
+    /// Does the running program want input events?
+    events_on: bool,
+    /// Input events the program hasn't taken yet
+    events: heapless::spsc::Queue<InputEvent, 16>,
+}
+
+/// A key or mouse event, for programs (like games) that need to know when
+/// keys go up and down, not just what was typed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum InputEvent {
+    /// A key was pressed or released
+    Key {
+        /// Which key
+        code: pc_keyboard::KeyCode,
+        /// Did it go down (or up)?
+        pressed: bool,
+        /// The modifier and lock keys, as `InputEvent::SHIFT` etc
+        modifiers: u8,
+    },
+    /// The mouse moved, or a button changed
+    Mouse {
+        /// How far the mouse moved to the right
+        dx: i16,
+        /// How far the mouse moved down the screen
+        dy: i16,
+        /// Bit 0 is the left button, bit 1 the right button
+        buttons: u8,
+    },
+}
+
+impl InputEvent {
+    /// A Shift key is held down
+    const SHIFT: u8 = 1 << 0;
+    /// A Ctrl key is held down
+    const CTRL: u8 = 1 << 1;
+    /// An Alt key is held down
+    const ALT: u8 = 1 << 2;
+    /// Caps Lock is on
+    const CAPS_LOCK: u8 = 1 << 3;
+    /// Num Lock is on
+    const NUM_LOCK: u8 = 1 << 4;
+
+    /// Pack the event into the value `ioctl` gives a program.
+    ///
+    /// See `program::api_ioctl` for the layout.
+    fn to_u64(self) -> u64 {
+        match self {
+            InputEvent::Key {
+                code,
+                pressed,
+                modifiers,
+            } => {
+                let kind = if pressed { 1 } else { 2 };
+                (u64::from(modifiers) << 16) | (u64::from(code as u8) << 8) | kind
+            }
+            InputEvent::Mouse { dx, dy, buttons } => {
+                (u64::from(dy as u16) << 32)
+                    | (u64::from(dx as u16) << 16)
+                    | (u64::from(buttons) << 8)
+                    | 3
+            }
+        }
+    }
+}
+
+/// How the mouse has moved, and which buttons are held down.
+///
+/// The BIOS tells us how far the mouse moved since its last report, so we
+/// add the movements up until someone asks.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct MouseMotion {
+    /// How far the mouse moved to the right
+    dx: i32,
+    /// How far the mouse moved down the screen
+    dy: i32,
+    /// Is the left button held down?
+    left: bool,
+    /// Is the right button held down?
+    right: bool,
+}
+
+/// A key that is being held down, for key repeat purposes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct HeldKey {
+    /// Which key is held down
+    code: pc_keyboard::KeyCode,
+    /// When (in BIOS ticks) we should next repeat it
+    next_repeat: u64,
+}
+
+/// Tracks which modifier keys are held down.
+///
+/// The keyboard decoder knows this too, but doesn't tell us.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct HeldModifiers {
+    /// One bit for each modifier key
+    keys: u8,
+}
+
+impl HeldModifiers {
+    /// Either Shift key
+    const SHIFT: u8 = (1 << 0) | (1 << 1);
+    /// Either Ctrl key
+    const CTRL: u8 = (1 << 2) | (1 << 3);
+    /// Alt or AltGr
+    const ALT: u8 = (1 << 4) | (1 << 5);
+
+    /// No keys held down
+    const fn new() -> HeldModifiers {
+        HeldModifiers { keys: 0 }
+    }
+
+    /// Update the state given a key press or release.
+    ///
+    /// Returns `true` if the state changed.
+    fn key_changed(&mut self, code: pc_keyboard::KeyCode, pressed: bool) -> bool {
+        let bit = match code {
+            pc_keyboard::KeyCode::LShift => 1 << 0,
+            pc_keyboard::KeyCode::RShift => 1 << 1,
+            pc_keyboard::KeyCode::LControl => 1 << 2,
+            pc_keyboard::KeyCode::RControl | pc_keyboard::KeyCode::RControl2 => 1 << 3,
+            pc_keyboard::KeyCode::LAlt => 1 << 4,
+            pc_keyboard::KeyCode::RAltGr | pc_keyboard::KeyCode::RAlt2 => 1 << 5,
+            _ => return false,
+        };
+        let old = self.keys;
+        if pressed {
+            self.keys |= bit;
+        } else {
+            self.keys &= !bit;
+        }
+        self.keys != old
+    }
+
+    /// Is a Shift key held down?
+    fn shift(&self) -> bool {
+        self.keys & Self::SHIFT != 0
+    }
+
+    /// Is a Ctrl key held down?
+    fn ctrl(&self) -> bool {
+        self.keys & Self::CTRL != 0
+    }
+
+    /// Is an Alt key held down?
+    fn alt(&self) -> bool {
+        self.keys & Self::ALT != 0
+    }
 }
 
 impl StdInput {
     const fn new() -> StdInput {
         StdInput {
-            keyboard: pc_keyboard::EventDecoder::new(
-                pc_keyboard::layouts::AnyLayout::Uk105Key(pc_keyboard::layouts::Uk105Key),
-                pc_keyboard::HandleControl::MapLettersToUnicode,
-            ),
+            keyboard: keyboard::Decoder::new(),
+            compose: keyboard::Compose::new(),
+            alt_code: keyboard::AltCode::new(),
             buffer: heapless::spsc::Queue::new(),
+            locks: keyboard::KeyboardLocks::new(),
+            key_repeat: None,
+            held_key: None,
+            modifiers: HeldModifiers::new(),
+            mouse: None,
+            events_on: false,
+            events: heapless::spsc::Queue::new(),
         }
     }
 
+    /// Start or stop queueing input events for the running program.
+    ///
+    /// Any events still queued are thrown away.
+    fn set_events(&mut self, on: bool) {
+        self.events_on = on;
+        while self.events.dequeue().is_some() {}
+    }
+
+    /// Get the next input event, if there is one.
+    ///
+    /// Keys typed while we look are still buffered for `get_data`, if
+    /// there is room.
+    fn get_event(&mut self) -> Option<InputEvent> {
+        if self.events.is_empty() {
+            self.poll();
+        }
+        self.events.dequeue()
+    }
+
+    /// Queue an input event, if the running program wants them.
+    ///
+    /// If the program isn't keeping up, we drop the event.
+    fn push_event(&mut self, event: InputEvent) {
+        if self.events_on {
+            let _ = self.events.enqueue(event);
+        }
+    }
+
+    /// Queue a key event, with the given modifier keys held down.
+    fn push_key_event(&mut self, code: pc_keyboard::KeyCode, pressed: bool, held: HeldModifiers) {
+        let mut modifiers = 0;
+        if held.shift() {
+            modifiers |= InputEvent::SHIFT;
+        }
+        if held.ctrl() {
+            modifiers |= InputEvent::CTRL;
+        }
+        if held.alt() {
+            modifiers |= InputEvent::ALT;
+        }
+        if self.locks.caps_lock() {
+            modifiers |= InputEvent::CAPS_LOCK;
+        }
+        if self.locks.num_lock() {
+            modifiers |= InputEvent::NUM_LOCK;
+        }
+        self.push_event(InputEvent::Key {
+            code,
+            pressed,
+            modifiers,
+        });
+    }
+
+    /// Turn OS key repeat on or off.
+    fn set_key_repeat(&mut self, key_repeat: Option<config::KeyRepeat>) {
+        self.key_repeat = key_repeat;
+        self.held_key = None;
+    }
+
+    /// Change the keyboard layout, or have the BIOS apply it.
+    ///
+    /// The new decoder starts with the lock keys in their power-up state, so
+    /// we reset ours to match. Call `update_leds` afterwards.
+    fn set_keymap(&mut self, keymap: config::Keymap) {
+        self.keyboard = keyboard::Decoder::with_keymap(keymap);
+        self.compose = keyboard::Compose::with_keymap(keymap);
+        self.locks = keyboard::KeyboardLocks::new();
+        self.held_key = None;
+        self.modifiers = HeldModifiers::new();
+        self.alt_code = keyboard::AltCode::new();
+    }
+
+    /// Which lock keys are on?
+    fn locks(&self) -> keyboard::KeyboardLocks {
+        self.locks
+    }
+
+    /// Which modifier keys are held down?
+    fn modifiers(&self) -> HeldModifiers {
+        self.modifiers
+    }
+
+    /// Take how the mouse has moved since we were last asked, if it has
+    /// done anything.
+    ///
+    /// The mouse is only read when someone polls the keyboard.
+    fn take_mouse(&mut self) -> Option<MouseMotion> {
+        self.mouse.take()
+    }
+
+    /// Set the keyboard LEDs to match our lock state.
+    ///
+    /// Call this at start-up, as the keyboard might not agree with us.
+    fn update_leds(&self) {
+        let api = API.get();
+        // Not every keyboard has LEDs, so we don't mind if this fails
+        let _ = (api.hid_set_leds)(self.locks.leds());
+    }
+
     fn get_buffered_data(&mut self, buffer: &mut [u8]) -> usize {
         // If there is some data, get it.
         let mut count = 0;
@@ -251,70 +871,258 @@ impl StdInput {
 
     /// Gets a raw event from the keyboard
     fn get_raw(&mut self) -> Option<pc_keyboard::DecodedKey> {
+        // Anyone polling the keyboard hasn't hung
+        watchdog::kick();
         let api = API.get();
-        match (api.hid_get_event)() {
-            bios::ApiResult::Ok(bios::FfiOption::Some(bios::hid::HidEvent::KeyPress(code))) => {
+        // Events we're replaying come first, and we record what the BIOS
+        // gives us
+        let event = hidrec::replay().or_else(|| match (api.hid_get_event)() {
+            bios::ApiResult::Ok(bios::FfiOption::Some(event)) => {
+                hidrec::record(&event);
+                Some(event)
+            }
+            bios::ApiResult::Ok(bios::FfiOption::None) | bios::ApiResult::Err(_) => None,
+        });
+        match event {
+            Some(bios::hid::HidEvent::KeyPress(code)) => {
+                if self.modifiers.alt() {
+                    if let Some(index) = Self::console_hotkey(code) {
+                        // Alt+Fn switches virtual console, and the
+                        // application never sees it
+                        let _ = vconsole::switch_to(index);
+                        return None;
+                    }
+                }
+                // Report the modifiers as they are with this key down
+                let mut held = self.modifiers;
+                held.key_changed(code, true);
+                self.push_key_event(code, true, held);
+                if self.modifiers.alt() && self.alt_code.key_pressed(code) {
+                    // Alt+numpad digits are a code point, which we type when
+                    // Alt is let go
+                    return None;
+                }
+                if self.modifiers.key_changed(code, true) {
+                    statusbar::keyboard_changed();
+                }
+                if self.locks.key_pressed(code) {
+                    self.update_leds();
+                    statusbar::keyboard_changed();
+                }
+                self.key_down(code);
                 let pckb_ev = pc_keyboard::KeyEvent {
                     code,
                     state: pc_keyboard::KeyState::Down,
                 };
                 self.keyboard.process_keyevent(pckb_ev)
             }
-            bios::ApiResult::Ok(bios::FfiOption::Some(bios::hid::HidEvent::KeyRelease(code))) => {
+            Some(bios::hid::HidEvent::KeyRelease(code)) => {
+                let alt_released = matches!(
+                    code,
+                    pc_keyboard::KeyCode::LAlt | pc_keyboard::KeyCode::RAltGr
+                );
+                if self.modifiers.key_changed(code, false) {
+                    statusbar::keyboard_changed();
+                }
+                self.push_key_event(code, false, self.modifiers);
+                if self.held_key.map(|h| h.code) == Some(code) {
+                    self.held_key = None;
+                }
                 let pckb_ev = pc_keyboard::KeyEvent {
                     code,
                     state: pc_keyboard::KeyState::Up,
                 };
-                self.keyboard.process_keyevent(pckb_ev)
+                // The decoder needs to see Alt go up, even if we then type
+                // an Alt+numpad character
+                let decoded_key = self.keyboard.process_keyevent(pckb_ev);
+                if alt_released {
+                    if let Some(ch) = self.alt_code.finish() {
+                        return Some(pc_keyboard::DecodedKey::Unicode(ch));
+                    }
+                }
+                decoded_key
             }
-            bios::ApiResult::Ok(bios::FfiOption::Some(bios::hid::HidEvent::MouseInput(
-                _ignore,
-            ))) => None,
-            bios::ApiResult::Ok(bios::FfiOption::None) => {
+            Some(bios::hid::HidEvent::MouseInput(data)) => {
+                let mut buttons = 0;
+                if data.buttons.is_left_pressed() {
+                    buttons |= 1 << 0;
+                }
+                if data.buttons.is_right_pressed() {
+                    buttons |= 1 << 1;
+                }
+                self.push_event(InputEvent::Mouse {
+                    dx: data.x,
+                    dy: data.y,
+                    buttons,
+                });
+                let motion = self.mouse.get_or_insert_with(MouseMotion::default);
+                motion.dx += i32::from(data.x);
+                motion.dy += i32::from(data.y);
+                motion.left = data.buttons.is_left_pressed();
+                motion.right = data.buttons.is_right_pressed();
+                None
+            }
+            None => {
                 // Do nothing
                 None
             }
-            bios::ApiResult::Err(_e) => None,
         }
     }
 
-    /// Gets some input bytes, as UTF-8.
+    /// Which virtual console (0 is the main console) does this function key
+    /// select?
+    fn console_hotkey(code: pc_keyboard::KeyCode) -> Option<usize> {
+        match code {
+            pc_keyboard::KeyCode::F1 => Some(0),
+            pc_keyboard::KeyCode::F2 => Some(1),
+            pc_keyboard::KeyCode::F3 => Some(2),
+            pc_keyboard::KeyCode::F4 => Some(3),
+            _ => None,
+        }
+    }
+
+    /// Note that a key has been pressed, so it can be repeated later.
     ///
-    /// The data you get might be cut in the middle of a UTF-8 character.
-    fn get_data(&mut self, buffer: &mut [u8]) -> usize {
-        let count = self.get_buffered_data(buffer);
-        if buffer.is_empty() || count > 0 {
-            return count;
+    /// Only the most recently pressed key repeats, and modifier and lock
+    /// keys never repeat.
+    fn key_down(&mut self, code: pc_keyboard::KeyCode) {
+        let Some(key_repeat) = self.key_repeat else {
+            return;
+        };
+        let repeats = !matches!(
+            code,
+            pc_keyboard::KeyCode::LShift
+                | pc_keyboard::KeyCode::RShift
+                | pc_keyboard::KeyCode::LControl
+                | pc_keyboard::KeyCode::RControl
+                | pc_keyboard::KeyCode::RControl2
+                | pc_keyboard::KeyCode::LAlt
+                | pc_keyboard::KeyCode::RAltGr
+                | pc_keyboard::KeyCode::RAlt2
+                | pc_keyboard::KeyCode::LWin
+                | pc_keyboard::KeyCode::RWin
+                | pc_keyboard::KeyCode::CapsLock
+                | pc_keyboard::KeyCode::NumpadLock
+                | pc_keyboard::KeyCode::ScrollLock
+        );
+        self.held_key = if repeats {
+            let api = API.get();
+            let now = (api.time_ticks_get)().0;
+            let ticks_per_second = (api.time_ticks_per_second)().0;
+            let delay = u64::from(key_repeat.delay_ms) * ticks_per_second / 1000;
+            Some(HeldKey {
+                code,
+                next_repeat: now + delay,
+            })
+        } else {
+            None
+        };
+    }
+
+    /// Generate a repeat of the held key, if it is time to do so.
+    fn get_repeat(&mut self) -> Option<pc_keyboard::DecodedKey> {
+        let key_repeat = self.key_repeat?;
+        let held_key = self.held_key.as_mut()?;
+        let api = API.get();
+        let now = (api.time_ticks_get)().0;
+        if now < held_key.next_repeat {
+            return None;
         }
+        let ticks_per_second = (api.time_ticks_per_second)().0;
+        let period = ticks_per_second / u64::from(key_repeat.rate_hz.max(1));
+        held_key.next_repeat = now + period;
+        let pckb_ev = pc_keyboard::KeyEvent {
+            code: held_key.code,
+            state: pc_keyboard::KeyState::Down,
+        };
+        self.keyboard.process_keyevent(pckb_ev)
+    }
 
-        // Nothing buffered - ask the keyboard for something
-        let decoded_key = self.get_raw();
+    /// Get the escape sequence for a navigation key.
+    ///
+    /// These are `ESC [ 0 ; <n> b`, where `<n>` is the IBM PC scan code for
+    /// the key.
+    fn key_sequence(code: pc_keyboard::KeyCode) -> Option<&'static [u8]> {
+        let sequence: &'static [u8] = match code {
+            pc_keyboard::KeyCode::Home => b"\x1b[0;71b",
+            pc_keyboard::KeyCode::ArrowUp => b"\x1b[0;72b",
+            pc_keyboard::KeyCode::PageUp => b"\x1b[0;73b",
+            pc_keyboard::KeyCode::ArrowLeft => b"\x1b[0;75b",
+            pc_keyboard::KeyCode::ArrowRight => b"\x1b[0;77b",
+            pc_keyboard::KeyCode::End => b"\x1b[0;79b",
+            pc_keyboard::KeyCode::ArrowDown => b"\x1b[0;80b",
+            pc_keyboard::KeyCode::PageDown => b"\x1b[0;81b",
+            pc_keyboard::KeyCode::Insert => b"\x1b[0;82b",
+            pc_keyboard::KeyCode::Delete => b"\x1b[0;83b",
+            _ => return None,
+        };
+        Some(sequence)
+    }
 
-        match decoded_key {
-            Some(pc_keyboard::DecodedKey::Unicode(mut ch)) => {
+    /// Put a decoded key in our buffer, as UTF-8 or an escape sequence.
+    ///
+    /// If it doesn't all fit (because a program is reading input events and
+    /// not stdin), we drop it.
+    fn queue_key(&mut self, key: pc_keyboard::DecodedKey) {
+        let mut buffer = [0u8; 6];
+        let bytes = match key {
+            pc_keyboard::DecodedKey::Unicode(mut ch) => {
                 if ch == '\n' {
                     ch = '\r';
                 }
-                let mut buffer = [0u8; 6];
-                let s = ch.encode_utf8(&mut buffer);
-                for b in s.as_bytes() {
-                    // This will always fit
-                    self.buffer.enqueue(*b).unwrap();
-                }
+                ch.encode_utf8(&mut buffer).as_bytes()
             }
-            Some(pc_keyboard::DecodedKey::RawKey(pc_keyboard::KeyCode::ArrowRight)) => {
-                // Load the ANSI sequence for a right arrow
-                for b in b"\x1b[0;77b" {
-                    // This will always fit
-                    self.buffer.enqueue(*b).unwrap();
+            pc_keyboard::DecodedKey::RawKey(code) => {
+                // With Num Lock off, the decoder turns the numeric keypad
+                // into these navigation keys too.
+                match Self::key_sequence(code) {
+                    Some(sequence) => sequence,
+                    None => return,
                 }
             }
-            _ => {
-                // Drop anything else
+        };
+        if self.buffer.capacity() - self.buffer.len() < bytes.len() {
+            return;
+        }
+        for b in bytes {
+            // We checked there was room
+            self.buffer.enqueue(*b).unwrap();
+        }
+    }
+
+    /// Gets some input bytes, as UTF-8.
+    ///
+    /// The data you get might be cut in the middle of a UTF-8 character.
+    fn get_data(&mut self, buffer: &mut [u8]) -> usize {
+        let count = self.get_buffered_data(buffer);
+        if buffer.is_empty() || count > 0 {
+            return count;
+        }
+        self.poll();
+        self.get_buffered_data(buffer)
+    }
+
+    /// Ask the keyboard and the serial console for input, and buffer it.
+    fn poll(&mut self) {
+        let route = Console::stdin_route();
+
+        // Nothing buffered - ask the keyboard for something, or repeat
+        // whatever is being held down. We always ask, so the lock keys and
+        // console hot-keys work, but we might then ignore what we get.
+        let decoded_key = self
+            .get_raw()
+            .or_else(|| self.get_repeat())
+            .filter(|_| route.vga);
+
+        if let Some(decoded_key) = decoded_key {
+            // A dead key gives us nothing yet, and a dead key followed by
+            // something it doesn't go with gives us both.
+            for key in self.compose.push(decoded_key) {
+                self.queue_key(key);
             }
         }
 
-        if let Some(console) = SERIAL_CONSOLE.lock().as_mut() {
+        if let Some(console) = SERIAL_CONSOLE.lock().as_mut().filter(|_| route.serial) {
             while !self.buffer.is_full() {
                 let mut buffer = [0u8];
                 if let Ok(1) = console.read_data(&mut buffer) {
@@ -324,8 +1132,6 @@ impl StdInput {
                 }
             }
         }
-
-        self.get_buffered_data(buffer)
     }
 }
 
@@ -336,9 +1142,70 @@ impl StdInput {
 pub struct Ctx {
     config: config::Config,
     tpa: program::TransientProgramArea,
-    /// This flag is set if the "run" command is entered. It tells us
-    /// to take our input bytes from the TPA.
-    exec_tpa: Option<usize>,
+    /// This is set if the "exec" command is entered. It tells us to take our
+    /// input bytes from a script.
+    script: Option<Script>,
+    /// Do we say how long each command took?
+    timing: bool,
+}
+
+/// A shell script we are running.
+///
+/// We read the script from disk one line at a time, rather than keeping it
+/// in the TPA, so any programs it runs get the whole TPA.
+pub struct Script {
+    /// The file the script is in
+    path: heapless::String<32>,
+    /// Where the next line starts, in bytes from the start of the file
+    offset: u32,
+}
+
+impl Script {
+    /// The longest line we can run from a script
+    const MAX_LINE_LEN: usize = 128;
+
+    /// Start running the script in the given file.
+    fn new(path: &str) -> Result<Script, &'static str> {
+        let mut script_path = heapless::String::new();
+        script_path
+            .push_str(path)
+            .map_err(|_| "Script path too long")?;
+        Ok(Script {
+            path: script_path,
+            offset: 0,
+        })
+    }
+
+    /// Read the next line of the script into `buffer`.
+    ///
+    /// The line doesn't include the `\n`, nor any `\r`. Returns `Ok(None)` at
+    /// the end of the script.
+    fn next_line<'a>(
+        &mut self,
+        buffer: &'a mut [u8; Self::MAX_LINE_LEN],
+    ) -> Result<Option<&'a str>, &'static str> {
+        let file = FILESYSTEM
+            .open_file(&self.path, embedded_sdmmc::Mode::ReadOnly)
+            .map_err(|_| "Can't open script")?;
+        file.seek_from_start(self.offset)
+            .map_err(|_| "Can't read script")?;
+        let count = file.read(buffer).map_err(|_| "Can't read script")?;
+        if count == 0 {
+            return Ok(None);
+        }
+        let (line_len, skip) = match buffer[0..count].iter().position(|b| *b == b'\n') {
+            Some(idx) => (idx, 1),
+            None if file.is_eof() => (count, 0),
+            None => return Err("Script line too long"),
+        };
+        self.offset += (line_len + skip) as u32;
+        let mut line = &buffer[0..line_len];
+        while let Some((b'\r', rest)) = line.split_last() {
+            line = rest;
+        }
+        let line = core::str::from_utf8(line).map_err(|_| "Script is not valid UTF-8")?;
+        Ok(Some(line))
+    }
 }
 
 impl core::fmt::Write for Ctx {
@@ -390,6 +1257,152 @@ unsafe fn start_up_init() {
 /// of this function in the ENTRY_POINT_ADDR variable.
 #[no_mangle]
 pub extern "C" fn os_main(api: &bios::Api) -> ! {
+    os_start(api)
+}
+
+/// Put the VGA console back in order after a program has run.
+///
+/// Programs can call the BIOS to change the video mode and the palette
+/// behind our back. If the mode or framebuffer isn't what it was
+/// (`old_mode` and `old_framebuffer`) we put the mode back, and if we can't,
+/// we make the console fit whatever text mode we did get. The text colours
+/// always go back to the defaults.
+pub fn reset_console(old_mode: bios::video::Mode, old_framebuffer: *mut u32) {
+    let api = API.get();
+    if VGA_CONSOLE.lock().is_none() {
+        return;
+    }
+    let mode = (api.video_get_mode)();
+    let framebuffer = (api.video_get_framebuffer)();
+    if mode.as_u8() != old_mode.as_u8() || framebuffer != old_framebuffer {
+        vconsole::release();
+        // # Safety
+        //
+        // It's always OK to pass NULL to this API - the BIOS uses its own
+        // memory.
+        let mode = match unsafe { (api.video_set_mode)(old_mode, core::ptr::null_mut()) } {
+            bios::FfiResult::Ok(_) => old_mode,
+            bios::FfiResult::Err(_) => (api.video_get_mode)(),
+        };
+        if let (Some(width), Some(height)) = (mode.text_width(), mode.text_height()) {
+            let mut guard = VGA_CONSOLE.lock();
+            if let Some(console) = guard.as_mut() {
+                let mut new_console = vgaconsole::VgaConsole::new(
+                    (api.video_get_framebuffer)(),
+                    width as isize,
+                    height as isize,
+                );
+                new_console.set_status_line(console.status_line());
+                *console = new_console;
+            }
+        }
+        vconsole::setup();
+    }
+    for (idx, colour) in vgaconsole::DEFAULT_PALETTE.iter().enumerate() {
+        (api.video_set_palette)(idx as u8, *colour);
+    }
+}
+
+/// Put the consoles back into a known state after a program has run.
+///
+/// Resets the text attributes and turns the cursor back on. The OS has no raw
+/// keyboard mode for a program to leave switched on, so there's nothing to
+/// undo on the input side.
+pub fn reset_terminal() {
+    if let Some(console) = VGA_CONSOLE.lock().as_mut() {
+        console.reset_state();
+    }
+    // This also tells any serial terminal
+    osprint!("\u{001b}[0m\u{001b}[?25h");
+}
+
+/// Start the OS.
+fn os_start(api: &bios::Api) -> ! {
+    let ctx = os_init(api);
+
+    let mut buffer = [0u8; 256];
+    let mut menu = menu::Runner::new(&commands::OS_MENU, &mut buffer, ctx);
+    let mut aliases = commands::AliasExpander::new();
+
+    fault::run(&mut || os_poll(&mut menu, &mut aliases))
+}
+
+/// Get the shell going again after we caught a CPU fault.
+///
+/// Whatever faulted never finishes, so any locks it held are still held,
+/// and if it was a program, nothing tidied up after it.
+///
+/// # Safety
+///
+/// Only call this once the code that faulted has been abandoned.
+// Only bare-metal Arm builds can catch faults
+#[cfg_attr(
+    not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))),
+    allow(dead_code)
+)]
+unsafe fn recover_after_fault() {
+    force_unlock_all();
+    program::recover();
+    *PAGER.lock() = None;
+    STD_INPUT.lock().set_events(false);
+    reset_terminal();
+    if let Some(fault) = fault::last() {
+        osprintln!(
+            log: Error,
+            "\nCaught a {} at PC=0x{:08x}. Type `fault` for details.",
+            fault.name(),
+            fault.frame.pc
+        );
+    }
+}
+
+/// Unlock every lock in the OS, even ones that someone is holding.
+///
+/// Anything we might use to get the shell going again (even just printing
+/// a message) could need any of these, so we do the lot.
+///
+/// # Safety
+///
+/// Only call this once whoever held the locks has been abandoned, like code
+/// that faulted. The same goes for each module's `force_unlock`, which is
+/// only for calling from here.
+unsafe fn force_unlock_all() {
+    VGA_CONSOLE.force_unlock();
+    SERIAL_CONSOLE.force_unlock();
+    VGA_STAGING.force_unlock();
+    SERIAL_STAGING.force_unlock();
+    PAGER.force_unlock();
+    STD_INPUT.force_unlock();
+    STDIN_ROUTE.force_unlock();
+    STDOUT_ROUTE.force_unlock();
+    FILESYSTEM.force_unlock();
+    RAM_FILESYSTEM.force_unlock();
+    bus::force_unlock();
+    fault::force_unlock();
+    hidrec::force_unlock();
+    history::force_unlock();
+    joypad::force_unlock();
+    log::force_unlock();
+    module::force_unlock();
+    #[cfg(feature = "net")]
+    net::force_unlock();
+    power::force_unlock();
+    program::force_unlock();
+    ramdisk::force_unlock();
+    schedule::force_unlock();
+    screensaver::force_unlock();
+    sensors::force_unlock();
+    spool::force_unlock();
+    stats::force_unlock();
+    statusbar::force_unlock();
+    vconsole::force_unlock();
+    vsync::force_unlock();
+    watchdog::force_unlock();
+}
+
+/// Set up the consoles, the filesystem and everything else, ready to run the
+/// shell.
+fn os_init(api: &bios::Api) -> Ctx {
     unsafe {
         start_up_init();
         API.store(api);
@@ -400,7 +1413,45 @@ pub extern "C" fn os_main(api: &bios::Api) -> ! {
         panic!("API mismatch!");
     }
 
-    let config = config::Config::load().unwrap_or_default();
+    let (tpa_start, tpa_size) = match (api.memory_get_region)(0) {
+        bios::FfiOption::None => {
+            panic!("No TPA offered by BIOS!");
+        }
+        bios::FfiOption::Some(tpa) => {
+            if tpa.length < 256 {
+                panic!("TPA not large enough");
+            }
+            let offset = tpa.start.align_offset(4);
+            (
+                unsafe { tpa.start.add(offset) as *mut u32 },
+                tpa.length - offset,
+            )
+        }
+    };
+
+    // We have to trust the values given to us by the BIOS. If it lies, we will crash.
+    let mut tpa = unsafe { program::TransientProgramArea::new(tpa_start, tpa_size) };
+
+    // The vector table wants the most alignment, so it goes at the very top
+    fault::install(&mut tpa);
+
+    // Somewhere to keep console output that can't be printed straight away
+    if let Some(memory) = tpa.reserve("Staging", 2 * staging::RING_LEN) {
+        let (vga, serial) = memory.split_at_mut(staging::RING_LEN);
+        VGA_STAGING.attach(vga);
+        SERIAL_STAGING.attach(serial);
+    }
+
+    // And the system log, before anything is logged
+    if let Some(memory) = tpa.reserve("Log", log::LOG_SIZE) {
+        log::attach(memory);
+    }
+
+    // We have no console yet, so hold on to any error until we do
+    let (config, config_error) = match config::Config::load() {
+        Ok(config) => (config, None),
+        Err(e) => (config::Config::default(), Some(e)),
+    };
 
     if let Some(mut mode) = config.get_vga_console() {
         // Set the configured mode
@@ -419,12 +1470,13 @@ pub extern "C" fn os_main(api: &bios::Api) -> ! {
                 width as isize,
                 height as isize,
             );
-            vga.clear();
+            vga.set_status_line(config.get_status_line());
             let mut guard = VGA_CONSOLE.lock();
             *guard = Some(vga);
             // Drop the lock before trying to grab it again to print something!
             drop(guard);
-            osprintln!("\u{001b}[0mConfigured VGA console {}x{}", width, height);
+            vconsole::setup();
+            osprintln!(log: Info, "\u{001b}[0mConfigured VGA console {}x{}", width, height);
         }
     }
 
@@ -432,41 +1484,29 @@ pub extern "C" fn os_main(api: &bios::Api) -> ! {
         let _ignored = (api.serial_configure)(idx, serial_config);
         let mut guard = SERIAL_CONSOLE.lock();
         *guard = Some(SerialConsole(idx));
+        rawconsole::set_serial_port(Some(idx));
         // Drop the lock before trying to grab it again to print something!
         drop(guard);
-        osprintln!("Configured Serial console on Serial {}", idx);
+        osprintln!(log: Info, "Configured Serial console on Serial {}", idx);
     }
 
     // Now we can call osprintln!
-    osprintln!("\u{001b}[44;33;1m{}\u{001b}[0m", OS_VERSION);
-    osprintln!("\u{001b}[41;37;1mCopyright © Jonathan 'theJPster' Pallant and the Neotron Developers, 2022\u{001b}[0m");
+    osprintln!(log: Info, "\u{001b}[44;33;1m{}\u{001b}[0m", OS_VERSION);
+    osprintln!(log: Info, "\u{001b}[41;37;1mCopyright © Jonathan 'theJPster' Pallant and the Neotron Developers, 2022\u{001b}[0m");
 
-    let (tpa_start, tpa_size) = match (api.memory_get_region)(0) {
-        bios::FfiOption::None => {
-            panic!("No TPA offered by BIOS!");
-        }
-        bios::FfiOption::Some(tpa) => {
-            if tpa.length < 256 {
-                panic!("TPA not large enough");
-            }
-            let offset = tpa.start.align_offset(4);
-            (
-                unsafe { tpa.start.add(offset) as *mut u32 },
-                tpa.length - offset,
-            )
-        }
-    };
+    if let Some(e) = config_error {
+        osprintln!(log: Warn, "{}, using defaults", e);
+    }
 
     let mut ctx = Ctx {
         config,
-        tpa: unsafe {
-            // We have to trust the values given to us by the BIOS. If it lies, we will crash.
-            program::TransientProgramArea::new(tpa_start, tpa_size)
-        },
-        exec_tpa: None,
+        tpa,
+        script: None,
+        timing: false,
     };
 
     osprintln!(
+        log: Info,
         "\u{001b}[7mTPA: {} bytes @ {:p}\u{001b}[0m",
         ctx.tpa.as_slice_u8().len(),
         ctx.tpa.as_slice_u8().as_ptr()
@@ -475,46 +1515,158 @@ pub extern "C" fn os_main(api: &bios::Api) -> ! {
     // Show the cursor
     osprint!("\u{001b}[?25h");
 
-    let mut buffer = [0u8; 256];
-    let mut menu = menu::Runner::new(&commands::OS_MENU, &mut buffer, ctx);
+    // Pick the keyboard layout, make the keyboard LEDs match our lock state,
+    // and set up key repeat
+    {
+        let mut std_input = STD_INPUT.lock();
+        std_input.set_keymap(ctx.config.get_keymap());
+        std_input.update_leds();
+        std_input.set_key_repeat(ctx.config.get_key_repeat());
+    }
 
-    loop {
-        let mut buffer = [0u8; 16];
-        let count = { STD_INPUT.lock().get_data(&mut buffer) };
-        for b in &buffer[0..count] {
-            menu.input_byte(*b);
-        }
-        // TODO: Consider recursively executing scripts, so that scripts can
-        // call scripts.
-        if let Some(n) = menu.context.exec_tpa {
-            menu.context.exec_tpa = None;
-            let ptr = menu.context.tpa.steal_top(n);
-            osprintln!("\rExecuting TPA...");
-            let mut has_chars = false;
-            let slice = unsafe { core::slice::from_raw_parts(ptr, n) };
-            // TODO: Give the user some way to break out of the loop.
-            for b in slice {
-                // Files contain `\n` or `\r\n` line endings.
-                // menu wants `\r` line endings.
-                if *b == b'\n' {
-                    if has_chars {
-                        // Execute this line
-                        menu.input_byte(b'\r');
-                        has_chars = false;
+    restore_mixer_levels(&ctx.config);
+
+    watchdog::configure(ctx.config.get_watchdog());
+    power::configure_blank(ctx.config.get_screen_blank());
+    power::configure_saver(ctx.config.get_screen_saver());
+    program::configure_check(ctx.config.get_program_check());
+    program::configure_scrub(ctx.config.get_scrub_tpa());
+    spool::configure(ctx.config.get_printer());
+    sensors::configure(ctx.config.get_sensor_limits());
+    statusbar::configure_modifiers(ctx.config.get_show_modifiers());
+    API.set_tz_offset(ctx.config.get_tz_offset());
+    schedule::start(&mut ctx.config);
+
+    // Tidy up any temporary files left over from last time
+    if let Err(e) = FILESYSTEM.clear_temp_dir() {
+        osprintln!(log: Debug, "Can't clear temporary files: {:?}", e);
+    }
+
+    if ctx.config.get_save_history() {
+        match history::load() {
+            Ok(count) => {
+                osprintln!(log: Debug, "Loaded {} commands from history", count);
+            }
+            Err(e) => {
+                osprintln!(log: Debug, "Can't load history: {:?}", e);
+            }
+        }
+    }
+
+    // The RAM disk goes next, before any modules
+    let ramdisk_kib = ctx.config.get_ramdisk_kib();
+    if ramdisk_kib != 0 {
+        match ramdisk::create(&mut ctx.tpa, ramdisk_kib) {
+            Ok(()) => {
+                osprintln!(log: Info, "RAM disk: {} KiB", ramdisk_kib);
+            }
+            Err(e) => {
+                osprintln!(log: Warn, "Can't make RAM disk: {}", e);
+            }
+        }
+    }
+
+    module::load_all(&mut ctx.tpa);
+
+    // Modules have taken what they need, so the TPA won't change size now
+    let tpa_range = ctx.tpa.as_slice_u8().as_ptr_range();
+    if mpu::configure(tpa_range.start, tpa_range.end) {
+        osprintln!(log: Info, "MPU protecting OS flash and TPA");
+    }
+
+    // Route the standard streams. If the configured consoles don't exist, we
+    // stay on all of them.
+    if let Err(e) = Console::set_stdin_route(ctx.config.get_stdin_route()) {
+        osprintln!(log: Warn, "Can't route stdin: {}", e);
+    }
+    if let Err(e) = Console::set_stdout_route(ctx.config.get_stdout_route()) {
+        osprintln!(log: Warn, "Can't route stdout: {}", e);
+    }
+
+    ctx
+}
+
+/// Set the mixer channels to the levels saved in the config.
+///
+/// A BIOS with no audio output has no mixer either, so we don't look.
+fn restore_mixer_levels(config: &config::Config) {
+    let levels = config.get_mixer_levels();
+    let api = API.get();
+    if levels.is_empty() || matches!((api.audio_output_get_config)(), bios::ApiResult::Err(_)) {
+        return;
+    }
+    for mixer_id in 0u8..=255u8 {
+        let bios::FfiOption::Some(mixer_info) = (api.audio_mixer_channel_get_info)(mixer_id) else {
+            // Run out of mixers
+            break;
+        };
+        let Some(saved) = levels.iter().find(|m| m.name == mixer_info.name.as_str()) else {
+            continue;
+        };
+        let level = saved.level.min(mixer_info.max_level);
+        if let bios::ApiResult::Err(e) = (api.audio_mixer_channel_set_level)(mixer_id, level) {
+            osprintln!(log: Warn, "Can't set mixer {}: {:?}", mixer_info.name, e);
+        }
+    }
+}
+
+/// Do one pass of the main loop: feed the shell any input, run the next
+/// line of any script, and let everything else poll.
+fn os_poll(menu: &mut menu::Runner<Ctx>, aliases: &mut commands::AliasExpander) {
+    let mut buffer = [0u8; 16];
+    let mut count = { STD_INPUT.lock().get_data(&mut buffer) };
+    if count > 0 && power::wake(&mut menu.context.tpa) {
+        // That key was just to wake the screen up
+        count = 0;
+    }
+    for b in &buffer[0..count] {
+        aliases.input_byte(menu, *b);
+    }
+    if let Some(command) = schedule::poll(&mut menu.context.config) {
+        osprintln!(log: Info, "Running scheduled command {:?}", command.as_str());
+        for b in command.bytes() {
+            aliases.input_byte(menu, b);
+        }
+        aliases.input_byte(menu, b'\r');
+    }
+    // TODO: Consider recursively executing scripts, so that scripts can
+    // call scripts. For now, running a script from a script replaces it.
+    if let Some(script) = menu.context.script.as_mut() {
+        // TODO: Give the user some way to break out of the script.
+        let mut line_buffer = [0u8; Script::MAX_LINE_LEN];
+        match script.next_line(&mut line_buffer) {
+            Ok(Some(line)) => {
+                // A running script counts as someone using the screen
+                power::reset_blank_timer();
+                // Skip blank lines. The menu wants `\r` line endings.
+                if !line.is_empty() {
+                    for b in line.bytes() {
+                        aliases.input_byte(menu, b);
                     }
-                } else if *b == b'\r' {
-                    // Drop carriage returns
-                } else {
-                    menu.input_byte(*b);
-                    has_chars = true;
+                    aliases.input_byte(menu, b'\r');
                 }
             }
-            unsafe {
-                menu.context.tpa.restore_top(n);
+            Ok(None) => {
+                menu.context.script = None;
+            }
+            Err(e) => {
+                osprintln!("{}", e);
+                menu.context.script = None;
             }
         }
-        (api.power_idle)();
     }
+    vconsole::poll(&mut menu.context.tpa);
+    statusbar::refresh(false);
+    power::poll(&mut menu.context.tpa);
+    bus::poll();
+    FILESYSTEM.poll_media();
+    spool::poll();
+    sensors::poll();
+    #[cfg(feature = "net")]
+    net::poll();
+    Console::flush_staged();
+    watchdog::kick();
+    stats::idle();
 }
 
 /// Called when we have a panic.
@@ -522,14 +1674,96 @@ pub extern "C" fn os_main(api: &bios::Api) -> ! {
 #[panic_handler]
 #[cfg(not(any(feature = "lib-mode", test)))]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    IS_PANIC.store(true, Ordering::Relaxed);
-    osprintln!("PANIC!\n{:#?}", info);
+    // If we panic whilst handling a panic, skip straight to the end
+    if !IS_PANIC.swap(true, Ordering::Relaxed) {
+        osprintln!(log: Error, "PANIC!\n{:#?}", info);
+        panic_print_registers();
+        panic_save_file(info);
+    }
+    osprintln!("Press R to reboot.");
     let api = API.get();
     loop {
+        let reboot = matches!(
+            (api.hid_get_event)(),
+            bios::ApiResult::Ok(bios::FfiOption::Some(bios::hid::HidEvent::KeyPress(
+                pc_keyboard::KeyCode::R
+            )))
+        );
+        // The serial console might be all the user has
+        let mut buffer = [0u8];
+        let from_serial = SERIAL_CONSOLE.try_lock().is_ok_and(|mut guard| {
+            guard.as_mut().is_some_and(|console| {
+                matches!(console.read_data(&mut buffer), Ok(1))
+                    && buffer[0].eq_ignore_ascii_case(&b'r')
+            })
+        });
+        if reboot || from_serial {
+            osprintln!("Rebooting...");
+            (api.power_control)(bios::PowerMode::Reset.make_ffi_safe());
+        }
         (api.power_idle)();
     }
 }
 
+/// Print the registers, and anything on the stack that looks like a return
+/// address, to help find where we panicked.
+#[cfg(all(target_arch = "arm", not(any(feature = "lib-mode", test))))]
+fn panic_print_registers() {
+    let pc: u32;
+    let lr: u32;
+    let sp: u32;
+    unsafe {
+        core::arch::asm!(
+            "mov {pc}, pc",
+            "mov {lr}, lr",
+            "mov {sp}, sp",
+            pc = out(reg) pc,
+            lr = out(reg) lr,
+            sp = out(reg) sp,
+        );
+    }
+    osprintln!(log: Error, "PC=0x{:08x} LR=0x{:08x} SP=0x{:08x}", pc, lr, sp);
+    osprint!("Possible return addresses:");
+    let stack = sp as *const u32;
+    for idx in 0..32 {
+        let word = unsafe { stack.add(idx).read_volatile() };
+        // Thumb code addresses are odd
+        if word & 1 == 1 && word < 0x2000_0000 {
+            osprint!(" 0x{:08x}", word);
+        }
+    }
+    osprintln!();
+}
+
+/// Print the registers, if we know how to on this CPU.
+#[cfg(all(not(target_arch = "arm"), not(any(feature = "lib-mode", test))))]
+fn panic_print_registers() {
+    osprintln!("Registers not available on this CPU");
+}
+
+/// Write the panic message to `PANIC.TXT`, if we can.
+#[cfg(not(any(feature = "lib-mode", test)))]
+fn panic_save_file(info: &core::panic::PanicInfo) {
+    if FILESYSTEM.is_busy() {
+        // We probably panicked inside the filesystem code
+        osprintln!("Filesystem busy - can't write PANIC.TXT");
+        return;
+    }
+    match FILESYSTEM.open_file("PANIC.TXT", embedded_sdmmc::Mode::ReadWriteCreateOrTruncate) {
+        Ok(mut file) => {
+            use core::fmt::Write as _;
+            if writeln!(file, "{}\n{:#?}", OS_VERSION, info).is_ok() {
+                osprintln!("Wrote PANIC.TXT");
+            } else {
+                osprintln!("Failed to write PANIC.TXT");
+            }
+        }
+        Err(e) => {
+            osprintln!("Can't open PANIC.TXT: {:?}", e);
+        }
+    }
+}
+
 // ===========================================================================
 // End of file
 // ===========================================================================
@@ -0,0 +1,286 @@
+//! # System Log
+//!
+//! Keeps the most recent OS messages in a ring buffer, so you can read them
+//! with `dmesg` after they have scrolled off the screen. The buffer is
+//! reserved off the top of the TPA when the OS starts, as OS RAM is too small
+//! to hold more than a couple of messages.
+//!
+//! Use `osprintln!(log: Info, "...")` to print a message and also log it.
+//! Messages less important than the console log level are only logged, and
+//! not printed.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{osprint, refcell::CsRefCell};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many bytes of log we keep - enough for everything printed at boot,
+/// and a good few messages after that
+pub const LOG_SIZE: usize = 2048;
+
+/// Our log messages
+static LOG: CsRefCell<LogBuffer> = CsRefCell::new(LogBuffer::new(None));
+
+/// The least important level of message that is printed on the console
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// How important a log message is
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// Something has gone wrong
+    Error = 0,
+    /// Something might go wrong
+    Warn = 1,
+    /// Normal operation
+    Info = 2,
+    /// Only interesting when tracking down problems
+    Debug = 3,
+}
+
+impl Level {
+    /// Convert from the number we store
+    fn from_u8(value: u8) -> Option<Level> {
+        match value {
+            0 => Some(Level::Error),
+            1 => Some(Level::Warn),
+            2 => Some(Level::Info),
+            3 => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    /// Parse a level like `warn`
+    pub fn parse(input: &str) -> Option<Level> {
+        match input {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    /// The name we show for this level
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// A ring buffer of log lines.
+///
+/// Each line is a level byte, the message, and then a `\n`. When the buffer
+/// wraps, the oldest line is probably only partially there.
+struct LogBuffer {
+    /// Where we keep the log, if we have anywhere yet
+    data: Option<&'static mut [u8]>,
+    /// Where the next byte goes
+    head: usize,
+    /// Have we written past the end of the buffer at least once?
+    wrapped: bool,
+    /// Is the oldest byte part way through a line?
+    partial: bool,
+}
+
+impl LogBuffer {
+    /// Make an empty log, kept in `data`
+    const fn new(data: Option<&'static mut [u8]>) -> LogBuffer {
+        LogBuffer {
+            data,
+            head: 0,
+            wrapped: false,
+            partial: false,
+        }
+    }
+
+    /// Add a byte, overwriting the oldest byte if we are full
+    fn push_byte(&mut self, b: u8) {
+        let Some(data) = self.data.as_deref_mut() else {
+            return;
+        };
+        // The byte after this one becomes the oldest, and it only starts a
+        // line if the byte we overwrite ended one
+        self.partial = self.wrapped && data[self.head] != b'\n';
+        data[self.head] = b;
+        self.head += 1;
+        if self.head == data.len() {
+            self.head = 0;
+            self.wrapped = true;
+        }
+    }
+
+    /// Add a line to the log
+    fn push_line(&mut self, level: Level, args: core::fmt::Arguments) {
+        use core::fmt::Write as _;
+        self.push_byte(level as u8);
+        let _ = self.write_fmt(args);
+        self.push_byte(b'\n');
+    }
+
+    /// Get the complete lines in the log, oldest first.
+    ///
+    /// Rotates the buffer so the oldest byte is at the start, which doesn't
+    /// change what we have logged.
+    fn lines(&mut self) -> impl Iterator<Item = &[u8]> {
+        let contents: &[u8] = match self.data.as_deref_mut() {
+            None => &[],
+            Some(data) if self.wrapped => {
+                data.rotate_left(self.head);
+                self.head = 0;
+                if self.partial {
+                    // Skip the line the head partially overwrote
+                    match data.iter().position(|b| *b == b'\n') {
+                        Some(idx) => &data[idx + 1..],
+                        None => &[],
+                    }
+                } else {
+                    data
+                }
+            }
+            Some(data) => &data[0..self.head],
+        };
+        contents
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+    }
+}
+
+impl core::fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            // We use new-lines to mark the end of a log entry
+            self.push_byte(if b == b'\n' { b' ' } else { b });
+        }
+        Ok(())
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Log a message, and print it on the console if it is important enough.
+pub fn log(level: Level, args: core::fmt::Arguments) {
+    // Don't wait for the lock, in case we are panicking whilst holding it
+    if let Ok(mut buffer) = LOG.try_lock() {
+        buffer.push_line(level, args);
+    }
+    if level <= console_level() {
+        osprint!("{}\n", args);
+    }
+}
+
+/// Get the least important level of message that is printed on the console
+pub fn console_level() -> Level {
+    Level::from_u8(CONSOLE_LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+}
+
+/// Set the least important level of message that is printed on the console
+pub fn set_console_level(level: Level) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Print every log message we have, optionally only those at least as
+/// important as `level`.
+pub fn print(level: Option<Level>) {
+    let mut buffer = LOG.lock();
+    for line in buffer.lines() {
+        let Some(line_level) = Level::from_u8(line[0]) else {
+            continue;
+        };
+        if level.is_some_and(|level| line_level > level) {
+            continue;
+        }
+        let text = core::str::from_utf8(&line[1..]).unwrap_or("<invalid>");
+        osprint!("[{:5}] {}\n", line_level.as_str(), text);
+    }
+}
+
+/// Throw away all the log messages
+pub fn clear() {
+    let mut buffer = LOG.lock();
+    buffer.head = 0;
+    buffer.wrapped = false;
+    buffer.partial = false;
+}
+
+/// Keep the log in `memory` from now on, throwing away anything we had.
+pub fn attach(memory: &'static mut [u8]) {
+    *LOG.lock() = LogBuffer::new(Some(memory));
+}
+
+/// Unlock the system log, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    LOG.force_unlock();
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the text of every line in the log
+    fn texts(buffer: &mut LogBuffer) -> Vec<String> {
+        buffer
+            .lines()
+            .map(|line| String::from_utf8_lossy(&line[1..]).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_lines() {
+        let mut buffer = LogBuffer::new(Some(vec![0u8; 16].leak()));
+        buffer.push_line(Level::Info, format_args!("one"));
+        buffer.push_line(Level::Warn, format_args!("two"));
+        assert_eq!(texts(&mut buffer), ["one", "two"]);
+    }
+
+    #[test]
+    fn wraps_on_a_line_boundary() {
+        // Three lines of 5 bytes fill the buffer exactly, so after the fourth
+        // the oldest byte is the start of the second line
+        let mut buffer = LogBuffer::new(Some(vec![0u8; 15].leak()));
+        for text in ["aaa", "bbb", "ccc", "ddd"] {
+            buffer.push_line(Level::Info, format_args!("{}", text));
+        }
+        assert_eq!(texts(&mut buffer), ["bbb", "ccc", "ddd"]);
+    }
+
+    #[test]
+    fn wraps_part_way_through_a_line() {
+        let mut buffer = LogBuffer::new(Some(vec![0u8; 16].leak()));
+        for text in ["aaa", "bbb", "ccc", "ddd"] {
+            buffer.push_line(Level::Info, format_args!("{}", text));
+        }
+        // Only the end of "aaa" is left, so we skip it
+        assert_eq!(texts(&mut buffer), ["bbb", "ccc", "ddd"]);
+        // Looking doesn't change what's there
+        buffer.push_line(Level::Info, format_args!("e"));
+        assert_eq!(texts(&mut buffer), ["ccc", "ddd", "e"]);
+    }
+}
+
+// End of file
@@ -0,0 +1,371 @@
+//! # LZ4 Decompression
+//!
+//! Unpacks LZ4 frames (the format the `lz4` command-line tool writes), so
+//! that programs can be stored compressed on disk or in ROM.
+//!
+//! LZ4 blocks can refer back to data in earlier blocks, so we need the whole
+//! output in one buffer. The input can come from anywhere, a few bytes at a
+//! time.
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The magic number at the start of every LZ4 frame
+pub const MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+
+/// How much compressed data we read at a time
+const READ_BUFFER_LEN: usize = 128;
+
+/// The frame flags byte has the version number in the top two bits
+const FLAG_VERSION_MASK: u8 = 0b1100_0000;
+
+/// The only frame version there is
+const FLAG_VERSION_1: u8 = 0b0100_0000;
+
+/// Each block is followed by a checksum
+const FLAG_BLOCK_CHECKSUM: u8 = 1 << 4;
+
+/// The frame header includes the uncompressed size
+const FLAG_CONTENT_SIZE: u8 = 1 << 3;
+
+/// The frame header includes a dictionary ID
+const FLAG_DICT_ID: u8 = 1 << 0;
+
+/// A block size with this bit set means the block is stored uncompressed
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+/// The shortest match LZ4 encodes
+const MIN_MATCH: usize = 4;
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Ways in which decompression can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    /// We couldn't read the compressed data
+    Read(E),
+    /// The compressed data stopped before the end of the frame
+    Truncated,
+    /// The data doesn't start with an LZ4 frame header
+    BadMagic,
+    /// The frame uses a feature we don't support (like a dictionary)
+    Unsupported,
+    /// The compressed data doesn't make sense
+    Corrupt,
+    /// The output doesn't fit in the buffer
+    TooLarge,
+}
+
+impl<E> core::fmt::Display for Error<E>
+where
+    E: core::fmt::Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Read(e) => write!(f, "Couldn't read the compressed data: {:?}", e),
+            Error::Truncated => write!(f, "The compressed data is cut short"),
+            Error::BadMagic => write!(f, "Not LZ4 compressed"),
+            Error::Unsupported => write!(f, "Uses an LZ4 feature we don't support"),
+            Error::Corrupt => write!(f, "The compressed data is corrupt"),
+            Error::TooLarge => write!(f, "Too large once decompressed"),
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Hands out compressed bytes, reading more when it runs out
+struct Reader<R> {
+    read: R,
+    buffer: [u8; READ_BUFFER_LEN],
+    pos: usize,
+    len: usize,
+}
+
+impl<R, E> Reader<R>
+where
+    R: FnMut(&mut [u8]) -> Result<usize, E>,
+{
+    /// Wrap a function that reads compressed data
+    fn new(read: R) -> Reader<R> {
+        Reader {
+            read,
+            buffer: [0u8; READ_BUFFER_LEN],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Make sure there's at least one byte in the buffer
+    fn fill(&mut self) -> Result<(), Error<E>> {
+        if self.pos == self.len {
+            self.len = (self.read)(&mut self.buffer).map_err(Error::Read)?;
+            self.pos = 0;
+            if self.len == 0 {
+                return Err(Error::Truncated);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the next byte
+    fn byte(&mut self) -> Result<u8, Error<E>> {
+        self.fill()?;
+        let b = self.buffer[self.pos];
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// Get the next byte in a block, which has `remaining` bytes left in it
+    fn block_byte(&mut self, remaining: &mut usize) -> Result<u8, Error<E>> {
+        *remaining = remaining.checked_sub(1).ok_or(Error::Corrupt)?;
+        self.byte()
+    }
+
+    /// Get the next four bytes, as a little-endian number
+    fn u32_le(&mut self) -> Result<u32, Error<E>> {
+        let mut bytes = [0u8; 4];
+        for b in bytes.iter_mut() {
+            *b = self.byte()?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Throw away the next `count` bytes
+    fn skip(&mut self, count: usize) -> Result<(), Error<E>> {
+        for _ in 0..count {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    /// Fill `out` with the next bytes
+    fn copy(&mut self, out: &mut [u8]) -> Result<(), Error<E>> {
+        let mut done = 0;
+        while done < out.len() {
+            self.fill()?;
+            let count = (self.len - self.pos).min(out.len() - done);
+            out[done..done + count].copy_from_slice(&self.buffer[self.pos..self.pos + count]);
+            self.pos += count;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Read the rest of a length that didn't fit in four bits
+    fn extra_length(&mut self, remaining: &mut usize) -> Result<usize, Error<E>> {
+        let mut length = 0usize;
+        loop {
+            let b = self.block_byte(remaining)?;
+            length = length.checked_add(usize::from(b)).ok_or(Error::Corrupt)?;
+            if b != 255 {
+                return Ok(length);
+            }
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Does this look like the start of an LZ4 frame?
+pub fn is_lz4(header: &[u8]) -> bool {
+    header.starts_with(&MAGIC)
+}
+
+/// Decompress one LZ4 frame into `output`.
+///
+/// `read` is called to get more compressed data. It should fill as much of
+/// the buffer as it can, and return how many bytes it put there (0 at the
+/// end of the data).
+///
+/// Returns how many bytes we put into `output`. We skip the checksums,
+/// rather than checking them.
+pub fn decompress<R, E>(read: R, output: &mut [u8]) -> Result<usize, Error<E>>
+where
+    R: FnMut(&mut [u8]) -> Result<usize, E>,
+{
+    let mut input = Reader::new(read);
+    if input.u32_le()? != u32::from_le_bytes(MAGIC) {
+        return Err(Error::BadMagic);
+    }
+    let flags = input.byte()?;
+    // The block descriptor only gives the largest block size, and we don't
+    // care about that
+    let _block_descriptor = input.byte()?;
+    if flags & FLAG_VERSION_MASK != FLAG_VERSION_1 || flags & FLAG_DICT_ID != 0 {
+        return Err(Error::Unsupported);
+    }
+    if flags & FLAG_CONTENT_SIZE != 0 {
+        input.skip(8)?;
+    }
+    let _header_checksum = input.byte()?;
+
+    let mut out_pos = 0;
+    loop {
+        let block_size = input.u32_le()?;
+        if block_size == 0 {
+            // End mark. There may be a content checksum after it, but that's
+            // the end of the data we need.
+            break;
+        }
+        let len = (block_size & !BLOCK_UNCOMPRESSED) as usize;
+        if block_size & BLOCK_UNCOMPRESSED != 0 {
+            let out = output
+                .get_mut(out_pos..out_pos + len)
+                .ok_or(Error::TooLarge)?;
+            input.copy(out)?;
+            out_pos += len;
+        } else {
+            out_pos = decompress_block(&mut input, len, output, out_pos)?;
+        }
+        if flags & FLAG_BLOCK_CHECKSUM != 0 {
+            input.skip(4)?;
+        }
+    }
+
+    Ok(out_pos)
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Decompress a block of `len` compressed bytes into `output`, starting at
+/// `out_pos`.
+///
+/// Returns where the block ended in `output`.
+fn decompress_block<R, E>(
+    input: &mut Reader<R>,
+    len: usize,
+    output: &mut [u8],
+    mut out_pos: usize,
+) -> Result<usize, Error<E>>
+where
+    R: FnMut(&mut [u8]) -> Result<usize, E>,
+{
+    let mut remaining = len;
+    loop {
+        let token = input.block_byte(&mut remaining)?;
+
+        // Some bytes to copy as-is
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += input.extra_length(&mut remaining)?;
+        }
+        remaining = remaining.checked_sub(literals).ok_or(Error::Corrupt)?;
+        let out = output
+            .get_mut(out_pos..out_pos.saturating_add(literals))
+            .ok_or(Error::TooLarge)?;
+        input.copy(out)?;
+        out_pos += literals;
+
+        if remaining == 0 {
+            // The last sequence in a block has no match
+            return Ok(out_pos);
+        }
+
+        // Then a copy of something we've already written
+        let offset = usize::from(u16::from_le_bytes([
+            input.block_byte(&mut remaining)?,
+            input.block_byte(&mut remaining)?,
+        ]));
+        if offset == 0 || offset > out_pos {
+            return Err(Error::Corrupt);
+        }
+        let mut match_len = usize::from(token & 0x0F);
+        if match_len == 15 {
+            match_len += input.extra_length(&mut remaining)?;
+        }
+        match_len += MIN_MATCH;
+        let match_end = out_pos.saturating_add(match_len);
+        if match_end > output.len() {
+            return Err(Error::TooLarge);
+        }
+        // The match can overlap the bytes it is writing, so go a byte at a
+        // time
+        for idx in out_pos..match_end {
+            output[idx] = output[idx - offset];
+        }
+        out_pos = match_end;
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"Hello, Neotron! Hello, Neotron! Hello, Neotron! Hello, Neotron!\n";
+
+    /// `lz4 --no-frame-crc`
+    const PLAIN_FRAME: [u8; 42] = [
+        0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82, 0x1b, 0x00, 0x00, 0x00, 0xff, 0x01, 0x48, 0x65,
+        0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x4e, 0x65, 0x6f, 0x74, 0x72, 0x6f, 0x6e, 0x21, 0x20, 0x10,
+        0x00, 0x18, 0x50, 0x72, 0x6f, 0x6e, 0x21, 0x0a, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// `lz4 -BD -BX --content-size`
+    const CHECKSUMMED_FRAME: [u8; 58] = [
+        0x04, 0x22, 0x4d, 0x18, 0x7c, 0x40, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe,
+        0x1b, 0x00, 0x00, 0x00, 0xff, 0x01, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x4e, 0x65,
+        0x6f, 0x74, 0x72, 0x6f, 0x6e, 0x21, 0x20, 0x10, 0x00, 0x18, 0x50, 0x72, 0x6f, 0x6e, 0x21,
+        0x0a, 0x5e, 0x17, 0xc1, 0xdc, 0x00, 0x00, 0x00, 0x00, 0x49, 0x3e, 0x17, 0xaf,
+    ];
+
+    /// Decompress `frame`, handing it over `chunk` bytes at a time
+    fn unpack(frame: &[u8], chunk: usize, output: &mut [u8]) -> Result<usize, Error<()>> {
+        let mut input = frame;
+        decompress(
+            |buffer: &mut [u8]| {
+                let count = input.len().min(buffer.len()).min(chunk);
+                buffer[0..count].copy_from_slice(&input[0..count]);
+                input = &input[count..];
+                Ok(count)
+            },
+            output,
+        )
+    }
+
+    #[test]
+    fn plain_frame() {
+        assert!(is_lz4(&PLAIN_FRAME));
+        let mut output = [0u8; 128];
+        let len = unpack(&PLAIN_FRAME, 128, &mut output).unwrap();
+        assert_eq!(&output[0..len], TEXT);
+        // A few bytes at a time works too
+        let mut output = [0u8; 128];
+        let len = unpack(&PLAIN_FRAME, 5, &mut output).unwrap();
+        assert_eq!(&output[0..len], TEXT);
+    }
+
+    #[test]
+    fn checksummed_frame() {
+        let mut output = [0u8; 128];
+        let len = unpack(&CHECKSUMMED_FRAME, 7, &mut output).unwrap();
+        assert_eq!(&output[0..len], TEXT);
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut output = [0u8; 128];
+        assert_eq!(
+            unpack(&PLAIN_FRAME[0..20], 128, &mut output),
+            Err(Error::Truncated)
+        );
+        assert_eq!(unpack(TEXT, 128, &mut output), Err(Error::BadMagic));
+        let mut output = [0u8; 32];
+        assert_eq!(unpack(&PLAIN_FRAME, 128, &mut output), Err(Error::TooLarge));
+    }
+}
+
+// End of file
@@ -0,0 +1,474 @@
+//! # MIDI
+//!
+//! Finds the UART the BIOS says is wired to a MIDI socket, sets it up at the
+//! MIDI data rate, and turns the bytes that arrive into messages.
+//!
+//! Applications get at the port through the `MIDI:` device, and you can
+//! watch what arrives with the `midimon` command.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// MIDI always runs at 31,250 bits per second
+pub const BAUD_RATE: u32 = 31_250;
+
+/// The names of the notes in an octave, starting at C
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways opening the MIDI port can fail
+#[derive(Debug)]
+pub enum Error {
+    /// The BIOS doesn't have a MIDI-type UART
+    NoPort,
+    /// The BIOS wouldn't set the UART up
+    Bios(bios::Error),
+}
+
+/// A MIDI message, decoded from the bytes on the wire
+///
+/// Channels are numbered from 0 to 15, as they are on the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A key was released (or pressed with a velocity of zero)
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    /// A key was pressed
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    /// The pressure on a held key changed
+    KeyPressure { channel: u8, note: u8, pressure: u8 },
+    /// A knob, slider or pedal moved
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// A different sound was selected
+    ProgramChange { channel: u8, program: u8 },
+    /// The pressure on all the held keys changed
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// The pitch wheel moved, from -8192 to 8191 (0 is the middle)
+    PitchBend { channel: u8, value: i16 },
+    /// A System Exclusive message finished, with this many data bytes
+    SysEx { len: usize },
+    /// A MIDI Time Code quarter frame
+    TimeCode(u8),
+    /// Where the song is, in sixteenth notes from the start
+    SongPosition(u16),
+    /// A song was selected
+    SongSelect(u8),
+    /// Analogue synths should tune themselves
+    TuneRequest,
+    /// One of 24 ticks per quarter note
+    Clock,
+    /// Start the song from the beginning
+    Start,
+    /// Carry on from where the song stopped
+    Continue,
+    /// Stop the song
+    Stop,
+    /// The sender is still connected
+    ActiveSensing,
+    /// Put everything back to how it was at power on
+    Reset,
+}
+
+impl Message {
+    /// Is this one of the single-byte real-time messages?
+    ///
+    /// These can arrive many times a second, so you might not want to see
+    /// them.
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            Message::Clock
+                | Message::Start
+                | Message::Continue
+                | Message::Stop
+                | Message::ActiveSensing
+                | Message::Reset
+        )
+    }
+}
+
+impl core::fmt::Display for Message {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => write!(
+                f,
+                "Ch{:<2} Note Off   {} vel {}",
+                channel + 1,
+                Note(*note),
+                velocity
+            ),
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => write!(
+                f,
+                "Ch{:<2} Note On    {} vel {}",
+                channel + 1,
+                Note(*note),
+                velocity
+            ),
+            Message::KeyPressure {
+                channel,
+                note,
+                pressure,
+            } => write!(
+                f,
+                "Ch{:<2} Pressure   {} {}",
+                channel + 1,
+                Note(*note),
+                pressure
+            ),
+            Message::ControlChange {
+                channel,
+                controller,
+                value,
+            } => write!(
+                f,
+                "Ch{:<2} Control    {} = {}",
+                channel + 1,
+                controller,
+                value
+            ),
+            Message::ProgramChange { channel, program } => {
+                write!(f, "Ch{:<2} Program    {}", channel + 1, program)
+            }
+            Message::ChannelPressure { channel, pressure } => {
+                write!(f, "Ch{:<2} Pressure   {}", channel + 1, pressure)
+            }
+            Message::PitchBend { channel, value } => {
+                write!(f, "Ch{:<2} Pitch Bend {}", channel + 1, value)
+            }
+            Message::SysEx { len } => write!(f, "SysEx ({} bytes)", len),
+            Message::TimeCode(value) => write!(f, "Time Code 0x{:02x}", value),
+            Message::SongPosition(position) => write!(f, "Song Position {}", position),
+            Message::SongSelect(song) => write!(f, "Song Select {}", song),
+            Message::TuneRequest => write!(f, "Tune Request"),
+            Message::Clock => write!(f, "Clock"),
+            Message::Start => write!(f, "Start"),
+            Message::Continue => write!(f, "Continue"),
+            Message::Stop => write!(f, "Stop"),
+            Message::ActiveSensing => write!(f, "Active Sensing"),
+            Message::Reset => write!(f, "Reset"),
+        }
+    }
+}
+
+/// Turns a stream of MIDI bytes into messages
+///
+/// Handles running status (where the status byte is only sent when it
+/// changes), and real-time bytes arriving in the middle of other messages.
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    /// The status byte that the next data bytes belong to
+    status: Option<u8>,
+    /// The data bytes we have so far
+    data: [u8; 2],
+    /// How many data bytes we have so far
+    count: usize,
+    /// How many bytes of System Exclusive we have, if we're in one
+    sysex: Option<usize>,
+}
+
+impl Parser {
+    /// Make a new parser, which is waiting for a status byte
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Give the parser a byte, and maybe get a whole message back
+    pub fn feed(&mut self, byte: u8) -> Option<Message> {
+        if byte >= 0xF8 {
+            // Real-time bytes don't disturb anything else
+            return match byte {
+                0xF8 => Some(Message::Clock),
+                0xFA => Some(Message::Start),
+                0xFB => Some(Message::Continue),
+                0xFC => Some(Message::Stop),
+                0xFE => Some(Message::ActiveSensing),
+                0xFF => Some(Message::Reset),
+                _ => None,
+            };
+        }
+
+        if byte >= 0x80 {
+            self.count = 0;
+            let sysex = self.sysex.take();
+            return match byte {
+                0xF7 => {
+                    self.status = None;
+                    sysex.map(|len| Message::SysEx { len })
+                }
+                0xF0 => {
+                    self.status = None;
+                    self.sysex = Some(0);
+                    None
+                }
+                0xF6 => {
+                    self.status = None;
+                    Some(Message::TuneRequest)
+                }
+                0xF4 | 0xF5 => {
+                    self.status = None;
+                    None
+                }
+                _ => {
+                    self.status = Some(byte);
+                    None
+                }
+            };
+        }
+
+        if let Some(len) = self.sysex.as_mut() {
+            *len += 1;
+            return None;
+        }
+
+        let status = self.status?;
+        self.data[self.count] = byte;
+        self.count += 1;
+        if self.count < Self::data_len(status) {
+            return None;
+        }
+        self.count = 0;
+        if status >= 0xF0 {
+            // System Common messages don't have running status
+            self.status = None;
+        }
+
+        let channel = status & 0x0F;
+        let [first, second] = self.data;
+        let message = match status & 0xF0 {
+            0x80 => Message::NoteOff {
+                channel,
+                note: first,
+                velocity: second,
+            },
+            0x90 if second == 0 => Message::NoteOff {
+                channel,
+                note: first,
+                velocity: 0,
+            },
+            0x90 => Message::NoteOn {
+                channel,
+                note: first,
+                velocity: second,
+            },
+            0xA0 => Message::KeyPressure {
+                channel,
+                note: first,
+                pressure: second,
+            },
+            0xB0 => Message::ControlChange {
+                channel,
+                controller: first,
+                value: second,
+            },
+            0xC0 => Message::ProgramChange {
+                channel,
+                program: first,
+            },
+            0xD0 => Message::ChannelPressure {
+                channel,
+                pressure: first,
+            },
+            0xE0 => Message::PitchBend {
+                channel,
+                value: ((i16::from(second) << 7) | i16::from(first)) - 8192,
+            },
+            _ => match status {
+                0xF1 => Message::TimeCode(first),
+                0xF2 => Message::SongPosition((u16::from(second) << 7) | u16::from(first)),
+                _ => Message::SongSelect(first),
+            },
+        };
+        Some(message)
+    }
+
+    /// How many data bytes follow this status byte
+    fn data_len(status: u8) -> usize {
+        match status {
+            0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+            _ => 2,
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Shows a MIDI note number as a name, like `C4 (60)`
+struct Note(u8);
+
+impl core::fmt::Display for Note {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = NOTE_NAMES[usize::from(self.0 % 12)];
+        let octave = i32::from(self.0 / 12) - 1;
+        write!(f, "{}{} ({})", name, octave, self.0)
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Find the first UART that the BIOS says is for MIDI
+pub fn find_port() -> Option<u8> {
+    let api = API.get();
+    (0..=255u8).find(|&dev_idx| match (api.serial_get_info)(dev_idx) {
+        bios::FfiOption::Some(device_info) => matches!(
+            device_info.device_type.make_safe(),
+            Ok(bios::serial::DeviceType::Midi)
+        ),
+        bios::FfiOption::None => false,
+    })
+}
+
+/// Find the MIDI port and set it up, returning which UART it is
+pub fn open() -> Result<u8, Error> {
+    let api = API.get();
+    let port = find_port().ok_or(Error::NoPort)?;
+    let config = bios::serial::Config {
+        data_rate_bps: BAUD_RATE,
+        data_bits: bios::serial::DataBits::Eight.make_ffi_safe(),
+        stop_bits: bios::serial::StopBits::One.make_ffi_safe(),
+        parity: bios::serial::Parity::None.make_ffi_safe(),
+        handshaking: bios::serial::Handshaking::None.make_ffi_safe(),
+    };
+    match (api.serial_configure)(port, config) {
+        bios::ApiResult::Ok(()) => Ok(port),
+        bios::ApiResult::Err(e) => Err(Error::Bios(e)),
+    }
+}
+
+/// Read whatever bytes have arrived on the MIDI port, without waiting
+pub fn read(port: u8, buffer: &mut [u8]) -> Result<usize, bios::Error> {
+    let api = API.get();
+    (api.serial_read)(
+        port,
+        bios::FfiBuffer::new(buffer),
+        bios::FfiOption::Some(bios::Timeout::new_ms(0)),
+    )
+    .into()
+}
+
+/// Send all of the given bytes on the MIDI port
+pub fn write(port: u8, mut data: &[u8]) -> Result<(), bios::Error> {
+    let api = API.get();
+    while !data.is_empty() {
+        let res: Result<usize, bios::Error> =
+            (api.serial_write)(port, bios::FfiByteSlice::new(data), bios::FfiOption::None).into();
+        data = &data[res?..];
+    }
+    Ok(())
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+// None
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Message> {
+        let mut parser = Parser::new();
+        bytes.iter().filter_map(|b| parser.feed(*b)).collect()
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x91, 60, 100, 64, 90, 60, 0]),
+            [
+                Message::NoteOn {
+                    channel: 1,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::NoteOn {
+                    channel: 1,
+                    note: 64,
+                    velocity: 90
+                },
+                Message::NoteOff {
+                    channel: 1,
+                    note: 60,
+                    velocity: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_in_the_middle() {
+        assert_eq!(
+            parse(&[0xB0, 7, 0xF8, 127, 0xC2, 5]),
+            [
+                Message::Clock,
+                Message::ControlChange {
+                    channel: 0,
+                    controller: 7,
+                    value: 127
+                },
+                Message::ProgramChange {
+                    channel: 2,
+                    program: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pitch_bend_and_sysex() {
+        assert_eq!(
+            parse(&[0xE0, 0x00, 0x40, 0xE0, 0x00, 0x00, 0xF0, 0x7E, 1, 2, 0xF7, 5]),
+            [
+                Message::PitchBend {
+                    channel: 0,
+                    value: 0
+                },
+                Message::PitchBend {
+                    channel: 0,
+                    value: -8192
+                },
+                Message::SysEx { len: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(format!("{}", Note(60)), "C4 (60)");
+        assert_eq!(format!("{}", Note(0)), "C-1 (0)");
+        assert_eq!(format!("{}", Note(70)), "A#4 (70)");
+    }
+}
+
+// End of file
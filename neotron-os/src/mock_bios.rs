@@ -0,0 +1,1291 @@
+//! # A Mock BIOS, for testing
+//!
+//! Almost everything in the OS calls the BIOS through `API.get()`, so without
+//! a BIOS it can't do much. This module is a BIOS that runs on the build
+//! machine, so the tests can boot the whole OS, type commands at the shell,
+//! and check what comes out.
+//!
+//! It has:
+//!
+//! * A text-mode video card, with an 80x30 screen (mode 0, the default) and
+//!   an 80x60 screen.
+//! * One serial port, which stores everything written to it, and gives back
+//!   whatever the test queued up for it.
+//! * A keyboard, which plays back key events queued by the test.
+//! * A block device, backed by a disk image in memory. You can supply your
+//!   own image, or have us format an empty FAT16 disk.
+//! * A Transient Program Area.
+//! * A clock, which moves on by one millisecond every time anyone looks at it,
+//!   so anything that waits for time to pass finishes quickly.
+//!
+//! The OS keeps its state in global variables, and so does this BIOS, so only
+//! one test can use it at a time. [`MockBios::new`] waits until it's our turn.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::convert::TryFrom;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+use pc_keyboard::KeyCode;
+
+use crate::{bios, commands, Ctx};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many ticks there are in a second
+const TICKS_PER_SECOND: u64 = 1000;
+
+/// How big our blocks are
+const BLOCK_SIZE: usize = 512;
+
+/// The largest framebuffer we need, in 32-bit words (80x60 cells, two bytes
+/// each)
+const VRAM_WORDS: usize = 80 * 60 * 2 / 4;
+
+/// How big the Transient Program Area is, in 32-bit words
+const TPA_WORDS: usize = 64 * 1024 / 4;
+
+/// How many times we poll the OS after the last key, so it can finish what
+/// the key started
+const SETTLE_POLLS: usize = 16;
+
+/// Only one test can use the BIOS at a time
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Everything our BIOS knows
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// Our video memory. The OS holds on to pointers into this, so it must never
+/// move.
+static mut VRAM: [u32; VRAM_WORDS] = [0; VRAM_WORDS];
+
+/// Our Transient Program Area. The OS holds on to pointers into this, so it
+/// must never move.
+static mut TPA: [u32; TPA_WORDS] = [0; TPA_WORDS];
+
+/// The BIOS API table we give the OS
+static MOCK_API: bios::Api = bios::Api {
+    api_version_get,
+    bios_version_get,
+    serial_get_info,
+    serial_configure,
+    serial_write,
+    serial_read,
+    time_clock_get,
+    time_clock_set,
+    time_ticks_get,
+    time_ticks_per_second,
+    configuration_get,
+    configuration_set,
+    video_is_valid_mode,
+    video_mode_needs_vram,
+    video_set_mode,
+    video_get_mode,
+    video_get_framebuffer,
+    video_wait_for_line,
+    video_get_palette,
+    video_set_palette,
+    video_set_whole_palette,
+    memory_get_region,
+    hid_get_event,
+    hid_set_leds,
+    i2c_bus_get_info,
+    i2c_write_read,
+    audio_mixer_channel_get_info,
+    audio_mixer_channel_set_level,
+    audio_output_set_config,
+    audio_output_get_config,
+    audio_output_data,
+    audio_output_get_space,
+    audio_input_set_config,
+    audio_input_get_config,
+    audio_input_data,
+    audio_input_get_count,
+    bus_select,
+    bus_get_info,
+    bus_write_read,
+    bus_exchange,
+    bus_interrupt_status,
+    block_dev_get_info,
+    block_dev_eject,
+    block_write,
+    block_read,
+    block_verify,
+    power_idle,
+    power_control,
+    compare_and_swap_bool,
+};
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// A BIOS for one test.
+///
+/// Holding one of these stops any other test using the BIOS. Set up the disk
+/// and the configuration, then [`boot`](MockBios::boot) the OS.
+pub struct MockBios {
+    /// Keeps the other tests out
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl MockBios {
+    /// Wait for our turn, then reset the BIOS and the OS.
+    ///
+    /// The BIOS starts with no disk, and no stored configuration.
+    pub fn new() -> MockBios {
+        // A test that failed still gives up its turn, so ignore the poison
+        let turn = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *state() = State::new();
+        // # Safety
+        //
+        // Only the test holding `TEST_LOCK` touches these, and the OS we
+        // booted last time has been reset below.
+        unsafe {
+            core::ptr::addr_of_mut!(VRAM).write([0; VRAM_WORDS]);
+            crate::API.store(&MOCK_API);
+        }
+        crate::vconsole::reset();
+        *crate::VGA_CONSOLE.lock() = None;
+        *crate::SERIAL_CONSOLE.lock() = None;
+        *crate::PAGER.lock() = None;
+        *crate::STD_INPUT.lock() = crate::StdInput::new();
+        crate::FILESYSTEM.reset();
+        crate::RAM_FILESYSTEM.reset();
+        crate::history::clear();
+        MockBios { _turn: turn }
+    }
+
+    /// Insert a disk with this image on it.
+    ///
+    /// The image must be a whole number of blocks.
+    pub fn insert_disk(&self, image: Vec<u8>) {
+        assert_eq!(image.len() % BLOCK_SIZE, 0, "Disk image isn't whole blocks");
+        state().disk = Some(image);
+    }
+
+    /// Insert a freshly formatted FAT16 disk of the given size.
+    pub fn insert_blank_disk(&self, num_blocks: u32) {
+        self.insert_disk(format_fat16(num_blocks));
+    }
+
+    /// Get a copy of the disk image, if there is a disk.
+    pub fn disk_image(&self) -> Option<Vec<u8>> {
+        state().disk.clone()
+    }
+
+    /// Store the configuration the OS will load when it boots.
+    pub fn set_config(&self, config: &crate::config::Config) {
+        // The OS only knows how to store its configuration through the BIOS
+        config.save().expect("Saving config");
+    }
+
+    /// Boot the OS, and give back its shell.
+    ///
+    /// If nothing else was configured, we turn on the serial console so the
+    /// tests can read what the OS prints.
+    pub fn boot(&self) -> Shell {
+        if state().config.is_none() {
+            let mut config = crate::config::Config::default();
+            config.set_serial_console_on(115200);
+            self.set_config(&config);
+        }
+        let ctx = crate::os_init(&MOCK_API);
+        // The shell needs a line buffer that lives as long as it does
+        let buffer = Box::leak(Box::new([0u8; 256]));
+        let menu = menu::Runner::new(&commands::OS_MENU, buffer, ctx);
+        let mut shell = Shell {
+            menu,
+            aliases: commands::AliasExpander::new(),
+        };
+        shell.settle();
+        shell
+    }
+
+    /// Take everything written to the serial port so far.
+    pub fn take_serial_output(&self) -> String {
+        let bytes = core::mem::take(&mut state().serial_output);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Get the text on the VGA console, one string per row, with trailing
+    /// spaces removed.
+    pub fn screen_text(&self) -> Vec<String> {
+        let glyphs = crate::vgaconsole::VgaConsole::glyph_table();
+        let mut guard = crate::VGA_CONSOLE.lock();
+        let Some(console) = guard.as_mut() else {
+            return Vec::new();
+        };
+        let width = console.width() as usize;
+        (0..console.full_height())
+            .map(|row| {
+                let mut line = vec![0u8; width];
+                console.read_glyphs(row, 0, &mut line);
+                let text: String = line.iter().map(|g| glyphs[usize::from(*g)]).collect();
+                text.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    /// How many ticks have gone by since the BIOS started.
+    pub fn ticks(&self) -> u64 {
+        state().ticks
+    }
+}
+
+impl Default for MockBios {
+    fn default() -> MockBios {
+        MockBios::new()
+    }
+}
+
+/// The shell of an OS that has booted on the mock BIOS.
+pub struct Shell {
+    /// The menu that runs the commands
+    menu: menu::Runner<'static, Ctx>,
+    /// Expands aliases on the way into the menu
+    aliases: commands::AliasExpander,
+}
+
+impl Shell {
+    /// Type a line at the shell, through the keyboard, and give back what
+    /// the serial console printed while it ran.
+    pub fn run(&mut self, line: &str) -> String {
+        let _ = core::mem::take(&mut state().serial_output);
+        for ch in line.chars() {
+            push_char(ch);
+        }
+        push_char('\r');
+        self.settle();
+        let bytes = core::mem::take(&mut state().serial_output);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Press a key on the keyboard, and give back what the serial console
+    /// printed.
+    pub fn press_key(&mut self, key: KeyCode) -> String {
+        let _ = core::mem::take(&mut state().serial_output);
+        push_key(key);
+        self.settle();
+        let bytes = core::mem::take(&mut state().serial_output);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Send some bytes to the serial console, as if someone had typed them
+    /// on a terminal.
+    pub fn type_serial(&mut self, data: &[u8]) {
+        state().serial_input.extend(data);
+        self.settle();
+    }
+
+    /// Run the main loop until the OS has used up all the input, and any
+    /// script has finished.
+    pub fn settle(&mut self) {
+        let mut quiet = 0;
+        while quiet < SETTLE_POLLS {
+            crate::os_poll(&mut self.menu, &mut self.aliases);
+            let busy = {
+                let state = state();
+                !state.hid_events.is_empty() || !state.serial_input.is_empty()
+            };
+            if busy || self.menu.context.script.is_some() {
+                quiet = 0;
+            } else {
+                quiet += 1;
+            }
+        }
+    }
+
+    /// Get the shell's context, to look at the configuration or the TPA.
+    pub fn context(&mut self) -> &mut Ctx {
+        &mut self.menu.context
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Everything our BIOS knows
+struct State {
+    /// The time, in ticks since the BIOS started
+    ticks: u64,
+    /// The wall-clock time at tick zero, in seconds since the Neotron epoch
+    clock_base: u32,
+    /// The stored configuration, if any
+    config: Option<Vec<u8>>,
+    /// The current video mode
+    video_mode: u8,
+    /// Where the framebuffer is, if the OS gave us one
+    framebuffer: Option<*mut u32>,
+    /// The palette
+    palette: [u32; 256],
+    /// Key events waiting to be read
+    hid_events: VecDeque<bios::hid::HidEvent>,
+    /// Bytes waiting to be read from the serial port
+    serial_input: VecDeque<u8>,
+    /// Everything written to the serial port
+    serial_output: Vec<u8>,
+    /// The disk image, if there's a disk in the drive
+    disk: Option<Vec<u8>>,
+}
+
+// # Safety
+//
+// The framebuffer pointer is only used by the test holding `TEST_LOCK`.
+unsafe impl Send for State {}
+
+impl State {
+    /// The state at power-on
+    const fn new() -> State {
+        State {
+            ticks: 0,
+            // 2024-01-01T00:00:00Z
+            clock_base: 757_382_400,
+            config: None,
+            video_mode: 0,
+            framebuffer: None,
+            palette: [0; 256],
+            hid_events: VecDeque::new(),
+            serial_input: VecDeque::new(),
+            serial_output: Vec::new(),
+            disk: None,
+        }
+    }
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Get our state
+fn state() -> MutexGuard<'static, State> {
+    // If the OS panicked in a test, the next test still needs the BIOS
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Queue the key presses that type one character.
+fn push_char(ch: char) {
+    let (key, shifted) = key_for_char(ch).unwrap_or_else(|| panic!("Can't type {:?}", ch));
+    if shifted {
+        state()
+            .hid_events
+            .push_back(bios::hid::HidEvent::KeyPress(KeyCode::LShift));
+    }
+    push_key(key);
+    if shifted {
+        state()
+            .hid_events
+            .push_back(bios::hid::HidEvent::KeyRelease(KeyCode::LShift));
+    }
+}
+
+/// Queue a key press, and its release.
+fn push_key(key: KeyCode) {
+    let mut state = state();
+    state
+        .hid_events
+        .push_back(bios::hid::HidEvent::KeyPress(key));
+    state
+        .hid_events
+        .push_back(bios::hid::HidEvent::KeyRelease(key));
+}
+
+/// Which key types this character on a UK keyboard, and do you need Shift?
+fn key_for_char(ch: char) -> Option<(KeyCode, bool)> {
+    const LETTERS: [KeyCode; 26] = [
+        KeyCode::A,
+        KeyCode::B,
+        KeyCode::C,
+        KeyCode::D,
+        KeyCode::E,
+        KeyCode::F,
+        KeyCode::G,
+        KeyCode::H,
+        KeyCode::I,
+        KeyCode::J,
+        KeyCode::K,
+        KeyCode::L,
+        KeyCode::M,
+        KeyCode::N,
+        KeyCode::O,
+        KeyCode::P,
+        KeyCode::Q,
+        KeyCode::R,
+        KeyCode::S,
+        KeyCode::T,
+        KeyCode::U,
+        KeyCode::V,
+        KeyCode::W,
+        KeyCode::X,
+        KeyCode::Y,
+        KeyCode::Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [
+        KeyCode::Key0,
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+    let key = match ch {
+        'a'..='z' => (LETTERS[usize::from(ch as u8 - b'a')], false),
+        'A'..='Z' => (LETTERS[usize::from(ch as u8 - b'A')], true),
+        '0'..='9' => (DIGITS[usize::from(ch as u8 - b'0')], false),
+        ' ' => (KeyCode::Spacebar, false),
+        '\r' | '\n' => (KeyCode::Return, false),
+        '\u{8}' => (KeyCode::Backspace, false),
+        '.' => (KeyCode::OemPeriod, false),
+        ',' => (KeyCode::OemComma, false),
+        '-' => (KeyCode::OemMinus, false),
+        '_' => (KeyCode::OemMinus, true),
+        '=' => (KeyCode::OemPlus, false),
+        '+' => (KeyCode::OemPlus, true),
+        '/' => (KeyCode::Oem2, false),
+        ';' => (KeyCode::Oem1, false),
+        ':' => (KeyCode::Oem1, true),
+        '"' => (KeyCode::Key2, true),
+        '*' => (KeyCode::Key8, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Make an empty FAT16 disk, with an MBR and one partition.
+///
+/// We use one block per cluster, one reserved block, two FATs and a 512
+/// entry root directory. FAT16 needs at least 4085 clusters, so the disk must
+/// be at least 4200 blocks or so.
+fn format_fat16(num_blocks: u32) -> Vec<u8> {
+    const PARTITION_START: u32 = 1;
+    const RESERVED_BLOCKS: u32 = 1;
+    const NUM_FATS: u32 = 2;
+    const ROOT_ENTRIES: u32 = 512;
+    const ROOT_DIR_BLOCKS: u32 = ROOT_ENTRIES * 32 / BLOCK_SIZE as u32;
+    let partition_blocks = num_blocks - PARTITION_START;
+    // Each FAT needs two bytes per cluster, plus two reserved entries. Work
+    // it out as if every block was a cluster, which is close enough.
+    let fat_blocks = (partition_blocks + 2) * 2 / BLOCK_SIZE as u32 + 1;
+    let clusters = partition_blocks - RESERVED_BLOCKS - (NUM_FATS * fat_blocks) - ROOT_DIR_BLOCKS;
+    assert!(
+        (4085..65525).contains(&clusters),
+        "Disk is the wrong size for FAT16"
+    );
+
+    let mut image = vec![0u8; num_blocks as usize * BLOCK_SIZE];
+
+    // The Master Boot Record, with one FAT16 partition
+    let mbr = &mut image[0..BLOCK_SIZE];
+    let entry = &mut mbr[446..462];
+    entry[4] = 0x06;
+    entry[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+    entry[12..16].copy_from_slice(&partition_blocks.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    // The boot sector, with the BIOS Parameter Block
+    let start = PARTITION_START as usize * BLOCK_SIZE;
+    let boot = &mut image[start..start + BLOCK_SIZE];
+    boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"NEOTRON ");
+    boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED_BLOCKS as u16).to_le_bytes());
+    boot[16] = NUM_FATS as u8;
+    boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    if let Ok(blocks) = u16::try_from(partition_blocks) {
+        boot[19..21].copy_from_slice(&blocks.to_le_bytes());
+    } else {
+        boot[32..36].copy_from_slice(&partition_blocks.to_le_bytes());
+    }
+    boot[21] = 0xF8;
+    boot[22..24].copy_from_slice(&(fat_blocks as u16).to_le_bytes());
+    boot[28..32].copy_from_slice(&PARTITION_START.to_le_bytes());
+    boot[38] = 0x29;
+    boot[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    boot[43..54].copy_from_slice(b"NEOTRON    ");
+    boot[54..62].copy_from_slice(b"FAT16   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    // The first two entries of each FAT are reserved
+    for fat in 0..NUM_FATS {
+        let block = PARTITION_START + RESERVED_BLOCKS + fat * fat_blocks;
+        let offset = block as usize * BLOCK_SIZE;
+        image[offset..offset + 4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+    }
+
+    image
+}
+
+/// Find the part of the disk image for some blocks, if they are all there.
+fn disk_range(
+    state: &State,
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    len: usize,
+) -> Result<core::ops::Range<usize>, bios::Error> {
+    if device_id != 0 {
+        return Err(bios::Error::InvalidDevice);
+    }
+    let Some(disk) = state.disk.as_ref() else {
+        return Err(bios::Error::NoMediaFound);
+    };
+    let start = start_block.0 as usize * BLOCK_SIZE;
+    let end = start + usize::from(num_blocks) * BLOCK_SIZE;
+    if end > disk.len() {
+        return Err(bios::Error::BlockOutOfBounds);
+    }
+    if len != end - start {
+        return Err(bios::Error::UnsupportedConfiguration);
+    }
+    Ok(start..end)
+}
+
+/// Turn a `Result` into an FFI-safe `ApiResult`
+fn api_result<T>(result: Result<T, bios::Error>) -> bios::ApiResult<T> {
+    match result {
+        Ok(value) => bios::ApiResult::Ok(value),
+        Err(e) => bios::ApiResult::Err(e),
+    }
+}
+
+// ===========================================================================
+// The BIOS API
+// ===========================================================================
+
+extern "C" fn api_version_get() -> bios::Version {
+    bios::API_VERSION
+}
+
+extern "C" fn bios_version_get() -> bios::FfiString<'static> {
+    bios::FfiString::new("Mock BIOS")
+}
+
+extern "C" fn serial_get_info(device_id: u8) -> bios::FfiOption<bios::serial::DeviceInfo> {
+    if device_id == 0 {
+        bios::FfiOption::Some(bios::serial::DeviceInfo {
+            name: bios::FfiString::new("Mock UART"),
+            device_type: bios::serial::DeviceType::TtlUart.make_ffi_safe(),
+        })
+    } else {
+        bios::FfiOption::None
+    }
+}
+
+extern "C" fn serial_configure(
+    device_id: u8,
+    _config: bios::serial::Config,
+) -> bios::ApiResult<()> {
+    api_result(if device_id == 0 {
+        Ok(())
+    } else {
+        Err(bios::Error::InvalidDevice)
+    })
+}
+
+extern "C" fn serial_write(
+    device_id: u8,
+    data: bios::FfiByteSlice,
+    _timeout: bios::FfiOption<bios::Timeout>,
+) -> bios::ApiResult<usize> {
+    if device_id != 0 {
+        return bios::ApiResult::Err(bios::Error::InvalidDevice);
+    }
+    let data = data.as_slice();
+    state().serial_output.extend_from_slice(data);
+    bios::ApiResult::Ok(data.len())
+}
+
+extern "C" fn serial_read(
+    device_id: u8,
+    mut data: bios::FfiBuffer,
+    _timeout: bios::FfiOption<bios::Timeout>,
+) -> bios::ApiResult<usize> {
+    if device_id != 0 {
+        return bios::ApiResult::Err(bios::Error::InvalidDevice);
+    }
+    let mut state = state();
+    let Some(buffer) = data.as_mut_slice() else {
+        return bios::ApiResult::Ok(0);
+    };
+    let mut count = 0;
+    for slot in buffer.iter_mut() {
+        let Some(b) = state.serial_input.pop_front() else {
+            break;
+        };
+        *slot = b;
+        count += 1;
+    }
+    bios::ApiResult::Ok(count)
+}
+
+extern "C" fn time_clock_get() -> bios::Time {
+    let state = state();
+    bios::Time {
+        secs: state.clock_base + (state.ticks / TICKS_PER_SECOND) as u32,
+        nsecs: ((state.ticks % TICKS_PER_SECOND) * 1_000_000) as u32,
+    }
+}
+
+extern "C" fn time_clock_set(time: bios::Time) {
+    let mut state = state();
+    state.clock_base = time.secs - (state.ticks / TICKS_PER_SECOND) as u32;
+}
+
+extern "C" fn time_ticks_get() -> bios::Ticks {
+    let mut state = state();
+    state.ticks += 1;
+    bios::Ticks(state.ticks)
+}
+
+extern "C" fn time_ticks_per_second() -> bios::Ticks {
+    bios::Ticks(TICKS_PER_SECOND)
+}
+
+extern "C" fn configuration_get(mut buffer: bios::FfiBuffer) -> bios::ApiResult<usize> {
+    let state = state();
+    let Some(config) = state.config.as_ref() else {
+        return bios::ApiResult::Err(bios::Error::NoMediaFound);
+    };
+    match buffer.as_mut_slice() {
+        Some(buffer) if buffer.len() >= config.len() => {
+            buffer[0..config.len()].copy_from_slice(config);
+            bios::ApiResult::Ok(config.len())
+        }
+        _ => bios::ApiResult::Err(bios::Error::UnsupportedConfiguration),
+    }
+}
+
+extern "C" fn configuration_set(buffer: bios::FfiByteSlice) -> bios::ApiResult<()> {
+    state().config = Some(buffer.as_slice().to_vec());
+    bios::ApiResult::Ok(())
+}
+
+extern "C" fn video_is_valid_mode(mode: bios::video::Mode) -> bool {
+    mode.is_text_mode()
+        && mode.timing() == bios::video::Timing::T640x480
+        && !mode.is_horiz_2x()
+        && !mode.is_vert_2x()
+}
+
+extern "C" fn video_mode_needs_vram(_mode: bios::video::Mode) -> bool {
+    false
+}
+
+unsafe extern "C" fn video_set_mode(mode: bios::video::Mode, fb: *mut u32) -> bios::ApiResult<()> {
+    if !video_is_valid_mode(mode) {
+        return bios::ApiResult::Err(bios::Error::UnsupportedConfiguration);
+    }
+    let mut state = state();
+    state.video_mode = mode.as_u8();
+    state.framebuffer = if fb.is_null() { None } else { Some(fb) };
+    bios::ApiResult::Ok(())
+}
+
+extern "C" fn video_get_mode() -> bios::video::Mode {
+    bios::video::Mode::try_from_u8(state().video_mode).expect("Valid mode")
+}
+
+extern "C" fn video_get_framebuffer() -> *mut u32 {
+    state()
+        .framebuffer
+        .unwrap_or(core::ptr::addr_of_mut!(VRAM) as *mut u32)
+}
+
+extern "C" fn video_wait_for_line(_line: u16) {}
+
+extern "C" fn video_get_palette(palette_idx: u8) -> bios::FfiOption<bios::video::RGBColour> {
+    let packed = state().palette[usize::from(palette_idx)];
+    bios::FfiOption::Some(bios::video::RGBColour::from_packed(packed))
+}
+
+extern "C" fn video_set_palette(palette_idx: u8, colour: bios::video::RGBColour) {
+    state().palette[usize::from(palette_idx)] = colour.as_packed();
+}
+
+unsafe extern "C" fn video_set_whole_palette(start: *const bios::video::RGBColour, length: usize) {
+    let colours = core::slice::from_raw_parts(start, length);
+    let mut state = state();
+    for (slot, colour) in state.palette.iter_mut().zip(colours) {
+        *slot = colour.as_packed();
+    }
+}
+
+extern "C" fn memory_get_region(region_index: u8) -> bios::FfiOption<bios::MemoryRegion> {
+    if region_index == 0 {
+        bios::FfiOption::Some(bios::MemoryRegion {
+            start: core::ptr::addr_of_mut!(TPA) as *mut u8,
+            length: TPA_WORDS * 4,
+            kind: bios::MemoryKind::Ram.make_ffi_safe(),
+        })
+    } else {
+        bios::FfiOption::None
+    }
+}
+
+extern "C" fn hid_get_event() -> bios::ApiResult<bios::FfiOption<bios::hid::HidEvent>> {
+    bios::ApiResult::Ok(state().hid_events.pop_front().into())
+}
+
+extern "C" fn hid_set_leds(_leds: bios::hid::KeyboardLeds) -> bios::ApiResult<()> {
+    bios::ApiResult::Ok(())
+}
+
+extern "C" fn i2c_bus_get_info(_bus_id: u8) -> bios::FfiOption<bios::i2c::BusInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn i2c_write_read(
+    _bus_id: u8,
+    _i2c_device_address: u8,
+    _tx: bios::FfiByteSlice,
+    _tx2: bios::FfiByteSlice,
+    _rx: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn audio_mixer_channel_get_info(
+    _audio_mixer_id: u8,
+) -> bios::FfiOption<bios::audio::MixerChannelInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn audio_mixer_channel_set_level(
+    _audio_mixer_id: u8,
+    _level: u8,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn audio_output_set_config(_config: bios::audio::Config) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_output_get_config() -> bios::ApiResult<bios::audio::Config> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+unsafe extern "C" fn audio_output_data(_samples: bios::FfiByteSlice) -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_output_get_space() -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_set_config(_config: bios::audio::Config) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_get_config() -> bios::ApiResult<bios::audio::Config> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+unsafe extern "C" fn audio_input_data(_samples: bios::FfiBuffer) -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn audio_input_get_count() -> bios::ApiResult<usize> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn bus_select(_peripheral_id: bios::FfiOption<u8>) {}
+
+extern "C" fn bus_get_info(_peripheral_id: u8) -> bios::FfiOption<bios::bus::PeripheralInfo> {
+    bios::FfiOption::None
+}
+
+extern "C" fn bus_write_read(
+    _tx: bios::FfiByteSlice,
+    _tx2: bios::FfiByteSlice,
+    _rx: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn bus_exchange(_buffer: bios::FfiBuffer) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::InvalidDevice)
+}
+
+extern "C" fn bus_interrupt_status() -> u32 {
+    0
+}
+
+extern "C" fn block_dev_get_info(device_id: u8) -> bios::FfiOption<bios::block_dev::DeviceInfo> {
+    if device_id != 0 {
+        return bios::FfiOption::None;
+    }
+    let state = state();
+    let num_blocks = state
+        .disk
+        .as_ref()
+        .map_or(0, |disk| (disk.len() / BLOCK_SIZE) as u64);
+    bios::FfiOption::Some(bios::block_dev::DeviceInfo {
+        name: bios::FfiString::new("Mock Disk"),
+        device_type: bios::block_dev::DeviceType::SecureDigitalCard.make_ffi_safe(),
+        block_size: BLOCK_SIZE as u32,
+        num_blocks,
+        ejectable: false,
+        removable: true,
+        media_present: state.disk.is_some(),
+        read_only: false,
+    })
+}
+
+extern "C" fn block_dev_eject(_device_id: u8) -> bios::ApiResult<()> {
+    bios::ApiResult::Err(bios::Error::Unimplemented)
+}
+
+extern "C" fn block_write(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    data: bios::FfiByteSlice,
+) -> bios::ApiResult<()> {
+    let data = data.as_slice();
+    let mut state = state();
+    api_result(
+        disk_range(&state, device_id, start_block, num_blocks, data.len()).map(|range| {
+            if let Some(disk) = state.disk.as_mut() {
+                disk[range].copy_from_slice(data);
+            }
+        }),
+    )
+}
+
+extern "C" fn block_read(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    mut data: bios::FfiBuffer,
+) -> bios::ApiResult<()> {
+    let Some(buffer) = data.as_mut_slice() else {
+        return bios::ApiResult::Err(bios::Error::UnsupportedConfiguration);
+    };
+    let state = state();
+    api_result(
+        disk_range(&state, device_id, start_block, num_blocks, buffer.len()).map(|range| {
+            if let Some(disk) = state.disk.as_ref() {
+                buffer.copy_from_slice(&disk[range]);
+            }
+        }),
+    )
+}
+
+extern "C" fn block_verify(
+    device_id: u8,
+    start_block: bios::block_dev::BlockIdx,
+    num_blocks: u8,
+    data: bios::FfiByteSlice,
+) -> bios::ApiResult<()> {
+    let data = data.as_slice();
+    let state = state();
+    api_result(
+        disk_range(&state, device_id, start_block, num_blocks, data.len()).and_then(|range| {
+            match state.disk.as_ref() {
+                Some(disk) if disk[range] == *data => Ok(()),
+                _ => Err(bios::Error::DeviceError),
+            }
+        }),
+    )
+}
+
+extern "C" fn power_idle() {
+    // Pretend we waited for the next video frame
+    state().ticks += TICKS_PER_SECOND / 60;
+}
+
+extern "C" fn power_control(mode: bios::FfiPowerMode) -> ! {
+    panic!("The OS asked for power mode {:?}", mode);
+}
+
+extern "C" fn compare_and_swap_bool(
+    value: &core::sync::atomic::AtomicBool,
+    old_value: bool,
+    new_value: bool,
+) -> bool {
+    value
+        .compare_exchange(
+            old_value,
+            new_value,
+            core::sync::atomic::Ordering::Acquire,
+            core::sync::atomic::Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boots() {
+        let bios = MockBios::new();
+        let _shell = bios.boot();
+        let output = bios.take_serial_output();
+        assert!(output.contains(crate::OS_VERSION), "{}", output);
+        // The staging rings and the log come off the top of the TPA
+        let tpa_len = TPA_WORDS * 4 - 2 * crate::staging::RING_LEN - crate::log::LOG_SIZE;
+        assert!(
+            output.contains(&format!("TPA: {} bytes", tpa_len)),
+            "{}",
+            output
+        );
+        let screen = bios.screen_text();
+        assert_eq!(screen.len(), 30);
+        assert!(
+            screen.iter().any(|line| line.contains("Neotron OS")),
+            "{:?}",
+            screen
+        );
+    }
+
+    #[test]
+    fn virtual_consoles() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let alt_key = |key| {
+            let mut state = state();
+            state
+                .hid_events
+                .push_back(bios::hid::HidEvent::KeyPress(KeyCode::LAlt));
+            state
+                .hid_events
+                .push_back(bios::hid::HidEvent::KeyPress(key));
+            state
+                .hid_events
+                .push_back(bios::hid::HidEvent::KeyRelease(key));
+            state
+                .hid_events
+                .push_back(bios::hid::HidEvent::KeyRelease(KeyCode::LAlt));
+        };
+        let consoles_reserved = |shell: &mut Shell| {
+            shell
+                .context()
+                .tpa
+                .reservations()
+                .iter()
+                .filter(|r| r.name.starts_with("Console"))
+                .count()
+        };
+        // Nothing is reserved until a console is used
+        assert_eq!(consoles_reserved(&mut shell), 0);
+        alt_key(KeyCode::F2);
+        shell.settle();
+        assert_eq!(crate::vconsole::active(), 1);
+        assert_eq!(consoles_reserved(&mut shell), 1);
+        assert!(crate::vconsole::write_bstr(1, b"Hello").is_ok());
+        assert!(crate::vconsole::write_bstr(2, b"Hello").is_err());
+        // Going back and forth keeps the same memory
+        alt_key(KeyCode::F1);
+        shell.settle();
+        assert_eq!(crate::vconsole::active(), 0);
+        alt_key(KeyCode::F2);
+        shell.settle();
+        assert_eq!(crate::vconsole::active(), 1);
+        assert_eq!(consoles_reserved(&mut shell), 1);
+    }
+
+    #[test]
+    fn recovers_after_fault() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        // Something took some locks and then faulted, so never let go
+        core::mem::forget(crate::VGA_CONSOLE.lock());
+        core::mem::forget(
+            crate::FILESYSTEM.open_file("X.TXT", embedded_sdmmc::Mode::ReadWriteCreate),
+        );
+        // A program was running, so the TPA was executable
+        core::mem::forget(crate::mpu::tpa_executable());
+        assert!(crate::mpu::is_tpa_executable());
+        unsafe {
+            crate::recover_after_fault();
+        }
+        assert!(!crate::mpu::is_tpa_executable());
+        let output = shell.run("dir");
+        assert!(output.contains("1 file(s)"), "{}", output);
+    }
+
+    #[test]
+    fn readers_share_files() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let _shell = bios.boot();
+        let open = |mode| crate::FILESYSTEM.open_file("SHARED.TXT", mode);
+        let writer = open(embedded_sdmmc::Mode::ReadWriteCreate).unwrap();
+        writer.write(b"ABCDEFGH").unwrap();
+        // Only one writer, and no readers while it's writing
+        assert!(matches!(
+            open(embedded_sdmmc::Mode::ReadWriteAppend),
+            Err(crate::fs::Error::AlreadyOpen)
+        ));
+        assert!(matches!(
+            open(embedded_sdmmc::Mode::ReadOnly),
+            Err(crate::fs::Error::AlreadyOpen)
+        ));
+        drop(writer);
+        // Readers each have their own place in the file
+        let first = open(embedded_sdmmc::Mode::ReadOnly).unwrap();
+        let second = open(embedded_sdmmc::Mode::ReadOnly).unwrap();
+        let mut buffer = [0u8; 4];
+        assert_eq!(first.read(&mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"ABCD");
+        assert_eq!(second.read(&mut buffer[0..2]).unwrap(), 2);
+        assert_eq!(&buffer[0..2], b"AB");
+        assert_eq!(first.read(&mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"EFGH");
+        // And a writer has to wait until they've all gone
+        drop(first);
+        assert!(open(embedded_sdmmc::Mode::ReadWriteAppend).is_err());
+        drop(second);
+        assert!(open(embedded_sdmmc::Mode::ReadWriteAppend).is_ok());
+    }
+
+    #[test]
+    fn notices_a_card_the_same_size() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        shell.run("touch X.TXT");
+        let file = crate::FILESYSTEM
+            .open_file("X.TXT", embedded_sdmmc::Mode::ReadOnly)
+            .unwrap();
+        // Another card, the same size, with a different volume serial number
+        let mut image = format_fat16(8192);
+        image[BLOCK_SIZE + 39] ^= 0xFF;
+        bios.insert_disk(image);
+        // The OS only reads the serial number a few times a second
+        state().ticks += TICKS_PER_SECOND;
+        let mut buffer = [0u8; 4];
+        assert!(matches!(
+            file.read(&mut buffer),
+            Err(crate::fs::Error::MediaChanged)
+        ));
+    }
+
+    #[test]
+    fn stages_output_while_locked() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let _ = bios.take_serial_output();
+        let guard = crate::VGA_CONSOLE.lock();
+        crate::osprintln!("Hello while locked");
+        drop(guard);
+        // The serial console wasn't locked, so it got it straight away
+        assert!(bios.take_serial_output().contains("Hello while locked"));
+        let guard = crate::SERIAL_CONSOLE.lock();
+        crate::osprintln!("Serial was busy");
+        drop(guard);
+        assert!(!bios.take_serial_output().contains("Serial was busy"));
+        // The main loop prints whatever was held back
+        shell.settle();
+        assert!(bios.take_serial_output().contains("Serial was busy"));
+        let screen = bios.screen_text();
+        assert!(
+            screen
+                .iter()
+                .any(|line| line.contains("Hello while locked")),
+            "{:?}",
+            screen
+        );
+    }
+
+    #[test]
+    fn loads_config() {
+        let bios = MockBios::new();
+        let mut config = crate::config::Config::default();
+        config.set_serial_console_on(9600);
+        bios.set_config(&config);
+        let mut shell = bios.boot();
+        let (_idx, serial_config) = shell.context().config.get_serial_console().unwrap();
+        assert_eq!(serial_config.data_rate_bps, 9600);
+    }
+
+    #[test]
+    fn unknown_command() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let output = shell.run("florp");
+        assert!(output.contains("florp"), "{}", output);
+        assert!(output.contains("not found"), "{}", output);
+    }
+
+    #[test]
+    fn help_on_a_command() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let output = shell.run("help sleep");
+        assert!(output.contains("Usage: sleep <ms>"), "{}", output);
+        assert!(output.contains("Parameter"), "{}", output);
+        assert!(!output.contains("SUMMARY"), "{}", output);
+        // Anything else is up to the menu
+        let output = shell.run("help florp");
+        assert!(output.contains("can't help"), "{}", output);
+    }
+
+    #[test]
+    fn lsblk() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        let output = shell.run("lsblk");
+        assert!(output.contains("Mock Disk"), "{}", output);
+        assert!(output.contains("8192"), "{}", output);
+    }
+
+    #[test]
+    fn sleep_takes_time() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let before = bios.ticks();
+        shell.run("sleep 2000");
+        assert!(bios.ticks() - before >= 2 * TICKS_PER_SECOND);
+    }
+
+    #[test]
+    fn timing() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        let output = shell.run("sleep 100");
+        assert!(!output.contains("took"), "{}", output);
+        shell.run("timing on");
+        let output = shell.run("sleep 1500");
+        assert!(output.contains("(took 1.5"), "{}", output);
+        shell.run("timing off");
+        let output = shell.run("sleep 100");
+        assert!(!output.contains("took"), "{}", output);
+    }
+
+    #[test]
+    fn history() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut config = crate::config::Config::default();
+        config.set_serial_console_on(115200);
+        config.set_save_history(true);
+        bios.set_config(&config);
+        let mut shell = bios.boot();
+        shell.run("sleep 1");
+        shell.run("timing on");
+        // Up twice gets the `sleep` back, and Enter runs it again
+        shell.press_key(KeyCode::ArrowUp);
+        shell.press_key(KeyCode::ArrowUp);
+        shell.press_key(KeyCode::ArrowDown);
+        shell.press_key(KeyCode::ArrowUp);
+        let output = shell.press_key(KeyCode::Return);
+        assert!(output.contains("(took"), "{}", output);
+        let output = shell.run("history");
+        assert!(
+            output.contains("  1  sleep 1\n  2  timing on\n  3  sleep 1\n  4  history"),
+            "{}",
+            output
+        );
+        // The serial console sends different arrows. The same command twice
+        // in a row is only remembered once.
+        shell.type_serial(b"\x1b[A\r");
+        let output = shell.run("history save");
+        assert!(output.contains("Saved history"), "{}", output);
+        shell.run("history clear");
+        // Booting again reads them back
+        let mut shell = bios.boot();
+        let output = shell.run("history");
+        assert!(
+            output.contains("  1  sleep 1\n  2  history\n  3  history save\n  4  history"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn block_read() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        // The MBR ends with the boot signature
+        let output = shell.run("readblk 0 0");
+        assert!(output.contains("55aa"), "{}", output);
+    }
+
+    #[test]
+    fn files() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        shell.run("touch HELLO.TXT");
+        let output = shell.run("dir");
+        assert!(output.contains("HELLO"), "{}", output);
+        // It really is on the disk
+        let image = bios.disk_image().unwrap();
+        assert!(image.windows(8).any(|w| w == b"HELLO   "));
+    }
+
+    #[test]
+    fn serial_input() {
+        let bios = MockBios::new();
+        let mut shell = bios.boot();
+        bios.take_serial_output();
+        shell.type_serial(b"calc 6*7\r");
+        let output = bios.take_serial_output();
+        assert!(output.contains("42"), "{}", output);
+    }
+
+    #[test]
+    fn load_missing_program() {
+        let bios = MockBios::new();
+        bios.insert_blank_disk(8192);
+        let mut shell = bios.boot();
+        let output = shell.run("load NOPE.ELF");
+        assert!(output.contains("Error"), "{}", output);
+    }
+
+    #[test]
+    fn input_events() {
+        let bios = MockBios::new();
+        let _shell = bios.boot();
+        let mut std_input = crate::STD_INPUT.lock();
+        std_input.set_events(true);
+        push_char('A');
+        let shifted = crate::InputEvent::SHIFT | crate::InputEvent::NUM_LOCK;
+        let expected = [
+            (KeyCode::LShift, true, shifted),
+            (KeyCode::A, true, shifted),
+            (KeyCode::A, false, shifted),
+            (KeyCode::LShift, false, crate::InputEvent::NUM_LOCK),
+        ];
+        for (code, pressed, modifiers) in expected {
+            assert_eq!(
+                std_input.get_event(),
+                Some(crate::InputEvent::Key {
+                    code,
+                    pressed,
+                    modifiers
+                })
+            );
+        }
+        assert_eq!(std_input.get_event(), None);
+        // The key was still typed
+        let mut buffer = [0u8; 4];
+        assert_eq!(std_input.get_data(&mut buffer), 1);
+        assert_eq!(buffer[0], b'A');
+    }
+}
+
+// End of file
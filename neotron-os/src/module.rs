@@ -0,0 +1,332 @@
+//! # Loadable OS Modules
+//!
+//! Small extensions to the OS, like extra shell commands or Neotron Bus
+//! drivers, that are loaded from disk when the OS starts. They let you add
+//! features without reflashing the OS.
+//!
+//! At boot we load every `.MOD` file in the `MODULES` directory, in name
+//! order. Each one goes into memory reserved off the top of the TPA (see
+//! [`crate::program::TransientProgramArea::reserve`]), and stays there
+//! until the system restarts.
+//!
+//! A module is a position-independent ELF file, built like an overlay. Its
+//! entry point is a [`ModuleStartFn`], which is called once, with a
+//! [`ModuleApi`]. The module should check the API version, register its
+//! commands and drivers, and return 0. Anything else means it failed, and
+//! none of its commands or drivers are used. Everything a module gives us
+//! must live in the module's own memory, as we hold on to it for ever.
+//!
+//! Module commands run like programs - they are given the application API
+//! and their arguments, with the standard streams open - but they must not
+//! touch the TPA, as the shell might have something loaded there. You run
+//! them by typing their name, as if they were built in (see `modrun`).
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{osprintln, program::TransientProgramArea, refcell::CsRefCell, FILESYSTEM};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The version of [`ModuleApi`]. Bump it whenever that (or anything it
+/// refers to) changes.
+pub const API_VERSION: u32 = 1;
+
+/// Where we look for modules
+const MODULE_DIR: &str = "MODULES";
+
+/// What modules are called
+const MODULE_EXTENSION: &str = "MOD";
+
+/// How many modules we can load
+const MAX_MODULES: usize = 8;
+
+/// How many commands modules can add, between them
+const MAX_COMMANDS: usize = 16;
+
+/// How many bus drivers a module can add
+const MAX_DRIVERS: usize = 4;
+
+/// The longest name a module command can have
+const MAX_COMMAND_LEN: usize = 16;
+
+/// The table of functions we give to modules
+static MODULE_API: ModuleApi = ModuleApi {
+    version: API_VERSION,
+    register_command,
+    register_bus_driver,
+};
+
+/// The modules we've loaded
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    modules: heapless::Vec::new(),
+    commands: heapless::Vec::new(),
+    drivers: heapless::Vec::new(),
+    loading: None,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The functions we give to a module when it starts.
+#[repr(C)]
+pub struct ModuleApi {
+    /// Which version of this API we are ([`API_VERSION`])
+    pub version: u32,
+    /// Add a shell command. Fails if the name is taken, or too long, or we
+    /// have no room.
+    pub register_command: extern "C" fn(command: &'static Command) -> bool,
+    /// Add a Neotron Bus driver (see [`crate::bus`]). Fails if the module
+    /// has already added too many.
+    pub register_bus_driver: extern "C" fn(driver: &'static crate::bus::Driver) -> bool,
+}
+
+/// A shell command from a module
+#[repr(C)]
+pub struct Command {
+    /// What you type to run the command
+    pub name: neotron_api::FfiString<'static>,
+    /// What the command does, for `lsmod`
+    pub help: neotron_api::FfiString<'static>,
+    /// Runs the command, just like a program's entry point
+    pub run: neotron_api::AppStartFn,
+}
+
+// # Safety
+//
+// The name and the help are the only pointers in a command, and they point
+// at text that never changes.
+unsafe impl Sync for Command {}
+
+/// The entry point of a module.
+///
+/// Returns 0 if the module started, or anything else if it didn't.
+pub type ModuleStartFn = extern "C" fn(api: &'static ModuleApi) -> i32;
+
+/// A module we have loaded
+pub struct Module {
+    /// The file it came from
+    pub name: heapless::String<12>,
+    /// Where it is in memory
+    pub address: usize,
+    /// How much memory it takes
+    pub size: usize,
+    /// Did it start?
+    pub started: bool,
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we know about the modules
+struct State {
+    /// The modules we loaded
+    modules: heapless::Vec<Module, MAX_MODULES>,
+    /// The commands they added, and which module added them
+    commands: heapless::Vec<(&'static Command, usize), MAX_COMMANDS>,
+    /// The bus drivers the starting module has added, which we pass on to
+    /// the bus once it has started
+    drivers: heapless::Vec<&'static crate::bus::Driver, MAX_DRIVERS>,
+    /// Which module is starting, if any
+    loading: Option<usize>,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Load and start every module in the modules directory.
+pub fn load_all(tpa: &mut TransientProgramArea) {
+    let mut names: heapless::Vec<embedded_sdmmc::ShortFileName, MAX_MODULES> = heapless::Vec::new();
+    let found = FILESYSTEM.iterate_dir(MODULE_DIR, |entry| {
+        if entry.attributes.is_directory() || entry.name.extension() != MODULE_EXTENSION.as_bytes()
+        {
+            return;
+        }
+        if names.push(entry.name.clone()).is_err() {
+            osprintln!(log: Warn, "Too many modules - skipping {}", entry.name);
+        }
+    });
+    if found.is_err() {
+        // No modules directory, so no modules
+        return;
+    }
+    names.sort_unstable_by(|a, b| a.base_name().cmp(b.base_name()));
+    for name in names.iter() {
+        load(tpa, name);
+    }
+}
+
+/// Run a module command, if there's one with this name.
+///
+/// Gives the command's exit code.
+pub fn run_command(name: &str, args: &[&str]) -> Option<i32> {
+    let command = find_command(name)?;
+    let mut ffi_args: heapless::Vec<neotron_api::FfiString, { crate::program::MAX_ARGS + 1 }> =
+        heapless::Vec::new();
+    // Like a program, the first argument is the command's name
+    for arg in core::iter::once(&name).chain(args.iter()) {
+        if ffi_args.push(neotron_api::FfiString::new(arg)).is_err() {
+            osprintln!("Too many arguments");
+            return None;
+        }
+    }
+    Some(crate::program::with_std_handles(|api| {
+        (command.run)(api, ffi_args.len(), ffi_args.as_ptr())
+    }))
+}
+
+/// Is there a module command with this name?
+pub fn is_command(name: &str) -> bool {
+    find_command(name).is_some()
+}
+
+/// Look at each module we loaded, with the names and help of its commands.
+pub fn for_each<F>(mut f: F)
+where
+    F: FnMut(&Module, &mut dyn Iterator<Item = (&str, &str)>),
+{
+    let state = STATE.lock();
+    for (idx, module) in state.modules.iter().enumerate() {
+        let mut commands = state
+            .commands
+            .iter()
+            .filter(|(_, owner)| *owner == idx)
+            .map(|(command, _)| (command.name.as_str(), command.help.as_str()));
+        f(module, &mut commands);
+    }
+}
+
+/// Unlock the module table, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Load and start one module
+fn load(tpa: &mut TransientProgramArea, file_name: &embedded_sdmmc::ShortFileName) {
+    use core::fmt::Write as _;
+
+    let mut name = heapless::String::new();
+    let _ = write!(name, "{}", file_name);
+    let file = match FILESYSTEM.open_file_in_dir(
+        MODULE_DIR,
+        file_name.clone(),
+        embedded_sdmmc::Mode::ReadOnly,
+    ) {
+        Ok(file) => file,
+        Err(e) => {
+            osprintln!(log: Warn, "Can't open module {}: {:?}", name, e);
+            return;
+        }
+    };
+    let (entry, memory) = match tpa.load_module(&name, file) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            osprintln!(log: Warn, "Can't load module {}: {:?}", name, e);
+            return;
+        }
+    };
+    let idx = {
+        let mut state = STATE.lock();
+        let idx = state.modules.len();
+        let module = Module {
+            name,
+            address: memory.as_ptr() as usize,
+            size: memory.len(),
+            started: false,
+        };
+        if state.modules.push(module).is_err() {
+            // Can't happen - we only load as many as we have room for
+            return;
+        }
+        state.loading = Some(idx);
+        idx
+    };
+
+    let result = crate::program::with_std_handles(|_api| {
+        // # Safety
+        //
+        // We just loaded a module to this address. We have to trust that it
+        // really is a module.
+        let start: ModuleStartFn = unsafe { core::mem::transmute(entry as *const ()) };
+        start(&MODULE_API)
+    });
+
+    let mut state = STATE.lock();
+    state.loading = None;
+    let drivers = core::mem::take(&mut state.drivers);
+    if result == 0 {
+        state.modules[idx].started = true;
+        for driver in drivers {
+            if !crate::bus::register(driver) {
+                osprintln!(log: Warn, "No room for bus driver {}", driver.name);
+            }
+        }
+        osprintln!(
+            log: Info,
+            "Loaded module {} ({} bytes @ 0x{:08x})",
+            state.modules[idx].name,
+            state.modules[idx].size,
+            state.modules[idx].address
+        );
+    } else {
+        // It might have registered some commands before it gave up
+        state.commands.retain(|(_, owner)| *owner != idx);
+        osprintln!(
+            log: Warn,
+            "Module {} failed to start ({})",
+            state.modules[idx].name,
+            result
+        );
+    }
+}
+
+/// Find a command from a module that started
+fn find_command(name: &str) -> Option<&'static Command> {
+    STATE
+        .lock()
+        .commands
+        .iter()
+        .find(|(command, _)| command.name.as_str() == name)
+        .map(|(command, _)| *command)
+}
+
+/// Called by a module to add a shell command
+extern "C" fn register_command(command: &'static Command) -> bool {
+    let name = command.name.as_str();
+    if name.is_empty()
+        || name.len() > MAX_COMMAND_LEN
+        || name.contains(' ')
+        || crate::commands::is_builtin(name)
+        || find_command(name).is_some()
+    {
+        return false;
+    }
+    let mut state = STATE.lock();
+    let Some(owner) = state.loading else {
+        // Modules can only add commands while they start
+        return false;
+    };
+    state.commands.push((command, owner)).is_ok()
+}
+
+/// Called by a module to add a Neotron Bus driver
+extern "C" fn register_bus_driver(driver: &'static crate::bus::Driver) -> bool {
+    let mut state = STATE.lock();
+    if state.loading.is_none() {
+        // Modules can only add drivers while they start
+        return false;
+    }
+    state.drivers.push(driver).is_ok()
+}
+
+// End of file
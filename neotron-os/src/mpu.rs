@@ -0,0 +1,411 @@
+//! # Memory Protection
+//!
+//! On Cortex-M CPUs with a Memory Protection Unit, we use it to turn some
+//! wild pointer bugs into faults (which the fault handler reports) rather
+//! than silent corruption:
+//!
+//! * The OS's flash is read-only.
+//! * The Transient Program Area can't be executed, except while a program
+//!   is running.
+//!
+//! Each of those gets one MPU region, which must be a power of two in size,
+//! and aligned to its size. We use the eight sub-regions to make each region
+//! fit as well as we can, but we never protect memory outside the range we
+//! were asked to - a little of the TPA might stay executable, but the BIOS's
+//! memory is never touched.
+//!
+//! We only know the Armv6-M and Armv7-M MPU. On other CPUs (and on CPUs with
+//! no MPU) this does nothing.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Have we made the TPA executable? We keep track even without an MPU, so
+/// the tests can check.
+static TPA_EXECUTABLE: AtomicBool = AtomicBool::new(false);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Keeps the TPA executable until it is dropped.
+pub struct TpaExecutable {
+    _private: (),
+}
+
+impl Drop for TpaExecutable {
+    fn drop(&mut self) {
+        tpa_protect();
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// An MPU region, with some of its sub-regions turned off
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+// Only bare-metal Arm builds have an MPU to program
+#[cfg_attr(
+    not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))),
+    allow(dead_code)
+)]
+struct Region {
+    /// Where it starts. Aligned to its size.
+    base: u32,
+    /// It is `2 ** size_log2` bytes long
+    size_log2: u8,
+    /// Bit N set means sub-region N is turned off
+    disabled: u8,
+}
+
+#[cfg_attr(
+    not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))),
+    allow(dead_code)
+)]
+impl Region {
+    /// The smallest region that can have sub-regions
+    const MIN_SIZE_LOG2: u8 = 8;
+
+    /// Find the region that covers as much of `start..end` as possible,
+    /// without covering anything outside it.
+    ///
+    /// Gives `None` if no region fits.
+    fn fit(start: u32, end: u32) -> Option<Region> {
+        let (start, end) = (u64::from(start), u64::from(end));
+        let mut best: Option<(u64, Region)> = None;
+        for size_log2 in Self::MIN_SIZE_LOG2..=32 {
+            let size = 1u64 << size_log2;
+            let sub_size = size / 8;
+            // The range could straddle two blocks of this size, so try both
+            let first = start & !(size - 1);
+            for base in [first, first + size] {
+                if base >= end || base > u64::from(u32::MAX) {
+                    continue;
+                }
+                let mut disabled = 0u8;
+                let mut covered = 0;
+                for sub in 0..8 {
+                    let sub_start = base + sub * sub_size;
+                    if sub_start >= start && sub_start + sub_size <= end {
+                        covered += sub_size;
+                    } else {
+                        disabled |= 1 << sub;
+                    }
+                }
+                if covered > best.map_or(0, |(most, _)| most) {
+                    let region = Region {
+                        base: base as u32,
+                        size_log2,
+                        disabled,
+                    };
+                    best = Some((covered, region));
+                }
+            }
+        }
+        best.map(|(_, region)| region)
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Protect the OS's flash, and the TPA (which is `tpa_start..tpa_end`).
+///
+/// Call this once the TPA is the size it will stay, after any OS modules
+/// have been loaded.
+#[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+pub fn configure(tpa_start: *const u8, tpa_end: *const u8) -> bool {
+    arm::configure(tpa_start as u32, tpa_end as u32)
+}
+
+/// Protect the OS's flash, and the TPA (which is `tpa_start..tpa_end`).
+///
+/// There's no MPU here, so this does nothing.
+#[cfg(not(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode"))))]
+pub fn configure(_tpa_start: *const u8, _tpa_end: *const u8) -> bool {
+    false
+}
+
+/// Make the TPA executable, until the value we give back is dropped.
+///
+/// Hold on to it while a program runs.
+pub fn tpa_executable() -> TpaExecutable {
+    TPA_EXECUTABLE.store(true, Ordering::Relaxed);
+    #[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+    arm::set_tpa_executable(true);
+    TpaExecutable { _private: () }
+}
+
+/// Stop the CPU executing code in the TPA.
+///
+/// Dropping a [`TpaExecutable`] does this, but if the program faulted, its
+/// [`TpaExecutable`] is never dropped, so we have to do it ourselves.
+pub fn tpa_protect() {
+    TPA_EXECUTABLE.store(false, Ordering::Relaxed);
+    #[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+    arm::set_tpa_executable(false);
+}
+
+/// Can the CPU execute code in the TPA at the moment?
+#[cfg(test)]
+pub fn is_tpa_executable() -> bool {
+    TPA_EXECUTABLE.load(Ordering::Relaxed)
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// The Cortex-M specific parts
+#[cfg(all(target_arch = "arm", target_os = "none", not(feature = "lib-mode")))]
+mod arm {
+    use super::Region;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    /// The CPUID Base Register
+    const CPUID: *const u32 = 0xE000_ED00 as *const u32;
+
+    /// The System Handler Control and State Register
+    const SHCSR: *mut u32 = 0xE000_ED24 as *mut u32;
+
+    /// The MPU Type Register
+    const MPU_TYPE: *const u32 = 0xE000_ED90 as *const u32;
+
+    /// The MPU Control Register
+    const MPU_CTRL: *mut u32 = 0xE000_ED94 as *mut u32;
+
+    /// The MPU Region Number Register
+    const MPU_RNR: *mut u32 = 0xE000_ED98 as *mut u32;
+
+    /// The MPU Region Base Address Register
+    const MPU_RBAR: *mut u32 = 0xE000_ED9C as *mut u32;
+
+    /// The MPU Region Attribute and Size Register
+    const MPU_RASR: *mut u32 = 0xE000_EDA0 as *mut u32;
+
+    /// Which region covers the OS's flash
+    const FLASH_REGION: u32 = 0;
+
+    /// Which region covers the TPA
+    const TPA_REGION: u32 = 1;
+
+    /// Turns on the MPU, with the default memory map behind our regions
+    const CTRL_ENABLE_WITH_DEFAULT_MAP: u32 = 0b101;
+
+    /// Never execute from this region
+    const RASR_XN: u32 = 1 << 28;
+
+    /// Read-only, for everyone
+    const RASR_AP_READ_ONLY: u32 = 0b110 << 24;
+
+    /// Read-write, for everyone
+    const RASR_AP_READ_WRITE: u32 = 0b011 << 24;
+
+    /// Normal, write-through memory
+    const RASR_FLASH_ATTRS: u32 = 0b000_0_1_0 << 16;
+
+    /// Normal, shareable, write-through memory
+    const RASR_SRAM_ATTRS: u32 = 0b000_1_1_0 << 16;
+
+    /// Turns the region on
+    const RASR_ENABLE: u32 = 1;
+
+    /// Enables the MemManage exception (Armv7-M only)
+    const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+
+    /// What the TPA region's RASR holds, when it's protected. Zero if it
+    /// isn't.
+    static TPA_RASR: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" {
+        // These symbols come from the linker scripts
+        static __sflash: u32;
+        static __eflash: u32;
+    }
+
+    /// Which MPU does this CPU have, if any?
+    enum Kind {
+        /// The Armv6-M or Armv7-M MPU, which we know how to drive
+        Pmsav7 { armv7: bool },
+        /// No MPU, or one we don't know
+        Unsupported,
+    }
+
+    /// Find out what MPU we have
+    fn kind() -> Kind {
+        let mpu_type = unsafe { MPU_TYPE.read_volatile() };
+        let num_regions = (mpu_type >> 8) & 0xFF;
+        if num_regions < 2 {
+            return Kind::Unsupported;
+        }
+        let part_no = (unsafe { CPUID.read_volatile() } >> 4) & 0xFFF;
+        match part_no {
+            // Cortex-M0+ (Cortex-M0 has no MPU)
+            0xC60 => Kind::Pmsav7 { armv7: false },
+            // Cortex-M3, Cortex-M4 and Cortex-M7
+            0xC23 | 0xC24 | 0xC27 => Kind::Pmsav7 { armv7: true },
+            // Armv8-M, or something we haven't heard of
+            _ => Kind::Unsupported,
+        }
+    }
+
+    /// Set up the MPU, if we have one. Says whether we did.
+    pub fn configure(tpa_start: u32, tpa_end: u32) -> bool {
+        let Kind::Pmsav7 { armv7 } = kind() else {
+            return false;
+        };
+        let (flash_start, flash_end) = unsafe { flash_range() };
+        let flash = Region::fit(flash_start, flash_end);
+        let tpa = Region::fit(tpa_start, tpa_end);
+        let tpa_rasr = tpa.map_or(0, |region| {
+            rasr(region) | RASR_XN | RASR_AP_READ_WRITE | RASR_SRAM_ATTRS
+        });
+        unsafe {
+            MPU_CTRL.write_volatile(0);
+            core::arch::asm!("dsb", "isb");
+            // A region that doesn't fit is turned off, in case it was on
+            // before the OS restarted
+            match flash {
+                Some(region) => write_region(
+                    FLASH_REGION,
+                    region.base,
+                    rasr(region) | RASR_AP_READ_ONLY | RASR_FLASH_ATTRS,
+                ),
+                None => write_region(FLASH_REGION, 0, 0),
+            }
+            write_region(TPA_REGION, tpa.map_or(0, |region| region.base), tpa_rasr);
+            if armv7 {
+                // Report MPU faults as MemManage, not HardFault
+                SHCSR.write_volatile(SHCSR.read_volatile() | SHCSR_MEMFAULTENA);
+            }
+            MPU_CTRL.write_volatile(CTRL_ENABLE_WITH_DEFAULT_MAP);
+            core::arch::asm!("dsb", "isb");
+        }
+        TPA_RASR.store(tpa_rasr, Ordering::Relaxed);
+        true
+    }
+
+    /// Let the CPU execute code in the TPA, or stop it.
+    pub fn set_tpa_executable(executable: bool) {
+        let tpa_rasr = TPA_RASR.load(Ordering::Relaxed);
+        if tpa_rasr == 0 {
+            return;
+        }
+        let value = if executable {
+            tpa_rasr & !RASR_XN
+        } else {
+            tpa_rasr
+        };
+        unsafe {
+            MPU_RNR.write_volatile(TPA_REGION);
+            MPU_RASR.write_volatile(value);
+            core::arch::asm!("dsb", "isb");
+        }
+    }
+
+    /// The size and sub-region bits of an RASR value
+    fn rasr(region: Region) -> u32 {
+        (u32::from(region.disabled) << 8) | (u32::from(region.size_log2 - 1) << 1) | RASR_ENABLE
+    }
+
+    /// Where the OS's flash starts and ends
+    unsafe fn flash_range() -> (u32, u32) {
+        // You have to take the address of a linker symbol to find out where
+        // it points
+        (
+            core::ptr::addr_of!(__sflash) as u32,
+            core::ptr::addr_of!(__eflash) as u32,
+        )
+    }
+
+    /// Program one MPU region
+    unsafe fn write_region(number: u32, base: u32, rasr: u32) {
+        MPU_RNR.write_volatile(number);
+        MPU_RBAR.write_volatile(base);
+        MPU_RASR.write_volatile(rasr);
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_block() {
+        assert_eq!(
+            Region::fit(0x2000_0000, 0x2000_1000),
+            Some(Region {
+                base: 0x2000_0000,
+                size_log2: 12,
+                disabled: 0
+            })
+        );
+    }
+
+    #[test]
+    fn uses_sub_regions() {
+        // 64 KiB sub-regions 2, 3 and 4 cover 0x1002_0000..0x1005_0000
+        assert_eq!(
+            Region::fit(0x1002_0000, 0x1005_8000),
+            Some(Region {
+                base: 0x1000_0000,
+                size_log2: 19,
+                disabled: 0b1110_0011
+            })
+        );
+    }
+
+    #[test]
+    fn straddles_blocks() {
+        // 32 KiB sub-regions 3, 4 and 5 cover 0x2001_8000..0x2003_0000,
+        // which is more than any block on either side of 0x2002_0000
+        assert_eq!(
+            Region::fit(0x2001_1000, 0x2003_7000),
+            Some(Region {
+                base: 0x2000_0000,
+                size_log2: 18,
+                disabled: 0b1100_0111
+            })
+        );
+    }
+
+    #[test]
+    fn never_covers_outside() {
+        for (start, end) in [
+            (0x2000_1000, 0x2003_c000),
+            (0x1002_0000, 0x1004_1234),
+            (0x0802_0000, 0x0806_0000),
+            (0xFFFF_0000, 0xFFFF_FF00),
+        ] {
+            let region = Region::fit(start, end).unwrap();
+            let sub_size = (1u64 << region.size_log2) / 8;
+            for sub in 0..8 {
+                if region.disabled & (1 << sub) == 0 {
+                    let sub_start = u64::from(region.base) + sub * sub_size;
+                    assert!(sub_start >= u64::from(start));
+                    assert!(sub_start + sub_size <= u64::from(end));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn too_small() {
+        assert_eq!(Region::fit(0x2000_0010, 0x2000_0030), None);
+    }
+}
+
+// End of file
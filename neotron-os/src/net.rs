@@ -0,0 +1,976 @@
+//! # Networking
+//!
+//! Runs IPv4 over SLIP (RFC 1055) on one of the BIOS UARTs, using smoltcp as
+//! the TCP/IP stack. Set it up with `ifconfig`, then `ping` and `telnet` to
+//! other machines. SLIP has no address discovery, so you give the address
+//! (and the gateway, if there is one) by hand, and only IPv4 addresses work
+//! (there's no DNS).
+//!
+//! Applications open `TCP:<address>:<port>` to make a TCP connection. The
+//! callback table can't grow without a new version of the application API,
+//! so sockets are files, like everything else.
+//!
+//! The stack only runs when [`poll`] is called - from the shell's idle loop,
+//! while an application waits, and while the network commands run.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use smoltcp::{
+    iface, phy,
+    socket::{icmp, tcp},
+    time::{Duration, Instant},
+    wire,
+};
+
+use crate::{bios, program::TransientProgramArea, refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The biggest packet we send or receive
+pub const MTU: usize = 576;
+
+/// How many TCP connections can be open at once
+pub const NUM_TCP: usize = 2;
+
+/// How big each TCP connection's send and receive buffers are
+const TCP_BUFFER_LEN: usize = 1024;
+
+/// How many ping packets we can hold, each way
+const ICMP_PACKETS: usize = 2;
+
+/// How big the ping buffers are, each way
+const ICMP_BUFFER_LEN: usize = 256;
+
+/// The identifier on the pings we send
+const PING_IDENT: u16 = 0x4E4F;
+
+/// What we put in the pings we send
+const PING_DATA: &[u8] = b"Neotron OS ping, 32 bytes long!!";
+
+/// The first port number we connect from
+const FIRST_LOCAL_PORT: u16 = 49152;
+
+/// How long an unanswered TCP connection lasts
+const TCP_TIMEOUT_SECS: u64 = 60;
+
+/// How many bytes we take from the UART each time we're polled
+const MAX_RX_BYTES_PER_POLL: usize = MTU * 2;
+
+/// Marks the end of a SLIP frame
+const SLIP_END: u8 = 0xC0;
+
+/// Marks an escaped byte in a SLIP frame
+const SLIP_ESC: u8 = 0xDB;
+
+/// An escaped [`SLIP_END`]
+const SLIP_ESC_END: u8 = 0xDC;
+
+/// An escaped [`SLIP_ESC`]
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// Telnet's Interpret As Command byte
+const IAC: u8 = 255;
+
+/// Telnet negotiation commands
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+
+/// Telnet sub-negotiation start and end
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Telnet options we're happy for the other end to do
+const OPT_ECHO: u8 = 1;
+const OPT_SUPPRESS_GO_AHEAD: u8 = 3;
+
+/// The network, once it's been set up.
+///
+/// It lives in memory reserved off the top of the TPA (see
+/// [`Net::reserve`]), as it's far too big for OS RAM.
+static NET: CsRefCell<Option<&'static mut Net>> = CsRefCell::new(None);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways networking can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The network hasn't been set up (see `ifconfig`)
+    Down,
+    /// The BIOS wouldn't set the UART up
+    BadPort,
+    /// All the TCP connections are in use
+    NoSockets,
+    /// The connection couldn't be made
+    Unreachable,
+    /// The connection has closed
+    Closed,
+    /// There isn't room in the TPA for the socket buffers
+    NoMemory,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Down => write!(f, "The network is down (try `ifconfig`)"),
+            Error::BadPort => write!(f, "Failed to set up the UART"),
+            Error::NoSockets => write!(f, "Too many connections open"),
+            Error::Unreachable => write!(f, "Can't connect to that address"),
+            Error::Closed => write!(f, "Connection closed"),
+            Error::NoMemory => write!(f, "Not enough free memory for the network"),
+        }
+    }
+}
+
+/// How the network is set up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// Which BIOS serial device we run SLIP on
+    pub port: u8,
+    /// How fast the UART runs
+    pub baud: u32,
+    /// Our address, and the size of our network
+    pub address: wire::Ipv4Cidr,
+    /// Where we send packets for other networks, if anywhere
+    pub gateway: Option<wire::Ipv4Address>,
+}
+
+/// How many packets have come and gone
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Good packets received
+    pub rx_packets: u32,
+    /// Packets sent
+    pub tx_packets: u32,
+    /// Bad packets received (too big for us)
+    pub rx_errors: u32,
+}
+
+/// The state of a TCP connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Still connecting
+    Connecting,
+    /// Connected
+    Connected,
+    /// Closed, or failed to connect
+    Closed,
+}
+
+/// A TCP connection
+///
+/// Dropping it closes the connection.
+#[derive(Debug)]
+pub struct Connection {
+    /// Which of our TCP sockets this is
+    slot: usize,
+}
+
+impl Connection {
+    /// Is this connection still going?
+    pub fn state(&self) -> ConnectionState {
+        with_tcp(self.slot, |socket| match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => ConnectionState::Connecting,
+            _ if socket.may_send() || socket.can_recv() => ConnectionState::Connected,
+            _ => ConnectionState::Closed,
+        })
+        .unwrap_or(ConnectionState::Closed)
+    }
+
+    /// Read whatever has arrived, without waiting
+    ///
+    /// Gives `Err(Error::Closed)` once the connection is closed and there's
+    /// nothing left to read.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        poll();
+        with_tcp(self.slot, |socket| {
+            if socket.can_recv() {
+                socket.recv_slice(buffer).map_err(|_e| Error::Closed)
+            } else if socket.may_recv()
+                || matches!(
+                    socket.state(),
+                    tcp::State::SynSent | tcp::State::SynReceived
+                )
+            {
+                Ok(0)
+            } else {
+                Err(Error::Closed)
+            }
+        })?
+    }
+
+    /// Send all of some data, waiting while the connection is made and for
+    /// space in the send buffer
+    pub fn write(&self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            poll();
+            let sent = with_tcp(self.slot, |socket| match socket.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => Ok(0),
+                _ if !socket.may_send() => Err(Error::Closed),
+                _ => socket.send_slice(data).map_err(|_e| Error::Closed),
+            })??;
+            data = &data[sent..];
+            if sent == 0 {
+                // The network is slow, but we haven't hung
+                crate::watchdog::kick();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut guard = NET.lock();
+        if let Some(net) = guard.as_deref_mut() {
+            if let Some(slot) = net.tcp.get_mut(self.slot) {
+                net.sockets.get_mut::<tcp::Socket>(slot.handle).close();
+                slot.in_use = false;
+            }
+        }
+    }
+}
+
+/// Turns a stream of SLIP bytes back into packets
+pub struct SlipDecoder {
+    /// The packet so far
+    buffer: [u8; MTU],
+    /// How much of `buffer` is used
+    len: usize,
+    /// Was the last byte an escape?
+    escaped: bool,
+    /// Was this packet too big to fit?
+    overflow: bool,
+    /// How many packets were too big
+    errors: u32,
+}
+
+impl SlipDecoder {
+    /// Make a decoder, waiting for the start of a packet
+    pub const fn new() -> SlipDecoder {
+        SlipDecoder {
+            buffer: [0; MTU],
+            len: 0,
+            escaped: false,
+            overflow: false,
+            errors: 0,
+        }
+    }
+
+    /// Give the decoder a byte. When a whole packet has arrived, you get
+    /// its length back, and it's at the start of [`SlipDecoder::packet`]
+    /// until the next byte goes in.
+    pub fn feed(&mut self, byte: u8) -> Option<usize> {
+        if byte == SLIP_END {
+            let len = core::mem::take(&mut self.len);
+            let overflow = core::mem::take(&mut self.overflow);
+            self.escaped = false;
+            if overflow {
+                self.errors = self.errors.wrapping_add(1);
+                return None;
+            }
+            // Empty packets are just line noise (or the start of a packet)
+            return if len > 0 { Some(len) } else { None };
+        }
+        if byte == SLIP_ESC {
+            self.escaped = true;
+            return None;
+        }
+        let byte = match (self.escaped, byte) {
+            (true, SLIP_ESC_END) => SLIP_END,
+            (true, SLIP_ESC_ESC) => SLIP_ESC,
+            _ => byte,
+        };
+        self.escaped = false;
+        match self.buffer.get_mut(self.len) {
+            Some(b) => {
+                *b = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+        None
+    }
+
+    /// The buffer the packets go in
+    pub fn packet(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+/// Takes the Telnet commands out of the incoming bytes, and refuses all the
+/// options we don't understand.
+pub struct TelnetFilter {
+    /// Where we are in a command
+    state: TelnetState,
+}
+
+/// Where we are in a Telnet command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TelnetState {
+    /// Ordinary text
+    Data,
+    /// Just had an IAC
+    Iac,
+    /// Just had IAC and a negotiation command, so the option comes next
+    Negotiate(u8),
+    /// In a sub-negotiation
+    Sub,
+    /// Had an IAC in a sub-negotiation
+    SubIac,
+}
+
+impl TelnetFilter {
+    /// Make a filter, expecting ordinary text
+    pub fn new() -> TelnetFilter {
+        TelnetFilter {
+            state: TelnetState::Data,
+        }
+    }
+
+    /// Give the filter a byte. Gives back the byte if it's text, and calls
+    /// `reply` with anything we need to send back.
+    pub fn feed<F>(&mut self, byte: u8, mut reply: F) -> Option<u8>
+    where
+        F: FnMut([u8; 3]),
+    {
+        match (self.state, byte) {
+            (TelnetState::Data, IAC) => {
+                self.state = TelnetState::Iac;
+                None
+            }
+            (TelnetState::Data, b) => Some(b),
+            (TelnetState::Iac, IAC) => {
+                // An escaped 255
+                self.state = TelnetState::Data;
+                Some(IAC)
+            }
+            (TelnetState::Iac, WILL | WONT | DO | DONT) => {
+                self.state = TelnetState::Negotiate(byte);
+                None
+            }
+            (TelnetState::Iac, SB) => {
+                self.state = TelnetState::Sub;
+                None
+            }
+            (TelnetState::Iac, _) => {
+                // Some other command, which we ignore
+                self.state = TelnetState::Data;
+                None
+            }
+            (TelnetState::Negotiate(command), option) => {
+                self.state = TelnetState::Data;
+                match command {
+                    DO => reply([IAC, WONT, option]),
+                    WILL if option == OPT_ECHO || option == OPT_SUPPRESS_GO_AHEAD => {
+                        reply([IAC, DO, option])
+                    }
+                    WILL => reply([IAC, DONT, option]),
+                    // They're turning something off, which is fine by us
+                    _ => {}
+                }
+                None
+            }
+            (TelnetState::Sub, IAC) => {
+                self.state = TelnetState::SubIac;
+                None
+            }
+            (TelnetState::Sub, _) => None,
+            (TelnetState::SubIac, SE) => {
+                self.state = TelnetState::Data;
+                None
+            }
+            (TelnetState::SubIac, _) => {
+                self.state = TelnetState::Sub;
+                None
+            }
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// The memory the sockets use
+struct Buffers {
+    /// Where the socket set keeps the sockets
+    sockets: [iface::SocketStorage<'static>; NUM_TCP + 1],
+    /// Receive buffers for the TCP sockets
+    tcp_rx: [[u8; TCP_BUFFER_LEN]; NUM_TCP],
+    /// Send buffers for the TCP sockets
+    tcp_tx: [[u8; TCP_BUFFER_LEN]; NUM_TCP],
+    /// Packet details for the ping socket (receive and send)
+    icmp_meta: [[icmp::PacketMetadata; ICMP_PACKETS]; 2],
+    /// Packet contents for the ping socket (receive and send)
+    icmp_data: [[u8; ICMP_BUFFER_LEN]; 2],
+}
+
+/// Everything the network needs, all in one piece of the TPA
+struct NetMemory {
+    /// The memory the sockets use
+    buffers: Buffers,
+    /// The stack, which uses `buffers`
+    net: Net,
+}
+
+/// One of our TCP sockets
+struct TcpSlot {
+    /// Where smoltcp keeps it
+    handle: iface::SocketHandle,
+    /// Does a [`Connection`] own it?
+    in_use: bool,
+}
+
+/// Everything the network needs
+struct Net {
+    /// How it's set up
+    settings: Settings,
+    /// Is it running?
+    up: bool,
+    /// Packets in and out of the UART
+    device: SlipDevice,
+    /// The TCP/IP stack
+    iface: iface::Interface,
+    /// All our sockets
+    sockets: iface::SocketSet<'static>,
+    /// Our TCP sockets
+    tcp: heapless::Vec<TcpSlot, NUM_TCP>,
+    /// Our ping socket
+    icmp: iface::SocketHandle,
+    /// The port the next connection comes from
+    next_port: u16,
+}
+
+/// Sends and receives packets over SLIP
+struct SlipDevice {
+    /// Which BIOS serial device we use
+    port: u8,
+    /// Puts the incoming packets back together
+    decoder: SlipDecoder,
+    /// Good packets received
+    rx_packets: u32,
+    /// Packets sent
+    tx_packets: u32,
+}
+
+impl phy::Device for SlipDevice {
+    type RxToken<'a> = SlipRxToken<'a>;
+    type TxToken<'a> = SlipTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(SlipRxToken<'_>, SlipTxToken<'_>)> {
+        let api = API.get();
+        for _ in 0..MAX_RX_BYTES_PER_POLL {
+            let mut byte = [0u8; 1];
+            let result: Result<usize, bios::Error> = (api.serial_read)(
+                self.port,
+                bios::FfiBuffer::new(&mut byte),
+                bios::FfiOption::Some(bios::Timeout::new_ms(0)),
+            )
+            .into();
+            if result != Ok(1) {
+                break;
+            }
+            if let Some(len) = self.decoder.feed(byte[0]) {
+                self.rx_packets = self.rx_packets.wrapping_add(1);
+                let rx = SlipRxToken {
+                    packet: &mut self.decoder.packet()[0..len],
+                };
+                let tx = SlipTxToken {
+                    port: self.port,
+                    count: &mut self.tx_packets,
+                };
+                return Some((rx, tx));
+            }
+        }
+        None
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<SlipTxToken<'_>> {
+        Some(SlipTxToken {
+            port: self.port,
+            count: &mut self.tx_packets,
+        })
+    }
+
+    fn capabilities(&self) -> phy::DeviceCapabilities {
+        let mut caps = phy::DeviceCapabilities::default();
+        caps.medium = phy::Medium::Ip;
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+}
+
+/// A packet that has arrived
+struct SlipRxToken<'a> {
+    /// The packet
+    packet: &'a mut [u8],
+}
+
+impl phy::RxToken for SlipRxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(self.packet)
+    }
+}
+
+/// Permission to send a packet
+struct SlipTxToken<'a> {
+    /// Which BIOS serial device we use
+    port: u8,
+    /// How many packets we've sent
+    count: &'a mut u32,
+}
+
+impl phy::TxToken for SlipTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = [0u8; MTU];
+        let packet = &mut buffer[0..len.min(MTU)];
+        let result = f(packet);
+        slip_encode(packet, |chunk| write_all(self.port, chunk));
+        *self.count = self.count.wrapping_add(1);
+        result
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Start the network with the given settings, or stop it with `None`
+///
+/// The first time it starts, the whole network stack is reserved off the top
+/// of the TPA.
+pub fn configure(tpa: &mut TransientProgramArea, settings: Option<Settings>) -> Result<(), Error> {
+    let mut guard = NET.lock();
+    let Some(settings) = settings else {
+        if let Some(net) = guard.as_deref_mut() {
+            net.up = false;
+            for slot in net.tcp.iter_mut() {
+                net.sockets.get_mut::<tcp::Socket>(slot.handle).abort();
+            }
+        }
+        return Ok(());
+    };
+
+    let api = API.get();
+    let serial_config = bios::serial::Config {
+        data_rate_bps: settings.baud,
+        data_bits: bios::serial::DataBits::Eight.make_ffi_safe(),
+        stop_bits: bios::serial::StopBits::One.make_ffi_safe(),
+        parity: bios::serial::Parity::None.make_ffi_safe(),
+        handshaking: bios::serial::Handshaking::None.make_ffi_safe(),
+    };
+    if let bios::ApiResult::Err(_e) = (api.serial_configure)(settings.port, serial_config) {
+        return Err(Error::BadPort);
+    }
+
+    let net: &mut Net = match guard.as_deref_mut() {
+        Some(net) => net,
+        None => guard.insert(Net::reserve(tpa, settings.port).ok_or(Error::NoMemory)?),
+    };
+    net.settings = settings;
+    net.device.port = settings.port;
+    net.iface.update_ip_addrs(|addrs| {
+        addrs.clear();
+        // We only ever have one address
+        let _ = addrs.push(wire::IpCidr::Ipv4(settings.address));
+    });
+    let routes = net.iface.routes_mut();
+    routes.remove_default_ipv4_route();
+    if let Some(gateway) = settings.gateway {
+        // There's room for the one route we have
+        let _ = routes.add_default_ipv4_route(gateway);
+    }
+    net.up = true;
+    Ok(())
+}
+
+/// How is the network set up, and how much has it done?
+///
+/// Gives `None` if the network is down.
+pub fn status() -> Option<(Settings, Stats)> {
+    let guard = NET.lock();
+    let net = guard.as_deref().filter(|net| net.up)?;
+    let stats = Stats {
+        rx_packets: net.device.rx_packets,
+        tx_packets: net.device.tx_packets,
+        rx_errors: net.device.decoder.errors,
+    };
+    Some((net.settings, stats))
+}
+
+/// Send and receive whatever packets need sending and receiving
+pub fn poll() {
+    let mut guard = NET.lock();
+    let Some(net) = guard.as_deref_mut().filter(|net| net.up) else {
+        return;
+    };
+    net.iface.poll(now(), &mut net.device, &mut net.sockets);
+}
+
+/// Parse an IPv4 address
+pub fn parse_address(input: &str) -> Option<wire::Ipv4Address> {
+    input.parse().ok()
+}
+
+/// Parse an IPv4 address and port, like `10.0.0.1:23`
+pub fn parse_endpoint(input: &str) -> Option<wire::IpEndpoint> {
+    let endpoint: wire::IpEndpoint = input.parse().ok()?;
+    if endpoint.port == 0 {
+        None
+    } else {
+        Some(endpoint)
+    }
+}
+
+/// Start connecting to a TCP server
+///
+/// This doesn't wait for the connection to be made - check
+/// [`Connection::state`].
+pub fn connect(remote: wire::IpEndpoint) -> Result<Connection, Error> {
+    let mut guard = NET.lock();
+    let net = guard
+        .as_deref_mut()
+        .filter(|net| net.up)
+        .ok_or(Error::Down)?;
+    let local_port = net.next_port;
+    net.next_port = net.next_port.checked_add(1).unwrap_or(FIRST_LOCAL_PORT);
+    let (slot_idx, slot) = net
+        .tcp
+        .iter_mut()
+        .enumerate()
+        .find(|(_idx, slot)| !slot.in_use)
+        .ok_or(Error::NoSockets)?;
+    let socket = net.sockets.get_mut::<tcp::Socket>(slot.handle);
+    // It might still be closing from last time
+    socket.abort();
+    socket.set_timeout(Some(Duration::from_secs(TCP_TIMEOUT_SECS)));
+    socket
+        .connect(net.iface.context(), remote, local_port)
+        .map_err(|_e| Error::Unreachable)?;
+    slot.in_use = true;
+    Ok(Connection { slot: slot_idx })
+}
+
+/// Send a ping (an ICMP Echo Request)
+pub fn ping_send(target: wire::Ipv4Address, seq_no: u16) -> Result<(), Error> {
+    let mut guard = NET.lock();
+    let net = guard
+        .as_deref_mut()
+        .filter(|net| net.up)
+        .ok_or(Error::Down)?;
+    let socket = net.sockets.get_mut::<icmp::Socket>(net.icmp);
+    let repr = wire::Icmpv4Repr::EchoRequest {
+        ident: PING_IDENT,
+        seq_no,
+        data: PING_DATA,
+    };
+    let payload = socket
+        .send(repr.buffer_len(), wire::IpAddress::Ipv4(target))
+        .map_err(|_e| Error::Unreachable)?;
+    let mut packet = wire::Icmpv4Packet::new_unchecked(payload);
+    repr.emit(&mut packet, &phy::ChecksumCapabilities::default());
+    Ok(())
+}
+
+/// See if a ping has come back, giving its sequence number
+pub fn ping_reply() -> Option<u16> {
+    let mut guard = NET.lock();
+    let net = guard.as_deref_mut().filter(|net| net.up)?;
+    let socket = net.sockets.get_mut::<icmp::Socket>(net.icmp);
+    while let Ok((payload, _from)) = socket.recv() {
+        let Ok(packet) = wire::Icmpv4Packet::new_checked(payload) else {
+            continue;
+        };
+        if let Ok(wire::Icmpv4Repr::EchoReply {
+            ident: PING_IDENT,
+            seq_no,
+            ..
+        }) = wire::Icmpv4Repr::parse(&packet, &phy::ChecksumCapabilities::default())
+        {
+            return Some(seq_no);
+        }
+    }
+    None
+}
+
+/// SLIP-encode a packet, giving the encoded bytes to `out` a chunk at a time
+pub fn slip_encode<F>(packet: &[u8], mut out: F)
+where
+    F: FnMut(&[u8]),
+{
+    let mut chunk: heapless::Vec<u8, 64> = heapless::Vec::new();
+    // Start with an END, to flush out any line noise
+    let _ = chunk.push(SLIP_END);
+    for b in packet {
+        if chunk.len() > chunk.capacity() - 2 {
+            out(&chunk);
+            chunk.clear();
+        }
+        // We always leave room for two bytes
+        let _ = match *b {
+            SLIP_END => chunk.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => chunk.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            b => chunk.push(b).map_err(|_| ()),
+        };
+    }
+    if chunk.len() == chunk.capacity() {
+        out(&chunk);
+        chunk.clear();
+    }
+    let _ = chunk.push(SLIP_END);
+    out(&chunk);
+}
+
+/// Unlock the network, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    NET.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+impl Net {
+    /// Make the stack and its sockets, in memory reserved off the top of the
+    /// TPA.
+    ///
+    /// The memory is never given back. Gives `None` if there isn't room.
+    fn reserve(tpa: &mut TransientProgramArea, port: u8) -> Option<&'static mut Net> {
+        let align = core::mem::align_of::<NetMemory>();
+        let memory = tpa.reserve("Network", core::mem::size_of::<NetMemory>() + align)?;
+        let offset = memory.as_ptr().align_offset(align);
+        let memory = memory[offset..].as_mut_ptr() as *mut NetMemory;
+        // # Safety
+        //
+        // The memory is big enough and suitably aligned, and it's ours for
+        // ever. We fill in every field of the buffers (in place, as they're
+        // too big for the stack) before we make a reference to them, and the
+        // stack only borrows the buffers, which sit beside it.
+        unsafe {
+            let buffers = core::ptr::addr_of_mut!((*memory).buffers);
+            let sockets = core::ptr::addr_of_mut!((*buffers).sockets);
+            for idx in 0..NUM_TCP + 1 {
+                sockets
+                    .cast::<iface::SocketStorage>()
+                    .add(idx)
+                    .write(iface::SocketStorage::EMPTY);
+            }
+            let icmp_meta = core::ptr::addr_of_mut!((*buffers).icmp_meta);
+            for idx in 0..ICMP_PACKETS * 2 {
+                icmp_meta
+                    .cast::<icmp::PacketMetadata>()
+                    .add(idx)
+                    .write(icmp::PacketMetadata::EMPTY);
+            }
+            core::ptr::addr_of_mut!((*buffers).tcp_rx).write_bytes(0, 1);
+            core::ptr::addr_of_mut!((*buffers).tcp_tx).write_bytes(0, 1);
+            core::ptr::addr_of_mut!((*buffers).icmp_data).write_bytes(0, 1);
+            let net = core::ptr::addr_of_mut!((*memory).net);
+            net.write(Net::new(port, &mut *buffers));
+            Some(&mut *net)
+        }
+    }
+
+    /// Set up the stack and the sockets, using the given memory
+    fn new(port: u8, buffers: &'static mut Buffers) -> Net {
+        let mut device = SlipDevice {
+            port,
+            decoder: SlipDecoder::new(),
+            rx_packets: 0,
+            tx_packets: 0,
+        };
+        let mut config = iface::Config::new(wire::HardwareAddress::Ip);
+        config.random_seed = (API.get().time_ticks_get)().0;
+        let iface = iface::Interface::new(config, &mut device, now());
+        let mut sockets = iface::SocketSet::new(&mut buffers.sockets[..]);
+        let mut tcp = heapless::Vec::new();
+        for (rx, tx) in buffers.tcp_rx.iter_mut().zip(buffers.tcp_tx.iter_mut()) {
+            let socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(&mut rx[..]),
+                tcp::SocketBuffer::new(&mut tx[..]),
+            );
+            let handle = sockets.add(socket);
+            // There's one slot per buffer
+            let _ = tcp.push(TcpSlot {
+                handle,
+                in_use: false,
+            });
+        }
+        let [icmp_rx_meta, icmp_tx_meta] = &mut buffers.icmp_meta;
+        let [icmp_rx_data, icmp_tx_data] = &mut buffers.icmp_data;
+        let mut icmp = icmp::Socket::new(
+            icmp::PacketBuffer::new(&mut icmp_rx_meta[..], &mut icmp_rx_data[..]),
+            icmp::PacketBuffer::new(&mut icmp_tx_meta[..], &mut icmp_tx_data[..]),
+        );
+        // A fresh socket can always be bound
+        let _ = icmp.bind(icmp::Endpoint::Ident(PING_IDENT));
+        let icmp = sockets.add(icmp);
+        Net {
+            settings: Settings {
+                port,
+                baud: 0,
+                address: wire::Ipv4Cidr::new(wire::Ipv4Address::UNSPECIFIED, 0),
+                gateway: None,
+            },
+            up: false,
+            device,
+            iface,
+            sockets,
+            tcp,
+            icmp,
+            next_port: FIRST_LOCAL_PORT,
+        }
+    }
+}
+
+/// Do something with one of our TCP sockets
+fn with_tcp<F, T>(slot: usize, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut tcp::Socket<'static>) -> T,
+{
+    let mut guard = NET.lock();
+    let net = guard
+        .as_deref_mut()
+        .filter(|net| net.up)
+        .ok_or(Error::Down)?;
+    let handle = net.tcp.get(slot).ok_or(Error::Closed)?.handle;
+    Ok(f(net.sockets.get_mut::<tcp::Socket>(handle)))
+}
+
+/// The time now, as smoltcp likes it
+fn now() -> Instant {
+    Instant::from_millis(crate::API.get_uptime().as_millis() as i64)
+}
+
+/// Send all of some bytes on a UART
+///
+/// If the UART fails, the packet is lost, and TCP sends it again.
+fn write_all(port: u8, mut data: &[u8]) {
+    let api = API.get();
+    while !data.is_empty() {
+        let result: Result<usize, bios::Error> =
+            (api.serial_write)(port, bios::FfiByteSlice::new(data), bios::FfiOption::None).into();
+        let Ok(count) = result else {
+            return;
+        };
+        data = &data[count..];
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(packet: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        slip_encode(packet, |chunk| out.extend_from_slice(chunk));
+        out
+    }
+
+    #[test]
+    fn slip_escapes() {
+        assert_eq!(
+            encode(&[1, SLIP_END, 2, SLIP_ESC, 3]),
+            [
+                SLIP_END,
+                1,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                2,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                3,
+                SLIP_END
+            ]
+        );
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        let packet: Vec<u8> = (0..=255u8).chain(0..=255u8).collect();
+        let encoded = encode(&packet);
+        let mut decoder = SlipDecoder::new();
+        let mut lengths = Vec::new();
+        for b in encoded {
+            if let Some(len) = decoder.feed(b) {
+                lengths.push(len);
+                assert_eq!(&decoder.packet()[0..len], &packet[..]);
+            }
+        }
+        assert_eq!(lengths, [packet.len()]);
+    }
+
+    #[test]
+    fn slip_too_big() {
+        let mut decoder = SlipDecoder::new();
+        for _ in 0..MTU + 1 {
+            assert_eq!(decoder.feed(0x45), None);
+        }
+        assert_eq!(decoder.feed(SLIP_END), None);
+        assert_eq!(decoder.errors, 1);
+        // The next packet is fine
+        decoder.feed(0x45);
+        assert_eq!(decoder.feed(SLIP_END), Some(1));
+    }
+
+    fn filter(input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut filter = TelnetFilter::new();
+        let mut text = Vec::new();
+        let mut replies = Vec::new();
+        for b in input {
+            if let Some(b) = filter.feed(*b, |reply| replies.extend_from_slice(&reply)) {
+                text.push(b);
+            }
+        }
+        (text, replies)
+    }
+
+    #[test]
+    fn telnet_negotiation() {
+        let (text, replies) = filter(&[
+            b'h', IAC, DO, 24, b'i', IAC, WILL, OPT_ECHO, IAC, WILL, 31, IAC, IAC,
+        ]);
+        assert_eq!(text, [b'h', b'i', IAC]);
+        assert_eq!(replies, [IAC, WONT, 24, IAC, DO, OPT_ECHO, IAC, DONT, 31]);
+    }
+
+    #[test]
+    fn telnet_subnegotiation() {
+        let (text, replies) = filter(&[IAC, SB, 24, 1, IAC, IAC, IAC, SE, b'!']);
+        assert_eq!(text, [b'!']);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = parse_endpoint("10.0.0.2:23").unwrap();
+        assert_eq!(endpoint.port, 23);
+        assert_eq!(
+            endpoint.addr,
+            wire::IpAddress::Ipv4(wire::Ipv4Address::new(10, 0, 0, 2))
+        );
+        assert!(parse_endpoint("10.0.0.2:0").is_none());
+        assert!(parse_endpoint("10.0.0.2").is_none());
+        assert!(parse_address("10.0.0.256").is_none());
+    }
+}
+
+// End of file
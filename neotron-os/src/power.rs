@@ -0,0 +1,123 @@
+//! # Power Management
+//!
+//! Starts the screensaver when nobody has pressed a key for a while, and
+//! puts the system into standby. Any key stops the screensaver.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{
+    config::ScreenSaver, program::TransientProgramArea, refcell::CsRefCell, screensaver, API,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Our screen blanking state
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    timeout_mins: None,
+    last_activity: 0,
+    saver: ScreenSaver::Blank,
+});
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we know about the screen blanking
+struct State {
+    /// How long we wait for a key before blanking the screen, if at all
+    timeout_mins: Option<u8>,
+    /// When (in ticks) we last saw a key pressed
+    last_activity: u64,
+    /// What we show once the screen blanks
+    saver: ScreenSaver,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Set how many minutes without a key press blanks the screen (or `None` to
+/// never blank it).
+pub fn configure_blank(timeout_mins: Option<u8>) {
+    let mut state = STATE.lock();
+    state.timeout_mins = timeout_mins;
+    state.last_activity = now();
+}
+
+/// Set what we show once the screen blanks
+pub fn configure_saver(saver: ScreenSaver) {
+    STATE.lock().saver = saver;
+}
+
+/// Note that a key was pressed, and put the screen back if it is blank.
+///
+/// Returns true if the screen was blank, in which case the key should be
+/// ignored.
+pub fn wake(tpa: &mut TransientProgramArea) -> bool {
+    STATE.lock().last_activity = now();
+    screensaver::stop(tpa)
+}
+
+/// Start the screen blanking timer again, without waking the screen.
+///
+/// Call this when we get back from something (like running a program)
+/// that might have taken a while.
+pub fn reset_blank_timer() {
+    STATE.lock().last_activity = now();
+}
+
+/// Start the screensaver, if it has been long enough since a key was
+/// pressed, or draw the next frame of it if it is running.
+///
+/// Only call this when no program is running.
+pub fn poll(tpa: &mut TransientProgramArea) {
+    if screensaver::is_running() {
+        screensaver::poll();
+        return;
+    }
+    let state = STATE.lock();
+    let Some(timeout_mins) = state.timeout_mins else {
+        return;
+    };
+    let api = API.get();
+    let timeout_ticks = u64::from(timeout_mins) * 60 * (api.time_ticks_per_second)().0;
+    if now().saturating_sub(state.last_activity) >= timeout_ticks {
+        screensaver::start(state.saver, tpa);
+    }
+}
+
+/// Blank the screen and idle the CPU until a key is pressed.
+pub fn standby(tpa: &mut TransientProgramArea) {
+    // Blanking uses less power than drawing flames
+    screensaver::stop(tpa);
+    screensaver::start(ScreenSaver::Blank, tpa);
+    loop {
+        let mut buffer = [0u8; 16];
+        // Reading the keyboard also kicks the watchdog
+        if crate::STD_INPUT.lock().get_data(&mut buffer) > 0 {
+            break;
+        }
+        crate::stats::idle();
+    }
+    wake(tpa);
+}
+
+/// Unlock the screen blanking state, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// The time now, in ticks
+fn now() -> u64 {
+    (API.get().time_ticks_get)().0
+}
+
+// End of file
@@ -1,9 +1,13 @@
 //! Program Loading and Execution
 
-use neotron_api::FfiByteSlice;
-
-use crate::{fs, osprintln, refcell::CsRefCell, API, FILESYSTEM};
+use crate::{config::ProgramCheck, fs, lz4, osprintln, refcell::CsRefCell, API, FILESYSTEM};
 
+/// The functions we give to applications.
+///
+/// Applications are started with this table, and an array of arguments. The
+/// first argument is always the path the program was loaded from (like
+/// `/HELLO.ELF`, or `ROM:hello` for programs in ROM), and any arguments the
+/// user gave come after it.
 #[allow(unused)]
 static CALLBACK_TABLE: neotron_api::Api = neotron_api::Api {
     open: api_open,
@@ -45,26 +49,446 @@ pub enum OpenHandle {
     Closed,
     /// Represents the audio device,
     Audio,
+    /// Represents a read-only file in the ROMFS
+    Rom {
+        /// The contents of the file
+        contents: &'static [u8],
+        /// How far through the file we have read
+        offset: usize,
+        /// When the file was created
+        ctime: neotron_api::file::Time,
+    },
+    /// Represents the framebuffer device
+    Gfx(GfxDevice),
+    /// Represents one of the virtual consoles (0 is the main console)
+    Console(usize),
+    /// Represents the system clock device
+    Clock,
+    /// Represents an overlay file, which the program can load into its own
+    /// memory
+    Overlay(fs::File),
+    /// Represents the game controllers
+    Pad,
+    /// Represents a card in a Neotron Bus slot, by peripheral ID
+    Bus(u8),
+    /// Represents the MIDI port, by BIOS serial device number
+    Midi(u8),
+    /// Represents the printer, which the program has to itself
+    Printer(crate::spool::Claim),
+    /// Represents a TCP connection
+    #[cfg(feature = "net")]
+    Tcp(crate::net::Connection),
+    /// Represents the system information device, or one of its files
+    Sys {
+        /// Which file, or `None` for the device itself
+        entry: Option<crate::sysfs::Entry>,
+        /// How far through the file we have read
+        offset: usize,
+    },
+}
+
+/// The state held by an open `GFX:` device
+pub struct GfxDevice {
+    /// The mode we were in when the device was opened
+    old_mode: neotron_common_bios::video::Mode,
+    /// The framebuffer we were using when the device was opened
+    old_ptr: *mut u32,
+    /// Has the application changed the video mode?
+    mode_changed: bool,
+    /// Where the next write goes, as a byte offset into the framebuffer
+    offset: usize,
+    /// Does this device hold the OS-managed video memory?
+    owns_vram: bool,
+    /// The buffer being drawn into, when double-buffering (otherwise null)
+    back_buffer: *mut u32,
+}
+
+impl GfxDevice {
+    /// Open the device, remembering the current video mode
+    fn new() -> GfxDevice {
+        let api = API.get();
+        GfxDevice {
+            old_mode: (api.video_get_mode)(),
+            old_ptr: (api.video_get_framebuffer)(),
+            mode_changed: false,
+            offset: 0,
+            owns_vram: false,
+            back_buffer: core::ptr::null_mut(),
+        }
+    }
+
+    /// Get the framebuffer we are drawing into as a slice of bytes
+    ///
+    /// This is the back buffer if we are double-buffering, otherwise it's
+    /// whatever is on screen.
+    fn framebuffer(&mut self) -> Option<&mut [u8]> {
+        let api = API.get();
+        let mode = (api.video_get_mode)();
+        let ptr = if self.back_buffer.is_null() {
+            (api.video_get_framebuffer)()
+        } else {
+            self.back_buffer
+        };
+        if ptr.is_null() {
+            None
+        } else {
+            // # Safety
+            //
+            // The BIOS says the framebuffer is here, and is this big.
+            Some(unsafe {
+                core::slice::from_raw_parts_mut(ptr as *mut u8, mode.frame_size_bytes())
+            })
+        }
+    }
+
+    /// Change video mode, using one or two buffers of OS-managed video memory.
+    ///
+    /// Returns the address of the buffer to draw into.
+    fn set_mode_with_vram(
+        &mut self,
+        mode: neotron_common_bios::video::Mode,
+        num_buffers: usize,
+    ) -> Result<*mut u32, neotron_api::Error> {
+        let api = API.get();
+        if !(api.video_is_valid_mode)(mode) || !(1..=2).contains(&num_buffers) {
+            return Err(neotron_api::Error::InvalidArg);
+        }
+        let mut vram = VRAM_POOL.lock();
+        if self.owns_vram {
+            // Get off the old buffer before we give it back
+            unsafe {
+                let _ = (api.video_set_mode)(self.old_mode, self.old_ptr);
+            }
+            vram.release();
+            self.owns_vram = false;
+            self.back_buffer = core::ptr::null_mut();
+        }
+        let frame_size = mode.frame_size_bytes();
+        let Some(front) = vram.allocate(frame_size * num_buffers) else {
+            return Err(neotron_api::Error::OutOfMemory);
+        };
+        self.owns_vram = true;
+        // # Safety
+        //
+        // We just allocated this memory, and it is big enough for the mode.
+        match unsafe { (api.video_set_mode)(mode, front) } {
+            neotron_common_bios::FfiResult::Ok(_) => {
+                self.mode_changed = true;
+                self.offset = 0;
+                if num_buffers == 2 {
+                    self.back_buffer = unsafe { front.add(frame_size / 4) };
+                    Ok(self.back_buffer)
+                } else {
+                    Ok(front)
+                }
+            }
+            neotron_common_bios::FfiResult::Err(_) => {
+                vram.release();
+                self.owns_vram = false;
+                Err(neotron_api::Error::DeviceSpecific)
+            }
+        }
+    }
+
+    /// Swap the front and back buffers, during the vertical blanking interval.
+    ///
+    /// Returns the address of the new back buffer.
+    fn flip(&mut self) -> Result<*mut u32, neotron_api::Error> {
+        if self.back_buffer.is_null() {
+            return Err(neotron_api::Error::InvalidArg);
+        }
+        let api = API.get();
+        let mode = (api.video_get_mode)();
+        let front = (api.video_get_framebuffer)();
+        // Wait for the blanking interval, so the swap happens off-screen
+        crate::vsync::wait_for_vsync();
+        // # Safety
+        //
+        // The back buffer is OS-managed video memory, the right size for this mode.
+        match unsafe { (api.video_set_mode)(mode, self.back_buffer) } {
+            neotron_common_bios::FfiResult::Ok(_) => {
+                self.back_buffer = front;
+                self.offset = 0;
+                Ok(front)
+            }
+            neotron_common_bios::FfiResult::Err(_) => Err(neotron_api::Error::DeviceSpecific),
+        }
+    }
+}
+
+impl Drop for GfxDevice {
+    fn drop(&mut self) {
+        if self.mode_changed {
+            // Put the console back the way it was
+            let api = API.get();
+            unsafe {
+                let _ = (api.video_set_mode)(self.old_mode, self.old_ptr);
+            }
+        }
+        if self.owns_vram {
+            VRAM_POOL.lock().release();
+        }
+    }
+}
+
+/// Video memory that the OS hands out to applications.
+///
+/// It is carved off the top of the TPA (below anything the OS has reserved),
+/// but only while a program is running.
+struct VramPool {
+    /// The bottom of the TPA
+    bottom: *mut u32,
+    /// The end of the running program's image - we never allocate below this
+    image_end: *mut u32,
+    /// The top of the TPA
+    top: *mut u32,
+    /// How many words are currently handed out
+    allocated_words: usize,
+}
+
+impl VramPool {
+    /// Create an empty pool, with no memory in it
+    const fn new() -> VramPool {
+        VramPool {
+            bottom: core::ptr::null_mut(),
+            image_end: core::ptr::null_mut(),
+            top: core::ptr::null_mut(),
+            allocated_words: 0,
+        }
+    }
+
+    /// Give the pool some memory to hand out.
+    ///
+    /// The TPA runs from `bottom` to `top`, and the program is loaded at the
+    /// bottom of it, up to `image_end`.
+    fn reset(&mut self, bottom: *mut u32, image_end: *mut u32, top: *mut u32) {
+        self.bottom = bottom;
+        self.image_end = image_end;
+        self.top = top;
+        self.allocated_words = 0;
+    }
+
+    /// Allocate `size` bytes from the top of the pool.
+    ///
+    /// Only one allocation can be active at any one time.
+    fn allocate(&mut self, size: usize) -> Option<*mut u32> {
+        if self.allocated_words != 0 || self.top.is_null() {
+            return None;
+        }
+        let words = size.div_ceil(4);
+        let available = unsafe { self.top.offset_from(self.image_end) };
+        if available <= 0 || words >= available as usize {
+            return None;
+        }
+        self.allocated_words = words;
+        Some(unsafe { self.top.sub(words) })
+    }
+
+    /// Give back the active allocation
+    fn release(&mut self) {
+        self.allocated_words = 0;
+    }
+
+    /// How big the TPA is, in bytes, or zero if no program is running
+    fn tpa_size(&self) -> usize {
+        if self.top.is_null() {
+            return 0;
+        }
+        unsafe { self.top.offset_from(self.bottom) as usize * core::mem::size_of::<u32>() }
+    }
+
+    /// The part of the TPA that isn't handed out as video memory, if a
+    /// program is running.
+    fn free_region(&self) -> Option<LoadRegion> {
+        if self.top.is_null() {
+            return None;
+        }
+        Some(LoadRegion {
+            bottom: self.bottom as u32,
+            top: unsafe { self.top.sub(self.allocated_words) } as u32,
+        })
+    }
 }
 
-/// The open handle table
+/// The open handle tables of whatever is running, innermost last.
 ///
-/// This is indexed by the file descriptors (or handles) that the application
-/// uses. When an application says "write to handle 4", we look at the 4th entry
-/// in here to work out what they are writing to.
+/// A program, or an OS module, gets a fresh table when it starts. When it
+/// ends, its table is thrown away (closing its files), which leaves the
+/// table of whatever started it. The shell opens its own files directly, so
+/// it has no table, and never uses up a program's handles.
+struct HandleTables {
+    tables: heapless::Vec<[OpenHandle; MAX_OPEN_HANDLES], MAX_HANDLE_TABLES>,
+}
+
+impl HandleTables {
+    /// Start with no tables, as the shell does
+    const fn new() -> HandleTables {
+        HandleTables {
+            tables: heapless::Vec::new(),
+        }
+    }
+
+    /// Close everything in the innermost table, and go back to the table
+    /// before it.
+    ///
+    /// If `scrub` is set, the table's memory is wiped too.
+    fn pop(&mut self, scrub: bool) {
+        if let Some(table) = self.tables.last_mut() {
+            *table = [CLOSED_HANDLE; MAX_OPEN_HANDLES];
+            if scrub {
+                scrub_handles(table);
+            }
+        }
+        self.tables.pop();
+    }
+}
+
+/// You can only use the innermost table
+impl core::ops::Deref for HandleTables {
+    type Target = [OpenHandle];
+
+    fn deref(&self) -> &[OpenHandle] {
+        match self.tables.last() {
+            Some(table) => table,
+            None => &[],
+        }
+    }
+}
+
+impl core::ops::DerefMut for HandleTables {
+    fn deref_mut(&mut self) -> &mut [OpenHandle] {
+        match self.tables.last_mut() {
+            Some(table) => table,
+            None => &mut [],
+        }
+    }
+}
+
+/// The video memory available to the running program
+static VRAM_POOL: CsRefCell<VramPool> = CsRefCell::new(VramPool::new());
+
+/// The open handle tables
 ///
-/// The table is initialised when a program is started, and any open files are
-/// closed when the program ends.
-static OPEN_HANDLES: CsRefCell<[OpenHandle; 8]> = CsRefCell::new([
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-    OpenHandle::Closed,
-]);
+/// The innermost table is indexed by the file descriptors (or handles) that
+/// the application uses. When an application says "write to handle 4", we
+/// look at the 4th entry in it to work out what they are writing to.
+static OPEN_HANDLES: CsRefCell<HandleTables> = CsRefCell::new(HandleTables::new());
+
+/// How many programs or OS modules can be running inside each other, each
+/// with its own handle table
+const MAX_HANDLE_TABLES: usize = 2;
+
+/// How many handles stdin, stdout and stderr take up
+const STD_HANDLES: usize = 3;
+
+/// How many devices (like `GFX:` or `PRN:`) a program can have open, on top
+/// of its files
+const MAX_DEVICE_HANDLES: usize = 4;
+
+/// How many handles a program can have open: its standard handles, as many
+/// files as the filesystem can open, and some devices.
+const MAX_OPEN_HANDLES: usize = STD_HANDLES + fs::MAX_FILES + MAX_DEVICE_HANDLES;
+
+/// An empty slot in the open handle table
+const CLOSED_HANDLE: OpenHandle = OpenHandle::Closed;
+
+/// The most arguments the user can pass to a program (not counting the
+/// program path, which we pass first)
+pub const MAX_ARGS: usize = 16;
+
+/// The longest path we can pass to a program as its first argument
+pub const MAX_PATH_LEN: usize = 32;
+
+/// How many pieces of memory the OS can reserve off the top of the TPA -
+/// enough for every OS module, the RAM disk, and a few more
+pub const MAX_RESERVATIONS: usize = 12;
+
+/// Where `e_type` lives in the ELF header
+const ELF_TYPE_OFFSET: u32 = 16;
+
+/// Marks a checksum footer on the end of a program file
+const FOOTER_MAGIC: [u8; 4] = *b"NCRC";
+
+/// A checksum footer is `FOOTER_MAGIC`, then the CRC32 (little-endian) of
+/// everything in the file before the footer
+const FOOTER_LEN: usize = 8;
+
+/// How many words of guard pattern sit between our RAM and the TPA
+const GUARD_WORDS: usize = 32;
+
+/// What the guard is filled with. A program that changes it has probably
+/// overflowed its stack.
+const GUARD_PATTERN: u32 = 0xDEAD_C0DE;
+
+/// The guard between our RAM and the TPA (see the linker scripts)
+#[cfg_attr(
+    all(target_os = "none", target_arch = "arm"),
+    link_section = ".tpa_guard"
+)]
+static mut TPA_GUARD: [u32; GUARD_WORDS] = [0; GUARD_WORDS];
+
+/// How fussy we are about program checksums
+static PROGRAM_CHECK: CsRefCell<ProgramCheck> = CsRefCell::new(ProgramCheck::Lenient);
+
+/// Do we wipe the TPA and the handle table after a program runs, so the
+/// next program can't see what this one left behind?
+static SCRUB_TPA: CsRefCell<bool> = CsRefCell::new(true);
+
+/// The `e_type` of a position-independent executable
+const ET_DYN: u16 = 3;
+
+/// Marks the end of the dynamic table
+const DT_NULL: u32 = 0;
+
+/// Dynamic table entry with the address of the relocations
+const DT_REL: u32 = 17;
+
+/// Dynamic table entry with the size of the relocations, in bytes
+const DT_RELSZ: u32 = 18;
+
+/// Dynamic table entry with the size of each relocation, in bytes
+const DT_RELENT: u32 = 19;
+
+/// A relocation that does nothing
+const R_ARM_NONE: u32 = 0;
+
+/// A relocation that adds the load offset to a word
+const R_ARM_RELATIVE: u32 = 23;
+
+/// Files to use instead of the console for a program's standard streams.
+#[derive(Default)]
+pub struct Redirects {
+    /// Read stdin from this file
+    pub stdin: Option<fs::File>,
+    /// Write stdout to this file
+    pub stdout: Option<fs::File>,
+    /// Write stderr to this file
+    pub stderr: Option<fs::File>,
+}
+
+/// A piece of memory the OS has reserved off the top of the TPA, like the
+/// RAM disk or an OS module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    /// What the memory is for
+    pub name: heapless::String<16>,
+    /// Where the memory starts
+    pub address: usize,
+    /// How big it is, in bytes
+    pub size: usize,
+}
+
+impl Reservation {
+    /// The address just past the end of the memory
+    pub fn end(&self) -> usize {
+        self.address + self.size
+    }
+
+    /// Does this reservation overlap `start..end`?
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        start < self.end() && end > self.address
+    }
+}
 
 /// Ways in which loading a program can fail.
 #[derive(Debug)]
@@ -77,6 +501,44 @@ pub enum Error {
     ElfRom(neotron_loader::Error<neotron_loader::traits::SliceError>),
     /// Tried to run when nothing was loaded
     NothingLoaded,
+    /// Tried to pass more than `MAX_ARGS` arguments
+    TooManyArguments,
+    /// The program won't fit in the TPA
+    ProgramTooLarge,
+    /// A relocation points outside the program
+    BadRelocation,
+    /// The program needs a kind of relocation we can't do
+    UnsupportedRelocation,
+    /// The program is compressed, and we couldn't decompress it
+    Decompress(lz4::Error<crate::fs::Error>),
+    /// The program doesn't match the checksum in its footer
+    BadChecksum,
+    /// The program has no checksum footer, and we're in strict mode
+    NoChecksum,
+    /// An OS module has to be position-independent, and this one isn't
+    NotPositionIndependent,
+    /// The program ran, but it wrote over the guard below the TPA, so it
+    /// probably overflowed its stack
+    StackSmashed,
+    /// The program has to go where the OS has reserved some memory, off the
+    /// top of the TPA
+    Reserved,
+    /// The ELF file is cut short, or its headers don't make sense
+    BadElf,
+    /// Too many programs are running inside each other to start another
+    NestedTooDeep,
+    /// The program's path is longer than [`MAX_PATH_LEN`]
+    PathTooLong,
+}
+
+/// Most errors just print their name, but we say why decompression failed
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Decompress(e) => write!(f, "Decompress: {}", e),
+            _ => write!(f, "{:?}", self),
+        }
+    }
 }
 
 impl From<crate::fs::Error> for Error {
@@ -85,6 +547,12 @@ impl From<crate::fs::Error> for Error {
     }
 }
 
+impl From<lz4::Error<crate::fs::Error>> for Error {
+    fn from(value: lz4::Error<crate::fs::Error>) -> Self {
+        Error::Decompress(value)
+    }
+}
+
 impl From<neotron_loader::Error<crate::fs::Error>> for Error {
     fn from(value: neotron_loader::Error<crate::fs::Error>) -> Self {
         Error::ElfFs(value)
@@ -120,6 +588,11 @@ impl FileSource {
         self.file.read(out_buffer)?;
         Ok(())
     }
+
+    /// Get the file back
+    fn into_file(self) -> crate::fs::File {
+        self.file
+    }
 }
 
 impl neotron_loader::traits::Source for &FileSource {
@@ -157,10 +630,32 @@ impl neotron_loader::traits::Source for &FileSource {
 /// This is a piece of memory that can be used for loading and executing programs.
 ///
 /// Only one program can be executed at a time.
+///
+/// The shell keeps nothing in the TPA while a program is running - its own
+/// state (the menu, the console, any script it is running) is in the OS's
+/// RAM or on disk - so the program gets all of it.
+///
+/// The OS can reserve memory off the top of the TPA to keep for itself (see
+/// [`Self::reserve`]). Reservations can be released in any order. We can't
+/// move a reservation, as whoever made it holds on to its address, so
+/// releasing one leaves a hole that later reservations can go in. The TPA
+/// only grows back once everything below the hole has been released too.
 pub struct TransientProgramArea {
     memory_bottom: *mut u32,
+    /// The top of the memory programs can use, just below the lowest
+    /// reservation
     memory_top: *mut u32,
+    /// The top of the TPA, before anything was reserved
+    memory_end: *mut u32,
+    /// The memory we have reserved off the top, highest first
+    reservations: heapless::Vec<Reservation, MAX_RESERVATIONS>,
     last_entry: u32,
+    /// The address just past the end of the program we last loaded
+    last_end: u32,
+    /// The name of the program we last loaded
+    last_name: heapless::String<16>,
+    /// The path of the program we last loaded
+    last_path: heapless::String<MAX_PATH_LEN>,
 }
 
 extern "C" {
@@ -171,10 +666,16 @@ extern "C" {
 impl TransientProgramArea {
     /// Construct a new [`TransientProgramArea`].
     pub unsafe fn new(start: *mut u32, length_in_bytes: usize) -> TransientProgramArea {
+        let memory_end = start.add(length_in_bytes / core::mem::size_of::<u32>());
         let mut tpa = TransientProgramArea {
             memory_bottom: start,
-            memory_top: start.add(length_in_bytes / core::mem::size_of::<u32>()),
+            memory_top: memory_end,
+            memory_end,
+            reservations: heapless::Vec::new(),
             last_entry: 0,
+            last_end: 0,
+            last_name: heapless::String::new(),
+            last_path: heapless::String::new(),
         };
 
         // You have to take the address of a linker symbol to find out where
@@ -213,182 +714,1218 @@ impl TransientProgramArea {
         }
     }
 
+    /// Reserve some memory off the top of the TPA, for the OS to keep.
+    ///
+    /// Programs can't use it until it's given back with [`Self::release`],
+    /// so anything kept there (like the RAM disk) lasts while programs run.
+    /// The `name` says what it's for, in `lsmem`.
+    ///
+    /// We use the highest gap that's big enough, so a hole left by an
+    /// earlier reservation gets used before the TPA shrinks. Gives `None`
+    /// if there's no room, or if it would leave no TPA.
+    pub fn reserve(&mut self, name: &str, length_in_bytes: usize) -> Option<&'static mut [u8]> {
+        self.reserve_aligned(name, length_in_bytes, core::mem::size_of::<u32>())
+    }
+
+    /// Like [`Self::reserve`], but the memory starts on a multiple of
+    /// `align` bytes, which must be a power of two.
+    pub fn reserve_aligned(
+        &mut self,
+        name: &str,
+        length_in_bytes: usize,
+        align: usize,
+    ) -> Option<&'static mut [u8]> {
+        if self.reservations.is_full() {
+            return None;
+        }
+        let len =
+            length_in_bytes.div_ceil(core::mem::size_of::<u32>()) * core::mem::size_of::<u32>();
+        let align = align.max(core::mem::size_of::<u32>());
+        let (idx, address) = self.find_space(len, align)?;
+        let mut reservation = Reservation {
+            name: heapless::String::new(),
+            address,
+            size: len,
+        };
+        for ch in name.chars() {
+            if reservation.name.push(ch).is_err() {
+                break;
+            }
+        }
+        // Can't fail - we checked there was room
+        let _ = self.reservations.insert(idx, reservation);
+        self.update_top();
+        // # Safety
+        //
+        // This memory is in the TPA, and nothing else will use it until it
+        // is released.
+        Some(unsafe { core::slice::from_raw_parts_mut(address as *mut u8, len) })
+    }
+
+    /// Give back memory from [`Self::reserve`].
+    pub fn release(&mut self, memory: &'static mut [u8]) {
+        let address = memory.as_ptr() as usize;
+        self.reservations.retain(|r| r.address != address);
+        self.update_top();
+    }
+
+    /// The memory reserved off the top of the TPA, highest first
+    pub fn reservations(&self) -> &[Reservation] {
+        &self.reservations
+    }
+
+    /// Find the highest gap that will hold `len` bytes, starting on a
+    /// multiple of `align` bytes.
+    ///
+    /// Gives where in the list the new reservation goes, and its address.
+    fn find_space(&self, len: usize, align: usize) -> Option<(usize, usize)> {
+        let place = |top: usize| top.checked_sub(len).map(|address| address & !(align - 1));
+        let mut top = self.memory_end as usize;
+        for (idx, reservation) in self.reservations.iter().enumerate() {
+            match place(top) {
+                Some(address) if address >= reservation.end() => return Some((idx, address)),
+                _ => {}
+            }
+            top = reservation.address;
+        }
+        // There must be some TPA left to load programs into
+        place(top)
+            .filter(|address| *address > self.memory_bottom as usize)
+            .map(|address| (self.reservations.len(), address))
+    }
+
+    /// Move the top of the TPA up to the lowest reservation
+    fn update_top(&mut self) {
+        self.memory_top = self
+            .reservations
+            .last()
+            .map_or(self.memory_end, |r| r.address as *mut u32);
+    }
+
+    /// Refuse to load a program that was linked to go somewhere between
+    /// `start` and `end`, if we've reserved any of that memory.
+    fn check_reserved(&self, (start, end): (u32, u32)) -> Result<(), Error> {
+        match self
+            .reservations
+            .iter()
+            .find(|r| r.overlaps(start as usize, end as usize))
+        {
+            Some(reservation) => {
+                osprintln!(
+                    "Program needs 0x{:08x}..0x{:08x}, but the {} is there",
+                    start,
+                    end,
+                    reservation.name
+                );
+                Err(Error::Reserved)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Load a program that is in memory into `region`, unless it was
+    /// linked to go where we've reserved memory.
+    ///
+    /// Returns the entry point, and the end of the loaded program.
+    fn load_image(&self, region: LoadRegion, contents: &[u8]) -> Result<(u32, u32), Error> {
+        if image_type(contents) != ET_DYN {
+            let loader = neotron_loader::Loader::new(contents)?;
+            self.check_reserved(load_span(&loader))?;
+        }
+        region.load_image(contents, true)
+    }
+
     /// Size of the TPA in 32-bit words
     fn size_words(&self) -> usize {
         unsafe { self.memory_top.offset_from(self.memory_bottom) as usize }
     }
 
+    /// The TPA, as somewhere to load a program
+    fn region(&self) -> LoadRegion {
+        LoadRegion {
+            bottom: self.memory_bottom as u32,
+            top: self.memory_top as u32,
+        }
+    }
+
     /// Loads a program from disk into the Transient Program Area.
     ///
-    /// The program must be in the Neotron Executable format.
+    /// The program must be in the Neotron Executable format, and may be
+    /// compressed with LZ4.
     pub fn load_program(&mut self, file_name: &str) -> Result<(), Error> {
         osprintln!("Loading /{} from Block Device 0", file_name);
+        let path = program_path("/", file_name)?;
+        // Big programs can take a while to load
+        let _pause = crate::watchdog::pause();
 
         let file = FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly)?;
 
+        let file_len = file.length() as usize;
+        let mut footer = [0u8; FOOTER_LEN];
+        if let Some(footer_start) = file_len.checked_sub(FOOTER_LEN) {
+            file.seek_from_start(footer_start as u32)?;
+            file.read(&mut footer)?;
+        }
+        check_footer(&footer, || {
+            // Use the TPA to read the file in big chunks
+            let buffer = self.as_slice_u8();
+            let mut remaining = file_len - FOOTER_LEN;
+            let mut crc = 0;
+            file.seek_from_start(0)?;
+            while remaining > 0 {
+                let chunk_len = remaining.min(buffer.len());
+                let count = file.read(&mut buffer[0..chunk_len])?;
+                if count == 0 {
+                    break;
+                }
+                crc = crate::crc::crc32_update(crc, &buffer[0..count]);
+                remaining -= count;
+            }
+            Ok(crc)
+        })?;
+
+        let mut magic = [0u8; 4];
+        file.seek_from_start(0)?;
+        file.read(&mut magic)?;
+        if lz4::is_lz4(&magic) {
+            file.seek_from_start(0)?;
+            (self.last_entry, self.last_end) = self.load_compressed(|buffer| file.read(buffer))?;
+            self.set_name(path, file_name);
+            return Ok(());
+        }
+
         let source = FileSource::new(file);
         let loader = neotron_loader::Loader::new(&source)?;
+        let mut e_type = [0u8; 2];
+        source.uncached_read(ELF_TYPE_OFFSET, &mut e_type)?;
+        let e_type = u16::from_le_bytes(e_type);
+        if e_type != ET_DYN {
+            self.check_reserved(load_span(&loader))?;
+        }
 
-        let mut iter = loader.iter_program_headers();
-        while let Some(Ok(ph)) = iter.next() {
-            if ph.p_vaddr() as *mut u32 >= self.memory_bottom
-                && ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD
-            {
-                osprintln!("Loading {} bytes to 0x{:08x}", ph.p_memsz(), ph.p_vaddr());
-                let ram = unsafe {
-                    core::slice::from_raw_parts_mut(ph.p_vaddr() as *mut u8, ph.p_memsz() as usize)
-                };
-                // Zero all of it.
-                for b in ram.iter_mut() {
-                    *b = 0;
-                }
-                // Replace some of those zeros with bytes from disk.
-                if ph.p_filesz() != 0 {
-                    source.uncached_read(ph.p_offset(), &mut ram[0..ph.p_filesz() as usize])?;
-                }
+        (self.last_entry, self.last_end) =
+            self.region()
+                .load_segments(&loader, e_type, true, |offset, buffer| {
+                    source.uncached_read(offset, buffer)?;
+                    Ok(())
+                })?;
+        self.set_name(path, file_name);
+
+        Ok(())
+    }
+
+    /// Prints what we need to know about a program on disk, without loading
+    /// it.
+    ///
+    /// Shows the entry point, the program headers, and whether the loadable
+    /// segments fit in the Transient Program Area.
+    pub fn inspect_program(&self, file_name: &str) -> Result<(), Error> {
+        let file = FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly)?;
+        let mut magic = [0u8; 4];
+        file.read(&mut magic)?;
+        if lz4::is_lz4(&magic) {
+            osprintln!("Compressed with LZ4 - load it to see if it fits");
+            return Ok(());
+        }
+        let source = FileSource::new(file);
+        let loader = neotron_loader::Loader::new(&source)?;
+        let mut e_type = [0u8; 2];
+        source.uncached_read(ELF_TYPE_OFFSET, &mut e_type)?;
+        let relocatable = u16::from_le_bytes(e_type) == ET_DYN;
+
+        let tpa_start = self.memory_bottom as u32;
+        let tpa_end = self.memory_top as u32;
+        let mut needed: Option<(u32, u32)> = None;
+
+        osprintln!("Entry point: 0x{:08x}", loader.e_entry());
+        osprintln!("Type     Offset     VirtAddr   FileSize   MemSize    Flags");
+        for ph in loader.iter_program_headers() {
+            let ph = ph?;
+            let type_name = match ph.p_type() {
+                neotron_loader::ProgramHeader::PT_NULL => "NULL",
+                neotron_loader::ProgramHeader::PT_LOAD => "LOAD",
+                neotron_loader::ProgramHeader::PT_DYNAMIC => "DYNAMIC",
+                neotron_loader::ProgramHeader::PT_INTERP => "INTERP",
+                neotron_loader::ProgramHeader::PT_NOTE => "NOTE",
+                _ => "OTHER",
+            };
+            let flags = ph.p_flags();
+            osprintln!(
+                "{:8} 0x{:08x} 0x{:08x} 0x{:08x} 0x{:08x} {}{}{}",
+                type_name,
+                ph.p_offset(),
+                ph.p_vaddr(),
+                ph.p_filesz(),
+                ph.p_memsz(),
+                if flags & 4 != 0 { 'R' } else { '-' },
+                if flags & 2 != 0 { 'W' } else { '-' },
+                if flags & 1 != 0 { 'X' } else { '-' },
+            );
+            if ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD && ph.p_memsz() != 0 {
+                let start = ph.p_vaddr();
+                let end = start.saturating_add(ph.p_memsz());
+                needed = Some(match needed {
+                    Some((lo, hi)) => (lo.min(start), hi.max(end)),
+                    None => (start, end),
+                });
             }
         }
 
-        self.last_entry = loader.e_entry();
+        osprintln!(
+            "TPA:         0x{:08x}..0x{:08x} ({} bytes)",
+            tpa_start,
+            tpa_end,
+            tpa_end - tpa_start
+        );
+        let Some((lo, hi)) = needed else {
+            osprintln!("Nothing to load!");
+            return Ok(());
+        };
+        osprintln!(
+            "Needs:       0x{:08x}..0x{:08x} ({} bytes)",
+            lo,
+            hi,
+            hi - lo
+        );
+        let entry = loader.e_entry() & !1;
+        if relocatable {
+            // We'll move it to wherever the TPA is
+            if hi - lo > tpa_end - tpa_start {
+                osprintln!(
+                    "Does not fit: {} bytes too large",
+                    (hi - lo) - (tpa_end - tpa_start)
+                );
+            } else {
+                osprintln!("Position-independent - fits in the TPA");
+            }
+        } else if lo < tpa_start {
+            osprintln!("Does not fit: starts below the TPA (mis-linked?)");
+        } else if hi > tpa_end {
+            osprintln!("Does not fit: {} bytes too large", hi - tpa_end);
+        } else if !(lo..hi).contains(&entry) {
+            osprintln!("Fits, but the entry point isn't in a loadable segment");
+        } else {
+            osprintln!("Fits in the TPA");
+        }
 
         Ok(())
     }
 
-    /// Loads a program from disk into the Transient Program Area.
+    /// Loads a program from ROM into the Transient Program Area.
+    ///
+    /// The program must be in the Neotron Executable format, and may be
+    /// compressed with LZ4.
+    #[cfg(feature = "romfs")]
+    pub fn load_rom_program(&mut self, name: &str, contents: &[u8]) -> Result<(), Error> {
+        let path = program_path("ROM:", name)?;
+        let footer_start = contents.len().saturating_sub(FOOTER_LEN);
+        check_footer(&contents[footer_start..], || {
+            Ok(crate::crc::crc32(&contents[0..footer_start]))
+        })?;
+        (self.last_entry, self.last_end) = if lz4::is_lz4(contents) {
+            let mut input = contents;
+            self.load_compressed(|buffer| {
+                let count = input.len().min(buffer.len());
+                buffer[0..count].copy_from_slice(&input[0..count]);
+                input = &input[count..];
+                Ok(count)
+            })?
+        } else {
+            self.load_image(self.region(), contents)?
+        };
+        self.set_name(path, name);
+
+        Ok(())
+    }
+
+    /// Decompress an LZ4-compressed program and load it.
+    ///
+    /// We decompress the ELF file into the top of the TPA, and load the
+    /// program into whatever space is left below it.
+    ///
+    /// Returns the entry point, and the end of the loaded program.
+    fn load_compressed<R>(&mut self, read: R) -> Result<(u32, u32), Error>
+    where
+        R: FnMut(&mut [u8]) -> Result<usize, crate::fs::Error>,
+    {
+        osprintln!("Decompressing...");
+        let tpa = self.as_slice_u8();
+        let len = lz4::decompress(read, tpa)?;
+        // Move it to the top, out of the way of the program
+        let image_start = (tpa.len() - len) & !3;
+        tpa.copy_within(0..len, image_start);
+        let region = LoadRegion {
+            bottom: self.memory_bottom as u32,
+            top: self.memory_bottom as u32 + image_start as u32,
+        };
+        let image = unsafe {
+            core::slice::from_raw_parts((self.memory_bottom as *const u8).add(image_start), len)
+        };
+        self.load_image(region, image)
+    }
+
+    /// Loads an OS module (see [`crate::module`]) into memory reserved off
+    /// the top of the TPA under `name`, and gives its entry point and the
+    /// memory it is in.
+    ///
+    /// Modules are ELF files, like overlays, but they must be
+    /// position-independent.
+    pub fn load_module(
+        &mut self,
+        name: &str,
+        file: fs::File,
+    ) -> Result<(u32, &'static [u8]), Error> {
+        let mut e_type = [0u8; 2];
+        file.seek_from_start(ELF_TYPE_OFFSET)?;
+        file.read(&mut e_type)?;
+        if u16::from_le_bytes(e_type) != ET_DYN {
+            return Err(Error::NotPositionIndependent);
+        }
+        file.seek_from_start(0)?;
+        let (file, size) = overlay_size(file);
+        let size = size? as usize;
+        let memory = self.reserve(name, size).ok_or(Error::ProgramTooLarge)?;
+        let range = memory.as_ptr_range();
+        let region = LoadRegion {
+            bottom: range.start as u32,
+            top: range.end as u32,
+        };
+        let (_file, result) = load_overlay(file, region);
+        match result.map(|(entry, _end)| entry) {
+            Ok(entry) => Ok((entry, memory)),
+            Err(e) => {
+                self.release(memory);
+                Err(e)
+            }
+        }
+    }
+
+    /// Remember the name and path of the program we loaded
+    fn set_name(&mut self, path: heapless::String<MAX_PATH_LEN>, name: &str) {
+        self.last_name.clear();
+        for ch in name.chars() {
+            if self.last_name.push(ch).is_err() {
+                break;
+            }
+        }
+        self.last_path = path;
+    }
+
+    /// Execute a program.
+    ///
+    /// The program gets the path it was loaded from, followed by `args`.
+    ///
+    /// If the program returns, you get `Ok(<exit_code>)`. The program returning
+    /// an exit code that is non-zero is not considered a failure from the point
+    /// of view of this API. You wanted to run a program, and the program was
+    /// run.
+    ///
+    /// Any standard streams in `redirects` go to files instead of the
+    /// console. The files are closed when the program returns.
+    pub fn execute(&mut self, args: &[&str], redirects: Redirects) -> Result<i32, Error> {
+        if self.last_entry == 0 {
+            return Err(Error::NothingLoaded);
+        }
+
+        let mut ffi_args: heapless::Vec<neotron_api::FfiString, { MAX_ARGS + 1 }> =
+            heapless::Vec::new();
+        // This can't fail - the Vec is empty
+        let _ = ffi_args.push(neotron_api::FfiString::new(&self.last_path));
+        for arg in args {
+            ffi_args
+                .push(neotron_api::FfiString::new(arg))
+                .map_err(|_| Error::TooManyArguments)?;
+        }
+
+        // Give the program a fresh set of handles, with the default ones open
+        let mut handles = [CLOSED_HANDLE; MAX_OPEN_HANDLES];
+        handles[0] = redirects.stdin.map_or(OpenHandle::StdIn, OpenHandle::File);
+        handles[1] = redirects
+            .stdout
+            .map_or(OpenHandle::Stdout, OpenHandle::File);
+        handles[2] = redirects
+            .stderr
+            .map_or(OpenHandle::StdErr, OpenHandle::File);
+        if OPEN_HANDLES.lock().tables.push(handles).is_err() {
+            return Err(Error::NestedTooDeep);
+        }
+
+        // Video memory has to go above the program we loaded
+        let image_end = (self.last_end.max(self.memory_bottom as u32) as usize + 3) & !3;
+        VRAM_POOL
+            .lock()
+            .reset(self.memory_bottom, image_end as *mut u32, self.memory_top);
+        crate::statusbar::set_program(Some(&self.last_name));
+
+        // Note the video mode, in case the program changes it
+        let api = API.get();
+        let old_mode = (api.video_get_mode)();
+        let old_framebuffer = (api.video_get_framebuffer)();
+
+        paint_guard();
+        let result = {
+            // The TPA is only executable while the program runs
+            let _executable = crate::mpu::tpa_executable();
+            unsafe {
+                let code: neotron_api::AppStartFn =
+                    ::core::mem::transmute(self.last_entry as *const ());
+                code(&CALLBACK_TABLE, ffi_args.len(), ffi_args.as_ptr())
+            }
+        };
+        drop(ffi_args);
+
+        crate::STD_INPUT.lock().set_events(false);
+
+        // Close any files the program left open, and go back to the handles
+        // of whatever started it
+        let scrub = *SCRUB_TPA.lock();
+        OPEN_HANDLES.lock().pop(scrub);
+
+        VRAM_POOL.lock().reset(
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        );
+
+        // The program may have left the screen in a mess
+        crate::reset_console(old_mode, old_framebuffer);
+        crate::reset_terminal();
+
+        // Don't blank the screen just because the program ran for a while
+        crate::power::reset_blank_timer();
+
+        // The program has gone, so don't leave its data for the next one
+        if scrub {
+            self.as_slice_u32().fill(0);
+        }
+
+        self.last_entry = 0;
+        if !guard_intact() {
+            return Err(Error::StackSmashed);
+        }
+        Ok(result)
+    }
+}
+
+/// Tidy up after a program (or the OS) faulted, and so never finished.
+///
+/// Closes the files of everything that was running, which leaves the
+/// shell's handles as they were before it started anything, takes back the
+/// video memory, and stops the TPA being executable. Call
+/// [`crate::force_unlock_all`] first.
+///
+/// # Safety
+///
+/// Only call this once the code that faulted has been abandoned.
+pub unsafe fn recover() {
+    let mut open_handles = OPEN_HANDLES.lock();
+    while !open_handles.tables.is_empty() {
+        open_handles.pop(false);
+    }
+    drop(open_handles);
+    // The program's `TpaExecutable` was never dropped
+    crate::mpu::tpa_protect();
+    VRAM_POOL.lock().reset(
+        core::ptr::null_mut(),
+        core::ptr::null_mut(),
+        core::ptr::null_mut(),
+    );
+    crate::statusbar::set_program(None);
+}
+
+/// Call some code from an OS module, with the standard streams open, just
+/// as if it were a program.
+///
+/// It gets its own handle table, so it can't see (or use up) the handles of
+/// a program that is running. Any files it leaves open are closed
+/// afterwards. If too much is running inside each other already for it to
+/// have a table, it doesn't run, and you get -1.
+pub fn with_std_handles<F>(f: F) -> i32
+where
+    F: FnOnce(&'static neotron_api::Api) -> i32,
+{
+    let mut handles = [CLOSED_HANDLE; MAX_OPEN_HANDLES];
+    handles[0] = OpenHandle::StdIn;
+    handles[1] = OpenHandle::Stdout;
+    handles[2] = OpenHandle::StdErr;
+    if OPEN_HANDLES.lock().tables.push(handles).is_err() {
+        osprintln!(log: Warn, "{:?}", Error::NestedTooDeep);
+        return -1;
+    }
+
+    let result = f(&CALLBACK_TABLE);
+
+    // This closes anything it left open
+    OPEN_HANDLES.lock().pop(false);
+    crate::reset_terminal();
+    result
+}
+
+/// Unlock the handle tables, the video memory and our settings, even if
+/// someone holds the locks.
+pub unsafe fn force_unlock() {
+    OPEN_HANDLES.force_unlock();
+    VRAM_POOL.force_unlock();
+    PROGRAM_CHECK.force_unlock();
+    SCRUB_TPA.force_unlock();
+}
+
+/// A region of memory we can load code into - either the whole TPA, or the
+/// part of it an application gave us for an overlay.
+#[derive(Debug, Copy, Clone)]
+struct LoadRegion {
+    /// The lowest address we can load to
+    bottom: u32,
+    /// The address just past the end of the region
+    top: u32,
+}
+
+impl LoadRegion {
+    /// Is the `len` bytes at `addr` entirely inside this region?
+    fn contains(&self, addr: u32, len: u32) -> bool {
+        addr >= self.bottom && addr.saturating_add(len) <= self.top
+    }
+
+    /// Load a program that is in memory (outside this region) into this
+    /// region.
     ///
-    /// The program must be in the Neotron Executable format.
-    pub fn load_rom_program(&mut self, contents: &[u8]) -> Result<(), Error> {
+    /// Returns the (possibly moved) entry point, and the address just past
+    /// the end of the loaded program.
+    fn load_image(&self, contents: &[u8], verbose: bool) -> Result<(u32, u32), Error> {
         let loader = neotron_loader::Loader::new(contents)?;
+        self.load_segments(&loader, image_type(contents), verbose, |offset, buffer| {
+            let offset = offset as usize;
+            let source = offset
+                .checked_add(buffer.len())
+                .and_then(|end| contents.get(offset..end))
+                .ok_or(Error::BadElf)?;
+            buffer.copy_from_slice(source);
+            Ok(())
+        })
+    }
+
+    /// Copy a program's loadable segments into this region.
+    ///
+    /// Position-independent programs (`e_type` is `ET_DYN`) are moved to the
+    /// bottom of the region, wherever they were linked, and then relocated.
+    /// Other programs go where they were linked. `read` fetches bytes from
+    /// the ELF file. If `verbose` is set, we say what we're doing.
+    ///
+    /// Returns the (possibly moved) entry point, and the address just past
+    /// the end of the loaded program.
+    fn load_segments<DS, F>(
+        &self,
+        loader: &neotron_loader::Loader<DS>,
+        e_type: u16,
+        verbose: bool,
+        mut read: F,
+    ) -> Result<(u32, u32), Error>
+    where
+        DS: neotron_loader::traits::Source,
+        F: FnMut(u32, &mut [u8]) -> Result<(), Error>,
+    {
+        // How far we move the program from where it was linked
+        let delta = if e_type == ET_DYN {
+            let (link_start, link_end) = load_span(loader);
+            // Keep anything that wants 8-byte alignment happy
+            let load_start = (self.bottom + 7) & !7;
+            if !self.contains(load_start, link_end.saturating_sub(link_start)) {
+                return Err(Error::ProgramTooLarge);
+            }
+            if verbose {
+                osprintln!("Relocating to 0x{:08x}", load_start);
+            }
+            load_start.wrapping_sub(link_start)
+        } else {
+            0
+        };
 
+        let mut dynamic = None;
+        let mut end = self.bottom;
         let mut iter = loader.iter_program_headers();
         while let Some(Ok(ph)) = iter.next() {
-            if ph.p_vaddr() as *mut u32 >= self.memory_bottom
-                && ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD
-            {
-                osprintln!("Loading {} bytes to 0x{:08x}", ph.p_memsz(), ph.p_vaddr());
+            let vaddr = ph.p_vaddr().wrapping_add(delta);
+            if ph.p_type() == neotron_loader::ProgramHeader::PT_DYNAMIC {
+                dynamic = Some((vaddr, ph.p_memsz()));
+            }
+            if ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD && ph.p_memsz() != 0 {
+                // Anything we can't put where it asked to go means the
+                // program won't work, so don't run it.
+                if !self.contains(vaddr, ph.p_memsz()) {
+                    return Err(Error::ProgramTooLarge);
+                }
+                if ph.p_filesz() > ph.p_memsz() {
+                    return Err(Error::BadElf);
+                }
+                if verbose {
+                    osprintln!("Loading {} bytes to 0x{:08x}", ph.p_memsz(), vaddr);
+                }
+                end = end.max(vaddr + ph.p_memsz());
                 let ram = unsafe {
-                    core::slice::from_raw_parts_mut(ph.p_vaddr() as *mut u8, ph.p_memsz() as usize)
+                    core::slice::from_raw_parts_mut(vaddr as *mut u8, ph.p_memsz() as usize)
                 };
                 // Zero all of it.
                 for b in ram.iter_mut() {
                     *b = 0;
                 }
-                // Replace some of those zeros with bytes from disk.
+                // Replace some of those zeros with bytes from the file.
                 if ph.p_filesz() != 0 {
-                    ram[0..ph.p_filesz() as usize].copy_from_slice(
-                        &contents[ph.p_offset() as usize
-                            ..(ph.p_offset() as usize + ph.p_filesz() as usize)],
-                    );
+                    read(ph.p_offset(), &mut ram[0..ph.p_filesz() as usize])?;
                 }
             }
         }
 
-        self.last_entry = loader.e_entry();
+        if let (true, Some(dynamic)) = (e_type == ET_DYN, dynamic) {
+            self.relocate(dynamic, delta)?;
+        }
 
-        Ok(())
+        Ok((loader.e_entry().wrapping_add(delta), end))
     }
 
-    /// Execute a program.
+    /// Apply the relocations in a loaded program's dynamic table.
     ///
-    /// If the program returns, you get `Ok(<exit_code>)`. The program returning
-    /// an exit code that is non-zero is not considered a failure from the point
-    /// of view of this API. You wanted to run a program, and the program was
-    /// run.
-    pub fn execute(&mut self, args: &[&str]) -> Result<i32, Error> {
-        if self.last_entry == 0 {
-            return Err(Error::NothingLoaded);
+    /// A position-independent program with no shared libraries only needs
+    /// `R_ARM_RELATIVE` relocations (for things like its GOT), so those are
+    /// all we support. `dynamic` is the address and length of the dynamic
+    /// table, after loading.
+    fn relocate(&self, dynamic: (u32, u32), delta: u32) -> Result<(), Error> {
+        let (dyn_addr, dyn_len) = dynamic;
+        if !self.contains(dyn_addr, dyn_len) {
+            return Err(Error::BadRelocation);
+        }
+        let mut rel_addr = None;
+        let mut rel_size = 0;
+        let mut rel_entry_size = 8;
+        for idx in 0..(dyn_len / 8) as usize {
+            let entry = (dyn_addr as *const u32).wrapping_add(idx * 2);
+            let (tag, value) = unsafe { (entry.read_unaligned(), entry.add(1).read_unaligned()) };
+            match tag {
+                DT_NULL => break,
+                DT_REL => rel_addr = Some(value.wrapping_add(delta)),
+                DT_RELSZ => rel_size = value,
+                DT_RELENT => rel_entry_size = value,
+                _ => {}
+            }
+        }
+        let Some(rel_addr) = rel_addr else {
+            // Nothing to relocate
+            return Ok(());
+        };
+        if rel_entry_size < 8 || !self.contains(rel_addr, rel_size) {
+            return Err(Error::BadRelocation);
+        }
+
+        for idx in 0..rel_size / rel_entry_size {
+            let entry = rel_addr.wrapping_add(idx * rel_entry_size) as *const u32;
+            let (r_offset, r_info) =
+                unsafe { (entry.read_unaligned(), entry.add(1).read_unaligned()) };
+            match r_info & 0xFF {
+                R_ARM_NONE => {}
+                R_ARM_RELATIVE => {
+                    let target = r_offset.wrapping_add(delta);
+                    if !self.contains(target, 4) {
+                        return Err(Error::BadRelocation);
+                    }
+                    let target = target as *mut u32;
+                    unsafe {
+                        target.write_unaligned(target.read_unaligned().wrapping_add(delta));
+                    }
+                }
+                other => {
+                    osprintln!("Can't do relocation type {}", other);
+                    return Err(Error::UnsupportedRelocation);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the addresses a program's loadable segments were linked at.
+///
+/// Returns the lowest address, and the address just past the highest one.
+fn load_span<DS>(loader: &neotron_loader::Loader<DS>) -> (u32, u32)
+where
+    DS: neotron_loader::traits::Source,
+{
+    let mut link_start = u32::MAX;
+    let mut link_end = 0;
+    let mut iter = loader.iter_program_headers();
+    while let Some(Ok(ph)) = iter.next() {
+        if ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD {
+            link_start = link_start.min(ph.p_vaddr());
+            link_end = link_end.max(ph.p_vaddr().saturating_add(ph.p_memsz()));
+        }
+    }
+    (link_start, link_end)
+}
+
+/// Get the `e_type` of an ELF file that is in memory, or zero if it's too
+/// short to have one.
+fn image_type(contents: &[u8]) -> u16 {
+    let offset = ELF_TYPE_OFFSET as usize;
+    match contents.get(offset..offset + 2) {
+        Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+/// Set how fussy we are about program checksums.
+pub fn configure_check(check: ProgramCheck) {
+    *PROGRAM_CHECK.lock() = check;
+}
+
+/// Do the OS's background work while an application waits, then idle the
+/// CPU until something happens.
+///
+/// The application still owns the screen, so this doesn't start the
+/// screensaver.
+fn app_yield() {
+    crate::statusbar::refresh(false);
+    crate::bus::poll();
+    crate::sensors::poll();
+    #[cfg(feature = "net")]
+    crate::net::poll();
+    // The application isn't hung, just waiting
+    crate::watchdog::kick();
+    crate::stats::idle();
+}
+
+/// Set whether we wipe the TPA and the handle table after a program runs.
+pub fn configure_scrub(scrub: bool) {
+    *SCRUB_TPA.lock() = scrub;
+}
+
+/// Zero every byte of the handle table, then mark every handle closed.
+///
+/// Closing a handle only changes its tag, which leaves things like file
+/// offsets behind in the rest of the slot. All the handles must already be
+/// closed.
+fn scrub_handles(handles: &mut [OpenHandle; MAX_OPEN_HANDLES]) {
+    let ptr = handles.as_mut_ptr();
+    // # Safety
+    //
+    // Every slot is `Closed`, so there's nothing to drop. The zeroed slots
+    // may not be valid handles, but we write a valid one over each before
+    // anyone can look at it.
+    unsafe {
+        core::ptr::write_bytes(ptr as *mut u8, 0, core::mem::size_of_val(handles));
+        for idx in 0..MAX_OPEN_HANDLES {
+            ptr.add(idx).write(OpenHandle::Closed);
+        }
+    }
+}
+
+/// Fill the guard below the TPA with its pattern.
+fn paint_guard() {
+    let guard = core::ptr::addr_of_mut!(TPA_GUARD) as *mut u32;
+    for idx in 0..GUARD_WORDS {
+        // # Safety
+        //
+        // The guard is ours, and no-one else should be touching it
+        unsafe { guard.add(idx).write_volatile(GUARD_PATTERN) };
+    }
+}
+
+/// Is the guard below the TPA still filled with its pattern?
+fn guard_intact() -> bool {
+    let guard = core::ptr::addr_of!(TPA_GUARD) as *const u32;
+    // # Safety
+    //
+    // The guard is ours. A program might have written over it, but it's
+    // still just memory.
+    (0..GUARD_WORDS).all(|idx| unsafe { guard.add(idx).read_volatile() } == GUARD_PATTERN)
+}
+
+/// Build the path we pass a program as its first argument.
+///
+/// We'd rather refuse to load it than hand it a path that's been cut short.
+fn program_path(prefix: &str, name: &str) -> Result<heapless::String<MAX_PATH_LEN>, Error> {
+    let mut path = heapless::String::new();
+    path.push_str(prefix)
+        .and_then(|_| path.push_str(name))
+        .map_err(|_| Error::PathTooLong)?;
+    Ok(path)
+}
+
+/// Check a program against the CRC32 in its checksum footer, if it has one.
+///
+/// `footer` is the last `FOOTER_LEN` bytes of the program file. `crc` works
+/// out the CRC32 of everything before them (we only call it if there is a
+/// footer).
+fn check_footer<F>(footer: &[u8], crc: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<u32, Error>,
+{
+    let expected = match footer.get(0..FOOTER_LEN) {
+        Some(footer) if footer[0..4] == FOOTER_MAGIC => {
+            u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]])
+        }
+        _ => {
+            return match *PROGRAM_CHECK.lock() {
+                ProgramCheck::Strict => Err(Error::NoChecksum),
+                ProgramCheck::Lenient => Ok(()),
+            };
+        }
+    };
+    if crc()? != expected {
+        return Err(Error::BadChecksum);
+    }
+    Ok(())
+}
+
+/// Load an overlay file into part of the running program's memory.
+///
+/// Gives back the file, so the overlay can be loaded again later.
+fn load_overlay(file: fs::File, region: LoadRegion) -> (fs::File, Result<(u32, u32), Error>) {
+    let source = FileSource::new(file);
+    let result = neotron_loader::Loader::new(&source)
+        .map_err(Error::from)
+        .and_then(|loader| {
+            let mut e_type = [0u8; 2];
+            source.uncached_read(ELF_TYPE_OFFSET, &mut e_type)?;
+            region.load_segments(
+                &loader,
+                u16::from_le_bytes(e_type),
+                false,
+                |offset, buffer| {
+                    source.uncached_read(offset, buffer)?;
+                    Ok(())
+                },
+            )
+        });
+    (source.into_file(), result)
+}
+
+/// Work out how many bytes of memory an overlay file needs.
+fn overlay_size(file: fs::File) -> (fs::File, Result<u32, Error>) {
+    let source = FileSource::new(file);
+    let result = neotron_loader::Loader::new(&source)
+        .map_err(Error::from)
+        .map(|loader| {
+            let (link_start, link_end) = load_span(&loader);
+            // Allow for aligning the start up to 8 bytes
+            link_end.saturating_sub(link_start) + 7
+        });
+    (source.into_file(), result)
+}
+
+/// The OS version, laid out like a BIOS API version (0x00<major>_<minor>_<patch>)
+fn os_version() -> u64 {
+    let part = |s: &str| s.parse::<u64>().unwrap_or(0).min(0xFF);
+    (part(env!("CARGO_PKG_VERSION_MAJOR")) << 16)
+        | (part(env!("CARGO_PKG_VERSION_MINOR")) << 8)
+        | part(env!("CARGO_PKG_VERSION_PATCH"))
+}
+
+/// Store an open handle, or fail if we're out of space
+fn allocate_handle(h: OpenHandle) -> Result<usize, OpenHandle> {
+    for (idx, slot) in OPEN_HANDLES.lock().iter_mut().enumerate() {
+        if matches!(*slot, OpenHandle::Closed) {
+            *slot = h;
+            return Ok(idx);
+        }
+    }
+    Err(h)
+}
+
+/// Open a file, given a path as UTF-8 string.
+///
+/// If the file does not exist, it returns `InvalidPath`. A file can be open
+/// for reading any number of times, or for writing once (including by the
+/// OS). If that would be broken, you get `DeviceSpecific`: inside the OS this
+/// is [`fs::Error::AlreadyOpen`], but the application API has no error code
+/// for it. Files are opened read-only unless `flags` has `WRITE` set.
+///
+/// Path may be relative to current directory, or it may be an absolute
+/// path.
+///
+/// Paths starting with `ROM:` refer to files in the ROMFS, and are read-only.
+///
+/// Paths starting with `OVERLAY:` open an ELF file that the program can load
+/// into its own memory with `ioctl` (see [`api_ioctl`]).
+///
+/// Opening `SYS:` gives you the OS version string (like the one printed at
+/// boot) to read, and some numbers about the system with `ioctl`. Files in
+/// `SYS:`, like `SYS:UPTIME`, tell you about the system as text (see
+/// [`crate::sysfs`]).
+///
+/// Opening `TEMP:` creates a new, empty, file in the `TEMP` directory. The
+/// file is deleted the next time the OS starts.
+///
+/// Opening `BUSn:` talks to the card in Neotron Bus slot `n` (the peripheral
+/// ID `lsbus` shows), through whichever driver claimed it (see
+/// [`crate::bus`]). If no driver has claimed the slot, you get
+/// `InvalidPath`.
+///
+/// Opening `MIDI:` sets up the first MIDI-type UART (see `lsuart`) at 31,250
+/// baud, so you can read and write raw MIDI bytes. Reads don't wait, so you
+/// may get nothing back. If there is no MIDI port, you get `InvalidPath`.
+///
+/// Opening `PRN:` lets you write text straight to the printer (see
+/// [`crate::spool`]). If there is no printer you get `InvalidPath`, and if
+/// the print spooler is still busy with some files, you get
+/// `DeviceSpecific`.
+///
+/// Opening `TCP:<address>:<port>` (like `TCP:10.0.0.1:23`) makes a TCP
+/// connection, if the OS was built with networking and `ifconfig` has set it
+/// up (see [`crate::net`]). The callback table can't grow, so this is how
+/// applications get sockets. You get the handle straight away, while the
+/// connection is made - writes wait for it, or you can check with `ioctl`.
+/// Reads don't wait, and give `EndOfFile` once the other end has closed the
+/// connection.
+extern "C" fn api_open(
+    path: neotron_api::FfiString,
+    flags: neotron_api::file::Flags,
+) -> neotron_api::Result<neotron_api::file::Handle> {
+    // Check for special devices
+    if path.as_str().eq_ignore_ascii_case("AUDIO:") {
+        match allocate_handle(OpenHandle::Audio) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
+
+    if path.as_str().eq_ignore_ascii_case("GFX:") {
+        match allocate_handle(OpenHandle::Gfx(GfxDevice::new())) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
+
+    if path.as_str().eq_ignore_ascii_case("CLOCK:") {
+        match allocate_handle(OpenHandle::Clock) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
+
+    if path.as_str().eq_ignore_ascii_case("PAD:") {
+        match allocate_handle(OpenHandle::Pad) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
         }
+    }
 
-        // Setup the default file handles
-        let mut open_handles = OPEN_HANDLES.lock();
-        open_handles[0] = OpenHandle::StdIn;
-        open_handles[1] = OpenHandle::Stdout;
-        open_handles[2] = OpenHandle::StdErr;
-        drop(open_handles);
-
-        // We support a maximum of four arguments.
-        #[allow(clippy::get_first)]
-        let ffi_args = [
-            neotron_api::FfiString::new(args.get(0).unwrap_or(&"")),
-            neotron_api::FfiString::new(args.get(1).unwrap_or(&"")),
-            neotron_api::FfiString::new(args.get(2).unwrap_or(&"")),
-            neotron_api::FfiString::new(args.get(3).unwrap_or(&"")),
-        ];
-
-        let result = unsafe {
-            let code: neotron_api::AppStartFn =
-                ::core::mem::transmute(self.last_entry as *const ());
-            code(&CALLBACK_TABLE, args.len(), ffi_args.as_ptr())
+    if path.as_str().eq_ignore_ascii_case("MIDI:") {
+        let port = match crate::midi::open() {
+            Ok(port) => port,
+            Err(crate::midi::Error::NoPort) => {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+            }
+            Err(crate::midi::Error::Bios(_e)) => {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            }
         };
+        match allocate_handle(OpenHandle::Midi(port)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
 
-        // Close any files the program left open
-        let mut open_handles = OPEN_HANDLES.lock();
-        for h in open_handles.iter_mut() {
-            *h = OpenHandle::Closed;
+    if path.as_str().eq_ignore_ascii_case("PRN:") {
+        let claim = match crate::spool::claim() {
+            Ok(claim) => claim,
+            Err(crate::spool::Error::NoPrinter) => {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+            }
+            Err(_e) => {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            }
+        };
+        match allocate_handle(OpenHandle::Printer(claim)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
         }
-        drop(open_handles);
+    }
 
-        self.last_entry = 0;
-        Ok(result)
+    #[cfg(feature = "net")]
+    if let Some(remote) = path
+        .as_str()
+        .get(0..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("TCP:"))
+        .and_then(|_| crate::net::parse_endpoint(&path.as_str()[4..]))
+    {
+        let connection = match crate::net::connect(remote) {
+            Ok(connection) => connection,
+            Err(crate::net::Error::Down) => {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+            }
+            Err(_e) => {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            }
+        };
+        match allocate_handle(OpenHandle::Tcp(connection)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
+
+    let sys_entry = crate::sysfs::Entry::from_path(path.as_str());
+    if sys_entry.is_some() || path.as_str().eq_ignore_ascii_case("SYS:") {
+        let handle = OpenHandle::Sys {
+            entry: sys_entry,
+            offset: 0,
+        };
+        match allocate_handle(handle) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
+    }
+
+    if path.as_str().eq_ignore_ascii_case("TEMP:") {
+        let Ok(file) = FILESYSTEM.create_temp_file() else {
+            return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+        };
+        match allocate_handle(OpenHandle::File(file)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
     }
 
-    /// Move data to the top of TPA and make TPA shorter.
-    ///
-    /// Moves `size` bytes to the top of the TPA, and then pretends the TPA is
-    /// `size` bytes shorter than it was.
-    ///
-    /// `size` will be rounded up to a multiple of 4.
-    ///
-    /// Panics if `n` is too big to fit in the TPA.
-    ///
-    /// Returns a pointer to the data that now sits outside of the TPA. There
-    /// will be `size` bytes at this address but you must manage the lifetimes
-    /// yourself.
-    pub fn steal_top(&mut self, size: usize) -> *const u8 {
-        let stolen_words = (size + 3) / 4;
-        if stolen_words >= self.size_words() {
-            panic!("Stole too much from TPA!");
-        }
-        unsafe {
-            // Top goes down to free memory above it
-            let new_top = self.memory_top.sub(stolen_words);
-            // Copy the data from the bottom to above the newly reduced TPA
-            core::ptr::copy(self.memory_bottom, new_top, stolen_words);
-            new_top as *mut u8
+    // Check for virtual consoles (CON1: to CON4:)
+    if let Some(index) = path
+        .as_str()
+        .strip_suffix(':')
+        .and_then(|s| {
+            s.get(0..3)
+                .filter(|p| p.eq_ignore_ascii_case("CON"))
+                .map(|_| &s[3..])
+        })
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| (1..=crate::vconsole::NUM_CONSOLES).contains(n))
+    {
+        match allocate_handle(OpenHandle::Console(index - 1)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
         }
     }
 
-    /// Restore the TPA back where it was.
-    pub unsafe fn restore_top(&mut self, size: usize) {
-        let restored_words = (size + 3) / 4;
-        self.memory_top = self.memory_top.add(restored_words);
+    // Check for cards on the Neotron Bus (BUS0: to BUS255:)
+    if let Some(slot) = path
+        .as_str()
+        .strip_suffix(':')
+        .and_then(|s| {
+            s.get(0..3)
+                .filter(|p| p.eq_ignore_ascii_case("BUS"))
+                .map(|_| &s[3..])
+        })
+        .and_then(|n| n.parse::<u8>().ok())
+    {
+        if crate::bus::driver_name(slot).is_none() {
+            return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+        }
+        match allocate_handle(OpenHandle::Bus(slot)) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
+        }
     }
-}
 
-/// Store an open handle, or fail if we're out of space
-fn allocate_handle(h: OpenHandle) -> Result<usize, OpenHandle> {
-    for (idx, slot) in OPEN_HANDLES.lock().iter_mut().enumerate() {
-        if matches!(*slot, OpenHandle::Closed) {
-            *slot = h;
-            return Ok(idx);
+    // Check for files in ROM
+    if let Some(rom_name) = path
+        .as_str()
+        .get(0..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("ROM:"))
+        .map(|_| &path.as_str()[4..])
+    {
+        let Ok(romfs) = neotron_romfs::RomFs::new(crate::ROMFS) else {
+            return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+        };
+        // `find` would only lend us the contents, but iterating gives us
+        // the `'static` slice
+        let Some(entry) = romfs
+            .into_iter()
+            .flatten()
+            .find(|e| e.metadata.file_name == rom_name)
+        else {
+            return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+        };
+        let handle = OpenHandle::Rom {
+            contents: entry.contents,
+            offset: 0,
+            // The ROMFS uses an older version of the API crate, so we
+            // copy the fields over
+            ctime: neotron_api::file::Time {
+                year_since_1970: entry.metadata.ctime.year_since_1970,
+                zero_indexed_month: entry.metadata.ctime.zero_indexed_month,
+                zero_indexed_day: entry.metadata.ctime.zero_indexed_day,
+                hours: entry.metadata.ctime.hours,
+                minutes: entry.metadata.ctime.minutes,
+                seconds: entry.metadata.ctime.seconds,
+            },
+        };
+        match allocate_handle(handle) {
+            Ok(n) => {
+                return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
+            }
+            Err(_f) => {
+                return neotron_api::Result::Err(neotron_api::Error::OutOfMemory);
+            }
         }
     }
-    Err(h)
-}
 
-/// Open a file, given a path as UTF-8 string.
-///
-/// If the file does not exist, or is already open, it returns an error.
-///
-/// Path may be relative to current directory, or it may be an absolute
-/// path.
-extern "C" fn api_open(
-    path: neotron_api::FfiString,
-    _flags: neotron_api::file::Flags,
-) -> neotron_api::Result<neotron_api::file::Handle> {
-    // Check for special devices
-    if path.as_str().eq_ignore_ascii_case("AUDIO:") {
-        match allocate_handle(OpenHandle::Audio) {
+    // Check for overlays
+    if let Some(file_name) = path
+        .as_str()
+        .get(0..8)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("OVERLAY:"))
+        .map(|_| &path.as_str()[8..])
+    {
+        let f = match FILESYSTEM.open_file(file_name, embedded_sdmmc::Mode::ReadOnly) {
+            Ok(f) => f,
+            Err(fs::Error::Io(embedded_sdmmc::Error::NotFound)) => {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
+            }
+            Err(_e) => {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            }
+        };
+        match allocate_handle(OpenHandle::Overlay(f)) {
             Ok(n) => {
                 return neotron_api::Result::Ok(neotron_api::file::Handle::new(n as u8));
             }
@@ -399,11 +1936,15 @@ extern "C" fn api_open(
     }
 
     // OK, let's assume it's a file relative to the root of our one and only volume
-    let f = match FILESYSTEM.open_file(path.as_str(), embedded_sdmmc::Mode::ReadOnly) {
+    let f = match FILESYSTEM.open_file(path.as_str(), open_mode(flags)) {
         Ok(f) => f,
         Err(fs::Error::Io(embedded_sdmmc::Error::NotFound)) => {
             return neotron_api::Result::Err(neotron_api::Error::InvalidPath);
         }
+        Err(fs::Error::AlreadyOpen) => {
+            // The API has nothing closer
+            return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+        }
         Err(_e) => {
             return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
         }
@@ -416,6 +1957,23 @@ extern "C" fn api_open(
     }
 }
 
+/// Work out how to open a file, from the flags a program gave [`api_open`]
+fn open_mode(flags: neotron_api::file::Flags) -> embedded_sdmmc::Mode {
+    use neotron_api::file::Flags;
+    if !flags.contains(Flags::WRITE) {
+        return embedded_sdmmc::Mode::ReadOnly;
+    }
+    match (
+        flags.contains(Flags::CREATE),
+        flags.contains(Flags::TRUNCATE),
+    ) {
+        (false, false) => embedded_sdmmc::Mode::ReadWriteAppend,
+        (false, true) => embedded_sdmmc::Mode::ReadWriteTruncate,
+        (true, false) => embedded_sdmmc::Mode::ReadWriteCreateOrAppend,
+        (true, true) => embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+    }
+}
+
 /// Close a previously opened file.
 extern "C" fn api_close(fd: neotron_api::file::Handle) -> neotron_api::Result<()> {
     let mut open_handles = OPEN_HANDLES.lock();
@@ -435,21 +1993,32 @@ extern "C" fn api_write(
     fd: neotron_api::file::Handle,
     buffer: neotron_api::FfiByteSlice,
 ) -> neotron_api::Result<()> {
+    // An application that is writing hasn't hung
+    crate::watchdog::kick();
     let mut open_handles = OPEN_HANDLES.lock();
+    // Don't draw text over the top of an application's graphics
+    let gfx_active = open_handles
+        .iter()
+        .any(|h| matches!(h, OpenHandle::Gfx(g) if g.mode_changed));
     let Some(h) = open_handles.get_mut(fd.value() as usize) else {
         return neotron_api::Result::Err(neotron_api::Error::BadHandle);
     };
     match h {
         OpenHandle::StdErr | OpenHandle::Stdout => {
             // Treat stderr and stdout the same
-            let mut guard = crate::VGA_CONSOLE.lock();
-            if let Some(console) = guard.as_mut() {
-                console.write_bstr(buffer.as_slice());
+            let route = crate::Console::stdout_route();
+            if route.vga && !gfx_active {
+                let mut guard = crate::VGA_CONSOLE.lock();
+                if let Some(console) = guard.as_mut() {
+                    console.write_bstr(buffer.as_slice());
+                }
             }
-            let mut guard = crate::SERIAL_CONSOLE.lock();
-            if let Some(console) = guard.as_mut() {
-                // Ignore serial errors on stdout
-                let _ = console.write_bstr(buffer.as_slice());
+            if route.serial {
+                let mut guard = crate::SERIAL_CONSOLE.lock();
+                if let Some(console) = guard.as_mut() {
+                    // Ignore serial errors on stdout
+                    let _ = console.write_bstr(buffer.as_slice());
+                }
             }
             neotron_api::Result::Ok(())
         }
@@ -458,11 +2027,10 @@ extern "C" fn api_write(
             Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
         },
         OpenHandle::Audio => {
-            let api = API.get();
             let mut slice = buffer.as_slice();
             // loop until we've sent all of it
             while !slice.is_empty() {
-                let result = unsafe { (api.audio_output_data)(FfiByteSlice::new(slice)) };
+                let result = crate::stats::audio_output(slice);
                 let this_time = match result {
                     neotron_common_bios::FfiResult::Ok(n) => n,
                     neotron_common_bios::FfiResult::Err(_e) => {
@@ -473,9 +2041,53 @@ extern "C" fn api_write(
             }
             neotron_api::Result::Ok(())
         }
-        OpenHandle::StdIn | OpenHandle::Closed => {
-            neotron_api::Result::Err(neotron_api::Error::BadHandle)
+        OpenHandle::Gfx(gfx) => {
+            let offset = gfx.offset;
+            let Some(fb) = gfx.framebuffer() else {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            };
+            let data = buffer.as_slice();
+            let Some(dest) = offset
+                .checked_add(data.len())
+                .and_then(|end| fb.get_mut(offset..end))
+            else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            dest.copy_from_slice(data);
+            gfx.offset += data.len();
+            neotron_api::Result::Ok(())
+        }
+        OpenHandle::Console(index) => {
+            if gfx_active && *index == crate::vconsole::active() {
+                // Don't draw over the application's graphics
+                return neotron_api::Result::Ok(());
+            }
+            match crate::vconsole::write_bstr(*index, buffer.as_slice()) {
+                Ok(()) => neotron_api::Result::Ok(()),
+                Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+            }
         }
+        OpenHandle::Bus(slot) => crate::bus::write(*slot, buffer.as_slice()).into(),
+        OpenHandle::Midi(port) => match crate::midi::write(*port, buffer.as_slice()) {
+            Ok(()) => neotron_api::Result::Ok(()),
+            Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+        },
+        OpenHandle::Printer(claim) => match crate::spool::write(claim, buffer.as_slice()) {
+            Ok(()) => neotron_api::Result::Ok(()),
+            Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+        },
+        #[cfg(feature = "net")]
+        OpenHandle::Tcp(connection) => match connection.write(buffer.as_slice()) {
+            Ok(()) => neotron_api::Result::Ok(()),
+            Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+        },
+        OpenHandle::StdIn
+        | OpenHandle::Rom { .. }
+        | OpenHandle::Clock
+        | OpenHandle::Overlay(_)
+        | OpenHandle::Pad
+        | OpenHandle::Sys { .. }
+        | OpenHandle::Closed => neotron_api::Result::Err(neotron_api::Error::BadHandle),
     }
 }
 
@@ -494,6 +2106,8 @@ extern "C" fn api_read(
         OpenHandle::StdIn => {
             if let Some(buffer) = buffer.as_mut_slice() {
                 let count = { crate::STD_INPUT.lock().get_data(buffer) };
+                // The application is probably waiting for us, so this is a good time
+                crate::statusbar::refresh(false);
                 Ok(count).into()
             } else {
                 neotron_api::Result::Err(neotron_api::Error::DeviceSpecific)
@@ -518,9 +2132,73 @@ extern "C" fn api_read(
                 }
             }
         }
-        OpenHandle::Stdout | OpenHandle::StdErr | OpenHandle::Closed => {
-            neotron_api::Result::Err(neotron_api::Error::BadHandle)
+        OpenHandle::Rom {
+            contents, offset, ..
+        } => {
+            let Some(buffer) = buffer.as_mut_slice() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            let remaining = &contents[*offset..];
+            let count = remaining.len().min(buffer.len());
+            buffer[0..count].copy_from_slice(&remaining[0..count]);
+            *offset += count;
+            neotron_api::Result::Ok(count)
+        }
+        OpenHandle::Sys { entry, offset } => {
+            let Some(buffer) = buffer.as_mut_slice() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            let generated;
+            let contents = match entry {
+                Some(entry) => {
+                    generated = entry.generate();
+                    generated.as_bytes()
+                }
+                None => crate::OS_VERSION.as_bytes(),
+            };
+            let remaining = contents.get(*offset..).unwrap_or(&[]);
+            let count = remaining.len().min(buffer.len());
+            buffer[0..count].copy_from_slice(&remaining[0..count]);
+            *offset += count;
+            neotron_api::Result::Ok(count)
+        }
+        OpenHandle::Bus(slot) => {
+            let Some(buffer) = buffer.as_mut_slice() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            crate::bus::read(*slot, buffer).into()
+        }
+        OpenHandle::Midi(port) => {
+            let Some(buffer) = buffer.as_mut_slice() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            match crate::midi::read(*port, buffer) {
+                Ok(n) => neotron_api::Result::Ok(n),
+                Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+            }
+        }
+        #[cfg(feature = "net")]
+        OpenHandle::Tcp(connection) => {
+            let Some(buffer) = buffer.as_mut_slice() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            match connection.read(buffer) {
+                Ok(n) => neotron_api::Result::Ok(n),
+                Err(crate::net::Error::Closed) => {
+                    neotron_api::Result::Err(neotron_api::Error::EndOfFile)
+                }
+                Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+            }
         }
+        OpenHandle::Stdout
+        | OpenHandle::StdErr
+        | OpenHandle::Gfx(_)
+        | OpenHandle::Console(_)
+        | OpenHandle::Clock
+        | OpenHandle::Overlay(_)
+        | OpenHandle::Pad
+        | OpenHandle::Printer(_)
+        | OpenHandle::Closed => neotron_api::Result::Err(neotron_api::Error::BadHandle),
     }
 }
 
@@ -561,6 +2239,37 @@ extern "C" fn api_rename(
 
 /// Perform a special I/O control operation.
 ///
+/// # Standard Output
+///
+/// These work on both stdout and stderr.
+///
+/// * `0` - get the console's character set
+///     * 0 => UTF-8
+///     * 1 => 8-bit Code Page 850, with bytes from 0x80 to 0xFF drawn as they are
+/// * `1` - set the console's character set (as above)
+///     * It goes back to UTF-8 when the program exits
+/// * `2` - get the size of the console (0x<rows_u16>_<columns_u16>)
+///     * This doesn't include the OS status line, if there is one
+///     * Fails if there is no VGA console
+/// * `3` - find out if the size of the console has changed (1) or not (0)
+///   since you last got the size with ioctl `2`
+///
+/// # Standard Input
+///
+/// * `0` - turn input events on (1) or off (0)
+///     * While they are on, every key press and release, and every mouse
+///       report, is queued as an event, as well as being typed
+///     * They go off when the program exits
+/// * `1` - get the next input event, or 0 if there isn't one
+///     * A key press is `0x0000_0000_00<modifiers_u8>_<keycode_u8>_01`, and
+///       a key release is the same but ending `02`. The key code is the
+///       number of the key in `pc_keyboard::KeyCode` (from pc-keyboard
+///       0.7).
+///     * The modifiers are, from bit 0: Shift, Ctrl, Alt, Caps Lock and Num
+///       Lock
+///     * Mouse movement is `0x0000_<dy_i16>_<dx_i16>_<buttons_u8>_03`, where
+///       the buttons are, from bit 0: Left and Right
+///
 /// # Audio Devices
 ///
 /// * `0` - get output sample rate/format (0xN000_0000_<sample_rate_u32>) where N indicates the sample format
@@ -572,6 +2281,81 @@ extern "C" fn api_rename(
 ///     * As above
 /// * `2` - get output sample space available
 ///     * Gets a value in bytes
+///
+/// # Graphics Devices
+///
+/// * `0` - get the current video mode number
+/// * `1` - set the video mode (0x<framebuffer_address_u32>_0000_00<mode_u8>)
+///     * An address of zero means use the BIOS's own video memory
+///     * The old mode is restored when the device is closed
+/// * `2` - get the framebuffer geometry (0x<line_size_u32>_<height_u16>_<width_u16>)
+/// * `3` - get the framebuffer address
+/// * `4` - set the byte offset into the framebuffer for the next write
+/// * `5` - set the video mode, with OS-managed video memory (0x0000_0000_0000_0N<mode_u8>)
+///     * N is the number of buffers - 1 for single-buffered, 2 for double-buffered
+///     * Returns the address of the buffer to draw into
+/// * `6` - show the back buffer at the next vertical blanking interval
+///     * Returns the address of the new back buffer
+///
+/// # Clock Devices
+///
+/// These count up from when the system started, and aren't affected by
+/// changes to the time and date.
+///
+/// * `0` - get the number of milliseconds since the system started
+/// * `1` - get the number of ticks since the system started
+/// * `2` - get the number of ticks per second
+/// * `3` - get the time and date in UTC, as seconds since 1970-01-01T00:00:00
+/// * `4` - get the local time and date, as seconds since 1970-01-01T00:00:00
+/// * `5` - get the local timezone, in minutes east of UTC (as an `i64`)
+/// * `6` - yield, so the OS can do its background work, and idle the CPU
+///   until something happens
+/// * `7` - sleep for the given number of milliseconds, yielding until they
+///   have passed
+///
+/// The callback table can't grow without a new version of the application
+/// API, so these are how a program avoids busy-waiting.
+///
+/// # Overlay Devices
+///
+/// These let a program load more code (like a plug-in, or a part of itself
+/// it doesn't always need) into some of its own memory. The overlay is an
+/// ELF file, just like a program.
+///
+/// * `0` - load the overlay (0x<length_u32>_<address_u32>)
+///     * The region must be inside the TPA, and not in use as video memory
+///     * Position-independent overlays are loaded at the start of the region
+///       (rounded up to 8 bytes), and others where they were linked
+///     * Returns the entry point of the overlay
+/// * `1` - get the number of bytes the overlay needs
+///
+/// # Game Controller Devices
+///
+/// * `0` - get the number of game controllers (none, if the BIOS can't
+///   report them)
+/// * `1` - get the state of a game controller (the value is the controller
+///   number) as `0x<axes_4x_i8>_000<connected_u4>_<buttons_u16>`
+///     * The buttons are, from bit 0: Up, Down, Left, Right, A, B, X, Y, L,
+///       R, Select and Start
+///     * Axis 0 (the left stick's X axis) is in bits 32 to 39, then the left
+///       stick's Y axis, then the right stick's X and Y axes
+///
+/// # MIDI Devices
+///
+/// * `0` - get the BIOS serial device number of the MIDI port
+///
+/// # TCP Connections
+///
+/// * `0` - get the state of the connection (0 = connecting, 1 = connected,
+///   2 = closed)
+///
+/// # System Devices
+///
+/// * `0` - get the OS version (0x0000_0000_00<major_u8>_<minor_u8>_<patch_u8>)
+/// * `1` - get the BIOS API version, in the same format
+/// * `2` - get the size of the TPA in bytes
+/// * `3` - get the git commit the OS was built from, as a number (so the
+///   commit `abc1234` is `0xabc1234`), or zero if it isn't known
 extern "C" fn api_ioctl(
     fd: neotron_api::file::Handle,
     command: u64,
@@ -583,6 +2367,63 @@ extern "C" fn api_ioctl(
     };
     let api = API.get();
     match (h, command) {
+        (OpenHandle::Stdout | OpenHandle::StdErr, 0) => {
+            // Getting the character set
+            let guard = crate::VGA_CONSOLE.lock();
+            let passthrough = guard
+                .as_ref()
+                .is_some_and(|console| console.code_page_passthrough());
+            neotron_api::Result::Ok(u64::from(passthrough))
+        }
+        (OpenHandle::Stdout | OpenHandle::StdErr, 1) => {
+            // Setting the character set
+            let passthrough = match value {
+                0 => false,
+                1 => true,
+                _ => {
+                    return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+                }
+            };
+            let mut guard = crate::VGA_CONSOLE.lock();
+            if let Some(console) = guard.as_mut() {
+                console.set_code_page_passthrough(passthrough);
+            }
+            neotron_api::Result::Ok(0)
+        }
+        (OpenHandle::Stdout | OpenHandle::StdErr, 2) => {
+            // Getting the console size
+            let mut guard = crate::VGA_CONSOLE.lock();
+            let Some(console) = guard.as_mut() else {
+                return neotron_api::Result::Err(neotron_api::Error::DeviceSpecific);
+            };
+            console.take_resized();
+            let rows = console.height() as u64;
+            let columns = console.width() as u64;
+            neotron_api::Result::Ok((rows << 16) | columns)
+        }
+        (OpenHandle::Stdout | OpenHandle::StdErr, 3) => {
+            // Checking if the console size has changed
+            let guard = crate::VGA_CONSOLE.lock();
+            let resized = guard.as_ref().is_some_and(|console| console.resized());
+            neotron_api::Result::Ok(u64::from(resized))
+        }
+        (OpenHandle::StdIn, 0) => {
+            let on = match value {
+                0 => false,
+                1 => true,
+                _ => {
+                    return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+                }
+            };
+            crate::STD_INPUT.lock().set_events(on);
+            neotron_api::Result::Ok(0)
+        }
+        (OpenHandle::StdIn, 1) => {
+            let event = { crate::STD_INPUT.lock().get_event() };
+            // The application is probably waiting for us, so this is a good time
+            crate::statusbar::refresh(false);
+            neotron_api::Result::Ok(event.map_or(0, |e| e.to_u64()))
+        }
         (OpenHandle::Audio, 0) => {
             // Getting sample rate
             let neotron_common_bios::FfiResult::Ok(config) = (api.audio_output_get_config)() else {
@@ -636,6 +2477,161 @@ extern "C" fn api_ioctl(
                 }
             }
         }
+        (OpenHandle::Gfx(_), 0) => {
+            // Getting video mode
+            neotron_api::Result::Ok(u64::from((api.video_get_mode)().as_u8()))
+        }
+        (OpenHandle::Gfx(gfx), 1) => {
+            // Setting video mode
+            let Some(mode) = neotron_common_bios::video::Mode::try_from_u8(value as u8) else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            let fb_ptr = (value >> 32) as usize as *mut u32;
+            if !(api.video_is_valid_mode)(mode)
+                || (fb_ptr.is_null() && (api.video_mode_needs_vram)(mode))
+            {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            }
+            // # Safety
+            //
+            // The application promises the framebuffer is big enough, and
+            // we put the old one back when the device is closed.
+            match unsafe { (api.video_set_mode)(mode, fb_ptr) } {
+                neotron_common_bios::FfiResult::Ok(_) => {
+                    gfx.mode_changed = true;
+                    gfx.offset = 0;
+                    neotron_api::Result::Ok(0)
+                }
+                neotron_common_bios::FfiResult::Err(_) => {
+                    neotron_api::Result::Err(neotron_api::Error::DeviceSpecific)
+                }
+            }
+        }
+        (OpenHandle::Gfx(_), 2) => {
+            // Getting framebuffer geometry
+            let mode = (api.video_get_mode)();
+            let mut result = u64::from(mode.horizontal_pixels());
+            result |= u64::from(mode.vertical_lines()) << 16;
+            result |= (mode.line_size_bytes() as u64) << 32;
+            neotron_api::Result::Ok(result)
+        }
+        (OpenHandle::Gfx(_), 3) => {
+            // Getting framebuffer address
+            neotron_api::Result::Ok((api.video_get_framebuffer)() as usize as u64)
+        }
+        (OpenHandle::Gfx(gfx), 4) => {
+            // Setting write offset
+            gfx.offset = value as usize;
+            neotron_api::Result::Ok(0)
+        }
+        (OpenHandle::Gfx(gfx), 5) => {
+            // Setting video mode with OS-managed VRAM
+            let Some(mode) = neotron_common_bios::video::Mode::try_from_u8(value as u8) else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            let num_buffers = ((value >> 8) & 0xFF) as usize;
+            match gfx.set_mode_with_vram(mode, num_buffers) {
+                Ok(ptr) => neotron_api::Result::Ok(ptr as usize as u64),
+                Err(e) => neotron_api::Result::Err(e),
+            }
+        }
+        (OpenHandle::Gfx(gfx), 6) => {
+            // Page flip
+            match gfx.flip() {
+                Ok(ptr) => neotron_api::Result::Ok(ptr as usize as u64),
+                Err(e) => neotron_api::Result::Err(e),
+            }
+        }
+        (OpenHandle::Gfx(_), 7) => {
+            // Waiting for vsync. Gives back the BIOS ticks since the last
+            // wait, so the application can pace its animation.
+            neotron_api::Result::Ok(crate::vsync::wait_for_vsync())
+        }
+        (OpenHandle::Clock, 0) => {
+            neotron_api::Result::Ok(crate::API.get_uptime().as_millis() as u64)
+        }
+        (OpenHandle::Clock, 1) => neotron_api::Result::Ok((api.time_ticks_get)().0),
+        (OpenHandle::Clock, 2) => neotron_api::Result::Ok((api.time_ticks_per_second)().0),
+        (OpenHandle::Clock, 3) => {
+            neotron_api::Result::Ok(crate::API.get_time().and_utc().timestamp() as u64)
+        }
+        (OpenHandle::Clock, 4) => {
+            neotron_api::Result::Ok(crate::API.get_local_time().and_utc().timestamp() as u64)
+        }
+        (OpenHandle::Clock, 5) => {
+            neotron_api::Result::Ok(i64::from(crate::API.get_tz_offset()) as u64)
+        }
+        (OpenHandle::Clock, 6) => {
+            app_yield();
+            neotron_api::Result::Ok(0)
+        }
+        (OpenHandle::Clock, 7) => {
+            let ticks_per_second = (api.time_ticks_per_second)().0;
+            let deadline = (api.time_ticks_get)()
+                .0
+                .saturating_add(value.saturating_mul(ticks_per_second) / 1000);
+            while (api.time_ticks_get)().0 < deadline {
+                app_yield();
+            }
+            neotron_api::Result::Ok(0)
+        }
+        (h @ OpenHandle::Overlay(_), 0) => {
+            // Loading the overlay
+            let region = LoadRegion {
+                bottom: value as u32,
+                top: (value as u32).saturating_add((value >> 32) as u32),
+            };
+            let Some(free) = VRAM_POOL.lock().free_region() else {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            };
+            if !free.contains(region.bottom, region.top - region.bottom) {
+                return neotron_api::Result::Err(neotron_api::Error::InvalidArg);
+            }
+            let OpenHandle::Overlay(file) = core::mem::replace(h, OpenHandle::Closed) else {
+                unreachable!();
+            };
+            let (file, result) = load_overlay(file, region);
+            *h = OpenHandle::Overlay(file);
+            match result {
+                Ok((entry, _end)) => neotron_api::Result::Ok(u64::from(entry)),
+                Err(Error::ProgramTooLarge) => {
+                    neotron_api::Result::Err(neotron_api::Error::OutOfMemory)
+                }
+                Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+            }
+        }
+        (OpenHandle::Sys { .. }, 0) => neotron_api::Result::Ok(os_version()),
+        (OpenHandle::Sys { .. }, 1) => {
+            neotron_api::Result::Ok(u64::from((api.api_version_get)().0))
+        }
+        (OpenHandle::Sys { .. }, 2) => neotron_api::Result::Ok(VRAM_POOL.lock().tpa_size() as u64),
+        (OpenHandle::Sys { .. }, 3) => {
+            neotron_api::Result::Ok(u64::from_str_radix(env!("OS_GIT_HASH"), 16).unwrap_or(0))
+        }
+        (OpenHandle::Midi(port), 0) => neotron_api::Result::Ok(u64::from(*port)),
+        #[cfg(feature = "net")]
+        (OpenHandle::Tcp(connection), 0) => neotron_api::Result::Ok(match connection.state() {
+            crate::net::ConnectionState::Connecting => 0,
+            crate::net::ConnectionState::Connected => 1,
+            crate::net::ConnectionState::Closed => 2,
+        }),
+        (OpenHandle::Pad, 0) => neotron_api::Result::Ok(crate::joypad::num_pads() as u64),
+        (OpenHandle::Pad, 1) => match crate::joypad::get(value as usize) {
+            Some(state) => neotron_api::Result::Ok(state.as_u64()),
+            None => neotron_api::Result::Err(neotron_api::Error::InvalidArg),
+        },
+        (h @ OpenHandle::Overlay(_), 1) => {
+            // Getting the overlay size
+            let OpenHandle::Overlay(file) = core::mem::replace(h, OpenHandle::Closed) else {
+                unreachable!();
+            };
+            let (file, result) = overlay_size(file);
+            *h = OpenHandle::Overlay(file);
+            match result {
+                Ok(size) => neotron_api::Result::Ok(u64::from(size)),
+                Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+            }
+        }
         _ => neotron_api::Result::Err(neotron_api::Error::InvalidArg),
     }
 }
@@ -661,9 +2657,23 @@ extern "C" fn api_readdir(
 
 /// Get information about a file
 extern "C" fn api_stat(
-    _path: neotron_api::FfiString,
+    path: neotron_api::FfiString,
 ) -> neotron_api::Result<neotron_api::file::Stat> {
-    neotron_api::Result::Err(neotron_api::Error::Unimplemented)
+    match FILESYSTEM.stat(path.as_str()) {
+        Ok(entry) => {
+            let stat = neotron_api::file::Stat {
+                file_size: u64::from(entry.size),
+                ctime: fs::convert_timestamp(&entry.ctime),
+                mtime: fs::convert_timestamp(&entry.mtime),
+                attr: fs::convert_attributes(entry.attributes),
+            };
+            neotron_api::Result::Ok(stat)
+        }
+        Err(fs::Error::Io(embedded_sdmmc::Error::NotFound)) => {
+            neotron_api::Result::Err(neotron_api::Error::InvalidPath)
+        }
+        Err(_e) => neotron_api::Result::Err(neotron_api::Error::DeviceSpecific),
+    }
 }
 
 /// Get information about an open file
@@ -695,6 +2705,17 @@ extern "C" fn api_fstat(
             };
             neotron_api::Result::Ok(stat)
         }
+        Some(OpenHandle::Rom {
+            contents, ctime, ..
+        }) => {
+            let stat = neotron_api::file::Stat {
+                file_size: contents.len() as u64,
+                ctime: *ctime,
+                mtime: *ctime,
+                attr: neotron_api::file::Attributes::READ_ONLY,
+            };
+            neotron_api::Result::Ok(stat)
+        }
         _ => neotron_api::Result::Err(neotron_api::Error::InvalidArg),
     }
 }
@@ -749,6 +2770,206 @@ extern "C" fn api_malloc(
 /// Free some previously allocated memory
 extern "C" fn api_free(_ptr: *mut core::ffi::c_void, _size: usize, _alignment: usize) {}
 
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make a TPA of `len` bytes
+    fn make_tpa(len: usize) -> TransientProgramArea {
+        let memory: &'static mut [u32] = Box::leak(vec![0u32; len / 4].into_boxed_slice());
+        unsafe { TransientProgramArea::new(memory.as_mut_ptr(), len) }
+    }
+
+    #[test]
+    fn reserve_and_release() {
+        let mut tpa = make_tpa(4096);
+        let end = tpa.as_slice_u8().as_ptr_range().end as usize;
+        let first = tpa.reserve("first", 1000).unwrap();
+        assert_eq!(first.len(), 1000);
+        assert_eq!(first.as_ptr() as usize, end - 1000);
+        // Sizes are rounded up to whole words
+        let second = tpa.reserve("second", 999).unwrap();
+        assert_eq!(second.len(), 1000);
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 2000);
+        assert_eq!(tpa.reservations().len(), 2);
+        assert_eq!(tpa.reservations()[1].name, "second");
+        // Releasing the lower one grows the TPA back
+        tpa.release(second);
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 1000);
+        tpa.release(first);
+        assert_eq!(tpa.as_slice_u8().len(), 4096);
+        assert!(tpa.reservations().is_empty());
+    }
+
+    #[test]
+    fn program_path_too_long() {
+        assert_eq!(program_path("/", "HELLO.ELF").unwrap(), "/HELLO.ELF");
+        let name = "A".repeat(MAX_PATH_LEN - 1);
+        assert_eq!(program_path("/", &name).unwrap().len(), MAX_PATH_LEN);
+        assert!(matches!(
+            program_path("ROM:", &name),
+            Err(Error::PathTooLong)
+        ));
+    }
+
+    #[test]
+    fn release_out_of_order() {
+        let mut tpa = make_tpa(4096);
+        let first = tpa.reserve("first", 1000).unwrap();
+        let first_addr = first.as_ptr() as usize;
+        let second = tpa.reserve("second", 1000).unwrap();
+        tpa.release(first);
+        // The TPA can't grow past the second one
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 2000);
+        // Something small goes in the hole
+        let third = tpa.reserve("third", 500).unwrap();
+        assert_eq!(third.as_ptr() as usize, first_addr + 500);
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 2000);
+        // Something too big for the hole goes below
+        let fourth = tpa.reserve("fourth", 600).unwrap();
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 2600);
+        let names: Vec<&str> = tpa.reservations().iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["third", "second", "fourth"]);
+        tpa.release(second);
+        tpa.release(fourth);
+        assert_eq!(tpa.as_slice_u8().len(), 4096 - 500);
+        tpa.release(third);
+        assert_eq!(tpa.as_slice_u8().len(), 4096);
+    }
+
+    #[test]
+    fn reserve_too_much() {
+        let mut tpa = make_tpa(4096);
+        // There has to be some TPA left
+        assert!(tpa.reserve("all", 4096).is_none());
+        assert!(tpa.reserve("most", 4092).is_some());
+        assert!(tpa.reserve("more", 4).is_none());
+    }
+
+    #[test]
+    fn reserve_aligned() {
+        let mut tpa = make_tpa(8192);
+        let end = tpa.as_slice_u8().as_ptr_range().end as usize;
+        let first = tpa.reserve("first", 100).unwrap();
+        assert_eq!(first.as_ptr() as usize, end - 100);
+        let table = tpa.reserve_aligned("table", 1024, 1024).unwrap();
+        assert_eq!(table.len(), 1024);
+        assert_eq!(table.as_ptr() as usize % 1024, 0);
+        assert!(table.as_ptr_range().end as usize <= end - 100);
+        // The gap left over is still used
+        let gap = (end - 100) - table.as_ptr_range().end as usize;
+        if gap >= 4 {
+            let small = tpa.reserve("small", 4).unwrap();
+            assert_eq!(small.as_ptr() as usize, end - 104);
+        }
+    }
+
+    #[test]
+    fn overlaps() {
+        let reservation = Reservation {
+            name: heapless::String::from("RAM disk"),
+            address: 0x2000_1000,
+            size: 0x400,
+        };
+        assert_eq!(reservation.end(), 0x2000_1400);
+        assert!(!reservation.overlaps(0x2000_0000, 0x2000_1000));
+        assert!(!reservation.overlaps(0x2000_1400, 0x2000_2000));
+        assert!(reservation.overlaps(0x2000_0000, 0x2000_1001));
+        assert!(reservation.overlaps(0x2000_13FF, 0x2000_2000));
+        assert!(reservation.overlaps(0x2000_0000, 0x2000_2000));
+    }
+
+    #[test]
+    fn vram_above_program() {
+        let memory: &'static mut [u32] = Box::leak(vec![0u32; 1024].into_boxed_slice());
+        let range = memory.as_mut_ptr_range();
+        let mut pool = VramPool::new();
+        // A 3 KiB program leaves 1 KiB free
+        pool.reset(range.start, unsafe { range.start.add(768) }, range.end);
+        assert!(pool.allocate(1024).is_none());
+        assert_eq!(pool.allocate(1000), Some(unsafe { range.end.sub(250) }));
+        assert_eq!(pool.tpa_size(), 4096);
+        // A program that fills the TPA leaves nothing
+        pool.reset(range.start, range.end, range.end);
+        assert!(pool.allocate(4).is_none());
+    }
+
+    #[test]
+    fn nested_handle_tables() {
+        let _bios = crate::mock_bios::MockBios::new();
+        with_std_handles(|_api| {
+            assert_eq!(allocate_handle(OpenHandle::Clock).ok(), Some(3));
+            // Something run from inside gets a whole table of its own
+            with_std_handles(|_api| {
+                for expected in STD_HANDLES..MAX_OPEN_HANDLES {
+                    assert_eq!(allocate_handle(OpenHandle::Pad).ok(), Some(expected));
+                }
+                assert!(allocate_handle(OpenHandle::Pad).is_err());
+                0
+            });
+            // And ours are just as we left them
+            let handles = OPEN_HANDLES.lock();
+            assert!(matches!(handles[3], OpenHandle::Clock));
+            assert!(matches!(handles[4], OpenHandle::Closed));
+            0
+        });
+    }
+
+    #[test]
+    fn api_open_shares_files() {
+        use neotron_api::file::Flags;
+        let bios = crate::mock_bios::MockBios::new();
+        bios.insert_blank_disk(8192);
+        let _shell = bios.boot();
+        let path = || neotron_api::FfiString::new("SHARED.TXT");
+        with_std_handles(|_api| {
+            let writer = api_open(path(), Flags::WRITE | Flags::CREATE).unwrap();
+            // Nobody else can open it while it's being written
+            assert!(matches!(
+                api_open(path(), Flags::empty()),
+                neotron_api::Result::Err(neotron_api::Error::DeviceSpecific)
+            ));
+            assert!(matches!(
+                api_open(path(), Flags::WRITE),
+                neotron_api::Result::Err(neotron_api::Error::DeviceSpecific)
+            ));
+            assert!(matches!(api_close(writer), neotron_api::Result::Ok(())));
+            // But any number of readers can share it
+            let first = api_open(path(), Flags::empty()).unwrap();
+            let second = api_open(path(), Flags::empty()).unwrap();
+            assert_ne!(first.value(), second.value());
+            assert!(matches!(
+                api_open(path(), Flags::WRITE),
+                neotron_api::Result::Err(neotron_api::Error::DeviceSpecific)
+            ));
+            0
+        });
+    }
+
+    #[test]
+    fn recover_goes_back_to_the_shell() {
+        let _bios = crate::mock_bios::MockBios::new();
+        // Things were running inside each other when one of them faulted
+        for _ in 0..MAX_HANDLE_TABLES {
+            let mut handles = [CLOSED_HANDLE; MAX_OPEN_HANDLES];
+            handles[0] = OpenHandle::Clock;
+            assert!(OPEN_HANDLES.lock().tables.push(handles).is_ok());
+        }
+        core::mem::forget(OPEN_HANDLES.lock());
+        unsafe {
+            force_unlock();
+            recover();
+        }
+        assert!(OPEN_HANDLES.lock().tables.is_empty());
+        // So there's room to run things again
+        assert_eq!(with_std_handles(|_api| 7), 7);
+    }
+}
+
 // ===========================================================================
 // End of file
 // ===========================================================================
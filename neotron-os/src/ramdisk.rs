@@ -0,0 +1,488 @@
+//! # RAM Disk
+//!
+//! A disk made from memory taken off the top of the TPA, so you have
+//! somewhere fast to keep files even without an SD card. It shows up as
+//! `RAM:`, so `RAM:NOTES.TXT` is a file on it. Everything on it is lost when
+//! the power goes off.
+//!
+//! `embedded-sdmmc` only understands FAT16 and FAT32, and even FAT16 needs at
+//! least 4085 clusters, which is more memory than we usually have. So the
+//! disk pretends to have [`NUM_CLUSTERS`] clusters, and marks the ones we
+//! don't have the memory for as bad in the FAT, so they are never used. The
+//! boot records, and the parts of the FAT that only list bad clusters, are
+//! made up as they are read, so they take no memory either.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios, refcell::CsRefCell};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The smallest RAM disk we make, in KiB
+pub const MIN_KIB: u16 = 8;
+
+/// The biggest RAM disk we make, in KiB (enough to back every cluster)
+pub const MAX_KIB: u16 = 2048 + 16;
+
+/// How big a block is
+const BLOCK_LEN: usize = embedded_sdmmc::Block::LEN;
+
+/// How many clusters (of one block each) the disk says it has
+const NUM_CLUSTERS: u32 = 4096;
+
+/// FAT16 entries per block
+const FAT_ENTRIES_PER_BLOCK: u32 = (BLOCK_LEN / 2) as u32;
+
+/// How many blocks the FAT takes up (the first two entries are reserved)
+const FAT_BLOCKS: u32 = (NUM_CLUSTERS + 2).div_ceil(FAT_ENTRIES_PER_BLOCK);
+
+/// How many files the root directory can hold
+const ROOT_ENTRIES: u16 = 64;
+
+/// How many blocks the root directory takes up
+const ROOT_BLOCKS: u32 = ROOT_ENTRIES as u32 * 32 / BLOCK_LEN as u32;
+
+/// Where the partition starts (after the Master Boot Record)
+const PARTITION_START: u32 = 1;
+
+/// Where the FAT starts (after the boot record)
+const FIRST_FAT_BLOCK: u32 = PARTITION_START + 1;
+
+/// Where the root directory starts
+const FIRST_ROOT_BLOCK: u32 = FIRST_FAT_BLOCK + FAT_BLOCKS;
+
+/// Where cluster 2 (the first one) starts
+const FIRST_DATA_BLOCK: u32 = FIRST_ROOT_BLOCK + ROOT_BLOCKS;
+
+/// How many blocks the disk says it has
+const TOTAL_BLOCKS: u32 = FIRST_DATA_BLOCK + NUM_CLUSTERS;
+
+/// The FAT entry for a bad cluster
+const BAD_CLUSTER: u16 = 0xFFF7;
+
+/// The RAM disk, if there is one
+static RAM_DISK: CsRefCell<Option<RamDisk>> = CsRefCell::new(None);
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways making or removing a RAM disk can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There's already a RAM disk
+    Exists,
+    /// There isn't a RAM disk
+    NoDisk,
+    /// That's too small or too big for a RAM disk
+    BadSize,
+    /// There isn't that much room in the TPA
+    NoMemory,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Exists => write!(f, "There is already a RAM disk"),
+            Error::NoDisk => write!(f, "There is no RAM disk"),
+            Error::BadSize => write!(
+                f,
+                "A RAM disk must be between {} and {} KiB",
+                MIN_KIB, MAX_KIB
+            ),
+            Error::NoMemory => write!(f, "Not enough room in the TPA"),
+        }
+    }
+}
+
+/// Reads and writes blocks on the RAM disk
+pub struct RamBlock;
+
+impl embedded_sdmmc::BlockDevice for RamBlock {
+    type Error = bios::Error;
+
+    fn read(
+        &self,
+        blocks: &mut [embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let guard = RAM_DISK.lock();
+        let disk = guard.as_ref().ok_or(bios::Error::NoMediaFound)?;
+        for (block, idx) in blocks.iter_mut().zip(start_block_idx.0..) {
+            disk.read_block(idx, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        blocks: &[embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+    ) -> Result<(), Self::Error> {
+        let mut guard = RAM_DISK.lock();
+        let disk = guard.as_mut().ok_or(bios::Error::NoMediaFound)?;
+        for (block, idx) in blocks.iter().zip(start_block_idx.0..) {
+            disk.write_block(idx, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, Self::Error> {
+        num_blocks()
+            .map(embedded_sdmmc::BlockCount)
+            .ok_or(bios::Error::NoMediaFound)
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// A formatted RAM disk
+struct RamDisk {
+    /// The memory we keep the blocks in
+    memory: &'static mut [u8],
+    /// How many blocks of the FAT are in `memory`
+    fat_blocks: u32,
+    /// How many clusters are in `memory`
+    clusters: u32,
+}
+
+/// Where a block of the disk comes from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Location {
+    /// The Master Boot Record, which we make up
+    Mbr,
+    /// The FAT16 boot record, which we make up
+    BootRecord,
+    /// A block of the FAT listing only bad clusters, which we make up
+    BadFat,
+    /// A cluster we don't have the memory for
+    Missing,
+    /// A block in our memory
+    Memory(usize),
+}
+
+impl RamDisk {
+    /// Format some memory as a RAM disk
+    fn format(memory: &'static mut [u8]) -> Result<RamDisk, &'static mut [u8]> {
+        let num_blocks = (memory.len() / BLOCK_LEN) as u32;
+        let fat_blocks = (num_blocks + 2)
+            .div_ceil(FAT_ENTRIES_PER_BLOCK)
+            .min(FAT_BLOCKS);
+        let clusters = num_blocks
+            .saturating_sub(fat_blocks + ROOT_BLOCKS)
+            .min(NUM_CLUSTERS);
+        if clusters == 0 {
+            return Err(memory);
+        }
+        memory.fill(0);
+        let fat = &mut memory[0..fat_blocks as usize * BLOCK_LEN];
+        for (cluster, entry) in fat.chunks_exact_mut(2).enumerate() {
+            let value = match cluster as u32 {
+                // The media byte, and the clean-shutdown flags
+                0 => 0xFFF8,
+                1 => 0xFFFF,
+                n if n >= clusters + 2 => BAD_CLUSTER,
+                _ => 0,
+            };
+            entry.copy_from_slice(&value.to_le_bytes());
+        }
+        Ok(RamDisk {
+            memory,
+            fat_blocks,
+            clusters,
+        })
+    }
+
+    /// Where does this block come from?
+    fn locate(&self, idx: u32) -> Location {
+        if idx < PARTITION_START {
+            Location::Mbr
+        } else if idx < FIRST_FAT_BLOCK {
+            Location::BootRecord
+        } else if idx < FIRST_ROOT_BLOCK {
+            let fat_block = idx - FIRST_FAT_BLOCK;
+            if fat_block < self.fat_blocks {
+                Location::Memory(fat_block as usize)
+            } else {
+                Location::BadFat
+            }
+        } else if idx < FIRST_DATA_BLOCK {
+            Location::Memory((self.fat_blocks + idx - FIRST_ROOT_BLOCK) as usize)
+        } else {
+            let cluster = idx - FIRST_DATA_BLOCK;
+            if cluster < self.clusters {
+                Location::Memory((self.fat_blocks + ROOT_BLOCKS + cluster) as usize)
+            } else {
+                Location::Missing
+            }
+        }
+    }
+
+    /// Read a block
+    fn read_block(&self, idx: u32, out: &mut [u8; BLOCK_LEN]) -> Result<(), bios::Error> {
+        if idx >= TOTAL_BLOCKS {
+            return Err(bios::Error::BlockOutOfBounds);
+        }
+        match self.locate(idx) {
+            Location::Mbr => write_mbr(out),
+            Location::BootRecord => write_boot_record(out),
+            Location::BadFat => {
+                for entry in out.chunks_exact_mut(2) {
+                    entry.copy_from_slice(&BAD_CLUSTER.to_le_bytes());
+                }
+            }
+            Location::Missing => out.fill(0),
+            Location::Memory(n) => {
+                out.copy_from_slice(&self.memory[n * BLOCK_LEN..(n + 1) * BLOCK_LEN]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a block
+    ///
+    /// Only blocks we keep in memory can be written - nothing should write
+    /// the others.
+    fn write_block(&mut self, idx: u32, data: &[u8; BLOCK_LEN]) -> Result<(), bios::Error> {
+        match self.locate(idx) {
+            Location::Memory(n) if idx < TOTAL_BLOCKS => {
+                self.memory[n * BLOCK_LEN..(n + 1) * BLOCK_LEN].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(bios::Error::BlockOutOfBounds),
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Make a RAM disk, with memory reserved off the top of the TPA.
+pub fn create(tpa: &mut crate::program::TransientProgramArea, kib: u16) -> Result<(), Error> {
+    if !(MIN_KIB..=MAX_KIB).contains(&kib) {
+        return Err(Error::BadSize);
+    }
+    let mut guard = RAM_DISK.lock();
+    if guard.is_some() {
+        return Err(Error::Exists);
+    }
+    let memory = tpa
+        .reserve("RAM disk", usize::from(kib) * 1024)
+        .ok_or(Error::NoMemory)?;
+    match RamDisk::format(memory) {
+        Ok(disk) => {
+            *guard = Some(disk);
+            drop(guard);
+            crate::RAM_FILESYSTEM.disk_changed();
+            Ok(())
+        }
+        Err(memory) => {
+            tpa.release(memory);
+            Err(Error::BadSize)
+        }
+    }
+}
+
+/// Throw away the RAM disk, and everything on it, and give its memory back
+/// to the TPA.
+///
+/// If anything has reserved memory below the RAM disk since it was made, the
+/// TPA doesn't grow until that is released too, but a new RAM disk can go
+/// in the gap.
+pub fn remove(tpa: &mut crate::program::TransientProgramArea) -> Result<(), Error> {
+    let disk = RAM_DISK.lock().take().ok_or(Error::NoDisk)?;
+    crate::RAM_FILESYSTEM.disk_changed();
+    tpa.release(disk.memory);
+    Ok(())
+}
+
+/// How much memory does the RAM disk use, and how much of that can hold
+/// files, in bytes?
+pub fn size() -> Option<(usize, usize)> {
+    RAM_DISK
+        .lock()
+        .as_ref()
+        .map(|disk| (disk.memory.len(), disk.clusters as usize * BLOCK_LEN))
+}
+
+/// How many blocks the RAM disk says it has, if there is one
+pub fn num_blocks() -> Option<u32> {
+    RAM_DISK.lock().as_ref().map(|_| TOTAL_BLOCKS)
+}
+
+/// Unlock the RAM disk, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    RAM_DISK.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Make up a Master Boot Record, with one FAT16 partition
+fn write_mbr(out: &mut [u8; BLOCK_LEN]) {
+    out.fill(0);
+    let partition = &mut out[446..462];
+    // Type 0x06 is FAT16
+    partition[4] = 0x06;
+    partition[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+    partition[12..16].copy_from_slice(&(TOTAL_BLOCKS - PARTITION_START).to_le_bytes());
+    out[510] = 0x55;
+    out[511] = 0xAA;
+}
+
+/// Make up a FAT16 boot record
+fn write_boot_record(out: &mut [u8; BLOCK_LEN]) {
+    out.fill(0);
+    out[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    out[3..11].copy_from_slice(b"NEOTRON ");
+    out[11..13].copy_from_slice(&(BLOCK_LEN as u16).to_le_bytes());
+    // One block per cluster
+    out[13] = 1;
+    // Just the boot record is reserved
+    out[14..16].copy_from_slice(&1u16.to_le_bytes());
+    // One copy of the FAT
+    out[16] = 1;
+    out[17..19].copy_from_slice(&ROOT_ENTRIES.to_le_bytes());
+    out[19..21].copy_from_slice(&((TOTAL_BLOCKS - PARTITION_START) as u16).to_le_bytes());
+    // A fixed disk
+    out[21] = 0xF8;
+    out[22..24].copy_from_slice(&(FAT_BLOCKS as u16).to_le_bytes());
+    out[28..32].copy_from_slice(&PARTITION_START.to_le_bytes());
+    out[36] = 0x80;
+    // There's a serial number, label and type after this
+    out[38] = 0x29;
+    out[43..54].copy_from_slice(b"RAMDISK    ");
+    out[54..62].copy_from_slice(b"FAT16   ");
+    out[510] = 0x55;
+    out[511] = 0xAA;
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::RefCell;
+
+    /// A RAM disk that isn't the global one
+    struct TestBlock(RefCell<RamDisk>);
+
+    impl embedded_sdmmc::BlockDevice for TestBlock {
+        type Error = bios::Error;
+
+        fn read(
+            &self,
+            blocks: &mut [embedded_sdmmc::Block],
+            start_block_idx: embedded_sdmmc::BlockIdx,
+            _reason: &str,
+        ) -> Result<(), Self::Error> {
+            for (block, idx) in blocks.iter_mut().zip(start_block_idx.0..) {
+                self.0.borrow().read_block(idx, &mut block.contents)?;
+            }
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            blocks: &[embedded_sdmmc::Block],
+            start_block_idx: embedded_sdmmc::BlockIdx,
+        ) -> Result<(), Self::Error> {
+            for (block, idx) in blocks.iter().zip(start_block_idx.0..) {
+                self.0.borrow_mut().write_block(idx, &block.contents)?;
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, Self::Error> {
+            Ok(embedded_sdmmc::BlockCount(TOTAL_BLOCKS))
+        }
+    }
+
+    struct NoTime;
+
+    impl embedded_sdmmc::TimeSource for NoTime {
+        fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
+            embedded_sdmmc::Timestamp::from_calendar(2024, 1, 1, 0, 0, 0).unwrap()
+        }
+    }
+
+    fn make_disk(kib: usize) -> TestBlock {
+        let memory = vec![0xA5u8; kib * 1024].leak();
+        TestBlock(RefCell::new(RamDisk::format(memory).ok().unwrap()))
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(FAT_BLOCKS, 17);
+        assert_eq!(TOTAL_BLOCKS, 4119);
+        let disk = make_disk(16);
+        let disk = disk.0.borrow();
+        // 32 blocks, less one of FAT and four of root directory
+        assert_eq!(disk.fat_blocks, 1);
+        assert_eq!(disk.clusters, 27);
+        assert_eq!(disk.locate(0), Location::Mbr);
+        assert_eq!(disk.locate(FIRST_FAT_BLOCK), Location::Memory(0));
+        assert_eq!(disk.locate(FIRST_FAT_BLOCK + 1), Location::BadFat);
+        assert_eq!(disk.locate(FIRST_ROOT_BLOCK), Location::Memory(1));
+        assert_eq!(disk.locate(FIRST_DATA_BLOCK + 26), Location::Memory(31));
+        assert_eq!(disk.locate(FIRST_DATA_BLOCK + 27), Location::Missing);
+    }
+
+    #[test]
+    fn too_small() {
+        let memory = vec![0u8; 4 * BLOCK_LEN].leak();
+        assert!(RamDisk::format(memory).is_err());
+    }
+
+    #[test]
+    fn files() {
+        let disk = make_disk(16);
+        let mut mgr: embedded_sdmmc::VolumeManager<_, _, 4, 4, 1> =
+            embedded_sdmmc::VolumeManager::new_with_limits(
+                disk,
+                NoTime,
+                crate::fs::FIRST_HANDLE_ID,
+            );
+        let mut volume = mgr.open_volume(embedded_sdmmc::VolumeIdx(0)).unwrap();
+        let mut root = volume.open_root_dir().unwrap();
+        let data = [0x42u8; 1000];
+        {
+            let mut file = root
+                .open_file_in_dir("HELLO.TXT", embedded_sdmmc::Mode::ReadWriteCreate)
+                .unwrap();
+            file.write(&data).unwrap();
+        }
+        {
+            let mut file = root
+                .open_file_in_dir("HELLO.TXT", embedded_sdmmc::Mode::ReadOnly)
+                .unwrap();
+            let mut buffer = [0u8; 1024];
+            assert_eq!(file.read(&mut buffer).unwrap(), 1000);
+            assert_eq!(&buffer[0..1000], &data[..]);
+        }
+        // Fill it up - we must run out of space, not write to memory we
+        // don't have
+        let mut file = root
+            .open_file_in_dir("BIG.DAT", embedded_sdmmc::Mode::ReadWriteCreate)
+            .unwrap();
+        let mut written = 0;
+        while file.write(&data).is_ok() {
+            written += data.len();
+            assert!(written < 16 * 1024);
+        }
+    }
+}
+
+// End of file
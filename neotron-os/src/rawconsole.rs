@@ -0,0 +1,297 @@
+//! # Raw Console
+//!
+//! A last-resort way to print, for when the OS has panicked.
+//!
+//! The usual consoles keep their state behind locks, and the VGA console
+//! checks the cursor is where it expects. If we panicked half-way through
+//! printing, the locks are held and the state might be nonsense, so printing
+//! the panic message that way could lose it, or panic all over again.
+//!
+//! This writes straight into whatever text-mode framebuffer the BIOS says
+//! it is showing, and straight out of the serial console's UART, without
+//! taking any locks or checking anything it doesn't have to. It does its
+//! best, and quietly gives up on anything it can't do.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    bios::{
+        self,
+        video::{Attr, TextBackgroundColour, TextForegroundColour},
+    },
+    vgaconsole::VgaConsole,
+    API,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Means there's no serial console
+const NO_PORT: u8 = 0xFF;
+
+/// What we print in - white on red, so it stands out
+const ATTR: Attr = Attr::new(
+    TextForegroundColour::White,
+    TextBackgroundColour::Red,
+    false,
+);
+
+/// The UART the serial console is on, or [`NO_PORT`]
+static SERIAL_PORT: AtomicU8 = AtomicU8::new(NO_PORT);
+
+/// Which row we print on next, or `usize::MAX` if we haven't printed
+/// anything yet
+static ROW: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Which column we print in next
+static COL: AtomicUsize = AtomicUsize::new(0);
+
+/// Are we in the middle of an escape sequence?
+static IN_ESCAPE: AtomicBool = AtomicBool::new(false);
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Where we are on the screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    in_escape: bool,
+}
+
+/// A text-mode framebuffer, with a glyph byte and an attribute byte for
+/// each character cell
+struct TextScreen {
+    base: *mut u8,
+    width: usize,
+    height: usize,
+}
+
+impl TextScreen {
+    /// Print some text, moving the cursor along.
+    ///
+    /// Escape sequences are skipped, and we scroll when we get to the
+    /// bottom.
+    fn write(&self, cursor: &mut Cursor, s: &str) {
+        if cursor.row >= self.height || cursor.col > self.width {
+            // We've not printed anything yet (or the mode changed), so
+            // start on a fresh line at the bottom
+            self.scroll();
+            cursor.row = self.height - 1;
+            cursor.col = 0;
+        }
+        for ch in s.chars() {
+            if cursor.in_escape {
+                // Escape sequences end with a letter
+                cursor.in_escape = !ch.is_ascii_alphabetic();
+                continue;
+            }
+            match ch {
+                '\u{001b}' => cursor.in_escape = true,
+                '\r' => cursor.col = 0,
+                '\n' => self.new_line(cursor),
+                '\t' => {
+                    let next = (cursor.col + 8) & !7;
+                    while cursor.col < next.min(self.width) {
+                        self.put(cursor.row, cursor.col, b' ');
+                        cursor.col += 1;
+                    }
+                }
+                ch => {
+                    if cursor.col >= self.width {
+                        self.new_line(cursor);
+                    }
+                    self.put(cursor.row, cursor.col, VgaConsole::char_to_glyph(ch));
+                    cursor.col += 1;
+                }
+            }
+        }
+    }
+
+    /// Go to the start of the next line, scrolling if we're at the bottom
+    fn new_line(&self, cursor: &mut Cursor) {
+        cursor.col = 0;
+        if cursor.row + 1 >= self.height {
+            self.scroll();
+        } else {
+            cursor.row += 1;
+        }
+    }
+
+    /// Put a glyph on the screen
+    fn put(&self, row: usize, col: usize, glyph: u8) {
+        let offset = ((row * self.width) + col) * 2;
+        unsafe {
+            self.base.add(offset).write_volatile(glyph);
+            self.base.add(offset + 1).write_volatile(ATTR.as_u8());
+        }
+    }
+
+    /// Move everything up a line, and blank the bottom line
+    fn scroll(&self) {
+        let row_len = self.width * 2;
+        for offset in 0..(self.height - 1) * row_len {
+            unsafe {
+                let byte = self.base.add(offset + row_len).read_volatile();
+                self.base.add(offset).write_volatile(byte);
+            }
+        }
+        for col in 0..self.width {
+            self.put(self.height - 1, col, b' ');
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Say which UART the serial console is on, if any
+pub fn set_serial_port(port: Option<u8>) {
+    SERIAL_PORT.store(port.unwrap_or(NO_PORT), Ordering::Relaxed);
+}
+
+/// Print some text on the screen and the serial console
+pub fn write_str(s: &str) {
+    let Some(api) = API.try_get() else {
+        // We're so early we can't print anything
+        return;
+    };
+    write_screen(api, s);
+    write_serial(api, s.as_bytes());
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Print some text on the screen, if it's in a text mode
+fn write_screen(api: &bios::Api, s: &str) {
+    let mode = (api.video_get_mode)();
+    let (Some(width), Some(height)) = (mode.text_width(), mode.text_height()) else {
+        return;
+    };
+    let base = (api.video_get_framebuffer)() as *mut u8;
+    if base.is_null() || width == 0 || height == 0 {
+        return;
+    }
+    let screen = TextScreen {
+        base,
+        width: usize::from(width),
+        height: usize::from(height),
+    };
+    let mut cursor = Cursor {
+        row: ROW.load(Ordering::Relaxed),
+        col: COL.load(Ordering::Relaxed),
+        in_escape: IN_ESCAPE.load(Ordering::Relaxed),
+    };
+    screen.write(&mut cursor, s);
+    ROW.store(cursor.row, Ordering::Relaxed);
+    COL.store(cursor.col, Ordering::Relaxed);
+    IN_ESCAPE.store(cursor.in_escape, Ordering::Relaxed);
+}
+
+/// Send some bytes out of the serial console's UART, if we have one
+fn write_serial(api: &bios::Api, mut data: &[u8]) {
+    let port = SERIAL_PORT.load(Ordering::Relaxed);
+    if port == NO_PORT {
+        return;
+    }
+    while !data.is_empty() {
+        match (api.serial_write)(port, bios::FfiByteSlice::new(data), bios::FfiOption::None) {
+            bios::ApiResult::Ok(count) if count > 0 => data = &data[count.min(data.len())..],
+            _ => break,
+        }
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the text on each row of the screen
+    fn rows(cells: &[u8], width: usize) -> Vec<String> {
+        cells
+            .chunks(width * 2)
+            .map(|row| {
+                row.chunks(2)
+                    .map(|cell| cell[0] as char)
+                    .collect::<String>()
+                    .trim_end_matches([' ', '\0'])
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn start() -> Cursor {
+        Cursor {
+            row: usize::MAX,
+            col: 0,
+            in_escape: false,
+        }
+    }
+
+    #[test]
+    fn starts_at_the_bottom() {
+        let mut cells = vec![b'x'; 10 * 3 * 2];
+        let screen = TextScreen {
+            base: cells.as_mut_ptr(),
+            width: 10,
+            height: 3,
+        };
+        let mut cursor = start();
+        screen.write(&mut cursor, "PANIC!");
+        assert_eq!(rows(&cells, 10), ["xxxxxxxxxx", "xxxxxxxxxx", "PANIC!"]);
+        assert_eq!(cells[1], b'x');
+        assert_eq!(cells[(2 * 10 * 2) + 1], ATTR.as_u8());
+    }
+
+    #[test]
+    fn wraps_and_scrolls() {
+        let mut cells = vec![0u8; 4 * 3 * 2];
+        let screen = TextScreen {
+            base: cells.as_mut_ptr(),
+            width: 4,
+            height: 3,
+        };
+        let mut cursor = start();
+        screen.write(&mut cursor, "abcdef\nxy");
+        screen.write(&mut cursor, "z\r!");
+        assert_eq!(rows(&cells, 4), ["abcd", "ef", "!yz"]);
+        assert_eq!(
+            cursor,
+            Cursor {
+                row: 2,
+                col: 1,
+                in_escape: false
+            }
+        );
+    }
+
+    #[test]
+    fn skips_escapes() {
+        let mut cells = vec![0u8; 10 * 2 * 2];
+        let screen = TextScreen {
+            base: cells.as_mut_ptr(),
+            width: 10,
+            height: 2,
+        };
+        let mut cursor = start();
+        screen.write(&mut cursor, "a\u{001b}[41");
+        screen.write(&mut cursor, ";37mb\tc");
+        assert_eq!(rows(&cells, 10), ["", "ab      c"]);
+    }
+}
+
+// End of file
@@ -77,6 +77,16 @@ impl<T> CsRefCell<T> {
             Err(LockError)
         }
     }
+
+    /// Unlock the cell, even if someone holds a guard for it.
+    ///
+    /// # Safety
+    ///
+    /// Only do this when whoever holds the guard will never run again, like
+    /// code that was abandoned after a CPU fault.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
 }
 
 /// Mark our type as thread-safe.
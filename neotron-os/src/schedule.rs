@@ -0,0 +1,431 @@
+//! # Scheduled Commands
+//!
+//! Runs the commands given to `at` when their time comes. They are kept in
+//! the config (and saved whenever they change), so they survive a restart.
+//!
+//! Commands only run from the shell prompt - if a program is running, they
+//! wait until it finishes.
+//!
+//! The BIOS API can't set an alarm, but if there is an MCP7940N real-time
+//! clock (like the one on a Neotron Pico) on one of the I²C buses, we set its
+//! alarm for the next command. Its MFP pin changes when the alarm goes off,
+//! so a board that wires that pin up to its power supply can switch itself
+//! on in time to run the command.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::convert::TryFrom;
+
+use chrono::{Datelike, Timelike};
+
+use crate::{bios, config::Config, osprintln, refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// If we start up this long after a command should have run (in seconds), we
+/// still run it. Starting up for the alarm takes a little while.
+const MISSED_GRACE_SECS: u32 = 300;
+
+/// The I²C address of an MCP7940N real-time clock
+const MCP7940N_ADDRESS: u8 = 0x6F;
+
+/// The MCP7940N's first time register (seconds)
+const MCP7940N_RTCSEC: u8 = 0x00;
+
+/// The MCP7940N's control register
+const MCP7940N_CONTROL: u8 = 0x07;
+
+/// The MCP7940N's first alarm 0 register (seconds)
+const MCP7940N_ALM0SEC: u8 = 0x0A;
+
+/// The bit in the control register that turns alarm 0 on
+const MCP7940N_ALM0EN: u8 = 1 << 4;
+
+/// Our state
+static STATE: CsRefCell<State> = CsRefCell::new(State { last_secs: None });
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Displays a time on the BIOS clock as a local time, like
+/// `2024-02-28 13:30`
+pub struct When(pub u32);
+
+impl core::fmt::Display for When {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let time = from_bios_secs(self.0);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            time.year(),
+            time.month(),
+            time.day(),
+            time.hour(),
+            time.minute()
+        )
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we need to remember between polls
+struct State {
+    /// The time (on the BIOS clock) that we last looked for commands to run
+    last_secs: Option<u32>,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Work out when a command should run, from a time typed in by the user.
+///
+/// We take `HH:MM` (the next time the clock says that), `+<minutes>` (from
+/// now) or `YYYY-MM-DDTHH:MM`. Times are local, like `now`.
+pub fn parse_when(text: &str, now: chrono::NaiveDateTime) -> Option<chrono::NaiveDateTime> {
+    if let Some(minutes) = text.strip_prefix('+') {
+        let minutes = minutes.parse::<u32>().ok()?;
+        return now.checked_add_signed(chrono::TimeDelta::minutes(i64::from(minutes)));
+    }
+    if let Ok(when) = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M") {
+        return Some(when).filter(|when| *when > now);
+    }
+    let time = chrono::NaiveTime::parse_from_str(text, "%H:%M").ok()?;
+    let today = now.date().and_time(time);
+    if today > now {
+        Some(today)
+    } else {
+        today.checked_add_signed(chrono::TimeDelta::days(1))
+    }
+}
+
+/// Convert a local time into seconds on the BIOS clock
+pub fn to_bios_secs(local: chrono::NaiveDateTime) -> Option<u32> {
+    let utc = local - chrono::TimeDelta::minutes(i64::from(API.get_tz_offset()));
+    let secs = utc.and_utc().timestamp() - crate::SECONDS_BETWEEN_UNIX_AND_NEOTRON_EPOCH;
+    u32::try_from(secs).ok()
+}
+
+/// Throw away any commands we missed while the machine was off, and set the
+/// alarm for the next one.
+///
+/// Call this once at start-up.
+pub fn start(config: &mut Config) {
+    let now = (API.get().time_clock_get)().secs;
+    let mut changed = false;
+    while let Some(first) = config.get_scheduled().first() {
+        if now.saturating_sub(first.when) <= MISSED_GRACE_SECS {
+            break;
+        }
+        osprintln!(log: Warn, "Missed scheduled command {:?}", first.command);
+        config.remove_scheduled(0);
+        changed = true;
+    }
+    if changed {
+        save(config);
+    }
+    set_alarm(config);
+}
+
+/// Find the next command that is due to run, and take it out of the
+/// schedule.
+///
+/// We only look once a second. Call this whenever the shell is idle.
+pub fn poll(config: &mut Config) -> Option<heapless::String<32>> {
+    let now = (API.get().time_clock_get)().secs;
+    {
+        let mut state = STATE.lock();
+        if state.last_secs == Some(now) {
+            return None;
+        }
+        state.last_secs = Some(now);
+    }
+    let first = config.get_scheduled().first()?;
+    if first.when > now {
+        return None;
+    }
+    let command = config.remove_scheduled(0)?.command;
+    save(config);
+    set_alarm(config);
+    Some(command)
+}
+
+/// The schedule has changed, so save it and set the alarm again.
+pub fn changed(config: &Config) {
+    save(config);
+    set_alarm(config);
+}
+
+/// Unlock the scheduler, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Convert seconds on the BIOS clock into a local time
+fn from_bios_secs(secs: u32) -> chrono::NaiveDateTime {
+    let unix_secs = i64::from(secs) + crate::SECONDS_BETWEEN_UNIX_AND_NEOTRON_EPOCH;
+    // Every u32 of seconds since 2000 is a valid time
+    chrono::DateTime::from_timestamp(unix_secs, 0)
+        .unwrap_or_default()
+        .naive_utc()
+        + chrono::TimeDelta::minutes(i64::from(API.get_tz_offset()))
+}
+
+/// Save the config, so the schedule survives a restart
+fn save(config: &Config) {
+    if let Err(e) = config.save() {
+        osprintln!(log: Warn, "Can't save scheduled commands: {}", e);
+    }
+}
+
+/// Set the RTC alarm for the first command in the schedule (or turn it off
+/// if there isn't one), if we have an RTC we understand.
+fn set_alarm(config: &Config) {
+    let Some(bus) = find_mcp7940n() else {
+        return;
+    };
+    let result = match config.get_scheduled().first() {
+        Some(first) => {
+            let now = (API.get().time_clock_get)().secs;
+            let from_now = first.when.saturating_sub(now);
+            mcp7940n_set_alarm(bus, from_now)
+        }
+        None => mcp7940n_enable_alarm(bus, false),
+    };
+    if let Err(e) = result {
+        osprintln!(log: Warn, "Can't set RTC alarm: {:?}", e);
+    }
+}
+
+/// Look on each I²C bus for an MCP7940N that is running and set up the way
+/// we expect.
+fn find_mcp7940n() -> Option<u8> {
+    let api = API.get();
+    for bus in 0..=255u8 {
+        if let bios::FfiOption::None = (api.i2c_bus_get_info)(bus) {
+            break;
+        }
+        if mcp7940n_read_time(bus).is_ok() {
+            return Some(bus);
+        }
+    }
+    None
+}
+
+/// Read the time from an MCP7940N.
+///
+/// Fails if there isn't one, or if it holds something we don't understand.
+fn mcp7940n_read_time(bus: u8) -> Result<(chrono::NaiveDateTime, u8), bios::Error> {
+    let mut registers = [0u8; 7];
+    i2c_read(bus, MCP7940N_RTCSEC, &mut registers)?;
+    decode_rtc_time(&registers).ok_or(bios::Error::DeviceError)
+}
+
+/// Set the MCP7940N's alarm 0 to go off in `from_now` seconds.
+///
+/// We work it out from the time in the RTC, so it doesn't matter whether it
+/// runs on UTC or local time.
+fn mcp7940n_set_alarm(bus: u8, from_now: u32) -> Result<(), bios::Error> {
+    let (rtc_now, weekday) = mcp7940n_read_time(bus)?;
+    let alarm = rtc_now + chrono::TimeDelta::seconds(i64::from(from_now));
+    let days = (alarm.date() - rtc_now.date()).num_days();
+    // The alarm matches the weekday too, so count on from today's, however
+    // the RTC numbers them
+    let alarm_weekday = ((i64::from(weekday) - 1 + days) % 7 + 1) as u8;
+    let mut tx = [0u8; 7];
+    tx[0] = MCP7940N_ALM0SEC;
+    tx[1..].copy_from_slice(&encode_alarm(alarm, alarm_weekday));
+    i2c_write(bus, &tx)?;
+    mcp7940n_enable_alarm(bus, true)
+}
+
+/// Turn the MCP7940N's alarm 0 on or off
+fn mcp7940n_enable_alarm(bus: u8, enable: bool) -> Result<(), bios::Error> {
+    let mut control = [0u8; 1];
+    i2c_read(bus, MCP7940N_CONTROL, &mut control)?;
+    let control = if enable {
+        control[0] | MCP7940N_ALM0EN
+    } else {
+        control[0] & !MCP7940N_ALM0EN
+    };
+    i2c_write(bus, &[MCP7940N_CONTROL, control])
+}
+
+/// Read some registers from the MCP7940N
+fn i2c_read(bus: u8, register: u8, buffer: &mut [u8]) -> Result<(), bios::Error> {
+    match (API.get().i2c_write_read)(
+        bus,
+        MCP7940N_ADDRESS,
+        bios::FfiByteSlice::new(&[register]),
+        bios::FfiByteSlice::empty(),
+        bios::FfiBuffer::new(buffer),
+    ) {
+        bios::ApiResult::Ok(()) => Ok(()),
+        bios::ApiResult::Err(e) => Err(e),
+    }
+}
+
+/// Write to the MCP7940N. The first byte is the register to start at.
+fn i2c_write(bus: u8, data: &[u8]) -> Result<(), bios::Error> {
+    match (API.get().i2c_write_read)(
+        bus,
+        MCP7940N_ADDRESS,
+        bios::FfiByteSlice::new(data),
+        bios::FfiByteSlice::empty(),
+        bios::FfiBuffer::empty(),
+    ) {
+        bios::ApiResult::Ok(()) => Ok(()),
+        bios::ApiResult::Err(e) => Err(e),
+    }
+}
+
+/// Decode the MCP7940N's time registers, giving the time and the weekday.
+///
+/// Gives `None` if the clock isn't running, is in 12-hour mode, or holds
+/// something that isn't a time.
+fn decode_rtc_time(registers: &[u8; 7]) -> Option<(chrono::NaiveDateTime, u8)> {
+    // The ST bit - the oscillator is running
+    if registers[0] & 0x80 == 0 {
+        return None;
+    }
+    // We don't do 12-hour mode
+    if registers[2] & 0x40 != 0 {
+        return None;
+    }
+    let secs = from_bcd(registers[0] & 0x7F)?;
+    let mins = from_bcd(registers[1] & 0x7F)?;
+    let hours = from_bcd(registers[2] & 0x3F)?;
+    let weekday = registers[3] & 0x07;
+    let day = from_bcd(registers[4] & 0x3F)?;
+    let month = from_bcd(registers[5] & 0x1F)?;
+    let year = from_bcd(registers[6])?;
+    if weekday == 0 {
+        return None;
+    }
+    let date = chrono::NaiveDate::from_ymd_opt(2000 + i32::from(year), month.into(), day.into())?;
+    let time = chrono::NaiveTime::from_hms_opt(hours.into(), mins.into(), secs.into())?;
+    Some((date.and_time(time), weekday))
+}
+
+/// Encode a time as the MCP7940N's six alarm registers.
+///
+/// The alarm goes off when the seconds, minutes, hours, weekday, day and
+/// month all match.
+fn encode_alarm(alarm: chrono::NaiveDateTime, weekday: u8) -> [u8; 6] {
+    // ALMxMSK = 0b111, match everything. This also clears the interrupt
+    // flag, and leaves the polarity as active low.
+    const MATCH_ALL: u8 = 0b0111_0000;
+    [
+        to_bcd(alarm.second() as u8),
+        to_bcd(alarm.minute() as u8),
+        to_bcd(alarm.hour() as u8),
+        MATCH_ALL | (weekday & 0x07),
+        to_bcd(alarm.day() as u8),
+        to_bcd(alarm.month() as u8),
+    ]
+}
+
+/// Decode a binary-coded decimal byte
+fn from_bcd(value: u8) -> Option<u8> {
+    let (tens, units) = (value >> 4, value & 0x0F);
+    if tens > 9 || units > 9 {
+        None
+    } else {
+        Some(tens * 10 + units)
+    }
+}
+
+/// Encode a number below 100 as binary-coded decimal
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, mi, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn when() {
+        let now = at(2024, 2, 28, 12, 0);
+        assert_eq!(parse_when("13:30", now), Some(at(2024, 2, 28, 13, 30)));
+        // Already gone today, so tomorrow
+        assert_eq!(parse_when("07:15", now), Some(at(2024, 2, 29, 7, 15)));
+        assert_eq!(parse_when("12:00", now), Some(at(2024, 2, 29, 12, 0)));
+        assert_eq!(parse_when("+90", now), Some(at(2024, 2, 28, 13, 30)));
+        assert_eq!(
+            parse_when("2024-03-01T08:00", now),
+            Some(at(2024, 3, 1, 8, 0))
+        );
+        // In the past
+        assert_eq!(parse_when("2024-01-01T08:00", now), None);
+        assert_eq!(parse_when("25:00", now), None);
+        assert_eq!(parse_when("soon", now), None);
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(from_bcd(0x59), Some(59));
+        assert_eq!(from_bcd(0x5A), None);
+    }
+
+    #[test]
+    fn rtc_time() {
+        // 2024-02-28 23:59:30, weekday 3, oscillator running, battery on
+        let registers = [
+            0x80 | 0x30,
+            0x59,
+            0x23,
+            0x08 | 0x20 | 0x03,
+            0x28,
+            0x02,
+            0x24,
+        ];
+        let (time, weekday) = decode_rtc_time(&registers).unwrap();
+        assert_eq!(
+            time,
+            at(2024, 2, 28, 23, 59) + chrono::TimeDelta::seconds(30)
+        );
+        assert_eq!(weekday, 3);
+        // Oscillator stopped
+        let mut stopped = registers;
+        stopped[0] &= 0x7F;
+        assert_eq!(decode_rtc_time(&stopped), None);
+        // 12-hour mode
+        let mut twelve_hour = registers;
+        twelve_hour[2] |= 0x40;
+        assert_eq!(decode_rtc_time(&twelve_hour), None);
+    }
+
+    #[test]
+    fn alarm() {
+        assert_eq!(
+            encode_alarm(at(2024, 12, 31, 7, 45), 2),
+            [0x00, 0x45, 0x07, 0x72, 0x31, 0x12]
+        );
+    }
+}
+
+// End of file
@@ -0,0 +1,350 @@
+//! # Screensaver
+//!
+//! The effects we can show once the screen blanks (see [`crate::power`]).
+//!
+//! Each effect takes over the screen when it starts, draws a frame every so
+//! often while it runs, and puts the screen back exactly as it was when it
+//! stops. We only get polled from the OS shell, so an effect never starts
+//! while a program has the screen.
+//!
+//! We can't switch the video signal off, so the blank effect makes all the
+//! text colours black instead. The flames effect
+//! draws into the text buffer, so it only works in text modes.
+//!
+//! OS RAM is too small for the flames, so they reserve their buffers off the
+//! top of the TPA when they start, and give them back when they stop. If
+//! there isn't room, we blank the screen instead.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{
+    bios::{
+        self,
+        video::{Attr, TextBackgroundColour, TextForegroundColour},
+    },
+    config::ScreenSaver,
+    program::TransientProgramArea,
+    refcell::CsRefCell,
+    API,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many palette entries the text modes use
+const TEXT_COLOURS: usize = 16;
+
+/// How many frames a second the effects run at
+const FRAMES_PER_SECOND: u64 = 30;
+
+/// Our screensaver state
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    blank: Blank {
+        saved_palette: [bios::video::RGBColour::from_rgb(0, 0, 0); TEXT_COLOURS],
+    },
+    flames: Flames {
+        seed: 123456789,
+        width: 0,
+        height: 0,
+        frame: core::ptr::null_mut(),
+        memory: None,
+    },
+    running: None,
+    last_frame: 0,
+});
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Something we can show on the screen while nobody is using it
+trait Effect {
+    /// Take over the screen.
+    ///
+    /// Returns false if this effect can't run in the current video mode, or
+    /// can't get the memory it needs from the TPA.
+    fn start(&mut self, tpa: &mut TransientProgramArea) -> bool;
+
+    /// Draw the next frame
+    fn step(&mut self);
+
+    /// Put the screen back the way it was before we started, and give back
+    /// any memory we took from the TPA
+    fn stop(&mut self, tpa: &mut TransientProgramArea);
+}
+
+/// Makes all the text colours black
+struct Blank {
+    /// The text colours, from before we started
+    saved_palette: [bios::video::RGBColour; TEXT_COLOURS],
+}
+
+impl Effect for Blank {
+    fn start(&mut self, _tpa: &mut TransientProgramArea) -> bool {
+        let api = API.get();
+        for (idx, entry) in self.saved_palette.iter_mut().enumerate() {
+            if let bios::FfiOption::Some(colour) = (api.video_get_palette)(idx as u8) {
+                *entry = colour;
+            }
+            (api.video_set_palette)(idx as u8, bios::video::RGBColour::from_rgb(0, 0, 0));
+        }
+        true
+    }
+
+    fn step(&mut self) {}
+
+    fn stop(&mut self, _tpa: &mut TransientProgramArea) {
+        let api = API.get();
+        for (idx, entry) in self.saved_palette.iter().enumerate() {
+            (api.video_set_palette)(idx as u8, *entry);
+        }
+    }
+}
+
+/// Flames, rising from the bottom of the screen.
+///
+/// This is the `flames` utility, drawing straight into the text buffer.
+/// Based on <https://gist.github.com/msimpson/1096950>.
+struct Flames {
+    /// For our random number generator
+    seed: u32,
+    /// The width of the screen, in characters
+    width: usize,
+    /// The height of the screen, in characters
+    height: usize,
+    /// The text buffer we are drawing into
+    frame: *mut u8,
+    /// Our memory in the TPA, while we are running. First comes how hot
+    /// each character is (with two more rows below the screen, where the
+    /// fire starts), then the glyphs and attributes that were on the screen
+    /// before we started.
+    memory: Option<&'static mut [u8]>,
+}
+
+impl Flames {
+    /// The glyphs (in Code Page 850) for each heat, from cold to hot
+    const GLYPHS: [u8; 10] = [b' ', b'`', b':', b'^', b'*', b'x', 0xB0, 0xB1, 0xB2, 0xDB];
+
+    /// The colours for each heat, from cold to hot
+    const COLOURS: [TextForegroundColour; 16] = [
+        TextForegroundColour::White,
+        TextForegroundColour::White,
+        TextForegroundColour::White,
+        TextForegroundColour::White,
+        TextForegroundColour::White,
+        TextForegroundColour::Yellow,
+        TextForegroundColour::Yellow,
+        TextForegroundColour::Yellow,
+        TextForegroundColour::Yellow,
+        TextForegroundColour::Yellow,
+        TextForegroundColour::LightRed,
+        TextForegroundColour::LightRed,
+        TextForegroundColour::LightRed,
+        TextForegroundColour::LightRed,
+        TextForegroundColour::LightRed,
+        TextForegroundColour::Magenta,
+    ];
+
+    /// How many characters are on the screen
+    fn num_cells(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// How many heat values we need
+    fn heat_len(&self) -> usize {
+        self.num_cells() + (self.width * 2) + 1
+    }
+
+    /// Generates a number in the range [0, limit)
+    fn random_up_to(&mut self, limit: usize) -> usize {
+        self.seed = self.seed.wrapping_mul(1103515245).wrapping_add(12345);
+        // The top bits are the most random
+        ((self.seed >> 16) as usize) % limit
+    }
+}
+
+impl Effect for Flames {
+    fn start(&mut self, tpa: &mut TransientProgramArea) -> bool {
+        let api = API.get();
+        let mode = (api.video_get_mode)();
+        let (Some(width), Some(height)) = (mode.text_width(), mode.text_height()) else {
+            return false;
+        };
+        let frame = (api.video_get_framebuffer)() as *mut u8;
+        if frame.is_null() {
+            return false;
+        }
+        self.width = usize::from(width);
+        self.height = usize::from(height);
+        self.frame = frame;
+        let heat_len = self.heat_len();
+        let num_bytes = self.num_cells() * 2;
+        let Some(memory) = tpa.reserve("Screensaver", heat_len + num_bytes) else {
+            return false;
+        };
+        let (heat, saved) = memory.split_at_mut(heat_len);
+        heat.fill(0);
+        for (idx, byte) in saved[0..num_bytes].iter_mut().enumerate() {
+            // # Safety
+            //
+            // The BIOS says the text buffer is this big
+            *byte = unsafe { core::ptr::read_volatile(frame.add(idx)) };
+        }
+        self.memory = Some(memory);
+        true
+    }
+
+    fn step(&mut self) {
+        let width = self.width;
+        let num_cells = self.num_cells();
+        let heat_len = self.heat_len();
+        let mut sparks = [0; 5];
+        for spark in sparks.iter_mut() {
+            *spark = num_cells + self.random_up_to(width);
+        }
+        let Some(memory) = self.memory.as_deref_mut() else {
+            return;
+        };
+        let heat_values = &mut memory[0..heat_len];
+        // Seed the fire just off-screen
+        for spark in sparks {
+            heat_values[spark] = 100;
+        }
+        // Cascade the flames
+        for idx in 0..(width * (self.height + 1)) {
+            let sum = u32::from(heat_values[idx])
+                + u32::from(heat_values[idx + 1])
+                + u32::from(heat_values[idx + width])
+                + u32::from(heat_values[idx + width + 1]);
+            let heat = (sum / 4) as u8;
+            heat_values[idx] = heat;
+            // Only draw what is on screen
+            if idx < num_cells {
+                let glyph = Self::GLYPHS
+                    .get(usize::from(heat))
+                    .unwrap_or(&Self::GLYPHS[Self::GLYPHS.len() - 1]);
+                let colour = Self::COLOURS
+                    .get(usize::from(heat))
+                    .unwrap_or(&Self::COLOURS[Self::COLOURS.len() - 1]);
+                let attr = Attr::new(*colour, TextBackgroundColour::Black, false);
+                // # Safety
+                //
+                // We checked the text buffer is this big when we started
+                unsafe {
+                    core::ptr::write_volatile(self.frame.add(idx * 2), *glyph);
+                    core::ptr::write_volatile(self.frame.add(idx * 2 + 1), attr.as_u8());
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self, tpa: &mut TransientProgramArea) {
+        let heat_len = self.heat_len();
+        let num_bytes = self.num_cells() * 2;
+        let Some(memory) = self.memory.take() else {
+            return;
+        };
+        for (idx, byte) in memory[heat_len..heat_len + num_bytes].iter().enumerate() {
+            // # Safety
+            //
+            // We checked the text buffer is this big when we started
+            unsafe { core::ptr::write_volatile(self.frame.add(idx), *byte) };
+        }
+        tpa.release(memory);
+    }
+}
+
+/// What we know about the screensaver
+struct State {
+    /// The blank effect
+    blank: Blank,
+    /// The flames effect
+    flames: Flames,
+    /// Which effect is on the screen, if any
+    running: Option<ScreenSaver>,
+    /// When (in ticks) we last drew a frame
+    last_frame: u64,
+}
+
+impl State {
+    /// Get one of our effects
+    fn effect(&mut self, which: ScreenSaver) -> &mut dyn Effect {
+        match which {
+            ScreenSaver::Blank => &mut self.blank,
+            ScreenSaver::Flames => &mut self.flames,
+        }
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Start an effect, if there isn't one running already.
+///
+/// If the effect can't run in this video mode, or there isn't room for it
+/// in the TPA, we blank the screen instead. Only call this when no program
+/// is running.
+pub fn start(which: ScreenSaver, tpa: &mut TransientProgramArea) {
+    let mut state = STATE.lock();
+    if state.running.is_some() {
+        return;
+    }
+    let which = if state.effect(which).start(tpa) {
+        which
+    } else {
+        state.blank.start(tpa);
+        ScreenSaver::Blank
+    };
+    state.running = Some(which);
+    state.last_frame = now();
+}
+
+/// Stop any running effect, putting the screen back and giving back its
+/// memory. Returns true if there was one.
+pub fn stop(tpa: &mut TransientProgramArea) -> bool {
+    let mut state = STATE.lock();
+    let Some(which) = state.running.take() else {
+        return false;
+    };
+    state.effect(which).stop(tpa);
+    true
+}
+
+/// Is an effect running?
+pub fn is_running() -> bool {
+    STATE.lock().running.is_some()
+}
+
+/// Draw the next frame of the running effect, if it's time.
+pub fn poll() {
+    let mut state = STATE.lock();
+    let Some(which) = state.running else {
+        return;
+    };
+    let frame_ticks = (API.get().time_ticks_per_second)().0 / FRAMES_PER_SECOND;
+    let now = now();
+    if now.saturating_sub(state.last_frame) >= frame_ticks {
+        state.last_frame = now;
+        state.effect(which).step();
+    }
+}
+
+/// Unlock the screensaver, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// The time now, in ticks
+fn now() -> u64 {
+    (API.get().time_ticks_get)().0
+}
+
+// End of file
@@ -0,0 +1,160 @@
+//! # Text Search
+//!
+//! Finds a fixed string in text that arrives a byte at a time, like a file
+//! read from disk in chunks. It doesn't matter where the chunks split the
+//! text - a match can start in one chunk and end in the next.
+//!
+//! This is the Knuth-Morris-Pratt algorithm, so we never have to go back and
+//! look at bytes we've already seen.
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The longest string we can search for
+pub const MAX_PATTERN_LEN: usize = 64;
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Looks for a fixed string in a stream of bytes
+pub struct Matcher {
+    /// What we're looking for (lower-cased, if we're ignoring case)
+    pattern: [u8; MAX_PATTERN_LEN],
+    /// How many bytes of `pattern` are in use
+    len: usize,
+    /// For each `pattern[0..=n]`, how long the longest bit at the start of
+    /// the pattern is that is also at the end of `pattern[0..=n]` (not
+    /// counting the whole thing). When a byte doesn't match, this tells us
+    /// how much of what we matched might still be the start of a match.
+    fallback: [u8; MAX_PATTERN_LEN],
+    /// How many bytes of `pattern` we have matched so far
+    matched: usize,
+    /// Do upper and lower case ASCII letters match each other?
+    ignore_case: bool,
+}
+
+impl Matcher {
+    /// Make a matcher that looks for `pattern`.
+    ///
+    /// Returns `None` if the pattern is empty, or longer than
+    /// [`MAX_PATTERN_LEN`].
+    pub fn new(pattern: &[u8], ignore_case: bool) -> Option<Matcher> {
+        if pattern.is_empty() || pattern.len() > MAX_PATTERN_LEN {
+            return None;
+        }
+        let mut matcher = Matcher {
+            pattern: [0u8; MAX_PATTERN_LEN],
+            len: pattern.len(),
+            fallback: [0u8; MAX_PATTERN_LEN],
+            matched: 0,
+            ignore_case,
+        };
+        for (idx, b) in pattern.iter().enumerate() {
+            matcher.pattern[idx] = matcher.fold(*b);
+        }
+        let mut border = 0;
+        for idx in 1..matcher.len {
+            while border > 0 && matcher.pattern[idx] != matcher.pattern[border] {
+                border = usize::from(matcher.fallback[border - 1]);
+            }
+            if matcher.pattern[idx] == matcher.pattern[border] {
+                border += 1;
+            }
+            // Can't overflow - the pattern is shorter than 256 bytes
+            matcher.fallback[idx] = border as u8;
+        }
+        Some(matcher)
+    }
+
+    /// Forget any partial match, ready to search some new text
+    pub fn reset(&mut self) {
+        self.matched = 0;
+    }
+
+    /// Look at the next byte. Returns true if it is the end of a match.
+    ///
+    /// Matches can overlap, so `aa` matches twice in `aaa`.
+    pub fn push(&mut self, b: u8) -> bool {
+        let b = self.fold(b);
+        while self.matched > 0 && self.pattern[self.matched] != b {
+            self.matched = usize::from(self.fallback[self.matched - 1]);
+        }
+        if self.pattern[self.matched] == b {
+            self.matched += 1;
+        }
+        if self.matched == self.len {
+            self.matched = usize::from(self.fallback[self.len - 1]);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Lower-case a byte, if we are ignoring case
+    fn fold(&self, b: u8) -> u8 {
+        if self.ignore_case {
+            b.to_ascii_lowercase()
+        } else {
+            b
+        }
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where (by the index of their last byte) the matches are
+    fn matches(pattern: &[u8], text: &[u8], ignore_case: bool) -> Vec<usize> {
+        let mut matcher = Matcher::new(pattern, ignore_case).unwrap();
+        text.iter()
+            .enumerate()
+            .filter(|(_, b)| matcher.push(**b))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(matches(b"PRINT", b"10 PRINT X: PRINT Y", false), [7, 16]);
+        assert_eq!(matches(b"print", b"10 PRINT X", false), []);
+        assert_eq!(matches(b"print", b"10 PRINT X", true), [7]);
+    }
+
+    #[test]
+    fn partial_matches() {
+        // After a partial match fails, the start of the pattern might be in
+        // the bytes we already matched
+        assert_eq!(matches(b"abab", b"abaabab", false), [6]);
+        assert_eq!(matches(b"aab", b"aaab", false), [3]);
+        assert_eq!(matches(b"aa", b"aaa", false), [1, 2]);
+    }
+
+    #[test]
+    fn split_text() {
+        // Feeding the text in two goes finds the match across the join
+        let mut matcher = Matcher::new(b"Neotron", false).unwrap();
+        assert!(!b"Hello Neo".iter().any(|b| matcher.push(*b)));
+        assert!(b"tron!".iter().any(|b| matcher.push(*b)));
+        // But not once we reset
+        matcher.reset();
+        assert!(!b"Neo".iter().any(|b| matcher.push(*b)));
+        matcher.reset();
+        assert!(!b"tron".iter().any(|b| matcher.push(*b)));
+    }
+
+    #[test]
+    fn bad_patterns() {
+        assert!(Matcher::new(b"", false).is_none());
+        assert!(Matcher::new(&[b'x'; MAX_PATTERN_LEN], false).is_some());
+        assert!(Matcher::new(&[b'x'; MAX_PATTERN_LEN + 1], false).is_none());
+    }
+}
+
+// End of file
@@ -0,0 +1,350 @@
+//! # Hardware Sensors
+//!
+//! Reads voltage and temperature sensors on the BIOS I²C buses, and warns on
+//! the status line when a reading goes outside the limits in the config.
+//!
+//! The BIOS API can't tell us what sensors there are, so we look for chips
+//! we understand at their usual addresses:
+//!
+//! * LM75 temperature sensors (and the TMP75, DS75 and friends, which work
+//!   the same way) at 0x48 to 0x4F.
+//! * INA219 power monitors at 0x40 to 0x47, which measure the voltage of
+//!   whatever they are wired to, like the battery or the 5V rail.
+//!
+//! Anything else at those addresses will give nonsense readings.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios, config::SensorLimits, osprintln, refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many sensors we keep track of
+pub const MAX_SENSORS: usize = 8;
+
+/// How often we check the sensors, in seconds
+const CHECK_INTERVAL_SECS: u64 = 5;
+
+/// The chips we know, and the addresses they can be at
+const KNOWN_CHIPS: &[(Chip, core::ops::RangeInclusive<u8>)] =
+    &[(Chip::Ina219, 0x40..=0x47), (Chip::Lm75, 0x48..=0x4F)];
+
+/// Our sensor state
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    sensors: heapless::Vec::new(),
+    scanned: false,
+    limits: None,
+    last_check: None,
+    warning: false,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The kinds of sensor chip we understand
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Chip {
+    /// A temperature sensor
+    Lm75,
+    /// A voltage and current monitor
+    Ina219,
+}
+
+impl core::fmt::Display for Chip {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Chip::Lm75 => write!(f, "LM75"),
+            Chip::Ina219 => write!(f, "INA219"),
+        }
+    }
+}
+
+/// A sensor we found
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sensor {
+    /// Which BIOS I²C bus it is on
+    pub bus: u8,
+    /// Its 7-bit I²C address
+    pub address: u8,
+    /// What kind of chip it is
+    pub chip: Chip,
+}
+
+/// Something a sensor measured
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reading {
+    /// A voltage, in millivolts
+    Millivolts(u32),
+    /// A temperature, in thousandths of a degree Celsius
+    MilliCelsius(i32),
+}
+
+impl core::fmt::Display for Reading {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Reading::Millivolts(mv) => write!(f, "{}.{:03} V", mv / 1000, mv % 1000),
+            Reading::MilliCelsius(mc) => {
+                let sign = if mc < 0 { "-" } else { "" };
+                let mc = mc.unsigned_abs();
+                write!(f, "{}{}.{} C", sign, mc / 1000, (mc % 1000) / 100)
+            }
+        }
+    }
+}
+
+/// A reading that is outside the limits
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A voltage is too low, like a flat battery
+    LowVoltage(u32),
+    /// A voltage is too high
+    HighVoltage(u32),
+    /// It's too hot
+    HighTemperature(i32),
+}
+
+impl core::fmt::Display for Warning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Warning::LowVoltage(mv) => write!(f, "LOW {}", Reading::Millivolts(mv)),
+            Warning::HighVoltage(mv) => write!(f, "HIGH {}", Reading::Millivolts(mv)),
+            Warning::HighTemperature(mc) => write!(f, "HOT {}", Reading::MilliCelsius(mc)),
+        }
+    }
+}
+
+impl Warning {
+    /// Is this reading outside these limits?
+    pub fn check(limits: &SensorLimits, reading: Reading) -> Option<Warning> {
+        match reading {
+            Reading::Millivolts(mv) if mv < u32::from(limits.min_mv) => {
+                Some(Warning::LowVoltage(mv))
+            }
+            Reading::Millivolts(mv) if mv > u32::from(limits.max_mv) => {
+                Some(Warning::HighVoltage(mv))
+            }
+            Reading::MilliCelsius(mc) if mc > i32::from(limits.max_temp_c) * 1000 => {
+                Some(Warning::HighTemperature(mc))
+            }
+            _ => None,
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we know about the sensors
+struct State {
+    /// The sensors we found
+    sensors: heapless::Vec<Sensor, MAX_SENSORS>,
+    /// Have we looked for sensors yet?
+    scanned: bool,
+    /// When to warn, if at all
+    limits: Option<SensorLimits>,
+    /// When (in ticks) we last checked the sensors
+    last_check: Option<u64>,
+    /// Are we showing a warning?
+    warning: bool,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Set when we warn about the sensor readings (or `None` to never warn).
+pub fn configure(limits: Option<SensorLimits>) {
+    let mut state = STATE.lock();
+    state.limits = limits;
+    state.last_check = None;
+    if limits.is_none() && state.warning {
+        state.warning = false;
+        crate::statusbar::set_warning(None);
+    }
+}
+
+/// Look on every I²C bus for sensors we understand.
+pub fn scan() -> heapless::Vec<Sensor, MAX_SENSORS> {
+    let api = API.get();
+    let mut sensors = heapless::Vec::new();
+    'buses: for bus in 0..=255u8 {
+        if let bios::FfiOption::None = (api.i2c_bus_get_info)(bus) {
+            break;
+        }
+        for (chip, addresses) in KNOWN_CHIPS {
+            for address in addresses.clone() {
+                let sensor = Sensor {
+                    bus,
+                    address,
+                    chip: *chip,
+                };
+                if read(&sensor).is_ok() && sensors.push(sensor).is_err() {
+                    break 'buses;
+                }
+            }
+        }
+    }
+    let mut state = STATE.lock();
+    state.sensors = sensors.clone();
+    state.scanned = true;
+    sensors
+}
+
+/// Read a sensor.
+pub fn read(sensor: &Sensor) -> Result<Reading, bios::Error> {
+    let register = match sensor.chip {
+        // The temperature register
+        Chip::Lm75 => 0x00,
+        // The bus voltage register
+        Chip::Ina219 => 0x02,
+    };
+    let mut buffer = [0u8; 2];
+    let api = API.get();
+    if let bios::ApiResult::Err(e) = (api.i2c_write_read)(
+        sensor.bus,
+        sensor.address,
+        bios::FfiByteSlice::new(&[register]),
+        bios::FfiByteSlice::empty(),
+        bios::FfiBuffer::new(&mut buffer),
+    ) {
+        return Err(e);
+    }
+    Ok(match sensor.chip {
+        Chip::Lm75 => Reading::MilliCelsius(lm75_millicelsius(buffer)),
+        Chip::Ina219 => Reading::Millivolts(ina219_millivolts(buffer)),
+    })
+}
+
+/// Check the sensors against the limits, if it's been long enough since we
+/// last looked, and warn on the status line if any are out of range.
+///
+/// Call this regularly.
+pub fn poll() {
+    let now = (API.get().time_ticks_get)().0;
+    let interval = (API.get().time_ticks_per_second)().0 * CHECK_INTERVAL_SECS;
+    let (limits, sensors) = {
+        let mut state = STATE.lock();
+        let Some(limits) = state.limits else {
+            return;
+        };
+        if let Some(last_check) = state.last_check {
+            if now.saturating_sub(last_check) < interval {
+                return;
+            }
+        }
+        state.last_check = Some(now);
+        (limits, state.scanned.then(|| state.sensors.clone()))
+    };
+    let sensors = sensors.unwrap_or_else(scan);
+    // Only show the first problem - there isn't room for more
+    let warning = sensors
+        .iter()
+        .filter_map(|sensor| read(sensor).ok())
+        .find_map(|reading| Warning::check(&limits, reading));
+    let was_warning = core::mem::replace(&mut STATE.lock().warning, warning.is_some());
+    match warning {
+        Some(warning) => {
+            if !was_warning {
+                osprintln!(log: Warn, "Sensors: {}", warning);
+            }
+            let mut text: heapless::String<16> = heapless::String::new();
+            let _ = core::fmt::write(&mut text, format_args!("{}", warning));
+            crate::statusbar::set_warning(Some(&text));
+        }
+        None if was_warning => {
+            osprintln!(log: Info, "Sensors: back in range");
+            crate::statusbar::set_warning(None);
+        }
+        None => {}
+    }
+}
+
+/// Unlock the sensor state, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Convert an LM75 temperature register into thousandths of a degree.
+///
+/// The top nine bits are the temperature in half degrees, two's complement.
+fn lm75_millicelsius(register: [u8; 2]) -> i32 {
+    i32::from(i16::from_be_bytes(register) >> 7) * 500
+}
+
+/// Convert an INA219 bus voltage register into millivolts.
+///
+/// The top thirteen bits are the voltage in 4 mV steps.
+fn ina219_millivolts(register: [u8; 2]) -> u32 {
+    u32::from(u16::from_be_bytes(register) >> 3) * 4
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lm75() {
+        assert_eq!(lm75_millicelsius([0x19, 0x00]), 25_000);
+        assert_eq!(lm75_millicelsius([0x19, 0x80]), 25_500);
+        assert_eq!(lm75_millicelsius([0xFF, 0x80]), -500);
+        assert_eq!(lm75_millicelsius([0xE7, 0x00]), -25_000);
+    }
+
+    #[test]
+    fn ina219() {
+        // 5.000 V is 1250 steps of 4 mV
+        assert_eq!(ina219_millivolts((1250u16 << 3).to_be_bytes()), 5000);
+        // The bottom three bits are flags
+        assert_eq!(
+            ina219_millivolts(((1250u16 << 3) | 0x03).to_be_bytes()),
+            5000
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", Reading::Millivolts(4987)), "4.987 V");
+        assert_eq!(format!("{}", Reading::MilliCelsius(25_500)), "25.5 C");
+        assert_eq!(format!("{}", Reading::MilliCelsius(-500)), "-0.5 C");
+        assert_eq!(format!("{}", Warning::LowVoltage(4500)), "LOW 4.500 V");
+    }
+
+    #[test]
+    fn check() {
+        let limits = SensorLimits {
+            min_mv: 4750,
+            max_mv: 5250,
+            max_temp_c: 60,
+        };
+        assert_eq!(Warning::check(&limits, Reading::Millivolts(5000)), None);
+        assert_eq!(
+            Warning::check(&limits, Reading::Millivolts(4700)),
+            Some(Warning::LowVoltage(4700))
+        );
+        assert_eq!(
+            Warning::check(&limits, Reading::Millivolts(5300)),
+            Some(Warning::HighVoltage(5300))
+        );
+        assert_eq!(Warning::check(&limits, Reading::MilliCelsius(60_000)), None);
+        assert_eq!(
+            Warning::check(&limits, Reading::MilliCelsius(60_500)),
+            Some(Warning::HighTemperature(60_500))
+        );
+    }
+}
+
+// End of file
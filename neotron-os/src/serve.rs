@@ -0,0 +1,608 @@
+//! # File Server
+//!
+//! Lets a program on another computer list, read, write and delete the files
+//! on our SD card, over one of the BIOS UARTs, so you don't have to take the
+//! card out. Start it with the `serve` command.
+//!
+//! Everything goes in frames, which look like:
+//!
+//! ```text
+//! SYNC (0x16) | kind (u8) | length (u16 LE) | payload | CRC32 (u32 LE)
+//! ```
+//!
+//! The CRC32 (see [`crate::crc::crc32`]) covers the kind, the length and
+//! the payload. The host sends a request frame and waits for our reply
+//! before sending the next one. Paths use `/` between directories, and
+//! offsets and lengths are little-endian.
+//!
+//! | Request | Payload                               | Reply payload            |
+//! |---------|---------------------------------------|--------------------------|
+//! | `H`     | nothing                               | version text             |
+//! | `L`     | directory path                        | listing text             |
+//! | `S`     | file path                             | one line of listing text |
+//! | `R`     | offset (u32), length (u16), file path | file data                |
+//! | `W`     | offset (u32), path length (u8), file path, file data | nothing   |
+//! | `X`     | file path                             | nothing                  |
+//! | `Q`     | nothing                               | nothing                  |
+//!
+//! We reply with an `O` frame if the request worked, or an `E` frame holding
+//! an error message if it didn't. A listing too big for one frame is sent as
+//! `D` frames, then an `O` frame with the rest. If a request arrives damaged,
+//! we reply with an empty `N` frame, and the host should send it again.
+//!
+//! Each line of listing text is `NAME.EXT size attributes
+//! YYYY-MM-DDTHH:MM:SS`, where the attributes are letters like `attrib`
+//! prints (with `D` for a directory).
+//!
+//! A read past the end of a file gives back less data (or none). A write
+//! with an offset of zero replaces the file. Any other offset must be the
+//! current length of the file, so files are written in order.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::fmt::Write as _;
+
+use crate::{
+    bios,
+    crc::{crc32, crc32_update},
+    API, FILESYSTEM,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The most payload a frame can carry
+pub const MAX_PAYLOAD: usize = 512;
+
+/// The version of the protocol, given in the reply to `H`
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Marks the start of every frame
+const SYNC: u8 = 0x16;
+
+/// How many bytes come between the [`SYNC`] and the payload
+const HEADER_LEN: usize = 3;
+
+/// How many bytes come after the payload
+const CRC_LEN: usize = 4;
+
+/// How long we wait for the rest of a frame before giving up on it
+const FRAME_TIMEOUT_MS: u128 = 1000;
+
+/// How many bytes come before the path in a read request
+const READ_HEADER_LEN: usize = 6;
+
+/// How many bytes come before the path in a write request
+const WRITE_HEADER_LEN: usize = 5;
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways serving can fail
+#[derive(Debug)]
+pub enum Error {
+    /// The BIOS wouldn't set the UART up
+    BadPort(bios::Error),
+    /// Reading from or writing to the UART failed
+    Serial(bios::Error),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::BadPort(e) => write!(f, "Failed to set up the UART: {:?}", e),
+            Error::Serial(e) => write!(f, "UART failed: {:?}", e),
+        }
+    }
+}
+
+/// What was done while serving
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// How many requests we answered
+    pub requests: u32,
+    /// How many requests arrived damaged
+    pub bad_frames: u32,
+    /// How many bytes of file we sent
+    pub bytes_read: u64,
+    /// How many bytes of file we received
+    pub bytes_written: u64,
+}
+
+/// Why a frame was thrown away
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The length was more than [`MAX_PAYLOAD`]
+    TooLong,
+    /// The CRC didn't match
+    BadCrc,
+}
+
+/// Picks frames out of a stream of bytes
+pub struct FrameDecoder {
+    /// The frame so far, after the [`SYNC`]
+    buffer: [u8; HEADER_LEN + MAX_PAYLOAD + CRC_LEN],
+    /// How much of `buffer` is used
+    len: usize,
+    /// Have we seen a [`SYNC`]?
+    synced: bool,
+}
+
+impl FrameDecoder {
+    /// Make a decoder, waiting for the start of a frame
+    pub const fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: [0; HEADER_LEN + MAX_PAYLOAD + CRC_LEN],
+            len: 0,
+            synced: false,
+        }
+    }
+
+    /// Give the decoder a byte. When a whole frame has arrived, you get its
+    /// kind back, and the payload is in [`FrameDecoder::payload`] until the
+    /// next byte goes in.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<u8, FrameError>> {
+        if !self.synced {
+            self.synced = byte == SYNC;
+            self.len = 0;
+            return None;
+        }
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        if self.len < HEADER_LEN {
+            return None;
+        }
+        let payload_len = self.payload_len();
+        if payload_len > MAX_PAYLOAD {
+            self.synced = false;
+            return Some(Err(FrameError::TooLong));
+        }
+        if self.len < HEADER_LEN + payload_len + CRC_LEN {
+            return None;
+        }
+        self.synced = false;
+        let (body, crc) = self.buffer[0..self.len].split_at(HEADER_LEN + payload_len);
+        if crc32(body).to_le_bytes() != crc {
+            return Some(Err(FrameError::BadCrc));
+        }
+        Some(Ok(self.buffer[0]))
+    }
+
+    /// The payload of the frame that just arrived
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[HEADER_LEN..HEADER_LEN + self.payload_len()]
+    }
+
+    /// Are we part way through a frame?
+    pub fn is_busy(&self) -> bool {
+        self.synced
+    }
+
+    /// Throw away any partial frame
+    pub fn reset(&mut self) {
+        self.synced = false;
+    }
+
+    /// How long the payload of the current frame is
+    fn payload_len(&self) -> usize {
+        usize::from(u16::from_le_bytes([self.buffer[1], self.buffer[2]]))
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Collects a reply payload, sending `D` frames when it gets full
+struct ReplyWriter {
+    /// Which BIOS serial device we reply on
+    port: u8,
+    /// The payload so far
+    buffer: heapless::Vec<u8, MAX_PAYLOAD>,
+    /// Did sending a frame fail?
+    error: Option<bios::Error>,
+}
+
+impl core::fmt::Write for ReplyWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            if self.buffer.is_full() {
+                if let Err(e) = send_frame(self.port, b'D', &self.buffer) {
+                    self.error = Some(e);
+                }
+                self.buffer.clear();
+            }
+            // There's always room, as we've just made some
+            let _ = self.buffer.push(b);
+        }
+        Ok(())
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Serve files on a UART until the host sends `Q`, or `should_stop` says
+/// to stop.
+///
+/// If `baud` is given, the UART is set up at that speed (8N1, with no
+/// handshaking), otherwise it's used as it is. The serial console is turned
+/// off while we serve, if it's on the same UART.
+pub fn run<F>(port: u8, baud: Option<u32>, mut should_stop: F) -> Result<Stats, Error>
+where
+    F: FnMut() -> bool,
+{
+    let api = API.get();
+    if let Some(baud) = baud {
+        let serial_config = bios::serial::Config {
+            data_rate_bps: baud,
+            data_bits: bios::serial::DataBits::Eight.make_ffi_safe(),
+            stop_bits: bios::serial::StopBits::One.make_ffi_safe(),
+            parity: bios::serial::Parity::None.make_ffi_safe(),
+            handshaking: bios::serial::Handshaking::None.make_ffi_safe(),
+        };
+        if let bios::ApiResult::Err(e) = (api.serial_configure)(port, serial_config) {
+            return Err(Error::BadPort(e));
+        }
+    }
+
+    let console = {
+        let mut guard = crate::SERIAL_CONSOLE.lock();
+        if guard.as_ref().is_some_and(|console| console.0 == port) {
+            guard.take()
+        } else {
+            None
+        }
+    };
+
+    let result = serve(port, &mut should_stop);
+
+    if let Some(console) = console {
+        *crate::SERIAL_CONSOLE.lock() = Some(console);
+    }
+    result
+}
+
+/// Send a frame on a UART
+pub fn send_frame(port: u8, kind: u8, payload: &[u8]) -> Result<(), bios::Error> {
+    encode_frame(kind, payload, |chunk| write_all(port, chunk))
+}
+
+/// Turn a payload into a frame, giving the bytes to `out` a piece at a time
+///
+/// The payload is cut short at [`MAX_PAYLOAD`] bytes.
+pub fn encode_frame<F, E>(kind: u8, payload: &[u8], mut out: F) -> Result<(), E>
+where
+    F: FnMut(&[u8]) -> Result<(), E>,
+{
+    let payload = &payload[0..payload.len().min(MAX_PAYLOAD)];
+    let [len_lo, len_hi] = (payload.len() as u16).to_le_bytes();
+    let header = [kind, len_lo, len_hi];
+    let crc = crc32_update(crc32(&header), payload);
+    out(&[SYNC])?;
+    out(&header)?;
+    out(payload)?;
+    out(&crc.to_le_bytes())
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Answer requests until we're told to stop
+fn serve<F>(port: u8, should_stop: &mut F) -> Result<Stats, Error>
+where
+    F: FnMut() -> bool,
+{
+    let api = API.get();
+    let mut stats = Stats::default();
+    let mut decoder = FrameDecoder::new();
+    let mut last_byte = API.get_uptime();
+    loop {
+        if should_stop() {
+            return Ok(stats);
+        }
+        let mut buffer = [0u8; 64];
+        let result: Result<usize, bios::Error> = (api.serial_read)(
+            port,
+            bios::FfiBuffer::new(&mut buffer),
+            bios::FfiOption::Some(bios::Timeout::new_ms(10)),
+        )
+        .into();
+        let count = result.map_err(Error::Serial)?;
+        let now = API.get_uptime();
+        if count == 0 {
+            if decoder.is_busy() && (now - last_byte).as_millis() > FRAME_TIMEOUT_MS {
+                // The rest of the frame isn't coming
+                decoder.reset();
+            }
+            continue;
+        }
+        last_byte = now;
+        for byte in &buffer[0..count] {
+            match decoder.feed(*byte) {
+                None => {}
+                Some(Err(_e)) => {
+                    stats.bad_frames += 1;
+                    send_frame(port, b'N', &[]).map_err(Error::Serial)?;
+                }
+                Some(Ok(b'Q')) => {
+                    stats.requests += 1;
+                    send_frame(port, b'O', &[]).map_err(Error::Serial)?;
+                    return Ok(stats);
+                }
+                Some(Ok(kind)) => {
+                    stats.requests += 1;
+                    handle(port, kind, decoder.payload(), &mut stats).map_err(Error::Serial)?;
+                }
+            }
+        }
+    }
+}
+
+/// Answer a request
+fn handle(port: u8, kind: u8, payload: &[u8], stats: &mut Stats) -> Result<(), bios::Error> {
+    let mut reply = ReplyWriter {
+        port,
+        buffer: heapless::Vec::new(),
+        error: None,
+    };
+    let result = match kind {
+        b'H' => {
+            let _ = write!(
+                reply,
+                "{} serve {} {}",
+                crate::OS_VERSION,
+                PROTOCOL_VERSION,
+                MAX_PAYLOAD
+            );
+            Ok(())
+        }
+        b'L' => path_arg(payload).and_then(|path| list(path, &mut reply)),
+        b'S' => path_arg(payload).and_then(|path| stat(path, &mut reply)),
+        b'R' => read(payload, &mut reply.buffer).map(|n| stats.bytes_read += n as u64),
+        b'W' => write(payload).map(|n| stats.bytes_written += n as u64),
+        b'X' => path_arg(payload).and_then(delete),
+        _ => Err("Unknown request"),
+    };
+    if let Some(e) = reply.error {
+        return Err(e);
+    }
+    match result {
+        Ok(()) => send_frame(port, b'O', &reply.buffer),
+        Err(message) => send_frame(port, b'E', message.as_bytes()),
+    }
+}
+
+/// Get a path from a payload
+fn path_arg(payload: &[u8]) -> Result<&str, &'static str> {
+    core::str::from_utf8(payload).map_err(|_e| "Bad path")
+}
+
+/// Split a path into its directory and its file name
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once(['/', '\\']).unwrap_or(("", path))
+}
+
+/// Write a line of listing text about a directory entry
+fn write_entry<W>(out: &mut W, entry: &embedded_sdmmc::DirEntry) -> core::fmt::Result
+where
+    W: core::fmt::Write,
+{
+    let mut attrs: heapless::String<6> = heapless::String::new();
+    for (set, letter) in [
+        (entry.attributes.is_directory(), 'D'),
+        (entry.attributes.is_read_only(), 'R'),
+        (entry.attributes.is_hidden(), 'H'),
+        (entry.attributes.is_system(), 'S'),
+        (entry.attributes.is_archive(), 'A'),
+    ] {
+        if set {
+            let _ = attrs.push(letter);
+        }
+    }
+    if attrs.is_empty() {
+        let _ = attrs.push('-');
+    }
+    writeln!(
+        out,
+        "{} {} {} {:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        entry.name,
+        entry.size,
+        attrs,
+        u32::from(entry.mtime.year_since_1970) + 1970,
+        entry.mtime.zero_indexed_month + 1,
+        entry.mtime.zero_indexed_day + 1,
+        entry.mtime.hours,
+        entry.mtime.minutes,
+        entry.mtime.seconds
+    )
+}
+
+/// List a directory
+fn list<W>(path: &str, out: &mut W) -> Result<(), &'static str>
+where
+    W: core::fmt::Write,
+{
+    FILESYSTEM
+        .iterate_dir(path, |entry| {
+            if !entry.attributes.is_volume() {
+                let _ = write_entry(out, entry);
+            }
+        })
+        .map_err(|_e| "Can't list that directory")
+}
+
+/// Describe one file
+fn stat<W>(path: &str, out: &mut W) -> Result<(), &'static str>
+where
+    W: core::fmt::Write,
+{
+    let (dir, name) = split_path(path);
+    let mut found = false;
+    FILESYSTEM
+        .iterate_dir(dir, |entry| {
+            let mut entry_name: heapless::String<12> = heapless::String::new();
+            let _ = write!(entry_name, "{}", entry.name);
+            if !found && entry_name.eq_ignore_ascii_case(name) {
+                let _ = write_entry(out, entry);
+                found = true;
+            }
+        })
+        .map_err(|_e| "Can't list that directory")?;
+    if found {
+        Ok(())
+    } else {
+        Err("File not found")
+    }
+}
+
+/// Read part of a file into `out`, giving how much we read
+fn read(payload: &[u8], out: &mut heapless::Vec<u8, MAX_PAYLOAD>) -> Result<usize, &'static str> {
+    if payload.len() < READ_HEADER_LEN {
+        return Err("Bad read request");
+    }
+    let (header, path) = payload.split_at(READ_HEADER_LEN);
+    let offset = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = usize::from(u16::from_le_bytes([header[4], header[5]])).min(MAX_PAYLOAD);
+    let (dir, name) = split_path(path_arg(path)?);
+    let file = FILESYSTEM
+        .open_file_in_dir(dir, name, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(|_e| "Can't open that file")?;
+    if offset > file.length() {
+        return Ok(0);
+    }
+    file.seek_from_start(offset)
+        .map_err(|_e| "Can't seek in that file")?;
+    // The buffer is empty and `len` fits in it
+    let _ = out.resize_default(len);
+    let mut total = 0;
+    while total < len {
+        match file.read(&mut out[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(_e) => return Err("Can't read that file"),
+        }
+    }
+    out.truncate(total);
+    Ok(total)
+}
+
+/// Write part of a file, giving how much we wrote
+fn write(payload: &[u8]) -> Result<usize, &'static str> {
+    if payload.len() < WRITE_HEADER_LEN {
+        return Err("Bad write request");
+    }
+    let (header, rest) = payload.split_at(WRITE_HEADER_LEN);
+    let offset = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let path_len = usize::from(header[4]);
+    if rest.len() < path_len {
+        return Err("Bad write request");
+    }
+    let (path, data) = rest.split_at(path_len);
+    let (dir, name) = split_path(path_arg(path)?);
+    let mode = if offset == 0 {
+        embedded_sdmmc::Mode::ReadWriteCreateOrTruncate
+    } else {
+        embedded_sdmmc::Mode::ReadWriteAppend
+    };
+    let file = FILESYSTEM
+        .open_file_in_dir(dir, name, mode)
+        .map_err(|e| match e {
+            crate::fs::Error::ReadOnly => "The disk is read-only",
+            _ => "Can't open that file",
+        })?;
+    if file.length() != offset {
+        return Err("Files must be written in order");
+    }
+    file.write(data).map_err(|_e| "Can't write that file")?;
+    Ok(data.len())
+}
+
+/// Delete a file
+fn delete(path: &str) -> Result<(), &'static str> {
+    match split_path(path) {
+        ("", name) => FILESYSTEM.delete_file(name).map_err(|e| match e {
+            crate::fs::Error::ReadOnly => "The disk is read-only",
+            _ => "Can't delete that file",
+        }),
+        _ => Err("Can only delete files in the root directory"),
+    }
+}
+
+/// Send all of some bytes on a UART
+fn write_all(port: u8, mut data: &[u8]) -> Result<(), bios::Error> {
+    let api = API.get();
+    while !data.is_empty() {
+        let result: Result<usize, bios::Error> =
+            (api.serial_write)(port, bios::FfiByteSlice::new(data), bios::FfiOption::None).into();
+        let count = result?;
+        data = &data[count..];
+    }
+    Ok(())
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_frame::<_, ()>(kind, payload, |chunk| {
+            out.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut decoder = FrameDecoder::new();
+        let mut stream = vec![0x00, 0xFF];
+        stream.extend(encode(b'L', b"GAMES"));
+        stream.extend(encode(b'Q', b""));
+        let mut frames = Vec::new();
+        for b in stream {
+            if let Some(result) = decoder.feed(b) {
+                frames.push((result, decoder.payload().to_vec()));
+            }
+        }
+        assert_eq!(
+            frames,
+            [(Ok(b'L'), b"GAMES".to_vec()), (Ok(b'Q'), Vec::new())]
+        );
+    }
+
+    #[test]
+    fn damaged() {
+        let mut decoder = FrameDecoder::new();
+        let mut frame = encode(b'X', b"A.TXT");
+        frame[5] ^= 0x20;
+        let results: Vec<_> = frame.into_iter().filter_map(|b| decoder.feed(b)).collect();
+        assert_eq!(results, [Err(FrameError::BadCrc)]);
+
+        let results: Vec<_> = [SYNC, b'W', 0xFF, 0xFF]
+            .iter()
+            .filter_map(|b| decoder.feed(*b))
+            .collect();
+        assert_eq!(results, [Err(FrameError::TooLong)]);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(split_path("GAMES/SAVES/A.SAV"), ("GAMES/SAVES", "A.SAV"));
+        assert_eq!(split_path("README.TXT"), ("", "README.TXT"));
+    }
+}
+
+// End of file
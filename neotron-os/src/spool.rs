@@ -0,0 +1,496 @@
+//! # Print Spooler
+//!
+//! Sends text files to a printer on one of the BIOS UARTs, in the background,
+//! while you carry on using the shell. Queue files with the `print` command.
+//! Applications can also write to the printer directly, through the `PRN:`
+//! device.
+//!
+//! The printer tells us to pause with XOFF (when its buffer fills up), and to
+//! carry on with XON. We send text as Code Page 850, like the console uses,
+//! translated into whatever the printer understands, with each bare line feed
+//! turned into a carriage return and a line feed.
+//!
+//! Only serial printers work. The BIOS doesn't know about parallel ports.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{
+    bios,
+    config::{Printer, PrinterCharset},
+    osprintln,
+    refcell::CsRefCell,
+    API, FILESYSTEM,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many files can be waiting to print
+pub const MAX_JOBS: usize = 4;
+
+/// The longest path we can queue
+pub const MAX_PATH_LEN: usize = 64;
+
+/// How much of a file we send each time we're polled
+const CHUNK_LEN: usize = 32;
+
+/// The printer sends this when it's ready for more
+const XON: u8 = 0x11;
+
+/// The printer sends this when it needs us to wait
+const XOFF: u8 = 0x13;
+
+/// How long an application's write waits for the printer to send XON
+/// before giving up
+const PAUSE_TIMEOUT_SECS: u64 = 30;
+
+/// What the printer gets for each Code Page 850 character from 0x80 up, when
+/// it only understands ASCII
+const CP850_TO_ASCII: [u8; 128] = *b"CueaaaaceeeiiiAA\
+EaAooouuyOUoLOxf\
+aiounNao?R-??!<>\
+###|+AAAC+|++cY+\
+++++-+aA+++++=+*\
+dDEEEiIII++##|I#\
+OsOOoOupPUUUyY-'\
+-+_???/,o\".132# ";
+
+/// The state of the spooler
+static SPOOLER: CsRefCell<Spooler> = CsRefCell::new(Spooler::new());
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways printing can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There's no printer set up (see `config printer`)
+    NoPrinter,
+    /// There are already [`MAX_JOBS`] files waiting to print
+    QueueFull,
+    /// The path is longer than [`MAX_PATH_LEN`]
+    PathTooLong,
+    /// The spooler is part way through printing some files
+    Busy,
+    /// The UART wouldn't take our data
+    Serial,
+    /// The printer asked us to wait, and never said to carry on
+    TimedOut,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::NoPrinter => write!(f, "No printer set up (try `config printer`)"),
+            Error::QueueFull => write!(f, "Too many files waiting to print"),
+            Error::PathTooLong => write!(f, "Path too long"),
+            Error::Busy => write!(f, "The printer is busy"),
+            Error::Serial => write!(f, "Failed to send to the printer"),
+            Error::TimedOut => write!(f, "The printer stopped responding"),
+        }
+    }
+}
+
+/// An application's hold on the printer, from [`claim`].
+///
+/// Nobody else can claim the printer, and queued files don't print, until
+/// this is dropped.
+pub struct Claim(());
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        SPOOLER.lock().claimed = false;
+    }
+}
+
+/// Turns Code Page 850 text into something the printer understands
+#[derive(Debug, Clone)]
+pub struct Translator {
+    /// What the printer understands
+    charset: PrinterCharset,
+    /// Was the last byte a carriage return?
+    last_cr: bool,
+}
+
+impl Translator {
+    /// Make a translator for the given kind of printer
+    pub const fn new(charset: PrinterCharset) -> Translator {
+        Translator {
+            charset,
+            last_cr: false,
+        }
+    }
+
+    /// Translate one byte, giving each byte for the printer to `out`
+    pub fn translate<F>(&mut self, byte: u8, mut out: F)
+    where
+        F: FnMut(u8),
+    {
+        if byte == b'\n' && !self.last_cr {
+            out(b'\r');
+        }
+        self.last_cr = byte == b'\r';
+        out(match self.charset {
+            PrinterCharset::Cp850 => byte,
+            PrinterCharset::Cp437 => cp850_to_cp437(byte),
+            PrinterCharset::Ascii => cp850_to_ascii(byte),
+        });
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// A file waiting to print
+struct Job {
+    /// Where the file is
+    path: heapless::String<MAX_PATH_LEN>,
+    /// How much of it we've sent
+    offset: u32,
+}
+
+/// The state of the spooler
+struct Spooler {
+    /// The printer, if there is one
+    printer: Option<Printer>,
+    /// The files waiting to print (the first one is printing)
+    jobs: heapless::Deque<Job, MAX_JOBS>,
+    /// Has the printer asked us to wait?
+    paused: bool,
+    /// Does an application have the printer to itself?
+    claimed: bool,
+    /// Turns our text into the printer's
+    translator: Translator,
+}
+
+impl Spooler {
+    /// Make a spooler with no printer
+    const fn new() -> Spooler {
+        Spooler {
+            printer: None,
+            jobs: heapless::Deque::new(),
+            paused: false,
+            claimed: false,
+            translator: Translator::new(PrinterCharset::Cp850),
+        }
+    }
+
+    /// See if the printer has sent XON or XOFF
+    fn check_flow(&mut self, port: u8) {
+        let api = API.get();
+        let mut buffer = [0u8; 8];
+        loop {
+            let result: Result<usize, bios::Error> = (api.serial_read)(
+                port,
+                bios::FfiBuffer::new(&mut buffer),
+                bios::FfiOption::Some(bios::Timeout::new_ms(0)),
+            )
+            .into();
+            let count = match result {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            for b in &buffer[0..count] {
+                match *b {
+                    XON => self.paused = false,
+                    XOFF => self.paused = true,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Translate some text and send it to the printer
+    fn send(&mut self, port: u8, data: &[u8]) -> Result<(), Error> {
+        let mut out: heapless::Vec<u8, { CHUNK_LEN * 2 }> = heapless::Vec::new();
+        for chunk in data.chunks(CHUNK_LEN) {
+            out.clear();
+            for b in chunk {
+                // Each byte makes two at most, so this always fits
+                self.translator.translate(*b, |t| {
+                    let _ = out.push(t);
+                });
+            }
+            write_all(port, &out)?;
+        }
+        Ok(())
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Set up the printer (or forget it, with `None`)
+///
+/// Anything waiting to print is thrown away.
+pub fn configure(printer: Option<Printer>) {
+    if let Some(printer) = printer {
+        let api = API.get();
+        if let bios::ApiResult::Err(e) =
+            (api.serial_configure)(printer.port, printer.serial_config())
+        {
+            osprintln!(log: Warn, "Failed to set up printer on UART {}: {:?}", printer.port, e);
+        }
+    }
+    let mut spooler = SPOOLER.lock();
+    spooler.jobs.clear();
+    spooler.paused = false;
+    spooler.translator = Translator::new(printer.map_or(PrinterCharset::Cp850, |p| p.charset));
+    spooler.printer = printer;
+}
+
+/// Queue a file to print, returning how many files are ahead of it
+pub fn submit(path: &str) -> Result<usize, Error> {
+    let mut spooler = SPOOLER.lock();
+    if spooler.printer.is_none() {
+        return Err(Error::NoPrinter);
+    }
+    let mut job = Job {
+        path: heapless::String::new(),
+        offset: 0,
+    };
+    job.path.push_str(path).map_err(|_| Error::PathTooLong)?;
+    let ahead = spooler.jobs.len();
+    spooler.jobs.push_back(job).map_err(|_| Error::QueueFull)?;
+    Ok(ahead)
+}
+
+/// Throw away everything waiting to print, returning how many files that
+/// was
+pub fn cancel() -> usize {
+    let mut spooler = SPOOLER.lock();
+    let count = spooler.jobs.len();
+    spooler.jobs.clear();
+    count
+}
+
+/// Call `f` with the path of each file waiting to print, and how many bytes
+/// of it we've sent
+pub fn for_each_job<F>(mut f: F)
+where
+    F: FnMut(&str, u32),
+{
+    let spooler = SPOOLER.lock();
+    for job in spooler.jobs.iter() {
+        f(job.path.as_str(), job.offset);
+    }
+}
+
+/// Has the printer asked us to wait?
+pub fn is_paused() -> bool {
+    SPOOLER.lock().paused
+}
+
+/// Give an application the printer to itself, until it drops the [`Claim`]
+pub fn claim() -> Result<Claim, Error> {
+    let mut spooler = SPOOLER.lock();
+    if spooler.printer.is_none() {
+        Err(Error::NoPrinter)
+    } else if spooler.claimed || !spooler.jobs.is_empty() {
+        Err(Error::Busy)
+    } else {
+        spooler.claimed = true;
+        Ok(Claim(()))
+    }
+}
+
+/// Send some text straight to the printer, for the `PRN:` device
+///
+/// Waits while the printer asks us to, but gives up with
+/// [`Error::TimedOut`] if it doesn't say to carry on within
+/// `PAUSE_TIMEOUT_SECS`.
+pub fn write(_claim: &Claim, data: &[u8]) -> Result<(), Error> {
+    let api = API.get();
+    let mut spooler = SPOOLER.lock();
+    let port = spooler.printer.ok_or(Error::NoPrinter)?.port;
+    for chunk in data.chunks(CHUNK_LEN) {
+        spooler.check_flow(port);
+        let deadline =
+            (api.time_ticks_get)().0 + PAUSE_TIMEOUT_SECS * (api.time_ticks_per_second)().0;
+        while spooler.paused {
+            if (api.time_ticks_get)().0 >= deadline {
+                return Err(Error::TimedOut);
+            }
+            // The program isn't hung, just waiting for the printer
+            crate::watchdog::kick();
+            spooler.check_flow(port);
+        }
+        spooler.send(port, chunk)?;
+    }
+    Ok(())
+}
+
+/// Send the next bit of whatever's printing
+///
+/// Call this when the shell is idle.
+pub fn poll() {
+    let mut spooler = SPOOLER.lock();
+    let Some(printer) = spooler.printer else {
+        return;
+    };
+    if spooler.jobs.is_empty() || spooler.claimed {
+        return;
+    }
+    spooler.check_flow(printer.port);
+    if spooler.paused || FILESYSTEM.is_busy() {
+        return;
+    }
+    let Some(job) = spooler.jobs.front() else {
+        return;
+    };
+    let offset = job.offset;
+    let mut buffer = [0u8; CHUNK_LEN];
+    let result = FILESYSTEM
+        .open_file(job.path.as_str(), embedded_sdmmc::Mode::ReadOnly)
+        .and_then(|file| {
+            file.seek_from_start(offset)?;
+            file.read(&mut buffer)
+        });
+    match result {
+        Ok(0) => {
+            if let Some(job) = spooler.jobs.pop_front() {
+                osprintln!(log: Debug, "Finished printing {}", job.path);
+            }
+        }
+        Ok(n) => {
+            if let Err(e) = spooler.send(printer.port, &buffer[0..n]) {
+                osprintln!(log: Warn, "Printing stopped: {}", e);
+                spooler.jobs.clear();
+            } else if let Some(job) = spooler.jobs.front_mut() {
+                job.offset += n as u32;
+            }
+        }
+        Err(e) => {
+            if let Some(job) = spooler.jobs.pop_front() {
+                osprintln!(log: Warn, "Failed to print {}: {:?}", job.path, e);
+            }
+        }
+    }
+}
+
+/// Unlock the spooler, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    SPOOLER.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Send all of some bytes on a UART
+fn write_all(port: u8, mut data: &[u8]) -> Result<(), Error> {
+    let api = API.get();
+    while !data.is_empty() {
+        let result: Result<usize, bios::Error> =
+            (api.serial_write)(port, bios::FfiByteSlice::new(data), bios::FfiOption::None).into();
+        let count = result.map_err(|_e| Error::Serial)?;
+        data = &data[count..];
+    }
+    Ok(())
+}
+
+/// Translate a Code Page 850 character into Code Page 437
+///
+/// The two share most of their characters (sometimes in different places).
+/// Anything Code Page 437 doesn't have loses its accent instead.
+fn cp850_to_cp437(byte: u8) -> u8 {
+    match byte {
+        0x00..=0x9A
+        | 0x9C
+        | 0x9F..=0xA8
+        | 0xAA..=0xB4
+        | 0xB9..=0xBC
+        | 0xBF..=0xC5
+        | 0xC8..=0xCE
+        | 0xD9..=0xDC
+        | 0xDF
+        | 0xE1
+        | 0xE6
+        | 0xF1
+        | 0xF6
+        | 0xF8
+        | 0xFA
+        | 0xFD..=0xFF => byte,
+        // Cent sign
+        0xBD => 0x9B,
+        // Yen sign
+        0xBE => 0x9D,
+        _ => cp850_to_ascii(byte),
+    }
+}
+
+/// Translate a Code Page 850 character into ASCII
+fn cp850_to_ascii(byte: u8) -> u8 {
+    if byte < 0x80 {
+        byte
+    } else {
+        CP850_TO_ASCII[usize::from(byte - 0x80)]
+    }
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(charset: PrinterCharset, input: &[u8]) -> Vec<u8> {
+        let mut translator = Translator::new(charset);
+        let mut out = Vec::new();
+        for b in input {
+            translator.translate(*b, |t| out.push(t));
+        }
+        out
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(
+            translate(PrinterCharset::Cp850, b"a\nb\r\nc"),
+            b"a\r\nb\r\nc"
+        );
+    }
+
+    #[test]
+    fn charsets() {
+        // "Ça coûte 5¢ ø" in Code Page 850
+        let input = b"\x80a co\x96te 5\xbd \x9b";
+        assert_eq!(translate(PrinterCharset::Cp850, input), input);
+        assert_eq!(
+            translate(PrinterCharset::Cp437, input),
+            b"\x80a co\x96te 5\x9b o"
+        );
+        assert_eq!(translate(PrinterCharset::Ascii, input), b"Ca coute 5c o");
+    }
+
+    #[test]
+    fn one_claim_at_a_time() {
+        SPOOLER.lock().printer = Some(Printer {
+            port: 0,
+            baud: 9600,
+            charset: PrinterCharset::Ascii,
+        });
+        let first = claim().unwrap();
+        assert!(matches!(claim(), Err(Error::Busy)));
+        // Letting go of it lets the next one in
+        drop(first);
+        assert!(claim().is_ok());
+        SPOOLER.lock().printer = None;
+        assert!(matches!(claim(), Err(Error::NoPrinter)));
+    }
+
+    #[test]
+    fn ascii_table_is_ascii() {
+        assert!(CP850_TO_ASCII.iter().all(|b| (0x20..0x7F).contains(b)));
+    }
+}
+
+// End of file
@@ -0,0 +1,188 @@
+//! # Console Staging
+//!
+//! Somewhere to keep console output that couldn't be printed straight away.
+//!
+//! If something prints while a console is locked - like an interrupt that
+//! logs a message while the main loop is drawing on the screen - we can't
+//! wait for the lock, as it would never be let go. Instead the text goes
+//! into a [`Ring`], and whoever next gets the console lock (or the main loop,
+//! if nobody does) prints it.
+//!
+//! Adding to a ring never waits. A writer claims the ring with the BIOS
+//! compare-and-swap, copies its text in, and moves the head along. The only
+//! reader is whoever holds the console lock, and it only moves the tail. If a
+//! writer finds the ring full, or interrupted another writer part way
+//! through, its text is dropped, and the reader says that some was lost.
+//!
+//! OS RAM is too small for rings big enough to be useful, so their memory is
+//! reserved off the top of the TPA when the OS starts (see [`Ring::attach`]).
+//! Until then, any text for a ring is lost.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many bytes each ring holds.
+///
+/// This must be a power of two, so the head and tail can wrap. It has to
+/// cover what gets printed between two trips round the main loop, which can
+/// be a few lines of log messages.
+pub const RING_LEN: usize = 256;
+
+/// How many bytes we take out of a ring at a time
+const CHUNK_LEN: usize = 16;
+
+/// What we print when some text didn't fit in the ring
+const LOST_MESSAGE: &str = "\n[some output was lost]\n";
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// A ring of text waiting for a console
+pub struct Ring {
+    /// The text, in [`RING_LEN`] bytes of TPA (or null if we don't have
+    /// any yet)
+    data: AtomicPtr<u8>,
+    /// How many bytes have ever been added (wrapping)
+    head: AtomicUsize,
+    /// How many bytes have ever been taken out (wrapping)
+    tail: AtomicUsize,
+    /// Is someone adding to the ring?
+    writing: AtomicBool,
+    /// Did we have to drop some text?
+    lost: AtomicBool,
+}
+
+impl Ring {
+    /// Make an empty ring
+    pub const fn new() -> Ring {
+        Ring {
+            data: AtomicPtr::new(null_mut()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            writing: AtomicBool::new(false),
+            lost: AtomicBool::new(false),
+        }
+    }
+
+    /// Give the ring somewhere to keep its text.
+    ///
+    /// Anything already in the ring is lost. Only call this when the OS
+    /// starts, before anyone uses the ring.
+    pub fn attach(&self, memory: &'static mut [u8]) {
+        if memory.len() < RING_LEN {
+            return;
+        }
+        self.tail
+            .store(self.head.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.data.store(memory.as_mut_ptr(), Ordering::Release);
+    }
+
+    /// Add some text to the ring.
+    ///
+    /// All of it goes in, or none of it does.
+    pub fn push(&self, s: &str) {
+        let api = crate::API.get();
+        if !(api.compare_and_swap_bool)(&self.writing, false, true) {
+            // We interrupted someone else who is adding to the ring
+            self.lost.store(true, Ordering::Relaxed);
+            return;
+        }
+        let data = self.data.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Relaxed);
+        let used = head.wrapping_sub(self.tail.load(Ordering::Acquire));
+        if data.is_null() || s.len() > RING_LEN - used {
+            self.lost.store(true, Ordering::Relaxed);
+        } else {
+            for (idx, byte) in s.bytes().enumerate() {
+                // # Safety
+                //
+                // This is in the free part of the ring, which only we can
+                // touch while we hold the `writing` flag.
+                unsafe {
+                    data.add(head.wrapping_add(idx) % RING_LEN).write(byte);
+                }
+            }
+            self.head
+                .store(head.wrapping_add(s.len()), Ordering::Release);
+        }
+        self.writing.store(false, Ordering::Release);
+    }
+
+    /// Let writers in again, even if one was abandoned part way through.
+    ///
+    /// A writer only moves the head once its text is all in, so the ring is
+    /// never left half-written.
+    pub unsafe fn force_unlock(&self) {
+        self.writing.store(false, Ordering::Release);
+    }
+
+    /// Is there anything in the ring (or lost from it) to print?
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Relaxed)
+            && !self.lost.load(Ordering::Relaxed)
+    }
+
+    /// Take everything out of the ring, and give it to `f` to print.
+    ///
+    /// Only call this while you hold the lock on the console the ring is
+    /// for, so there's only ever one reader.
+    pub fn drain<F>(&self, mut f: F) -> core::fmt::Result
+    where
+        F: FnMut(&str) -> core::fmt::Result,
+    {
+        let data = self.data.load(Ordering::Acquire) as *const u8;
+        let mut buffer = [0u8; CHUNK_LEN];
+        // How many bytes in the buffer we still have to print
+        let mut len = 0;
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let mut tail = self.tail.load(Ordering::Relaxed);
+            let start = len;
+            // Nothing gets into the ring until it has some memory
+            while !data.is_null() && len < CHUNK_LEN && tail != head {
+                // # Safety
+                //
+                // This is in the used part of the ring, which only we can
+                // touch.
+                buffer[len] = unsafe { data.add(tail % RING_LEN).read() };
+                len += 1;
+                tail = tail.wrapping_add(1);
+            }
+            self.tail.store(tail, Ordering::Release);
+            if len == start {
+                // The ring is empty
+                break;
+            }
+            // We only ever add whole strings, but a chunk might end part way
+            // through a character, so keep any bits of one for next time
+            let valid = match core::str::from_utf8(&buffer[0..len]) {
+                Ok(s) => s.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            // # Safety
+            //
+            // We just checked this much of it is valid UTF-8.
+            f(unsafe { core::str::from_utf8_unchecked(&buffer[0..valid]) })?;
+            buffer.copy_within(valid..len, 0);
+            len -= valid;
+        }
+        if self.lost.load(Ordering::Relaxed) {
+            self.lost.store(false, Ordering::Relaxed);
+            f(LOST_MESSAGE)?;
+        }
+        Ok(())
+    }
+}
+
+// End of file
@@ -0,0 +1,128 @@
+//! # Statistics
+//!
+//! Counters that help find out where the time goes on a particular Neotron
+//! board. The `top` command shows them.
+//!
+//! The only thing the BIOS counts is ticks, so the OS keeps its own counts
+//! of everything else. Whenever the OS has nothing to do it calls [`idle`],
+//! which counts the calls and how many ticks the BIOS spent in them. All
+//! audio the OS plays goes through [`audio_output`], which counts the bytes,
+//! and counts an underrun whenever it finds the BIOS output buffer empty
+//! part way through a sound.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{bios, refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Our counters
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    counters: Counters {
+        idle_calls: 0,
+        idle_ticks: 0,
+        audio_bytes: 0,
+        audio_underruns: 0,
+    },
+    audio_capacity: 0,
+    last_audio_write: None,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The counters, as they were at some point in time.
+///
+/// They all start at zero when the OS boots.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Counters {
+    /// How many times the OS has called the BIOS `power_idle` function
+    pub idle_calls: u64,
+    /// How many ticks the BIOS spent in `power_idle`
+    pub idle_ticks: u64,
+    /// How many bytes of audio the OS has given the BIOS
+    pub audio_bytes: u64,
+    /// How many times the audio output ran dry in the middle of a sound
+    pub audio_underruns: u32,
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we know
+struct State {
+    /// The counters so far
+    counters: Counters,
+    /// The most space we have ever seen in the audio output buffer, which
+    /// we take to be its size
+    audio_capacity: usize,
+    /// When (in ticks) we last gave the BIOS some audio, if we have
+    last_audio_write: Option<u64>,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Let the BIOS idle until something happens, counting how long it took.
+pub fn idle() {
+    let api = API.get();
+    let start = (api.time_ticks_get)().0;
+    (api.power_idle)();
+    let end = (api.time_ticks_get)().0;
+    // We might be called while someone has the counters, so don't wait
+    if let Ok(mut state) = STATE.try_lock() {
+        state.counters.idle_calls += 1;
+        state.counters.idle_ticks += end.saturating_sub(start);
+    }
+}
+
+/// Give the BIOS some audio samples to play, and say how many bytes it took.
+///
+/// If the output buffer is empty and we gave it something less than a
+/// second ago, the sound has broken up, and we count an underrun. A longer
+/// gap means the last sound finished on purpose.
+pub fn audio_output(data: &[u8]) -> bios::ApiResult<usize> {
+    let api = API.get();
+    let now = (api.time_ticks_get)().0;
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    if let bios::FfiResult::Ok(space) = (api.audio_output_get_space)() {
+        let mut state = STATE.lock();
+        state.audio_capacity = state.audio_capacity.max(space);
+        let recent = state
+            .last_audio_write
+            .is_some_and(|last| now.saturating_sub(last) < ticks_per_second);
+        if recent && space >= state.audio_capacity {
+            state.counters.audio_underruns += 1;
+        }
+    }
+    // # Safety
+    //
+    // The BIOS only looks at the samples until this call returns, and they
+    // are borrowed until then.
+    let result = unsafe { (api.audio_output_data)(bios::FfiByteSlice::new(data)) };
+    if let bios::FfiResult::Ok(played) = result {
+        let mut state = STATE.lock();
+        state.counters.audio_bytes += played as u64;
+        state.last_audio_write = Some(now);
+    }
+    result
+}
+
+/// Get the counters as they are now
+pub fn counters() -> Counters {
+    STATE.lock().counters
+}
+
+/// Unlock the counters, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// End of file
@@ -0,0 +1,162 @@
+//! # Status Bar
+//!
+//! Draws the OS status line, if the VGA console has one. It shows the time,
+//! the output volume, the keyboard lock state and the name of whatever
+//! program is running. It can also show which modifier keys are held down,
+//! which helps when a keyboard seems to have a stuck key, and warnings from
+//! the hardware sensors.
+
+use core::fmt::Write;
+
+use chrono::Timelike;
+
+use crate::{bios, refcell::CsRefCell, vconsole, API, STD_INPUT};
+
+/// What we need to remember between refreshes
+struct State {
+    /// The time (in whole seconds) that we last drew the status line
+    last_secs: Option<u32>,
+    /// The name of the running program (or empty)
+    program: heapless::String<16>,
+    /// Do we show which modifier keys are held down?
+    show_modifiers: bool,
+    /// A warning to show (or empty)
+    warning: heapless::String<16>,
+}
+
+/// Our status bar state
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    last_secs: None,
+    program: heapless::String::new(),
+    show_modifiers: false,
+    warning: heapless::String::new(),
+});
+
+/// Record the name of the program that is running, or `None` if it has finished.
+pub fn set_program(name: Option<&str>) {
+    let mut state = STATE.lock();
+    state.program.clear();
+    if let Some(name) = name {
+        for ch in name.chars() {
+            if state.program.push(ch).is_err() {
+                break;
+            }
+        }
+    }
+    state.last_secs = None;
+}
+
+/// Set whether we show which modifier keys are held down
+pub fn configure_modifiers(show: bool) {
+    let mut state = STATE.lock();
+    state.show_modifiers = show;
+    state.last_secs = None;
+}
+
+/// Show a warning, like a low battery, or `None` to stop showing it.
+pub fn set_warning(warning: Option<&str>) {
+    let mut state = STATE.lock();
+    state.warning.clear();
+    if let Some(warning) = warning {
+        for ch in warning.chars() {
+            if state.warning.push(ch).is_err() {
+                break;
+            }
+        }
+    }
+    state.last_secs = None;
+}
+
+/// The keyboard lock or modifier keys have changed, so redraw the status
+/// line next time, instead of waiting for the clock to tick.
+pub fn keyboard_changed() {
+    STATE.lock().last_secs = None;
+}
+
+/// Redraw the status line.
+///
+/// Unless `force` is set, we only redraw once a second. Call this whenever
+/// the system is idle.
+pub fn refresh(force: bool) {
+    let api = API.get();
+    let secs = (api.time_clock_get)().secs;
+    let mut line: heapless::String<80> = heapless::String::new();
+    {
+        let mut state = STATE.lock();
+        if !force && state.last_secs == Some(secs) {
+            return;
+        }
+        state.last_secs = Some(secs);
+
+        let time = API.get_local_time();
+        let _ = write!(
+            line,
+            " {:02}:{:02}:{:02}",
+            time.hour(),
+            time.minute(),
+            time.second()
+        );
+        if !state.warning.is_empty() {
+            let _ = write!(line, " | !{}", state.warning);
+        }
+        if let Some(volume) = output_volume() {
+            let _ = write!(line, " | Vol {:3}%", volume);
+        }
+        let (locks, modifiers) = {
+            let std_input = STD_INPUT.lock();
+            (std_input.locks(), std_input.modifiers())
+        };
+        let _ = write!(
+            line,
+            " | {} {}",
+            if locks.caps_lock() { "CAPS" } else { "    " },
+            if locks.num_lock() { "NUM" } else { "   " }
+        );
+        if state.show_modifiers {
+            let _ = write!(
+                line,
+                " {} {} {}",
+                if modifiers.shift() { "SHIFT" } else { "     " },
+                if modifiers.ctrl() { "CTRL" } else { "    " },
+                if modifiers.alt() { "ALT" } else { "   " }
+            );
+        }
+        if !state.program.is_empty() {
+            let _ = write!(line, " | {}", state.program);
+        }
+        let _ = write!(line, " | Con {}", vconsole::active() + 1);
+    }
+
+    vconsole::write_status(&line);
+}
+
+/// Unlock the status bar, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+/// Get the level of the first output mixer channel, as a percentage
+fn output_volume() -> Option<u32> {
+    let api = API.get();
+    for mixer_id in 0u8..=255u8 {
+        match (api.audio_mixer_channel_get_info)(mixer_id) {
+            bios::FfiOption::Some(mixer_info) => {
+                if let Ok(bios::audio::Direction::Output) = mixer_info.direction.make_safe() {
+                    if mixer_info.max_level == 0 {
+                        return None;
+                    }
+                    return Some(
+                        (u32::from(mixer_info.current_level) * 100)
+                            / u32::from(mixer_info.max_level),
+                    );
+                }
+            }
+            bios::FfiOption::None => {
+                break;
+            }
+        }
+    }
+    None
+}
+
+// End of file
@@ -0,0 +1,172 @@
+//! # System Information Files
+//!
+//! The files in the `SYS:` device, like `SYS:UPTIME`, which applications
+//! can open and read, and `type` can show. None of them exist on disk - we
+//! make up their contents from the BIOS APIs every time they are read.
+//!
+//! Applications can read these a bit at a time, but the contents might
+//! change between reads (the uptime certainly will), so it's best to read
+//! the whole file in one go.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::fmt::Write as _;
+
+use crate::{bios, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The largest file we can make. Anything more is cut off.
+pub const MAX_FILE_LEN: usize = 1024;
+
+/// What every path in our device starts with
+const PREFIX: &str = "SYS:";
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The contents of one of our files
+pub type Contents = heapless::String<MAX_FILE_LEN>;
+
+/// One of the files in the `SYS:` device
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// `SYS:VERSION` - the OS, BIOS and BIOS API versions
+    Version,
+    /// `SYS:MEM` - the BIOS memory regions
+    Mem,
+    /// `SYS:BLKDEV` - the block devices
+    BlkDev,
+    /// `SYS:UPTIME` - seconds since the system started
+    Uptime,
+}
+
+impl Entry {
+    /// All the files, with their names
+    const ALL: [(&'static str, Entry); 4] = [
+        ("VERSION", Entry::Version),
+        ("MEM", Entry::Mem),
+        ("BLKDEV", Entry::BlkDev),
+        ("UPTIME", Entry::Uptime),
+    ];
+
+    /// Find the file a path (like `SYS:MEM` or `sys:mem`) refers to, if it is
+    /// one of ours.
+    pub fn from_path(path: &str) -> Option<Entry> {
+        let name = path
+            .get(0..PREFIX.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(PREFIX))
+            .map(|_| &path[PREFIX.len()..])?;
+        Self::ALL
+            .iter()
+            .find(|(entry_name, _)| entry_name.eq_ignore_ascii_case(name))
+            .map(|(_, entry)| *entry)
+    }
+
+    /// Make the contents of this file, as they are right now
+    pub fn generate(self) -> Contents {
+        let mut contents = Contents::new();
+        // If we run out of room, the file is just cut short
+        let _ = match self {
+            Entry::Version => write_version(&mut contents),
+            Entry::Mem => write_mem(&mut contents),
+            Entry::BlkDev => write_blkdev(&mut contents),
+            Entry::Uptime => write_uptime(&mut contents),
+        };
+        contents
+    }
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Write the contents of `SYS:VERSION`
+fn write_version(out: &mut Contents) -> core::fmt::Result {
+    let api = API.get();
+    let api_version = (api.api_version_get)();
+    writeln!(out, "OS: {}", crate::OS_VERSION)?;
+    writeln!(out, "BIOS: {}", (api.bios_version_get)())?;
+    writeln!(
+        out,
+        "BIOS API: {}.{}.{}",
+        api_version.major(),
+        api_version.minor(),
+        api_version.patch()
+    )
+}
+
+/// Write the contents of `SYS:MEM`, one region per line
+fn write_mem(out: &mut Contents) -> core::fmt::Result {
+    let api = API.get();
+    for region_idx in 0..=255u8 {
+        if let bios::FfiOption::Some(region) = (api.memory_get_region)(region_idx) {
+            writeln!(out, "{}: {}", region_idx, region)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the contents of `SYS:BLKDEV`, one device per line
+fn write_blkdev(out: &mut Contents) -> core::fmt::Result {
+    let api = API.get();
+    for dev_idx in 0..=255u8 {
+        if let bios::FfiOption::Some(device_info) = (api.block_dev_get_info)(dev_idx) {
+            writeln!(
+                out,
+                "{}: {} ({:?}), {} blocks of {} bytes, media {}{}",
+                dev_idx,
+                device_info.name,
+                device_info.device_type,
+                device_info.num_blocks,
+                device_info.block_size,
+                if device_info.media_present {
+                    "present"
+                } else {
+                    "missing"
+                },
+                if device_info.read_only {
+                    ", read only"
+                } else {
+                    ""
+                }
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the contents of `SYS:UPTIME`, in seconds
+fn write_uptime(out: &mut Contents) -> core::fmt::Result {
+    let uptime = API.get_uptime();
+    writeln!(out, "{}.{:03}", uptime.as_secs(), uptime.subsec_millis())
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(Entry::from_path("SYS:VERSION"), Some(Entry::Version));
+        assert_eq!(Entry::from_path("sys:mem"), Some(Entry::Mem));
+        assert_eq!(Entry::from_path("Sys:BlkDev"), Some(Entry::BlkDev));
+        assert_eq!(Entry::from_path("SYS:UPTIME"), Some(Entry::Uptime));
+        assert_eq!(Entry::from_path("SYS:"), None);
+        assert_eq!(Entry::from_path("SYS:FOO"), None);
+        assert_eq!(Entry::from_path("ROM:MEM"), None);
+        assert_eq!(Entry::from_path("MEM"), None);
+        assert_eq!(Entry::from_path("S"), None);
+    }
+}
+
+// End of file
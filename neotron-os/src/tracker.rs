@@ -0,0 +1,696 @@
+//! # Tracker Music
+//!
+//! Plays ProTracker MOD files, which are far smaller than the same music as
+//! raw PCM. A MOD file has a table of up to 31 instrument samples, a list of
+//! the order to play its patterns in, the patterns themselves (64 rows of
+//! notes and effects for each channel), and then the sample data.
+//!
+//! The sample data has to be in RAM, because any note can play any part of
+//! any sample, so we load it all at the start. The patterns are only read
+//! one at a time, as the song gets to them.
+//!
+//! We handle 4, 6 and 8 channel files, and the common effects (arpeggio,
+//! portamento, volume slides, volume, position jump, pattern break, note cut
+//! and speed). Finetune, vibrato and the rest are ignored, and the song stops
+//! if it would loop back on itself. XM files aren't supported.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::fs;
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The rate we mix at, in frames per second
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// How many bytes each frame of output takes (16-bit stereo)
+pub const BYTES_PER_FRAME: usize = 4;
+
+/// How many instrument samples a MOD file has
+const NUM_SAMPLES: usize = 31;
+
+/// Where the first sample header is
+const SAMPLE_HEADERS_OFFSET: usize = 20;
+
+/// How long each sample header is
+const SAMPLE_HEADER_LEN: usize = 30;
+
+/// Where the song length is
+const SONG_LENGTH_OFFSET: usize = 950;
+
+/// Where the pattern order table is
+const ORDERS_OFFSET: usize = 952;
+
+/// How many entries the pattern order table has
+const MAX_ORDERS: usize = 128;
+
+/// Where the tag that says how many channels there are lives
+const TAG_OFFSET: usize = 1080;
+
+/// How long the header is, up to the first pattern
+pub const HEADER_LEN: usize = 1084;
+
+/// How many rows each pattern has
+const ROWS_PER_PATTERN: usize = 64;
+
+/// How many bytes each note in a pattern takes
+const BYTES_PER_NOTE: usize = 4;
+
+/// The most channels we can play
+const MAX_CHANNELS: usize = 8;
+
+/// How many fractional bits our sample positions have
+const FRAC_BITS: u32 = 12;
+
+/// Half the Amiga's (PAL) clock. A note with period `p` plays its sample at
+/// `PAULA_CLOCK / p` samples per second.
+const PAULA_CLOCK: u64 = 3_546_895;
+
+/// How many ticks each row lasts, unless the song says otherwise
+const DEFAULT_SPEED: u8 = 6;
+
+/// How fast the ticks go, unless the song says otherwise. There are
+/// `tempo * 2 / 5` ticks a second.
+const DEFAULT_TEMPO: u8 = 125;
+
+/// The loudest a channel can be
+const MAX_VOLUME: u8 = 64;
+
+/// The periods of the notes from C-1 to B-3, with no finetune
+const PERIODS: [u16; 36] = [
+    856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453, //
+    428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226, //
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113,
+];
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways loading a module can fail
+#[derive(Debug)]
+pub enum Error {
+    /// We couldn't read the file
+    Io(fs::Error),
+    /// The file isn't a MOD file we understand
+    NotAModule,
+    /// The samples won't fit in the memory we were given
+    TooBig,
+}
+
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Somewhere we can read a module from
+pub trait Source {
+    /// Read from the given offset, filling as much of `buffer` as we can.
+    ///
+    /// Gives back less than `buffer.len()` only at the end of the file.
+    fn read_at(&self, offset: u32, buffer: &mut [u8]) -> Result<usize, fs::Error>;
+}
+
+impl Source for fs::File {
+    fn read_at(&self, offset: u32, buffer: &mut [u8]) -> Result<usize, fs::Error> {
+        self.seek_from_start(offset)?;
+        let mut count = 0;
+        while count < buffer.len() {
+            let n = self.read(&mut buffer[count..])?;
+            if n == 0 {
+                break;
+            }
+            count += n;
+        }
+        Ok(count)
+    }
+}
+
+/// Plays a MOD file
+pub struct Player<'a, S: Source> {
+    /// Where the module is
+    source: &'a S,
+    /// The song's title
+    title: [u8; 20],
+    /// How many channels the song has
+    num_channels: usize,
+    /// How many entries of `orders` are used
+    song_length: usize,
+    /// Which pattern to play at each position in the song
+    orders: [u8; MAX_ORDERS],
+    /// The instruments
+    samples: [Sample; NUM_SAMPLES],
+    /// The pattern we are playing, read from the file
+    pattern: &'a mut [u8],
+    /// Which pattern is in `pattern`
+    loaded_pattern: Option<u8>,
+    /// The sample data
+    sample_data: &'a [u8],
+    /// Which positions in the song we have played, so we can stop if it
+    /// loops back on itself
+    visited: u128,
+    /// Where we are in the song
+    order_idx: usize,
+    /// Which row of the pattern we are on
+    row: usize,
+    /// Which tick of the row we are on
+    tick: u8,
+    /// How many ticks each row lasts
+    speed: u8,
+    /// How fast the ticks go
+    tempo: u8,
+    /// How many more frames the current tick lasts
+    frames_left: u32,
+    /// Where to go after this row, if an effect said to jump
+    jump: Option<(usize, usize)>,
+    /// Has the song ended?
+    finished: bool,
+    /// What each channel is playing
+    channels: [Channel; MAX_CHANNELS],
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// One of the song's instruments
+#[derive(Debug, Default, Copy, Clone)]
+struct Sample {
+    /// Where its data starts in `Player::sample_data`
+    start: usize,
+    /// How many bytes of data it has
+    length: usize,
+    /// Where the looped part starts, in bytes
+    loop_start: usize,
+    /// How long the looped part is, in bytes. Zero if it doesn't loop.
+    loop_length: usize,
+    /// How loud it plays, unless a note says otherwise
+    volume: u8,
+}
+
+/// What one channel is doing
+#[derive(Debug, Default, Copy, Clone)]
+struct Channel {
+    /// Which instrument (from 1), or 0 for none
+    sample: usize,
+    /// Where we are in the sample, with `FRAC_BITS` fractional bits
+    position: u32,
+    /// How far we move through the sample for each frame
+    step: u32,
+    /// Is a note sounding?
+    playing: bool,
+    /// The period of the note, after any slides
+    period: u16,
+    /// The period arpeggio works from
+    base_period: u16,
+    /// How loud it is, from 0 to 64
+    volume: u8,
+    /// The effect on this row
+    effect: u8,
+    /// The effect's parameter
+    param: u8,
+    /// The note a tone portamento is sliding to
+    porta_target: u16,
+    /// How fast a tone portamento slides
+    porta_speed: u8,
+}
+
+impl Channel {
+    /// Work out how fast to move through the sample, for our period.
+    fn update_step(&mut self) {
+        self.step = if self.period == 0 {
+            0
+        } else {
+            ((PAULA_CLOCK << FRAC_BITS) / (u64::from(self.period) * u64::from(SAMPLE_RATE))) as u32
+        };
+    }
+
+    /// Slide the volume up by the top nibble of `param`, or down by the
+    /// bottom nibble.
+    fn volume_slide(&mut self, param: u8) {
+        let up = param >> 4;
+        let down = param & 0x0F;
+        self.volume = if up != 0 {
+            (self.volume + up).min(MAX_VOLUME)
+        } else {
+            self.volume.saturating_sub(down)
+        };
+    }
+
+    /// Slide the period towards the tone portamento target.
+    fn tone_portamento(&mut self) {
+        let speed = u16::from(self.porta_speed);
+        if self.porta_target == 0 {
+            return;
+        }
+        self.period = if self.period < self.porta_target {
+            (self.period + speed).min(self.porta_target)
+        } else {
+            self.period.saturating_sub(speed).max(self.porta_target)
+        };
+        self.base_period = self.period;
+    }
+}
+
+impl<'a, S: Source> Player<'a, S> {
+    /// Load a module, putting its samples (and the pattern being played)
+    /// into `memory`.
+    pub fn new(source: &'a S, memory: &'a mut [u8]) -> Result<Player<'a, S>, Error> {
+        let header = memory.get_mut(0..HEADER_LEN).ok_or(Error::TooBig)?;
+        if source.read_at(0, header)? != HEADER_LEN {
+            return Err(Error::NotAModule);
+        }
+        let num_channels = match &header[TAG_OFFSET..HEADER_LEN] {
+            b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => 4,
+            b"6CHN" => 6,
+            b"8CHN" => 8,
+            _ => return Err(Error::NotAModule),
+        };
+        let song_length = usize::from(header[SONG_LENGTH_OFFSET]);
+        if song_length == 0 || song_length > MAX_ORDERS {
+            return Err(Error::NotAModule);
+        }
+        let mut title = [0u8; 20];
+        title.copy_from_slice(&header[0..20]);
+        let mut orders = [0u8; MAX_ORDERS];
+        orders.copy_from_slice(&header[ORDERS_OFFSET..ORDERS_OFFSET + MAX_ORDERS]);
+        // Every pattern in the table is stored, even if the song doesn't
+        // play it
+        let num_patterns = orders
+            .iter()
+            .map(|&p| usize::from(p) + 1)
+            .max()
+            .unwrap_or(1);
+
+        let mut samples = [Sample::default(); NUM_SAMPLES];
+        let mut start = 0;
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            let offset = SAMPLE_HEADERS_OFFSET + idx * SAMPLE_HEADER_LEN;
+            let h = &header[offset..offset + SAMPLE_HEADER_LEN];
+            let words = |hi: u8, lo: u8| usize::from(u16::from_be_bytes([hi, lo])) * 2;
+            let length = words(h[22], h[23]);
+            let loop_start = words(h[26], h[27]);
+            let loop_length = words(h[28], h[29]);
+            *sample = Sample {
+                start,
+                length,
+                loop_start,
+                // A loop of one word means no loop
+                loop_length: if loop_length > 2 && loop_start < length {
+                    loop_length.min(length - loop_start)
+                } else {
+                    0
+                },
+                volume: h[25].min(MAX_VOLUME),
+            };
+            start += length;
+        }
+
+        let pattern_len = ROWS_PER_PATTERN * num_channels * BYTES_PER_NOTE;
+        if memory.len() < pattern_len + start {
+            return Err(Error::TooBig);
+        }
+        let (pattern, rest) = memory.split_at_mut(pattern_len);
+        let sample_data = &mut rest[0..start];
+        let samples_offset = HEADER_LEN + num_patterns * pattern_len;
+        let count = source.read_at(samples_offset as u32, sample_data)?;
+        // Some files are cut short. Play silence instead.
+        sample_data[count..].fill(0);
+
+        let mut player = Player {
+            source,
+            title,
+            num_channels,
+            song_length,
+            orders,
+            samples,
+            pattern,
+            loaded_pattern: None,
+            sample_data,
+            visited: 1,
+            order_idx: 0,
+            row: 0,
+            tick: 0,
+            speed: DEFAULT_SPEED,
+            tempo: DEFAULT_TEMPO,
+            frames_left: 0,
+            jump: None,
+            finished: false,
+            channels: [Channel::default(); MAX_CHANNELS],
+        };
+        // Make sure the first pattern is really there
+        player.load_pattern()?;
+        Ok(player)
+    }
+
+    /// The song's title
+    pub fn title(&self) -> &str {
+        let end = self.title.iter().position(|&b| b == 0).unwrap_or(20);
+        core::str::from_utf8(&self.title[0..end])
+            .unwrap_or("")
+            .trim_end()
+    }
+
+    /// Fill `buffer` with 16-bit little-endian stereo audio.
+    ///
+    /// Gives back how many bytes we wrote, which is only less than the
+    /// length of the buffer when the song ends.
+    pub fn fill(&mut self, buffer: &mut [u8]) -> Result<usize, fs::Error> {
+        let mut count = 0;
+        for frame in buffer.chunks_exact_mut(BYTES_PER_FRAME) {
+            if self.frames_left == 0 && !self.next_tick()? {
+                break;
+            }
+            let (left, right) = self.mix();
+            frame[0..2].copy_from_slice(&left.to_le_bytes());
+            frame[2..4].copy_from_slice(&right.to_le_bytes());
+            self.frames_left -= 1;
+            count += BYTES_PER_FRAME;
+        }
+        Ok(count)
+    }
+
+    /// Read the pattern for our position in the song, if we don't have it.
+    fn load_pattern(&mut self) -> Result<(), fs::Error> {
+        let pattern = self.orders[self.order_idx];
+        if self.loaded_pattern == Some(pattern) {
+            return Ok(());
+        }
+        let offset = HEADER_LEN + usize::from(pattern) * self.pattern.len();
+        let count = self.source.read_at(offset as u32, self.pattern)?;
+        self.pattern[count..].fill(0);
+        self.loaded_pattern = Some(pattern);
+        Ok(())
+    }
+
+    /// Start the next tick. Returns false if the song has ended.
+    fn next_tick(&mut self) -> Result<bool, fs::Error> {
+        if self.finished {
+            return Ok(false);
+        }
+        if self.tick == 0 {
+            self.load_pattern()?;
+            self.play_row();
+        } else {
+            self.play_effects();
+        }
+        self.frames_left = SAMPLE_RATE * 5 / (2 * u32::from(self.tempo.max(1)));
+        self.tick += 1;
+        if self.tick >= self.speed {
+            self.tick = 0;
+            self.next_row();
+        }
+        Ok(true)
+    }
+
+    /// Move on to the next row, or wherever an effect told us to jump.
+    fn next_row(&mut self) {
+        let (order_idx, row) = match self.jump.take() {
+            Some(jump) => jump,
+            None if self.row + 1 < ROWS_PER_PATTERN => (self.order_idx, self.row + 1),
+            None => (self.order_idx + 1, 0),
+        };
+        if order_idx != self.order_idx {
+            let bit = 1u128 << (order_idx % MAX_ORDERS);
+            if order_idx >= self.song_length || self.visited & bit != 0 {
+                self.finished = true;
+                return;
+            }
+            self.visited |= bit;
+        }
+        self.order_idx = order_idx;
+        self.row = row;
+    }
+
+    /// Start the notes and effects on the current row.
+    fn play_row(&mut self) {
+        let row_len = self.num_channels * BYTES_PER_NOTE;
+        for idx in 0..self.num_channels {
+            let offset = self.row * row_len + idx * BYTES_PER_NOTE;
+            let note = &self.pattern[offset..offset + BYTES_PER_NOTE];
+            let sample = usize::from((note[0] & 0xF0) | (note[2] >> 4));
+            let period = (u16::from(note[0] & 0x0F) << 8) | u16::from(note[1]);
+            let effect = note[2] & 0x0F;
+            let param = note[3];
+
+            let ch = &mut self.channels[idx];
+            if ch.effect == 0 && ch.param != 0 {
+                // Arpeggio has finished
+                ch.period = ch.base_period;
+            }
+            ch.effect = effect;
+            ch.param = param;
+            if (1..=NUM_SAMPLES).contains(&sample) {
+                ch.sample = sample;
+                ch.volume = self.samples[sample - 1].volume;
+            }
+            if period != 0 {
+                if effect == 0x3 || effect == 0x5 {
+                    ch.porta_target = period;
+                } else {
+                    ch.period = period;
+                    ch.base_period = period;
+                    ch.position = 0;
+                    ch.playing = ch.sample != 0;
+                }
+            }
+            match effect {
+                0x3 if param != 0 => ch.porta_speed = param,
+                0xB => {
+                    let row = self.jump.map_or(0, |(_, row)| row);
+                    self.jump = Some((usize::from(param), row));
+                }
+                0xC => ch.volume = param.min(MAX_VOLUME),
+                0xD => {
+                    let row = usize::from(param >> 4) * 10 + usize::from(param & 0x0F);
+                    let order_idx = self
+                        .jump
+                        .map_or(self.order_idx + 1, |(order_idx, _)| order_idx);
+                    self.jump = Some((order_idx, row.min(ROWS_PER_PATTERN - 1)));
+                }
+                0xE => match param >> 4 {
+                    0xA => ch.volume = (ch.volume + (param & 0x0F)).min(MAX_VOLUME),
+                    0xB => ch.volume = ch.volume.saturating_sub(param & 0x0F),
+                    0xC if param & 0x0F == 0 => ch.volume = 0,
+                    _ => {}
+                },
+                0xF if param == 0 => {}
+                0xF if param < 32 => self.speed = param,
+                0xF => self.tempo = param,
+                _ => {}
+            }
+            ch.update_step();
+        }
+    }
+
+    /// Apply the effects that happen on every tick after the first.
+    fn play_effects(&mut self) {
+        let tick = self.tick;
+        for ch in self.channels[0..self.num_channels].iter_mut() {
+            let param = ch.param;
+            match ch.effect {
+                0x0 if param != 0 => {
+                    let semitones = match tick % 3 {
+                        0 => 0,
+                        1 => param >> 4,
+                        _ => param & 0x0F,
+                    };
+                    ch.period = shift_period(ch.base_period, semitones);
+                }
+                0x1 => {
+                    ch.period = ch.period.saturating_sub(u16::from(param)).max(PERIODS[35]);
+                    ch.base_period = ch.period;
+                }
+                0x2 => {
+                    ch.period = (ch.period + u16::from(param)).min(PERIODS[0]);
+                    ch.base_period = ch.period;
+                }
+                0x3 => ch.tone_portamento(),
+                0x5 => {
+                    ch.tone_portamento();
+                    ch.volume_slide(param);
+                }
+                0xA => ch.volume_slide(param),
+                0xE if param >> 4 == 0xC && tick == param & 0x0F => ch.volume = 0,
+                _ => {}
+            }
+            ch.update_step();
+        }
+    }
+
+    /// Mix one frame from all the channels.
+    ///
+    /// Channels 0 and 3 (and 4 and 7) are on the left, like on an Amiga, and
+    /// the rest are on the right. We mix a little of each side into the
+    /// other, so it's easier on headphones.
+    fn mix(&mut self) -> (i16, i16) {
+        let mut left = 0i32;
+        let mut right = 0i32;
+        for (idx, ch) in self.channels[0..self.num_channels].iter_mut().enumerate() {
+            if !ch.playing || ch.sample == 0 {
+                continue;
+            }
+            let sample = &self.samples[ch.sample - 1];
+            let mut offset = (ch.position >> FRAC_BITS) as usize;
+            if sample.loop_length != 0 {
+                let loop_end = sample.loop_start + sample.loop_length;
+                if offset >= loop_end {
+                    offset = sample.loop_start + (offset - loop_end) % sample.loop_length;
+                    ch.position =
+                        ((offset as u32) << FRAC_BITS) | (ch.position & ((1 << FRAC_BITS) - 1));
+                }
+            } else if offset >= sample.length {
+                ch.playing = false;
+                continue;
+            }
+            let value = i32::from(self.sample_data[sample.start + offset] as i8);
+            let value = value * i32::from(ch.volume);
+            if matches!(idx % 4, 0 | 3) {
+                left += value;
+            } else {
+                right += value;
+            }
+            ch.position = ch.position.wrapping_add(ch.step);
+        }
+        let mixed_left = left * 3 / 4 + right / 4;
+        let mixed_right = right * 3 / 4 + left / 4;
+        (clamp(mixed_left), clamp(mixed_right))
+    }
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Move a note up some semitones, as far as the top of our period table.
+fn shift_period(period: u16, semitones: u8) -> u16 {
+    let Some(idx) = PERIODS.iter().position(|&p| p <= period) else {
+        return period;
+    };
+    let idx = (idx + usize::from(semitones)).min(PERIODS.len() - 1);
+    PERIODS[idx]
+}
+
+/// Squash a mixed value into a 16-bit sample.
+fn clamp(value: i32) -> i16 {
+    value.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
+}
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Source for Vec<u8> {
+        fn read_at(&self, offset: u32, buffer: &mut [u8]) -> Result<usize, fs::Error> {
+            let data = self.get(offset as usize..).unwrap_or(&[]);
+            let count = data.len().min(buffer.len());
+            buffer[0..count].copy_from_slice(&data[0..count]);
+            Ok(count)
+        }
+    }
+
+    /// Make a one-pattern, four-channel module with a looping square wave
+    /// sample. `notes` are (row, channel, note bytes).
+    fn make_module(notes: &[(usize, usize, [u8; 4])]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_LEN];
+        data[0..4].copy_from_slice(b"TEST");
+        // Sample 1 is 32 bytes long, at full volume, and loops all of it
+        let h = SAMPLE_HEADERS_OFFSET;
+        data[h + 22..h + 24].copy_from_slice(&16u16.to_be_bytes());
+        data[h + 25] = 64;
+        data[h + 28..h + 30].copy_from_slice(&16u16.to_be_bytes());
+        data[SONG_LENGTH_OFFSET] = 1;
+        data[TAG_OFFSET..HEADER_LEN].copy_from_slice(b"M.K.");
+        let mut pattern = vec![0u8; ROWS_PER_PATTERN * 4 * BYTES_PER_NOTE];
+        for (row, channel, note) in notes {
+            let offset = (row * 4 + channel) * BYTES_PER_NOTE;
+            pattern[offset..offset + BYTES_PER_NOTE].copy_from_slice(note);
+        }
+        data.extend_from_slice(&pattern);
+        for idx in 0..32 {
+            data.push(if idx < 16 { 0x40 } else { 0xC0 });
+        }
+        data
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let data = vec![0u8; 2048];
+        let mut memory = vec![0u8; 8192];
+        assert!(matches!(
+            Player::new(&data, &mut memory),
+            Err(Error::NotAModule)
+        ));
+    }
+
+    #[test]
+    fn plays_a_note_then_stops() {
+        // Sample 1 at C-2 (period 428), on the left
+        let data = make_module(&[(0, 0, [0x01, 0xAC, 0x10, 0x00])]);
+        let mut memory = vec![0u8; 8192];
+        let mut player = Player::new(&data, &mut memory).unwrap();
+        assert_eq!(player.title(), "TEST");
+        let mut buffer = [0u8; 4096];
+        let mut total = 0;
+        let mut loudest_left = 0;
+        let mut loudest_right = 0;
+        loop {
+            let count = player.fill(&mut buffer).unwrap();
+            for frame in buffer[0..count].chunks_exact(BYTES_PER_FRAME) {
+                let left = i16::from_le_bytes([frame[0], frame[1]]);
+                let right = i16::from_le_bytes([frame[2], frame[3]]);
+                loudest_left = loudest_left.max(left.unsigned_abs());
+                loudest_right = loudest_right.max(right.unsigned_abs());
+            }
+            total += count;
+            if count < buffer.len() {
+                break;
+            }
+        }
+        // 64 rows of 6 ticks at 125 BPM is 7.68 seconds
+        assert_eq!(total / BYTES_PER_FRAME, 64 * 6 * 960);
+        assert_eq!(loudest_left, 64 * 64 * 3 / 4);
+        assert_eq!(loudest_right, 64 * 64 / 4);
+    }
+
+    #[test]
+    fn pattern_break_ends_song() {
+        // Break to the next position (which doesn't exist) on row 1, with
+        // the speed set to 3
+        let data = make_module(&[
+            (0, 1, [0x00, 0x00, 0x0F, 0x03]),
+            (1, 2, [0x00, 0x00, 0x0D, 0x00]),
+        ]);
+        let mut memory = vec![0u8; 8192];
+        let mut player = Player::new(&data, &mut memory).unwrap();
+        let mut buffer = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            let count = player.fill(&mut buffer).unwrap();
+            total += count;
+            if count < buffer.len() {
+                break;
+            }
+        }
+        assert_eq!(total / BYTES_PER_FRAME, 2 * 3 * 960);
+    }
+
+    #[test]
+    fn arpeggio_periods() {
+        assert_eq!(shift_period(428, 0), 428);
+        assert_eq!(shift_period(428, 4), 339);
+        assert_eq!(shift_period(428, 7), 285);
+        assert_eq!(shift_period(113, 3), 113);
+    }
+}
+
+// End of file
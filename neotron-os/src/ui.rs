@@ -0,0 +1,613 @@
+//! # Text User Interface
+//!
+//! Menus, dialog boxes and buttons that OS commands can use. They are drawn
+//! straight on to the VGA console, and driven by the keyboard and the mouse.
+//!
+//! Each window saves what was on the screen underneath it, and puts it back
+//! when it closes. The mouse pointer is the character under it in reverse
+//! colours, and only appears once the mouse moves. If the main VGA console
+//! isn't on the screen there is no UI, and commands should fall back to
+//! asking on the command line (see [`is_available`]).
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::{convert::TryFrom, fmt::Write as _};
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::{
+    bios::video::{Attr, TextBackgroundColour, TextForegroundColour},
+    vgaconsole::VgaConsole,
+    MouseMotion, FILESYSTEM, STD_INPUT, VGA_CONSOLE,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// The widest window we can save the screen under
+const MAX_WINDOW_WIDTH: usize = 64;
+
+/// The tallest window we can save the screen under
+const MAX_WINDOW_HEIGHT: usize = 20;
+
+/// The most buttons a dialog box can have
+const MAX_BUTTONS: usize = 4;
+
+/// The most files [`pick_file`] will offer
+const MAX_PICK_FILES: usize = 64;
+
+/// How far the mouse has to move to move the pointer one column
+const MOUSE_UNITS_PER_COL: i32 = 4;
+
+/// How far the mouse has to move to move the pointer one row
+const MOUSE_UNITS_PER_ROW: i32 = 8;
+
+/// Windows are white on blue
+const WINDOW_ATTR: Attr = Attr::new(
+    TextForegroundColour::White,
+    TextBackgroundColour::Blue,
+    false,
+);
+
+/// The selected menu item is black on cyan
+const SELECTED_ATTR: Attr = Attr::new(
+    TextForegroundColour::Black,
+    TextBackgroundColour::Cyan,
+    false,
+);
+
+/// Buttons are black on grey
+const BUTTON_ATTR: Attr = Attr::new(
+    TextForegroundColour::Black,
+    TextBackgroundColour::LightGray,
+    false,
+);
+
+/// The button Enter would press is white on green
+const FOCUSED_BUTTON_ATTR: Attr = Attr::new(
+    TextForegroundColour::White,
+    TextBackgroundColour::Green,
+    false,
+);
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Something the user did
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Event {
+    /// A key was pressed (the right mouse button counts as Escape)
+    Key(DecodedKey),
+    /// The left mouse button was clicked, with the pointer at this column
+    /// and row of the screen
+    Click { col: isize, row: isize },
+}
+
+/// A box on the screen, with a frame and a title.
+///
+/// When it is dropped, it puts back what was on the screen underneath.
+struct Window {
+    /// The screen column of the left edge of the frame
+    col: isize,
+    /// The screen row of the top edge of the frame
+    row: isize,
+    /// How wide the window is, including the frame
+    width: usize,
+    /// How tall the window is, including the frame
+    height: usize,
+    /// What was on the screen underneath, a row at a time
+    saved: [u16; MAX_WINDOW_WIDTH * MAX_WINDOW_HEIGHT],
+}
+
+impl Window {
+    /// Open a window in the middle of the screen, with room for `width` by
+    /// `height` characters inside the frame.
+    ///
+    /// The window is made smaller if it won't fit. Returns `None` if there's
+    /// no UI.
+    fn open(title: &str, width: usize, height: usize) -> Option<Window> {
+        if !is_available() {
+            return None;
+        }
+        let mut guard = VGA_CONSOLE.lock();
+        let console = guard.as_mut()?;
+        let screen_width = console.width() as usize;
+        let screen_height = console.full_height() as usize;
+        let width = (width + 2).min(MAX_WINDOW_WIDTH).min(screen_width);
+        let height = (height + 2).min(MAX_WINDOW_HEIGHT).min(screen_height);
+        if width < 3 || height < 3 {
+            return None;
+        }
+        let mut window = Window {
+            col: ((screen_width - width) / 2) as isize,
+            row: ((screen_height - height) / 2) as isize,
+            width,
+            height,
+            saved: [0; MAX_WINDOW_WIDTH * MAX_WINDOW_HEIGHT],
+        };
+        for (row, saved) in window
+            .saved
+            .chunks_exact_mut(width)
+            .take(height)
+            .enumerate()
+        {
+            console.read_cells(window.row + row as isize, window.col, saved);
+        }
+        // The shell's cursor mustn't appear in our window
+        console.write_bstr(b"\x1b[?25l");
+        drop(guard);
+
+        // Draw the frame, with the title on the top edge
+        let inner = width - 2;
+        window.draw(0, 0, core::iter::once('┌'), WINDOW_ATTR);
+        window.draw(0, 1, core::iter::repeat_n('─', inner), WINDOW_ATTR);
+        window.draw(0, width - 1, core::iter::once('┐'), WINDOW_ATTR);
+        window.draw(
+            0,
+            2,
+            title.chars().take(inner.saturating_sub(2)),
+            WINDOW_ATTR,
+        );
+        for row in 1..height - 1 {
+            window.draw(row, 0, core::iter::once('│'), WINDOW_ATTR);
+            window.draw(row, 1, core::iter::repeat_n(' ', inner), WINDOW_ATTR);
+            window.draw(row, width - 1, core::iter::once('│'), WINDOW_ATTR);
+        }
+        window.draw(height - 1, 0, core::iter::once('└'), WINDOW_ATTR);
+        window.draw(height - 1, 1, core::iter::repeat_n('─', inner), WINDOW_ATTR);
+        window.draw(height - 1, width - 1, core::iter::once('┘'), WINDOW_ATTR);
+        Some(window)
+    }
+
+    /// How many columns are there inside the frame?
+    fn inner_width(&self) -> usize {
+        self.width - 2
+    }
+
+    /// How many rows are there inside the frame?
+    fn inner_height(&self) -> usize {
+        self.height - 2
+    }
+
+    /// Draw some characters, starting at a row and column of the window
+    /// (where the top left of the frame is 0, 0). They are cut off at the
+    /// right edge of the window.
+    fn draw<I>(&self, row: usize, col: usize, chars: I, attr: Attr)
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut cells = [0u16; MAX_WINDOW_WIDTH];
+        let mut count = 0;
+        let space = self.width.saturating_sub(col);
+        for (cell, ch) in cells[0..space].iter_mut().zip(chars) {
+            *cell = u16::from_le_bytes([VgaConsole::char_to_glyph(ch), attr.as_u8()]);
+            count += 1;
+        }
+        if let Some(console) = VGA_CONSOLE.lock().as_mut() {
+            console.write_cells(
+                self.row + row as isize,
+                self.col + col as isize,
+                &cells[0..count],
+            );
+        }
+    }
+
+    /// Write a line of text inside the frame, padded with spaces (and a
+    /// space either side) to fill the row.
+    fn print_line(&self, row: usize, text: &str, attr: Attr) {
+        let chars = core::iter::once(' ')
+            .chain(text.chars())
+            .chain(core::iter::repeat(' '));
+        self.draw(row + 1, 1, chars.take(self.inner_width()), attr);
+    }
+
+    /// Which row and column inside the frame is this screen position at, if
+    /// it is inside the frame?
+    fn inside(&self, col: isize, row: isize) -> Option<(usize, usize)> {
+        let row = usize::try_from(row - self.row - 1).ok()?;
+        let col = usize::try_from(col - self.col - 1).ok()?;
+        if row < self.inner_height() && col < self.inner_width() {
+            Some((row, col))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        if let Some(console) = VGA_CONSOLE.lock().as_mut() {
+            for (row, saved) in self
+                .saved
+                .chunks_exact(self.width)
+                .take(self.height)
+                .enumerate()
+            {
+                console.write_cells(self.row + row as isize, self.col, saved);
+            }
+            console.write_bstr(b"\x1b[?25h");
+        }
+    }
+}
+
+/// A button in a window
+struct Button<'a> {
+    /// What it says on the button
+    label: &'a str,
+    /// The row inside the window frame
+    row: usize,
+    /// The column inside the window frame
+    col: usize,
+}
+
+impl<'a> Button<'a> {
+    /// How wide the button is, in characters
+    fn width(label: &str) -> usize {
+        label.chars().count() + 4
+    }
+
+    /// Draw the button
+    fn draw(&self, window: &Window, focused: bool) {
+        let attr = if focused {
+            FOCUSED_BUTTON_ATTR
+        } else {
+            BUTTON_ATTR
+        };
+        let chars = "[ ".chars().chain(self.label.chars()).chain(" ]".chars());
+        window.draw(self.row + 1, self.col + 1, chars, attr);
+    }
+
+    /// Is this row and column inside the window frame on the button?
+    fn contains(&self, row: usize, col: usize) -> bool {
+        row == self.row && col >= self.col && col < self.col + Self::width(self.label)
+    }
+}
+
+/// The mouse pointer
+struct Pointer {
+    /// The screen column
+    col: isize,
+    /// The screen row
+    row: isize,
+    /// How wide the screen is
+    screen_width: isize,
+    /// How tall the screen is
+    screen_height: isize,
+    /// Mouse movement that didn't add up to a whole column
+    x_rem: i32,
+    /// Mouse movement that didn't add up to a whole row
+    y_rem: i32,
+    /// What was on the screen where the pointer is, if it is showing
+    under: Option<u16>,
+    /// Has the mouse moved since we started?
+    active: bool,
+    /// Was the left button held down last time we looked?
+    left: bool,
+    /// Was the right button held down last time we looked?
+    right: bool,
+}
+
+impl Pointer {
+    /// Make a pointer in the middle of the screen. It isn't shown until the
+    /// mouse moves.
+    fn new() -> Pointer {
+        let (screen_width, screen_height) = VGA_CONSOLE
+            .lock()
+            .as_ref()
+            .map_or((1, 1), |c| (c.width(), c.full_height()));
+        Pointer {
+            col: screen_width / 2,
+            row: screen_height / 2,
+            screen_width,
+            screen_height,
+            x_rem: 0,
+            y_rem: 0,
+            under: None,
+            active: false,
+            left: false,
+            right: false,
+        }
+    }
+
+    /// Draw the pointer, if the mouse has been used
+    fn show(&mut self) {
+        if !self.active || self.under.is_some() {
+            return;
+        }
+        if let Some(console) = VGA_CONSOLE.lock().as_mut() {
+            let mut cell = [0u16];
+            if console.read_cells(self.row, self.col, &mut cell) == 1 {
+                self.under = Some(cell[0]);
+                let [glyph, attr] = cell[0].to_le_bytes();
+                // Swap the foreground and background colours
+                let attr = ((attr & 0x07) << 4) | ((attr >> 4) & 0x07);
+                console.write_cells(self.row, self.col, &[u16::from_le_bytes([glyph, attr])]);
+            }
+        }
+    }
+
+    /// Take the pointer off the screen, so something can be drawn under it
+    fn hide(&mut self) {
+        if let Some(cell) = self.under.take() {
+            if let Some(console) = VGA_CONSOLE.lock().as_mut() {
+                console.write_cells(self.row, self.col, &[cell]);
+            }
+        }
+    }
+
+    /// Move the pointer, and work out whether a button was clicked.
+    fn update(&mut self, motion: MouseMotion) -> Option<Event> {
+        self.hide();
+        self.active = true;
+        self.x_rem += motion.dx;
+        self.y_rem += motion.dy;
+        self.col = (self.col + (self.x_rem / MOUSE_UNITS_PER_COL) as isize)
+            .clamp(0, self.screen_width - 1);
+        self.row = (self.row + (self.y_rem / MOUSE_UNITS_PER_ROW) as isize)
+            .clamp(0, self.screen_height - 1);
+        self.x_rem %= MOUSE_UNITS_PER_COL;
+        self.y_rem %= MOUSE_UNITS_PER_ROW;
+        self.show();
+
+        let clicked = motion.left && !self.left;
+        let cancelled = motion.right && !self.right;
+        self.left = motion.left;
+        self.right = motion.right;
+        if clicked {
+            Some(Event::Click {
+                col: self.col,
+                row: self.row,
+            })
+        } else if cancelled {
+            Some(Event::Key(DecodedKey::RawKey(KeyCode::Escape)))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Pointer {
+    fn drop(&mut self) {
+        self.hide();
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Can we show windows on the screen?
+///
+/// We need the main VGA console to be on the screen.
+pub fn is_available() -> bool {
+    crate::vconsole::active() == 0 && VGA_CONSOLE.lock().is_some()
+}
+
+/// Let the user choose one of some items, from a menu in a window.
+///
+/// Use the arrow keys and Enter (or type the first letter of an item), or
+/// click on an item. Gives `None` if the user presses Escape (or the right
+/// mouse button), or if there's no UI.
+pub fn choose(title: &str, items: &[&str]) -> Option<usize> {
+    let last = items.len().checked_sub(1)?;
+    let widest = items
+        .iter()
+        .map(|item| item.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(title.chars().count() + 2);
+    let window = Window::open(title, widest + 2, items.len())?;
+    let mut pointer = Pointer::new();
+    let visible = window.inner_height();
+    let mut selected = 0;
+    let mut top = 0;
+    loop {
+        // Keep the selected item on the screen
+        if selected < top {
+            top = selected;
+        } else if selected >= top + visible {
+            top = selected + 1 - visible;
+        }
+        pointer.hide();
+        for line in 0..visible {
+            let idx = top + line;
+            let attr = if idx == selected {
+                SELECTED_ATTR
+            } else {
+                WINDOW_ATTR
+            };
+            window.print_line(line, items.get(idx).copied().unwrap_or(""), attr);
+        }
+        pointer.show();
+
+        match next_event(&mut pointer) {
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowUp)) => {
+                selected = selected.saturating_sub(1);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowDown)) => {
+                selected = (selected + 1).min(last);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::PageUp)) => {
+                selected = selected.saturating_sub(visible);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::PageDown)) => {
+                selected = (selected + visible).min(last);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::Home)) => {
+                selected = 0;
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::End)) => {
+                selected = last;
+            }
+            Event::Key(DecodedKey::Unicode('\r' | '\n')) => {
+                return Some(selected);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::Escape) | DecodedKey::Unicode('\u{1b}')) => {
+                return None;
+            }
+            Event::Key(DecodedKey::Unicode(ch)) => {
+                // Go to the next item that starts with this letter
+                let starts_with = |idx: &usize| {
+                    items[*idx]
+                        .chars()
+                        .next()
+                        .is_some_and(|first| first.eq_ignore_ascii_case(&ch))
+                };
+                if let Some(idx) = (selected + 1..items.len())
+                    .chain(0..=selected)
+                    .find(starts_with)
+                {
+                    selected = idx;
+                }
+            }
+            Event::Click { col, row } => {
+                if let Some((line, _)) = window.inside(col, row) {
+                    if top + line <= last {
+                        return Some(top + line);
+                    }
+                }
+            }
+            Event::Key(_) => {}
+        }
+    }
+}
+
+/// Show a message in a window, with some buttons under it.
+///
+/// Use Left, Right or Tab and Enter, or click on a button. Gives which
+/// button was pressed, or `None` if the user presses Escape (or the right
+/// mouse button), or if there's no UI. Only the first few buttons are
+/// shown.
+pub fn dialog(title: &str, message: &str, labels: &[&str]) -> Option<usize> {
+    let labels = &labels[0..labels.len().min(MAX_BUTTONS)];
+    let buttons_width: usize =
+        labels.iter().map(|l| Button::width(l)).sum::<usize>() + labels.len().saturating_sub(1);
+    let num_lines = message.lines().count();
+    let widest = message
+        .lines()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(buttons_width)
+        .max(title.chars().count() + 2);
+    let window = Window::open(title, widest + 2, num_lines + 2)?;
+    for (row, line) in message.lines().enumerate() {
+        window.print_line(row, line, WINDOW_ATTR);
+    }
+
+    // The buttons go in the middle of the bottom row
+    let mut buttons: heapless::Vec<Button, MAX_BUTTONS> = heapless::Vec::new();
+    let mut col = window.inner_width().saturating_sub(buttons_width) / 2;
+    for label in labels {
+        // Can't fail - we only took as many labels as we have room for
+        let _ = buttons.push(Button {
+            label,
+            row: window.inner_height() - 1,
+            col,
+        });
+        col += Button::width(label) + 1;
+    }
+
+    let mut pointer = Pointer::new();
+    let mut focus = 0;
+    loop {
+        pointer.hide();
+        for (idx, button) in buttons.iter().enumerate() {
+            button.draw(&window, idx == focus);
+        }
+        pointer.show();
+
+        match next_event(&mut pointer) {
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowLeft)) => {
+                focus = focus.saturating_sub(1);
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::ArrowRight)) => {
+                focus = (focus + 1).min(buttons.len().saturating_sub(1));
+            }
+            Event::Key(DecodedKey::Unicode('\t')) => {
+                focus = (focus + 1) % buttons.len().max(1);
+            }
+            Event::Key(DecodedKey::Unicode('\r' | '\n')) => {
+                return if buttons.is_empty() {
+                    None
+                } else {
+                    Some(focus)
+                };
+            }
+            Event::Key(DecodedKey::RawKey(KeyCode::Escape) | DecodedKey::Unicode('\u{1b}')) => {
+                return None;
+            }
+            Event::Click { col, row } => {
+                if let Some((row, col)) = window.inside(col, row) {
+                    if let Some(idx) = buttons.iter().position(|b| b.contains(row, col)) {
+                        return Some(idx);
+                    }
+                }
+            }
+            Event::Key(_) => {}
+        }
+    }
+}
+
+/// Let the user choose a file from the root directory of the disk.
+///
+/// Gives `None` if the user gives up, or if there's no UI.
+pub fn pick_file(title: &str) -> Option<embedded_sdmmc::ShortFileName> {
+    let mut files: heapless::Vec<embedded_sdmmc::ShortFileName, MAX_PICK_FILES> =
+        heapless::Vec::new();
+    if FILESYSTEM
+        .iterate_root_dir(|entry| {
+            if !entry.attributes.is_directory() && !entry.attributes.is_volume() {
+                let _ = files.push(entry.name.clone());
+            }
+        })
+        .is_err()
+    {
+        dialog(title, "Can't read the disk.", &["OK"]);
+        return None;
+    }
+    if files.is_empty() {
+        dialog(title, "There are no files.", &["OK"]);
+        return None;
+    }
+    files.sort_unstable_by(|a, b| {
+        (a.base_name(), a.extension()).cmp(&(b.base_name(), b.extension()))
+    });
+
+    let mut names: heapless::Vec<heapless::String<12>, MAX_PICK_FILES> = heapless::Vec::new();
+    for file in files.iter() {
+        let mut name = heapless::String::new();
+        let _ = write!(name, "{}", file);
+        // Can't fail - we have as much room as `files` does
+        let _ = names.push(name);
+    }
+    let items: heapless::Vec<&str, MAX_PICK_FILES> = names.iter().map(|s| s.as_str()).collect();
+    choose(title, &items).map(|idx| files[idx].clone())
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Wait for the user to do something
+fn next_event(pointer: &mut Pointer) -> Event {
+    loop {
+        let (key, motion) = {
+            let mut std_input = STD_INPUT.lock();
+            (std_input.get_raw(), std_input.take_mouse())
+        };
+        if let Some(key) = key {
+            return Event::Key(key);
+        }
+        if let Some(event) = motion.and_then(|motion| pointer.update(motion)) {
+            return event;
+        }
+        crate::stats::idle();
+    }
+}
+
+// End of file
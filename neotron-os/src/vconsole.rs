@@ -0,0 +1,280 @@
+//! # Virtual Consoles
+//!
+//! Console 1 is our main VGA console (`VGA_CONSOLE`), which the shell uses.
+//! Consoles 2 and up live here. Only one console is on the screen at any one
+//! time - the others draw into buffers in RAM. Switching consoles exchanges
+//! the contents of video memory with the RAM buffer of the console coming on
+//! screen.
+//!
+//! OS RAM is too small for those buffers, so each one is reserved off the
+//! top of the TPA the first time we switch to its console, and kept from
+//! then on. The TPA belongs to the running program, so that can only happen
+//! at the shell - switching to a console that has never been used waits
+//! until the program has finished.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use core::fmt::Write as _;
+
+use crate::{
+    osprintln, program::TransientProgramArea, refcell::CsRefCell, vgaconsole::VgaConsole,
+    VGA_CONSOLE,
+};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// How many consoles we have, including the main VGA console
+pub const NUM_CONSOLES: usize = 4;
+
+/// The largest screen (in characters) we can hold in a RAM buffer
+const MAX_CELLS: usize = 80 * 30;
+
+/// How many bytes we need for each RAM buffer (a glyph and an attribute for
+/// each character)
+const BUFFER_LEN: usize = MAX_CELLS * 2;
+
+/// Holds consoles 2 and up
+static VIRTUAL_CONSOLES: CsRefCell<VirtualConsoles> = CsRefCell::new(VirtualConsoles {
+    consoles: [None, None, None],
+    memory: [None, None, None],
+    enabled: false,
+    active: 0,
+    pending: None,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// The ways in which using a virtual console can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There is no such console
+    NoSuchConsole,
+    /// The consoles are busy
+    Busy,
+    /// The console isn't the same shape as the screen (the video mode
+    /// changed)
+    WrongShape,
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// Our extra consoles
+struct VirtualConsoles {
+    /// Consoles 2 and up, if they have any memory
+    consoles: [Option<VgaConsole>; NUM_CONSOLES - 1],
+    /// The RAM that consoles use when they aren't on screen
+    memory: [Option<&'static mut [u8]>; NUM_CONSOLES - 1],
+    /// Is the main console small enough for us to have extra consoles?
+    enabled: bool,
+    /// Which console is on screen (0 is the main VGA console)
+    active: usize,
+    /// A console we were asked to switch to, which doesn't have any memory
+    /// yet
+    pending: Option<usize>,
+}
+
+impl VirtualConsoles {
+    /// Swap screens between the main console and one of ours.
+    fn swap(&mut self, main: &mut VgaConsole, index: usize) -> Result<(), Error> {
+        let Some(Some(console)) = self.consoles.get_mut(index.wrapping_sub(1)) else {
+            return Err(Error::NoSuchConsole);
+        };
+        main.swap_screens(console).map_err(|_| Error::WrongShape)
+    }
+
+    /// Put the given console (0 is the main console) on the screen.
+    fn show(&mut self, main: &mut VgaConsole, index: usize) -> Result<(), Error> {
+        if index != 0 && !matches!(self.consoles.get(index - 1), Some(Some(_))) {
+            return Err(Error::NoSuchConsole);
+        }
+        // Put the main console back on screen, then swap it with the one we want
+        let active = self.active;
+        if active != 0 {
+            self.swap(main, active)?;
+            self.active = 0;
+        }
+        if index != 0 {
+            self.swap(main, index)?;
+        }
+        self.active = index;
+        Ok(())
+    }
+
+    /// Make the given console (1 and up) in its memory, if it has some, with
+    /// the same shape as the main console.
+    fn create(&mut self, main: &VgaConsole, index: usize) {
+        let Some(memory) = self.memory[index - 1].as_mut() else {
+            return;
+        };
+        let mut console = VgaConsole::new(
+            memory.as_mut_ptr() as *mut u32,
+            main.width(),
+            main.full_height(),
+        );
+        console.set_status_line(main.status_line());
+        self.consoles[index - 1] = Some(console);
+    }
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Create the extra consoles, to match the main VGA console.
+///
+/// If the screen is too big to fit in our RAM buffers, there will only be
+/// the main console. Call `release` first if the consoles already exist.
+pub fn setup() {
+    let mut vga_guard = VGA_CONSOLE.lock();
+    let mut vcs = VIRTUAL_CONSOLES.lock();
+    vcs.active = 0;
+    let Some(main) = vga_guard.as_mut() else {
+        return;
+    };
+    vcs.enabled = (main.width() * main.full_height()) as usize <= MAX_CELLS;
+    if vcs.enabled {
+        for index in 1..NUM_CONSOLES {
+            vcs.create(main, index);
+        }
+    }
+}
+
+/// Put the main console back on the screen and remove the extra consoles.
+///
+/// They keep their memory, for when `setup` makes them again. Do this before
+/// you change the geometry of the main console.
+pub fn release() {
+    let mut vga_guard = VGA_CONSOLE.lock();
+    let mut vcs = VIRTUAL_CONSOLES.lock();
+    if let Some(main) = vga_guard.as_mut() {
+        let active = vcs.active;
+        if active != 0 {
+            let _ = vcs.swap(main, active);
+        }
+    }
+    vcs.active = 0;
+    vcs.enabled = false;
+    vcs.pending = None;
+    for console in vcs.consoles.iter_mut() {
+        *console = None;
+    }
+}
+
+/// Forget the extra consoles and their memory, as if the OS had just
+/// started.
+///
+/// The tests boot the OS over and over, each time with a new TPA.
+#[cfg(test)]
+pub fn reset() {
+    release();
+    for memory in VIRTUAL_CONSOLES.lock().memory.iter_mut() {
+        *memory = None;
+    }
+}
+
+/// Unlock the virtual consoles, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    VIRTUAL_CONSOLES.force_unlock();
+}
+
+/// Put the given console (0 is the main console) on the screen.
+///
+/// If the console has never been used, it has no memory yet, so we only
+/// switch to it at the next [`poll`].
+pub fn switch_to(index: usize) -> Result<(), Error> {
+    let mut vga_guard = VGA_CONSOLE.try_lock().map_err(|_| Error::Busy)?;
+    let mut vcs = VIRTUAL_CONSOLES.try_lock().map_err(|_| Error::Busy)?;
+    let main = vga_guard.as_mut().ok_or(Error::NoSuchConsole)?;
+    if index == vcs.active {
+        return Ok(());
+    }
+    if index >= NUM_CONSOLES || (index != 0 && !vcs.enabled) {
+        return Err(Error::NoSuchConsole);
+    }
+    if index != 0 && vcs.consoles[index - 1].is_none() {
+        vcs.pending = Some(index);
+        return Ok(());
+    }
+    vcs.pending = None;
+    vcs.show(main, index)
+}
+
+/// Finish switching to a console that has never been used, by reserving
+/// memory for it off the top of the TPA.
+///
+/// Only call this when no program is running.
+pub fn poll(tpa: &mut TransientProgramArea) {
+    let Ok(mut vga_guard) = VGA_CONSOLE.try_lock() else {
+        return;
+    };
+    let Ok(mut vcs) = VIRTUAL_CONSOLES.try_lock() else {
+        return;
+    };
+    let (Some(index), Some(main)) = (vcs.pending.take(), vga_guard.as_mut()) else {
+        return;
+    };
+    if vcs.memory[index - 1].is_none() {
+        let mut name: heapless::String<16> = heapless::String::new();
+        let _ = write!(name, "Console {}", index + 1);
+        vcs.memory[index - 1] = tpa.reserve(&name, BUFFER_LEN);
+    }
+    vcs.create(main, index);
+    if vcs.show(main, index).is_err() {
+        drop(vcs);
+        drop(vga_guard);
+        osprintln!(log: Warn, "Not enough memory for console {}", index + 1);
+    }
+}
+
+/// Which console (0 is the main console) is on the screen?
+pub fn active() -> usize {
+    VIRTUAL_CONSOLES.lock().active
+}
+
+/// Write some bytes to the given console (0 is the main console).
+///
+/// Consoles that have never been on the screen don't have anywhere to keep
+/// the text, so they give [`Error::NoSuchConsole`].
+pub fn write_bstr(index: usize, data: &[u8]) -> Result<(), Error> {
+    if index == 0 {
+        let mut vga_guard = VGA_CONSOLE.try_lock().map_err(|_| Error::Busy)?;
+        let main = vga_guard.as_mut().ok_or(Error::NoSuchConsole)?;
+        main.write_bstr(data);
+    } else {
+        let mut vcs = VIRTUAL_CONSOLES.try_lock().map_err(|_| Error::Busy)?;
+        let Some(Some(console)) = vcs.consoles.get_mut(index - 1) else {
+            return Err(Error::NoSuchConsole);
+        };
+        console.write_bstr(data);
+    }
+    Ok(())
+}
+
+/// Draw some text on the status line of whichever console is on screen.
+pub fn write_status(text: &str) {
+    let Ok(mut vga_guard) = VGA_CONSOLE.try_lock() else {
+        return;
+    };
+    let Ok(mut vcs) = VIRTUAL_CONSOLES.try_lock() else {
+        return;
+    };
+    let active = vcs.active;
+    let console = if active == 0 {
+        vga_guard.as_mut()
+    } else {
+        vcs.consoles.get_mut(active - 1).and_then(|c| c.as_mut())
+    };
+    if let Some(console) = console {
+        console.write_status(text);
+    }
+}
+
+// End of file
@@ -24,12 +24,36 @@
 // Modules and Imports
 // ===========================================================================
 
-use crate::bios::video::{Attr, Mode, TextBackgroundColour, TextForegroundColour};
+use crate::bios::video::{Attr, Mode, RGBColour, TextBackgroundColour, TextForegroundColour};
 
 // ===========================================================================
 // Global Variables
 // ===========================================================================
 
+/// The standard VGA text colours, in [`TextForegroundColour`] order.
+pub const DEFAULT_PALETTE: [RGBColour; 16] = [
+    RGBColour::from_rgb(0x00, 0x00, 0x00),
+    RGBColour::from_rgb(0x00, 0x00, 0xAA),
+    RGBColour::from_rgb(0x00, 0xAA, 0x00),
+    RGBColour::from_rgb(0x00, 0xAA, 0xAA),
+    RGBColour::from_rgb(0xAA, 0x00, 0x00),
+    RGBColour::from_rgb(0xAA, 0x00, 0xAA),
+    RGBColour::from_rgb(0xAA, 0x55, 0x00),
+    RGBColour::from_rgb(0xAA, 0xAA, 0xAA),
+    RGBColour::from_rgb(0x55, 0x55, 0x55),
+    RGBColour::from_rgb(0x55, 0x55, 0xFF),
+    RGBColour::from_rgb(0x55, 0xFF, 0x55),
+    RGBColour::from_rgb(0x55, 0xFF, 0xFF),
+    RGBColour::from_rgb(0xFF, 0x55, 0x55),
+    RGBColour::from_rgb(0xFF, 0x55, 0xFF),
+    RGBColour::from_rgb(0xFF, 0xFF, 0x55),
+    RGBColour::from_rgb(0xFF, 0xFF, 0xFF),
+];
+
+/// How many characters we put together in RAM before copying them to the
+/// screen
+const ROW_BUFFER_CELLS: usize = 80;
+
 // ===========================================================================
 // Macros
 // ===========================================================================
@@ -38,10 +62,34 @@ use crate::bios::video::{Attr, Mode, TextBackgroundColour, TextForegroundColour}
 // Public types
 // ===========================================================================
 
+/// Where on the screen the OS status line goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StatusLine {
+    /// The first row of the screen
+    Top,
+    /// The last row of the screen
+    Bottom,
+}
+
+/// Two consoles couldn't swap screens, because they aren't the same shape.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WrongShape;
+
 /// Represents our simulation of a DEC-like ANSI video terminal.
 pub struct VgaConsole {
     inner: ConsoleInner,
     parser: vte::Parser<16>,
+    /// The start of the whole text buffer, including any status line
+    base_addr: *mut u32,
+    /// The height of the whole screen, including any status line
+    full_height: isize,
+    /// Where the status line is, if we have one
+    status_line: Option<StatusLine>,
+    /// Do bytes from 0x80 to 0xFF go straight to the screen as glyphs,
+    /// instead of being decoded as UTF-8?
+    code_page_passthrough: bool,
+    /// Has the size of the console changed since someone last asked?
+    resized: bool,
 }
 
 impl VgaConsole {
@@ -52,6 +100,13 @@ impl VgaConsole {
         false,
     );
 
+    /// White on Blue
+    const STATUS_ATTR: Attr = Attr::new(
+        TextForegroundColour::White,
+        TextBackgroundColour::Blue,
+        false,
+    );
+
     pub fn new(addr: *mut u32, width: isize, height: isize) -> VgaConsole {
         VgaConsole {
             inner: ConsoleInner {
@@ -66,8 +121,16 @@ impl VgaConsole {
                 cursor_wanted: false,
                 cursor_holder: None,
                 cursor_depth: 0,
+                scroll_top: 0,
+                scroll_bottom: height - 1,
+                ground: true,
             },
             parser: vte::Parser::new_with_size(),
+            base_addr: addr,
+            full_height: height,
+            status_line: None,
+            code_page_passthrough: false,
+            resized: false,
         }
     }
 
@@ -76,12 +139,245 @@ impl VgaConsole {
     /// Non text modes are ignored.
     pub fn change_mode(&mut self, mode: Mode) {
         if let (Some(height), Some(width)) = (mode.text_height(), mode.text_width()) {
-            self.inner.height = height as isize;
+            self.full_height = height as isize;
             self.inner.width = width as isize;
+            self.update_geometry();
             self.clear();
         }
     }
 
+    /// Put the terminal back how it started, without touching the screen.
+    ///
+    /// Forgets any half-finished escape sequence, and resets the text
+    /// attributes, the scrolling region, the cursor and code page
+    /// passthrough mode.
+    pub fn reset_state(&mut self) {
+        self.parser = vte::Parser::new_with_size();
+        self.inner.ground = true;
+        self.code_page_passthrough = false;
+        self.inner.cursor_disable();
+        self.inner.attr = Self::DEFAULT_ATTR;
+        self.inner.bright = false;
+        self.inner.reverse = false;
+        self.inner.scroll_top = 0;
+        self.inner.scroll_bottom = self.inner.height - 1;
+        self.inner.cursor_wanted = true;
+        self.inner.cursor_enable();
+    }
+
+    /// Reserve a row of the screen for the OS status line, or give it back.
+    ///
+    /// Everything else on the console only sees the rest of the screen, so
+    /// applications can't scroll or draw over the status line.
+    pub fn set_status_line(&mut self, status_line: Option<StatusLine>) {
+        self.status_line = status_line;
+        self.update_geometry();
+        self.clear();
+        if status_line.is_some() {
+            self.write_status("");
+        }
+    }
+
+    /// Where is the status line, if we have one?
+    pub fn status_line(&self) -> Option<StatusLine> {
+        self.status_line
+    }
+
+    /// The width of the screen, in characters
+    pub fn width(&self) -> isize {
+        self.inner.width
+    }
+
+    /// The height of the screen in characters, including any status line
+    pub fn full_height(&self) -> isize {
+        self.full_height
+    }
+
+    /// The height of the screen in characters, not counting any status line
+    pub fn height(&self) -> isize {
+        self.inner.height
+    }
+
+    /// Has the size of the console changed since `take_resized` was last
+    /// called?
+    pub fn resized(&self) -> bool {
+        self.resized
+    }
+
+    /// Check if the size of the console has changed, and clear the flag.
+    pub fn take_resized(&mut self) -> bool {
+        core::mem::take(&mut self.resized)
+    }
+
+    /// Swap screens with another console.
+    ///
+    /// The contents of the two text buffers are exchanged, and each console
+    /// carries on using the other's buffer. This is how we move a console on
+    /// to (or off of) the real screen.
+    ///
+    /// If the video mode changed under one of them, they won't be the same
+    /// shape any more, so we leave them both alone and give an error.
+    pub fn swap_screens(&mut self, other: &mut VgaConsole) -> Result<(), WrongShape> {
+        if self.inner.width != other.inner.width
+            || self.full_height != other.full_height
+            || self.status_line != other.status_line
+        {
+            return Err(WrongShape);
+        }
+        self.inner.cursor_disable();
+        other.inner.cursor_disable();
+        let num_words = (self.inner.width * self.full_height / 2) as usize;
+        unsafe {
+            core::ptr::swap_nonoverlapping(self.base_addr, other.base_addr, num_words);
+        }
+        core::mem::swap(&mut self.base_addr, &mut other.base_addr);
+        core::mem::swap(&mut self.inner.addr, &mut other.inner.addr);
+        other.inner.cursor_enable();
+        self.inner.cursor_enable();
+        Ok(())
+    }
+
+    /// Draw some text on the status line.
+    ///
+    /// The text is truncated or padded with spaces to fill the line. Does
+    /// nothing if there is no status line.
+    pub fn write_status(&mut self, text: &str) {
+        let row = match self.status_line {
+            None => return,
+            Some(StatusLine::Top) => 0,
+            Some(StatusLine::Bottom) => self.full_height - 1,
+        };
+        let byte_addr = self.base_addr as *mut u8;
+        let mut chars = text.chars();
+        for col in 0..self.inner.width {
+            let glyph = chars
+                .next()
+                .map(ConsoleInner::map_char_to_glyph)
+                .unwrap_or(b' ');
+            let offset = ((row * self.inner.width) + col) * 2;
+            unsafe {
+                core::ptr::write_volatile(byte_addr.offset(offset), glyph);
+                core::ptr::write_volatile(byte_addr.offset(offset + 1), Self::STATUS_ATTR.as_u8());
+            }
+        }
+    }
+
+    /// Copy the glyphs from part of a row of the whole screen (including any
+    /// status line) into `out`.
+    ///
+    /// You get whatever is under the cursor, rather than the cursor. Returns
+    /// how many glyphs we copied.
+    pub fn read_glyphs(&mut self, row: isize, col: isize, out: &mut [u8]) -> usize {
+        if row < 0 || row >= self.full_height || col < 0 || col >= self.inner.width {
+            return 0;
+        }
+        let count = out.len().min((self.inner.width - col) as usize);
+        self.inner.cursor_disable();
+        let byte_addr = self.base_addr as *const u8;
+        for (idx, glyph) in out[0..count].iter_mut().enumerate() {
+            let offset = ((row * self.inner.width) + col + idx as isize) * 2;
+            *glyph = unsafe { core::ptr::read_volatile(byte_addr.offset(offset)) };
+        }
+        self.inner.cursor_enable();
+        count
+    }
+
+    /// Copy the glyphs and attributes from part of a row of the whole screen
+    /// (including any status line) into `out`.
+    ///
+    /// Each cell has the glyph in the bottom byte and the attribute in the
+    /// top byte. You get whatever is under the cursor, rather than the
+    /// cursor. Returns how many cells we copied.
+    pub fn read_cells(&mut self, row: isize, col: isize, out: &mut [u16]) -> usize {
+        if row < 0 || row >= self.full_height || col < 0 || col >= self.inner.width {
+            return 0;
+        }
+        let count = out.len().min((self.inner.width - col) as usize);
+        self.inner.cursor_disable();
+        let byte_addr = self.base_addr as *const u8;
+        for (idx, cell) in out[0..count].iter_mut().enumerate() {
+            let offset = ((row * self.inner.width) + col + idx as isize) * 2;
+            let (glyph, attr) = unsafe {
+                (
+                    core::ptr::read_volatile(byte_addr.offset(offset)),
+                    core::ptr::read_volatile(byte_addr.offset(offset + 1)),
+                )
+            };
+            *cell = u16::from_le_bytes([glyph, attr]);
+        }
+        self.inner.cursor_enable();
+        count
+    }
+
+    /// Put cells (like [`VgaConsole::read_cells`] gives you) straight on to
+    /// part of a row of the whole screen, without moving the cursor or
+    /// going through the ANSI decoder.
+    ///
+    /// Returns how many cells we wrote.
+    pub fn write_cells(&mut self, row: isize, col: isize, cells: &[u16]) -> usize {
+        if row < 0 || row >= self.full_height || col < 0 || col >= self.inner.width {
+            return 0;
+        }
+        let count = cells.len().min((self.inner.width - col) as usize);
+        self.inner.cursor_disable();
+        let byte_addr = self.base_addr as *mut u8;
+        for (idx, cell) in cells[0..count].iter().enumerate() {
+            let offset = ((row * self.inner.width) + col + idx as isize) * 2;
+            let [glyph, attr] = cell.to_le_bytes();
+            unsafe {
+                core::ptr::write_volatile(byte_addr.offset(offset), glyph);
+                core::ptr::write_volatile(byte_addr.offset(offset + 1), attr);
+            }
+        }
+        self.inner.cursor_enable();
+        count
+    }
+
+    /// Which glyph in the font shows this character?
+    pub fn char_to_glyph(ch: char) -> u8 {
+        ConsoleInner::map_char_to_glyph(ch)
+    }
+
+    /// Work out which character each glyph in the font shows.
+    ///
+    /// Glyphs that don't show a character we know about come out as `?`,
+    /// except glyph 0 (which is what an empty screen is full of), which is a
+    /// space.
+    pub fn glyph_table() -> [char; 256] {
+        let mut table = ['?'; 256];
+        for ch in (0..=0xFFFF).filter_map(char::from_u32) {
+            table[usize::from(ConsoleInner::map_char_to_glyph(ch))] = ch;
+        }
+        // Everything we can't draw maps to the `?` glyph, so put that back
+        table[usize::from(b'?')] = '?';
+        table[0] = ' ';
+        table
+    }
+
+    /// Work out which part of the screen the console can use.
+    fn update_geometry(&mut self) {
+        self.inner.cursor_disable();
+        let row_len_words = self.inner.width / 2;
+        match self.status_line {
+            None => {
+                self.inner.addr = self.base_addr;
+                self.inner.height = self.full_height;
+            }
+            Some(StatusLine::Top) => {
+                self.inner.addr = unsafe { self.base_addr.offset(row_len_words) };
+                self.inner.height = self.full_height - 1;
+            }
+            Some(StatusLine::Bottom) => {
+                self.inner.addr = self.base_addr;
+                self.inner.height = self.full_height - 1;
+            }
+        }
+        self.inner.reset_scroll_region();
+        self.inner.move_cursor_relative(0, 0);
+        self.inner.cursor_enable();
+        self.resized = true;
+    }
+
     /// Clear the screen.
     ///
     /// Every character on the screen is replaced with an space (U+0020).
@@ -95,13 +391,61 @@ impl VgaConsole {
     ///
     /// Is parsed for ANSI codes, and Unicode is converted to Code Page 850 for
     /// display on the VGA screen.
+    ///
+    /// The parser keeps hold of any partial UTF-8 sequence until the next
+    /// call, so it's fine for a character to be split across two writes.
+    ///
+    /// In code page passthrough mode, bytes from 0x80 to 0xFF aren't UTF-8,
+    /// but are drawn as they are.
     pub fn write_bstr(&mut self, bstr: &[u8]) {
         self.inner.cursor_disable();
-        for b in bstr {
-            self.parser.advance(&mut self.inner, *b);
-        }
+        self.write_bytes(bstr, self.code_page_passthrough);
         self.inner.cursor_enable();
     }
+
+    /// Turn code page passthrough mode on or off.
+    ///
+    /// This is for programs that write Code Page 850 bytes, rather than
+    /// UTF-8. It only affects [`Self::write_bstr`].
+    pub fn set_code_page_passthrough(&mut self, enabled: bool) {
+        self.code_page_passthrough = enabled;
+    }
+
+    /// Are we in code page passthrough mode?
+    pub fn code_page_passthrough(&self) -> bool {
+        self.code_page_passthrough
+    }
+
+    /// Send some bytes to the screen.
+    ///
+    /// Runs of printable ASCII (and, if `passthrough` is set, bytes from 0x80
+    /// to 0xFF) skip the ANSI parser, as long as it isn't part way through an
+    /// escape sequence. Everything else goes through the parser.
+    ///
+    /// Don't do this if the cursor is enabled.
+    fn write_bytes(&mut self, mut bytes: &[u8], passthrough: bool) {
+        let is_printable = |b: &u8| (0x20..=0x7E).contains(b) || (passthrough && *b >= 0x80);
+        while let Some((&first, rest)) = bytes.split_first() {
+            if self.inner.ground && is_printable(&first) {
+                let run_len = bytes
+                    .iter()
+                    .position(|b| !is_printable(b))
+                    .unwrap_or(bytes.len());
+                let (run, rest) = bytes.split_at(run_len);
+                self.inner.print_glyphs(run);
+                bytes = rest;
+            } else {
+                // C0 control codes (other than ESC) don't leave the ground
+                // state. For anything else, we wait for the parser to tell
+                // us it's got back there.
+                if first >= 0x20 || first == 0x1B {
+                    self.inner.ground = false;
+                }
+                self.parser.advance(&mut self.inner, first);
+                bytes = rest;
+            }
+        }
+    }
 }
 
 // ===========================================================================
@@ -138,6 +482,14 @@ struct ConsoleInner {
     cursor_depth: u8,
     /// What character should be where the cursor currently is?
     cursor_holder: Option<u8>,
+    /// The top row of the scrolling region (inclusive)
+    scroll_top: isize,
+    /// The bottom row of the scrolling region (inclusive)
+    scroll_bottom: isize,
+    /// Do we know the parser is between escape sequences?
+    ///
+    /// If so, plain ASCII text can skip the parser.
+    ground: bool,
 }
 
 impl ConsoleInner {
@@ -216,24 +568,61 @@ impl ConsoleInner {
     fn scroll_as_required(&mut self) {
         while self.col >= self.width {
             self.col -= self.width;
-            self.row += 1;
+            self.line_feed();
         }
         while self.row >= self.height {
             self.row -= 1;
-            self.scroll_page();
+            if !self.has_scroll_region() {
+                self.scroll_page();
+            }
+        }
+    }
+
+    /// Move down one line.
+    ///
+    /// If we're at the bottom of a scrolling region, the region scrolls up
+    /// immediately. Otherwise we might end up off-screen, and that's handled
+    /// by `scroll_as_required`.
+    fn line_feed(&mut self) {
+        if self.has_scroll_region() && self.row == self.scroll_bottom {
+            self.scroll_up(self.scroll_top, self.scroll_bottom, 1);
+        } else {
+            self.row += 1;
         }
     }
 
+    /// Is a scrolling region set that isn't the whole screen?
+    fn has_scroll_region(&self) -> bool {
+        self.scroll_top != 0 || self.scroll_bottom != self.height - 1
+    }
+
+    /// Make the scrolling region the whole screen
+    fn reset_scroll_region(&mut self) {
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+    }
+
     /// Blank the screen
     fn clear(&mut self) {
-        for row in 0..self.height {
-            for col in 0..self.width {
-                self.write_at(row, col, b' ', false);
-            }
-        }
+        self.blank_rows(0, self.height - 1);
         self.home();
     }
 
+    /// Fill the rows from `top` to `bottom` (inclusive) with space characters.
+    ///
+    /// Rows are always a whole number of 32-bit words long, so we can write
+    /// two characters at a time.
+    ///
+    /// Don't do this if the cursor is enabled.
+    fn blank_rows(&mut self, top: isize, bottom: isize) {
+        let cell = u32::from(u16::from_ne_bytes([b' ', self.draw_attr().as_u8()]));
+        let cell_pair = (cell << 16) | cell;
+        let row_len_words = self.width / 2;
+        for word in (row_len_words * top)..(row_len_words * (bottom + 1)) {
+            unsafe { core::ptr::write_volatile(self.addr.offset(word), cell_pair) };
+        }
+    }
+
     /// Put a glyph at the current position on the screen.
     ///
     /// Don't do this if the cursor is enabled.
@@ -254,15 +643,49 @@ impl ConsoleInner {
         let offset = ((row * self.width) + col) * 2;
         let byte_addr = self.addr as *mut u8;
         unsafe { core::ptr::write_volatile(byte_addr.offset(offset), glyph) };
-        let attr = if self.reverse {
+        let attr = self.draw_attr();
+        unsafe { core::ptr::write_volatile(byte_addr.offset(offset + 1), attr.as_u8()) };
+    }
+
+    /// Draw a run of glyphs and update states.
+    ///
+    /// For printable ASCII, this does the same as calling `print` for each
+    /// character, but builds up each row of text in RAM and copies it to the
+    /// screen in one go.
+    ///
+    /// Don't do this if the cursor is enabled.
+    fn print_glyphs(&mut self, mut text: &[u8]) {
+        let attr = self.draw_attr().as_u8();
+        let mut row_buffer = [0u16; ROW_BUFFER_CELLS];
+        while !text.is_empty() {
+            self.scroll_as_required();
+            let space = ((self.width - self.col) as usize).min(ROW_BUFFER_CELLS);
+            let (chunk, rest) = text.split_at(text.len().min(space));
+            for (cell, glyph) in row_buffer.iter_mut().zip(chunk) {
+                *cell = u16::from_ne_bytes([*glyph, attr]);
+            }
+            let offset = (self.row * self.width) + self.col;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    row_buffer.as_ptr(),
+                    (self.addr as *mut u16).offset(offset),
+                    chunk.len(),
+                );
+            }
+            self.col += chunk.len() as isize;
+            text = rest;
+        }
+    }
+
+    /// The attribute we draw characters with, allowing for reverse video.
+    fn draw_attr(&self) -> Attr {
+        if self.reverse {
             let new_fg = self.attr.bg().make_foreground();
             let new_bg = self.attr.fg().make_background();
             Attr::new(new_fg, new_bg, false)
         } else {
             self.attr
-        };
-
-        unsafe { core::ptr::write_volatile(byte_addr.offset(offset + 1), attr.as_u8()) };
+        }
     }
 
     /// Read a glyph at the current position
@@ -290,19 +713,46 @@ impl ConsoleInner {
     ///
     /// The bottom line will be all space characters.
     fn scroll_page(&mut self) {
+        self.scroll_up(0, self.height - 1, 1);
+    }
+
+    /// Move the rows from `top` to `bottom` (inclusive) up by `count` lines.
+    ///
+    /// The lines at the bottom of the region will be all space characters.
+    ///
+    /// The CPU copies whole 32-bit words, which works because rows are always
+    /// a whole number of words long.
+    fn scroll_up(&mut self, top: isize, bottom: isize, count: isize) {
         let row_len_words = self.width / 2;
+        let count = count.min(bottom + 1 - top);
         unsafe {
-            // Scroll rows[1..=height-1] to become rows[0..=height-2].
+            // Scroll rows[top+count..=bottom] to become rows[top..=bottom-count].
             core::ptr::copy(
-                self.addr.offset(row_len_words),
-                self.addr,
-                (row_len_words * (self.height - 1)) as usize,
+                self.addr.offset(row_len_words * (top + count)),
+                self.addr.offset(row_len_words * top),
+                (row_len_words * (bottom + 1 - top - count)) as usize,
             );
         }
-        // Blank the bottom line of the screen (rows[height-1]).
-        for col in 0..self.width {
-            self.write_at(self.height - 1, col, b' ', false);
+        // Blank the bottom lines of the region
+        self.blank_rows(bottom + 1 - count, bottom);
+    }
+
+    /// Move the rows from `top` to `bottom` (inclusive) down by `count` lines.
+    ///
+    /// The lines at the top of the region will be all space characters.
+    fn scroll_down(&mut self, top: isize, bottom: isize, count: isize) {
+        let row_len_words = self.width / 2;
+        let count = count.min(bottom + 1 - top);
+        unsafe {
+            // Scroll rows[top..=bottom-count] to become rows[top+count..=bottom].
+            core::ptr::copy(
+                self.addr.offset(row_len_words * top),
+                self.addr.offset(row_len_words * (top + count)),
+                (row_len_words * (bottom + 1 - top - count)) as usize,
+            );
         }
+        // Blank the top lines of the region
+        self.blank_rows(top, top + count - 1);
     }
 
     /// Convert a Unicode Scalar Value to a font glyph.
@@ -488,9 +938,8 @@ impl core::fmt::Write for VgaConsole {
     fn write_str(&mut self, data: &str) -> core::fmt::Result {
         self.inner.cursor_disable();
         assert!(self.inner.cursor_holder.is_none());
-        for b in data.bytes() {
-            self.parser.advance(&mut self.inner, b);
-        }
+        // The OS always talks UTF-8
+        self.write_bytes(data.as_bytes(), false);
         self.inner.cursor_enable();
         Ok(())
     }
@@ -499,6 +948,8 @@ impl core::fmt::Write for VgaConsole {
 impl vte::Perform for ConsoleInner {
     /// Draw a character to the screen and update states.
     fn print(&mut self, ch: char) {
+        // The parser only prints from the ground state
+        self.ground = true;
         self.scroll_as_required();
         self.write(Self::map_char_to_glyph(ch));
         self.col += 1;
@@ -524,7 +975,7 @@ impl vte::Perform for ConsoleInner {
             }
             b'\n' => {
                 self.col = 0;
-                self.row += 1;
+                self.line_feed();
             }
             _ => {
                 // ignore unknown C0 or C1 control code
@@ -546,6 +997,8 @@ impl vte::Perform for ConsoleInner {
         _ignore: bool,
         action: char,
     ) {
+        // The parser goes back to the ground state after this
+        self.ground = true;
         // Just in case you want a single parameter, here it is
         let mut first = *params.iter().next().and_then(|s| s.first()).unwrap_or(&1) as isize;
         let mut second = *params.iter().nth(1).and_then(|s| s.first()).unwrap_or(&1) as isize;
@@ -725,11 +1178,7 @@ impl vte::Perform for ConsoleInner {
                     }
                     2 => {
                         // Erase the complete display
-                        for row in 0..self.height {
-                            for col in 0..self.width {
-                                self.write_at(row, col, b' ', false);
-                            }
-                        }
+                        self.blank_rows(0, self.height - 1);
                     }
                     _ => {
                         // Ignore it
@@ -762,6 +1211,36 @@ impl vte::Perform for ConsoleInner {
                     }
                 }
             }
+            'r' => {
+                // Set Top and Bottom Margins (DECSTBM)
+                if first == 0 {
+                    first = 1;
+                }
+                let bottom = match params.iter().nth(1).and_then(|s| s.first()) {
+                    Some(0) | None => self.height,
+                    Some(n) => *n as isize,
+                };
+                // We are zero-indexed, ANSI is 1-indexed
+                if first < bottom && bottom <= self.height {
+                    self.scroll_top = first - 1;
+                    self.scroll_bottom = bottom - 1;
+                    self.home();
+                }
+            }
+            'S' => {
+                // Scroll Up
+                if first == 0 {
+                    first = 1;
+                }
+                self.scroll_up(self.scroll_top, self.scroll_bottom, first);
+            }
+            'T' => {
+                // Scroll Down
+                if first == 0 {
+                    first = 1;
+                }
+                self.scroll_down(self.scroll_top, self.scroll_bottom, first);
+            }
             'n' if first == 6 => {
                 // Device Status Report - todo.
                 //
@@ -783,6 +1262,14 @@ impl vte::Perform for ConsoleInner {
             }
         }
     }
+
+    /// The final character of an escape sequence has arrived.
+    ///
+    /// We don't support any of these, but the parser is back in the ground
+    /// state afterwards.
+    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {
+        self.ground = true;
+    }
 }
 
 // ===========================================================================
@@ -852,6 +1339,43 @@ mod tests {
         assert_eq!(console.inner.col, 0);
     }
 
+    #[test]
+    fn read_back() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        console.write_bstr("\nab\u{00E9}\u{2500}".as_bytes());
+        let table = VgaConsole::glyph_table();
+        let mut glyphs = [0u8; 20];
+        let count = console.read_glyphs(1, 1, &mut glyphs);
+        // Stops at the edge of the screen
+        assert_eq!(count, WIDTH - 1);
+        let text: String = glyphs[0..count]
+            .iter()
+            .map(|g| table[*g as usize])
+            .collect();
+        assert_eq!(text, "b\u{00E9}\u{2500}        ");
+        assert_eq!(console.read_glyphs(HEIGHT as isize, 0, &mut glyphs), 0);
+        assert_eq!(table[b'?' as usize], '?');
+    }
+
+    #[test]
+    fn cells() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        console.write_bstr(b"Hello");
+        let mut saved = [0u16; 3];
+        assert_eq!(console.read_cells(0, 1, &mut saved), 3);
+        assert_eq!(saved, [0x0765, 0x076c, 0x076c]);
+        // Stops at the edge of the screen
+        assert_eq!(console.write_cells(0, WIDTH as isize - 2, &[0x1f41; 4]), 2);
+        console.write_cells(0, 1, &saved);
+        let mut glyphs = [0u8; WIDTH];
+        console.read_glyphs(0, 0, &mut glyphs);
+        assert_eq!(&glyphs, b"Hello\0\0\0\0\0AA");
+        // The cursor didn't move
+        assert_eq!(console.inner.col, 5);
+    }
+
     #[test]
     fn cr_overprint() {
         let mut buffer = [0u32; WIDTH * HEIGHT / 2];
@@ -1640,6 +2164,303 @@ mod tests {
         assert_eq!(console.inner.row, 1);
         assert_eq!(console.inner.col, 1);
     }
+    #[test]
+    fn status_line() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        console.set_status_line(Some(super::StatusLine::Top));
+        console.write_status("Hi");
+        // Scroll the rest of the screen, which shouldn't touch the status line
+        console.write_bstr(b"0\n1\n2\n3\n4\n5\n6");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        48 1f|69 1f|20 1f|20 1f|20 1f|20 1f|20 1f|20 1f|20 1f|20 1f|20 1f|20 1f|\n\
+        31 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        32 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        33 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        34 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        35 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        36 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n"
+        );
+        assert_eq!(console.inner.row, 5);
+        assert_eq!(console.inner.col, 1);
+    }
+
+    #[test]
+    fn scroll_region() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        // Draw a status line on the bottom row, then make rows 2..=4 scroll
+        console.write_bstr(b"\x1b[7Hs\x1b[2;4r");
+        assert_eq!(console.inner.row, 0);
+        assert_eq!(console.inner.col, 0);
+        console.write_bstr(b"\x1b[2H1\n2\n3\n4");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        32 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        33 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        34 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        73 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n"
+        );
+        assert_eq!(console.inner.row, 3);
+        assert_eq!(console.inner.col, 1);
+        // Resetting the region makes the whole screen scroll again
+        console.write_bstr(b"\x1b[r");
+        assert!(!console.inner.has_scroll_region());
+    }
+
+    #[test]
+    fn scroll_up_and_down() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        console.write_bstr(b"0\n1\n2\x1b[2S");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        32 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n"
+        );
+        console.write_bstr(b"\x1b[T");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n\
+        32 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|20 07|\n"
+        );
+        // The cursor doesn't move when we scroll
+        assert_eq!(console.inner.row, 2);
+        assert_eq!(console.inner.col, 1);
+    }
+
+    #[test]
+    fn reset_state() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        // Red on blue, a scroll region, no cursor and half an escape sequence
+        console.write_bstr(b"\x1b[31;44m\x1b[2;4r\x1b[?25l\x1b[1");
+        console.reset_state();
+        assert!(!console.inner.has_scroll_region());
+        assert!(console.inner.cursor_wanted);
+        // The '1' is in the default colours, and the cursor is back
+        console.write_bstr(b"1");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        31 07|5f 07|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n"
+        );
+    }
+
+    #[test]
+    fn utf8_split_across_writes() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        // An e-acute and a pound sign, each split over two writes
+        console.write_bstr(b"Caf\xC3");
+        console.write_bstr(b"\xA9 \xC2");
+        console.write_bstr(b"\xA31");
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        43 07|61 07|66 07|82 07|20 07|9c 07|31 07|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n"
+        );
+    }
+
+    #[test]
+    fn code_page_passthrough() {
+        let mut buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut console = VgaConsole::new(buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        console.set_code_page_passthrough(true);
+        // A box, in Code Page 850, with some colour and a control code
+        console.write_bstr(b"\xC9\xCD\x1b[1m\xBB\r\n\xC8\xCD\xBC");
+        // The OS still talks UTF-8
+        use std::fmt::Write;
+        write!(console, "\u{00E9}").unwrap();
+        assert_eq!(
+            print_buffer(&buffer),
+            "\
+        c9 07|cd 07|bb 0f|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        c8 0f|cd 0f|bc 0f|82 0f|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n\
+        00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|00 00|\n"
+        );
+        console.reset_state();
+        assert!(!console.code_page_passthrough());
+    }
+
+    /// Some text with a bit of everything in it
+    fn sample_text(lines: usize) -> Vec<u8> {
+        let mut text = Vec::new();
+        for line in 0..lines {
+            match line % 4 {
+                0 => text.extend_from_slice(b"The quick brown fox jumps over the lazy dog\n"),
+                1 => {
+                    text.extend_from_slice(b"\x1b[1;33mBright\x1b[0m and \x1b[7mreverse\x1b[0m\r\n")
+                }
+                2 => text.extend_from_slice("Caf\u{00E9} \u{00A3}1\t\u{266A}\n".as_bytes()),
+                _ => text.extend_from_slice(b"\x1b[1\n0Gsplit escape\x08!\n"),
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn fast_path_matches_parser() {
+        let text = sample_text(50);
+        let mut fast_buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut fast = VgaConsole::new(fast_buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        fast.write_bstr(&text);
+        let mut slow_buffer = [0u32; WIDTH * HEIGHT / 2];
+        let mut slow = VgaConsole::new(slow_buffer.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        for b in &text {
+            slow.parser.advance(&mut slow.inner, *b);
+        }
+        assert_eq!(print_buffer(&fast_buffer), print_buffer(&slow_buffer));
+        assert_eq!(fast.inner.row, slow.inner.row);
+        assert_eq!(fast.inner.col, slow.inner.col);
+    }
+
+    #[test]
+    fn large_write() {
+        const BIG_WIDTH: usize = 80;
+        const BIG_HEIGHT: usize = 25;
+        const LINES: usize = 30;
+        let mut text = Vec::new();
+        for line in 0..LINES {
+            text.extend_from_slice(format!("Line {}\r\n", line).as_bytes());
+        }
+        let mut buffer = [0u32; BIG_WIDTH * BIG_HEIGHT / 2];
+        let mut console =
+            VgaConsole::new(buffer.as_mut_ptr(), BIG_WIDTH as isize, BIG_HEIGHT as isize);
+        console.clear();
+        // All in one go, so it takes the fast path
+        console.write_bstr(&text);
+        // Scrolling waits for the next glyph, so the cursor sits just off
+        // the bottom
+        assert_eq!(console.inner.row, BIG_HEIGHT as isize);
+        assert_eq!(console.inner.col, 0);
+        // Each cell is a glyph and then an attribute
+        let cells = unsafe {
+            core::slice::from_raw_parts(buffer.as_ptr() as *const u8, BIG_WIDTH * BIG_HEIGHT * 2)
+        };
+        let row_text = |row: usize| {
+            let row = &cells[row * BIG_WIDTH * 2..(row + 1) * BIG_WIDTH * 2];
+            let text: String = row.iter().step_by(2).map(|b| char::from(*b)).collect();
+            text.trim_end().to_owned()
+        };
+        // The first few lines scrolled off the top
+        let first = LINES - BIG_HEIGHT;
+        for row in 0..BIG_HEIGHT {
+            assert_eq!(row_text(row), format!("Line {}", first + row));
+        }
+    }
+
+    /// Run with `cargo test fast_path_timing -- --nocapture` to see the
+    /// timings.
+    #[test]
+    fn fast_path_timing() {
+        const BIG_WIDTH: usize = 80;
+        const BIG_HEIGHT: usize = 25;
+        let text = sample_text(1000);
+        let mut fast_buffer = [0u32; BIG_WIDTH * BIG_HEIGHT / 2];
+        let mut fast = VgaConsole::new(
+            fast_buffer.as_mut_ptr(),
+            BIG_WIDTH as isize,
+            BIG_HEIGHT as isize,
+        );
+        let start = std::time::Instant::now();
+        fast.write_bstr(&text);
+        let fast_time = start.elapsed();
+        let mut slow_buffer = [0u32; BIG_WIDTH * BIG_HEIGHT / 2];
+        let mut slow = VgaConsole::new(
+            slow_buffer.as_mut_ptr(),
+            BIG_WIDTH as isize,
+            BIG_HEIGHT as isize,
+        );
+        let start = std::time::Instant::now();
+        for b in &text {
+            slow.parser.advance(&mut slow.inner, *b);
+        }
+        let slow_time = start.elapsed();
+        println!(
+            "{} bytes: {:?} with the fast path, {:?} without",
+            text.len(),
+            fast_time,
+            slow_time
+        );
+        assert_eq!(fast_buffer, slow_buffer);
+        assert_eq!(fast.inner.row, slow.inner.row);
+        assert_eq!(fast.inner.col, slow.inner.col);
+    }
+
+    #[test]
+    fn swap_screens() {
+        let mut screen = [0u32; WIDTH * HEIGHT / 2];
+        let mut ram = [0u32; WIDTH * HEIGHT / 2];
+        let mut first = VgaConsole::new(screen.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        let mut second = VgaConsole::new(ram.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        first.clear();
+        second.clear();
+        first.write_bstr(b"A");
+        second.write_bstr(b"B");
+        first.swap_screens(&mut second).unwrap();
+        // Each console carries on where it was, in the other buffer
+        first.write_bstr(b"C");
+        second.write_bstr(b"D");
+        let blank_line = "20 07|".repeat(WIDTH) + "\n";
+        let mut expected = String::from("42 07|44 07|") + &"20 07|".repeat(WIDTH - 2) + "\n";
+        expected.push_str(&blank_line.repeat(HEIGHT - 1));
+        assert_eq!(print_buffer(&screen), expected);
+        let mut expected = String::from("41 07|43 07|") + &"20 07|".repeat(WIDTH - 2) + "\n";
+        expected.push_str(&blank_line.repeat(HEIGHT - 1));
+        assert_eq!(print_buffer(&ram), expected);
+    }
+
+    #[test]
+    fn swap_screens_wrong_shape() {
+        let mut screen = [0u32; WIDTH * HEIGHT / 2];
+        let mut ram = [0u32; WIDTH * HEIGHT / 2];
+        let mut first = VgaConsole::new(screen.as_mut_ptr(), WIDTH as isize, HEIGHT as isize);
+        let mut second = VgaConsole::new(ram.as_mut_ptr(), WIDTH as isize, HEIGHT as isize - 1);
+        first.clear();
+        second.clear();
+        first.write_bstr(b"A");
+        let before = print_buffer(&screen);
+        assert_eq!(first.swap_screens(&mut second), Err(super::WrongShape));
+        // Nothing moved
+        assert_eq!(print_buffer(&screen), before);
+        first.write_bstr(b"B");
+        assert!(print_buffer(&screen).starts_with("41 07|42 07|"));
+    }
 }
 
 // ===========================================================================
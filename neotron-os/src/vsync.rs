@@ -0,0 +1,63 @@
+//! # Frame Pacing
+//!
+//! Lets the OS, and applications (through an `ioctl` on `GFX:`), wait for
+//! the vertical blanking interval in whatever video mode is running.
+//!
+//! The BIOS can only wait for a given scan line, and which line is the last
+//! one depends on the mode. We wait for the last visible line, which is as
+//! late as we can go before the blanking interval starts. We also say how
+//! long it has been since the last wait, so animations can run at the same
+//! speed even if they miss a frame, or the mode has a different frame rate.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::{refcell::CsRefCell, API};
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// When (in BIOS ticks) we last saw the vertical blanking interval start
+static LAST_VSYNC: CsRefCell<Option<u64>> = CsRefCell::new(None);
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Wait until the vertical blanking interval is about to start.
+///
+/// Returns how many BIOS ticks have passed since the last time this
+/// returned, or 0 the first time.
+pub fn wait_for_vsync() -> u64 {
+    let api = API.get();
+    let mode = (api.video_get_mode)();
+    let ticks_per_second = (api.time_ticks_per_second)().0;
+    let frame_ticks = ticks_per_second / u64::from(mode.frame_rate_hz().max(1));
+
+    let last_vsync = *LAST_VSYNC.lock();
+    if let Some(last_vsync) = last_vsync {
+        let now = (api.time_ticks_get)().0;
+        if now.saturating_sub(last_vsync) < frame_ticks / 2 {
+            // We might still be on the last line, and we mustn't return
+            // twice in one frame, so wait for the top of the next frame
+            // first
+            (api.video_wait_for_line)(0);
+        }
+    }
+    (api.video_wait_for_line)(mode.vertical_lines().saturating_sub(1));
+
+    let now = (api.time_ticks_get)().0;
+    let mut last_vsync = LAST_VSYNC.lock();
+    let delta = last_vsync.map_or(0, |last| now.saturating_sub(last));
+    *last_vsync = Some(now);
+    delta
+}
+
+/// Unlock the frame timer, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    LAST_VSYNC.force_unlock();
+}
+
+// End of file
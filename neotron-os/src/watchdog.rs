@@ -0,0 +1,124 @@
+//! # Watchdog
+//!
+//! Resets the system if the OS stops running for too long - for example,
+//! because an application has hung without ever calling back into the OS.
+//!
+//! We kick the watchdog from the main loop, whenever anyone polls the
+//! keyboard, and whenever an application writes something. OS operations
+//! that take a long time without doing any of those should hold a [`Pause`]
+//! while they run.
+//!
+//! None of this can reset the system yet. There is no hardware watchdog for
+//! `bios_start` and `bios_kick` to drive until the BIOS offers one, so
+//! [`is_supported`] says no and `config watchdog` won't turn it on.
+
+// ===========================================================================
+// Modules and Imports
+// ===========================================================================
+
+use crate::refcell::CsRefCell;
+
+// ===========================================================================
+// Global Variables
+// ===========================================================================
+
+/// Our watchdog state
+static STATE: CsRefCell<State> = CsRefCell::new(State {
+    timeout_secs: None,
+    pause_depth: 0,
+});
+
+// ===========================================================================
+// Public types
+// ===========================================================================
+
+/// Holds the watchdog off until dropped.
+///
+/// Get one of these with [`pause`].
+pub struct Pause(());
+
+impl Drop for Pause {
+    fn drop(&mut self) {
+        let mut state = STATE.lock();
+        state.pause_depth -= 1;
+        if state.pause_depth == 0 {
+            bios_start(state.timeout_secs);
+        }
+    }
+}
+
+// ===========================================================================
+// Private types
+// ===========================================================================
+
+/// What we know about the watchdog
+struct State {
+    /// How long we can go without a kick, if the watchdog is enabled
+    timeout_secs: Option<u16>,
+    /// How many `Pause` objects exist
+    pause_depth: u8,
+}
+
+// ===========================================================================
+// Public functions
+// ===========================================================================
+
+/// Can the BIOS reset the system for us?
+///
+/// If not, the watchdog is never really on, whatever it is configured to do.
+pub fn is_supported() -> bool {
+    false
+}
+
+/// Turn the watchdog on (with the given timeout in seconds), or off.
+pub fn configure(timeout_secs: Option<u16>) {
+    let mut state = STATE.lock();
+    state.timeout_secs = timeout_secs;
+    if state.pause_depth == 0 {
+        bios_start(timeout_secs);
+    }
+}
+
+/// Tell the watchdog we are still running.
+pub fn kick() {
+    // Don't wait for the lock - someone else is clearly still running
+    let Ok(state) = STATE.try_lock() else {
+        return;
+    };
+    if state.timeout_secs.is_some() && state.pause_depth == 0 {
+        bios_kick();
+    }
+}
+
+/// Stop the watchdog whilst we do something that takes a long time.
+///
+/// The watchdog starts again when the returned object is dropped.
+pub fn pause() -> Pause {
+    let mut state = STATE.lock();
+    if state.pause_depth == 0 {
+        bios_start(None);
+    }
+    state.pause_depth += 1;
+    Pause(())
+}
+
+/// Unlock the watchdog state, even if someone holds the lock.
+pub unsafe fn force_unlock() {
+    STATE.force_unlock();
+}
+
+// ===========================================================================
+// Private functions
+// ===========================================================================
+
+/// Ask the BIOS to start (or stop, if `None`) the hardware watchdog.
+fn bios_start(_timeout_secs: Option<u16>) {
+    // Nothing to start - see the module docs
+}
+
+/// Ask the BIOS to kick the hardware watchdog.
+fn bios_kick() {
+    // Nothing to kick - see the module docs
+}
+
+// End of file
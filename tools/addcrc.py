@@ -0,0 +1,39 @@
+#!/usr/bin/env python3
+"""
+Adds a checksum footer to a Neotron program.
+
+The footer is the four bytes `NCRC`, then the CRC32 (little-endian) of
+everything in the file before the footer. Neotron OS checks it when it loads
+the program, and refuses to run the program if it doesn't match. Use
+`config verify strict` to make the OS refuse programs without a footer too.
+
+If the file already has a footer, we replace it.
+
+Usage: addcrc.py <file>
+"""
+
+import struct
+import sys
+import zlib
+
+MAGIC = b"NCRC"
+FOOTER_LEN = 8
+
+
+def main():
+    if len(sys.argv) != 2:
+        print(__doc__)
+        sys.exit(1)
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        data = f.read()
+    if len(data) >= FOOTER_LEN and data[-FOOTER_LEN:-4] == MAGIC:
+        data = data[:-FOOTER_LEN]
+    crc = zlib.crc32(data)
+    with open(path, "wb") as f:
+        f.write(data + MAGIC + struct.pack("<I", crc))
+    print(f"Added CRC32 0x{crc:08x} to {path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,45 @@
+#!/usr/bin/env python3
+"""
+Answers time queries from Neotron OS over a serial port.
+
+Run this on the PC attached to your Neotron's serial console, then type
+`settime host` on the Neotron. When the OS sends `ESC ] neotron;time? BEL`,
+we reply with `ESC ] neotron;time=<secs> BEL`, where `<secs>` is the number
+of seconds since 1970-01-01T00:00:00 UTC.
+
+Needs pyserial (`pip install pyserial`).
+
+Usage: hosttime.py <port> [baud]
+"""
+
+import sys
+import time
+
+import serial
+
+QUERY = b"\x1b]neotron;time?\x07"
+
+
+def main():
+    if len(sys.argv) < 2:
+        print(__doc__)
+        sys.exit(1)
+    port = sys.argv[1]
+    baud = int(sys.argv[2]) if len(sys.argv) > 2 else 115200
+    with serial.Serial(port, baud, timeout=0.1) as ser:
+        print(f"Waiting for time queries on {port} at {baud} bps (Ctrl-C to quit)")
+        received = b""
+        while True:
+            received += ser.read(64)
+            if QUERY in received:
+                now = int(time.time())
+                ser.write(b"\x1b]neotron;time=%d\x07" % now)
+                print(f"Sent time {now}")
+                received = b""
+            else:
+                # Only keep enough to spot a query split across reads
+                received = received[-len(QUERY):]
+
+
+if __name__ == "__main__":
+    main()